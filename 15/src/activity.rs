@@ -0,0 +1,122 @@
+// Rolling reply counts per thread for the "popular threads" list. Replies
+// are counted in hourly buckets in the `activity` tree, keyed
+// "{hour:010}/{thread id}", so the last day is a single range scan and old
+// buckets can be dropped by key.
+//
+// Every post is also counted board-wide in `post_hours`, keyed "{hour:010}",
+// for the stats heatmap, which shows HEATMAP_DAYS. How long those buckets
+// are kept is the activity retention, see retention.rs.
+
+use sled::Db;
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use crate::{counters, load_post, Post};
+
+pub const HOUR: u64 = 60 * 60;
+pub const WINDOW_HOURS: u64 = 24;
+pub const HEATMAP_DAYS: u64 = 30;
+
+pub fn record_reply(db: &Db, thread_id: &str, timestamp: u64) {
+    counters::increment_bucket(&db.open_tree("activity").unwrap(), timestamp / HOUR, thread_id, 1);
+}
+
+pub fn record_post(db: &Db, timestamp: u64) {
+    let hour = timestamp / HOUR;
+    // Only the first post sets it; later swaps fail and are ignored
+    let _ = db
+        .open_tree("meta")
+        .unwrap()
+        .compare_and_swap("post_hours_since", None as Option<&[u8]>, Some(&hour.to_be_bytes()[..]))
+        .unwrap();
+    counters::increment_bucket(&db.open_tree("post_hours").unwrap(), hour, "", 1);
+}
+
+// The first hour with post counts, so the heatmap can tell "no posts" from
+// "the board didn't exist yet". None before the first post.
+pub fn post_hours_since(db: &Db) -> Option<u64> {
+    let bytes = db.open_tree("meta").unwrap().get("post_hours_since").unwrap()?;
+    Some(u64::from_be_bytes(bytes.as_ref().try_into().ok()?))
+}
+
+// Board-wide post counts per hour, for hours `from..=to`. At most one key
+// per hour is read.
+pub fn post_hours(db: &Db, from: u64, to: u64) -> HashMap<u64, u64> {
+    counters::buckets(&db.open_tree("post_hours").unwrap(), from, to)
+        .into_iter()
+        .map(|(hour, _, count)| (hour, count))
+        .collect()
+}
+
+// Posts from before the heatmap existed are counted once, on first start.
+pub fn build_post_hours_if_missing(db: &Db) {
+    let meta = db.open_tree("meta").unwrap();
+    if meta.contains_key("post_hours_built").unwrap() {
+        return;
+    }
+    let tree = db.open_tree("post_hours").unwrap();
+    let mut earliest: Option<u64> = None;
+    for raw in db.iter().values() {
+        if let Ok(post) = Post::upgrade(&raw.unwrap()) {
+            counters::increment_bucket(&tree, post.timestamp / HOUR, "", 1);
+            earliest = Some(earliest.map_or(post.timestamp, |e| e.min(post.timestamp)));
+        }
+    }
+    if let Some(earliest) = earliest {
+        meta.insert("post_hours_since", &(earliest / HOUR).to_be_bytes()).unwrap();
+    }
+    meta.insert("post_hours_built", &[]).unwrap();
+    db.flush().unwrap();
+}
+
+// Replies per thread in the last day. Threads without any are left out.
+pub fn recent_replies(db: &Db, now: u64) -> HashMap<String, u64> {
+    let to = now / HOUR;
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    for (_, thread_id, count) in counters::buckets(&db.open_tree("activity").unwrap(), to.saturating_sub(WINDOW_HOURS - 1), to) {
+        *counts.entry(thread_id).or_insert(0) += count;
+    }
+    counts
+}
+
+// The `limit` threads with the most replies in the last day, busiest
+// first. Threads that have since been deleted are skipped.
+pub fn popular_threads(db: &Db, now: u64, limit: usize) -> Vec<(Post, u64)> {
+    let mut ranked: Vec<(String, u64)> = recent_replies(db, now).into_iter().collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    ranked
+        .into_iter()
+        .filter_map(|(thread_id, count)| load_post(db, &thread_id).map(|thread| (thread, count)))
+        .take(limit)
+        .collect()
+}
+
+// Drops a deleted thread's buckets. There are at most a day of them per
+// thread, but they're keyed by hour first, so the tree is scanned. Returns
+// how many were removed.
+pub fn forget_thread(db: &Db, thread_id: &str) -> usize {
+    let activity = db.open_tree("activity").unwrap();
+    let suffix = format!("/{}", thread_id);
+    let keys: Vec<_> = activity
+        .iter()
+        .keys()
+        .map(|key| key.unwrap())
+        .filter(|key| key.ends_with(suffix.as_bytes()))
+        .collect();
+    for key in &keys {
+        activity.remove(key).unwrap();
+    }
+    keys.len()
+}
+
+// Drops reply buckets that have left the popular threads window. Returns
+// how many were removed.
+pub fn prune(db: &Db, now: u64) -> usize {
+    counters::expire_buckets(&db.open_tree("activity").unwrap(), (now / HOUR).saturating_sub(WINDOW_HOURS - 1))
+}
+
+// Drops board-wide counts for hours that ended before `before`. Returns
+// how many were removed.
+pub fn prune_post_hours(db: &Db, before: u64) -> usize {
+    counters::expire_buckets(&db.open_tree("post_hours").unwrap(), before / HOUR)
+}
@@ -0,0 +1,1564 @@
+// Admin login and admin-only endpoints.
+//
+// There is a single shared password (ADMIN_PASSWORD). Logging in asks for a
+// name as well, which is what ends up in the audit log.
+//
+// A login lasts ADMIN_SESSION_HOURS. Its cookie is SameSite=Strict and,
+// unless ADMIN_COOKIE_SECURE is off, sent only over HTTPS. Each session
+// also has a CSRF token, which every admin form puts in its action as
+// `csrf`, and anything an admin sends other than GET or HEAD is refused
+// without it, see Admin. A form on another site can't know the token.
+
+use actix_multipart::Multipart;
+use actix_web::cookie::time::Duration as CookieDuration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::Payload;
+use actix_web::http::Method;
+use actix_web::{error, web, Error, FromRequest, HttpRequest, HttpResponse};
+use askama::Template;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sled::Db;
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::io::Write;
+use std::time::SystemTime;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::api::ApiPost;
+use crate::archive;
+use crate::archive_db::ArchiveDb;
+use crate::audit;
+use crate::diff::{self, Span};
+use crate::diskspace::DiskGuard;
+use crate::edits::{self, Version};
+use crate::events::{BoardEvent, EventBus};
+use crate::exemptions::{self, Exemption, ExemptionCache};
+use crate::filename::{self, Batch};
+use crate::format;
+use crate::indexes::{self, Direction};
+use crate::intake::{Intake, IntakeError};
+use crate::moderation;
+use crate::notify;
+use crate::pending;
+use crate::redirect::{self, ReturnTo};
+use crate::render;
+use crate::runtime::{self, RuntimeCache};
+use crate::settings::{self, BoardSettings, SettingsCache};
+use crate::setup::{self, Step};
+use crate::storage;
+use crate::upload;
+use crate::upload_slots::UploadSlots;
+use crate::validation;
+use crate::watchlist;
+use crate::webhooks;
+use crate::widget;
+use crate::config::Config;
+use crate::{bad_page, load_post, now, parse_page, render_fragment, Post};
+
+const SESSION_COOKIE: &str = "admin_session";
+
+// Sessions from before CSRF tokens don't parse, so they have to log in again
+#[derive(Serialize, Deserialize)]
+struct AdminSession {
+    name: String,
+    created: u64,
+    csrf: String,
+}
+
+// Extractor for handlers that require a logged-in admin. For anything but
+// GET and HEAD the request also has to carry the session's CSRF token.
+pub struct Admin {
+    pub name: String,
+    pub csrf: String,
+}
+
+#[derive(Deserialize)]
+struct CsrfQuery {
+    csrf: Option<String>,
+}
+
+impl Admin {
+    // The admin whose session cookie came with `req`, if it hasn't
+    // expired, without checking for a CSRF token. For pages that show
+    // something more to admins.
+    pub fn signed_in(req: &HttpRequest) -> Option<Admin> {
+        let db = req.app_data::<web::Data<Db>>().unwrap();
+        let config = req.app_data::<web::Data<Config>>().unwrap();
+        let cookie = req.cookie(SESSION_COOKIE)?;
+        let sessions = db.open_tree("admin_sessions").unwrap();
+        let session: AdminSession = serde_json::from_slice(&sessions.get(cookie.value()).unwrap()?).ok()?;
+        if now().saturating_sub(session.created) >= config.admin_session_secs {
+            sessions.remove(cookie.value()).unwrap();
+            return None;
+        }
+        Some(Admin {
+            name: session.name,
+            csrf: session.csrf,
+        })
+    }
+
+    // "csrf=...", for the query string of a form's action
+    pub fn csrf_query(&self) -> String {
+        format!("csrf={}", self.csrf)
+    }
+
+    fn sent_token(&self, req: &HttpRequest) -> bool {
+        let sent = web::Query::<CsrfQuery>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.into_inner().csrf)
+            .unwrap_or_default();
+        bool::from(sent.as_bytes().ct_eq(self.csrf.as_bytes()))
+    }
+}
+
+impl FromRequest for Admin {
+    type Error = Error;
+    type Future = Ready<Result<Admin, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(match Admin::signed_in(req) {
+            Some(admin) if matches!(*req.method(), Method::GET | Method::HEAD) || admin.sent_token(req) => Ok(admin),
+            Some(_) => Err(error::ErrorForbidden("this form is out of date, reload the page and try again")),
+            None => Err(error::ErrorForbidden("admin login required")),
+        })
+    }
+}
+
+// Where post forms send to. An admin's carries the CSRF token, so what
+// they post as an admin (capcodes, announcements, thread locks) counts as
+// theirs; without it, a post is anyone's.
+pub fn submit_url(config: &Config, admin: Option<&Admin>) -> String {
+    let url = config.url_for("/submit");
+    match admin {
+        Some(admin) => format!("{}?{}", url, admin.csrf_query()),
+        None => url,
+    }
+}
+
+// Expired sessions of admins who never came back, for the maintenance job
+pub fn prune_sessions(db: &Db, config: &Config, now: u64) -> usize {
+    let sessions = db.open_tree("admin_sessions").unwrap();
+    let expired: Vec<_> = sessions
+        .iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|(_, bytes)| {
+            serde_json::from_slice::<AdminSession>(bytes)
+                .map_or(true, |session| now.saturating_sub(session.created) >= config.admin_session_secs)
+        })
+        .map(|(key, _)| key)
+        .collect();
+    for key in &expired {
+        sessions.remove(key).unwrap();
+    }
+    expired.len()
+}
+
+// Compared as digests, so the time taken says nothing about how much of
+// the password was right, nor how long it is
+fn password_matches(sent: &str, password: &str) -> bool {
+    bool::from(Sha256::digest(sent.as_bytes()).ct_eq(&Sha256::digest(password.as_bytes())))
+}
+
+#[derive(Template)]
+#[template(path = "admin_login.html")]
+struct LoginTemplate<'a> {
+    config: &'a Config,
+    failed: bool,
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    name: String,
+    password: String,
+}
+
+pub async fn login_form(config: web::Data<Config>) -> HttpResponse {
+    let template = LoginTemplate { config: &config, failed: false };
+    render::respond(HttpResponse::Ok(), &template, "the login form")
+}
+
+pub async fn login(db: web::Data<Db>, config: web::Data<Config>, form: web::Form<LoginForm>) -> HttpResponse {
+    let authorized = match &config.admin_password {
+        Some(password) => password_matches(&form.password, password) && !form.name.trim().is_empty(),
+        None => false,
+    };
+    if !authorized {
+        let template = LoginTemplate { config: &config, failed: true };
+        return render::respond(HttpResponse::Forbidden(), &template, "the login form");
+    }
+
+    let token = Uuid::new_v4().to_string();
+    let session = AdminSession {
+        name: form.name.trim().to_string(),
+        created: now(),
+        csrf: Uuid::new_v4().simple().to_string(),
+    };
+    db.open_tree("admin_sessions").unwrap().insert(&token, serde_json::to_vec(&session).unwrap()).unwrap();
+    audit::record(&db, &session.name, "login", "");
+
+    redirect::see_other(&config.index_url())
+        .cookie(
+            Cookie::build(SESSION_COOKIE, token)
+                .path(config.index_url())
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .secure(config.admin_cookie_secure)
+                .max_age(CookieDuration::seconds(config.admin_session_secs as i64))
+                .finish(),
+        )
+        .finish()
+}
+
+pub async fn logout(db: web::Data<Db>, config: web::Data<Config>, _admin: Admin, req: HttpRequest) -> HttpResponse {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        db.open_tree("admin_sessions").unwrap().remove(cookie.value()).unwrap();
+    }
+    let mut removal = Cookie::build(SESSION_COOKIE, "").path(config.index_url()).finish();
+    removal.make_removal();
+    redirect::see_other(&config.index_url()).cookie(removal).finish()
+}
+
+// Everything known about a post, for abuse complaints and takedowns.
+// Sections with nothing to report are left out of the JSON entirely.
+#[derive(Serialize)]
+struct Dossier<'a> {
+    generated_at: u64,
+    generated_by: &'a str,
+    post: &'a Post,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<FileMeta>,
+    // Every version of the message, see edits.rs
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    edits: Vec<Version>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    poster: Option<PosterRecord>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    bans: Vec<BanRecord>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    reports: Vec<Report>,
+}
+
+// The poster's ip_hash and what else was posted under it, oldest first
+#[derive(Serialize)]
+struct PosterRecord {
+    ip_hash: String,
+    other_posts: Vec<OtherPost>,
+}
+
+#[derive(Serialize)]
+struct OtherPost {
+    id: String,
+    timestamp: u64,
+}
+
+// Bans on the poster's ip_hash and blocks on the attachment's hash
+#[derive(Serialize)]
+struct BanRecord {
+    kind: &'static str,
+    hash: String,
+    since: u64,
+}
+
+// Why the post ended up in a moderation queue: a duplicate image group, or
+// a watch pattern by name
+#[derive(Serialize)]
+struct Report {
+    kind: &'static str,
+    reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flagged_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct FileMeta {
+    name: String,
+    size: u64,
+    sha256: String,
+}
+
+fn file_meta(config: &Config, file: &str) -> Option<FileMeta> {
+    let bytes = std::fs::read(config.upload_path(file)).ok()?;
+    let sha256 = Sha256::digest(&bytes).iter().map(|b| format!("{:02x}", b)).collect();
+    Some(FileMeta {
+        name: file.to_string(),
+        size: bytes.len() as u64,
+        sha256,
+    })
+}
+
+// There's no index by ip_hash, so finding the poster's other posts reads
+// every post, see storage::scan_all_parallel
+fn poster_record(db: &Db, config: &Config, post: &Post) -> Option<PosterRecord> {
+    let ip_hash = post.ip_hash.as_deref()?;
+    let others = std::sync::Mutex::new(Vec::new());
+    storage::scan_all_parallel(db, config.scan_threads, |other| {
+        if other.id != post.id && other.ip_hash.as_deref() == Some(ip_hash) {
+            others.lock().unwrap().push(OtherPost {
+                id: other.id,
+                timestamp: other.timestamp,
+            });
+        }
+    })
+    .log_unreadable("dossier");
+    let mut other_posts = others.into_inner().unwrap();
+    other_posts.sort_by(|a, b| (a.timestamp, &a.id).cmp(&(b.timestamp, &b.id)));
+    Some(PosterRecord {
+        ip_hash: ip_hash.to_string(),
+        other_posts,
+    })
+}
+
+fn ban_records(db: &Db, post: &Post) -> Vec<BanRecord> {
+    let poster = post.ip_hash.as_ref().map(|hash| ("poster", hash, moderation::banned_at(db, hash)));
+    let file = post.file_hash.as_ref().map(|hash| ("file", hash, moderation::blocked_at(db, hash)));
+    poster
+        .into_iter()
+        .chain(file)
+        .filter_map(|(kind, hash, since)| {
+            Some(BanRecord {
+                kind,
+                hash: hash.clone(),
+                since: since?,
+            })
+        })
+        .collect()
+}
+
+fn reports(db: &Db, post: &Post) -> Vec<Report> {
+    let duplicate = moderation::flagged_at(db, post).map(|flagged_at| Report {
+        kind: "duplicate_image",
+        reason: post.file_hash.clone().unwrap_or_default(),
+        flagged_at: Some(flagged_at),
+    });
+    let watched = watchlist::patterns_for(db, &post.id).into_iter().map(|name| Report {
+        kind: "watch_pattern",
+        reason: name,
+        flagged_at: None,
+    });
+    duplicate.into_iter().chain(watched).collect()
+}
+
+fn build_dossier(db: &Db, config: &Config, admin_name: &str, post: &Post) -> Vec<u8> {
+    let dossier = Dossier {
+        generated_at: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
+        generated_by: admin_name,
+        post,
+        file: post.file.as_deref().and_then(|file| file_meta(config, file)),
+        edits: edits::stored(db, &post.id),
+        poster: poster_record(db, config, post),
+        bans: ban_records(db, post),
+        reports: reports(db, post),
+    };
+    audit::record(db, admin_name, "dossier", &post.id);
+    serde_json::to_vec_pretty(&dossier).unwrap()
+}
+
+// Built off the async threads, as it reads every post
+async fn dossier_json(db: &Db, config: &Config, admin: &Admin, post: &Post) -> Result<Vec<u8>, Error> {
+    let (db, config, name, post) = (db.clone(), config.clone(), admin.name.clone(), post.clone());
+    Ok(web::block(move || build_dossier(&db, &config, &name, &post)).await?)
+}
+
+pub async fn dossier(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    admin: Admin,
+    post_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    match load_post(&db, &post_id) {
+        Some(post) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .body(dossier_json(&db, &config, &admin, &post).await?)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+// Same document zipped together with the media file itself, under the name
+// it was uploaded with.
+pub async fn dossier_zip(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    admin: Admin,
+    post_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let post = match load_post(&db, &post_id) {
+        Some(post) => post,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let json = dossier_json(&db, &config, &admin, &post).await?;
+    let mut names = Batch::default();
+    let json_name = names.suggest("dossier", "json").name;
+    let media = post.file.as_ref().and_then(|file| {
+        let bytes = std::fs::read(config.upload_path(file)).ok()?;
+        let uploaded = post.original_name.as_deref().unwrap_or_else(|| file.rsplit('/').next().unwrap_or_default());
+        let (stem, extension) = uploaded.rsplit_once('.').unwrap_or((uploaded, ""));
+        Some((names.suggest(stem, extension).name, bytes))
+    });
+
+    let archive = web::block(move || -> zip::result::ZipResult<Vec<u8>> {
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+        zip.start_file(json_name, options)?;
+        zip.write_all(&json)?;
+        if let Some((name, bytes)) = media {
+            zip.start_file(name, options)?;
+            zip.write_all(&bytes)?;
+        }
+        Ok(zip.finish()?.into_inner())
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+
+    let name = filename::suggest_filename(&format!("{} dossier", post.title), "zip");
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .append_header(("Content-Disposition", name.disposition()))
+        .body(archive))
+}
+
+#[derive(Template)]
+#[template(path = "admin_pending.html")]
+struct PendingTemplate<'a> {
+    config: &'a Config,
+    admin: &'a Admin,
+    posts: &'a [Post],
+    error: Option<&'a str>,
+}
+
+fn pending_page(db: &Db, config: &Config, admin: &Admin, error: Option<&str>) -> HttpResponse {
+    let posts = pending::list(db);
+    let template = PendingTemplate {
+        config,
+        admin,
+        posts: &posts,
+        error,
+    };
+    let response = if error.is_some() { HttpResponse::Conflict() } else { HttpResponse::Ok() };
+    render::respond(response, &template, "the approval queue")
+}
+
+pub async fn pending_queue(db: web::Data<Db>, config: web::Data<Config>, admin: Admin) -> HttpResponse {
+    pending_page(&db, &config, &admin, None)
+}
+
+pub async fn approve_pending(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    settings: web::Data<SettingsCache>,
+    events: web::Data<EventBus>,
+    admin: Admin,
+    post_id: web::Path<String>,
+) -> HttpResponse {
+    match pending::approve(&db, &config, &settings.get(&db), &post_id) {
+        Ok(Some(post)) => {
+            audit::record(&db, &admin.name, "approve", &post_id);
+            events.publish(BoardEvent::Created {
+                post: Box::new(post),
+                spam_checks: false,
+            });
+        }
+        Ok(None) => {}
+        Err(refused) => {
+            let error = format!("Not approved: {}", validation::refusal(refused).message);
+            return pending_page(&db, &config, &admin, Some(&error));
+        }
+    }
+    redirect::see_other(&config.url_for("/admin/pending")).finish()
+}
+
+pub async fn reject_pending(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    admin: Admin,
+    post_id: web::Path<String>,
+) -> HttpResponse {
+    if let Some(post) = pending::take(&db, &post_id) {
+        if let Some(file) = &post.file {
+            let _ = std::fs::remove_file(config.upload_path(file));
+        }
+        if post.parent_id.is_none() {
+            notify::forget(&db, &post.id);
+        }
+        audit::record(&db, &admin.name, "reject", &post_id);
+    }
+    redirect::see_other(&config.url_for("/admin/pending")).finish()
+}
+
+const ADMIN_POSTS_PER_PAGE: usize = 100;
+const EXCERPT_CHARS: usize = 200;
+
+// One row of the admin post list, with its thread's title looked up.
+struct PostRow {
+    post: Post,
+    sticky: bool,
+    archived: bool,
+    excerpt: String,
+    thread_title: Option<String>,
+    exempt: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_posts.html")]
+struct PostsTemplate<'a> {
+    config: &'a Config,
+    admin: &'a Admin,
+    rows: &'a [PostRow],
+    page: usize,
+    prev_page: Option<usize>,
+    next_page: Option<usize>,
+    // This page, for the row actions to come back to
+    return_to: String,
+    // Free space against the minimum while uploads are off
+    uploads_disabled: Option<String>,
+    // See UploadSlots::report
+    uploads: String,
+    // Until dismissed on a new board, see setup.rs
+    setup: Vec<Step>,
+    // In the order the index shows them, for moving up and down
+    stickies: Vec<Post>,
+}
+
+#[derive(Deserialize)]
+pub struct AdminPageQuery {
+    // Kept as text, see parse_page
+    page: Option<String>,
+}
+
+// Every post and reply, newest first.
+pub async fn posts(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    settings: web::Data<SettingsCache>,
+    exemptions: web::Data<ExemptionCache>,
+    admin: Admin,
+    req: HttpRequest,
+    query: web::Query<AdminPageQuery>,
+) -> HttpResponse {
+    let disk = req.app_data::<web::Data<DiskGuard>>().unwrap();
+    let upload_slots = req.app_data::<web::Data<UploadSlots>>().unwrap();
+    let page = match parse_page(query.page.as_deref()) {
+        Some(page) => page,
+        None => return bad_page(&config, config.url_for("/admin/posts")),
+    };
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    let (page_posts, more) = indexes::latest_posts(&db, page.saturating_mul(ADMIN_POSTS_PER_PAGE), ADMIN_POSTS_PER_PAGE);
+    // A page as far out as usize::MAX can't have more after it
+    let next_page = page.checked_add(1).filter(|_| more);
+
+    // One lookup per distinct thread on the page rather than per reply
+    let mut thread_titles: HashMap<String, Option<String>> = HashMap::new();
+    for post in &page_posts {
+        if let Some(parent_id) = &post.parent_id {
+            thread_titles
+                .entry(parent_id.clone())
+                .or_insert_with(|| load_post(&db, parent_id).map(|thread| thread.title));
+        }
+    }
+
+    let sticky_ids = indexes::sticky_ids(&db);
+    let rows: Vec<PostRow> = page_posts
+        .into_iter()
+        .map(|post| {
+            let thread_title = match &post.parent_id {
+                Some(parent_id) => thread_titles[parent_id].clone(),
+                None => Some(post.title.clone()),
+            };
+            PostRow {
+                sticky: sticky_ids.contains(&post.id),
+                archived: post.parent_id.is_none() && archive::is_archived(&db, &post.id),
+                excerpt: format::format_message_plain(&post.message, EXCERPT_CHARS),
+                thread_title,
+                exempt: post.ip_hash.as_deref().is_some_and(|ip_hash| exemptions.is_exempt(&db, ip_hash, now)),
+                post,
+            }
+        })
+        .collect();
+
+    let template = PostsTemplate {
+        config: &config,
+        admin: &admin,
+        rows: &rows,
+        page,
+        prev_page: if page > 0 { Some(page - 1) } else { None },
+        next_page,
+        return_to: config.url_for(&format!("/admin/posts?page={}", page)),
+        uploads_disabled: disk.low_report(),
+        uploads: upload_slots.report(),
+        setup: setup::checklist(&db, &config, &settings.get(&db)),
+        stickies: indexes::stickies(&db),
+    };
+    render::respond(HttpResponse::Ok(), &template, &format!("admin posts page {}", page))
+}
+
+#[derive(Template)]
+#[template(path = "admin_raw.html")]
+struct RawTemplate<'a> {
+    config: &'a Config,
+    id: &'a str,
+    raw: &'a str,
+}
+
+// The stored bytes of a post exactly as they are in sled, for debugging.
+pub async fn raw_record(db: web::Data<Db>, config: web::Data<Config>, _admin: Admin, post_id: web::Path<String>) -> HttpResponse {
+    let raw = match db.get(post_id.as_str()).unwrap() {
+        Some(bytes) => bytes,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let pretty = pretty_json(&String::from_utf8_lossy(&raw));
+    let template = RawTemplate {
+        config: &config,
+        id: &post_id,
+        raw: &pretty,
+    };
+    render::respond(HttpResponse::Ok(), &template, &format!("post {}", post_id))
+}
+
+// One place a post shows up, as the markup or text that place gets
+struct Rendering {
+    surface: &'static str,
+    source: String,
+    // Rendered live on the page as well; otherwise only the source is shown
+    live: bool,
+    // Whole pages go in a sandboxed frame instead
+    framed: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_renderings.html")]
+struct RenderingsTemplate<'a> {
+    config: &'a Config,
+    id: &'a str,
+    renderings: &'a [Rendering],
+}
+
+// Runs a post through every place it's rendered, so formatting abuse can
+// be judged by what readers actually get.
+fn renderings_of(config: &Config, post: &Post) -> Vec<Rendering> {
+    vec![
+        Rendering {
+            surface: "Index and thread",
+            source: render_fragment(config, post),
+            live: true,
+            framed: false,
+        },
+        Rendering {
+            surface: "Widget (full style)",
+            source: widget::render(config, std::slice::from_ref(post), false),
+            live: true,
+            framed: true,
+        },
+        Rendering {
+            surface: "Admin post list excerpt",
+            source: format::format_message_plain(&post.message, EXCERPT_CHARS),
+            live: false,
+            framed: false,
+        },
+        Rendering {
+            surface: "Webhook payload",
+            source: pretty_json(&webhooks::payload(config, post)),
+            live: false,
+            framed: false,
+        },
+        Rendering {
+            surface: "API",
+            source: serde_json::to_string_pretty(&ApiPost::new(config, post.clone())).unwrap(),
+            live: false,
+            framed: false,
+        },
+    ]
+}
+
+fn pretty_json(json: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(json)
+        .map(|value| serde_json::to_string_pretty(&value).unwrap())
+        .unwrap_or_else(|_| json.to_string())
+}
+
+pub async fn renderings(db: web::Data<Db>, config: web::Data<Config>, _admin: Admin, post_id: web::Path<String>) -> HttpResponse {
+    let post = match load_post(&db, &post_id) {
+        Some(post) => post,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let template = RenderingsTemplate {
+        config: &config,
+        id: &post_id,
+        renderings: &renderings_of(&config, &post),
+    };
+    render::respond(HttpResponse::Ok(), &template, &format!("post {}", post_id))
+}
+
+// One version on the history page, with how it differs from the one before
+struct HistoryRow {
+    version: Version,
+    // "3 h"
+    age: String,
+    // None for the first version, and when the messages are too long to
+    // diff, in which case `previous` is shown next to it instead
+    diff: Option<Vec<Span>>,
+    previous: Option<String>,
+    latest: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_history.html")]
+struct HistoryTemplate<'a> {
+    config: &'a Config,
+    admin: &'a Admin,
+    post: &'a Post,
+    thread_id: &'a str,
+    // Newest first
+    rows: &'a [HistoryRow],
+    error: Option<&'a str>,
+}
+
+fn history_rows(versions: Vec<Version>, now: u64) -> Vec<HistoryRow> {
+    let latest = versions.last().map(|version| version.version);
+    let mut rows = Vec::new();
+    let mut previous: Option<String> = None;
+    for version in versions {
+        let diff = previous.as_deref().and_then(|previous| diff::words(previous, &version.message));
+        let side_by_side = if diff.is_none() { previous.take() } else { None };
+        previous = Some(version.message.clone());
+        rows.push(HistoryRow {
+            age: format::human_duration(now.saturating_sub(version.timestamp)),
+            latest: Some(version.version) == latest,
+            diff,
+            previous: side_by_side,
+            version,
+        });
+    }
+    rows.reverse();
+    rows
+}
+
+fn history_page(db: &Db, config: &Config, admin: &Admin, post: &Post, error: Option<&str>) -> HttpResponse {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    let rows = history_rows(edits::versions(db, post), now);
+    let template = HistoryTemplate {
+        config,
+        admin,
+        post,
+        thread_id: post.parent_id.as_deref().unwrap_or(&post.id),
+        rows: &rows,
+        error,
+    };
+    let response = if error.is_some() { HttpResponse::BadRequest() } else { HttpResponse::Ok() };
+    render::respond(response, &template, &format!("the history of post {}", post.id))
+}
+
+// Every version of a post's message, with word-level changes between them
+pub async fn history(db: web::Data<Db>, config: web::Data<Config>, admin: Admin, post_id: web::Path<String>) -> HttpResponse {
+    match load_post(&db, &post_id) {
+        Some(post) => history_page(&db, &config, &admin, &post, None),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+fn back_to_history(config: &Config, post_id: &str) -> HttpResponse {
+    redirect::see_other(&config.url_for(&format!("/admin/post/{}/history", post_id))).finish()
+}
+
+#[derive(Deserialize)]
+pub struct EditForm {
+    message: String,
+}
+
+pub async fn edit_message(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    events: web::Data<EventBus>,
+    admin: Admin,
+    post_id: web::Path<String>,
+    form: web::Form<EditForm>,
+) -> HttpResponse {
+    let post = match load_post(&db, &post_id) {
+        Some(post) => post,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    if form.message.chars().count() > validation::MAX_MESSAGE_CHARS {
+        let error = format!("A message can be at most {} characters.", validation::MAX_MESSAGE_CHARS);
+        return history_page(&db, &config, &admin, &post, Some(&error));
+    }
+    if form.message != post.message {
+        edits::edit(&db, &post.id, &form.message, &admin.name, None);
+        audit::record(&db, &admin.name, "edit", &post.id);
+        events.publish(BoardEvent::Edited { post_id: post.id.clone() });
+    }
+    back_to_history(&config, &post.id)
+}
+
+// Makes an earlier version the live message again, as a new version
+pub async fn restore_version(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    events: web::Data<EventBus>,
+    admin: Admin,
+    path: web::Path<(String, u64)>,
+) -> HttpResponse {
+    let (post_id, number) = path.into_inner();
+    let post = match load_post(&db, &post_id) {
+        Some(post) => post,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let version = match edits::versions(&db, &post).into_iter().find(|version| version.version == number) {
+        Some(version) => version,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    if version.message != post.message {
+        edits::edit(&db, &post.id, &version.message, &admin.name, Some(number));
+        audit::record(&db, &admin.name, "restore", &format!("{} version {}", post.id, number));
+        events.publish(BoardEvent::Edited { post_id: post.id.clone() });
+    }
+    back_to_history(&config, &post.id)
+}
+
+#[derive(Template)]
+#[template(path = "admin_flagged.html")]
+struct FlaggedTemplate<'a> {
+    config: &'a Config,
+    admin: &'a Admin,
+    groups: &'a [(String, Vec<Post>)],
+}
+
+// Uploads seen on several posts in a short time, grouped by file hash.
+pub async fn flagged_images(db: web::Data<Db>, config: web::Data<Config>, admin: Admin) -> HttpResponse {
+    let groups: Vec<(String, Vec<Post>)> = moderation::flagged_groups(&db).into_iter().collect();
+    let template = FlaggedTemplate {
+        config: &config,
+        admin: &admin,
+        groups: &groups,
+    };
+    render::respond(HttpResponse::Ok(), &template, "flagged images")
+}
+
+#[derive(Template)]
+#[template(path = "admin_takedown.html")]
+struct TakedownTemplate<'a> {
+    config: &'a Config,
+    admin: &'a Admin,
+    // What the last takedown removed
+    result: Option<&'a str>,
+    error: Option<&'a str>,
+}
+
+fn takedown_page(config: &Config, admin: &Admin, result: Option<&str>, error: Option<&str>) -> HttpResponse {
+    let template = TakedownTemplate {
+        config,
+        admin,
+        result,
+        error,
+    };
+    let response = if error.is_some() { HttpResponse::BadRequest() } else { HttpResponse::Ok() };
+    render::respond(response, &template, "the takedown form")
+}
+
+pub async fn takedown_form(config: web::Data<Config>, admin: Admin) -> HttpResponse {
+    takedown_page(&config, &admin, None, None)
+}
+
+fn is_sha256_hex(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// Removes every copy of a file, given either the file itself (`file`) or
+// the hex sha256 of its contents (`hash`). A file wins if both are sent.
+pub async fn takedown(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    events: web::Data<EventBus>,
+    archive: web::Data<ArchiveDb>,
+    admin: Admin,
+    mut payload: Multipart,
+) -> HttpResponse {
+    let mut intake = Intake::new(&config);
+    let mut typed_hash = String::new();
+    let mut file_hash: Option<String> = None;
+    let read = async {
+        while let Some(mut field) = intake.next_field(&mut payload).await? {
+            let content_disposition = field.content_disposition();
+            let is_file = content_disposition.get_filename().map(|name| !name.is_empty()).unwrap_or(false);
+            match content_disposition.get_name().unwrap_or_default() {
+                "hash" => typed_hash = intake.read_text(&mut field).await?,
+                "file" if is_file => {
+                    let mut hasher = Sha256::new();
+                    while let Some(chunk) = intake.next_chunk(&mut field).await? {
+                        if let Ok(data) = chunk {
+                            hasher.update(&data);
+                        }
+                    }
+                    file_hash = Some(upload::hex(&hasher.finalize()));
+                }
+                _ => while intake.next_chunk(&mut field).await?.is_some() {},
+            }
+        }
+        Ok::<(), IntakeError>(())
+    };
+    if let Err(e) = read.await {
+        return takedown_page(&config, &admin, None, Some(e.message()));
+    }
+
+    let hash = match file_hash {
+        Some(hash) => hash,
+        None => typed_hash.trim().to_ascii_lowercase(),
+    };
+    if !is_sha256_hex(&hash) {
+        return takedown_page(&config, &admin, None, Some("Upload the file or enter its SHA-256 hash."));
+    }
+
+    let (changed, report) = storage::take_down(&db, &config, &hash);
+    audit::record(&db, &admin.name, "takedown", &hash);
+    for post_id in &changed {
+        audit::record(&db, &admin.name, "takedown_post", post_id);
+        events.publish(BoardEvent::Edited { post_id: post_id.clone() });
+    }
+    // Threads moved to the archive database have their copies there
+    let (archived, archived_report) = match archive.get() {
+        Some(archive) => storage::take_down(&archive, &config, &hash),
+        None => Default::default(),
+    };
+    for post_id in &archived {
+        audit::record(&db, &admin.name, "takedown_post", post_id);
+    }
+    let result = format!(
+        "Blocked {}. Removed the attachment from {} posts and deleted {} files.",
+        hash,
+        changed.len() + archived.len(),
+        report.files + archived_report.files
+    );
+    takedown_page(&config, &admin, Some(&result), None)
+}
+
+fn back_to_flagged(config: &Config) -> HttpResponse {
+    redirect::see_other(&config.url_for("/admin/flagged-images")).finish()
+}
+
+fn delete_group(db: &Db, config: &Config, events: &EventBus, admin: &Admin, posts: &[Post]) {
+    for post in posts {
+        let report = storage::delete_post(db, config, &post.id);
+        if report.posts > 0 {
+            audit::record(db, &admin.name, "delete", &post.id);
+            events.publish(BoardEvent::Deleted { posts: report.removed });
+        }
+    }
+}
+
+fn ban_posters(db: &Db, admin: &Admin, posts: &[Post]) {
+    for ip_hash in posts.iter().filter_map(|post| post.ip_hash.as_deref()) {
+        if !moderation::is_banned(db, ip_hash) {
+            moderation::ban(db, ip_hash);
+            audit::record(db, &admin.name, "ban", ip_hash);
+        }
+    }
+}
+
+pub async fn delete_flagged(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    events: web::Data<EventBus>,
+    admin: Admin,
+    hash: web::Path<String>,
+) -> HttpResponse {
+    let posts = moderation::flagged_group(&db, &hash);
+    delete_group(&db, &config, &events, &admin, &posts);
+    back_to_flagged(&config)
+}
+
+// Bans every poster in the group, then deletes the posts.
+pub async fn ban_flagged(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    events: web::Data<EventBus>,
+    admin: Admin,
+    hash: web::Path<String>,
+) -> HttpResponse {
+    let posts = moderation::flagged_group(&db, &hash);
+    ban_posters(&db, &admin, &posts);
+    delete_group(&db, &config, &events, &admin, &posts);
+    back_to_flagged(&config)
+}
+
+pub async fn allow_flagged(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    admin: Admin,
+    hash: web::Path<String>,
+) -> HttpResponse {
+    moderation::allow_hash(&db, &hash);
+    audit::record(&db, &admin.name, "allow_hash", &hash);
+    back_to_flagged(&config)
+}
+
+#[derive(Template)]
+#[template(path = "admin_watch.html")]
+struct WatchTemplate<'a> {
+    config: &'a Config,
+    admin: &'a Admin,
+    groups: &'a [(String, Vec<WatchedPost>)],
+}
+
+struct WatchedPost {
+    post: Post,
+    // HTML, with what the pattern matched marked
+    snippet: String,
+}
+
+fn watched_posts(posts: Vec<Post>, pattern: Option<&Regex>) -> Vec<WatchedPost> {
+    posts
+        .into_iter()
+        .map(|post| WatchedPost {
+            snippet: watchlist::snippet(&post.message, pattern, EXCERPT_CHARS),
+            post,
+        })
+        .collect()
+}
+
+// Posts matching the watch patterns, grouped by pattern, see watchlist.rs.
+pub async fn watch_matches(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    settings: web::Data<SettingsCache>,
+    admin: Admin,
+) -> HttpResponse {
+    let settings = settings.get(&db);
+    let groups: Vec<(String, Vec<WatchedPost>)> = watchlist::groups(&db)
+        .into_iter()
+        .map(|(name, posts)| {
+            let posts = watched_posts(posts, watchlist::pattern(&settings, &name).as_ref());
+            (name, posts)
+        })
+        .collect();
+    let template = WatchTemplate {
+        config: &config,
+        admin: &admin,
+        groups: &groups,
+    };
+    render::respond(HttpResponse::Ok(), &template, "watch pattern matches")
+}
+
+fn back_to_watch(config: &Config) -> HttpResponse {
+    redirect::see_other(&config.url_for("/admin/flagged")).finish()
+}
+
+pub async fn delete_watched(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    events: web::Data<EventBus>,
+    admin: Admin,
+    name: web::Path<String>,
+) -> HttpResponse {
+    let posts = watchlist::group(&db, &name);
+    delete_group(&db, &config, &events, &admin, &posts);
+    back_to_watch(&config)
+}
+
+pub async fn ban_watched(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    events: web::Data<EventBus>,
+    admin: Admin,
+    name: web::Path<String>,
+) -> HttpResponse {
+    let posts = watchlist::group(&db, &name);
+    ban_posters(&db, &admin, &posts);
+    delete_group(&db, &config, &events, &admin, &posts);
+    back_to_watch(&config)
+}
+
+// Leaves the posts up and clears the group
+pub async fn dismiss_watched(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    admin: Admin,
+    name: web::Path<String>,
+) -> HttpResponse {
+    watchlist::dismiss(&db, &name);
+    audit::record(&db, &admin.name, "dismiss_watch", &name);
+    back_to_watch(&config)
+}
+
+// Removes a thread and everything hanging off it, see storage::delete_thread.
+pub async fn delete_thread(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    events: web::Data<EventBus>,
+    admin: Admin,
+    post_id: web::Path<String>,
+    query: web::Query<ReturnTo>,
+) -> HttpResponse {
+    let report = storage::delete_thread(&db, &config, &post_id);
+    if report.posts == 0 {
+        return HttpResponse::NotFound().finish();
+    }
+    audit::record(&db, &admin.name, "delete_thread", &post_id);
+    events.publish(BoardEvent::Deleted { posts: report.removed });
+    redirect::back(&config, query.return_to.as_deref(), config.url_for("/admin/posts"))
+}
+
+fn set_sticky(db: &Db, config: &Config, admin: &Admin, thread_id: &str, sticky: bool, return_to: Option<&str>) -> HttpResponse {
+    // Archived threads stay off the board
+    match load_post(db, thread_id) {
+        Some(thread) if thread.parent_id.is_none() && !archive::is_archived(db, thread_id) => {}
+        _ => return HttpResponse::NotFound().finish(),
+    }
+    indexes::set_sticky(db, thread_id, sticky);
+    audit::record(db, &admin.name, if sticky { "sticky" } else { "unsticky" }, thread_id);
+    redirect::back(config, return_to, config.url_for("/admin/posts"))
+}
+
+#[derive(Deserialize)]
+pub struct StickyOrderQuery {
+    // "up" or "down"
+    dir: String,
+    return_to: Option<String>,
+}
+
+// Moves a sticky one place up or down the index, see indexes.rs
+pub async fn sticky_order(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    admin: Admin,
+    post_id: web::Path<String>,
+    query: web::Query<StickyOrderQuery>,
+) -> HttpResponse {
+    let direction = match query.dir.as_str() {
+        "up" => Direction::Up,
+        "down" => Direction::Down,
+        _ => return HttpResponse::BadRequest().body("dir has to be up or down"),
+    };
+    if !indexes::move_sticky(&db, &post_id, direction) {
+        return HttpResponse::NotFound().finish();
+    }
+    audit::record(&db, &admin.name, if direction == Direction::Up { "sticky_up" } else { "sticky_down" }, &post_id);
+    redirect::back(&config, query.return_to.as_deref(), config.url_for("/admin/posts"))
+}
+
+// Takes a thread off the board and closes it, see archive.rs. Archiving a
+// thread that already is just goes back.
+pub async fn archive_thread(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    admin: Admin,
+    post_id: web::Path<String>,
+    query: web::Query<ReturnTo>,
+) -> HttpResponse {
+    match load_post(&db, &post_id) {
+        Some(thread) if thread.parent_id.is_none() => {}
+        _ => return HttpResponse::NotFound().finish(),
+    }
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    if archive::archive(&db, &post_id, now) {
+        audit::record(&db, &admin.name, "archive", &post_id);
+    }
+    redirect::back(&config, query.return_to.as_deref(), config.url_for("/admin/posts"))
+}
+
+pub async fn sticky(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    admin: Admin,
+    post_id: web::Path<String>,
+    query: web::Query<ReturnTo>,
+) -> HttpResponse {
+    set_sticky(&db, &config, &admin, &post_id, true, query.return_to.as_deref())
+}
+
+pub async fn unsticky(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    admin: Admin,
+    post_id: web::Path<String>,
+    query: web::Query<ReturnTo>,
+) -> HttpResponse {
+    set_sticky(&db, &config, &admin, &post_id, false, query.return_to.as_deref())
+}
+
+// A day, which is already more than slow
+const MAX_SLOW_MODE_SECS: u32 = 24 * 60 * 60;
+
+#[derive(Deserialize)]
+pub struct SlowModeForm {
+    secs: String,
+}
+
+// Lets one reply into a thread every `secs` seconds, see
+// changes::insert_reply. Empty or 0 turns slow mode off.
+pub async fn slow_mode(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    events: web::Data<EventBus>,
+    admin: Admin,
+    post_id: web::Path<String>,
+    query: web::Query<ReturnTo>,
+    form: web::Form<SlowModeForm>,
+) -> HttpResponse {
+    let secs = form.secs.trim();
+    let secs = match secs.parse::<u32>() {
+        _ if secs.is_empty() => None,
+        Ok(0) => None,
+        Ok(secs) if secs <= MAX_SLOW_MODE_SECS => Some(secs),
+        _ => {
+            let message = format!("Slow mode is a whole number of seconds, at most {}.", MAX_SLOW_MODE_SECS);
+            return HttpResponse::BadRequest().body(message);
+        }
+    };
+    match load_post(&db, &post_id) {
+        Some(thread) if thread.parent_id.is_none() && !archive::is_archived(&db, &post_id) => {}
+        _ => return HttpResponse::NotFound().finish(),
+    }
+    if !crate::set_slow_mode(&db, &post_id, secs) {
+        return HttpResponse::NotFound().finish();
+    }
+    let target = format!("{} {}", post_id, secs.map_or("off".to_string(), |secs| format!("{}s", secs)));
+    audit::record(&db, &admin.name, "slow_mode", &target);
+    events.publish(BoardEvent::Edited { post_id: post_id.clone() });
+    redirect::back(&config, query.return_to.as_deref(), config.url_for("/admin/posts"))
+}
+
+#[derive(Template)]
+#[template(path = "admin_settings.html")]
+struct SettingsTemplate<'a> {
+    config: &'a Config,
+    admin: &'a Admin,
+    settings: &'a BoardSettings,
+    error: Option<&'a str>,
+    // What a config reload changed
+    reloaded: Option<&'a str>,
+}
+
+pub async fn settings_form(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    settings: web::Data<SettingsCache>,
+    admin: Admin,
+) -> HttpResponse {
+    let template = SettingsTemplate {
+        config: &config,
+        admin: &admin,
+        settings: &settings.get(&db),
+        error: None,
+        reloaded: None,
+    };
+    render::respond(HttpResponse::Ok(), &template, "board settings")
+}
+
+#[derive(Deserialize)]
+pub struct SettingsForm {
+    name: String,
+    description: String,
+    posts_per_page: usize,
+    bump_limit: usize,
+    require_file_for_threads: Option<String>,
+    wordfilters: String,
+    watch_patterns: String,
+    locked: Option<String>,
+    nsfw: Option<String>,
+}
+
+pub async fn save_settings(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    cache: web::Data<SettingsCache>,
+    admin: Admin,
+    form: web::Form<SettingsForm>,
+) -> HttpResponse {
+    let form = form.into_inner();
+    let parsed = settings::parse_wordfilters(&form.wordfilters).and_then(|wordfilters| {
+        Ok(BoardSettings {
+            name: form.name.trim().to_string(),
+            description: form.description.trim().to_string(),
+            posts_per_page: form.posts_per_page,
+            bump_limit: form.bump_limit,
+            require_file_for_threads: form.require_file_for_threads.is_some(),
+            wordfilters,
+            watch_patterns: watchlist::parse_patterns(&form.watch_patterns)?,
+            locked: form.locked.is_some(),
+            nsfw: form.nsfw.is_some(),
+        })
+    });
+    let result = parsed.and_then(|settings| settings::validate(&settings).map(|_| settings));
+
+    match result {
+        Ok(settings) => {
+            let summary = serde_json::to_string(&settings).unwrap();
+            cache.save(&db, settings);
+            audit::record(&db, &admin.name, "settings", &summary);
+            redirect::see_other(&config.url_for("/admin/settings")).finish()
+        }
+        Err(error) => {
+            let template = SettingsTemplate {
+                config: &config,
+                admin: &admin,
+                settings: &cache.get(&db),
+                error: Some(&error),
+                reloaded: None,
+            };
+            render::respond(HttpResponse::BadRequest(), &template, "board settings")
+        }
+    }
+}
+
+// Reads CONFIG_FILE again, see runtime.rs. A file with mistakes changes
+// nothing and the page says what they are.
+pub async fn reload_config(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    cache: web::Data<SettingsCache>,
+    runtime: web::Data<RuntimeCache>,
+    admin: Admin,
+) -> HttpResponse {
+    let result = runtime.reload().map(|changed| runtime::describe(&changed));
+    if let Ok(summary) = &result {
+        audit::record(&db, &admin.name, "reload_config", summary);
+    }
+    let template = SettingsTemplate {
+        config: &config,
+        admin: &admin,
+        settings: &cache.get(&db),
+        error: result.as_ref().err().map(String::as_str),
+        reloaded: result.as_ref().ok().map(String::as_str),
+    };
+    let status = if result.is_ok() { HttpResponse::Ok() } else { HttpResponse::BadRequest() };
+    render::respond(status, &template, "board settings")
+}
+
+// One exemption as the list shows it
+struct ExemptionRow {
+    ip_hash: String,
+    exemption: Exemption,
+    // "3 d"
+    added: String,
+    // "expires in 5 d", "never expires" or "expired"
+    expires: String,
+}
+
+impl ExemptionRow {
+    fn new(ip_hash: String, exemption: Exemption, now: u64) -> ExemptionRow {
+        let expires = match exemption.expires_at {
+            None => "never expires".to_string(),
+            Some(expires_at) if expires_at > now => format!("expires in {}", format::human_duration(expires_at - now)),
+            Some(_) => "expired".to_string(),
+        };
+        ExemptionRow {
+            added: format::human_duration(now.saturating_sub(exemption.added_at)),
+            ip_hash,
+            exemption,
+            expires,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin_exemptions.html")]
+struct ExemptionsTemplate<'a> {
+    config: &'a Config,
+    admin: &'a Admin,
+    rows: &'a [ExemptionRow],
+    error: Option<&'a str>,
+}
+
+fn exemptions_page(db: &Db, config: &Config, admin: &Admin, error: Option<&str>) -> HttpResponse {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    let mut rows: Vec<ExemptionRow> = exemptions::list(db)
+        .into_iter()
+        .map(|(ip_hash, exemption)| ExemptionRow::new(ip_hash, exemption, now))
+        .collect();
+    rows.sort_by_key(|row| std::cmp::Reverse(row.exemption.added_at));
+    let template = ExemptionsTemplate {
+        config,
+        admin,
+        rows: &rows,
+        error,
+    };
+    let response = if error.is_some() { HttpResponse::BadRequest() } else { HttpResponse::Ok() };
+    render::respond(response, &template, "exemptions")
+}
+
+pub async fn exemption_list(db: web::Data<Db>, config: web::Data<Config>, admin: Admin) -> HttpResponse {
+    exemptions_page(&db, &config, &admin, None)
+}
+
+#[derive(Deserialize)]
+pub struct ExemptionForm {
+    ip_hash: String,
+    #[serde(default)]
+    note: String,
+    // Empty for an exemption that doesn't expire
+    #[serde(default)]
+    days: String,
+}
+
+// Shaped like poster::ip_hash: 16 hex digits
+fn is_ip_hash(value: &str) -> bool {
+    value.len() == 16 && value.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+pub async fn add_exemption(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    cache: web::Data<ExemptionCache>,
+    admin: Admin,
+    query: web::Query<ReturnTo>,
+    form: web::Form<ExemptionForm>,
+) -> HttpResponse {
+    let ip_hash = form.ip_hash.trim().to_ascii_lowercase();
+    if !is_ip_hash(&ip_hash) {
+        return exemptions_page(&db, &config, &admin, Some("An ip hash is 16 hex digits."));
+    }
+    let days = form.days.trim();
+    let days = match days.parse::<u64>() {
+        _ if days.is_empty() => None,
+        Ok(days) if days > 0 => Some(days),
+        _ => return exemptions_page(&db, &config, &admin, Some("The expiry has to be a whole number of days.")),
+    };
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    let exemption = Exemption {
+        note: format::truncate_chars(form.note.trim(), 200),
+        expires_at: days.map(|days| now.saturating_add(days.saturating_mul(24 * 60 * 60))),
+        added_by: admin.name.clone(),
+        added_at: now,
+    };
+    cache.add(&db, &ip_hash, &exemption);
+    audit::record(&db, &admin.name, "exempt", &ip_hash);
+    redirect::back(&config, query.return_to.as_deref(), config.url_for("/admin/exemptions"))
+}
+
+pub async fn remove_exemption(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    cache: web::Data<ExemptionCache>,
+    admin: Admin,
+    ip_hash: web::Path<String>,
+) -> HttpResponse {
+    if cache.remove(&db, &ip_hash) {
+        audit::record(&db, &admin.name, "unexempt", &ip_hash);
+    }
+    redirect::see_other(&config.url_for("/admin/exemptions")).finish()
+}
+
+pub fn check_templates(config: &Config, thread: &Post, reply: &Post) -> Result<(), String> {
+    let admin = Admin { name: "admin".to_string(), csrf: String::new() };
+    let posts = [thread.clone(), reply.clone()];
+    render::check(&LoginTemplate { config, failed: true })?;
+    render::check(&PendingTemplate {
+        config,
+        admin: &admin,
+        posts: &posts,
+        error: Some("Not approved: This thread is closed."),
+    })?;
+    let rows: Vec<PostRow> = posts
+        .iter()
+        .map(|post| PostRow {
+            post: post.clone(),
+            sticky: post.parent_id.is_none(),
+            archived: post.parent_id.is_none(),
+            excerpt: format::format_message_plain(&post.message, EXCERPT_CHARS),
+            thread_title: Some(thread.title.clone()),
+            exempt: post.parent_id.is_some(),
+        })
+        .collect();
+    render::check(&PostsTemplate {
+        config,
+        admin: &admin,
+        rows: &rows,
+        page: 1,
+        prev_page: Some(0),
+        next_page: Some(2),
+        return_to: config.index_url(),
+        uploads_disabled: Some("312.4 MiB free, under the 512 MiB minimum".to_string()),
+        uploads: "3 of 8 upload slots in use".to_string(),
+        setup: vec![
+            Step {
+                title: "Set an admin password",
+                detail: "Detail.".to_string(),
+                done: true,
+                link: None,
+            },
+            Step {
+                title: "Write the rules",
+                detail: "Detail.".to_string(),
+                done: false,
+                link: Some(config.url_for("/admin/settings")),
+            },
+        ],
+        stickies: vec![thread.clone(), thread.clone()],
+    })?;
+    render::check(&RawTemplate {
+        config,
+        id: &thread.id,
+        raw: "{}",
+    })?;
+    render::check(&RenderingsTemplate {
+        config,
+        id: &thread.id,
+        renderings: &renderings_of(config, thread),
+    })?;
+    render::check(&FlaggedTemplate {
+        config,
+        admin: &admin,
+        groups: &[(thread.file_hash.clone().unwrap_or_default(), posts.to_vec())],
+    })?;
+    render::check(&WatchTemplate {
+        config,
+        admin: &admin,
+        groups: &[("phone-numbers".to_string(), watched_posts(posts.to_vec(), Regex::new("quoted").ok().as_ref()))],
+    })?;
+    render::check(&TakedownTemplate {
+        config,
+        admin: &admin,
+        result: Some("Removed 1 file."),
+        error: Some("No such file."),
+    })?;
+    render::check(&SettingsTemplate {
+        config,
+        admin: &admin,
+        settings: &BoardSettings::default(),
+        error: Some("Posts per page has to be a number."),
+        reloaded: Some("POSTS_PER_HOUR changed"),
+    })?;
+    let exemption = Exemption {
+        note: "School library".to_string(),
+        expires_at: Some(2),
+        added_by: admin.name.clone(),
+        added_at: 0,
+    };
+    let versions = vec![
+        Version {
+            version: 0,
+            message: "as posted".to_string(),
+            timestamp: 0,
+            edited_by: None,
+            restored_from: None,
+        },
+        Version {
+            version: 1,
+            message: "as edited".to_string(),
+            timestamp: 1,
+            edited_by: Some(admin.name.clone()),
+            restored_from: None,
+        },
+        Version {
+            version: 2,
+            message: "x ".repeat(2000),
+            timestamp: 2,
+            edited_by: Some(admin.name.clone()),
+            restored_from: Some(0),
+        },
+    ];
+    render::check(&HistoryTemplate {
+        config,
+        admin: &admin,
+        post: reply,
+        thread_id: &thread.id,
+        rows: &history_rows(versions, 3),
+        error: Some("A message can be at most 100000 characters."),
+    })?;
+    render::check(&ExemptionsTemplate {
+        config,
+        admin: &admin,
+        rows: &[ExemptionRow::new("0123456789abcdef".to_string(), exemption, 1)],
+        error: Some("An ip hash is 16 hex digits."),
+    })
+}
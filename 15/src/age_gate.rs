@@ -0,0 +1,188 @@
+// Age confirmation for boards marked adult in their settings. Until a
+// visitor says they are 18 or over, every page, fragment and upload answers
+// with the interstitial instead, so deep links to media don't get around
+// it. Saying yes sets the `age_ok` cookie for a year; it holds the time it
+// was issued and an HMAC of that under a key kept in the database, so it
+// can't be made up by hand. Saying no leaves for NSFW_LEAVE_URL.
+//
+// The JSON API and the widget are read by scripts and other sites, which
+// have no cookie: they take NSFW_API_TOKEN as a `token` query parameter
+// instead, and are off for them when it isn't set.
+
+use actix_web::cookie::time::Duration as CookieDuration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, CacheControl, CacheDirective};
+use actix_web::{web, Error, FromRequest, HttpRequest, HttpResponse};
+use askama::Template;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sled::Db;
+use std::future::{ready, Ready};
+use std::time::SystemTime;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::redirect;
+use crate::render;
+use crate::settings::SettingsCache;
+use crate::upload;
+
+const COOKIE: &str = "age_ok";
+const COOKIE_DAYS: i64 = 365;
+
+// Made once per database, like the ip salt
+fn key(db: &Db) -> Vec<u8> {
+    let meta = db.open_tree("meta").unwrap();
+    let fresh = Uuid::new_v4().to_string();
+    let _ = meta.compare_and_swap("age_cookie_key", None as Option<&[u8]>, Some(fresh.as_bytes())).unwrap();
+    meta.get("age_cookie_key").unwrap().unwrap().to_vec()
+}
+
+fn signature(db: &Db, issued: u64) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key(db)).unwrap();
+    mac.update(format!("{}:{}", COOKIE, issued).as_bytes());
+    upload::hex(&mac.finalize().into_bytes())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
+
+// "{issued}.{signature}", good for a year from `issued`
+fn has_valid_cookie(db: &Db, req: &HttpRequest) -> bool {
+    let cookie = match req.cookie(COOKIE) {
+        Some(cookie) => cookie,
+        None => return false,
+    };
+    let (issued, given) = match cookie.value().split_once('.') {
+        Some((issued, given)) => (issued, given),
+        None => return false,
+    };
+    let issued = match issued.parse::<u64>() {
+        Ok(issued) => issued,
+        Err(_) => return false,
+    };
+    let fresh = now().saturating_sub(issued) < COOKIE_DAYS as u64 * 24 * 60 * 60;
+    fresh && given == signature(db, issued)
+}
+
+fn is_nsfw(req: &HttpRequest) -> bool {
+    let db = req.app_data::<web::Data<Db>>().unwrap();
+    req.app_data::<web::Data<SettingsCache>>().unwrap().get(db).nsfw
+}
+
+#[derive(Template)]
+#[template(path = "age_gate.html")]
+struct AgeGateTemplate<'a> {
+    config: &'a Config,
+    board_name: &'a str,
+    // Where to go once confirmed, see redirect.rs
+    return_to: String,
+}
+
+// Extractor for anything that shows the board's content. On an adult board
+// without the cookie, the request is answered with the interstitial.
+pub struct AgeOk;
+
+impl FromRequest for AgeOk {
+    type Error = Error;
+    type Future = Ready<Result<AgeOk, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let db = req.app_data::<web::Data<Db>>().unwrap();
+        if !is_nsfw(req) || has_valid_cookie(db, req) {
+            return ready(Ok(AgeOk));
+        }
+        let config = req.app_data::<web::Data<Config>>().unwrap();
+        let settings = req.app_data::<web::Data<SettingsCache>>().unwrap().get(db);
+        let template = AgeGateTemplate {
+            config,
+            board_name: &settings.name,
+            return_to: req.uri().path_and_query().map(|path| path.as_str()).unwrap_or_default().to_string(),
+        };
+        let mut response = HttpResponse::Forbidden();
+        response.insert_header(CacheControl(vec![CacheDirective::Private, CacheDirective::NoStore]));
+        let response = render::respond(response, &template, "the age check");
+        ready(Err(InternalError::from_response("age check required", response).into()))
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+// Extractor for the JSON API and the widget. On an adult board they need
+// the age cookie or NSFW_API_TOKEN, and are a 404 otherwise.
+pub struct ApiAccess;
+
+impl FromRequest for ApiAccess {
+    type Error = Error;
+    type Future = Ready<Result<ApiAccess, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let db = req.app_data::<web::Data<Db>>().unwrap();
+        if !is_nsfw(req) || has_valid_cookie(db, req) {
+            return ready(Ok(ApiAccess));
+        }
+        let config = req.app_data::<web::Data<Config>>().unwrap();
+        let given = web::Query::<TokenQuery>::from_query(req.query_string()).ok().and_then(|query| query.0.token);
+        let allowed = match (&config.nsfw_api_token, given) {
+            // As digests, like admin passwords, so the time taken says
+            // nothing about the token or its length
+            (Some(expected), Some(given)) => bool::from(Sha256::digest(given.as_bytes()).ct_eq(&Sha256::digest(expected.as_bytes()))),
+            _ => false,
+        };
+        ready(if allowed {
+            Ok(ApiAccess)
+        } else {
+            Err(actix_web::error::ErrorNotFound("not found"))
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AgeForm {
+    return_to: Option<String>,
+}
+
+// "I am 18 or older"
+pub async fn confirm(db: web::Data<Db>, config: web::Data<Config>, form: web::Form<AgeForm>) -> HttpResponse {
+    let issued = now();
+    let value = format!("{}.{}", issued, signature(&db, issued));
+    let location = form
+        .return_to
+        .as_deref()
+        .and_then(|raw| redirect::return_target(&config, raw).or_else(|| upload_target(&config, raw)))
+        .unwrap_or_else(|| config.index_url());
+    redirect::see_other(&location)
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .cookie(
+            Cookie::build(COOKIE, value)
+                .path(config.index_url())
+                .http_only(true)
+                .same_site(SameSite::Lax)
+                .max_age(CookieDuration::days(COOKIE_DAYS))
+                .finish(),
+        )
+        .finish()
+}
+
+// The gate also stands in front of uploads, so a deep link to a file goes
+// back to that file. Only the bare path of a stored file counts.
+fn upload_target(config: &Config, raw: &str) -> Option<String> {
+    let file = raw.strip_prefix(&config.upload_url(""))?;
+    upload::is_stored_path(file).then(|| raw.to_string())
+}
+
+pub fn check_templates(config: &Config) -> Result<(), String> {
+    render::check(&AgeGateTemplate {
+        config,
+        board_name: "Main Board",
+        return_to: config.index_url(),
+    })
+}
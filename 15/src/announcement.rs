@@ -0,0 +1,79 @@
+// Announcements: threads posted as the board itself. An admin writes one on
+// /admin/announce, which sends it to /submit like any new thread with an
+// `announcement` field on top; that field is only honoured from a logged-in
+// admin. The thread goes through the same validation and storage as any
+// other, stored with the capcode, `announcement` set and, unless the box
+// was unticked, replies closed from the start. It's written stickied and
+// linked from the banner at the top of the index in the same transaction,
+// so no one sees it without either.
+//
+// There's one banner, for the latest announcement. Deleting or archiving
+// that thread takes the banner down; stickies stay as they are otherwise.
+
+use actix_web::{web, HttpResponse};
+use askama::Template;
+use sled::transaction::ConflictableTransactionError;
+use sled::{Db, Transactional};
+
+use crate::admin::Admin;
+use crate::config::Config;
+use crate::{indexes, load_post, render, replay, Post};
+
+const BANNER: &str = "announcement";
+
+// Writes a new announcement thread to the main tree along with its sticky
+// entry and the banner. Its other index entries follow as for any thread.
+pub fn insert(db: &Db, thread_id: &str, raw: &[u8]) {
+    let (_lock, order) = indexes::next_sticky(db);
+    let stickies = db.open_tree("stickies").unwrap();
+    let meta = db.open_tree("meta").unwrap();
+    let main: &sled::Tree = db;
+    (main, &stickies, &meta)
+        .transaction(|(main, stickies, meta)| {
+            main.insert(thread_id.as_bytes(), raw)?;
+            stickies.insert(thread_id.as_bytes(), &order.to_be_bytes())?;
+            meta.insert(BANNER, thread_id.as_bytes())?;
+            Ok::<_, ConflictableTransactionError>(())
+        })
+        .unwrap();
+}
+
+// The thread the banner links to
+pub fn current(db: &Db) -> Option<Post> {
+    let id = db.open_tree("meta").unwrap().get(BANNER).unwrap()?;
+    load_post(db, &String::from_utf8_lossy(&id))
+}
+
+// Takes the banner down if it links to `thread_id`
+pub fn forget(db: &Db, thread_id: &str) {
+    let _ = db
+        .open_tree("meta")
+        .unwrap()
+        .compare_and_swap(BANNER, Some(thread_id.as_bytes()), None as Option<&[u8]>)
+        .unwrap();
+}
+
+#[derive(Template)]
+#[template(path = "admin_announce.html")]
+struct AnnounceTemplate<'a> {
+    config: &'a Config,
+    admin: &'a Admin,
+    submit_token: String,
+}
+
+pub async fn compose(config: web::Data<Config>, admin: Admin) -> HttpResponse {
+    let template = AnnounceTemplate {
+        config: &config,
+        admin: &admin,
+        submit_token: replay::new_token(),
+    };
+    render::respond(HttpResponse::Ok(), &template, "the announcement form")
+}
+
+pub fn check_templates(config: &Config) -> Result<(), String> {
+    render::check(&AnnounceTemplate {
+        config,
+        admin: &Admin { name: "admin".to_string(), csrf: String::new() },
+        submit_token: replay::new_token(),
+    })
+}
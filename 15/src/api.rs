@@ -0,0 +1,348 @@
+// Read-only JSON API. Listings are paged with opaque cursors instead of
+// page numbers: a cursor is the index key of the last item returned, so
+// bumps and new posts between requests can't shift what comes next.
+
+use actix_web::http::header;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use crate::age_gate::ApiAccess;
+use crate::changes::{self, ChangeKind};
+use crate::config::Config;
+use crate::indexes;
+use crate::posters;
+use crate::sorting::{self, Rankings, ThreadSort};
+use crate::upload::{self, MediaKind};
+use crate::{load_post, Post};
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 200;
+// Most posts one /api/posts request may ask for
+const MAX_BATCH: usize = 50;
+// Batches back hover previews, which can stand being a little stale
+const BATCH_CACHE_CONTROL: &str = "public, max-age=10";
+
+// The public part of a post; ip and file hashes stay out of the API.
+#[derive(Serialize)]
+pub struct ApiPost {
+    id: String,
+    parent_id: Option<String>,
+    title: String,
+    name: Option<String>,
+    tripcode: Option<String>,
+    capcode: Option<String>,
+    message: String,
+    file_url: Option<String>,
+    // Set once the file was replaced by a thumbnail, see media_prune.rs
+    thumbnail_url: Option<String>,
+    media_pruned: bool,
+    // Posted as the board, see announcement.rs
+    announcement: bool,
+    original_name: Option<String>,
+    file_size: Option<u64>,
+    media_type: Option<MediaKind>,
+    reply_number: Option<u64>,
+    locks_at: Option<u64>,
+    timestamp: u64,
+}
+
+impl ApiPost {
+    pub fn new(config: &Config, post: Post) -> ApiPost {
+        ApiPost {
+            media_type: post.media_kind(),
+            reply_number: post.reply_number,
+            locks_at: post.locks_at,
+            file_url: post.file.as_deref().map(|file| config.upload_url(file)),
+            thumbnail_url: post.thumbnail.as_deref().map(|thumbnail| config.upload_url(thumbnail)),
+            media_pruned: post.media_pruned,
+            announcement: post.announcement,
+            id: post.id,
+            parent_id: post.parent_id,
+            title: post.title,
+            name: post.name,
+            tripcode: post.tripcode,
+            capcode: post.capcode,
+            message: post.message,
+            original_name: post.original_name,
+            file_size: post.file_size,
+            timestamp: post.timestamp,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ThreadsPage {
+    threads: Vec<ApiPost>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RepliesPage {
+    post: ApiPost,
+    // Different people who posted in the thread, at most posters::CAP
+    // (which means that many or more). Only for threads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    posters: Option<u64>,
+    replies: Vec<ApiPost>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    cursor: Option<String>,
+    // Only threads bumped after this time, in seconds since the epoch
+    since: Option<u64>,
+    // As on the index; a cursor only works with the order it came from
+    sort: Option<String>,
+    limit: Option<usize>,
+    // Same as on the index: clamped to the configured range, and junk is
+    // ignored. Takes precedence over `limit`.
+    per_page: Option<String>,
+}
+
+impl ListQuery {
+    fn limit(&self, config: &Config) -> usize {
+        config
+            .clamp_per_page(self.per_page.as_deref())
+            .unwrap_or_else(|| self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT))
+    }
+}
+
+fn encode_cursor(key: &[u8]) -> String {
+    upload::hex(key)
+}
+
+// Cursors are only ever hex of a key we produced; anything else is None.
+fn decode_cursor(cursor: &str) -> Option<String> {
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(cursor.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+fn bad_cursor() -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({ "error": "invalid cursor" }))
+}
+
+// Threads, most recently bumped first unless `sort` says otherwise.
+pub async fn threads(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    _access: ApiAccess,
+    rankings: web::Data<Rankings>,
+    query: web::Query<ListQuery>,
+) -> HttpResponse {
+    let sort = ThreadSort::parse(query.sort.as_deref());
+    let after = match query.cursor.as_deref().map(decode_cursor) {
+        Some(Some(key)) if sort.is_key(&key) => Some(key),
+        Some(_) => return bad_cursor(),
+        None => None,
+    };
+
+    let limit = query.limit(&config);
+    let mut threads = Vec::new();
+    let mut next_cursor = None;
+    for (key, thread) in sorting::threads(&db, &rankings, sort, after.as_deref()) {
+        if query.since.map(|since| thread.timestamp <= since).unwrap_or(false) {
+            // Nothing further down the bump order is newer
+            if sort == ThreadSort::Bump {
+                break;
+            }
+            continue;
+        }
+        threads.push(ApiPost::new(&config, thread));
+        if threads.len() == limit {
+            next_cursor = Some(encode_cursor(key.as_bytes()));
+            break;
+        }
+    }
+
+    HttpResponse::Ok().json(ThreadsPage { threads, next_cursor })
+}
+
+// A post and its replies in the order they were numbered, which is the
+// order they were posted, even within one second.
+pub async fn post(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    _access: ApiAccess,
+    post_id: web::Path<String>,
+    query: web::Query<ListQuery>,
+) -> HttpResponse {
+    let post = match load_post(&db, &post_id) {
+        Some(post) => post,
+        None => return HttpResponse::NotFound().json(serde_json::json!({ "error": "not found" })),
+    };
+    let prefix = format!("{}/", post.id);
+    let is_number_key = |key: &str| {
+        key.strip_prefix(&prefix).is_some_and(|number| number.len() == 20 && number.bytes().all(|b| b.is_ascii_digit()))
+    };
+    let start = match query.cursor.as_deref().map(decode_cursor) {
+        Some(Some(key)) if is_number_key(&key) => Bound::Excluded(key),
+        Some(_) => return bad_cursor(),
+        None => Bound::Included(prefix.clone()),
+    };
+    // '0' is the byte after '/', so this ends right after the thread's keys
+    let end = Bound::Excluded(format!("{}0", post.id));
+
+    let limit = query.limit(&config);
+    let mut replies = Vec::new();
+    let mut next_cursor = None;
+    for entry in db.open_tree("numbers").unwrap().range::<String, _>((start, end)) {
+        let (key, reply_id) = entry.unwrap();
+        if let Some(reply) = load_post(&db, std::str::from_utf8(&reply_id).unwrap_or_default()) {
+            replies.push(ApiPost::new(&config, reply));
+        }
+        if replies.len() == limit {
+            next_cursor = Some(encode_cursor(&key));
+            break;
+        }
+    }
+
+    HttpResponse::Ok().json(RepliesPage {
+        posters: post.parent_id.is_none().then(|| posters::count(&db, &post.id)),
+        post: ApiPost::new(&config, post),
+        replies,
+        next_cursor,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct BatchQuery {
+    // Comma-separated post ids
+    ids: Option<String>,
+    // Comma-separated reply numbers in `thread`
+    numbers: Option<String>,
+    thread: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Batch {
+    // Keyed by id, or by number for `numbers`. Posts that don't exist
+    // (never did, or were deleted) are left out.
+    posts: BTreeMap<String, ApiPost>,
+}
+
+fn bad_batch(message: String) -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({ "error": message }))
+}
+
+fn split_list(raw: &str) -> Vec<&str> {
+    raw.split(',').map(str::trim).filter(|item| !item.is_empty()).collect()
+}
+
+// Several posts in one request, for quote-link previews. Either `ids`, or
+// `thread` with `numbers`. Every post is one lookup by key, no scans.
+pub async fn posts(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    _access: ApiAccess,
+    query: web::Query<BatchQuery>,
+) -> HttpResponse {
+    // Number to reply id, or id to itself
+    let wanted: Vec<(String, Option<String>)> = match (&query.ids, &query.numbers, &query.thread) {
+        (Some(ids), None, None) => split_list(ids).into_iter().map(|id| (id.to_string(), Some(id.to_string()))).collect(),
+        (None, Some(numbers), Some(thread_id)) => {
+            let numbers = split_list(numbers);
+            if numbers.len() > MAX_BATCH {
+                return bad_batch(format!("at most {} posts per request", MAX_BATCH));
+            }
+            let mut wanted = Vec::new();
+            for number in numbers {
+                match number.parse::<u64>() {
+                    Ok(parsed) => wanted.push((number.to_string(), indexes::numbered_reply(&db, thread_id, parsed))),
+                    Err(_) => return bad_batch(format!("invalid reply number: {}", number)),
+                }
+            }
+            wanted
+        }
+        (None, Some(_), None) => return bad_batch("numbers needs a thread".to_string()),
+        _ => return bad_batch("give either ids, or thread and numbers".to_string()),
+    };
+    if wanted.len() > MAX_BATCH {
+        return bad_batch(format!("at most {} posts per request", MAX_BATCH));
+    }
+
+    let mut posts = BTreeMap::new();
+    for (key, post_id) in wanted {
+        if let Some(post) = post_id.and_then(|post_id| load_post(&db, &post_id)) {
+            posts.insert(key, ApiPost::new(&config, post));
+        }
+    }
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, BATCH_CACHE_CONTROL))
+        .json(Batch { posts })
+}
+
+#[derive(Deserialize)]
+pub struct ChangesQuery {
+    since_version: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ChangeEntry {
+    version: u64,
+    kind: ChangeKind,
+    id: String,
+    // Only for added or edited posts still present
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post: Option<ApiPost>,
+}
+
+#[derive(Serialize)]
+struct ThreadChanges {
+    version: u64,
+    // Set when `since_version` is older than the kept history; fetch
+    // /api/post/{id} instead and continue from `version`
+    full_fetch_required: bool,
+    changes: Vec<ChangeEntry>,
+}
+
+// Replies added, edited or removed since `since_version`, and edits to the
+// first post, see changes.rs.
+pub async fn thread_changes(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    _access: ApiAccess,
+    thread_id: web::Path<String>,
+    query: web::Query<ChangesQuery>,
+) -> HttpResponse {
+    match load_post(&db, &thread_id) {
+        Some(thread) if thread.parent_id.is_none() => {}
+        _ => return HttpResponse::NotFound().json(serde_json::json!({ "error": "not found" })),
+    }
+    let version = changes::current_version(&db, &thread_id);
+    let since = query.since_version.unwrap_or(0);
+    if since > version {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "since_version is ahead of the thread" }));
+    }
+
+    let body = match changes::since(&db, &thread_id, since) {
+        Some(log) => ThreadChanges {
+            version,
+            full_fetch_required: false,
+            changes: log
+                .into_iter()
+                .map(|change| ChangeEntry {
+                    post: match change.kind {
+                        ChangeKind::Added | ChangeKind::Edited => load_post(&db, &change.post_id).map(|post| ApiPost::new(&config, post)),
+                        ChangeKind::Deleted => None,
+                    },
+                    version: change.version,
+                    kind: change.kind,
+                    id: change.post_id,
+                })
+                .collect(),
+        },
+        None => ThreadChanges {
+            version,
+            full_fetch_required: true,
+            changes: Vec::new(),
+        },
+    };
+    HttpResponse::Ok().json(body)
+}
@@ -0,0 +1,317 @@
+// Archived threads. Archiving takes a thread off the board's listings and
+// closes it to replies; it stays readable at its own page and is found by
+// the month (UTC) it was started in:
+//
+//   /archive            years
+//   /archive/2024       the months of 2024 with their thread counts
+//   /archive/2024/06    the threads started in June 2024, oldest first
+//
+// `archived` maps a thread id to when it was archived and started.
+// `archive_by_date` is keyed "{YYYY}/{MM}/{started:020}/{thread id}", so a
+// month is one prefix scan, and `archive_counts` keeps counters for "YYYY"
+// and "YYYY/MM" so the year and month pages don't scan anything. With
+// ARCHIVE_DB set, these pages read the archive database instead, see
+// archive_db.rs.
+
+use actix_web::{web, HttpResponse};
+use askama::Template;
+use serde::{Deserialize, Serialize};
+use sled::Db;
+
+use crate::age_gate::AgeOk;
+use crate::archive_db::{self, ArchiveDb};
+use crate::config::Config;
+use crate::{announcement, counters, format, indexes, load_post, render, upload, Post};
+
+const MONTHS: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June", "July", "August", "September", "October", "November", "December",
+];
+const EXCERPT_CHARS: usize = 150;
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Archived {
+    pub archived_at: u64,
+    pub started: u64,
+}
+
+fn by_date_key(started: u64, thread_id: &str) -> String {
+    format!("{}/{:020}/{}", upload::dated_dir(started), started, thread_id)
+}
+
+pub fn is_archived(db: &Db, thread_id: &str) -> bool {
+    db.open_tree("archived").unwrap().contains_key(thread_id).unwrap()
+}
+
+// Returns false if `thread_id` isn't a thread or is archived already. The
+// thread's entry in the creation order goes when the listing next passes
+// it, see sorting::threads.
+pub fn archive(db: &Db, thread_id: &str, now: u64) -> bool {
+    let thread = match load_post(db, thread_id) {
+        Some(thread) if thread.parent_id.is_none() => thread,
+        _ => return false,
+    };
+    let record = Archived {
+        archived_at: now,
+        started: indexes::created_at(db, &thread),
+    };
+    if !list(db, thread_id, &record) {
+        return false;
+    }
+    indexes::remove(db, &thread);
+    announcement::forget(db, thread_id);
+    db.flush().unwrap();
+    true
+}
+
+// Puts a thread in the year and month listings of `db`. Returns false if
+// it's there already.
+pub fn list(db: &Db, thread_id: &str, record: &Archived) -> bool {
+    let claimed = db
+        .open_tree("archived")
+        .unwrap()
+        .compare_and_swap(thread_id, None as Option<&[u8]>, Some(serde_json::to_vec(record).unwrap()))
+        .unwrap();
+    if claimed.is_err() {
+        return false;
+    }
+    db.open_tree("archive_by_date").unwrap().insert(by_date_key(record.started, thread_id), &[]).unwrap();
+    let counts = db.open_tree("archive_counts").unwrap();
+    let month = upload::dated_dir(record.started);
+    counters::increment(&counts, &month[..4], 1);
+    counters::increment(&counts, &month, 1);
+    true
+}
+
+
+// Archived threads, oldest archived first, for moving to the archive
+// database, see archive_db.rs
+pub fn oldest(db: &Db, limit: usize) -> Vec<(String, Archived)> {
+    let mut found: Vec<(String, Archived)> = db
+        .open_tree("archived")
+        .unwrap()
+        .iter()
+        .filter_map(|entry| {
+            let (thread_id, record) = entry.unwrap();
+            Some((String::from_utf8(thread_id.to_vec()).ok()?, serde_json::from_slice(&record).ok()?))
+        })
+        .collect();
+    found.sort_by_key(|(_, record)| record.archived_at);
+    found.truncate(limit);
+    found
+}
+
+// Threads archived before `before`, for the archives retention
+pub fn archived_before(db: &Db, before: u64) -> Vec<String> {
+    db.open_tree("archived")
+        .unwrap()
+        .iter()
+        .filter_map(|entry| {
+            let (thread_id, record) = entry.unwrap();
+            let record: Archived = serde_json::from_slice(&record).ok()?;
+            (record.archived_at < before).then(|| String::from_utf8_lossy(&thread_id).into_owned())
+        })
+        .collect()
+}
+
+// For a thread being deleted. Returns how many entries were removed.
+pub fn forget(db: &Db, thread_id: &str) -> usize {
+    let record = match db.open_tree("archived").unwrap().remove(thread_id).unwrap() {
+        Some(bytes) => serde_json::from_slice::<Archived>(&bytes).ok(),
+        None => return 0,
+    };
+    let record = match record {
+        Some(record) => record,
+        None => return 1,
+    };
+    db.open_tree("archive_by_date").unwrap().remove(by_date_key(record.started, thread_id)).unwrap();
+    let counts = db.open_tree("archive_counts").unwrap();
+    let month = upload::dated_dir(record.started);
+    counters::increment(&counts, &month[..4], -1);
+    counters::increment(&counts, &month, -1);
+    2
+}
+
+// A year or month link with its thread count
+struct Bucket {
+    label: String,
+    url: String,
+    count: u64,
+}
+
+struct Card {
+    post: Post,
+    started: String,
+    replies: u64,
+    excerpt: String,
+}
+
+#[derive(Template)]
+#[template(path = "archive.html")]
+struct ArchiveTemplate<'a> {
+    config: &'a Config,
+    heading: String,
+    // This page, for the theme footer to come back to
+    url: String,
+    // One level up, None on /archive itself
+    up_url: Option<String>,
+    buckets: Vec<Bucket>,
+    cards: Vec<Card>,
+}
+
+// Counters under `prefix` whose key has `len` characters, newest first.
+// Buckets emptied by deletions are left out.
+fn buckets(db: &Db, prefix: &str, len: usize) -> Vec<(String, u64)> {
+    let mut found: Vec<(String, u64)> = db
+        .open_tree("archive_counts")
+        .unwrap()
+        .scan_prefix(prefix)
+        .filter_map(|item| {
+            let (key, value) = item.unwrap();
+            let key = String::from_utf8(key.to_vec()).ok().filter(|key| key.len() == len)?;
+            Some((key, counters::decode(Some(&value))))
+        })
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    found.reverse();
+    found
+}
+
+fn month_name(month: u32) -> &'static str {
+    MONTHS[(month as usize).saturating_sub(1).min(11)]
+}
+
+pub async fn years(db: web::Data<Db>, config: web::Data<Config>, archive: web::Data<ArchiveDb>, _age: AgeOk) -> HttpResponse {
+    let db = match archive.listing(&db) {
+        Some(db) => db,
+        None => return archive_db::missing(&config),
+    };
+    let buckets = buckets(&db, "", 4)
+        .into_iter()
+        .map(|(year, count)| Bucket {
+            url: config.url_for(&format!("/archive/{}", year)),
+            label: year,
+            count,
+        })
+        .collect();
+    let template = ArchiveTemplate {
+        config: &config,
+        heading: "Archive".to_string(),
+        url: config.url_for("/archive"),
+        up_url: None,
+        buckets,
+        cards: Vec::new(),
+    };
+    render::respond(HttpResponse::Ok(), &template, "the archive")
+}
+
+// Only four-digit years and months 01 to 12 are accepted, so every page
+// has one URL
+fn parse_year(raw: &str) -> Option<u32> {
+    (raw.len() == 4 && raw.chars().all(|c| c.is_ascii_digit())).then(|| raw.parse().ok()).flatten()
+}
+
+fn parse_month(raw: &str) -> Option<u32> {
+    let month: u32 = raw.parse().ok().filter(|_| raw.len() == 2 && raw.chars().all(|c| c.is_ascii_digit()))?;
+    (1..=12).contains(&month).then_some(month)
+}
+
+pub async fn months(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    archive: web::Data<ArchiveDb>,
+    _age: AgeOk,
+    year: web::Path<String>,
+) -> HttpResponse {
+    let year = match parse_year(&year) {
+        Some(year) => year,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let db = match archive.listing(&db) {
+        Some(db) => db,
+        None => return archive_db::missing(&config),
+    };
+    let buckets = buckets(&db, &format!("{:04}/", year), 7)
+        .into_iter()
+        .map(|(key, count)| Bucket {
+            label: month_name(key[5..].parse().unwrap_or(0)).to_string(),
+            url: config.url_for(&format!("/archive/{}", key)),
+            count,
+        })
+        .collect();
+    let template = ArchiveTemplate {
+        config: &config,
+        heading: format!("Archive: {}", year),
+        url: config.url_for(&format!("/archive/{:04}", year)),
+        up_url: Some(config.url_for("/archive")),
+        buckets,
+        cards: Vec::new(),
+    };
+    render::respond(HttpResponse::Ok(), &template, "the archive")
+}
+
+pub async fn month(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    archive: web::Data<ArchiveDb>,
+    _age: AgeOk,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (year, month) = match (parse_year(&path.0), parse_month(&path.1)) {
+        (Some(year), Some(month)) => (year, month),
+        _ => return HttpResponse::NotFound().finish(),
+    };
+    let db = match archive.listing(&db) {
+        Some(db) => db,
+        None => return archive_db::missing(&config),
+    };
+    let reply_counts = db.open_tree("reply_counts").unwrap();
+    let cards = db
+        .open_tree("archive_by_date")
+        .unwrap()
+        .scan_prefix(format!("{:04}/{:02}/", year, month))
+        .keys()
+        .filter_map(|key| {
+            let key = String::from_utf8(key.unwrap().to_vec()).ok()?;
+            let mut parts = key.rsplit('/');
+            let thread_id = parts.next()?;
+            let started: u64 = parts.next()?.parse().ok()?;
+            let post = load_post(&db, thread_id)?;
+            let (year, month, day) = format::civil_date(started);
+            Some(Card {
+                started: format!("{:04}-{:02}-{:02}", year, month, day),
+                replies: counters::get(&reply_counts, thread_id),
+                excerpt: format::format_message_plain(&post.message, EXCERPT_CHARS),
+                post,
+            })
+        })
+        .collect();
+    let template = ArchiveTemplate {
+        config: &config,
+        heading: format!("Archive: {} {}", month_name(month), year),
+        url: config.url_for(&format!("/archive/{:04}/{:02}", year, month)),
+        up_url: Some(config.url_for(&format!("/archive/{:04}", year))),
+        buckets: Vec::new(),
+        cards,
+    };
+    render::respond(HttpResponse::Ok(), &template, "the archive")
+}
+
+pub fn check_templates(config: &Config, thread: &Post) -> Result<(), String> {
+    render::check(&ArchiveTemplate {
+        config,
+        heading: "Archive: 2024".to_string(),
+        url: config.url_for("/archive/2024"),
+        up_url: Some(config.url_for("/archive")),
+        buckets: vec![Bucket {
+            label: "June".to_string(),
+            url: config.url_for("/archive/2024/06"),
+            count: 3,
+        }],
+        cards: vec![Card {
+            post: thread.clone(),
+            started: "2024-06-01".to_string(),
+            replies: 2,
+            excerpt: thread.message.clone(),
+        }],
+    })
+}
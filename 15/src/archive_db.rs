@@ -0,0 +1,186 @@
+// Archived threads in a database of their own, for boards whose archive
+// has outgrown the live one. With ARCHIVE_DB set, maintenance moves
+// archived threads there BATCH_SIZE at a time: each thread is exported from
+// the live database as its stored records, imported into the archive one,
+// flushed, and only then taken out of the live one, so a crash in between
+// leaves the thread in both rather than neither. Import skips threads the
+// archive already lists, so the next run finishes the move.
+//
+// The /archive pages read only the archive database. It is opened at
+// startup if it's there and created by the first move otherwise; until
+// then they answer 404 with a page saying why. sled can't open a database
+// read-only, so it's opened as usual, but only the move and takedowns
+// write to it, and a copy of its directory can be served elsewhere as is.
+// Retention doesn't reach threads once they're moved.
+//
+// Only records move, with the edit history of their messages and the
+// backlinks of their quotes; files stay in UPLOAD_DIR. The live database
+// keeps `archive_moved`, post id -> thread id for every post moved, so a
+// link to a moved thread, reply or reply number, a quote's included, is
+// sent to the right database without looking in both.
+
+use actix_web::HttpResponse;
+use sled::{Db, IVec};
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use crate::archive::{self, Archived};
+use crate::config::Config;
+use crate::{backlinks, counters, edits, indexes, load_post, moderation, numbering, posters, render, startup, storage, upload, NoticeTemplate, Post};
+
+const BATCH_SIZE: usize = 100;
+
+pub struct ArchiveDb {
+    path: Option<PathBuf>,
+    db: RwLock<Option<Db>>,
+}
+
+impl ArchiveDb {
+    pub fn open(config: &Config) -> ArchiveDb {
+        let db = config
+            .archive_db
+            .as_ref()
+            .filter(|path| path.exists())
+            .map(|path| startup::open_or_exit(&path.to_string_lossy(), None));
+        ArchiveDb {
+            path: config.archive_db.clone(),
+            db: RwLock::new(db),
+        }
+    }
+
+    // None when ARCHIVE_DB is unset or nothing has been moved there yet
+    pub fn get(&self) -> Option<Db> {
+        self.db.read().unwrap().clone()
+    }
+
+    // The database the archive pages list threads from, None if it should
+    // be a separate one that isn't there, see missing
+    pub fn listing(&self, live: &Db) -> Option<Db> {
+        match &self.path {
+            Some(_) => self.get(),
+            None => Some(live.clone()),
+        }
+    }
+
+    // The database holding `post_id`: the archive one for a post that was
+    // moved there, the live one otherwise
+    pub fn holding(&self, live: &Db, post_id: &str) -> Db {
+        let moved = live.open_tree("archive_moved").unwrap().contains_key(post_id).unwrap();
+        match self.get() {
+            Some(archive) if moved => archive,
+            _ => live.clone(),
+        }
+    }
+
+    fn open_or_create(&self) -> Option<Db> {
+        let path = self.path.as_ref()?;
+        let mut db = self.db.write().unwrap();
+        if db.is_none() {
+            match sled::open(path) {
+                Ok(opened) => *db = Some(opened),
+                Err(e) => eprintln!("couldn't create the archive database at {}: {}", path.display(), e),
+            }
+        }
+        db.clone()
+    }
+
+    // Moves up to BATCH_SIZE archived threads out of `live`, oldest
+    // archived first. Returns how many went.
+    pub fn transfer(&self, live: &Db, config: &Config) -> usize {
+        let batch = archive::oldest(live, BATCH_SIZE);
+        if batch.is_empty() {
+            return 0;
+        }
+        let target = match self.open_or_create() {
+            Some(target) => target,
+            None => return 0,
+        };
+        let exported: Vec<ThreadExport> =
+            batch.into_iter().filter_map(|(thread_id, record)| export(live, &thread_id, record)).collect();
+        for thread in &exported {
+            import(&target, thread);
+        }
+        target.flush().unwrap();
+        let moved = live.open_tree("archive_moved").unwrap();
+        for thread in &exported {
+            for (post, _) in &thread.posts {
+                moved.insert(&post.id, thread.thread_id.as_bytes()).unwrap();
+            }
+            storage::move_thread_out(live, config, &thread.thread_id);
+        }
+        exported.len()
+    }
+}
+
+// One archived thread as it leaves the live database
+struct ThreadExport {
+    thread_id: String,
+    record: Archived,
+    // The first post, then its replies in post order, with their stored
+    // records as they are
+    posts: Vec<(Post, IVec)>,
+    // Every stored version of their edited messages
+    edits: Vec<(IVec, IVec)>,
+    last_number: u64,
+    replies: u64,
+}
+
+fn export(live: &Db, thread_id: &str, record: Archived) -> Option<ThreadExport> {
+    let thread = load_post(live, thread_id)?;
+    let mut posts = vec![(thread, live.get(thread_id).unwrap()?)];
+    for reply in indexes::thread_replies(live, thread_id) {
+        if let Some(raw) = live.get(&reply.id).unwrap() {
+            posts.push((reply, raw));
+        }
+    }
+    let edits = posts.iter().flat_map(|(post, _)| edits::raw(live, &post.id)).collect();
+    Some(ThreadExport {
+        thread_id: thread_id.to_string(),
+        record,
+        posts,
+        edits,
+        last_number: numbering::last(live, thread_id),
+        replies: counters::get(&live.open_tree("reply_counts").unwrap(), thread_id),
+    })
+}
+
+// Counters are set rather than added to, so importing a thread again after
+// an interrupted move leaves them right
+fn set_counter(tree: &sled::Tree, key: &str, value: u64) {
+    counters::increment(tree, key, value as i64 - counters::get(tree, key) as i64);
+}
+
+fn import(target: &Db, thread: &ThreadExport) {
+    if archive::is_archived(target, &thread.thread_id) {
+        return;
+    }
+    for (post, raw) in &thread.posts {
+        target.insert(&post.id, raw).unwrap();
+        // The first post stays off the bumps and creations listings, as
+        // when it was archived
+        if post.parent_id.is_some() {
+            indexes::add(target, post);
+            backlinks::add(target, post);
+        }
+        posters::record(target, post);
+        upload::index(target, post);
+        moderation::index_upload(target, post);
+    }
+    edits::restore(target, &thread.edits);
+    set_counter(&target.open_tree("reply_numbers").unwrap(), &thread.thread_id, thread.last_number);
+    set_counter(&target.open_tree("reply_counts").unwrap(), &thread.thread_id, thread.replies);
+    // Listed last: a thread the archive lists is there in full
+    archive::list(target, &thread.thread_id, &thread.record);
+}
+
+// What the archive pages say while the archive database isn't there
+pub fn missing(config: &Config) -> HttpResponse {
+    let template = NoticeTemplate {
+        config,
+        heading: "No archive yet",
+        message: "Archived threads are kept in a separate database on this board, and it isn't there yet. \
+                  It's created when the first archived threads are moved to it.",
+        back_url: config.index_url(),
+    };
+    render::respond(HttpResponse::NotFound(), &template, "the missing archive notice")
+}
@@ -0,0 +1,39 @@
+// Append-only log of admin actions, kept in its own sled tree.
+
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub admin: String,
+    pub action: String,
+    pub target: String,
+    pub timestamp: u64,
+}
+
+pub fn record(db: &Db, admin: &str, action: &str, target: &str) {
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    let entry = AuditEntry {
+        admin: admin.to_string(),
+        action: action.to_string(),
+        target: target.to_string(),
+        timestamp,
+    };
+
+    // Zero-padded timestamp first so the tree iterates in chronological order
+    let key = format!("{:020}-{}", timestamp, Uuid::new_v4());
+    let tree = db.open_tree("audit").unwrap();
+    tree.insert(key, serde_json::to_vec(&entry).unwrap()).unwrap();
+}
+
+// Drops entries recorded before `before`. Returns how many were removed.
+pub fn prune(db: &Db, before: u64) -> usize {
+    let tree = db.open_tree("audit").unwrap();
+    let expired: Vec<_> = tree.range(..format!("{:020}", before)).keys().map(|key| key.unwrap()).collect();
+    for key in &expired {
+        tree.remove(key).unwrap();
+    }
+    expired.len()
+}
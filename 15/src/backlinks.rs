@@ -0,0 +1,128 @@
+// Which replies quote which. For ">>3" in reply 5 of a thread, `backlinks`
+// holds "{thread id}/{3:020}/{5:020}" -> reply 5's id, so the thread page
+// lists the replies quoting each reply with one scan per thread. Only
+// replies count; a first post has no number to be quoted back by.
+//
+// Entries go in with the reply, see changes::insert_reply, follow its
+// message through edits in the same transaction, see edits::edit, and go
+// with the reply. Quotes of numbers with no reply behind them keep their
+// entries too, so what's here depends only on the messages of the replies
+// there are, and `rebuild` arrives at exactly what the updates did. It
+// runs once for databases from before backlinks, and on `reindex`.
+
+use sled::transaction::{TransactionalTree, UnabortableTransactionError};
+use sled::Db;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{format, storage, Post};
+
+fn key(thread_id: &str, target: u64, source: u64) -> String {
+    format!("{}/{:020}/{:020}", thread_id, target, source)
+}
+
+// The numbers reply `number` quotes, itself left out
+fn quoted(message: &str, number: u64) -> BTreeSet<u64> {
+    format::find_quotes(message).into_iter().filter(|&target| target != number).collect()
+}
+
+// Inside the transaction that stores or edits reply `number`: entries for
+// quotes only in `before` go and those only in `after` come. `before` is
+// empty for a new reply.
+pub fn update_in(
+    backlinks: &TransactionalTree,
+    thread_id: &str,
+    reply_id: &str,
+    number: u64,
+    before: &str,
+    after: &str,
+) -> Result<(), UnabortableTransactionError> {
+    let before = quoted(before, number);
+    let after = quoted(after, number);
+    for &target in before.difference(&after) {
+        backlinks.remove(key(thread_id, target, number).as_bytes())?;
+    }
+    for &target in after.difference(&before) {
+        backlinks.insert(key(thread_id, target, number).as_bytes(), reply_id.as_bytes())?;
+    }
+    Ok(())
+}
+
+// A reply's entries outside a transaction, for rebuild and for replies
+// copied into the archive database. Returns how many it has.
+pub fn add(db: &Db, reply: &Post) -> usize {
+    let (thread_id, number) = match (&reply.parent_id, reply.reply_number) {
+        (Some(thread_id), Some(number)) => (thread_id, number),
+        _ => return 0,
+    };
+    let backlinks = db.open_tree("backlinks").unwrap();
+    let targets = quoted(&reply.message, number);
+    for &target in &targets {
+        backlinks.insert(key(thread_id, target, number), reply.id.as_bytes()).unwrap();
+    }
+    targets.len()
+}
+
+// For a reply being deleted. Returns how many entries were removed.
+pub fn forget(db: &Db, reply: &Post) -> usize {
+    let (thread_id, number) = match (&reply.parent_id, reply.reply_number) {
+        (Some(thread_id), Some(number)) => (thread_id, number),
+        _ => return 0,
+    };
+    let backlinks = db.open_tree("backlinks").unwrap();
+    quoted(&reply.message, number)
+        .into_iter()
+        .filter(|&target| backlinks.remove(key(thread_id, target, number)).unwrap().is_some())
+        .count()
+}
+
+// Returns how many entries were removed.
+pub fn forget_thread(db: &Db, thread_id: &str) -> usize {
+    let backlinks = db.open_tree("backlinks").unwrap();
+    let keys: Vec<_> = backlinks.scan_prefix(format!("{}/", thread_id)).keys().map(|key| key.unwrap()).collect();
+    for key in &keys {
+        backlinks.remove(key).unwrap();
+    }
+    keys.len()
+}
+
+// Reply number -> the numbers of the replies quoting it, lowest first
+pub fn for_thread(db: &Db, thread_id: &str) -> HashMap<u64, Vec<u64>> {
+    let mut found: HashMap<u64, Vec<u64>> = HashMap::new();
+    let prefix = format!("{}/", thread_id);
+    for key in db.open_tree("backlinks").unwrap().scan_prefix(&prefix).keys() {
+        let key = key.unwrap();
+        let parsed = std::str::from_utf8(&key[prefix.len()..]).ok().and_then(|rest| {
+            let (target, source) = rest.split_once('/')?;
+            Some((target.parse().ok()?, source.parse().ok()?))
+        });
+        if let Some((target, source)) = parsed {
+            found.entry(target).or_default().push(source);
+        }
+    }
+    found
+}
+
+// Every entry again from the replies in the main tree. Returns how many
+// there are.
+pub fn rebuild(db: &Db, parallelism: usize) -> usize {
+    db.open_tree("backlinks").unwrap().clear().unwrap();
+    let total = AtomicUsize::new(0);
+    let scan = storage::scan_all_parallel(db, parallelism, |post| {
+        total.fetch_add(add(db, &post), Ordering::Relaxed);
+    });
+    scan.log_unreadable("backlinks");
+    db.flush().unwrap();
+    total.into_inner()
+}
+
+// Once, on first start
+pub fn build_if_missing(db: &Db, parallelism: usize) {
+    let meta = db.open_tree("meta").unwrap();
+    if meta.contains_key("backlinks_built").unwrap() {
+        return;
+    }
+    rebuild(db, parallelism);
+    meta.insert("backlinks_built", &[]).unwrap();
+    db.flush().unwrap();
+}
@@ -0,0 +1,192 @@
+// Byte sizes as people write and read them. Config values like
+// MAX_UPLOAD_BYTES take "8MiB", "100 MB" or a plain number of bytes, and
+// sizes on pages and in messages are shown with one decimal ("4.2 MiB"),
+// in binary or decimal units per SIZE_UNITS.
+
+use std::str::FromStr;
+
+// Powers of 1024 with KiB, MiB..., or of 1000 with KB, MB...
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SizeUnits {
+    Binary,
+    Decimal,
+}
+
+impl FromStr for SizeUnits {
+    type Err = ();
+
+    fn from_str(raw: &str) -> Result<SizeUnits, ()> {
+        match raw {
+            "binary" => Ok(SizeUnits::Binary),
+            "decimal" => Ok(SizeUnits::Decimal),
+            _ => Err(()),
+        }
+    }
+}
+
+const BINARY: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+const DECIMAL: [&str; 6] = ["KB", "MB", "GB", "TB", "PB", "EB"];
+
+impl SizeUnits {
+    fn base(self) -> u64 {
+        match self {
+            SizeUnits::Binary => 1024,
+            SizeUnits::Decimal => 1000,
+        }
+    }
+
+    fn names(self) -> &'static [&'static str; 6] {
+        match self {
+            SizeUnits::Binary => &BINARY,
+            SizeUnits::Decimal => &DECIMAL,
+        }
+    }
+}
+
+// The multiplier for a unit, matched without regard to case. A bare "K",
+// "M"... counts as binary, as in most config files.
+fn multiplier(unit: &str) -> Option<u64> {
+    let unit = unit.to_ascii_lowercase();
+    if unit.is_empty() || unit == "b" {
+        return Some(1);
+    }
+    for units in [SizeUnits::Binary, SizeUnits::Decimal] {
+        for (power, name) in units.names().iter().enumerate() {
+            let short = &name[..1];
+            let matches = unit == name.to_ascii_lowercase() || (units == SizeUnits::Binary && unit == short.to_ascii_lowercase());
+            if matches {
+                return units.base().checked_pow(power as u32 + 1);
+            }
+        }
+    }
+    None
+}
+
+// "8MiB" -> 8388608, "1.5 KB" -> 1500, "42" -> 42. Fractions are allowed
+// and rounded down to a whole byte.
+pub fn parse(raw: &str) -> Result<u64, String> {
+    let raw = raw.trim();
+    let split = raw.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(raw.len());
+    let (number, unit) = (&raw[..split], raw[split..].trim());
+    if !number.contains(|c: char| c.is_ascii_digit()) {
+        return Err(format!("\"{}\" doesn't start with a number", raw));
+    }
+    let multiplier = multiplier(unit).ok_or_else(|| format!("\"{}\" isn't a size unit, try MiB or MB", unit))?;
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if fraction.contains('.') {
+        return Err(format!("\"{}\" isn't a number", number));
+    }
+    let too_large = || format!("{} is too large", raw);
+    let whole: u64 = if whole.is_empty() { 0 } else { whole.parse().map_err(|_| too_large())? };
+    // Digits past the 19th can't change the result by a whole byte
+    let fraction = &fraction[..fraction.len().min(19)];
+    let rest = if fraction.is_empty() {
+        0
+    } else {
+        let numerator: u128 = fraction.parse().map_err(|_| format!("\"{}\" isn't a number", number))?;
+        (u128::from(multiplier) * numerator / 10u128.pow(fraction.len() as u32)) as u64
+    };
+    whole.checked_mul(multiplier).and_then(|bytes| bytes.checked_add(rest)).ok_or_else(too_large)
+}
+
+// 1536 -> "1.5 KiB", 8388608 -> "8 MiB", 1000 -> "1000 B" in binary.
+// Whole numbers drop the ".0".
+pub fn format(bytes: u64, units: SizeUnits) -> String {
+    let base = units.base();
+    if bytes < base {
+        return format!("{} B", bytes);
+    }
+    let mut power = 0;
+    while power + 1 < units.names().len() && bytes / base.pow(power as u32 + 1) >= base {
+        power += 1;
+    }
+    let size = bytes as f64 / base.pow(power as u32 + 1) as f64;
+    let shown = format!("{:.1}", size);
+    // 1023.96 KiB rounds up to "1024.0", which reads better as the next unit
+    let (shown, power) = if shown == format!("{}.0", base) && power + 1 < units.names().len() {
+        ("1.0".to_string(), power + 1)
+    } else {
+        (shown, power)
+    };
+    format!("{} {}", shown.strip_suffix(".0").unwrap_or(&shown), units.names()[power])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const KIB: u64 = 1024;
+    const MIB: u64 = 1024 * KIB;
+
+    #[test]
+    fn sizes_change_unit_at_the_base() {
+        assert_eq!(format(0, SizeUnits::Binary), "0 B");
+        assert_eq!(format(1023, SizeUnits::Binary), "1023 B");
+        assert_eq!(format(1024, SizeUnits::Binary), "1 KiB");
+        assert_eq!(format(999, SizeUnits::Decimal), "999 B");
+        assert_eq!(format(1000, SizeUnits::Decimal), "1 KB");
+        assert_eq!(format(1023, SizeUnits::Decimal), "1 KB");
+        assert_eq!(format(1536, SizeUnits::Binary), "1.5 KiB");
+        assert_eq!(format(4_200_000, SizeUnits::Decimal), "4.2 MB");
+        // Just under a MiB rounds up into it rather than reading "1024 KiB"
+        assert_eq!(format(MIB - 1, SizeUnits::Binary), "1 MiB");
+        assert_eq!(format(8 * MIB, SizeUnits::Binary), "8 MiB");
+    }
+
+    #[test]
+    fn the_largest_size_stays_in_the_largest_unit() {
+        assert_eq!(format(u64::MAX, SizeUnits::Binary), "16 EiB");
+        assert_eq!(format(u64::MAX, SizeUnits::Decimal), "18.4 EB");
+        assert_eq!(parse(&u64::MAX.to_string()), Ok(u64::MAX));
+        assert_eq!(parse("18446744073709551616"), Err("18446744073709551616 is too large".to_string()));
+        assert_eq!(parse("15EiB"), Ok(15 << 60));
+        assert_eq!(parse("16EiB"), Err("16EiB is too large".to_string()));
+        assert_eq!(parse("18.4467440737095516 EB"), Ok(18_446_744_073_709_551_600));
+    }
+
+    #[test]
+    fn units_are_read_either_way() {
+        assert_eq!(parse("0"), Ok(0));
+        assert_eq!(parse("1023"), Ok(1023));
+        assert_eq!(parse("1KiB"), Ok(1024));
+        assert_eq!(parse("1 kb"), Ok(1000));
+        assert_eq!(parse("1K"), Ok(1024));
+        assert_eq!(parse(" 8MiB "), Ok(8 * MIB));
+        assert_eq!(parse("100MB"), Ok(100_000_000));
+        assert_eq!(parse("1.5KB"), Ok(1500));
+        assert_eq!(parse(".5 KiB"), Ok(512));
+        assert_eq!(parse("0.0001 KiB"), Ok(0));
+        assert_eq!(parse("12 B"), Ok(12));
+    }
+
+    #[test]
+    fn mistakes_say_what_is_wrong() {
+        assert_eq!(parse(""), Err("\"\" doesn't start with a number".to_string()));
+        assert_eq!(parse("MiB"), Err("\"MiB\" doesn't start with a number".to_string()));
+        assert_eq!(parse("-1"), Err("\"-1\" doesn't start with a number".to_string()));
+        assert_eq!(parse("1.2.3"), Err("\"1.2.3\" isn't a number".to_string()));
+        assert_eq!(parse("5 parsecs"), Err("\"parsecs\" isn't a size unit, try MiB or MB".to_string()));
+    }
+
+    proptest! {
+        // What's shown reads back as the size it stands for, give or take
+        // the rounding to one decimal
+        #[test]
+        fn shown_sizes_read_back(bytes in any::<u64>(), binary in any::<bool>()) {
+            let units = if binary { SizeUnits::Binary } else { SizeUnits::Decimal };
+            let read = parse(&format(bytes, units));
+            match read {
+                Ok(read) => prop_assert!(read.abs_diff(bytes) <= bytes / 20, "{} read back as {}", bytes, read),
+                // Rounded up past the largest size there is
+                Err(e) => prop_assert!(bytes > u64::MAX / 100 * 99, "{}: {}", bytes, e),
+            }
+        }
+
+        #[test]
+        fn whole_units_read_back_exactly(count in 0u64..1024, power in 0u32..6) {
+            let bytes = count * KIB.pow(power);
+            prop_assert_eq!(parse(&format(bytes, SizeUnits::Binary)), Ok(bytes));
+        }
+    }
+}
@@ -0,0 +1,358 @@
+// Per-thread change log, so archivers can fetch only what changed since
+// their last visit. Each thread has a version in `thread_versions` that
+// goes up by one for every reply added, edited or removed and every edit
+// of the first post, and `thread_changes` keeps what each version did,
+// keyed "{thread id}/{version:020}". Only the last RETAINED_CHANGES are
+// kept; clients further behind are told to refetch the whole thread.
+//
+// The reply itself is written in the same transaction as its version and
+// log entry, so the log can't disagree with the main tree. That
+// transaction also keeps each thread's last reply time in
+// `last_reply_times`, for slow mode.
+//
+// The board keeps a second log, for incremental backups (see export.rs):
+// every post added, edited or deleted anywhere, in `board_changes` keyed
+// "{sequence:020}", with the last sequence handed out in `meta` under
+// "board_sequence". It's written by BoardLog once each change is committed
+// and keeps the last EXPORT_RETAINED_CHANGES entries.
+
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError, TransactionalTree};
+use sled::{Db, Transactional};
+use std::time::SystemTime;
+
+use crate::events::{BoardEvent, EventHandler};
+use crate::{backlinks, counters, edits, indexes, load_post, numbering, schema, Post};
+
+pub const RETAINED_CHANGES: u64 = 1000;
+const BOARD_SEQUENCE: &str = "board_sequence";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Edited,
+    Deleted,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Change {
+    pub version: u64,
+    pub post_id: String,
+    pub kind: ChangeKind,
+}
+
+fn change_key(thread_id: &str, version: u64) -> String {
+    format!("{}/{:020}", thread_id, version)
+}
+
+pub fn current_version(db: &Db, thread_id: &str) -> u64 {
+    counters::get(&db.open_tree("thread_versions").unwrap(), thread_id)
+}
+
+// Inside a transaction: bumps the version and logs the change, dropping the
+// entry that just fell out of the retained window.
+fn log_change<E>(
+    versions: &TransactionalTree,
+    changes: &TransactionalTree,
+    thread_id: &str,
+    post_id: &str,
+    kind: ChangeKind,
+) -> Result<(), ConflictableTransactionError<E>> {
+    let version = counters::increment_in(versions, thread_id, 1)?;
+    let change = Change {
+        version,
+        post_id: post_id.to_string(),
+        kind,
+    };
+    changes.insert(change_key(thread_id, version).as_bytes(), serde_json::to_vec(&change).unwrap())?;
+    if version > RETAINED_CHANGES {
+        changes.remove(change_key(thread_id, version - RETAINED_CHANGES).as_bytes())?;
+    }
+    Ok(())
+}
+
+// Why a reply wasn't stored
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReplyRefused {
+    // Deleted, or never a thread
+    ThreadGone,
+    Archived,
+    Closed,
+    // The thread is in slow mode and its last reply was too recent.
+    // `wait_secs` is how long until the next one is allowed.
+    SlowMode { wait_secs: u64 },
+}
+
+// Writes a reply to the main tree with the next number in its thread, logs
+// it and adds its `replies` and `numbers` entries, all in one transaction
+// that first checks the thread is there and open at the reply's time. A
+// thread deleted or archived at the same moment either comes first and the
+// reply is refused, or comes after and sees the reply. With `slow_mode`
+// the thread's slow_mode_secs is checked against its last reply in the
+// same transaction, so of two replies sent at once only one gets in. The
+// reply's quotes go into `backlinks` there too. Returns the number.
+pub fn insert_reply(db: &Db, thread_id: &str, post: &Post, raw: &[u8], slow_mode: bool) -> Result<u64, ReplyRefused> {
+    let versions = db.open_tree("thread_versions").unwrap();
+    let changes = db.open_tree("thread_changes").unwrap();
+    let archived = db.open_tree("archived").unwrap();
+    let reply_numbers = db.open_tree("reply_numbers").unwrap();
+    let replies = db.open_tree("replies").unwrap();
+    let numbers = db.open_tree("numbers").unwrap();
+    let last_replies = db.open_tree("last_reply_times").unwrap();
+    let backlinks = db.open_tree("backlinks").unwrap();
+    let posted = db.open_tree("posted").unwrap();
+    let main: &sled::Tree = db;
+    let trees = (main, &versions, &changes, &archived, &reply_numbers, &replies, &numbers, &last_replies, &backlinks, &posted);
+    let outcome = trees.transaction(
+        |(main, versions, changes, archived, reply_numbers, replies, numbers, last_replies, backlinks, posted)| {
+            let thread = main.get(thread_id.as_bytes())?.and_then(|bytes| Post::upgrade(&bytes).ok());
+            let refused = match thread {
+                Some(thread) if thread.parent_id.is_none() => {
+                    if archived.get(thread_id.as_bytes())?.is_some() {
+                        Some(ReplyRefused::Archived)
+                    } else if thread.locks_at.is_some_and(|locks_at| post.timestamp >= locks_at) {
+                        Some(ReplyRefused::Closed)
+                    } else {
+                        let last = counters::decode(last_replies.get(thread_id.as_bytes())?.as_deref());
+                        let next = last + u64::from(thread.slow_mode_secs.unwrap_or(0));
+                        (slow_mode && last > 0 && post.timestamp < next).then(|| ReplyRefused::SlowMode {
+                            wait_secs: next - post.timestamp,
+                        })
+                    }
+                }
+                _ => Some(ReplyRefused::ThreadGone),
+            };
+            if let Some(refused) = refused {
+                return Err(ConflictableTransactionError::Abort(refused));
+            }
+            let number = numbering::next_in(reply_numbers, thread_id)?;
+            let numbered = schema::merge_fields(raw, |fields| {
+                fields.insert("reply_number".to_string(), number.into());
+            })
+            .unwrap_or_else(|_| raw.to_vec());
+            main.insert(post.id.as_bytes(), numbered)?;
+            log_change(versions, changes, thread_id, &post.id, ChangeKind::Added)?;
+            indexes::add_reply_in(replies, numbers, posted, thread_id, post, number)?;
+            backlinks::update_in(backlinks, thread_id, &post.id, number, "", &post.message)?;
+            let last = counters::decode(last_replies.get(thread_id.as_bytes())?.as_deref());
+            last_replies.insert(thread_id.as_bytes(), last.max(post.timestamp).to_string().as_bytes())?;
+            Ok(number)
+        },
+    );
+    match outcome {
+        Ok(number) => Ok(number),
+        Err(TransactionError::Abort(refused)) => Err(refused),
+        Err(TransactionError::Storage(e)) => panic!("storing reply {}: {}", post.id, e),
+    }
+}
+
+// Removes a reply from the main tree and logs it. Returns whether it was
+// there.
+pub fn remove_reply(db: &Db, thread_id: &str, post_id: &str) -> bool {
+    let versions = db.open_tree("thread_versions").unwrap();
+    let changes = db.open_tree("thread_changes").unwrap();
+    let main: &sled::Tree = db;
+    (main, &versions, &changes)
+        .transaction(|(main, versions, changes)| {
+            if main.remove(post_id.as_bytes())?.is_none() {
+                return Ok(false);
+            }
+            log_change::<()>(versions, changes, thread_id, post_id, ChangeKind::Deleted)?;
+            Ok(true)
+        })
+        .unwrap()
+}
+
+// Replaces a post's message after an edit, in one transaction with its
+// version in the edit history, the backlinks its quotes changed and a new
+// version of its thread, logged as an edit. Returns the updated post, None
+// if it's gone.
+pub fn edit_message(db: &Db, post_id: &str, message: &str, edit: &edits::Edit) -> Option<Post> {
+    let versions = db.open_tree("thread_versions").unwrap();
+    let changes = db.open_tree("thread_changes").unwrap();
+    let backlinks = db.open_tree("backlinks").unwrap();
+    let post_edits = db.open_tree("post_edits").unwrap();
+    let edit_versions = db.open_tree("edit_versions").unwrap();
+    let stored = edits::stored_count(db, post_id);
+    let main: &sled::Tree = db;
+    let edited = (main, &versions, &changes, &backlinks, &post_edits, &edit_versions)
+        .transaction(|(main, versions, changes, backlinks, post_edits, edit_versions)| {
+            let raw = match main.get(post_id.as_bytes())? {
+                Some(raw) => raw,
+                None => return Ok(false),
+            };
+            let post = match Post::upgrade(&raw) {
+                Ok(post) => post,
+                Err(_) => return Ok(false),
+            };
+            let updated = match schema::merge_fields(&raw, |fields| {
+                fields.insert("message".to_string(), message.into());
+            }) {
+                Ok(updated) => updated,
+                Err(_) => return Ok(false),
+            };
+            main.insert(post_id.as_bytes(), updated)?;
+            edits::record_in(post_edits, edit_versions, &post, message, edit, stored)?;
+            let thread_id = post.parent_id.as_deref().unwrap_or(post_id);
+            if let (Some(_), Some(number)) = (&post.parent_id, post.reply_number) {
+                backlinks::update_in(backlinks, thread_id, post_id, number, &post.message, message)?;
+            }
+            log_change::<()>(versions, changes, thread_id, post_id, ChangeKind::Edited)?;
+            Ok(true)
+        })
+        .unwrap();
+    if !edited {
+        return None;
+    }
+    db.flush().unwrap();
+    load_post(db, post_id)
+}
+
+// Drops the version and log of a deleted thread. Returns how many entries
+// were removed.
+pub fn forget_thread(db: &Db, thread_id: &str) -> usize {
+    let changes = db.open_tree("thread_changes").unwrap();
+    let keys: Vec<_> = changes.scan_prefix(format!("{}/", thread_id)).keys().map(|key| key.unwrap()).collect();
+    for key in &keys {
+        changes.remove(key).unwrap();
+    }
+    db.open_tree("thread_versions").unwrap().remove(thread_id).unwrap();
+    keys.len()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BoardChange {
+    pub sequence: u64,
+    // Milliseconds since the epoch
+    pub at: u64,
+    pub kind: ChangeKind,
+    pub post_id: String,
+    // For replies
+    pub thread_id: Option<String>,
+}
+
+// The last sequence handed out, 0 before the first change
+pub fn board_sequence(db: &Db) -> u64 {
+    counters::get(&db.open_tree("meta").unwrap(), BOARD_SEQUENCE)
+}
+
+// The oldest sequence still kept, None while the log is empty
+pub fn oldest_board_change(db: &Db) -> Option<u64> {
+    let (key, _) = db.open_tree("board_changes").unwrap().first().unwrap()?;
+    std::str::from_utf8(&key).ok()?.parse().ok()
+}
+
+// Drops board log entries made before `before_ms`, oldest first, stopping
+// at the first newer one. An entry that doesn't parse goes too, so it
+// can't hold the rest back. Returns how many were removed.
+pub fn prune_board_changes(db: &Db, before_ms: u64) -> usize {
+    let log = db.open_tree("board_changes").unwrap();
+    let mut removed = 0;
+    for entry in log.iter() {
+        let (key, bytes) = entry.unwrap();
+        let expired = serde_json::from_slice::<BoardChange>(&bytes).map_or(true, |change| change.at < before_ms);
+        if !expired {
+            break;
+        }
+        log.remove(key).unwrap();
+        removed += 1;
+    }
+    removed
+}
+
+// Up to `limit` entries after `after` and no later than `last`, oldest first
+pub fn board_changes(db: &Db, after: u64, last: u64, limit: usize) -> Vec<BoardChange> {
+    db.open_tree("board_changes")
+        .unwrap()
+        .range(format!("{:020}", after + 1)..=format!("{:020}", last))
+        .values()
+        .take(limit)
+        .filter_map(|bytes| serde_json::from_slice(&bytes.unwrap()).ok())
+        .collect()
+}
+
+// Appends the board log entries for one event in a single transaction, so
+// sequences are handed out in the order the entries become visible: a
+// reader that has seen sequence N has seen everything before it.
+fn log_board_changes(db: &Db, retained: u64, entries: &[(ChangeKind, &str, Option<&str>)]) {
+    let at = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+    let meta = db.open_tree("meta").unwrap();
+    let log = db.open_tree("board_changes").unwrap();
+    (&meta, &log)
+        .transaction(|(meta, log)| {
+            for &(kind, post_id, thread_id) in entries {
+                let sequence = counters::increment_in(meta, BOARD_SEQUENCE, 1)?;
+                let change = BoardChange {
+                    sequence,
+                    at,
+                    kind,
+                    post_id: post_id.to_string(),
+                    thread_id: thread_id.map(str::to_string),
+                };
+                log.insert(format!("{:020}", sequence).as_bytes(), serde_json::to_vec(&change).unwrap())?;
+                if sequence > retained {
+                    log.remove(format!("{:020}", sequence - retained).as_bytes())?;
+                }
+            }
+            Ok::<_, ConflictableTransactionError>(())
+        })
+        .unwrap();
+}
+
+pub struct BoardLog {
+    db: Db,
+    retained: u64,
+}
+
+impl BoardLog {
+    pub fn new(db: &Db, retained: u64) -> BoardLog {
+        BoardLog { db: db.clone(), retained }
+    }
+}
+
+impl EventHandler for BoardLog {
+    fn name(&self) -> &'static str {
+        "board_log"
+    }
+
+    fn handle(&self, event: &BoardEvent) -> Result<(), String> {
+        match event {
+            BoardEvent::Created { post, .. } => {
+                log_board_changes(&self.db, self.retained, &[(ChangeKind::Added, &post.id, post.parent_id.as_deref())]);
+            }
+            BoardEvent::Edited { post_id } => {
+                let thread_id = load_post(&self.db, post_id).and_then(|post| post.parent_id);
+                log_board_changes(&self.db, self.retained, &[(ChangeKind::Edited, post_id, thread_id.as_deref())]);
+            }
+            BoardEvent::Deleted { posts } => {
+                let entries: Vec<_> = posts
+                    .iter()
+                    .map(|post| (ChangeKind::Deleted, post.id.as_str(), post.parent_id.as_deref()))
+                    .collect();
+                log_board_changes(&self.db, self.retained, &entries);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Changes after `since`, oldest first, or None if some of them have
+// already been dropped from the log.
+pub fn since(db: &Db, thread_id: &str, since: u64) -> Option<Vec<Change>> {
+    let current = current_version(db, thread_id);
+    let oldest_retained = current.saturating_sub(RETAINED_CHANGES) + 1;
+    if since + 1 < oldest_retained {
+        return None;
+    }
+    let changes = db.open_tree("thread_changes").unwrap();
+    let start = change_key(thread_id, since + 1);
+    let end = change_key(thread_id, current + 1);
+    Some(
+        changes
+            .range(start..end)
+            .values()
+            .filter_map(|bytes| serde_json::from_slice(&bytes.unwrap()).ok())
+            .collect(),
+    )
+}
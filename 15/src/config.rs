@@ -0,0 +1,553 @@
+// Runtime configuration, read from environment variables at startup. The
+// settings that can change without a restart are in runtime.rs.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::bytesize::{self, SizeUnits};
+use crate::rate_limit::BucketPolicy;
+use crate::retention::{self, RetentionPolicy};
+use crate::route_limits::{LimitClass, Limits};
+use crate::theme::Theme;
+use crate::sorting::ThreadSort;
+use crate::upload::{self, OnFailure};
+use crate::Post;
+
+// Smallest page size a `per_page` query can ask for
+pub const MIN_PER_PAGE: usize = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NamePolicy {
+    // Every post is anonymous, the name field isn't shown
+    Disabled,
+    Optional,
+    Required,
+}
+
+impl std::str::FromStr for NamePolicy {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<NamePolicy, ()> {
+        match value {
+            "disabled" => Ok(NamePolicy::Disabled),
+            "optional" => Ok(NamePolicy::Optional),
+            "required" => Ok(NamePolicy::Required),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SmtpTls {
+    StartTls,
+    // TLS from the start, usually port 465
+    Tls,
+    // Plain text, for a relay on the same machine
+    None,
+}
+
+#[derive(Clone)]
+pub struct Config {
+    // With no trailing separator; the server makes it absolute at startup,
+    // see startup::prepare_upload_dir
+    pub upload_dir: PathBuf,
+    // Prefix for every generated URL when served from a subpath behind a
+    // reverse proxy, e.g. "/board". Empty when served from the root.
+    pub base_path: String,
+    // Spam heuristics: reject long messages made mostly of URLs, and any
+    // message with an absurdly long run of one repeated character.
+    pub spam_url_fraction: f64,
+    pub spam_url_min_length: usize,
+    pub spam_max_repeat_run: usize,
+    // Admin login is disabled unless ADMIN_PASSWORD is set
+    pub admin_password: Option<String>,
+    // How long an admin login lasts, from ADMIN_SESSION_HOURS
+    pub admin_session_secs: u64,
+    // Send the admin session cookie over HTTPS only. Turn off only for a
+    // board reached over plain HTTP, such as on a LAN.
+    pub admin_cookie_secure: bool,
+    // Use X-Forwarded-For / Forwarded for the client address. Only safe
+    // when every request comes through a proxy that sets them.
+    pub trust_proxy_headers: bool,
+    // Hold posts from posters with no approved post yet for moderation
+    pub approval_queue: bool,
+    // When an attachment fails to upload, reject the whole post instead of
+    // keeping the text with a notice
+    pub reject_post_on_upload_failure: bool,
+    // Flag an upload once the same file is on this many other posts from
+    // the last `duplicate_image_window_secs`
+    pub duplicate_image_threshold: usize,
+    pub duplicate_image_window_secs: u64,
+    // NAMES=disabled|optional|required, TRIPCODES=enabled|disabled
+    pub names: NamePolicy,
+    pub tripcodes: bool,
+    // Upload pipeline, see upload.rs. Extensions are lowercase, without dots.
+    // Sizes here take units, e.g. MAX_UPLOAD_BYTES=8MiB, see bytesize.rs.
+    pub allowed_extensions: Vec<String>,
+    pub max_upload_bytes: u64,
+    // Whether either of those was set, for the setup checklist, see setup.rs
+    pub upload_limits_set: bool,
+    // Uploads are turned off while the upload volume has less free space
+    // than this, see diskspace.rs
+    pub min_free_upload_bytes: u64,
+    // SNIFF_UPLOADS=reject|log|off: what to do with images whose contents
+    // don't match their extension
+    pub sniff_uploads: Option<OnFailure>,
+    // Command that turns a HEIC/HEIF photo into a JPEG, run as
+    // "{command} {input} {output.jpg}", e.g. HEIF_CONVERTER=heif-convert or
+    // "heif-convert -q 90". Without one those photos are refused.
+    pub heif_converter: Option<Vec<String>>,
+    // The index's list of threads with the most replies in the last day
+    pub show_popular_threads: bool,
+    // Keep each thread's reply preview rendered between requests, see
+    // previews.rs
+    pub preview_cache: bool,
+    // Time storage calls and renders for /admin/performance, see timings.rs
+    pub timings: bool,
+    // Entries kept in the board-wide change log, see export.rs
+    pub export_retained_changes: u64,
+    // How long each class of dated data is kept, see retention.rs
+    pub retention: RetentionPolicy,
+    // Attachments are replaced by thumbnails once older than this, or
+    // oldest first while they take more than the budget, see
+    // media_prune.rs. Both unset by default.
+    pub media_prune_after: Option<u64>,
+    pub media_budget: Option<u64>,
+    pub media_prune_dry_run: bool,
+    // Where the board describes itself for directory sites, see
+    // manifest.rs. Under BASE_PATH like every other route.
+    pub manifest_path: String,
+    // Shown after "##" on posts made by a logged-in admin using #admin
+    pub capcode_name: String,
+    // Largest page size a `per_page` query can ask for
+    pub max_per_page: usize,
+    // Scheme and host the board is reached on, e.g. "https://example.org",
+    // for links sent off-site. Empty keeps them relative.
+    pub public_url: String,
+    // New threads are POSTed to every WEBHOOK_URLS entry, see webhooks.rs
+    pub webhook_urls: Vec<String>,
+    pub webhook_secret: Option<String>,
+    pub webhook_replies: bool,
+    pub webhook_attempts: u32,
+    // Show "Reply N deleted" in place of removed replies, so the numbering
+    // visibly skips instead of silently
+    pub show_deleted_replies: bool,
+    // Replies on each page of a thread, by number: page 0 has replies 1 to
+    // REPLIES_PER_PAGE and so on, so a reply's page never changes. 0 keeps
+    // every reply on one page.
+    pub replies_per_page: u64,
+    // Sites allowed to frame /widget and read /widget.json, e.g.
+    // "https://example.org". "*" allows any.
+    pub widget_origins: Vec<String>,
+    // File of settings that can be reloaded while running, see runtime.rs
+    pub config_file: Option<PathBuf>,
+    // Separate database that archived threads are moved to and the archive
+    // pages are served from, see archive_db.rs. Unset keeps them here.
+    pub archive_db: Option<PathBuf>,
+    // BCP 47 language tag for the html lang and dir attributes and
+    // Content-Language, e.g. "en" or "pt-BR". Page text itself isn't
+    // translated.
+    pub lang: String,
+    // Theme for visitors who haven't picked one, see theme.rs
+    pub default_theme: Theme,
+    // Limits on how slowly or in how many pieces a post may arrive, see
+    // intake.rs
+    pub intake_max_chunks: usize,
+    pub intake_max_secs: u64,
+    pub intake_min_bytes_per_sec: u64,
+    pub intake_rate_window_secs: u64,
+    // How many uploads are read at once, 0 for no limit, and how long one
+    // waits for a turn, see upload_slots.rs
+    pub max_concurrent_uploads: usize,
+    pub upload_slot_wait_secs: u64,
+    // Body size, handler time and keep-alive by route class, see
+    // route_limits.rs
+    pub limits_upload: Limits,
+    pub limits_standard: Limits,
+    pub limits_export: Limits,
+    // New threads can be given a closing time by admins, and by everyone
+    // with THREAD_LOCKS_PUBLIC. At most MAX_THREAD_LOCK_HOURS ahead.
+    pub thread_locks_public: bool,
+    pub max_thread_lock_hours: u64,
+    // For boards marked adult, see age_gate.rs: where "Leave" goes, and
+    // the token scripts pass to use the API
+    pub nsfw_leave_url: String,
+    pub nsfw_api_token: Option<String>,
+    // Reply notification emails, see notify.rs. Off unless SMTP_HOST,
+    // SMTP_FROM and NOTIFY_KEY are all set. SMTP_TLS=starttls|tls|none.
+    pub smtp_host: Option<String>,
+    pub smtp_port: Option<u16>,
+    pub smtp_tls: SmtpTls,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub smtp_from: Option<String>,
+    // Encrypts stored addresses and signs unsubscribe links
+    pub notify_key: Option<String>,
+    // Threads for jobs that read every post, see storage::scan_all_parallel.
+    // Defaults to one per CPU.
+    pub scan_threads: usize,
+    // SIZE_UNITS=binary|decimal: whether sizes are shown as KiB, MiB... or
+    // as KB, MB...
+    pub size_units: SizeUnits,
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        // Uploads default to room for the largest file and the time intake
+        // allows it, plus some over for the rest of the form
+        let max_upload_bytes = size_or("MAX_UPLOAD_BYTES", 20 * 1024 * 1024);
+        let intake_max_secs = env_or("INTAKE_MAX_SECS", 600);
+        let upload_limits = Limits {
+            max_body: max_upload_bytes + 1024 * 1024,
+            timeout: Duration::from_secs(intake_max_secs + 60),
+            keep_alive: true,
+        };
+        Config {
+            upload_dir: std::env::var_os("UPLOAD_DIR")
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| upload::clean_dir(Path::new(&dir)))
+                .unwrap_or_else(|| PathBuf::from("./static/uploads")),
+            base_path: normalize_base_path(&std::env::var("BASE_PATH").unwrap_or_default()),
+            spam_url_fraction: env_or("SPAM_URL_FRACTION", 0.7),
+            spam_url_min_length: env_or("SPAM_URL_MIN_LENGTH", 200),
+            spam_max_repeat_run: env_or("SPAM_MAX_REPEAT_RUN", 2000),
+            admin_password: std::env::var("ADMIN_PASSWORD").ok().filter(|p| !p.is_empty()),
+            admin_session_secs: env_or("ADMIN_SESSION_HOURS", 12u64) * 60 * 60,
+            admin_cookie_secure: env_or("ADMIN_COOKIE_SECURE", true),
+            trust_proxy_headers: env_or("TRUST_PROXY_HEADERS", false),
+            approval_queue: env_or("APPROVAL_QUEUE", false),
+            reject_post_on_upload_failure: env_or("REJECT_POST_ON_UPLOAD_FAILURE", true),
+            duplicate_image_threshold: env_or("DUPLICATE_IMAGE_THRESHOLD", 2),
+            duplicate_image_window_secs: env_or("DUPLICATE_IMAGE_WINDOW_SECS", 24 * 60 * 60),
+            names: env_or("NAMES", NamePolicy::Optional),
+            tripcodes: std::env::var("TRIPCODES").map(|v| v.trim() != "disabled").unwrap_or(true),
+            allowed_extensions: list_or("ALLOWED_EXTENSIONS", "jpg,jpeg,gif,png,mp3,mp4,webm,webp"),
+            max_upload_bytes,
+            upload_limits_set: std::env::var_os("MAX_UPLOAD_BYTES").is_some() || std::env::var_os("ALLOWED_EXTENSIONS").is_some(),
+            min_free_upload_bytes: size_or("MIN_FREE_UPLOAD_BYTES", 512 * 1024 * 1024),
+            sniff_uploads: match std::env::var("SNIFF_UPLOADS").as_deref().map(str::trim) {
+                Ok("off") => None,
+                Ok("log") => Some(OnFailure::Degrade),
+                _ => Some(OnFailure::RejectPost),
+            },
+            heif_converter: std::env::var("HEIF_CONVERTER")
+                .ok()
+                .map(|command| command.split_whitespace().map(str::to_string).collect::<Vec<_>>())
+                .filter(|command| !command.is_empty()),
+            show_popular_threads: env_or("POPULAR_THREADS", true),
+            preview_cache: env_or("PREVIEW_CACHE", true),
+            timings: env_or("TIMINGS", false),
+            export_retained_changes: env_or("EXPORT_RETAINED_CHANGES", 100_000).max(1),
+            retention: retention_or_default("RETENTION"),
+            media_prune_after: duration_or_none("MEDIA_PRUNE_AFTER"),
+            media_budget: Some(size_or("MEDIA_BUDGET", 0)).filter(|&budget| budget > 0),
+            media_prune_dry_run: env_or("MEDIA_PRUNE_DRY_RUN", false),
+            manifest_path: std::env::var("MANIFEST_PATH")
+                .ok()
+                .map(|path| format!("/{}", path.trim().trim_matches('/')))
+                .filter(|path| path.len() > 1)
+                .unwrap_or_else(|| "/.well-known/board.json".to_string()),
+            capcode_name: std::env::var("CAPCODE_NAME")
+                .ok()
+                .filter(|name| !name.trim().is_empty())
+                .unwrap_or_else(|| "Admin".to_string()),
+            max_per_page: env_or("MAX_PER_PAGE", 100).max(MIN_PER_PAGE),
+            public_url: std::env::var("PUBLIC_URL").unwrap_or_default().trim().trim_end_matches('/').to_string(),
+            webhook_urls: std::env::var("WEBHOOK_URLS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|url| url.trim().to_string())
+                .filter(|url| !url.is_empty())
+                .collect(),
+            webhook_secret: std::env::var("WEBHOOK_SECRET").ok().filter(|s| !s.is_empty()),
+            webhook_replies: env_or("WEBHOOK_REPLIES", false),
+            webhook_attempts: env_or("WEBHOOK_ATTEMPTS", 5).max(1),
+            show_deleted_replies: env_or("SHOW_DELETED_REPLIES", true),
+            replies_per_page: env_or("REPLIES_PER_PAGE", 0),
+            widget_origins: std::env::var("WIDGET_ORIGINS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+            config_file: std::env::var_os("CONFIG_FILE").filter(|file| !file.is_empty()).map(PathBuf::from),
+            archive_db: std::env::var_os("ARCHIVE_DB").filter(|path| !path.is_empty()).map(PathBuf::from),
+            lang: std::env::var("BOARD_LANG")
+                .ok()
+                .map(|tag| tag.trim().to_string())
+                .filter(|tag| !tag.is_empty() && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+                .unwrap_or_else(|| "en".to_string()),
+            default_theme: env_or("DEFAULT_THEME", Theme::Light),
+            intake_max_chunks: env_or("INTAKE_MAX_CHUNKS", 10_000),
+            intake_max_secs,
+            intake_min_bytes_per_sec: size_or("INTAKE_MIN_BYTES_PER_SEC", 1024),
+            intake_rate_window_secs: env_or("INTAKE_RATE_WINDOW_SECS", 15),
+            max_concurrent_uploads: env_or("MAX_CONCURRENT_UPLOADS", 8),
+            upload_slot_wait_secs: env_or("UPLOAD_SLOT_WAIT_SECS", 5),
+            limits_upload: limits_or("LIMITS_UPLOAD", upload_limits),
+            limits_standard: limits_or(
+                "LIMITS_STANDARD",
+                Limits {
+                    max_body: 16 * 1024,
+                    timeout: Duration::from_secs(30),
+                    keep_alive: true,
+                },
+            ),
+            limits_export: limits_or(
+                "LIMITS_EXPORT",
+                Limits {
+                    max_body: 16 * 1024,
+                    timeout: Duration::from_secs(60 * 60),
+                    keep_alive: false,
+                },
+            ),
+            thread_locks_public: env_or("THREAD_LOCKS_PUBLIC", false),
+            max_thread_lock_hours: env_or("MAX_THREAD_LOCK_HOURS", 30 * 24).max(1),
+            nsfw_leave_url: std::env::var("NSFW_LEAVE_URL")
+                .ok()
+                .filter(|url| !url.trim().is_empty())
+                .unwrap_or_else(|| "about:blank".to_string()),
+            nsfw_api_token: std::env::var("NSFW_API_TOKEN").ok().filter(|s| !s.is_empty()),
+            smtp_host: std::env::var("SMTP_HOST").ok().map(|host| host.trim().to_string()).filter(|host| !host.is_empty()),
+            smtp_port: std::env::var("SMTP_PORT").ok().and_then(|port| port.trim().parse().ok()),
+            smtp_tls: match std::env::var("SMTP_TLS").as_deref().map(str::trim) {
+                Ok("tls") => SmtpTls::Tls,
+                Ok("none") => SmtpTls::None,
+                _ => SmtpTls::StartTls,
+            },
+            smtp_username: std::env::var("SMTP_USERNAME").ok().filter(|s| !s.is_empty()),
+            smtp_password: std::env::var("SMTP_PASSWORD").ok().filter(|s| !s.is_empty()),
+            smtp_from: std::env::var("SMTP_FROM").ok().map(|from| from.trim().to_string()).filter(|from| !from.is_empty()),
+            notify_key: std::env::var("NOTIFY_KEY").ok().filter(|s| !s.is_empty()),
+            scan_threads: env_or("SCAN_THREADS", std::thread::available_parallelism().map_or(1, |n| n.get())).max(1),
+            size_units: env_or("SIZE_UNITS", SizeUnits::Binary),
+        }
+    }
+
+    // Whether the new-thread form offers reply notifications
+    pub fn notify_enabled(&self) -> bool {
+        self.smtp_host.is_some() && self.smtp_from.is_some() && self.notify_key.is_some()
+    }
+
+    // For pages and messages, in the board's SIZE_UNITS
+    pub fn human_size(&self, bytes: u64) -> String {
+        bytesize::format(bytes, self.size_units)
+    }
+
+    pub fn names_enabled(&self) -> bool {
+        self.names != NamePolicy::Disabled
+    }
+
+    pub fn names_required(&self) -> bool {
+        self.names == NamePolicy::Required
+    }
+
+    // For the html dir attribute: "rtl" when `lang` is written right to
+    // left, by its language or its script subtag
+    pub fn text_dir(&self) -> &'static str {
+        const RTL_LANGUAGES: &[&str] = &["ar", "arc", "ckb", "dv", "fa", "he", "ks", "ps", "sd", "ug", "ur", "yi"];
+        const RTL_SCRIPTS: &[&str] = &["arab", "hebr", "syrc", "thaa", "nkoo", "adlm", "rohg"];
+        let lang = self.lang.to_ascii_lowercase();
+        let mut subtags = lang.split('-');
+        let language = subtags.next().unwrap_or_default();
+        let script = subtags.find(|subtag| subtag.len() == 4);
+        let rtl = match script {
+            Some(script) => RTL_SCRIPTS.contains(&script),
+            None => RTL_LANGUAGES.contains(&language),
+        };
+        if rtl {
+            "rtl"
+        } else {
+            "ltr"
+        }
+    }
+
+    // For the file input's accept attribute
+    pub fn accept_extensions(&self) -> String {
+        let converted: &[&str] = if self.heif_converter.is_some() { &upload::HEIF_EXTENSIONS } else { &[] };
+        self.allowed_extensions
+            .iter()
+            .map(String::as_str)
+            .chain(converted.iter().copied())
+            .map(|ext| format!(".{}", ext))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    pub fn limits(&self, class: LimitClass) -> Limits {
+        match class {
+            LimitClass::Upload => self.limits_upload,
+            LimitClass::Standard => self.limits_standard,
+            LimitClass::Export => self.limits_export,
+        }
+    }
+
+
+    // Every internally generated link goes through here so nothing escapes
+    // the base path. `path` must be root-relative.
+    pub fn url_for(&self, path: &str) -> String {
+        format!("{}{}", self.base_path, path)
+    }
+
+    pub fn index_url(&self) -> String {
+        self.url_for("/")
+    }
+
+    // `per_page` is only added when the visitor asked for a size, and
+    // `sort` when it isn't the default
+    pub fn page_url(&self, page: usize, per_page: Option<usize>, sort: ThreadSort) -> String {
+        let mut url = format!("/?page={}", page);
+        if let Some(per_page) = per_page {
+            url.push_str(&format!("&per_page={}", per_page));
+        }
+        if sort != ThreadSort::Bump {
+            url.push_str(&format!("&sort={}", sort.as_str()));
+        }
+        self.url_for(&url)
+    }
+
+    // A requested page size clamped to the allowed range, or None if it
+    // isn't a number so the caller falls back to its default. Numbers too
+    // big for usize are still numbers and get the maximum.
+    pub fn clamp_per_page(&self, requested: Option<&str>) -> Option<usize> {
+        let requested = requested?.trim();
+        if requested.is_empty() || !requested.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let requested: usize = requested.parse().unwrap_or(usize::MAX);
+        Some(requested.clamp(MIN_PER_PAGE, self.max_per_page))
+    }
+
+    pub fn post_url(&self, id: &str) -> String {
+        self.url_for(&format!("/post/{}", id))
+    }
+
+    // Page `page` of a thread's replies, see replies_per_page
+    pub fn thread_page_url(&self, thread_id: &str, page: u64) -> String {
+        match page {
+            0 => self.post_url(thread_id),
+            page => format!("{}?page={}", self.post_url(thread_id), page),
+        }
+    }
+
+    // The page of its thread reply `number` is on
+    pub fn reply_page(&self, number: u64) -> u64 {
+        match self.replies_per_page {
+            0 => 0,
+            per_page => number.saturating_sub(1) / per_page,
+        }
+    }
+
+    // A reply in its thread, on its page. Without a number (replies from
+    // before numbers) it's just the thread.
+    pub fn reply_url(&self, thread_id: &str, number: Option<u64>) -> String {
+        match number {
+            Some(number) => format!("{}#r{}", self.thread_page_url(thread_id, self.reply_page(number)), number),
+            None => self.post_url(thread_id),
+        }
+    }
+
+    // Where a post is shown: a thread's page, or a reply's place in its
+    // thread
+    pub fn url_of(&self, post: &Post) -> String {
+        match &post.parent_id {
+            Some(thread_id) => self.reply_url(thread_id, post.reply_number),
+            None => self.post_url(&post.id),
+        }
+    }
+
+    pub fn static_url(&self, name: &str) -> String {
+        self.url_for(&format!("/static/{}", name))
+    }
+
+    pub fn upload_url(&self, file: &str) -> String {
+        self.url_for(&format!("/static/uploads/{}", file))
+    }
+
+    // Where a stored file is on disk, see upload::path_in
+    pub fn upload_path(&self, file: &str) -> PathBuf {
+        upload::path_in(&self.upload_dir, file)
+    }
+}
+
+// Unset or unparseable values fall back to the default.
+pub fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name).ok().and_then(|v| v.trim().parse().ok()).unwrap_or(default)
+}
+
+// A size with or without a unit. A value that doesn't parse is reported and
+// the default used, as a typo shouldn't take the board down.
+fn size_or(name: &str, default: u64) -> u64 {
+    match std::env::var(name).map(|raw| bytesize::parse(&raw)) {
+        Ok(Ok(bytes)) => bytes,
+        Ok(Err(e)) => {
+            eprintln!("{}: {}, using {}", name, e, bytesize::format(default, SizeUnits::Binary));
+            default
+        }
+        Err(_) => default,
+    }
+}
+
+// Like size_or: a policy that doesn't parse is reported, and every class
+// keeps its default rather than purging by a half-read policy.
+fn retention_or_default(name: &str) -> RetentionPolicy {
+    match std::env::var(name).map(|raw| RetentionPolicy::parse(&raw)) {
+        Ok(Ok(policy)) => policy,
+        Ok(Err(e)) => {
+            eprintln!("{}: {}, using the defaults", name, e);
+            RetentionPolicy::default()
+        }
+        Err(_) => RetentionPolicy::default(),
+    }
+}
+
+// A duration as RETENTION takes them, e.g. "90d". Unset, "forever" or one
+// that doesn't parse is None, the last reported.
+fn duration_or_none(name: &str) -> Option<u64> {
+    match std::env::var(name).map(|raw| retention::parse_duration(raw.trim())) {
+        Ok(Ok(duration)) => duration,
+        Ok(Err(e)) => {
+            eprintln!("{}: {}, leaving it unset", name, e);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+// Comma separated, e.g. "jpg, png,.gif" -> ["jpg", "png", "gif"]
+fn list_or(name: &str, default: &str) -> Vec<String> {
+    let raw = std::env::var(name).unwrap_or_else(|_| default.to_string());
+    raw.split(',')
+        .map(|item| item.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+// Like size_or, reported and the default used when it doesn't parse
+fn limits_or(name: &str, default: Limits) -> Limits {
+    match std::env::var(name).map(|raw| Limits::parse(&raw)) {
+        Ok(Ok(limits)) => limits,
+        Ok(Err(e)) => {
+            eprintln!("{}: {}, using the default", name, e);
+            default
+        }
+        Err(_) => default,
+    }
+}
+
+pub fn policy_or(name: &str, burst: f64, per_second: f64) -> BucketPolicy {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| BucketPolicy::parse(&v))
+        .unwrap_or(BucketPolicy { burst, per_second })
+}
+
+// "board/", "/board" and "/board/" all become "/board"; "" and "/" become "".
+fn normalize_base_path(raw: &str) -> String {
+    let trimmed = raw.trim().trim_matches('/');
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", trimmed)
+    }
+}
@@ -0,0 +1,225 @@
+// Counters kept in sled trees. Every change is a single atomic
+// read-modify-write, so concurrent posts can't lose increments.
+//
+// Values are stored as decimal text. Some older trees stored 8-byte
+// big-endian numbers; `decode` reads both, and a counter is rewritten as
+// text the next time it changes. Arithmetic saturates: a counter never
+// wraps past u64::MAX and never goes below zero.
+//
+// Windowed counters put a bucket number (e.g. the hour) in front of the
+// key, "{bucket:010}/{key}", so a window is one range scan and expired
+// buckets are dropped by key.
+
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree, UnabortableTransactionError};
+use sled::Tree;
+use std::convert::TryInto;
+
+pub fn decode(bytes: Option<&[u8]>) -> u64 {
+    let bytes = match bytes {
+        Some(bytes) => bytes,
+        None => return 0,
+    };
+    if !bytes.is_empty() && bytes.iter().all(u8::is_ascii_digit) {
+        return std::str::from_utf8(bytes).unwrap().parse().unwrap_or(u64::MAX);
+    }
+    bytes.try_into().map(u64::from_be_bytes).unwrap_or(0)
+}
+
+fn encode(value: u64) -> Vec<u8> {
+    value.to_string().into_bytes()
+}
+
+fn apply(value: u64, delta: i64) -> u64 {
+    if delta >= 0 {
+        value.saturating_add(delta as u64)
+    } else {
+        value.saturating_sub(delta.unsigned_abs())
+    }
+}
+
+pub fn get(tree: &Tree, key: impl AsRef<[u8]>) -> u64 {
+    decode(tree.get(key).unwrap().as_deref())
+}
+
+// Adds `delta` (which may be negative) and returns the new value.
+pub fn increment(tree: &Tree, key: impl AsRef<[u8]>, delta: i64) -> u64 {
+    let updated = tree
+        .update_and_fetch(key, |old| Some(encode(apply(decode(old), delta))))
+        .unwrap();
+    decode(updated.as_deref())
+}
+
+// `increment` for use inside a multi-tree transaction.
+pub fn increment_in(tree: &TransactionalTree, key: &str, delta: i64) -> Result<u64, UnabortableTransactionError> {
+    let value = apply(decode(tree.get(key)?.as_deref()), delta);
+    tree.insert(key, encode(value))?;
+    Ok(value)
+}
+
+// Overwrites the counter, inside a multi-tree transaction.
+pub fn set_in(tree: &TransactionalTree, key: &str, value: u64) -> Result<(), UnabortableTransactionError> {
+    tree.insert(key, encode(value))?;
+    Ok(())
+}
+
+// Adds `delta` only if the result stays within `cap`. Ok with the new
+// value, or Err with the unchanged current one.
+pub fn increment_capped(tree: &Tree, key: &str, delta: u64, cap: u64) -> Result<u64, u64> {
+    tree.transaction(|tree| -> ConflictableTransactionResult<Result<u64, u64>> {
+        let current = decode(tree.get(key)?.as_deref());
+        let value = current.saturating_add(delta);
+        if value > cap {
+            return Ok(Err(current));
+        }
+        tree.insert(key, encode(value))?;
+        Ok(Ok(value))
+    })
+    .unwrap()
+}
+
+// An empty `key` gives a bare "{bucket:010}", for board-wide windows.
+pub fn bucket_key(bucket: u64, key: &str) -> String {
+    if key.is_empty() {
+        format!("{:010}", bucket)
+    } else {
+        format!("{:010}/{}", bucket, key)
+    }
+}
+
+pub fn increment_bucket(tree: &Tree, bucket: u64, key: &str, delta: i64) -> u64 {
+    increment(tree, bucket_key(bucket, key), delta)
+}
+
+// Every (bucket, key, value) with a bucket in `from..=to`.
+pub fn buckets(tree: &Tree, from: u64, to: u64) -> Vec<(u64, String, u64)> {
+    tree.range(bucket_key(from, "")..bucket_key(to.saturating_add(1), ""))
+        .filter_map(|entry| {
+            let (key, value) = entry.unwrap();
+            let key = std::str::from_utf8(&key).ok()?;
+            let (bucket, key) = key.split_once('/').unwrap_or((key, ""));
+            Some((bucket.parse().ok()?, key.to_string(), decode(Some(&value))))
+        })
+        .collect()
+}
+
+// Drops every bucket before `bucket`. Returns how many entries went.
+pub fn expire_buckets(tree: &Tree, bucket: u64) -> usize {
+    let stale: Vec<_> = tree.range(..bucket_key(bucket, "")).keys().map(|key| key.unwrap()).collect();
+    for key in &stale {
+        tree.remove(key).unwrap();
+    }
+    stale.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sled::transaction::ConflictableTransactionError;
+
+    const TASKS: u64 = 100;
+
+    fn tree() -> Tree {
+        sled::Config::new().temporary(true).open().unwrap().open_tree("counters").unwrap()
+    }
+
+    #[test]
+    fn concurrent_increments_are_all_counted() {
+        let tree = tree();
+        std::thread::scope(|scope| {
+            for _ in 0..TASKS {
+                scope.spawn(|| {
+                    for _ in 0..10 {
+                        increment(&tree, "posts", 1);
+                    }
+                });
+            }
+        });
+        assert_eq!(get(&tree, "posts"), TASKS * 10);
+    }
+
+    #[test]
+    fn concurrent_increments_inside_transactions_are_all_counted() {
+        let tree = tree();
+        let values = std::sync::Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..TASKS {
+                scope.spawn(|| {
+                    let value = tree
+                        .transaction(|tree| Ok::<_, ConflictableTransactionError>(increment_in(tree, "replies", 1)?))
+                        .unwrap();
+                    values.lock().unwrap().push(value);
+                });
+            }
+        });
+        // Each transaction saw its own value, as a number handed out would
+        let mut values = values.into_inner().unwrap();
+        values.sort();
+        assert_eq!(values, (1..=TASKS).collect::<Vec<_>>());
+        assert_eq!(get(&tree, "replies"), TASKS);
+    }
+
+    #[test]
+    fn concurrent_capped_increments_stop_at_the_cap() {
+        let tree = tree();
+        let granted = std::sync::atomic::AtomicU64::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..TASKS {
+                scope.spawn(|| match increment_capped(&tree, "quota", 1, 37) {
+                    Ok(value) => {
+                        assert!(value <= 37);
+                        granted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    }
+                    Err(current) => assert_eq!(current, 37),
+                });
+            }
+        });
+        assert_eq!(granted.into_inner(), 37);
+        assert_eq!(get(&tree, "quota"), 37);
+        // A step that would cross the cap is refused whole
+        assert_eq!(increment_capped(&tree, "other", 30, 37), Ok(30));
+        assert_eq!(increment_capped(&tree, "other", 8, 37), Err(30));
+        assert_eq!(increment_capped(&tree, "other", 7, 37), Ok(37));
+    }
+
+    #[test]
+    fn arithmetic_saturates_at_both_ends() {
+        let tree = tree();
+        assert_eq!(increment(&tree, "down", -5), 0);
+        assert_eq!(increment(&tree, "down", 3), 3);
+        assert_eq!(increment(&tree, "down", -4), 0);
+        assert_eq!(increment(&tree, "up", i64::MAX), i64::MAX as u64);
+        assert_eq!(increment(&tree, "up", i64::MAX), u64::MAX - 1);
+        assert_eq!(increment(&tree, "up", i64::MAX), u64::MAX);
+        assert_eq!(increment(&tree, "up", 1), u64::MAX);
+        assert_eq!(increment(&tree, "up", i64::MIN), u64::MAX - (1 << 63));
+        assert_eq!(increment_capped(&tree, "capped", u64::MAX, u64::MAX), Ok(u64::MAX));
+        assert_eq!(increment_capped(&tree, "capped", 1, u64::MAX), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn old_binary_values_are_read_and_rewritten_as_text() {
+        let tree = tree();
+        tree.insert("old", &41u64.to_be_bytes()).unwrap();
+        assert_eq!(get(&tree, "old"), 41);
+        assert_eq!(increment(&tree, "old", 1), 42);
+        assert_eq!(&*tree.get("old").unwrap().unwrap(), b"42");
+        assert_eq!(decode(None), 0);
+        assert_eq!(decode(Some(b"")), 0);
+        assert_eq!(decode(Some(b"99999999999999999999999")), u64::MAX);
+        assert_eq!(decode(Some(b"junk")), 0);
+    }
+
+    #[test]
+    fn buckets_are_read_by_window_and_expire_by_key() {
+        let tree = tree();
+        for (bucket, key) in [(7, "a"), (8, "a"), (8, "b"), (9, ""), (10, "a")] {
+            increment_bucket(&tree, bucket, key, 2);
+        }
+        increment_bucket(&tree, 8, "a", 1);
+        let window = buckets(&tree, 8, 9);
+        assert_eq!(window, [(8, "a".to_string(), 3), (8, "b".to_string(), 2), (9, String::new(), 2)]);
+        assert_eq!(buckets(&tree, 10, u64::MAX), [(10, "a".to_string(), 2)]);
+        assert_eq!(expire_buckets(&tree, 9), 3);
+        assert_eq!(buckets(&tree, 0, 10).len(), 2);
+    }
+}
@@ -0,0 +1,126 @@
+// Word-level diff between two versions of a message, for the admin edit
+// history. Both texts are split into words and the runs of whitespace
+// between them and compared with similar's Myers diff; what isn't common
+// to both shows as removed or added. A diff that takes longer than
+// DEADLINE is only an approximation, so then there's no diff and the page
+// shows both versions whole instead.
+
+use similar::{ChangeTag, TextDiff};
+use std::time::{Duration, Instant};
+
+const DEADLINE: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SpanKind {
+    Same,
+    Removed,
+    Added,
+}
+
+pub struct Span {
+    pub kind: SpanKind,
+    pub text: String,
+}
+
+impl Span {
+    pub fn is_removed(&self) -> bool {
+        self.kind == SpanKind::Removed
+    }
+
+    pub fn is_added(&self) -> bool {
+        self.kind == SpanKind::Added
+    }
+}
+
+// Adjacent pieces of the same kind are merged into one span
+fn push(spans: &mut Vec<Span>, kind: SpanKind, text: &str) {
+    match spans.last_mut() {
+        Some(last) if last.kind == kind => last.text.push_str(text),
+        _ => spans.push(Span {
+            kind,
+            text: text.to_string(),
+        }),
+    }
+}
+
+// None when the two texts took too long to compare
+pub fn words(old: &str, new: &str) -> Option<Vec<Span>> {
+    let deadline = Instant::now() + DEADLINE;
+    let diff = TextDiff::configure().deadline(deadline).diff_words(old, new);
+    if Instant::now() > deadline {
+        return None;
+    }
+    let mut spans = Vec::new();
+    for change in diff.iter_all_changes() {
+        let kind = match change.tag() {
+            ChangeTag::Equal => SpanKind::Same,
+            ChangeTag::Delete => SpanKind::Removed,
+            ChangeTag::Insert => SpanKind::Added,
+        };
+        push(&mut spans, kind, change.value());
+    }
+    Some(spans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(old: &str, new: &str) -> Vec<(SpanKind, String)> {
+        words(old, new).unwrap().into_iter().map(|span| (span.kind, span.text)).collect()
+    }
+
+    // The text a side of the diff reads as
+    fn side(spans: &[(SpanKind, String)], skip: SpanKind) -> String {
+        spans.iter().filter(|(kind, _)| *kind != skip).map(|(_, text)| text.as_str()).collect()
+    }
+
+    #[test]
+    fn a_changed_word_is_removed_and_added() {
+        use SpanKind::*;
+        assert_eq!(
+            spans("the quick fox", "the slow fox"),
+            vec![(Same, "the ".into()), (Removed, "quick".into()), (Added, "slow".into()), (Same, " fox".into())]
+        );
+    }
+
+    #[test]
+    fn the_same_text_is_one_span() {
+        assert_eq!(spans("no  change\nhere", "no  change\nhere"), vec![(SpanKind::Same, "no  change\nhere".into())]);
+        assert!(words("", "").unwrap().is_empty());
+    }
+
+    #[test]
+    fn whole_texts_added_or_removed() {
+        assert_eq!(spans("", "new text"), vec![(SpanKind::Added, "new text".into())]);
+        assert_eq!(spans("old text", ""), vec![(SpanKind::Removed, "old text".into())]);
+    }
+
+    #[test]
+    fn each_side_reads_as_its_version() {
+        let old = "A reply  with\ttabs, two spaces\nand a line — or three.";
+        let new = "A longer reply with\ttabs, one space\n\nand some lines — or three!";
+        let spans = spans(old, new);
+        assert_eq!(side(&spans, SpanKind::Added), old);
+        assert_eq!(side(&spans, SpanKind::Removed), new);
+    }
+
+    #[test]
+    fn whitespace_changes_show() {
+        use SpanKind::*;
+        assert_eq!(
+            spans("a b", "a  b"),
+            vec![(Same, "a".into()), (Removed, " ".into()), (Added, "  ".into()), (Same, "b".into())]
+        );
+    }
+
+    #[test]
+    fn long_messages_still_diff() {
+        let old: Vec<String> = (0..5000).map(|i| format!("word{}", i)).collect();
+        let mut new = old.clone();
+        new[2500] = "changed".to_string();
+        let spans = spans(&old.join(" "), &new.join(" "));
+        let changed: Vec<_> = spans.iter().filter(|(kind, _)| *kind != SpanKind::Same).collect();
+        assert_eq!(changed, vec![&(SpanKind::Removed, "word2500".into()), &(SpanKind::Added, "changed".into())]);
+    }
+}
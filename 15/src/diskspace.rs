@@ -0,0 +1,104 @@
+// Stops taking uploads while the upload volume is nearly full, so files
+// don't start failing halfway through a write. Text-only posts still go
+// through. Free space is checked by the maintenance job and again by
+// save_post when the last check is more than RECHECK old; once space is
+// freed the next check turns uploads back on.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::bytesize::{self, SizeUnits};
+use crate::config::Config;
+
+const RECHECK: Duration = Duration::from_secs(30);
+
+// Where free space comes from; a stand-in can be swapped in to simulate a
+// full disk.
+pub trait SpaceProbe: Send + Sync {
+    fn available(&self, dir: &Path) -> io::Result<u64>;
+}
+
+pub struct VolumeProbe;
+
+impl SpaceProbe for VolumeProbe {
+    fn available(&self, dir: &Path) -> io::Result<u64> {
+        fs2::available_space(dir)
+    }
+}
+
+pub struct DiskGuard {
+    probe: Box<dyn SpaceProbe>,
+    dir: PathBuf,
+    min_free: u64,
+    units: SizeUnits,
+    low: AtomicBool,
+    // Bytes free at the last successful check
+    available: AtomicU64,
+    checked: Mutex<Option<Instant>>,
+}
+
+impl DiskGuard {
+    pub fn new(config: &Config, probe: Box<dyn SpaceProbe>) -> DiskGuard {
+        DiskGuard {
+            probe,
+            dir: config.upload_dir.clone(),
+            min_free: config.min_free_upload_bytes,
+            units: config.size_units,
+            low: AtomicBool::new(false),
+            available: AtomicU64::new(u64::MAX),
+            checked: Mutex::new(None),
+        }
+    }
+
+    // Probes the volume now. A probe that fails leaves the last state, so
+    // a flaky filesystem call doesn't flip uploads on and off.
+    pub fn check(&self) {
+        *self.checked.lock().unwrap() = Some(Instant::now());
+        let available = match self.probe.available(&self.dir) {
+            Ok(available) => available,
+            Err(e) => {
+                eprintln!("checking free space in {} failed: {}", self.dir.display(), e);
+                return;
+            }
+        };
+        self.available.store(available, Ordering::Relaxed);
+        let low = available < self.min_free;
+        if self.low.swap(low, Ordering::Relaxed) != low {
+            if low {
+                eprintln!("{} has {}, uploads disabled", self.dir.display(), self.describe(available));
+            } else {
+                eprintln!("{} has {} free again, uploads enabled", self.dir.display(), bytesize::format(available, self.units));
+            }
+        }
+    }
+
+    // For save_post: re-checks first if the last check is stale
+    pub fn uploads_allowed(&self) -> bool {
+        let stale = self.checked.lock().unwrap().is_none_or(|checked| checked.elapsed() >= RECHECK);
+        if stale {
+            self.check();
+        }
+        !self.is_low()
+    }
+
+    // As of the last check
+    pub fn is_low(&self) -> bool {
+        self.low.load(Ordering::Relaxed)
+    }
+
+    fn describe(&self, available: u64) -> String {
+        format!(
+            "{} free, under the {} minimum",
+            bytesize::format(available, self.units),
+            bytesize::format(self.min_free, self.units)
+        )
+    }
+
+    // For the admin pages: how low space is, None while it isn't
+    pub fn low_report(&self) -> Option<String> {
+        self.is_low().then(|| self.describe(self.available.load(Ordering::Relaxed)))
+    }
+}
@@ -0,0 +1,146 @@
+// Moderator edits to post messages, and the history behind them. Every
+// version of an edited message is kept in `post_edits` as
+// "{post id}/{version:020}", version 0 being the message as posted, saved
+// on the first edit. The post record itself only holds the latest text.
+// Restoring an old version is recorded as a new version, so the history
+// only ever grows until the post is deleted.
+//
+// `edit_versions` counts each edited post's versions. The next version
+// number is taken from it in the same transaction that writes the version
+// and the new message, with its backlinks and thread version (see
+// changes::edit_message), so two edits can't take the same number and an
+// edit can't outlive a delete that commits first. A history kept before
+// the count was is counted from `post_edits` on its next edit.
+
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionalTree};
+use sled::{Db, IVec};
+use std::time::SystemTime;
+
+use crate::{changes, counters, Post};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Version {
+    pub version: u64,
+    pub message: String,
+    pub timestamp: u64,
+    // None for the message as posted
+    pub edited_by: Option<String>,
+    pub restored_from: Option<u64>,
+}
+
+fn version_key(post_id: &str, version: u64) -> String {
+    format!("{}/{:020}", post_id, version)
+}
+
+// The versions kept for the post, oldest first. Empty if it was never
+// edited.
+pub fn stored(db: &Db, post_id: &str) -> Vec<Version> {
+    db.open_tree("post_edits")
+        .unwrap()
+        .scan_prefix(format!("{}/", post_id))
+        .values()
+        .filter_map(|bytes| serde_json::from_slice(&bytes.unwrap()).ok())
+        .collect()
+}
+
+// The post's versions as stored, for moving its history to another
+// database with `restore`, see archive_db.rs
+pub fn raw(db: &Db, post_id: &str) -> Vec<(IVec, IVec)> {
+    db.open_tree("post_edits")
+        .unwrap()
+        .scan_prefix(format!("{}/", post_id))
+        .map(|entry| entry.unwrap())
+        .collect()
+}
+
+// Its count in `edit_versions` isn't copied; the next edit there counts
+// the versions instead
+pub fn restore(db: &Db, entries: &[(IVec, IVec)]) {
+    let tree = db.open_tree("post_edits").unwrap();
+    for (key, value) in entries {
+        tree.insert(key, value).unwrap();
+    }
+}
+
+// Oldest first. A post that was never edited has just its message as
+// version 0.
+pub fn versions(db: &Db, post: &Post) -> Vec<Version> {
+    let stored = stored(db, &post.id);
+    if !stored.is_empty() {
+        return stored;
+    }
+    vec![Version {
+        version: 0,
+        message: post.message.clone(),
+        timestamp: post.timestamp,
+        edited_by: None,
+        restored_from: None,
+    }]
+}
+
+// Who made an edit, and which version it brought back if it was a restore
+pub struct Edit<'a> {
+    pub editor: &'a str,
+    pub restored_from: Option<u64>,
+}
+
+// Replaces the post's message, recording the new version. Returns the
+// updated post, or None if there's no such post.
+pub fn edit(db: &Db, post_id: &str, message: &str, editor: &str, restored_from: Option<u64>) -> Option<Post> {
+    changes::edit_message(db, post_id, message, &Edit { editor, restored_from })
+}
+
+// How many versions are stored, for a history from before `edit_versions`
+pub fn stored_count(db: &Db, post_id: &str) -> u64 {
+    stored(db, post_id).last().map(|latest| latest.version + 1).unwrap_or(0)
+}
+
+// Records `message` as the post's next version inside the edit's
+// transaction, first saving the message as posted if this is its first
+// edit. `stored` is stored_count from before the transaction.
+pub fn record_in<E>(
+    versions: &TransactionalTree,
+    counts: &TransactionalTree,
+    post: &Post,
+    message: &str,
+    edit: &Edit,
+    stored: u64,
+) -> Result<(), ConflictableTransactionError<E>> {
+    let mut next = counters::decode(counts.get(post.id.as_bytes())?.as_deref());
+    if next == 0 {
+        next = stored;
+    }
+    if next == 0 {
+        let original = Version {
+            version: 0,
+            message: post.message.clone(),
+            timestamp: post.timestamp,
+            edited_by: None,
+            restored_from: None,
+        };
+        versions.insert(version_key(&post.id, 0).as_bytes(), serde_json::to_vec(&original).unwrap())?;
+        next = 1;
+    }
+    let version = Version {
+        version: next,
+        message: message.to_string(),
+        timestamp: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
+        edited_by: Some(edit.editor.to_string()),
+        restored_from: edit.restored_from,
+    };
+    versions.insert(version_key(&post.id, next).as_bytes(), serde_json::to_vec(&version).unwrap())?;
+    counters::set_in(counts, &post.id, next + 1)?;
+    Ok(())
+}
+
+// Returns how many versions were removed.
+pub fn forget(db: &Db, post_id: &str) -> usize {
+    let tree = db.open_tree("post_edits").unwrap();
+    let keys: Vec<_> = tree.scan_prefix(format!("{}/", post_id)).keys().map(|key| key.unwrap()).collect();
+    for key in &keys {
+        tree.remove(key).unwrap();
+    }
+    db.open_tree("edit_versions").unwrap().remove(post_id).unwrap();
+    keys.len()
+}
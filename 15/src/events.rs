@@ -0,0 +1,169 @@
+// Things that happen to posts, for the features that react to them:
+// webhooks, reply notifications, watch patterns, the duplicate image
+// check, index previews and the board change log. Handlers register once at startup; the code that stores, edits or
+// deletes posts publishes one BoardEvent after the change is committed and
+// doesn't need to know who's listening.
+//
+// A cheap handler runs inline, before publish returns. A slow one says so
+// with Delivery::Queued and runs on the event thread, fed by a bounded
+// queue; when the queue is full the event is dropped for those handlers
+// and logged. A handler that fails or panics is logged and counted and
+// never stops the others. The index trees aren't handlers: they're written
+// with the post, so listings never disagree with the main tree.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+
+use crate::Post;
+
+const QUEUE_SIZE: usize = 256;
+
+pub enum BoardEvent {
+    // A post went up on the board, from a poster or out of the approval
+    // queue. `spam_checks` is false for exempt posters, admins and approved
+    // posts, which a moderator has already vouched for.
+    Created { post: Box<Post>, spam_checks: bool },
+    // A moderator changed a post's message, took down its file or set a
+    // thread's slow mode
+    Edited { post_id: String },
+    // Posts removed, a thread's replies included
+    Deleted { posts: Vec<Post> },
+}
+
+impl BoardEvent {
+    fn describe(&self) -> String {
+        match self {
+            BoardEvent::Created { post, .. } => format!("creation of {}", post.id),
+            BoardEvent::Edited { post_id } => format!("edit of {}", post_id),
+            BoardEvent::Deleted { posts } => format!("deletion of {} posts", posts.len()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Inline,
+    Queued,
+}
+
+pub trait EventHandler: Send + Sync {
+    // For logs and the counts at /readyz
+    fn name(&self) -> &'static str;
+
+    fn delivery(&self) -> Delivery {
+        Delivery::Inline
+    }
+
+    fn handle(&self, event: &BoardEvent) -> Result<(), String>;
+}
+
+#[derive(Serialize)]
+pub struct HandlerCounts {
+    handled: u64,
+    failed: u64,
+    dropped: u64,
+}
+
+#[derive(Default)]
+struct Counts {
+    handled: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+struct Subscriber {
+    handler: Box<dyn EventHandler>,
+    counts: Counts,
+}
+
+impl Subscriber {
+    fn run(&self, event: &BoardEvent) {
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| self.handler.handle(event)))
+            .unwrap_or_else(|_| Err("panicked".to_string()));
+        match outcome {
+            Ok(()) => self.counts.handled.fetch_add(1, Ordering::Relaxed),
+            Err(e) => {
+                eprintln!("event handler {} failed on the {}: {}", self.handler.name(), event.describe(), e);
+                self.counts.failed.fetch_add(1, Ordering::Relaxed)
+            }
+        };
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    subscribers: Arc<Vec<Subscriber>>,
+    // None when no handler is queued
+    queue: Option<SyncSender<Arc<BoardEvent>>>,
+}
+
+impl EventBus {
+    pub fn start(handlers: Vec<Box<dyn EventHandler>>) -> EventBus {
+        let subscribers: Arc<Vec<Subscriber>> = Arc::new(
+            handlers
+                .into_iter()
+                .map(|handler| Subscriber {
+                    handler,
+                    counts: Counts::default(),
+                })
+                .collect(),
+        );
+        let queue = subscribers.iter().any(|s| s.handler.delivery() == Delivery::Queued).then(|| {
+            let (queue, events) = mpsc::sync_channel::<Arc<BoardEvent>>(QUEUE_SIZE);
+            let subscribers = subscribers.clone();
+            std::thread::spawn(move || {
+                for event in events {
+                    for subscriber in queued(&subscribers) {
+                        subscriber.run(&event);
+                    }
+                }
+            });
+            queue
+        });
+        EventBus { subscribers, queue }
+    }
+
+    // Call once the change is committed, exactly once per change
+    pub fn publish(&self, event: BoardEvent) {
+        for subscriber in self.subscribers.iter().filter(|s| s.handler.delivery() == Delivery::Inline) {
+            subscriber.run(&event);
+        }
+        let queue = match &self.queue {
+            Some(queue) => queue,
+            None => return,
+        };
+        let event = Arc::new(event);
+        let reason = match queue.try_send(event.clone()) {
+            Ok(()) => return,
+            Err(TrySendError::Full(_)) => "the event queue is full",
+            Err(TrySendError::Disconnected(_)) => "the event thread stopped",
+        };
+        for subscriber in queued(&self.subscribers) {
+            eprintln!("{}, {} skips the {}", reason, subscriber.handler.name(), event.describe());
+            subscriber.counts.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Per handler, since startup
+    pub fn counts(&self) -> BTreeMap<&'static str, HandlerCounts> {
+        self.subscribers
+            .iter()
+            .map(|s| {
+                let counts = HandlerCounts {
+                    handled: s.counts.handled.load(Ordering::Relaxed),
+                    failed: s.counts.failed.load(Ordering::Relaxed),
+                    dropped: s.counts.dropped.load(Ordering::Relaxed),
+                };
+                (s.handler.name(), counts)
+            })
+            .collect()
+    }
+}
+
+fn queued(subscribers: &[Subscriber]) -> impl Iterator<Item = &Subscriber> {
+    subscribers.iter().filter(|s| s.handler.delivery() == Delivery::Queued)
+}
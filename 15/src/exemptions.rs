@@ -0,0 +1,97 @@
+// Posters let off the automatic limits, for schools, offices and other
+// networks where many people share one address and so one ip hash. An
+// exempt hash skips the posting rate limit, the hourly quota, the spam
+// checks and duplicate image flagging; bans still apply. Entries live in
+// the `exemptions` tree keyed by ip hash and stop counting at their expiry,
+// after which the maintenance job removes them.
+//
+// Every post asks, so the set is kept in memory and reloaded after each
+// change made through ExemptionCache.
+
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Exemption {
+    pub note: String,
+    // None for exemptions that don't expire
+    pub expires_at: Option<u64>,
+    pub added_by: String,
+    pub added_at: u64,
+}
+
+impl Exemption {
+    fn is_live(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+}
+
+// ip hash to expiry
+type Live = HashMap<String, Option<u64>>;
+
+#[derive(Default)]
+pub struct ExemptionCache {
+    current: RwLock<Option<Arc<Live>>>,
+}
+
+impl ExemptionCache {
+    pub fn is_exempt(&self, db: &Db, ip_hash: &str, now: u64) -> bool {
+        self.live(db).get(ip_hash).is_some_and(|expires_at| expires_at.is_none_or(|expires_at| now < expires_at))
+    }
+
+    fn live(&self, db: &Db) -> Arc<Live> {
+        if let Some(live) = self.current.read().unwrap().as_ref() {
+            return live.clone();
+        }
+        let live: Live = list(db).into_iter().map(|(ip_hash, exemption)| (ip_hash, exemption.expires_at)).collect();
+        let live = Arc::new(live);
+        *self.current.write().unwrap() = Some(live.clone());
+        live
+    }
+
+    // Replaces any earlier exemption for the same hash
+    pub fn add(&self, db: &Db, ip_hash: &str, exemption: &Exemption) {
+        let mut current = self.current.write().unwrap();
+        let tree = db.open_tree("exemptions").unwrap();
+        tree.insert(ip_hash, serde_json::to_vec(exemption).unwrap()).unwrap();
+        tree.flush().unwrap();
+        *current = None;
+    }
+
+    // Whether there was one to remove
+    pub fn remove(&self, db: &Db, ip_hash: &str) -> bool {
+        let mut current = self.current.write().unwrap();
+        let removed = db.open_tree("exemptions").unwrap().remove(ip_hash).unwrap();
+        *current = None;
+        removed.is_some()
+    }
+}
+
+// Every stored exemption, expired ones included, by ip hash
+pub fn list(db: &Db) -> Vec<(String, Exemption)> {
+    db.open_tree("exemptions")
+        .unwrap()
+        .iter()
+        .filter_map(|item| {
+            let (key, value) = item.unwrap();
+            let exemption = serde_json::from_slice(&value).ok()?;
+            Some((String::from_utf8_lossy(&key).into_owned(), exemption))
+        })
+        .collect()
+}
+
+// Drops expired exemptions from the tree; the cache already ignores them.
+// Returns how many entries were removed.
+pub fn prune(db: &Db, now: u64) -> usize {
+    let tree = db.open_tree("exemptions").unwrap();
+    let mut removed = 0;
+    for (ip_hash, exemption) in list(db) {
+        if !exemption.is_live(now) {
+            tree.remove(ip_hash).unwrap();
+            removed += 1;
+        }
+    }
+    removed
+}
@@ -0,0 +1,145 @@
+// Incremental backups: GET /admin/export/stream sends the board log (see
+// changes.rs) as newline-delimited JSON, one record per change,
+//
+//   {"sequence":41,"at":1718000000123,"kind":"added","post_id":"...","thread_id":null,"post":{...}}
+//
+// where `post` is the stored record as it is now, left out for deletions
+// and for posts deleted since. The last line is a summary,
+//
+//   {"summary":{"records":3,"added":2,"edited":0,"deleted":1,"last_sequence":43,"complete":true}}
+//
+// and passing its last_sequence as `after` next time picks up where this
+// left off. `since` (milliseconds since the epoch) starts from a time
+// instead. `complete` is false when entries the client asked for have
+// already been dropped from the log; take a full backup then.
+//
+// Records are read BATCH_SIZE at a time, and the next batch only when the
+// connection has taken the last one, so a slow client holds no more than
+// that in memory. The stream stops at the sequence that was last when it
+// started. It downloads as "backup to 43.ndjson", after the last sequence.
+
+use actix_web::web::{self, Bytes};
+use actix_web::{Error, HttpResponse};
+use futures_util::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use sled::Db;
+
+use crate::admin::Admin;
+use crate::audit;
+use crate::changes::{self, BoardChange, ChangeKind};
+use crate::filename;
+
+const BATCH_SIZE: usize = 200;
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    // Milliseconds since the epoch
+    since: Option<u64>,
+    // A sequence from an earlier summary; wins over `since`
+    after: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ExportRecord<'a> {
+    #[serde(flatten)]
+    change: &'a BoardChange,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Default)]
+struct Summary {
+    records: u64,
+    added: u64,
+    edited: u64,
+    deleted: u64,
+    last_sequence: u64,
+    complete: bool,
+}
+
+struct ExportScan {
+    db: web::Data<Db>,
+    since: u64,
+    // Last sequence sent or skipped
+    after: u64,
+    last: u64,
+    summary: Option<Summary>,
+}
+
+impl ExportScan {
+    // The next batch of lines, then the summary, then None
+    fn next_chunk(&mut self) -> Option<String> {
+        let summary = self.summary.as_mut()?;
+        let batch = changes::board_changes(&self.db, self.after, self.last, BATCH_SIZE);
+        if batch.is_empty() {
+            let summary = self.summary.take()?;
+            let line = serde_json::json!({ "summary": summary });
+            return Some(format!("{}\n", line));
+        }
+        let mut lines = String::new();
+        for change in &batch {
+            self.after = change.sequence;
+            if change.at < self.since {
+                continue;
+            }
+            let post = match change.kind {
+                ChangeKind::Added | ChangeKind::Edited => self
+                    .db
+                    .get(&change.post_id)
+                    .unwrap()
+                    .and_then(|raw| serde_json::from_slice(&raw).ok()),
+                ChangeKind::Deleted => None,
+            };
+            let record = ExportRecord { change, post };
+            lines.push_str(&serde_json::to_string(&record).unwrap());
+            lines.push('\n');
+            summary.records += 1;
+            match change.kind {
+                ChangeKind::Added => summary.added += 1,
+                ChangeKind::Edited => summary.edited += 1,
+                ChangeKind::Deleted => summary.deleted += 1,
+            }
+        }
+        Some(lines)
+    }
+}
+
+pub async fn stream(db: web::Data<Db>, admin: Admin, query: web::Query<ExportQuery>) -> HttpResponse {
+    let last = changes::board_sequence(&db);
+    let oldest = changes::oldest_board_change(&db).unwrap_or(last + 1);
+    let (after, since, from) = match (query.after, query.since) {
+        (Some(after), _) => (after, 0, format!("after {}", after)),
+        (None, Some(since)) => (0, since, format!("since {}", since)),
+        (None, None) => (0, 0, "everything kept".to_string()),
+    };
+    if after > last {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "after is ahead of the log" }));
+    }
+    // Entries before `oldest` have been dropped. With `since` they only
+    // matter if they might be newer than it, which the oldest kept entry
+    // rules out when it's older.
+    let complete = match query.after {
+        Some(after) => after + 1 >= oldest,
+        None if oldest <= 1 => true,
+        None => changes::board_changes(&db, oldest - 1, oldest, 1).first().is_some_and(|first| first.at < since),
+    };
+    audit::record(&db, &admin.name, "export_stream", &from);
+    let scan = ExportScan {
+        db,
+        since,
+        after,
+        last,
+        summary: Some(Summary {
+            last_sequence: last,
+            complete,
+            ..Summary::default()
+        }),
+    };
+    let body = stream::unfold(scan, |mut scan| async move { scan.next_chunk().map(|chunk| (chunk, scan)) })
+        .map(|chunk| Ok::<_, Error>(Bytes::from(chunk)));
+    let name = filename::suggest_filename(&format!("backup to {}", last), "ndjson");
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .append_header(("Content-Disposition", name.disposition()))
+        .streaming(body)
+}
@@ -0,0 +1,158 @@
+// Names suggested for downloads, in Content-Disposition and inside zips.
+// Titles and upload names can hold anything: characters some file system
+// refuses (/ \ : * ? " < > |), controls, names Windows keeps for devices
+// (CON, NUL, COM1...), trailing dots and spaces, and any length. These
+// are replaced or dropped, and the name is kept to MAX_BYTES, cut between
+// graphemes so an emoji or accented letter is never split.
+//
+// A header carries the name twice, per RFC 6266 and RFC 5987: a quoted
+// ASCII `filename` for old clients, with everything else replaced by '_',
+// and `filename*` with the exact name percent-encoded as UTF-8. A Batch
+// hands out the names for several files in one download, adding "-2",
+// "-3"... where two would clash.
+
+use std::collections::HashSet;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::validation;
+
+// Extension included
+const MAX_BYTES: usize = 80;
+const MAX_EXTENSION_CHARS: usize = 10;
+// For a name with nothing usable left in it
+const DEFAULT_STEM: &str = "download";
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+pub struct SuggestedName {
+    // What the file should be called, at most MAX_BYTES
+    pub name: String,
+    // The same in ASCII, for clients that don't read `filename*`
+    pub ascii: String,
+}
+
+impl SuggestedName {
+    pub fn disposition(&self) -> String {
+        format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", self.ascii, percent_encode(&self.name))
+    }
+}
+
+pub fn suggest_filename(title: &str, extension: &str) -> SuggestedName {
+    suggest(title, extension, None)
+}
+
+// "photo.tar.gz" as "photo.tar" and "gz", for names that come with their
+// extension
+pub fn suggest_for(name: &str) -> SuggestedName {
+    match name.rsplit_once('.') {
+        Some((stem, extension)) => suggest_filename(stem, extension),
+        None => suggest_filename(name, ""),
+    }
+}
+
+// Names for the files of one download, none the same as another, as most
+// file systems compare them: without regard to case
+#[derive(Default)]
+pub struct Batch {
+    taken: HashSet<String>,
+}
+
+impl Batch {
+    pub fn suggest(&mut self, title: &str, extension: &str) -> SuggestedName {
+        let mut suggested = suggest(title, extension, None);
+        let mut next = 2;
+        while !self.taken.insert(suggested.name.to_lowercase()) {
+            suggested = suggest(title, extension, Some(next));
+            next += 1;
+        }
+        suggested
+    }
+}
+
+fn suggest(title: &str, extension: &str, suffix: Option<u32>) -> SuggestedName {
+    let extension: String = extension
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .take(MAX_EXTENSION_CHARS)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    let mut tail = suffix.map(|n| format!("-{}", n)).unwrap_or_default();
+    if !extension.is_empty() {
+        tail = format!("{}.{}", tail, extension);
+    }
+    let room = MAX_BYTES - tail.len();
+    let stem = clean(title);
+    SuggestedName {
+        name: format!("{}{}", truncate(&stem, room), tail),
+        ascii: format!("{}{}", truncate(&ascii(&stem), room), tail),
+    }
+}
+
+fn clean(title: &str) -> String {
+    let replaced: String = validation::strip_format_chars(title)
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() || c.is_whitespace() => ' ',
+            c => c,
+        })
+        .collect();
+    let stem = replaced.split(' ').filter(|word| !word.is_empty()).collect::<Vec<_>>().join(" ");
+    let stem = stem.trim_matches(['.', ' ']);
+    if stem.is_empty() {
+        return DEFAULT_STEM.to_string();
+    }
+    // "CON" is the console on Windows, and so is "CON.tar"
+    let device = stem.split('.').next().unwrap_or_default().to_ascii_uppercase();
+    if RESERVED.contains(&device.as_str()) {
+        return format!("_{}", stem);
+    }
+    stem.to_string()
+}
+
+// Each grapheme that isn't plain ASCII as one '_', with runs of them as one
+fn ascii(stem: &str) -> String {
+    let mut out = String::with_capacity(stem.len());
+    for grapheme in stem.graphemes(true) {
+        let plain = grapheme.chars().all(|c| c.is_ascii() && !c.is_ascii_control() && !matches!(c, ';' | '%'));
+        if plain {
+            out.push_str(grapheme);
+        } else if !out.ends_with('_') {
+            out.push('_');
+        }
+    }
+    if !out.chars().any(|c| c.is_ascii_alphanumeric()) {
+        return DEFAULT_STEM.to_string();
+    }
+    out
+}
+
+// The longest run of whole graphemes from the start that fits in
+// `max_bytes`, without dots or spaces left at the end
+fn truncate(stem: &str, max_bytes: usize) -> &str {
+    let mut end = 0;
+    for (at, grapheme) in stem.grapheme_indices(true) {
+        if at + grapheme.len() > max_bytes {
+            break;
+        }
+        end = at + grapheme.len();
+    }
+    match stem[..end].trim_end_matches(['.', ' ']) {
+        "" => DEFAULT_STEM,
+        cut => cut,
+    }
+}
+
+fn percent_encode(name: &str) -> String {
+    name.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,289 @@
+// Message formatting: turns a raw stored message into HTML that is safe to
+// emit with `|safe` in the templates, or into plain text for places that
+// can't take HTML. Both start from the same split of the message into
+// pieces, so a link or a quote is one to both or to neither.
+
+use std::ops::Range;
+
+const MAX_LINK_TEXT: usize = 60;
+// Runs of non-whitespace longer than this get a <wbr> every this many
+// characters, so one enormous word can't push the page sideways
+const MAX_UNBROKEN_RUN: usize = 80;
+
+pub fn escape_html(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// Thread titles are cut to these where space is tight: on cards such as
+// the archive's and the widget's, and in a page's <title>. Cut titles keep
+// the full one in a title attribute; the thread page shows it whole.
+pub const CARD_TITLE_CHARS: usize = 30;
+pub const PAGE_TITLE_CHARS: usize = 60;
+
+// Cuts `text` to at most `max` characters, marking the cut with an ellipsis.
+// The cut backs off rather than split an emoji sequence or a letter from
+// its accent.
+pub fn truncate_chars(text: &str, max: usize) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.len() <= max {
+        return text.to_string();
+    }
+    let mut end = max.saturating_sub(1);
+    while end > 0 && (is_continuation(chars[end]) || chars[end - 1] == '\u{200D}') {
+        end -= 1;
+    }
+    // Flags are pairs of regional indicators
+    let indicators = chars[..end].iter().rev().take_while(|&&c| is_regional_indicator(c)).count();
+    if indicators % 2 == 1 && is_regional_indicator(chars[end]) {
+        end -= 1;
+    }
+    let mut cut: String = chars[..end].iter().collect();
+    cut.push('…');
+    cut
+}
+
+// Characters that belong to the one before them: combining accents,
+// variation selectors, the zero width joiner, skin tones and tag characters
+fn is_continuation(c: char) -> bool {
+    matches!(c, '\u{0300}'..='\u{036F}' | '\u{FE00}'..='\u{FE0F}' | '\u{200D}' | '\u{1F3FB}'..='\u{1F3FF}' | '\u{E0020}'..='\u{E007F}')
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+// 2700 -> "45 min", 18000 -> "5 h", 259200 -> "3 d"
+pub fn human_duration(secs: u64) -> String {
+    const MINUTE: u64 = 60;
+    const HOUR: u64 = 60 * MINUTE;
+    if secs < HOUR {
+        format!("{} min", secs.div_ceil(MINUTE).max(1))
+    } else if secs < 48 * HOUR {
+        format!("{} h", secs / HOUR)
+    } else {
+        format!("{} d", secs / (24 * HOUR))
+    }
+}
+
+// Year, month and day (UTC) of a time in seconds since the epoch. Worked
+// out from the day count (Howard Hinnant's civil_from_days), since nothing
+// here needs a full calendar.
+pub fn civil_date(timestamp: u64) -> (i64, u32, u32) {
+    let days = (timestamp / 86_400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = (if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 }) as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// A message cut up at its links and quotes, as byte ranges covering all of
+// it in order
+enum Piece {
+    Text(Range<usize>),
+    Link(Range<usize>),
+    // ">>12", with the number
+    Quote(Range<usize>, u64),
+}
+
+impl Piece {
+    fn range(&self) -> &Range<usize> {
+        match self {
+            Piece::Text(range) | Piece::Link(range) | Piece::Quote(range, _) => range,
+        }
+    }
+}
+
+fn pieces(message: &str) -> Vec<Piece> {
+    let mut marked: Vec<Piece> = find_urls(message).into_iter().map(Piece::Link).collect();
+    // A URL ends at '>', so no quote is ever inside one
+    marked.extend(quote_ranges(message).into_iter().map(|(range, number)| Piece::Quote(range, number)));
+    marked.sort_by_key(|piece| piece.range().start);
+
+    let mut pieces = Vec::with_capacity(marked.len() * 2 + 1);
+    let mut last = 0;
+    for piece in marked {
+        let range = piece.range().clone();
+        if range.start > last {
+            pieces.push(Piece::Text(last..range.start));
+        }
+        last = range.end;
+        pieces.push(piece);
+    }
+    if last < message.len() {
+        pieces.push(Piece::Text(last..message.len()));
+    }
+    pieces
+}
+
+// Quotes aren't marked up on the page, so they're escaped along with the
+// text around them
+pub fn format_message(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    let mut last = 0;
+
+    for piece in pieces(message) {
+        if let Piece::Link(url) = piece {
+            out.push_str(&escape_with_breaks(&message[last..url.start]));
+            out.push_str(&render_link(&message[url.clone()]));
+            last = url.end;
+        }
+    }
+    out.push_str(&escape_with_breaks(&message[last..]));
+
+    out
+}
+
+// The message as plain text, for webhook payloads and excerpts: links as
+// they were written, quotes as "»12", greentext and every other line as
+// is, and control characters other than newlines and tabs dropped. Cut to
+// `max` characters like truncate_chars. Nothing in it is escaped; that's
+// up to wherever it goes.
+pub fn format_message_plain(message: &str, max: usize) -> String {
+    let mut out = String::with_capacity(message.len());
+    for piece in pieces(message) {
+        match piece {
+            Piece::Text(range) => out.extend(message[range].chars().filter(|&c| !c.is_control() || c == '\n' || c == '\t')),
+            Piece::Link(range) => out.push_str(&message[range]),
+            Piece::Quote(_, number) => out.push_str(&format!("»{}", number)),
+        }
+    }
+    truncate_chars(out.trim_end(), max)
+}
+
+// Escapes `text`, adding break opportunities inside long runs. Counting is
+// done on the raw characters, so a break can never land inside a multi-byte
+// character or an entity like &amp;.
+fn escape_with_breaks(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut run = 0;
+    let mut buf = [0u8; 4];
+    for c in text.chars() {
+        if c.is_whitespace() {
+            run = 0;
+        } else {
+            if run == MAX_UNBROKEN_RUN {
+                out.push_str("<wbr>");
+                run = 0;
+            }
+            run += 1;
+        }
+        out.push_str(&escape_html(c.encode_utf8(&mut buf)));
+    }
+    out
+}
+
+// Reply numbers quoted as ">>12", in order of first appearance, without
+// repeats. A third '>' or a letter or digit straight before the token means
+// it isn't one, and so does a number too long to be a reply's.
+pub fn find_quotes(text: &str) -> Vec<u64> {
+    let mut numbers = Vec::new();
+    for (_, number) in quote_ranges(text) {
+        if !numbers.contains(&number) {
+            numbers.push(number);
+        }
+    }
+    numbers
+}
+
+// Every quote in `text` with its byte range, repeats included
+fn quote_ranges(text: &str) -> Vec<(Range<usize>, u64)> {
+    let mut quotes = Vec::new();
+    let mut offset = 0;
+    while let Some(at) = text[offset..].find(">>").map(|i| offset + i) {
+        offset = at + 2;
+        let preceded = text[..at].chars().next_back().is_some_and(|c| c == '>' || c.is_alphanumeric());
+        let digits = text[offset..].find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len() - offset);
+        let followed = text[offset + digits..].chars().next().is_some_and(char::is_alphanumeric);
+        if preceded || digits == 0 || followed {
+            continue;
+        }
+        if let Ok(number) = text[offset..offset + digits].parse::<u64>() {
+            quotes.push((at..offset + digits, number));
+        }
+        offset += digits;
+    }
+    quotes
+}
+
+// Byte ranges of every linkable URL in `text`, in order.
+pub fn find_urls(text: &str) -> Vec<Range<usize>> {
+    let mut urls = Vec::new();
+    let mut offset = 0;
+
+    while let Some(start) = find_url_start(&text[offset..]).map(|i| offset + i) {
+        let len = url_len(&text[start..]);
+        if len == 0 {
+            // Just a scheme with nothing after it, leave it as text
+            offset = start + text[start..].find("//").unwrap() + 2;
+            continue;
+        }
+        urls.push(start..start + len);
+        offset = start + len;
+    }
+
+    urls
+}
+
+// Only http and https are ever linked, the scheme whitelist is what keeps
+// javascript: and friends out of href attributes.
+fn find_url_start(text: &str) -> Option<usize> {
+    let lower = text.to_ascii_lowercase();
+    let mut offset = 0;
+    while let Some(pos) = lower[offset..].find("http") {
+        let at = offset + pos;
+        let tail = &lower[at..];
+        let preceded_by_word = lower[..at]
+            .chars()
+            .next_back()
+            .map(|c| c.is_alphanumeric())
+            .unwrap_or(false);
+        if !preceded_by_word && (tail.starts_with("http://") || tail.starts_with("https://")) {
+            return Some(at);
+        }
+        offset = at + 4;
+    }
+    None
+}
+
+// Length of the URL starting at the beginning of `text`, or 0 if there is
+// nothing after the scheme.
+fn url_len(text: &str) -> usize {
+    let scheme_len = text.find("//").unwrap() + 2;
+    let body_end = text[scheme_len..]
+        .find(|c: char| c.is_whitespace() || c.is_control() || matches!(c, '<' | '>' | '"' | '\'' | '`'))
+        .map(|i| scheme_len + i)
+        .unwrap_or(text.len());
+
+    // Trailing punctuation is almost always part of the sentence, not the URL
+    let trimmed = text[..body_end].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']']);
+    if trimmed.len() <= scheme_len {
+        0
+    } else {
+        trimmed.len()
+    }
+}
+
+fn render_link(url: &str) -> String {
+    let display = truncate_chars(url, MAX_LINK_TEXT);
+    format!(
+        "<a href=\"{}\" rel=\"nofollow noopener\" target=\"_blank\">{}</a>",
+        escape_html(url),
+        escape_html(&display)
+    )
+}
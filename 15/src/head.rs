@@ -0,0 +1,107 @@
+// HEAD for every GET route, for monitoring tools and link checkers.
+//
+// The request is routed as a GET, so it gets exactly the headers a GET
+// would, then the body is swapped for an empty one that reports the same
+// size. That keeps Content-Length (or chunked encoding, for the streamed
+// index) the same as for GET without producing the body: the index never
+// scans its threads and uploads are never read from disk. Pages rendered in
+// one piece are still rendered once, since their length is the rendered
+// length.
+//
+// actix-http decides from the method it parsed whether to write a body, so
+// the rewritten method doesn't make it send one.
+//
+// GET routes that change something, like an unsubscribe link, are guarded
+// with NotHead, so a link checker's HEAD gets a 405 instead of running them.
+
+use actix_web::body::{BodySize, EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::guard::{Guard, GuardContext};
+use actix_web::http::Method;
+use actix_web::HttpMessage;
+use actix_web::web::Bytes;
+use actix_web::Error;
+use futures_util::future::LocalBoxFuture;
+use std::future::{ready, Ready};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Claims the size of the body it replaced but has nothing in it
+pub struct HeadBody {
+    size: BodySize,
+}
+
+impl MessageBody for HeadBody {
+    type Error = Error;
+
+    fn size(&self) -> BodySize {
+        self.size
+    }
+
+    fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Poll::Ready(None)
+    }
+}
+
+// Left on a request that came in as HEAD
+struct WasHead;
+
+// Passes only requests that really were GETs
+pub struct NotHead;
+
+impl Guard for NotHead {
+    fn check(&self, ctx: &GuardContext<'_>) -> bool {
+        ctx.req_data().get::<WasHead>().is_none()
+    }
+}
+
+pub struct HeadRequests;
+
+impl<S, B> Transform<S, ServiceRequest> for HeadRequests
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B, HeadBody>>;
+    type Error = Error;
+    type Transform = HeadRequestsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(HeadRequestsMiddleware { service }))
+    }
+}
+
+pub struct HeadRequestsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for HeadRequestsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B, HeadBody>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let head = req.method() == Method::HEAD;
+        if head {
+            req.head_mut().method = Method::GET;
+            req.extensions_mut().insert(WasHead);
+        }
+        let response = self.service.call(req);
+        Box::pin(async move {
+            let response = response.await?;
+            if head {
+                Ok(response.map_body(|_, body| EitherBody::right(HeadBody { size: body.size() })))
+            } else {
+                Ok(response.map_into_left_body())
+            }
+        })
+    }
+}
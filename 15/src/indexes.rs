@@ -0,0 +1,438 @@
+// Secondary indexes over the main tree, so listings can range-scan instead
+// of reading every post:
+//
+//   bumps      "{bump time:020}/{thread id}"           threads by last bump
+//   creations  "{creation time:020}/{thread id}"       threads by when they were started
+//   posted     "{post time:020}/{post id}"             every post, threads as started, for /admin/posts
+//   replies    "{thread id}/{time:020}/{reply id}"     replies in post order
+//   stickies   "{thread id}" -> sticky order (u32 BE)   threads pinned to the index
+//   numbers    "{thread id}/{reply number:020}"        reply ids by their number
+//
+// Bumps race with each other, so the bumps index can briefly hold an old
+// key for a thread. Readers check the key against the stored post and drop
+// entries that no longer match. A thread's creation time isn't kept once
+// it has been bumped, so `remove` can't find its creations key; readers
+// drop those when the thread turns out to be gone. Deleting a thread finds
+// its creations and posted keys with remove_started instead, so nothing
+// is left of it. Archiving takes a thread off the listings but not off
+// `posted`, since it's still in the main tree.
+//
+// Stickies are shown in their sticky order, 0 first, which admins set with
+// the up and down buttons on /admin/posts. A new sticky goes last. Entries
+// from before the order existed have an empty value and go after the rest,
+// most recently bumped first, until the next reorder numbers them. The
+// order is renumbered from 0 whenever a sticky goes, so it has no gaps.
+
+use sled::transaction::{TransactionalTree, UnabortableTransactionError};
+use sled::Db;
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::timings::{self, Op};
+use crate::{load_post, storage, Post};
+
+// Reorders read every sticky and write them all back
+static STICKY_ORDER: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+pub fn bump_key(timestamp: u64, thread_id: &str) -> String {
+    format!("{:020}/{}", timestamp, thread_id)
+}
+
+pub fn reply_key(thread_id: &str, timestamp: u64, reply_id: &str) -> String {
+    format!("{}/{:020}/{}", thread_id, timestamp, reply_id)
+}
+
+pub fn number_key(thread_id: &str, number: u64) -> String {
+    format!("{}/{:020}", thread_id, number)
+}
+
+// "{time:020}/{id}" back into its parts
+pub fn parse_bump_key(key: &str) -> Option<(u64, &str)> {
+    let (timestamp, id) = key.split_once('/')?;
+    if timestamp.len() != 20 || id.is_empty() || id.contains('/') {
+        return None;
+    }
+    Some((timestamp.parse().ok()?, id))
+}
+
+pub fn add(db: &Db, post: &Post) {
+    match &post.parent_id {
+        None => {
+            db.open_tree("creations").unwrap().insert(bump_key(post.timestamp, &post.id), &[]).unwrap();
+            db.open_tree("posted").unwrap().insert(bump_key(post.timestamp, &post.id), &[]).unwrap();
+            db.open_tree("bumps").unwrap().insert(bump_key(post.timestamp, &post.id), &[])
+        }
+        Some(thread_id) => {
+            db.open_tree("posted").unwrap().insert(bump_key(post.timestamp, &post.id), &[]).unwrap();
+            if let Some(number) = post.reply_number {
+                db.open_tree("numbers").unwrap().insert(number_key(thread_id, number), post.id.as_bytes()).unwrap();
+            }
+            db.open_tree("replies")
+                .unwrap()
+                .insert(reply_key(thread_id, post.timestamp, &post.id), &[])
+        }
+    }
+    .unwrap();
+}
+
+// A reply's entries, inside the transaction that stores it, see
+// changes::insert_reply
+pub fn add_reply_in(
+    replies: &TransactionalTree,
+    numbers: &TransactionalTree,
+    posted: &TransactionalTree,
+    thread_id: &str,
+    reply: &Post,
+    number: u64,
+) -> Result<(), UnabortableTransactionError> {
+    numbers.insert(number_key(thread_id, number).as_bytes(), reply.id.as_bytes())?;
+    replies.insert(reply_key(thread_id, reply.timestamp, &reply.id).as_bytes(), &[])?;
+    posted.insert(bump_key(reply.timestamp, &reply.id).as_bytes(), &[])?;
+    Ok(())
+}
+
+// The id of reply `number` in a thread, if it was ever handed out
+pub fn numbered_reply(db: &Db, thread_id: &str, number: u64) -> Option<String> {
+    let id = db.open_tree("numbers").unwrap().get(number_key(thread_id, number)).unwrap()?;
+    String::from_utf8(id.to_vec()).ok()
+}
+
+// numbered_reply for several numbers, in the same order
+pub fn numbered_replies(db: &Db, thread_id: &str, numbers: &[u64]) -> Vec<Option<String>> {
+    let tree = db.open_tree("numbers").unwrap();
+    numbers
+        .iter()
+        .map(|&number| {
+            let id = tree.get(number_key(thread_id, number)).unwrap()?;
+            String::from_utf8(id.to_vec()).ok()
+        })
+        .collect()
+}
+
+// Up to `limit` threads, most recently bumped first. Stale entries are
+// skipped here and left for the API listing to clean up.
+pub fn latest_threads(db: &Db, limit: usize) -> Vec<Post> {
+    timings::time(Op::Scan, "latest_threads", || scan_latest_threads(db, limit))
+}
+
+fn scan_latest_threads(db: &Db, limit: usize) -> Vec<Post> {
+    let mut threads = Vec::new();
+    for key in db.open_tree("bumps").unwrap().iter().keys().rev() {
+        if threads.len() == limit {
+            break;
+        }
+        let key = key.unwrap();
+        let (timestamp, thread_id) = match parse_bump_key(std::str::from_utf8(&key).unwrap_or_default()) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        match load_post(db, thread_id) {
+            Some(thread) if thread.timestamp == timestamp => threads.push(thread),
+            _ => continue,
+        }
+    }
+    threads
+}
+
+// One page of every post in the main tree, newest first: `limit` posts
+// after skipping `skip`, and whether there are more. Entries for posts
+// that are gone are dropped; records that don't parse are logged and
+// skipped, and keep their entries.
+pub fn latest_posts(db: &Db, skip: usize, limit: usize) -> (Vec<Post>, bool) {
+    timings::time(Op::Scan, "latest_posts", || scan_latest_posts(db, skip, limit))
+}
+
+fn scan_latest_posts(db: &Db, skip: usize, limit: usize) -> (Vec<Post>, bool) {
+    let posted = db.open_tree("posted").unwrap();
+    let mut skipped = 0;
+    let mut posts = Vec::new();
+    for key in posted.iter().keys().rev() {
+        let key = match key {
+            Ok(key) => key,
+            Err(e) => {
+                eprintln!("listing posts: skipped an index entry: {}", e);
+                continue;
+            }
+        };
+        let post_id = match parse_bump_key(std::str::from_utf8(&key).unwrap_or_default()) {
+            Some((_, post_id)) => post_id.to_string(),
+            None => continue,
+        };
+        let raw = match db.get(&post_id) {
+            Ok(Some(raw)) => raw,
+            Ok(None) => {
+                posted.remove(&key).unwrap();
+                continue;
+            }
+            Err(e) => {
+                eprintln!("listing posts: skipped post {}: {}", post_id, e);
+                continue;
+            }
+        };
+        let post = match Post::upgrade(&raw) {
+            Ok(post) => post,
+            Err(e) => {
+                eprintln!("listing posts: skipped post {}: {}", post_id, e);
+                continue;
+            }
+        };
+        if skipped < skip {
+            skipped += 1;
+        } else if posts.len() == limit {
+            return (posts, true);
+        } else {
+            posts.push(post);
+        }
+    }
+    (posts, false)
+}
+
+// A thread's replies in post order, from the replies index
+pub fn thread_replies(db: &Db, thread_id: &str) -> Vec<Post> {
+    // '0' is the byte after '/', so this covers exactly the thread's keys
+    let range = format!("{}/", thread_id)..format!("{}0", thread_id);
+    timings::time(Op::Scan, "thread_replies", || {
+        db.open_tree("replies")
+            .unwrap()
+            .range(range)
+            .keys()
+            .filter_map(|key| {
+                let key = key.unwrap();
+                let reply_id = std::str::from_utf8(&key).ok()?.rsplit('/').next()?.to_string();
+                load_post(db, &reply_id)
+            })
+            .collect()
+    })
+}
+
+pub fn bumped(db: &Db, thread_id: &str, from: u64, to: u64) {
+    if from == to {
+        return;
+    }
+    let bumps = db.open_tree("bumps").unwrap();
+    bumps.remove(bump_key(from, thread_id)).unwrap();
+    bumps.insert(bump_key(to, thread_id), &[]).unwrap();
+}
+
+pub fn set_sticky(db: &Db, thread_id: &str, sticky: bool) {
+    let tree = db.open_tree("stickies").unwrap();
+    if sticky {
+        let (_lock, order) = next_sticky(db);
+        if !tree.contains_key(thread_id).unwrap() {
+            tree.insert(thread_id, &order.to_be_bytes()).unwrap();
+        }
+    } else {
+        let _lock = STICKY_ORDER.lock().unwrap();
+        if tree.remove(thread_id).unwrap().is_some() {
+            renumber(db, &stickies(db));
+        }
+    }
+}
+
+// The order a new sticky takes, last, and the lock that keeps it free
+// until that sticky is written. For stickying a thread inside another
+// transaction, see announcement::insert.
+pub fn next_sticky(db: &Db) -> (MutexGuard<'static, ()>, u32) {
+    let lock = STICKY_ORDER.lock().unwrap();
+    let last = db.open_tree("stickies").unwrap().iter().values().filter_map(|order| sticky_order(&order.unwrap())).max();
+    (lock, last.map_or(0, |last| last.saturating_add(1)))
+}
+
+fn sticky_order(value: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(value.try_into().ok()?))
+}
+
+// Sticky threads in their order, see the top of this file. There are only
+// ever a few, so they're loaded and sorted here.
+pub fn stickies(db: &Db) -> Vec<Post> {
+    let stickies = db.open_tree("stickies").unwrap();
+    let mut threads: Vec<(Option<u32>, Post)> = stickies
+        .iter()
+        .filter_map(|entry| {
+            let (id, order) = entry.unwrap();
+            let thread = load_post(db, std::str::from_utf8(&id).ok()?)?;
+            Some((sticky_order(&order), thread))
+        })
+        .collect();
+    threads.sort_by_key(|(order, thread)| (order.is_none(), *order, std::cmp::Reverse(thread.timestamp)));
+    threads.into_iter().map(|(_, thread)| thread).collect()
+}
+
+// Numbers `threads` 0, 1, 2... in the order given
+fn renumber(db: &Db, threads: &[Post]) {
+    let stickies = db.open_tree("stickies").unwrap();
+    for (order, thread) in threads.iter().enumerate() {
+        stickies.insert(&thread.id, &(order as u32).to_be_bytes()).unwrap();
+    }
+}
+
+// Swaps a sticky with the one before or after it. Returns false if it
+// isn't sticky; moving the first up or the last down leaves it be.
+pub fn move_sticky(db: &Db, thread_id: &str, direction: Direction) -> bool {
+    let _lock = STICKY_ORDER.lock().unwrap();
+    let mut threads = stickies(db);
+    let at = match threads.iter().position(|thread| thread.id == thread_id) {
+        Some(at) => at,
+        None => return false,
+    };
+    let other = match direction {
+        Direction::Up => at.checked_sub(1),
+        Direction::Down => Some(at + 1).filter(|&next| next < threads.len()),
+    };
+    if let Some(other) = other {
+        threads.swap(at, other);
+    }
+    renumber(db, &threads);
+    true
+}
+
+pub fn sticky_ids(db: &Db) -> HashSet<String> {
+    db.open_tree("stickies")
+        .unwrap()
+        .iter()
+        .keys()
+        .filter_map(|key| String::from_utf8(key.unwrap().to_vec()).ok())
+        .collect()
+}
+
+// Takes a deleted post off `posted`. A bumped thread's key isn't found
+// here, see remove_started. Returns how many entries were removed.
+pub fn unpost(db: &Db, post: &Post) -> usize {
+    let removed = db.open_tree("posted").unwrap().remove(bump_key(post.timestamp, &post.id)).unwrap();
+    removed.is_some() as usize
+}
+
+// Takes a thread being deleted off `creations` and `posted`. Its keys
+// there are from when it was started, which is no later than created_at,
+// so they're looked for from that time back. Call it while the replies
+// are still there. Returns how many entries were removed.
+pub fn remove_started(db: &Db, thread: &Post) -> usize {
+    let latest = bump_key(created_at(db, thread), &thread.id);
+    let suffix = format!("/{}", thread.id);
+    let mut removed = 0;
+    for name in ["creations", "posted"] {
+        let tree = db.open_tree(name).unwrap();
+        let key = tree
+            .range(..=latest.as_bytes())
+            .keys()
+            .rev()
+            .filter_map(Result::ok)
+            .find(|key| key.ends_with(suffix.as_bytes()));
+        if let Some(key) = key {
+            removed += tree.remove(key).unwrap().is_some() as usize;
+        }
+    }
+    removed
+}
+
+// Returns how many entries were removed.
+pub fn remove(db: &Db, post: &Post) -> usize {
+    let removed = match &post.parent_id {
+        None => {
+            set_sticky(db, &post.id, false);
+            db.open_tree("bumps").unwrap().remove(bump_key(post.timestamp, &post.id))
+        }
+        Some(thread_id) => {
+            if let Some(number) = post.reply_number {
+                db.open_tree("numbers").unwrap().remove(number_key(thread_id, number)).unwrap();
+            }
+            db.open_tree("replies")
+                .unwrap()
+                .remove(reply_key(thread_id, post.timestamp, &post.id))
+        }
+    };
+    removed.unwrap().is_some() as usize
+}
+
+// Posts from before the indexes existed are added once, on first start.
+pub fn build_if_missing(db: &Db, parallelism: usize) {
+    let meta = db.open_tree("meta").unwrap();
+    if meta.contains_key("indexes_built").unwrap() {
+        return;
+    }
+    storage::scan_all_parallel(db, parallelism, |post| add(db, &post)).log_unreadable("building indexes");
+    meta.insert("indexes_built", &[]).unwrap();
+    db.flush().unwrap();
+}
+
+// When a thread was started, as near as its record tells. The timestamp is
+// the last bump, so it's taken as the earlier of that and the first reply:
+// exact for threads without replies, and close for the rest.
+pub fn created_at(db: &Db, thread: &Post) -> u64 {
+    let first_reply = db
+        .open_tree("replies")
+        .unwrap()
+        .scan_prefix(format!("{}/", thread.id))
+        .keys()
+        .next()
+        .and_then(|key| {
+            let key = key.unwrap();
+            std::str::from_utf8(&key).ok()?.split('/').nth(1)?.parse::<u64>().ok()
+        });
+    first_reply.map_or(thread.timestamp, |first| first.min(thread.timestamp))
+}
+
+// Threads from before the creations index existed, see created_at.
+pub fn build_creations_if_missing(db: &Db, parallelism: usize) {
+    let meta = db.open_tree("meta").unwrap();
+    if meta.contains_key("creations_built").unwrap() {
+        return;
+    }
+    let creations = db.open_tree("creations").unwrap();
+    // build_if_missing may have just added these with bump times
+    creations.clear().unwrap();
+    storage::scan_all_parallel(db, parallelism, |thread| {
+        if thread.parent_id.is_none() {
+            creations.insert(bump_key(created_at(db, &thread), &thread.id), &[]).unwrap();
+        }
+    })
+    .log_unreadable("building the creation order");
+    meta.insert("creations_built", &[]).unwrap();
+    db.flush().unwrap();
+}
+
+// Posts from before the posted index existed, threads at created_at
+pub fn build_posted_if_missing(db: &Db, parallelism: usize) {
+    let meta = db.open_tree("meta").unwrap();
+    if meta.contains_key("posted_built").unwrap() {
+        return;
+    }
+    let posted = db.open_tree("posted").unwrap();
+    // build_if_missing may have just added threads at their bump times
+    posted.clear().unwrap();
+    storage::scan_all_parallel(db, parallelism, |post| {
+        let timestamp = match post.parent_id {
+            None => created_at(db, &post),
+            Some(_) => post.timestamp,
+        };
+        posted.insert(bump_key(timestamp, &post.id), &[]).unwrap();
+    })
+    .log_unreadable("building the post order");
+    meta.insert("posted_built", &[]).unwrap();
+    db.flush().unwrap();
+}
+
+// Replies numbered before the numbers index existed. Runs after
+// numbering::assign_if_missing, so every reply has its number by then.
+pub fn build_numbers_if_missing(db: &Db, parallelism: usize) {
+    let meta = db.open_tree("meta").unwrap();
+    if meta.contains_key("numbers_built").unwrap() {
+        return;
+    }
+    let numbers = db.open_tree("numbers").unwrap();
+    storage::scan_all_parallel(db, parallelism, |post| {
+        if let (Some(thread_id), Some(number)) = (&post.parent_id, post.reply_number) {
+            numbers.insert(number_key(thread_id, number), post.id.as_bytes()).unwrap();
+        }
+    })
+    .log_unreadable("building reply numbers");
+    meta.insert("numbers_built", &[]).unwrap();
+    db.flush().unwrap();
+}
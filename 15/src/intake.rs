@@ -0,0 +1,145 @@
+// Limits on how a post submission arrives, as opposed to what it contains.
+// Every field of the multipart body, file or text, is read through one
+// Intake so a client can't tie up the handler by trickling the body in
+// tiny chunks:
+//
+// - each field may arrive in at most INTAKE_MAX_CHUNKS chunks,
+// - the whole body must arrive within INTAKE_MAX_SECS,
+// - after the first INTAKE_RATE_WINDOW_SECS it must have averaged at least
+//   INTAKE_MIN_BYTES_PER_SEC.
+
+use actix_multipart::{Field, Multipart, MultipartError};
+use actix_web::rt::time::timeout;
+use actix_web::web::Bytes;
+use futures_util::{StreamExt, TryStreamExt};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+#[derive(Debug)]
+pub enum IntakeError {
+    // Sent to 413
+    TooManyChunks,
+    // Sent to 408
+    TooSlow,
+    // A part the parser won't take, such as one without a name. Sent to 400.
+    Malformed,
+}
+
+impl IntakeError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            IntakeError::TooManyChunks => "The post was sent in too many pieces.",
+            IntakeError::TooSlow => "The post took too long to arrive.",
+            IntakeError::Malformed => "The form arrived in an unexpected shape. Reload the page and try again.",
+        }
+    }
+}
+
+pub struct Intake {
+    started: Instant,
+    bytes: u64,
+    field_chunks: usize,
+    max_chunks: usize,
+    max_duration: Duration,
+    min_bytes_per_sec: u64,
+    rate_window: Duration,
+}
+
+impl Intake {
+    pub fn new(config: &Config) -> Intake {
+        Intake {
+            started: Instant::now(),
+            bytes: 0,
+            field_chunks: 0,
+            max_chunks: config.intake_max_chunks,
+            max_duration: Duration::from_secs(config.intake_max_secs),
+            min_bytes_per_sec: config.intake_min_bytes_per_sec,
+            rate_window: Duration::from_secs(config.intake_rate_window_secs),
+        }
+    }
+
+    fn remaining(&self) -> Result<Duration, IntakeError> {
+        self.max_duration.checked_sub(self.started.elapsed()).ok_or(IntakeError::TooSlow)
+    }
+
+    // A body that breaks off ends the same way running out of fields does,
+    // so what arrived can still be judged. A part the parser refuses would
+    // otherwise end it too, dropping everything after it unseen.
+    pub async fn next_field(&mut self, payload: &mut Multipart) -> Result<Option<Field>, IntakeError> {
+        let field = timeout(self.remaining()?, payload.try_next()).await.map_err(|_| IntakeError::TooSlow)?;
+        self.field_chunks = 0;
+        match field {
+            Ok(field) => Ok(field),
+            Err(MultipartError::Payload(_)) | Err(MultipartError::Incomplete) => Ok(None),
+            Err(_) => Err(IntakeError::Malformed),
+        }
+    }
+
+    pub async fn next_chunk(&mut self, field: &mut Field) -> Result<Option<Result<Bytes, String>>, IntakeError> {
+        let chunk = timeout(self.remaining()?, field.next()).await.map_err(|_| IntakeError::TooSlow)?;
+        let chunk = match chunk {
+            Some(chunk) => chunk.map_err(|e| e.to_string()),
+            None => return Ok(None),
+        };
+        self.field_chunks += 1;
+        if self.field_chunks > self.max_chunks {
+            return Err(IntakeError::TooManyChunks);
+        }
+        if let Ok(data) = &chunk {
+            self.bytes += data.len() as u64;
+        }
+        let elapsed = self.started.elapsed();
+        if elapsed >= self.rate_window && self.bytes < self.min_bytes_per_sec.saturating_mul(elapsed.as_secs()) {
+            return Err(IntakeError::TooSlow);
+        }
+        Ok(Some(chunk))
+    }
+
+    // A whole text field. Invalid UTF-8 is replaced rather than rejected.
+    pub async fn read_text(&mut self, field: &mut Field) -> Result<String, IntakeError> {
+        let mut data = Vec::new();
+        while let Some(chunk) = self.next_chunk(field).await? {
+            match chunk {
+                Ok(chunk) => data.extend_from_slice(&chunk),
+                Err(_) => break,
+            }
+        }
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    }
+
+    // A text field left empty reads as if it weren't sent, so an empty
+    // parent_id starts a thread like a missing one does
+    pub async fn read_optional_text(&mut self, field: &mut Field) -> Result<Option<String>, IntakeError> {
+        Ok(Some(self.read_text(field).await?).filter(|text| !text.is_empty()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::error::PayloadError;
+    use actix_web::http::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+    use futures_util::stream;
+
+    // A body with one text field named parent_id
+    fn parent_id_body(value: &str) -> Multipart {
+        let body = format!("--b\r\nContent-Disposition: form-data; name=\"parent_id\"\r\n\r\n{}\r\n--b--\r\n", value);
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("multipart/form-data; boundary=b"));
+        Multipart::new(&headers, stream::iter(vec![Ok::<_, PayloadError>(Bytes::from(body))]))
+    }
+
+    async fn read_parent_id(value: &str) -> Option<String> {
+        let mut intake = Intake::new(&Config::from_env());
+        let mut payload = parent_id_body(value);
+        let mut field = intake.next_field(&mut payload).await.unwrap().unwrap();
+        intake.read_optional_text(&mut field).await.unwrap()
+    }
+
+    #[actix_web::test]
+    async fn an_empty_parent_id_starts_a_thread() {
+        assert_eq!(read_parent_id("").await, None);
+        assert_eq!(read_parent_id("abc").await.as_deref(), Some("abc"));
+    }
+}
@@ -1,26 +1,184 @@
 use actix_files as fs;
 use actix_multipart::Multipart;
-use actix_web::{web, App, Error, HttpResponse, HttpServer, Responder};
-use futures_util::{StreamExt, TryStreamExt};
+use actix_web::cookie::{time::Duration as CookieDuration, Cookie};
+use actix_web::http::header::{CacheControl, CacheDirective, HeaderValue, CONTENT_DISPOSITION, CONTENT_LANGUAGE};
+use actix_web::http::StatusCode;
+use actix_web::middleware::DefaultHeaders;
+use actix_web::web::Bytes;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder};
+use futures_util::stream::{self, StreamExt};
 use serde::{Deserialize, Serialize};
 use sled::Db;
+use std::collections::{HashMap, HashSet};
 use std::time::SystemTime;
-use std::io::Write;
 use uuid::Uuid;
 use askama::Template;
-use serde_json;
 
-const POSTS_PER_PAGE: usize = 30;
+mod activity;
+mod age_gate;
+mod admin;
+mod announcement;
+mod api;
+mod archive;
+mod archive_db;
+mod backlinks;
+mod audit;
+mod changes;
+mod bytesize;
+mod config;
+mod counters;
+mod diff;
+mod diskspace;
+mod edits;
+mod events;
+mod exemptions;
+mod export;
+mod filename;
+mod format;
+mod head;
+mod indexes;
+mod intake;
+mod maintenance;
+mod manifest;
+mod media_prune;
+mod moderation;
+mod notify;
+mod numbering;
+mod pending;
+mod poster;
+mod posters;
+mod previews;
+mod quotes;
+mod quota;
+mod rate_limit;
+mod redirect;
+mod reindex;
+mod relocate;
+mod rejection;
+mod render;
+mod remember;
+mod reply_form;
+mod replay;
+mod retention;
+mod route_limits;
+mod runtime;
+mod schema;
+mod seen;
+mod seed;
+mod settings;
+mod setup;
+mod sorting;
+mod stats;
+mod startup;
+mod storage;
+mod theme;
+mod timings;
+mod upload;
+mod upload_slots;
+mod validation;
+mod verify;
+mod webhooks;
+mod watchlist;
+mod widget;
 
-#[derive(Serialize, Deserialize, Clone)]
+#[cfg(test)]
+mod tests;
+
+use age_gate::AgeOk;
+use changes::ReplyRefused;
+use config::Config;
+use diskspace::{DiskGuard, VolumeProbe};
+use events::{BoardEvent, EventBus};
+use exemptions::ExemptionCache;
+use rate_limit::{RateLimit, RateLimiter, RouteClass};
+use rejection::{ErrorCode, FieldError, Rejection};
+use reply_form::FormState;
+use archive_db::ArchiveDb;
+use runtime::{RuntimeCache, RuntimeSettings};
+use settings::{BoardSettings, SettingsCache};
+use startup::ServerFlags;
+use timings::Op;
+use seen::LastSeen;
+use sorting::{Rankings, ThreadSort};
+use upload::{Dimensions, MediaKind};
+use webhooks::Webhooks;
+
+const MAX_DOWNLOAD_NAME: usize = 40;
+const POPULAR_THREADS: usize = 5;
+
+#[derive(Serialize, Deserialize, Clone, Default)]
 struct Post {
+    // See schema.rs before adding fields
+    #[serde(default)]
+    schema: u16,
     id: String,
     parent_id: Option<String>,
     title: String,
+    // None for anonymous posts
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    tripcode: Option<String>,
+    // Staff marker, only ever set for posts made with an admin session
+    #[serde(default)]
+    capcode: Option<String>,
     message: String,
     file: Option<String>,
     #[serde(default = "default_timestamp")]
     timestamp: u64,
+    #[serde(default)]
+    ip_hash: Option<String>,
+    // Set when the attachment failed and the post was kept as text only
+    #[serde(default)]
+    upload_error: Option<String>,
+    // sha256 of the attachment as uploaded, see moderation.rs
+    #[serde(default)]
+    file_hash: Option<String>,
+    // Name and size of the attachment as the poster uploaded it
+    #[serde(default)]
+    original_name: Option<String>,
+    #[serde(default)]
+    file_size: Option<u64>,
+    // Worked out from the file name at save time; use media_kind() to read it
+    #[serde(default)]
+    media_kind: Option<MediaKind>,
+    // Position in the thread, set when a reply is stored and never reused,
+    // see numbering.rs. None for first posts.
+    #[serde(default)]
+    reply_number: Option<u64>,
+    // Set when the attachment was taken down, see storage::take_down
+    #[serde(default)]
+    removal_reason: Option<String>,
+    // First posts only: replies are refused from this time on
+    #[serde(default)]
+    locks_at: Option<u64>,
+    // Pixel size of an image attachment, read at upload. None for other
+    // media and for images from before it was kept.
+    #[serde(default)]
+    width: Option<u32>,
+    #[serde(default)]
+    height: Option<u32>,
+    // "heic" or "heif" when the poster's photo was stored as a JPEG, see
+    // upload.rs
+    #[serde(default)]
+    converted_from: Option<String>,
+    // First posts only: the least time between two replies, set by a
+    // moderator, see changes::insert_reply
+    #[serde(default)]
+    slow_mode_secs: Option<u32>,
+    // Set when the attachment was replaced by a thumbnail to save disk,
+    // see media_prune.rs. `file` is None from then on; non-images get no
+    // thumbnail.
+    #[serde(default)]
+    thumbnail: Option<String>,
+    #[serde(default)]
+    media_pruned: bool,
+    // First posts only: posted as the board from /admin/announce, see
+    // announcement.rs
+    #[serde(default)]
+    announcement: bool,
 }
 
 impl Post {
@@ -28,28 +186,111 @@ impl Post {
         self.file.as_deref()
     }
 
-    fn is_image(&self) -> bool {
-        if let Some(file_url) = self.file_url() {
-            file_url.ends_with(".jpg") || file_url.ends_with(".jpeg") || file_url.ends_with(".png") || file_url.ends_with(".gif") || file_url.ends_with(".webp")
-        } else {
-            false
+    fn thumbnail_url(&self) -> Option<&str> {
+        self.thumbnail.as_deref()
+    }
+
+    // Posts are shown with whatever name they were stored with, regardless
+    // of the current NAMES setting
+    fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or("Anonymous")
+    }
+
+    fn formatted_message(&self) -> String {
+        format::format_message(&self.message)
+    }
+
+    // "1 post / 60 s" for a thread in slow mode
+    fn slow_mode(&self) -> Option<String> {
+        self.slow_mode_secs.map(|secs| format!("1 post / {} s", secs))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.locks_at.map(|locks_at| now() >= locks_at).unwrap_or(false)
+    }
+
+    // "5 h" until a thread with a closing time closes
+    fn closes_in(&self) -> Option<String> {
+        let left = self.locks_at?.checked_sub(now()).filter(|&left| left > 0)?;
+        Some(format::human_duration(left))
+    }
+
+    // The title on cards; the template puts the full one in a title
+    // attribute when this is cut
+    fn card_title(&self) -> String {
+        format::truncate_chars(&self.title, format::CARD_TITLE_CHARS)
+    }
+
+    fn page_title(&self) -> String {
+        format::truncate_chars(&self.title, format::PAGE_TITLE_CHARS)
+    }
+
+    // Short label used where there's no room to show the media itself
+    fn media_label(&self) -> &'static str {
+        match self.media_kind() {
+            Some(MediaKind::Image) => "IMG",
+            Some(MediaKind::Video) => "VID",
+            Some(MediaKind::Audio) => "AUD",
+            Some(MediaKind::Other) | None => "FILE",
         }
     }
 
-    fn is_video(&self) -> bool {
-        if let Some(file_url) = self.file_url() {
-            file_url.ends_with(".mp4") || file_url.ends_with(".webm")
-        } else {
-            false
+    // None when there's no attachment. Records saved before the kind was
+    // stored fall back to the file name.
+    fn media_kind(&self) -> Option<MediaKind> {
+        let file = self.file_url()?;
+        Some(self.media_kind.unwrap_or_else(|| MediaKind::from_file_name(file)))
+    }
+
+    // What a plain download link shows; falls back to the stored name for
+    // posts from before original names were kept
+    fn download_name(&self) -> String {
+        let name = self.original_name.as_deref().or_else(|| self.file_url()).unwrap_or_default();
+        format::truncate_chars(name, MAX_DOWNLOAD_NAME)
+    }
+
+    // Alt text for the attachment: the name it was uploaded under, or
+    // which post it belongs to when that wasn't kept
+    fn alt_text(&self) -> String {
+        match (&self.original_name, self.reply_number) {
+            (Some(name), _) => format::truncate_chars(name, MAX_DOWNLOAD_NAME),
+            (None, Some(number)) => format!("Attachment for reply {}", number),
+            (None, None) => "Attachment for the first post".to_string(),
         }
     }
 
+    fn file_extension(&self) -> String {
+        self.file_url()
+            .and_then(|file| file.rsplit_once('.'))
+            .map(|(_, ext)| ext.to_ascii_uppercase())
+            .unwrap_or_else(|| "FILE".to_string())
+    }
+
+    fn is_media(&self) -> bool {
+        matches!(self.media_kind(), Some(MediaKind::Image) | Some(MediaKind::Video) | Some(MediaKind::Audio))
+    }
+
+    // The size an image is shown at within a `max` pixel square, for the
+    // img width and height. None when the size wasn't stored; those images
+    // keep the fixed square.
+    fn display_size(&self, max: u32) -> Option<Dimensions> {
+        let dimensions = Dimensions {
+            width: self.width?,
+            height: self.height?,
+        };
+        Some(dimensions.fit_within(max))
+    }
+
+    fn is_image(&self) -> bool {
+        self.media_kind() == Some(MediaKind::Image)
+    }
+
+    fn is_video(&self) -> bool {
+        self.media_kind() == Some(MediaKind::Video)
+    }
+
     fn is_audio(&self) -> bool {
-        if let Some(file_url) = self.file_url() {
-            file_url.ends_with(".mp3")
-        } else {
-            false
-        }
+        self.media_kind() == Some(MediaKind::Audio)
     }
 }
 
@@ -57,217 +298,1657 @@ fn default_timestamp() -> u64 {
     0
 }
 
+fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn load_post(db: &Db, id: &str) -> Option<Post> {
+    let bytes = timings::time(Op::Get, "load_post", || db.get(id).unwrap());
+    bytes.and_then(|bytes| Post::upgrade(&bytes).ok())
+}
+
+fn store_post(db: &Db, settings: &BoardSettings, post: &Post, slow_mode: bool) -> Result<Post, ReplyRefused> {
+    store_record(db, settings, post, &serde_json::to_vec(post).unwrap(), slow_mode)
+}
+
+// Writes a post to the main tree and bumps its thread, up to the bump
+// limit. `raw` is what gets stored, so records from the approval queue go
+// in byte for byte apart from the reply number. Returns the post as stored,
+// or why a reply's thread can't take it, see changes::insert_reply.
+// `slow_mode` is false for posts a moderator made or approved.
+fn store_record(db: &Db, settings: &BoardSettings, post: &Post, raw: &[u8], slow_mode: bool) -> Result<Post, ReplyRefused> {
+    let mut post = post.clone();
+    let _lock = post.parent_id.as_deref().map(storage::lock_thread);
+    match &post.parent_id {
+        Some(thread_id) => {
+            let inserted = timings::time(Op::Transaction, "insert_reply", || changes::insert_reply(db, thread_id, &post, raw, slow_mode));
+            post.reply_number = Some(inserted?);
+        }
+        None => timings::time(Op::Insert, "insert_thread", || {
+            if post.announcement {
+                announcement::insert(db, &post.id, raw);
+            } else {
+                db.insert(&post.id, raw).unwrap();
+            }
+            indexes::add(db, &post);
+        }),
+    }
+    posters::record(db, &post);
+    activity::record_post(db, post.timestamp);
+    moderation::index_upload(db, &post);
+    upload::index(db, &post);
+    if let Some(parent_id) = &post.parent_id {
+        activity::record_reply(db, parent_id, post.timestamp);
+        if settings.bumps(count_reply(db, parent_id)) {
+            bump_thread(db, parent_id, post.timestamp);
+        }
+    }
+    timings::time(Op::Insert, "flush", || db.flush().unwrap());
+    Ok(post)
+}
+
+// Replies per thread, kept in `reply_counts` so the bump limit doesn't need
+// a scan. Returns the new count.
+fn count_reply(db: &Db, thread_id: &str) -> usize {
+    counters::increment(&db.open_tree("reply_counts").unwrap(), thread_id, 1) as usize
+}
+
+fn reply_count(db: &Db, thread_id: &str) -> u64 {
+    counters::get(&db.open_tree("reply_counts").unwrap(), thread_id)
+}
+
+// "37 replies, 12 posters"
+fn thread_summary(db: &Db, thread_id: &str) -> String {
+    posters::summary(reply_count(db, thread_id), posters::count(db, thread_id))
+}
+
+fn uncount_reply(db: &Db, thread_id: &str) {
+    counters::increment(&db.open_tree("reply_counts").unwrap(), thread_id, -1);
+}
+
+// Only the timestamp field of the thread record is touched, so a record
+// written by a newer binary keeps its unknown fields. Replies bump with
+// their own timestamp, which may be older than "now" for posts coming out
+// of the approval queue, so a thread is never bumped backwards.
+fn bump_thread(db: &Db, thread_id: &str, timestamp: u64) {
+    let previous = db
+        .fetch_and_update(thread_id, |old| {
+            let old = old?;
+            let bumped = schema::merge_fields(old, |fields| {
+                let current = fields.get("timestamp").and_then(|t| t.as_u64()).unwrap_or(0);
+                fields.insert("timestamp".to_string(), current.max(timestamp).into());
+            });
+            Some(bumped.unwrap_or_else(|_| old.to_vec()))
+        })
+        .unwrap();
+    if let Some(thread) = previous.and_then(|bytes| Post::upgrade(&bytes).ok()) {
+        indexes::bumped(db, thread_id, thread.timestamp, thread.timestamp.max(timestamp));
+    }
+}
+
+// Sets or clears a thread's slow_mode_secs the same way, leaving the rest
+// of the record alone. Returns false if there's no such thread.
+fn set_slow_mode(db: &Db, thread_id: &str, secs: Option<u32>) -> bool {
+    let _lock = storage::lock_thread(thread_id);
+    let previous = db
+        .fetch_and_update(thread_id, |old| {
+            let old = old?;
+            let changed = schema::merge_fields(old, |fields| {
+                fields.insert("slow_mode_secs".to_string(), secs.into());
+            });
+            Some(changed.unwrap_or_else(|_| old.to_vec()))
+        })
+        .unwrap();
+    previous.and_then(|bytes| Post::upgrade(&bytes).ok()).is_some_and(|thread| thread.parent_id.is_none())
+}
+
+// The index is sent in three parts so the first bytes go out before the
+// threads are read: IndexTemplate up to and including the stickies, one
+// IndexThreadTemplate per thread as it comes off the index scan, then
+// IndexFooterTemplate with the page links.
 #[derive(Template)]
 #[template(path = "index.html")]
 struct IndexTemplate<'a> {
-    posts: &'a [Post],
+    config: &'a Config,
+    settings: &'a BoardSettings,
+    // Carried through sort links when the visitor picked a page size
+    per_page: Option<usize>,
+    sort: ThreadSort,
+    // Whether the form offers a closing time, see Config::thread_locks_public
+    show_lock_field: bool,
+    // Fresh for every page, see replay.rs
+    submit_token: String,
+    // See admin::submit_url
+    submit_url: String,
+    // See rate_limit::posting_status
+    posting_status: Option<String>,
+    // Rendered blocks, only on page 0
+    stickies: &'a [String],
+    // Empty when POPULAR_THREADS is off
+    popular: &'a [(Post, u64)],
+    // The thread the banner links to, see announcement.rs
+    announcement: Option<Post>,
+}
+
+#[derive(Template)]
+#[template(path = "index_thread.html")]
+struct IndexThreadTemplate<'a> {
+    config: &'a Config,
+    post: &'a Post,
+    sticky: bool,
+    // "37 replies, 12 posters"
+    summary: String,
+    // Replies since this visitor last viewed the thread, see seen.rs
+    new_replies: Option<usize>,
+    // The last few replies, see previews.rs
+    preview: Option<previews::Preview>,
+}
+
+#[derive(Template)]
+#[template(path = "index_footer.html")]
+struct IndexFooterTemplate<'a> {
+    config: &'a Config,
+    // Shown instead of the page links on a board without any threads
+    empty_message: Option<&'static str>,
+    page: usize,
+    page_count: usize,
+    per_page: Option<usize>,
+    sort: ThreadSort,
     prev_page: Option<usize>,
     next_page: Option<usize>,
 }
 
+impl IndexFooterTemplate<'_> {
+    fn page_link(&self, page: &usize) -> String {
+        self.config.page_url(*page, self.per_page, self.sort)
+    }
+}
+
+// None, logged, if the thread's block fails to render; the index leaves
+// that thread out rather than losing the page
+fn render_thread_block(db: &Db, config: &Config, seen: &LastSeen, thread: &Post, sticky: bool) -> Option<String> {
+    let template = IndexThreadTemplate {
+        config,
+        post: thread,
+        sticky,
+        summary: thread_summary(db, &thread.id),
+        new_replies: seen.new_replies(db, &thread.id).filter(|&count| count > 0),
+        preview: previews::get(db, config, &thread.id),
+    };
+    render::to_string(&template, &format!("thread {} on the index", thread.id))
+}
+
+// Where the index scan is between chunks of a streamed page
+struct ThreadScan {
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    rankings: web::Data<Rankings>,
+    sort: ThreadSort,
+    sticky_ids: HashSet<String>,
+    seen: LastSeen,
+    // Key of the last thread sent
+    after: Option<String>,
+    remaining: usize,
+}
+
+impl ThreadScan {
+    // The next thread's block. Each step picks the scan up again after the
+    // last key sent, so nothing borrowed is held between chunks. A thread
+    // that fails to render is skipped (and still counts towards the page,
+    // so paging doesn't shift).
+    fn next_block(&mut self) -> Option<String> {
+        while self.remaining > 0 {
+            let (key, thread) = timings::time(Op::Scan, "index_threads", || {
+                sorting::threads(&self.db, &self.rankings, self.sort, self.after.as_deref())
+                    .find(|(_, thread)| !self.sticky_ids.contains(&thread.id))
+            })?;
+            self.after = Some(key);
+            self.remaining -= 1;
+            if let Some(block) = render_thread_block(&self.db, &self.config, &self.seen, &thread, false) {
+                return Some(block);
+            }
+        }
+        None
+    }
+}
+
+impl IndexTemplate<'_> {
+    fn sorts(&self) -> &'static [ThreadSort] {
+        &ThreadSort::ALL
+    }
+
+    // Changing the order starts again from the first page
+    fn sort_link(&self, sort: &ThreadSort) -> String {
+        self.config.page_url(0, self.per_page, *sort)
+    }
+}
+
 #[derive(Template)]
 #[template(path = "post_view.html")]
 struct PostViewTemplate<'a> {
+    config: &'a Config,
+    settings: &'a BoardSettings,
     post: &'a Post,
-    replies: &'a [Post],
+    // The replies on this page
+    replies: Vec<ReplySlot>,
+    order: ReplyOrder,
+    // Which page of replies this is and how many there are, see
+    // Config::replies_per_page
+    page: u64,
+    page_count: u64,
+    // This thread in the order and on the page it's shown, for the reply
+    // form
+    return_to: String,
+    // Fresh for every page, see replay.rs
+    submit_token: String,
+    // See admin::submit_url
+    submit_url: String,
+    // Whether an admin is looking, whose form carries their CSRF token
+    admin: bool,
+    // See rate_limit::posting_status
+    posting_status: Option<String>,
+    // For the reply form, see remember.rs
+    remembered_options: Option<String>,
+    // A reply sent back by validation, and why, see reply_form.rs
+    form_state: Option<FormState>,
+    form_errors: Vec<String>,
+    // ?quote=N, to start a new reply with ">>N"
+    quote: Option<u64>,
+    // Whether this visitor has viewed the thread before, see seen.rs
+    seen_before: bool,
+    // Number of the first reply since this visitor's last view
+    first_new: Option<u64>,
+    // "37 replies, 12 posters"; None when the post is a reply
+    summary: Option<String>,
+    // A reply whose thread was deleted, shown on its own
+    orphaned: bool,
+    // Taken off the board, see archive.rs
+    archived: bool,
+    // When the page was made, for the reply form, see reply_form.rs
+    rendered_at: u64,
+    // The reply this visitor just posted and how many replies went in
+    // while they wrote it, from ?just_posted=N&since=T
+    meanwhile: Option<(u64, usize)>,
+}
+
+impl PostViewTemplate<'_> {
+    fn newest_first(&self) -> bool {
+        self.order == ReplyOrder::Desc
+    }
+
+    // The divider goes before the first new reply, or after it when the
+    // newest replies come first
+    fn starts_new(&self, slot: &ReplySlot) -> bool {
+        self.first_new == Some(slot.number)
+    }
+
+    // A link to reply `number`, which may be on another page
+    fn reply_href(&self, number: &u64) -> String {
+        if self.config.reply_page(*number) == self.page {
+            format!("#r{}", number)
+        } else {
+            self.config.reply_url(&self.post.id, Some(*number))
+        }
+    }
+
+    fn page_url(&self, page: &u64) -> String {
+        self.config.thread_page_url(&self.post.id, *page)
+    }
+
+    // Stays on this page, whose replies don't change with the order
+    fn order_url(&self, order: ReplyOrder) -> String {
+        let url = self.config.thread_page_url(&self.post.id, self.page);
+        let separator = if url.contains('?') { '&' } else { '?' };
+        format!("{}{}order={}", url, separator, order.as_str())
+    }
+
+    // This page with the reply form starting ">>number"
+    fn quote_url(&self, slot: &ReplySlot) -> String {
+        let separator = if self.return_to.contains('?') { '&' } else { '?' };
+        format!("{}{}quote={}#reply-form", self.return_to, separator, slot.number)
+    }
+
+    // The reply form's fields. A form that was sent back wins over the
+    // quote and the remembered options, see reply_form.rs.
+    fn form_title(&self) -> &str {
+        self.form_state.as_ref().map_or("", |form| form.title.as_str())
+    }
+
+    fn form_name(&self) -> &str {
+        self.form_state.as_ref().map_or("", |form| form.name.as_str())
+    }
+
+    fn form_options(&self) -> &str {
+        match &self.form_state {
+            Some(form) => &form.options,
+            None => self.remembered_options.as_deref().unwrap_or_default(),
+        }
+    }
+
+    fn form_remember_options(&self) -> bool {
+        match &self.form_state {
+            Some(form) => form.remember_options,
+            None => self.remembered_options.is_some(),
+        }
+    }
+
+    fn form_message(&self) -> String {
+        match (&self.form_state, self.quote) {
+            (Some(form), _) => form.message.clone(),
+            (None, Some(number)) => format!(">>{}\n", number),
+            (None, None) => String::new(),
+        }
+    }
+
+    fn form_lost_file(&self) -> bool {
+        self.form_state.as_ref().is_some_and(|form| form.had_file)
+    }
+
+    // A form sent back keeps the time it was first shown
+    fn form_rendered_at(&self) -> u64 {
+        self.form_state.as_ref().and_then(|form| form.rendered_at).unwrap_or(self.rendered_at)
+    }
+
+    // Shown on the visitor's own reply when others went in first
+    fn meanwhile_notice(&self, slot: &ReplySlot) -> Option<String> {
+        match self.meanwhile {
+            Some((number, count)) if number == slot.number && count > 0 => {
+                Some(reply_form::meanwhile_notice(count, self.newest_first()))
+            }
+            _ => None,
+        }
+    }
+
+    // Anything on the page that's only for this visitor
+    fn personal(&self) -> bool {
+        self.posting_status.is_some()
+            || self.admin
+            || self.remembered_options.is_some()
+            || self.form_state.is_some()
+            || self.seen_before
+            || self.meanwhile.is_some()
+    }
+
+    // The latest post time on the page, for seen.rs
+    fn newest(&self) -> u64 {
+        self.replies
+            .iter()
+            .filter_map(|slot| slot.post.as_ref())
+            .map(|reply| reply.timestamp)
+            .fold(self.post.timestamp, u64::max)
+    }
 }
 
+const REPLY_ORDER_COOKIE: &str = "reply_order";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ReplyOrder {
+    Asc,
+    Desc,
+}
+
+impl ReplyOrder {
+    fn parse(value: &str) -> Option<ReplyOrder> {
+        match value {
+            "asc" => Some(ReplyOrder::Asc),
+            "desc" => Some(ReplyOrder::Desc),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            ReplyOrder::Asc => "asc",
+            ReplyOrder::Desc => "desc",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ThreadQuery {
+    order: Option<String>,
+    quote: Option<u64>,
+    // Set by save_post on the way back from a reply, see reply_form.rs
+    just_posted: Option<u64>,
+    since: Option<u64>,
+    // Kept as text, see parse_page
+    page: Option<String>,
+}
+
+// A numbered place in a thread. `post` is None when that reply was deleted
+// and the thread shows a stub in its place.
+struct ReplySlot {
+    number: u64,
+    post: Option<Post>,
+    // Numbers of the replies quoting this one, see backlinks.rs
+    quoted_by: Vec<u64>,
+}
+
+// Lays replies out by number, with stubs for the numbers whose replies are
+// gone (up to the last number handed out) unless those are hidden.
+fn reply_slots(
+    mut replies: Vec<Post>,
+    mut quoted_by: HashMap<u64, Vec<u64>>,
+    last_number: u64,
+    show_deleted: bool,
+) -> Vec<ReplySlot> {
+    let stub = |number| ReplySlot {
+        number,
+        post: None,
+        quoted_by: Vec::new(),
+    };
+    replies.sort_by_key(|reply| (reply.reply_number.is_none(), reply.reply_number, reply.timestamp));
+    let mut slots = Vec::new();
+    let mut expected = 1;
+    for reply in replies {
+        let number = reply.reply_number.unwrap_or(expected);
+        if show_deleted {
+            slots.extend((expected..number).map(stub));
+        }
+        expected = expected.max(number + 1);
+        slots.push(ReplySlot {
+            number,
+            post: Some(reply),
+            quoted_by: quoted_by.remove(&number).unwrap_or_default(),
+        });
+    }
+    if show_deleted {
+        slots.extend((expected..=last_number).map(stub));
+    }
+    slots
+}
+
+#[derive(Template)]
+#[template(path = "post_fragment.html")]
+struct PostFragmentTemplate<'a> {
+    config: &'a Config,
+    post: &'a Post,
+}
+
+#[derive(Template)]
+#[template(path = "notice.html")]
+struct NoticeTemplate<'a> {
+    config: &'a Config,
+    heading: &'a str,
+    message: &'a str,
+    back_url: String,
+}
+
+// Form fields that may come only once
+const SINGLE_FIELDS: &[&str] = &["title", "name", "options", "message", "email", "parent_id", "file"];
+
 async fn save_post(
     db: web::Data<Db>,
-    upload_dir: web::Data<String>,
+    config: web::Data<Config>,
+    settings: web::Data<SettingsCache>,
+    events: web::Data<EventBus>,
+    admin: Option<admin::Admin>,
+    req: HttpRequest,
     mut payload: Multipart,
 ) -> Result<HttpResponse, Error> {
     let mut title = String::new();
+    let mut name = String::new();
+    let mut options = String::new();
     let mut message = String::new();
-    let mut filename: Option<String> = None;
+    let mut lock_after_hours = String::new();
+    let mut email = String::new();
+    let mut submit_token: Option<String> = None;
+    // Holds the submit token in the in-flight set until this request ends
+    let mut _in_flight: Option<replay::InFlightGuard> = None;
+    let mut return_to: Option<String> = None;
+    let mut rendered_at: Option<u64> = None;
+    let mut stored_file: Option<upload::StoredFile> = None;
     let mut parent_id: Option<String> = None;
+    let mut upload_error: Option<String> = None;
+    let mut quotes_confirmed = false;
+    let mut remember_options = false;
+    let mut announcement = false;
+    let mut announcement_locked = false;
+    // Kept for the whole request, so a reload can't change the quota
+    // between the form arriving and the post being stored
+    let runtime = req.app_data::<web::Data<RuntimeCache>>().unwrap().get();
 
     // Get the current timestamp
     let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
 
     // Process each field in the multipart payload
-    while let Ok(Some(mut field)) = payload.try_next().await {
-        let content_disposition = field.content_disposition();
-        let field_name = content_disposition.get_name().unwrap().to_string();
-
-        match field_name.as_str() {
-            "title" => {
-                while let Some(chunk) = field.next().await {
-                    let data = chunk.unwrap();
-                    title.push_str(std::str::from_utf8(&data).unwrap());
-                }
-            }
-            "message" => {
-                while let Some(chunk) = field.next().await {
-                    let data = chunk.unwrap();
-                    message.push_str(std::str::from_utf8(&data).unwrap());
+    let disk = req.app_data::<web::Data<DiskGuard>>().unwrap();
+    let uploads_in_flight = req.app_data::<web::Data<replay::InFlight>>().unwrap();
+    let upload_slots = req.app_data::<web::Data<upload_slots::UploadSlots>>().unwrap();
+    let mut intake = intake::Intake::new(&config);
+    let read = async {
+        let mut first = true;
+        let mut seen_fields = HashSet::new();
+        while let Some(mut field) = intake.next_field(&mut payload).await? {
+            let content_disposition = field.content_disposition();
+            let field_name = match content_disposition.get_name().filter(|name| !name.is_empty()) {
+                Some(name) => name.to_string(),
+                None => {
+                    eprintln!("skipping a form part with no name");
+                    while intake.next_chunk(&mut field).await?.is_some() {}
+                    continue;
                 }
+            };
+            let is_first = std::mem::replace(&mut first, false);
+            // A second title, message or file would silently replace the
+            // first, and a second file would be left behind on disk
+            if SINGLE_FIELDS.contains(&field_name.as_str()) && !seen_fields.insert(field_name.clone()) {
+                let message = "The form arrived in an unexpected shape. Reload the page and try again.";
+                return Ok(Some(FieldError::new("post", ErrorCode::Invalid, message)));
             }
-            "parent_id" => {
-                while let Some(chunk) = field.next().await {
-                    let data = chunk.unwrap();
-                    parent_id = Some(std::str::from_utf8(&data).unwrap().to_string());
+
+            match field_name.as_str() {
+                // Only honoured first, before anything is uploaded
+                "submit_token" => {
+                    let token = intake.read_text(&mut field).await?;
+                    if !is_first || !replay::is_valid_token(&token) {
+                        let message = "The form arrived in an unexpected shape. Reload the page and try again.";
+                        return Ok(Some(FieldError::new("submit_token", ErrorCode::Invalid, message)));
+                    }
+                    match uploads_in_flight.begin(&token) {
+                        Some(guard) => _in_flight = Some(guard),
+                        None => {
+                            let message = "This upload is already in progress.";
+                            return Ok(Some(FieldError::new("post", ErrorCode::InProgress, message)));
+                        }
+                    }
+                    submit_token = Some(token);
                 }
-            }
-            "file" => {
-                if let Some(filename_value) = content_disposition.get_filename() {
-                    if !filename_value.is_empty() {
-                        let file_extension = filename_value
-                            .split('.')
-                            .last()
-                            .map(String::from)
-                            .unwrap_or_else(|| "tmp".to_string());
-                        let file_name = format!("{}.{}", Uuid::new_v4(), file_extension);
-                        let filepath = format!("{}/{}", upload_dir.get_ref(), &file_name);
-
-                        let mut f = web::block(|| std::fs::File::create(filepath)).await??;
-
-                        while let Some(chunk) = field.next().await {
-                            let data = chunk.unwrap();
-                            f = web::block(move || {
-                                f.write_all(&data).map(|_| f)
-                            }).await??;
+                "title" => title = validation::strip_format_chars(&intake.read_text(&mut field).await?),
+                "name" => name = validation::strip_format_chars(&intake.read_text(&mut field).await?),
+                "options" => options = validation::strip_format_chars(&intake.read_text(&mut field).await?),
+                "message" => message = intake.read_text(&mut field).await?,
+                "lock_after_hours" => lock_after_hours = intake.read_text(&mut field).await?,
+                "email" => email = intake.read_text(&mut field).await?,
+                "parent_id" => parent_id = intake.read_optional_text(&mut field).await?,
+                "return_to" => return_to = Some(intake.read_text(&mut field).await?),
+                // Anything but a time is ignored; it only decides a notice
+                "rendered_at" => rendered_at = intake.read_text(&mut field).await?.trim().parse().ok(),
+                "quotes_confirmed" => quotes_confirmed = !intake.read_text(&mut field).await?.is_empty(),
+                "remember_options" => remember_options = !intake.read_text(&mut field).await?.is_empty(),
+                "announcement" => announcement = !intake.read_text(&mut field).await?.is_empty(),
+                "announcement_locked" => announcement_locked = !intake.read_text(&mut field).await?.is_empty(),
+                "file" => {
+                    let client_name = content_disposition.get_filename().unwrap_or_default().to_string();
+                    if !client_name.is_empty() {
+                        if !disk.uploads_allowed() {
+                            let message = "Uploads are temporarily disabled. You can still post without a file.";
+                            return Ok(Some(FieldError::new("file", ErrorCode::UploadsDisabled, message)));
+                        }
+                        // Held until the file is stored
+                        let _slot = match upload_slots.take().await {
+                            Some(slot) => slot,
+                            None => {
+                                let message = "Too many uploads in progress, try again shortly. You can still post without a file.";
+                                let error = FieldError::new("file", ErrorCode::UploadsBusy, message)
+                                    .retry_after(upload_slots.retry_after());
+                                return Ok(Some(error));
+                            }
+                        };
+                        match upload::UploadPipeline::from_config(&config, &db).run(&mut field, &client_name, &mut intake).await {
+                            Ok(stored) => stored_file = stored,
+                            Err(upload::UploadError::Rejected(error)) => return Ok(Some(error)),
+                            Err(upload::UploadError::Failed(reason)) => upload_error = Some(reason),
+                            Err(upload::UploadError::Aborted(e)) => return Err(e),
                         }
-
-                        filename = Some(file_name);
                     }
                 }
+                _ => while intake.next_chunk(&mut field).await?.is_some() {},
+            }
+        }
+        Ok(None)
+    };
+    match read.await {
+        Ok(None) => {}
+        Ok(Some(error)) => {
+            if let Some(stored) = &stored_file {
+                let _ = std::fs::remove_file(config.upload_path(&stored.file_name));
+            }
+            return Err(Rejection::new(&config, &req, parent_id.as_deref(), vec![error]).into());
+        }
+        Err(e) => {
+            if let Some(stored) = &stored_file {
+                let _ = std::fs::remove_file(config.upload_path(&stored.file_name));
+            }
+            let (code, status) = match e {
+                intake::IntakeError::TooManyChunks => (ErrorCode::TooManyChunks, StatusCode::PAYLOAD_TOO_LARGE),
+                intake::IntakeError::TooSlow => (ErrorCode::TooSlow, StatusCode::REQUEST_TIMEOUT),
+                intake::IntakeError::Malformed => (ErrorCode::Invalid, StatusCode::BAD_REQUEST),
+            };
+            let error = FieldError::new("post", code, e.message());
+            return Err(Rejection::new(&config, &req, parent_id.as_deref(), vec![error]).status(status).into());
+        }
+    }
+
+    if let Some(reason) = upload_error.as_deref().filter(|_| config.reject_post_on_upload_failure) {
+        let error = FieldError::new("file", ErrorCode::UploadFailed, reason);
+        return Err(Rejection::new(&config, &req, parent_id.as_deref(), vec![error]).into());
+    }
+
+    // The same post sent again after the first went through is answered
+    // the same way, without a second copy
+    if submit_token.as_deref().is_some_and(|token| replay::is_replay(&db, token, &message, timestamp)) {
+        if let Some(stored) = &stored_file {
+            let _ = std::fs::remove_file(config.upload_path(&stored.file_name));
+        }
+        let default = match &parent_id {
+            Some(parent_id) => config.post_url(parent_id),
+            None => config.index_url(),
+        };
+        return Ok(redirect::back(&config, return_to.as_deref(), default));
+    }
+
+    let settings = settings.get(&db);
+    let ip_hash = poster::ip_hash(&db, &config, &req);
+    let exemptions = req.app_data::<web::Data<ExemptionCache>>().unwrap();
+    let exempt = ip_hash.as_deref().is_some_and(|ip_hash| exemptions.is_exempt(&db, ip_hash, timestamp));
+    let skip_spam_checks = exempt || admin.is_some();
+    let typed_name = name;
+    let (name, tripcode) = poster::parse_name(&db, &config, &typed_name);
+    let thread = parent_id.as_deref().and_then(|thread_id| load_post(&db, thread_id));
+    let submission = validation::Submission {
+        title: &title,
+        name: name.as_deref(),
+        message: &message,
+        is_thread: parent_id.is_none(),
+        has_file: stored_file.is_some(),
+        thread_locks_at: thread.and_then(|thread| thread.locks_at),
+        thread_archived: parent_id.as_deref().is_some_and(|thread_id| archive::is_archived(&db, thread_id)),
+        timestamp,
+        skip_spam_checks,
+    };
+    let mut verdict = match &ip_hash {
+        Some(ip_hash) if moderation::is_banned(&db, ip_hash) => Err(vec![FieldError::new(
+            "post",
+            ErrorCode::Banned,
+            "You are banned from posting.",
+        )]),
+        _ => validation::validate_post(&config, &settings, &submission),
+    };
+    // Only new threads get a closing time, and only from those allowed to
+    // set one; anyone else's field is ignored
+    let may_set_lock = parent_id.is_none() && (admin.is_some() || config.thread_locks_public);
+    let locks_at = if may_set_lock {
+        validation::parse_lock_after(&config, &lock_after_hours, timestamp)
+    } else {
+        Ok(None)
+    };
+    let locks_at = locks_at.unwrap_or_else(|error| {
+        match &mut verdict {
+            Err(errors) => errors.push(error),
+            Ok(()) => verdict = Err(vec![error]),
+        }
+        None
+    });
+    // Only a new thread from an admin is announced; anyone else's field is
+    // ignored like the closing time above
+    let announcement = announcement && admin.is_some() && parent_id.is_none();
+    let locks_at = if announcement && announcement_locked { Some(timestamp) } else { locks_at };
+    let email = validation::parse_email(&config, &email, parent_id.is_none()).unwrap_or_else(|error| {
+        match &mut verdict {
+            Err(errors) => errors.push(error),
+            Ok(()) => verdict = Err(vec![error]),
+        }
+        None
+    });
+    let parsed_options = validation::parse_options(&options);
+    // A reply as it was sent, to fill the form in again if it comes back.
+    // Options come back tidied, or as typed when they were refused.
+    let form_state = parent_id.as_ref().map(|thread_id| FormState {
+        parent_id: thread_id.clone(),
+        return_to: return_to.clone(),
+        title: title.clone(),
+        name: typed_name.clone(),
+        options: parsed_options.as_ref().cloned().unwrap_or_else(|_| options.clone()),
+        remember_options,
+        message: message.clone(),
+        had_file: stored_file.is_some(),
+        rendered_at,
+    });
+    let options = parsed_options.unwrap_or_else(|error| {
+        match &mut verdict {
+            Err(errors) => errors.push(error),
+            Ok(()) => verdict = Err(vec![error]),
+        }
+        String::new()
+    });
+    // A reply quoting numbers that aren't in its thread comes back for a
+    // second look before it counts against the quota, see quotes.rs
+    if let (Ok(()), Some(form_state)) = (&verdict, &form_state) {
+        let check = !quotes_confirmed && !rejection::wants_json(&req);
+        let unresolved = if check { quotes::unresolved(&db, &form_state.parent_id, &message) } else { Vec::new() };
+        if !unresolved.is_empty() {
+            if let Some(stored) = &stored_file {
+                let _ = std::fs::remove_file(config.upload_path(&stored.file_name));
             }
-            _ => (),
+            let submit_url = admin::submit_url(&config, admin.as_ref());
+            return Ok(quotes::confirm_page(&config, form_state, &unresolved, replay::new_token(), submit_url));
         }
     }
+    // Checked last so rejected posts don't use up the quota
+    let verdict = verdict.and_then(|()| match &ip_hash {
+        Some(_) if exempt => Ok(()),
+        Some(ip_hash) => quota::take(&db, &runtime, ip_hash, timestamp).map_err(|error| vec![error]),
+        None => Ok(()),
+    });
+    if let Err(errors) = verdict {
+        if let Some(stored) = &stored_file {
+            let _ = std::fs::remove_file(config.upload_path(&stored.file_name));
+        }
+        let rejection = Rejection::new(&config, &req, parent_id.as_deref(), errors);
+        return Err(bounce_reply(&db, &config, &settings, &req, form_state, rejection).into());
+    }
 
     let post = Post {
+        schema: schema::POST_SCHEMA,
         id: Uuid::new_v4().to_string(),
         parent_id,
         title,
-        message,
-        file: filename.clone(),
+        name,
+        tripcode,
+        capcode: if announcement {
+            Some(config.capcode_name.clone())
+        } else {
+            poster::capcode(&config, admin.as_ref(), &options)
+        },
+        message: settings.apply_wordfilters(&message),
+        file: stored_file.as_ref().map(|stored| stored.file_name.clone()),
         timestamp,
+        ip_hash,
+        upload_error,
+        file_hash: stored_file.as_ref().map(|stored| stored.sha256.clone()),
+        original_name: stored_file.as_ref().map(|stored| stored.original_name.clone()),
+        file_size: stored_file.as_ref().map(|stored| stored.size),
+        media_kind: stored_file.as_ref().map(|stored| stored.kind),
+        // Assigned by store_record
+        reply_number: None,
+        removal_reason: None,
+        locks_at,
+        width: stored_file.as_ref().and_then(|stored| stored.dimensions).map(|dimensions| dimensions.width),
+        height: stored_file.as_ref().and_then(|stored| stored.dimensions).map(|dimensions| dimensions.height),
+        converted_from: stored_file.as_ref().and_then(|stored| stored.converted_from.clone()),
+        slow_mode_secs: None,
+        thumbnail: None,
+        media_pruned: false,
+        announcement,
     };
 
-    let serialized = serde_json::to_vec(&post).unwrap();
-    db.insert(&post.id, serialized).unwrap();
-    
-    if let Some(parent_id) = &post.parent_id {
-        if let Ok(Some(parent_post_bytes)) = db.get(&parent_id) {
-            let mut parent_post: Post = serde_json::from_slice(&parent_post_bytes).unwrap();
-            parent_post.timestamp = timestamp;
-            let serialized_parent = serde_json::to_vec(&parent_post).unwrap();
-            db.insert(&parent_post.id, serialized_parent).unwrap();
+    if let Some(email) = &email {
+        notify::subscribe(&db, &config, &post.id, email, timestamp);
+    }
+    let needs_approval = config.approval_queue
+        && !announcement
+        && !post.ip_hash.as_deref().map(|hash| pending::is_approved_poster(&db, hash)).unwrap_or(false);
+    if needs_approval {
+        pending::hold(&db, &post);
+        if let Some(token) = &submit_token {
+            replay::record(&db, token, &post.id, &message, timestamp);
         }
+        let template = NoticeTemplate {
+            config: &config,
+            heading: "Your post is awaiting approval",
+            message: "Posts from new posters are checked by a moderator before they appear on the board.",
+            back_url: match &post.parent_id {
+                Some(parent_id) => config.post_url(parent_id),
+                None => config.index_url(),
+            },
+        };
+        let mut response = render::respond(HttpResponse::Accepted(), &template, "the approval notice");
+        if post.parent_id.is_some() {
+            remember::apply(&db, &config, &req, &mut response, remember_options, &options);
+        }
+        return Ok(response);
     }
 
-    db.flush().unwrap();
+    // The thread can go between the checks above and here, and slow mode
+    // is only known once the reply is going in. A refused post isn't
+    // recorded as sent, so the same form can be sent again.
+    let post = match store_post(&db, &settings, &post, admin.is_none()) {
+        Ok(post) => post,
+        Err(refused) => {
+            if let Some(file) = &post.file {
+                let _ = std::fs::remove_file(config.upload_path(file));
+            }
+            let error = validation::refusal(refused);
+            let rejection = Rejection::new(&config, &req, post.parent_id.as_deref(), vec![error]);
+            return Err(bounce_reply(&db, &config, &settings, &req, form_state, rejection).into());
+        }
+    };
+    if let Some(token) = &submit_token {
+        replay::record(&db, token, &post.id, &message, timestamp);
+    }
+    if let (true, Some(admin)) = (post.announcement, &admin) {
+        audit::record(&db, &admin.name, "announce", &post.id);
+    }
+    let default = match &post.parent_id {
+        Some(parent_id) => config.post_url(parent_id),
+        None => config.index_url(),
+    };
+    let mut location = redirect::location(&config, return_to.as_deref(), default);
+    // A reply goes to itself when it's going back to its thread
+    if let (Some(parent_id), Some(number)) = (&post.parent_id, post.reply_number) {
+        let thread_url = config.post_url(parent_id);
+        if location.split(['?', '#']).next() == Some(thread_url.as_str()) {
+            location = reply_form::after_reply(&location, number, config.reply_page(number), rendered_at);
+        }
+    }
+    let is_reply = post.parent_id.is_some();
+    events.publish(BoardEvent::Created {
+        post: Box::new(post),
+        spam_checks: !skip_spam_checks,
+    });
+    let mut response = redirect::see_other(&location).finish();
+    if is_reply {
+        remember::apply(&db, &config, &req, &mut response, remember_options, &options);
+    }
+    Ok(response)
+}
 
-    if let Some(parent_id) = post.parent_id {
-        Ok(HttpResponse::SeeOther()
-            .append_header(("Location", format!("/post/{}", parent_id)))
-            .finish())
-    } else {
-        Ok(HttpResponse::SeeOther()
-            .append_header(("Location", "/"))
-            .finish())
+// The page for `post`, for view_post and for a reply validation sent back.
+// The reply form starts from `form_state` when there is one; callers set
+// `quote` or `form_errors` themselves.
+fn thread_view<'a>(
+    db: &Db,
+    config: &'a Config,
+    settings: &'a BoardSettings,
+    req: &HttpRequest,
+    post: &'a Post,
+    chosen: Option<ReplyOrder>,
+    page: u64,
+) -> PostViewTemplate<'a> {
+    let order = chosen
+        .or_else(|| req.cookie(REPLY_ORDER_COOKIE).and_then(|cookie| ReplyOrder::parse(cookie.value())))
+        .unwrap_or(ReplyOrder::Asc);
+    // Replies whose thread is still there are sent to it instead
+    let orphaned = post.parent_id.is_some();
+    let replies = indexes::thread_replies(db, &post.id);
+    let last_seen = LastSeen::from_request(req).get(&post.id).filter(|_| !orphaned);
+    let first_new = last_seen.and_then(|last_seen| {
+        replies
+            .iter()
+            .filter(|reply| reply.timestamp > last_seen)
+            .filter_map(|reply| reply.reply_number)
+            .min()
+    });
+    let last_number = numbering::last(db, &post.id);
+    let mut replies = reply_slots(replies, backlinks::for_thread(db, &post.id), last_number, config.show_deleted_replies);
+    let last_number = replies.last().map_or(last_number, |slot| slot.number.max(last_number));
+    let page_count = config.reply_page(last_number) + 1;
+    // Past the end is the last page, as on the index
+    let page = page.min(page_count - 1);
+    if config.replies_per_page > 0 {
+        replies.retain(|slot| config.reply_page(slot.number) == page);
+    }
+    if order == ReplyOrder::Desc {
+        replies.reverse();
+    }
+    let admin = admin::Admin::signed_in(req);
+    let mut return_to = config.thread_page_url(&post.id, page);
+    if let Some(chosen) = chosen {
+        let separator = if return_to.contains('?') { '&' } else { '?' };
+        return_to.push_str(&format!("{}order={}", separator, chosen.as_str()));
+    }
+    PostViewTemplate {
+        config,
+        settings,
+        post,
+        replies,
+        order,
+        page,
+        page_count,
+        return_to,
+        summary: post.parent_id.is_none().then(|| thread_summary(db, &post.id)),
+        orphaned,
+        archived: post.parent_id.is_none() && archive::is_archived(db, &post.id),
+        submit_token: replay::new_token(),
+        submit_url: admin::submit_url(config, admin.as_ref()),
+        admin: admin.is_some(),
+        posting_status: rate_limit::posting_status(req),
+        remembered_options: remember::options(db, req),
+        form_state: None,
+        form_errors: Vec::new(),
+        quote: None,
+        seen_before: last_seen.is_some(),
+        first_new,
+        rendered_at: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
+        meanwhile: None,
     }
 }
 
-async fn view_post(db: web::Data<Db>, post_id: web::Path<String>) -> impl Responder {
-    let mut post = None;
-    let mut replies = Vec::new();
+// A reply sent from the form and refused goes back to its thread with the
+// form filled in again and why, see reply_form.rs. API clients, and replies
+// to threads that are gone, get the plain rejection.
+fn bounce_reply(
+    db: &Db,
+    config: &Config,
+    settings: &BoardSettings,
+    req: &HttpRequest,
+    form_state: Option<FormState>,
+    rejection: Rejection,
+) -> Rejection {
+    if rejection::wants_json(req) {
+        return rejection;
+    }
+    let form_state = match form_state {
+        Some(form_state) => form_state,
+        None => return rejection,
+    };
+    let thread = match load_post(db, &form_state.parent_id).filter(|thread| thread.parent_id.is_none()) {
+        Some(thread) => thread,
+        None => return rejection,
+    };
+    let mut template = thread_view(db, config, settings, req, &thread, None, 0);
+    template.form_state = Some(form_state);
+    template.form_errors = reply_form::banner(rejection.errors());
+    match render::to_string(&template, &format!("post {}", thread.id)) {
+        Some(html) => rejection.page(html),
+        None => rejection,
+    }
+}
 
-    for item in db.iter().values() {
-        let current_post: Post = serde_json::from_slice(&item.unwrap()).unwrap_or_else(|_| Post {
-            id: String::new(),
-            parent_id: None,
-            title: String::new(),
-            message: String::new(),
-            file: None,
-            timestamp: 0,
-        });
+async fn view_post(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    _age: AgeOk,
+    settings: web::Data<SettingsCache>,
+    req: HttpRequest,
+    post_id: web::Path<String>,
+    query: web::Query<ThreadQuery>,
+) -> impl Responder {
+    // An explicit ?order= wins and is remembered for later thread views
+    let chosen = query.order.as_deref().and_then(ReplyOrder::parse);
+    let page = match parse_page(query.page.as_deref()) {
+        Some(page) => page as u64,
+        None => return bad_page(&config, config.post_url(&post_id)),
+    };
+    let live = db;
+    let db = req.app_data::<web::Data<ArchiveDb>>().unwrap().holding(&live, &post_id);
 
-        if current_post.id == *post_id {
-            post = Some(current_post.clone());
-        } else if let Some(parent_id) = &current_post.parent_id {
-            if parent_id == &*post_id {
-                replies.push(current_post.clone());
+    if let Some(post) = load_post(&db, &post_id) {
+        // A link to a reply goes to that reply in its thread. Replies whose
+        // thread is gone are shown on their own rather than lost.
+        if let Some(thread_id) = &post.parent_id {
+            if db.contains_key(thread_id).unwrap() {
+                return redirect::found(&config.reply_url(thread_id, post.reply_number));
             }
         }
+        let settings = settings.get(&live);
+        let mut template = thread_view(&db, &config, &settings, &req, &post, chosen, page);
+        template.quote = query.quote.filter(|&number| number > 0);
+        // Worked out on every load rather than kept, see reply_form.rs. The
+        // whole thread counts, not just this page.
+        if let (Some(number), Some(since)) = (query.just_posted, query.since) {
+            let replies = indexes::thread_replies(&db, &post.id);
+            template.meanwhile = Some((number, reply_form::posted_meanwhile(replies.iter(), number, since)));
+        }
+        let mut response = HttpResponse::Ok();
+        if template.personal() {
+            response.insert_header(CacheControl(vec![CacheDirective::Private, CacheDirective::NoStore]));
+        }
+        if !template.orphaned {
+            response.cookie(LastSeen::from_request(&req).viewed(&config, &post.id, template.newest()));
+        }
+        if let Some(chosen) = chosen {
+            response.cookie(
+                Cookie::build(REPLY_ORDER_COOKIE, chosen.as_str())
+                    .path(config.index_url())
+                    .max_age(CookieDuration::days(365))
+                    .finish(),
+            );
+        }
+        render::respond(response, &template, &format!("post {}", post.id))
+    } else {
+        HttpResponse::NotFound().finish()
     }
+}
 
-    // Sort replies by timestamp in descending order
-    replies.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
-    replies.reverse();
+// `/post/{thread id}/{number}`: reply `number` of a thread, in place. Numbers
+// of deleted replies still go to the thread, where they show as a stub.
+async fn view_reply_number(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    archive: web::Data<ArchiveDb>,
+    path: web::Path<(String, u64)>,
+) -> HttpResponse {
+    let (thread_id, number) = path.into_inner();
+    let db = archive.holding(&db, &thread_id);
+    match load_post(&db, &thread_id) {
+        Some(thread) if thread.parent_id.is_none() && number >= 1 && number <= numbering::last(&db, &thread_id) => {
+            redirect::found(&config.reply_url(&thread_id, Some(number)))
+        }
+        _ => HttpResponse::NotFound().finish(),
+    }
+}
 
-    if let Some(post) = post {
-        let template = PostViewTemplate {
-            post: &post,
-            replies: &replies,
-        };
-        HttpResponse::Ok().content_type("text/html").body(template.render().unwrap())
-    } else {
-        HttpResponse::NotFound().finish()
+// For load balancers and monitoring. The board stays ready while uploads
+// are off, since text posts still work; `uploads` says which it is.
+// `events` counts what each event handler did since startup.
+async fn readyz(
+    config: web::Data<Config>,
+    disk: web::Data<DiskGuard>,
+    events: web::Data<EventBus>,
+    retained: web::Data<retention::RetentionCounts>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "ok",
+        "uploads": if disk.is_low() { "disabled" } else { "enabled" },
+        "events": events.counts(),
+        "retention": retained.counts(&config.retention),
+    }))
+}
+
+// Uploaded files. Media is served inline as before; anything else is sent as
+// an attachment under the name it was uploaded with.
+async fn serve_upload(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    archive: web::Data<ArchiveDb>,
+    _age: AgeOk,
+    req: HttpRequest,
+    file: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    if !upload::is_stored_path(&file) {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    let named = fs::NamedFile::open_async(config.upload_path(&file)).await?;
+
+    // Files of moved threads are still here, their posts aren't
+    let owner = upload::owner(&db, &file).or_else(|| upload::owner(&archive.get()?, &file));
+    match owner.filter(|post| !post.is_media()) {
+        Some(post) => {
+            let name = post.original_name.unwrap_or_else(|| file.rsplit('/').next().unwrap_or_default().to_string());
+            let mut response = named.disable_content_disposition().into_response(&req);
+            response.headers_mut().insert(
+                CONTENT_DISPOSITION,
+                HeaderValue::from_str(&filename::suggest_for(&name).disposition()).unwrap(),
+            );
+            Ok(response)
+        }
+        None => Ok(named.into_response(&req)),
+    }
+}
+
+// A post as the index and thread pages show it, without the reply link
+fn render_fragment(config: &Config, post: &Post) -> String {
+    render::fragment(&PostFragmentTemplate { config, post }, &format!("post {}", post.id))
+}
+
+// A single post's markup, for hover previews and the like.
+async fn post_fragment(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    archive: web::Data<ArchiveDb>,
+    _age: AgeOk,
+    post_id: web::Path<String>,
+) -> HttpResponse {
+    match load_post(&archive.holding(&db, &post_id), &post_id) {
+        Some(post) => HttpResponse::Ok().content_type("text/html").body(render_fragment(&config, &post)),
+        None => HttpResponse::NotFound().finish(),
     }
 }
 
+#[derive(Deserialize)]
+struct PreviewForm {
+    message: String,
+}
+
+// The formatted HTML a message would be stored as, without storing it.
+async fn preview(form: web::Form<PreviewForm>) -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html").body(format::format_message(&form.message))
+}
+
 #[derive(Deserialize)]
 struct PageQuery {
-    page: Option<usize>,
-}
-
-async fn index(db: web::Data<Db>, query: web::Query<PageQuery>) -> impl Responder {
-    let page = query.page.unwrap_or(0);
-    let start_index = page * POSTS_PER_PAGE;
-    let end_index = start_index + POSTS_PER_PAGE;
-
-    let mut posts = Vec::new();
-    for item in db.iter().values() {
-        let post: Post = serde_json::from_slice(&item.unwrap()).unwrap_or_else(|_| Post {
-            id: String::new(),
-            parent_id: None,
-            title: String::new(),
-            message: String::new(),
-            file: None,
-            timestamp: 0,
-        });
-        if post.parent_id.is_none() {
-            posts.push(post);
-        }
+    // Kept as text, see parse_page
+    page: Option<String>,
+    // Kept as text so junk falls back to the default instead of a 400
+    per_page: Option<String>,
+    sort: Option<String>,
+}
+
+// Missing or empty is the first page. Numbers too big for usize are still
+// numbers; the caller caps them at the last page. Anything else, including
+// negative numbers, is None.
+fn parse_page(raw: Option<&str>) -> Option<usize> {
+    let raw = raw.unwrap_or_default().trim();
+    if raw.is_empty() {
+        return Some(0);
     }
+    if !raw.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(raw.parse().unwrap_or(usize::MAX))
+}
 
-    // Sort posts by timestamp in descending order
-    posts.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+// What index_footer.html closes, for when it can't be rendered
+const INDEX_CLOSING: &str = "    </main>\n</body>\n</html>\n";
 
-    // Paginate posts
-    let paginated_posts: Vec<Post> = posts[start_index..end_index.min(posts.len())].to_vec();
+// For a `page` parse_page refused, with a link back to the list's first page
+fn bad_page(config: &Config, back_url: String) -> HttpResponse {
+    let template = NoticeTemplate {
+        config,
+        heading: "No such page",
+        message: "The page number has to be a whole number, like 0, 1 or 2.",
+        back_url,
+    };
+    render::respond(HttpResponse::BadRequest(), &template, "the bad page notice")
+}
+
+async fn index(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    _age: AgeOk,
+    settings: web::Data<SettingsCache>,
+    admin: Option<admin::Admin>,
+    req: HttpRequest,
+    query: web::Query<PageQuery>,
+) -> impl Responder {
+    let page = match parse_page(query.page.as_deref()) {
+        Some(page) => page,
+        None => return bad_page(&config, config.index_url()),
+    };
+    let settings = settings.get(&db);
+    let rankings = req.app_data::<web::Data<Rankings>>().unwrap();
+    let requested_per_page = config.clamp_per_page(query.per_page.as_deref());
+    let per_page = requested_per_page.unwrap_or(settings.posts_per_page);
+    let sort = ThreadSort::parse(query.sort.as_deref());
+
+    // Stickies sit above page 0 without taking up any of its slots, so
+    // paging only ever counts normal threads
+    let seen = LastSeen::from_request(&req);
+    let sticky_ids = indexes::sticky_ids(&db);
+    let thread_count = db.open_tree("bumps").unwrap().len().saturating_sub(sticky_ids.len());
+    let page_count = thread_count.div_ceil(per_page).max(1);
+    let page = page.min(page_count - 1);
+    let stickies: Vec<String> = if page == 0 { indexes::stickies(&db) } else { Vec::new() }
+        .iter()
+        .filter_map(|thread| render_thread_block(&db, &config, &seen, thread, true))
+        .collect();
+
+    // The page starts after the last key of the pages before it
+    let skip = page.saturating_mul(per_page);
+    let mut after = None;
+    let mut skipped = 0;
+    for (key, _) in sorting::threads(&db, rankings, sort, None)
+        .filter(|(_, thread)| !sticky_ids.contains(&thread.id))
+        .take(skip)
+    {
+        after = Some(key);
+        skipped += 1;
+    }
 
     let prev_page = if page > 0 { Some(page - 1) } else { None };
-    let next_page = if end_index < posts.len() { Some(page + 1) } else { None };
+    let next_page = if page + 1 < page_count { Some(page + 1) } else { None };
 
-    let template = IndexTemplate {
-        posts: &paginated_posts,
+    let popular = if config.show_popular_threads {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        activity::popular_threads(&db, now, POPULAR_THREADS)
+    } else {
+        Vec::new()
+    };
+
+    let posting_status = rate_limit::posting_status(&req);
+    let head = IndexTemplate {
+        config: &config,
+        settings: &settings,
+        popular: &popular,
+        per_page: requested_per_page,
+        sort,
+        show_lock_field: admin.is_some() || config.thread_locks_public,
+        submit_token: replay::new_token(),
+        submit_url: admin::submit_url(&config, admin.as_ref()),
+        posting_status: posting_status.clone(),
+        stickies: &stickies,
+        announcement: announcement::current(&db),
+    };
+    // Nothing has been sent yet, so a broken head can still be a proper
+    // error page
+    let head = match render::to_string(&head, &format!("index page {}", page)) {
+        Some(head) => head,
+        None => return render::error_page(),
+    };
+    let empty_message = (thread_count == 0 && sticky_ids.is_empty()).then_some(if settings.locked {
+        "No threads yet."
+    } else {
+        "No threads yet. Start the first one with the form above."
+    });
+    let footer = IndexFooterTemplate {
+        config: &config,
+        empty_message,
+        page,
+        page_count,
+        per_page: requested_per_page,
+        sort,
         prev_page,
         next_page,
     };
+    // Without page links the threads still show
+    let footer = render::to_string(&footer, &format!("index page {}", page)).unwrap_or_else(|| INDEX_CLOSING.to_string());
+
+    // Status lines, new-reply badges and an admin's token are this
+    // visitor's own
+    let personal = posting_status.is_some() || !seen.is_empty() || admin.is_some();
+    let scan = ThreadScan {
+        db: db.clone(),
+        config: config.clone(),
+        rankings: rankings.clone(),
+        sort,
+        sticky_ids,
+        seen,
+        after,
+        // A page past the end (threads deleted meanwhile) is empty
+        remaining: if skipped < skip { 0 } else { per_page },
+    };
+    let threads = stream::unfold(scan, |mut scan| async move { scan.next_block().map(|block| (block, scan)) });
+    let body = stream::once(async { head })
+        .chain(threads)
+        .chain(stream::once(async { footer }))
+        .map(|chunk| Ok::<_, Error>(Bytes::from(chunk)));
+    let mut response = HttpResponse::Ok();
+    if personal {
+        response.insert_header(CacheControl(vec![CacheDirective::Private, CacheDirective::NoStore]));
+    }
+    response.content_type("text/html").streaming(body)
+}
+
+// See render::self_check
+fn check_templates(config: &Config, thread: &Post, reply: &Post) -> Result<(), String> {
+    let settings = BoardSettings::default();
+    for order in [ReplyOrder::Asc, ReplyOrder::Desc] {
+        let slots = vec![
+            ReplySlot {
+                number: 1,
+                post: Some(reply.clone()),
+                quoted_by: vec![2],
+            },
+            ReplySlot {
+                number: 2,
+                post: None,
+                quoted_by: Vec::new(),
+            },
+        ];
+        // A form sent back, on the same page as remembered options and a
+        // quote it has to win over
+        let form_state = (order == ReplyOrder::Asc).then(|| FormState {
+            parent_id: thread.id.clone(),
+            return_to: None,
+            title: "\"><b>".to_string(),
+            name: "Tester#secret".to_string(),
+            options: "sage".to_string(),
+            remember_options: false,
+            message: "\n>>1 <b>see above</b>".to_string(),
+            had_file: true,
+            rendered_at: Some(1),
+        });
+        render::check(&PostViewTemplate {
+            config,
+            settings: &settings,
+            post: thread,
+            replies: slots,
+            order,
+            page: (order == ReplyOrder::Desc) as u64,
+            page_count: 2,
+            return_to: config.post_url(&thread.id),
+            summary: Some(posters::summary(1, 1)),
+            orphaned: false,
+            archived: order == ReplyOrder::Desc,
+            submit_token: replay::new_token(),
+            submit_url: config.url_for("/submit?csrf=token"),
+            admin: order == ReplyOrder::Asc,
+            posting_status: (order == ReplyOrder::Asc).then(|| "3 of 10 hourly posts used.".to_string()),
+            remembered_options: (order == ReplyOrder::Asc).then(|| "\"><b>sage".to_string()),
+            form_state,
+            form_errors: vec!["Title: The title is too long.".to_string()],
+            quote: Some(1),
+            seen_before: true,
+            first_new: Some(1),
+            rendered_at: 2,
+            meanwhile: Some((1, 3)),
+        })?;
+    }
+    render::check(&PostViewTemplate {
+        config,
+        settings: &settings,
+        post: reply,
+        replies: Vec::new(),
+        order: ReplyOrder::Asc,
+        page: 0,
+        page_count: 1,
+        return_to: config.post_url(&reply.id),
+        summary: None,
+        orphaned: true,
+        archived: false,
+        submit_token: replay::new_token(),
+        submit_url: config.url_for("/submit"),
+        admin: false,
+        posting_status: None,
+        remembered_options: None,
+        form_state: None,
+        form_errors: Vec::new(),
+        quote: None,
+        seen_before: false,
+        first_new: None,
+        rendered_at: 2,
+        meanwhile: None,
+    })?;
+    for post in [thread, reply] {
+        render::check(&PostFragmentTemplate { config, post })?;
+    }
+    for sticky in [true, false] {
+        render::check(&IndexThreadTemplate {
+            config,
+            post: thread,
+            sticky,
+            summary: posters::summary(1, 1),
+            new_replies: sticky.then_some(3),
+            preview: (!sticky).then(previews::sample),
+        })?;
+    }
+    render::check(&IndexTemplate {
+        config,
+        settings: &settings,
+        per_page: Some(10),
+        sort: ThreadSort::Replies,
+        show_lock_field: true,
+        submit_token: replay::new_token(),
+        submit_url: config.url_for("/submit"),
+        posting_status: Some("You can post again in 12 s.".to_string()),
+        stickies: &["<div></div>".to_string()],
+        popular: &[(thread.clone(), 3)],
+        announcement: Some(thread.clone()),
+    })?;
+    for empty_message in [None, Some("No threads yet.")] {
+        render::check(&IndexFooterTemplate {
+            config,
+            empty_message,
+            page: 1,
+            page_count: 3,
+            per_page: Some(10),
+            sort: ThreadSort::Replies,
+            prev_page: Some(0),
+            next_page: Some(2),
+        })?;
+    }
+    render::check(&NoticeTemplate {
+        config,
+        heading: "Heading",
+        message: "Message.",
+        back_url: config.index_url(),
+    })
+}
+
+// Opens the database and brings data from older versions up to date. An
+// in-memory one starts empty and is gone when the server stops.
+fn open_db(config: &Config, flags: &ServerFlags) -> Db {
+    let db = if flags.in_memory {
+        sled::Config::new().temporary(true).open().unwrap()
+    } else {
+        startup::open_or_exit("my_db", flags.wait_for_lock)
+    };
+    prepare_db(&db, config);
+    db
+}
+
+// Markers and indexes a database needs before the board is served from
+// it, built once for databases from before they existed
+fn prepare_db(db: &Db, config: &Config) {
+    setup::mark_if_new(db);
+    indexes::build_if_missing(db, config.scan_threads);
+    indexes::build_creations_if_missing(db, config.scan_threads);
+    indexes::build_posted_if_missing(db, config.scan_threads);
+    upload::backfill_media_kinds(db);
+    numbering::assign_if_missing(db);
+    indexes::build_numbers_if_missing(db, config.scan_threads);
+    backlinks::build_if_missing(db, config.scan_threads);
+    activity::build_post_hours_if_missing(db);
+    posters::build_if_missing(db);
+}
+
+// What the handlers share, made once at startup; every worker's App gets
+// a clone, see app
+#[derive(Clone)]
+struct AppState {
+    db: Db,
+    config: Config,
+    runtime: web::Data<RuntimeCache>,
+    archive: web::Data<ArchiveDb>,
+    limiter: web::Data<RateLimiter>,
+    settings: web::Data<SettingsCache>,
+    stats_cache: web::Data<stats::StatsCache>,
+    manifest_cache: web::Data<manifest::ManifestCache>,
+    rankings: web::Data<Rankings>,
+    disk: web::Data<DiskGuard>,
+    exemptions: web::Data<ExemptionCache>,
+    uploads_in_flight: web::Data<replay::InFlight>,
+    upload_slots: web::Data<upload_slots::UploadSlots>,
+    events: web::Data<EventBus>,
+    retained: web::Data<retention::RetentionCounts>,
+}
 
-    HttpResponse::Ok().content_type("text/html").body(template.render().unwrap())
+// Everything that reacts to BoardEvents, see events.rs
+fn event_handlers(db: &Db, config: &Config, settings: &web::Data<SettingsCache>) -> Vec<Box<dyn events::EventHandler>> {
+    vec![
+        Box::new(Webhooks::start(config)),
+        Box::new(notify::ReplyNotifier::new(db, config)),
+        Box::new(watchlist::Watchlist::new(db, settings.clone())),
+        Box::new(moderation::DuplicateCheck::new(db, config)),
+        Box::new(previews::PreviewCache::new(db, config)),
+        Box::new(changes::BoardLog::new(db, config.export_retained_changes)),
+    ]
+}
+
+impl AppState {
+    fn new(db: Db, config: Config) -> AppState {
+        let settings = web::Data::new(SettingsCache::default());
+        let events = web::Data::new(EventBus::start(event_handlers(&db, &config, &settings)));
+        AppState {
+            runtime: web::Data::new(RuntimeCache::new(RuntimeSettings::from_env(), config.config_file.clone())),
+            archive: web::Data::new(ArchiveDb::open(&config)),
+            limiter: web::Data::new(RateLimiter::default()),
+            settings,
+            stats_cache: web::Data::new(stats::StatsCache::default()),
+            manifest_cache: web::Data::new(manifest::ManifestCache::default()),
+            rankings: web::Data::new(Rankings::default()),
+            disk: web::Data::new(DiskGuard::new(&config, Box::new(VolumeProbe))),
+            exemptions: web::Data::new(ExemptionCache::default()),
+            uploads_in_flight: web::Data::new(replay::InFlight::default()),
+            upload_slots: web::Data::new(upload_slots::UploadSlots::new(&config)),
+            events,
+            retained: web::Data::new(retention::RetentionCounts::default()),
+            db,
+            config,
+        }
+    }
+}
+
+// The whole board as one App, for the server and for the tests
+fn app(
+    state: AppState,
+) -> App<
+    impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse<impl MessageBody>, Error = Error, InitError = ()>,
+> {
+    App::new()
+        .app_data(web::Data::new(state.db.clone()))
+        .app_data(web::Data::new(state.config.clone()))
+        .app_data(state.runtime.clone())
+        .app_data(state.archive.clone())
+        .app_data(state.limiter.clone())
+        .app_data(state.settings.clone())
+        .app_data(state.stats_cache.clone())
+        .app_data(state.manifest_cache.clone())
+        .app_data(state.rankings.clone())
+        .app_data(state.disk.clone())
+        .app_data(state.exemptions.clone())
+        .app_data(state.uploads_in_flight.clone())
+        .app_data(state.upload_slots.clone())
+        .app_data(state.events.clone())
+        .app_data(state.retained.clone())
+        .wrap(DefaultHeaders::new().add((CONTENT_LANGUAGE, state.config.lang.clone())))
+        .wrap(head::HeadRequests)
+        .wrap(route_limits::RouteLimits)
+        .service(
+            web::scope(&state.config.base_path)
+                .route("/static/uploads/{file:.*}", web::get().to(serve_upload))
+                .route("/readyz", web::get().to(readyz))
+                // Not rate limited: directory sites poll it and it's cached
+                .route(&state.config.manifest_path, web::get().to(manifest::manifest))
+                .service(
+                    web::resource("/unsubscribe/{token}")
+                        .wrap(RateLimit::new(RouteClass::Cheap))
+                        .route(web::get().guard(head::NotHead).to(notify::unsubscribe)),
+                )
+                .service(web::resource("/age-check").wrap(RateLimit::new(RouteClass::Cheap)).route(web::post().to(age_gate::confirm)))
+                .service(web::resource("/theme.css").wrap(RateLimit::new(RouteClass::Cheap)).route(web::get().to(theme::stylesheet)))
+                .service(web::resource("/theme").wrap(RateLimit::new(RouteClass::Cheap)).route(web::post().to(theme::pick)))
+                .service(fs::Files::new("/static", "./static").show_files_listing())
+                .service(web::resource("/").wrap(RateLimit::new(RouteClass::Render)).route(web::get().to(index)))
+                .service(web::resource("/submit").wrap(RateLimit::new(RouteClass::Write)).route(web::post().to(save_post)))
+                .service(web::resource("/post/{id}").wrap(RateLimit::new(RouteClass::Render)).route(web::get().to(view_post)))
+                .service(
+                    web::resource("/post/{id}/{number}")
+                        .wrap(RateLimit::new(RouteClass::Cheap))
+                        .route(web::get().to(view_reply_number)),
+                )
+                .service(web::resource("/preview").wrap(RateLimit::new(RouteClass::Render)).route(web::post().to(preview)))
+                .service(
+                    web::resource("/fragment/post/{id}")
+                        .wrap(RateLimit::new(RouteClass::Cheap))
+                        .route(web::get().to(post_fragment)),
+                )
+                .service(web::resource("/archive").wrap(RateLimit::new(RouteClass::Render)).route(web::get().to(archive::years)))
+                .service(
+                    web::resource("/archive/{year}")
+                        .wrap(RateLimit::new(RouteClass::Render))
+                        .route(web::get().to(archive::months)),
+                )
+                .service(
+                    web::resource("/archive/{year}/{month}")
+                        .wrap(RateLimit::new(RouteClass::Render))
+                        .route(web::get().to(archive::month)),
+                )
+                .service(web::resource("/stats").wrap(RateLimit::new(RouteClass::Render)).route(web::get().to(stats::stats)))
+                .service(
+                    web::resource("/stats.json")
+                        .wrap(RateLimit::new(RouteClass::Cheap))
+                        .route(web::get().to(stats::stats_json)),
+                )
+                .service(
+                    web::resource("/widget")
+                        .wrap(RateLimit::new(RouteClass::Cheap))
+                        .route(web::get().to(widget::widget)),
+                )
+                .service(
+                    web::resource("/widget.json")
+                        .wrap(RateLimit::new(RouteClass::Cheap))
+                        .route(web::get().to(widget::widget_json)),
+                )
+                .service(
+                    web::resource("/api/threads")
+                        .wrap(RateLimit::new(RouteClass::Render))
+                        .route(web::get().to(api::threads)),
+                )
+                .service(
+                    web::resource("/api/posts")
+                        .wrap(RateLimit::new(RouteClass::Render))
+                        .route(web::get().to(api::posts)),
+                )
+                .service(
+                    web::resource("/api/post/{id}")
+                        .wrap(RateLimit::new(RouteClass::Render))
+                        .route(web::get().to(api::post)),
+                )
+                .service(
+                    web::resource("/api/thread/{id}/changes")
+                        .wrap(RateLimit::new(RouteClass::Cheap))
+                        .route(web::get().to(api::thread_changes)),
+                )
+                .route("/admin/login", web::get().to(admin::login_form))
+                .service(
+                    web::resource("/admin/login")
+                        .wrap(RateLimit::new(RouteClass::Write))
+                        .route(web::post().to(admin::login)),
+                )
+                .route("/admin/logout", web::post().to(admin::logout))
+                .route("/admin/post/{id}/dossier", web::get().to(admin::dossier))
+                .route("/admin/post/{id}/dossier.zip", web::get().to(admin::dossier_zip))
+                .route("/admin/posts", web::get().to(admin::posts))
+                .route("/admin/post/{id}/raw", web::get().to(admin::raw_record))
+                .route("/admin/post/{id}/renderings", web::get().to(admin::renderings))
+                .route("/admin/performance", web::get().to(timings::performance))
+                .route("/admin/setup/dismiss", web::post().to(setup::dismiss))
+                .route("/admin/announce", web::get().to(announcement::compose))
+                .route("/admin/post/{id}/history", web::get().to(admin::history))
+                .route("/admin/post/{id}/edit", web::post().to(admin::edit_message))
+                .route("/admin/post/{id}/restore/{version}", web::post().to(admin::restore_version))
+                .route("/admin/post/{id}/delete-thread", web::post().to(admin::delete_thread))
+                .route("/admin/post/{id}/sticky", web::post().to(admin::sticky))
+                .route("/admin/post/{id}/unsticky", web::post().to(admin::unsticky))
+                .route("/admin/post/{id}/sticky-order", web::post().to(admin::sticky_order))
+                .route("/admin/post/{id}/slow-mode", web::post().to(admin::slow_mode))
+                .route("/admin/post/{id}/archive", web::post().to(admin::archive_thread))
+                .route("/admin/pending", web::get().to(admin::pending_queue))
+                .route("/admin/pending/{id}/approve", web::post().to(admin::approve_pending))
+                .route("/admin/pending/{id}/reject", web::post().to(admin::reject_pending))
+                .route("/admin/settings", web::get().to(admin::settings_form))
+                .route("/admin/settings", web::post().to(admin::save_settings))
+                .route("/admin/reload-config", web::post().to(admin::reload_config))
+                .route("/admin/export/stream", web::get().to(export::stream))
+                .route("/admin/takedown", web::get().to(admin::takedown_form))
+                .route("/admin/takedown", web::post().to(admin::takedown))
+                .route("/admin/exemptions", web::get().to(admin::exemption_list))
+                .route("/admin/exemptions", web::post().to(admin::add_exemption))
+                .route("/admin/exemptions/{ip_hash}/remove", web::post().to(admin::remove_exemption))
+                .route("/admin/flagged-images", web::get().to(admin::flagged_images))
+                .route("/admin/flagged-images/{hash}/delete", web::post().to(admin::delete_flagged))
+                .route("/admin/flagged-images/{hash}/ban", web::post().to(admin::ban_flagged))
+                .route("/admin/flagged-images/{hash}/allow", web::post().to(admin::allow_flagged))
+                .route("/admin/flagged", web::get().to(admin::watch_matches))
+                .route("/admin/flagged/{name}/delete", web::post().to(admin::delete_watched))
+                .route("/admin/flagged/{name}/ban", web::post().to(admin::ban_watched))
+                .route("/admin/flagged/{name}/dismiss", web::post().to(admin::dismiss_watched)),
+        )
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let db = sled::open("my_db").unwrap();
-    let upload_dir = std::env::var("UPLOAD_DIR").unwrap_or_else(|_| "./static/uploads".to_string());
-    std::fs::create_dir_all(&upload_dir).unwrap();
-
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(db.clone()))
-            .app_data(web::Data::new(upload_dir.clone()))
-            .service(fs::Files::new("/static", "./static").show_files_listing())
-            .route("/", web::get().to(index))
-            .route("/submit", web::post().to(save_post))
-            .route("/post/{id}", web::get().to(view_post))
-    })
-    .bind("0.0.0.0:8080")?
-    .run()
-    .await
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("verify-files") => return verify::run(&args[1..]),
+        Some("seed") => return seed::run(&args[1..]),
+        Some("migrate-uploads") => return relocate::run(&args[1..]),
+        Some("reindex") => return reindex::run(&args[1..]),
+        _ => {}
+    }
+    let flags = ServerFlags::parse(&args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+
+    let mut config = Config::from_env();
+    timings::enable(config.timings);
+    let db = open_db(&config, &flags);
+    previews::clear(&db);
+    if let Err(e) = render::self_check(&config) {
+        eprintln!("template self-check failed, not starting: {}", e);
+        std::process::exit(1);
+    }
+    config.upload_dir = startup::prepare_upload_dir(&config.upload_dir).unwrap_or_else(|e| {
+        eprintln!("{}, not starting", e);
+        std::process::exit(1);
+    });
+    upload::clean_temp(&config.upload_dir);
+    notify::start(&config, &db);
+    let state = AppState::new(db, config);
+    state.disk.check();
+    actix_web::rt::spawn(maintenance::run(
+        state.db.clone(),
+        state.config.clone(),
+        state.archive.clone(),
+        state.disk.clone(),
+        state.events.clone(),
+        state.retained.clone(),
+    ));
+    actix_web::rt::spawn(runtime::reload_on_hangup(state.runtime.clone()));
+
+    HttpServer::new(move || app(state.clone())).bind("0.0.0.0:8080")?.run().await
 }
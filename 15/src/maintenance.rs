@@ -0,0 +1,55 @@
+// Periodic housekeeping, run in the background for as long as the server
+// is up. Dated data is purged per the retention policy, see retention.rs,
+// and old attachments shrunk to thumbnails per media_prune.rs; the rest
+// here is expiry the board relies on, not a policy. Archived threads move
+// to the archive database when there is one, see archive_db.rs.
+
+use actix_web::rt::time;
+use actix_web::web;
+use sled::Db;
+use std::time::{Duration, SystemTime};
+
+use crate::archive_db::ArchiveDb;
+use crate::config::Config;
+use crate::diskspace::DiskGuard;
+use crate::events::EventBus;
+use crate::retention::{self, RetentionCounts};
+use crate::{activity, admin, exemptions, media_prune, replay, upload};
+
+const INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+pub async fn run(
+    db: Db,
+    config: Config,
+    archive: web::Data<ArchiveDb>,
+    disk: web::Data<DiskGuard>,
+    events: web::Data<EventBus>,
+    retained: web::Data<RetentionCounts>,
+) {
+    let mut interval = time::interval(INTERVAL);
+    loop {
+        interval.tick().await;
+        let db = db.clone();
+        let config = config.clone();
+        let archive = archive.clone();
+        let disk = disk.clone();
+        let events = events.clone();
+        let retained = retained.clone();
+        let result = web::block(move || {
+            disk.check();
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            retention::run(&db, &config, &events, &retained, now)
+                + media_prune::run(&db, &config, &events, now)
+                + activity::prune(&db, now)
+                + exemptions::prune(&db, now)
+                + replay::prune(&db, now)
+                + admin::prune_sessions(&db, &config, now)
+                + upload::clean_temp(&config.upload_dir)
+                + archive.transfer(&db, &config)
+        })
+        .await;
+        if let Err(e) = result {
+            eprintln!("maintenance failed: {}", e);
+        }
+    }
+}
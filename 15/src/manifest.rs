@@ -0,0 +1,121 @@
+// A description of the board for sites that list boards, served at
+// MANIFEST_PATH (/.well-known/board.json unless set). It's put together
+// from the board settings, the config and the index trees, and cached for
+// CACHE_TTL since counting threads reads every key of `bumps`. The format
+// is static/board.schema.json; fields without a value are left out rather
+// than sent as null or "".
+//
+// The schema has a `boards` list for servers hosting several boards. This
+// one hosts a single board, so it never sends it.
+
+use actix_web::http::header;
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use sled::Db;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::counters;
+use crate::settings::SettingsCache;
+
+const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+const CACHE_CONTROL: &str = "public, max-age=300";
+// Of the features listing sites ask about, the ones this board has.
+// There are no feeds or search yet.
+const FEATURES: [&str; 4] = ["api", "archive", "stats", "widget"];
+
+#[derive(Serialize)]
+struct PostCounts {
+    // On the board, stickies included
+    threads: usize,
+    archived_threads: usize,
+    replies: u64,
+}
+
+#[derive(Serialize)]
+struct Software {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct Manifest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    // Absolute only when PUBLIC_URL is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    language: String,
+    nsfw: bool,
+    posts: PostCounts,
+    software: Software,
+    features: &'static [&'static str],
+}
+
+fn non_empty(text: &str) -> Option<String> {
+    Some(text.trim().to_string()).filter(|text| !text.is_empty())
+}
+
+impl Manifest {
+    fn build(db: &Db, config: &Config, settings: &SettingsCache) -> Manifest {
+        let settings = settings.get(db);
+        let replies = db
+            .open_tree("reply_counts")
+            .unwrap()
+            .iter()
+            .values()
+            .map(|count| counters::decode(Some(&count.unwrap())))
+            .sum();
+        Manifest {
+            name: non_empty(&settings.name),
+            description: non_empty(&settings.description),
+            url: non_empty(&config.public_url).map(|public_url| format!("{}{}", public_url, config.index_url())),
+            language: config.lang.clone(),
+            nsfw: settings.nsfw,
+            posts: PostCounts {
+                threads: db.open_tree("bumps").unwrap().len(),
+                archived_threads: db.open_tree("archived").unwrap().len(),
+                replies,
+            },
+            software: Software {
+                name: env!("CARGO_PKG_NAME"),
+                version: env!("CARGO_PKG_VERSION"),
+            },
+            features: &FEATURES,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ManifestCache {
+    manifest: Mutex<Option<(Instant, Arc<Manifest>)>>,
+}
+
+impl ManifestCache {
+    fn get(&self, db: &Db, config: &Config, settings: &SettingsCache) -> Arc<Manifest> {
+        let mut cached = self.manifest.lock().unwrap();
+        if let Some((built, manifest)) = cached.as_ref() {
+            if built.elapsed() < CACHE_TTL {
+                return manifest.clone();
+            }
+        }
+        let manifest = Arc::new(Manifest::build(db, config, settings));
+        *cached = Some((Instant::now(), manifest.clone()));
+        manifest
+    }
+}
+
+pub async fn manifest(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    settings: web::Data<SettingsCache>,
+    cache: web::Data<ManifestCache>,
+) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, CACHE_CONTROL))
+        .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+        .json(&*cache.get(&db, &config, &settings))
+}
@@ -0,0 +1,218 @@
+// Making room by shrinking old attachments instead of deleting old threads.
+// The hourly maintenance job replaces an attachment with a thumbnail once
+// it's older than MEDIA_PRUNE_AFTER, and, with MEDIA_BUDGET set, oldest
+// first for as long as the attachments together take more than the budget.
+// Neither is set by default, which leaves this off.
+//
+// A pruned post loses `file` and gains `thumbnail` and `media_pruned`, and
+// its pages show the thumbnail with a note that the full file is gone. Only
+// images get a thumbnail; other media are just deleted. Thumbnails aren't
+// counted toward the budget.
+//
+// The first posts of sticky threads are never pruned, though their files
+// count toward the budget. Posts don't share files, but a file is only
+// deleted while the uploads index still names the post as its owner, so
+// one another post took over is left alone.
+//
+// Each run that prunes anything logs, and writes to the audit log, how much
+// went and how much is left against the budget. MEDIA_PRUNE_DRY_RUN=true
+// only logs what a run would prune, and changes nothing.
+
+use serde_json::Value;
+use sled::Db;
+
+use crate::config::Config;
+use crate::events::{BoardEvent, EventBus};
+use crate::{audit, indexes, load_post, schema, storage, upload, Post};
+
+// The longest side of a thumbnail, as the pages show images
+const THUMBNAIL_SIZE: u32 = 200;
+
+struct Candidate {
+    post_id: String,
+    file: String,
+    // When the post was made, see indexes::created_at for first posts
+    at: u64,
+    bytes: u64,
+}
+
+// How many of `candidates`, oldest first, to prune: every one older than
+// `after` seconds, then more while the files still on disk add up to more
+// than `budget`. `total` is every file's size, sticky ones included.
+fn select(candidates: &[(u64, u64)], total: u64, now: u64, after: Option<u64>, budget: Option<u64>) -> usize {
+    let mut left = total;
+    let mut count = 0;
+    for &(at, bytes) in candidates {
+        let too_old = after.is_some_and(|after| at.saturating_add(after) <= now);
+        let over_budget = budget.is_some_and(|budget| left > budget);
+        if !too_old && !over_budget {
+            break;
+        }
+        left = left.saturating_sub(bytes);
+        count += 1;
+    }
+    count
+}
+
+// Every post with its file still on disk, oldest first, and the size of
+// all their files
+fn candidates(db: &Db, config: &Config) -> (Vec<Candidate>, u64) {
+    let stickies = indexes::sticky_ids(db);
+    let mut found = Vec::new();
+    let mut total = 0;
+    for entry in db.open_tree("uploads").unwrap().iter() {
+        let (file, post_id) = entry.unwrap();
+        let (file, post_id) = (String::from_utf8_lossy(&file).into_owned(), String::from_utf8_lossy(&post_id).into_owned());
+        let post = match load_post(db, &post_id) {
+            Some(post) if post.file.as_deref() == Some(file.as_str()) => post,
+            _ => continue,
+        };
+        let bytes = post
+            .file_size
+            .or_else(|| std::fs::metadata(config.upload_path(&file)).ok().map(|meta| meta.len()))
+            .unwrap_or(0);
+        total += bytes;
+        if post.parent_id.is_none() && stickies.contains(&post.id) {
+            continue;
+        }
+        let at = match post.parent_id {
+            Some(_) => post.timestamp,
+            None => indexes::created_at(db, &post),
+        };
+        found.push(Candidate { post_id, file, at, bytes });
+    }
+    found.sort_by_key(|candidate| candidate.at);
+    (found, total)
+}
+
+// Writes a thumbnail next to `file`, as a JPEG for JPEGs and a PNG for
+// the rest. Images already that small are kept at their size, as scaling
+// them up would take more room than the file did. Returns its path under
+// the upload directory.
+fn make_thumbnail(config: &Config, file: &str) -> Result<String, String> {
+    let image = image::open(config.upload_path(file)).map_err(|e| e.to_string())?;
+    let (stem, extension) = file.rsplit_once('.').unwrap_or((file, ""));
+    let extension = match extension.to_ascii_lowercase().as_str() {
+        "jpg" | "jpeg" => "jpg",
+        _ => "png",
+    };
+    let thumbnail = format!("{}-thumb.{}", stem, extension);
+    let image = if image.width() > THUMBNAIL_SIZE || image.height() > THUMBNAIL_SIZE {
+        image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+    } else {
+        image
+    };
+    image.save(config.upload_path(&thumbnail)).map_err(|e| e.to_string())?;
+    Ok(thumbnail)
+}
+
+// Swaps one post's file for a thumbnail and deletes the file. Returns the
+// post as it was, or None if it changed since it was picked.
+fn prune(db: &Db, config: &Config, candidate: &Candidate) -> Option<Post> {
+    let post = load_post(db, &candidate.post_id)?;
+    let _lock = storage::lock_thread(post.parent_id.as_deref().unwrap_or(&post.id));
+    let post = load_post(db, &candidate.post_id).filter(|post| post.file.as_deref() == Some(candidate.file.as_str()))?;
+    if upload::owner(db, &candidate.file).map(|owner| owner.id) != Some(post.id.clone()) {
+        return None;
+    }
+    let thumbnail = if post.is_image() {
+        make_thumbnail(config, &candidate.file)
+            .map_err(|e| eprintln!("no thumbnail for {}, pruning it anyway: {}", candidate.file, e))
+            .ok()
+    } else {
+        None
+    };
+    let updated = db
+        .fetch_and_update(&post.id, |old| {
+            let old = old?;
+            let changed = schema::merge_fields(old, |fields| {
+                fields.insert("file".to_string(), Value::Null);
+                fields.insert("thumbnail".to_string(), thumbnail.clone().into());
+                fields.insert("media_pruned".to_string(), true.into());
+            });
+            Some(changed.unwrap_or_else(|_| old.to_vec()))
+        })
+        .unwrap()
+        .is_some();
+    if !updated {
+        if let Some(thumbnail) = &thumbnail {
+            let _ = std::fs::remove_file(config.upload_path(thumbnail));
+        }
+        return None;
+    }
+    upload::forget(db, &post);
+    let _ = std::fs::remove_file(config.upload_path(&candidate.file));
+    Some(post)
+}
+
+// Prunes what's due and reports it, see the top of this file. Returns how
+// many files went, none on a dry run.
+pub fn run(db: &Db, config: &Config, events: &EventBus, now: u64) -> usize {
+    if config.media_prune_after.is_none() && config.media_budget.is_none() {
+        return 0;
+    }
+    let (candidates, total) = candidates(db, config);
+    let ages: Vec<(u64, u64)> = candidates.iter().map(|candidate| (candidate.at, candidate.bytes)).collect();
+    let chosen = &candidates[..select(&ages, total, now, config.media_prune_after, config.media_budget)];
+    if chosen.is_empty() {
+        return 0;
+    }
+
+    let report = |pruned: usize, freed: u64| {
+        let mut report = format!("{} files, {}; {} left", pruned, config.human_size(freed), config.human_size(total - freed));
+        if let Some(budget) = config.media_budget {
+            report.push_str(&format!(" of a {} budget", config.human_size(budget)));
+        }
+        report
+    };
+    if config.media_prune_dry_run {
+        let freed = chosen.iter().map(|candidate| candidate.bytes).sum();
+        eprintln!("media prune, dry run: would prune {}", report(chosen.len(), freed));
+        return 0;
+    }
+
+    let mut pruned = 0;
+    let mut freed = 0;
+    for candidate in chosen {
+        if let Some(post) = prune(db, config, candidate) {
+            events.publish(BoardEvent::Edited { post_id: post.id });
+            pruned += 1;
+            freed += candidate.bytes;
+        }
+    }
+    if pruned > 0 {
+        let report = report(pruned, freed);
+        eprintln!("media prune: pruned {}", report);
+        audit::record(db, "maintenance", "media_prune", &report);
+    }
+    pruned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::select;
+
+    // Four 100 byte files, a day apart and the newest a day old
+    const DAY: u64 = 24 * 60 * 60;
+    const NOW: u64 = 10 * DAY;
+    const FILES: [(u64, u64); 4] = [(NOW - 4 * DAY, 100), (NOW - 3 * DAY, 100), (NOW - 2 * DAY, 100), (NOW - DAY, 100)];
+
+    #[test]
+    fn the_budget_is_met_oldest_first() {
+        assert_eq!(select(&FILES, 400, NOW, None, Some(400)), 0);
+        assert_eq!(select(&FILES, 400, NOW, None, Some(399)), 1);
+        assert_eq!(select(&FILES, 400, NOW, None, Some(200)), 2);
+        assert_eq!(select(&FILES, 400, NOW, None, Some(0)), 4);
+        // Files that aren't candidates still count
+        assert_eq!(select(&FILES, 550, NOW, None, Some(300)), 3);
+    }
+
+    #[test]
+    fn the_age_limit_is_inclusive_and_adds_to_the_budget() {
+        assert_eq!(select(&FILES, 400, NOW, Some(3 * DAY), None), 2);
+        assert_eq!(select(&FILES, 400, NOW, Some(3 * DAY + 1), None), 1);
+        assert_eq!(select(&FILES, 400, NOW, Some(10 * DAY), None), 0);
+        assert_eq!(select(&FILES, 400, NOW, Some(3 * DAY), Some(100)), 3);
+        assert_eq!(select(&FILES, 400, NOW, Some(4 * DAY), Some(300)), 1);
+    }
+}
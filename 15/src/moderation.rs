@@ -0,0 +1,203 @@
+// Moderation signals and the trees behind them.
+//
+// Every stored upload is indexed by content hash in `file_hashes`, keyed
+// "{hash}/{post_id}" with the upload time as the value. When the same file
+// turns up on enough other posts within the window, every post in the group
+// is flagged in `flagged` under the same key shape, so a prefix scan over a
+// hash finds the whole group. Hashes an admin has marked harmless go in
+// `allowed_hashes` and are never flagged again. Hashes of files taken down
+// go in `blocked_hashes` and are refused on upload.
+
+use sled::Db;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::time::SystemTime;
+
+use crate::config::Config;
+use crate::events::{BoardEvent, Delivery, EventHandler};
+use crate::{load_post, Post};
+
+fn hash_key(hash: &str, post_id: &str) -> String {
+    format!("{}/{}", hash, post_id)
+}
+
+fn post_id_of(key: &[u8]) -> String {
+    let key = String::from_utf8_lossy(key);
+    key.split_once('/').map(|(_, id)| id.to_string()).unwrap_or_default()
+}
+
+pub fn index_upload(db: &Db, post: &Post) {
+    if let Some(hash) = &post.file_hash {
+        db.open_tree("file_hashes")
+            .unwrap()
+            .insert(hash_key(hash, &post.id), &post.timestamp.to_be_bytes())
+            .unwrap();
+    }
+}
+
+// Drops the post from the hash index and from any flagged group, returning
+// how many entries were removed from each.
+pub fn forget_upload(db: &Db, post: &Post) -> (usize, usize) {
+    match &post.file_hash {
+        Some(hash) => {
+            let key = hash_key(hash, &post.id);
+            let indexed = db.open_tree("file_hashes").unwrap().remove(&key).unwrap();
+            let flagged = db.open_tree("flagged").unwrap().remove(&key).unwrap();
+            (indexed.is_some() as usize, flagged.is_some() as usize)
+        }
+        None => (0, 0),
+    }
+}
+
+pub fn is_allowed_hash(db: &Db, hash: &str) -> bool {
+    db.open_tree("allowed_hashes").unwrap().contains_key(hash).unwrap()
+}
+
+// Marks the hash harmless and clears its flags.
+pub fn allow_hash(db: &Db, hash: &str) {
+    db.open_tree("allowed_hashes").unwrap().insert(hash, &[]).unwrap();
+    let flagged = db.open_tree("flagged").unwrap();
+    for key in flagged.scan_prefix(format!("{}/", hash)).keys() {
+        flagged.remove(key.unwrap()).unwrap();
+    }
+}
+
+pub fn is_blocked_hash(db: &Db, hash: &str) -> bool {
+    db.open_tree("blocked_hashes").unwrap().contains_key(hash).unwrap()
+}
+
+pub fn block_hash(db: &Db, hash: &str) {
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    db.open_tree("blocked_hashes").unwrap().insert(hash, &timestamp.to_be_bytes()).unwrap();
+}
+
+// Every stored post whose attachment has this hash
+pub fn posts_with_hash(db: &Db, hash: &str) -> Vec<Post> {
+    db.open_tree("file_hashes")
+        .unwrap()
+        .scan_prefix(format!("{}/", hash))
+        .keys()
+        .filter_map(|key| load_post(db, &post_id_of(&key.unwrap())))
+        .collect()
+}
+
+fn timestamp_in(db: &Db, tree: &str, key: &str) -> Option<u64> {
+    let value = db.open_tree(tree).unwrap().get(key).unwrap()?;
+    value.as_ref().try_into().map(u64::from_be_bytes).ok()
+}
+
+// When the post's attachment was flagged as a duplicate, if it is
+pub fn flagged_at(db: &Db, post: &Post) -> Option<u64> {
+    timestamp_in(db, "flagged", &hash_key(post.file_hash.as_deref()?, &post.id))
+}
+
+pub fn blocked_at(db: &Db, hash: &str) -> Option<u64> {
+    timestamp_in(db, "blocked_hashes", hash)
+}
+
+pub fn banned_at(db: &Db, ip_hash: &str) -> Option<u64> {
+    timestamp_in(db, "bans", ip_hash)
+}
+
+pub fn is_banned(db: &Db, ip_hash: &str) -> bool {
+    db.open_tree("bans").unwrap().contains_key(ip_hash).unwrap()
+}
+
+pub fn ban(db: &Db, ip_hash: &str) {
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    db.open_tree("bans").unwrap().insert(ip_hash, &timestamp.to_be_bytes()).unwrap();
+}
+
+// Runs check_duplicate on the event thread for new posts with files, from
+// posters the spam checks apply to
+pub struct DuplicateCheck {
+    db: Db,
+    config: Config,
+}
+
+impl DuplicateCheck {
+    pub fn new(db: &Db, config: &Config) -> DuplicateCheck {
+        DuplicateCheck {
+            db: db.clone(),
+            config: config.clone(),
+        }
+    }
+}
+
+impl EventHandler for DuplicateCheck {
+    fn name(&self) -> &'static str {
+        "duplicate_images"
+    }
+
+    fn delivery(&self) -> Delivery {
+        Delivery::Queued
+    }
+
+    fn handle(&self, event: &BoardEvent) -> Result<(), String> {
+        if let BoardEvent::Created { post, spam_checks: true } = event {
+            check_duplicate(&self.db, &self.config, post);
+        }
+        Ok(())
+    }
+}
+
+// Runs after the post is stored, off the request path. Flags the new post
+// and the earlier ones when the same file is on at least
+// `duplicate_image_threshold` other posts from the last window.
+pub fn check_duplicate(db: &Db, config: &Config, post: &Post) {
+    let hash = match &post.file_hash {
+        Some(hash) => hash,
+        None => return,
+    };
+    if is_allowed_hash(db, hash) {
+        return;
+    }
+
+    let since = post.timestamp.saturating_sub(config.duplicate_image_window_secs);
+    let mut group = vec![post.id.clone()];
+    for item in db.open_tree("file_hashes").unwrap().scan_prefix(format!("{}/", hash)) {
+        let (key, value) = item.unwrap();
+        let uploaded = value.as_ref().try_into().map(u64::from_be_bytes).unwrap_or(0);
+        let post_id = post_id_of(&key);
+        if post_id != post.id && uploaded >= since {
+            group.push(post_id);
+        }
+    }
+    if group.len() <= config.duplicate_image_threshold {
+        return;
+    }
+
+    let flagged = db.open_tree("flagged").unwrap();
+    for post_id in group {
+        flagged.insert(hash_key(hash, &post_id), &post.timestamp.to_be_bytes()).unwrap();
+    }
+    flagged.flush().unwrap();
+}
+
+// Flagged posts grouped by hash. Posts deleted since they were flagged are
+// left out.
+pub fn flagged_groups(db: &Db) -> BTreeMap<String, Vec<Post>> {
+    let mut groups: BTreeMap<String, Vec<Post>> = BTreeMap::new();
+    for key in db.open_tree("flagged").unwrap().iter().keys() {
+        let key = String::from_utf8_lossy(&key.unwrap()).into_owned();
+        if let Some((hash, post_id)) = key.split_once('/') {
+            if let Some(post) = load_post(db, post_id) {
+                groups.entry(hash.to_string()).or_default().push(post);
+            }
+        }
+    }
+    for posts in groups.values_mut() {
+        posts.sort_by_key(|post| post.timestamp);
+    }
+    groups.retain(|_, posts| !posts.is_empty());
+    groups
+}
+
+pub fn flagged_group(db: &Db, hash: &str) -> Vec<Post> {
+    db.open_tree("flagged")
+        .unwrap()
+        .scan_prefix(format!("{}/", hash))
+        .keys()
+        .filter_map(|key| load_post(db, &post_id_of(&key.unwrap())))
+        .collect()
+}
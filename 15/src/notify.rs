@@ -0,0 +1,291 @@
+// Reply notification emails for thread creators. Whoever starts a thread
+// can leave an email address, which is never shown anywhere, and gets a
+// message when the thread receives its first reply of the day (UTC).
+//
+// Addresses are kept in `notify_emails` keyed by thread id, encrypted with
+// ChaCha20-Poly1305 under a key derived from NOTIFY_KEY; the value is the
+// time it was given (8 bytes big-endian), the nonce and the ciphertext.
+// A reply only marks its thread in `notify_due`; a background thread sends
+// the due emails in batches every BATCH_INTERVAL and records the day each
+// thread was last mailed in `notify_sent`, so there's at most one email per
+// thread per day however many replies come in. Every email links to
+// /unsubscribe/{token}, the token being the thread id signed with
+// NOTIFY_KEY. Addresses go when the thread is deleted, and once they're
+// older than the watches retention (see retention.rs).
+//
+// Nothing here runs unless SMTP_HOST, SMTP_FROM and NOTIFY_KEY are set.
+
+use actix_web::{web, HttpResponse};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use sha2::{Digest, Sha256};
+use sled::Db;
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{Config, SmtpTls};
+use crate::events::{BoardEvent, EventHandler};
+use crate::{load_post, render, storage, upload, NoticeTemplate};
+
+const BATCH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+const DAY: u64 = 24 * 60 * 60;
+const NONCE_LEN: usize = 12;
+
+fn cipher(config: &Config) -> ChaCha20Poly1305 {
+    let secret = config.notify_key.as_deref().unwrap_or_default();
+    let key = Sha256::digest(format!("notify-email:{}", secret).as_bytes());
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+fn encrypt(config: &Config, email: &str, now: u64) -> Vec<u8> {
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let sealed = cipher(config).encrypt(Nonce::from_slice(&nonce), email.as_bytes()).unwrap();
+    [&now.to_be_bytes()[..], &nonce, &sealed].concat()
+}
+
+// The time or day at the start of a value
+fn leading_u64(value: &[u8]) -> u64 {
+    value.get(..8).and_then(|bytes| bytes.try_into().ok()).map(u64::from_be_bytes).unwrap_or(0)
+}
+
+// None if the value is damaged or NOTIFY_KEY has changed since
+fn decrypt(config: &Config, value: &[u8]) -> Option<String> {
+    let nonce = value.get(8..8 + NONCE_LEN)?;
+    let sealed = value.get(8 + NONCE_LEN..)?;
+    let email = cipher(config).decrypt(Nonce::from_slice(nonce), sealed).ok()?;
+    String::from_utf8(email).ok()
+}
+
+fn signature(config: &Config, thread_id: &str) -> String {
+    let secret = config.notify_key.as_deref().unwrap_or_default();
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("unsubscribe:{}", thread_id).as_bytes());
+    upload::hex(&mac.finalize().into_bytes())
+}
+
+pub fn unsubscribe_token(config: &Config, thread_id: &str) -> String {
+    format!("{}.{}", thread_id, signature(config, thread_id))
+}
+
+// The thread id, if the token is one we made
+fn check_token<'a>(config: &Config, token: &'a str) -> Option<&'a str> {
+    let (thread_id, given) = token.split_once('.')?;
+    (given == signature(config, thread_id)).then_some(thread_id)
+}
+
+fn day(timestamp: u64) -> u64 {
+    timestamp / DAY
+}
+
+fn mailed_today(db: &Db, thread_id: &str, now: u64) -> bool {
+    let sent = db.open_tree("notify_sent").unwrap().get(thread_id).unwrap();
+    sent.map(|sent| leading_u64(&sent) == day(now)).unwrap_or(false)
+}
+
+// Called for a new thread whose creator gave an address
+pub fn subscribe(db: &Db, config: &Config, thread_id: &str, email: &str, now: u64) {
+    db.open_tree("notify_emails").unwrap().insert(thread_id, encrypt(config, email, now)).unwrap();
+}
+
+// Called once a reply is visible on the board. Threads already mailed today
+// aren't marked again.
+pub fn reply_added(db: &Db, config: &Config, thread_id: &str, now: u64) {
+    // Deleting the thread forgets its address under the same lock
+    let _lock = storage::lock_thread(thread_id);
+    if !config.notify_enabled() || !db.open_tree("notify_emails").unwrap().contains_key(thread_id).unwrap() {
+        return;
+    }
+    if !mailed_today(db, thread_id, now) {
+        db.open_tree("notify_due").unwrap().insert(thread_id, &[]).unwrap();
+    }
+}
+
+// Calls reply_added for each new reply
+pub struct ReplyNotifier {
+    db: Db,
+    config: Config,
+}
+
+impl ReplyNotifier {
+    pub fn new(db: &Db, config: &Config) -> ReplyNotifier {
+        ReplyNotifier {
+            db: db.clone(),
+            config: config.clone(),
+        }
+    }
+}
+
+impl EventHandler for ReplyNotifier {
+    fn name(&self) -> &'static str {
+        "reply_notifications"
+    }
+
+    fn handle(&self, event: &BoardEvent) -> Result<(), String> {
+        if let BoardEvent::Created { post, .. } = event {
+            if let Some(thread_id) = &post.parent_id {
+                let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+                reply_added(&self.db, &self.config, thread_id, now);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Returns how many entries were removed.
+pub fn forget(db: &Db, thread_id: &str) -> usize {
+    ["notify_emails", "notify_sent", "notify_due"]
+        .iter()
+        .filter(|tree| db.open_tree(tree).unwrap().remove(thread_id).unwrap().is_some())
+        .count()
+}
+
+// Addresses given before `before`. Returns how many were removed.
+pub fn prune(db: &Db, before: u64) -> usize {
+    let expired: Vec<_> = db
+        .open_tree("notify_emails")
+        .unwrap()
+        .iter()
+        .map(|item| item.unwrap())
+        .filter(|(_, value)| leading_u64(value) < before)
+        .map(|(thread_id, _)| String::from_utf8_lossy(&thread_id).into_owned())
+        .collect();
+    for thread_id in &expired {
+        forget(db, thread_id);
+    }
+    expired.len()
+}
+
+fn message(config: &Config, from: &str, to: &str, thread_id: &str, title: &str) -> Result<Message, String> {
+    let thread_url = format!("{}{}", config.public_url, config.post_url(thread_id));
+    let unsubscribe_url = format!(
+        "{}{}",
+        config.public_url,
+        config.url_for(&format!("/unsubscribe/{}", unsubscribe_token(config, thread_id)))
+    );
+    let body = format!(
+        "Your thread \"{}\" has a new reply:\n{}\n\nYou'll get at most one of these a day. To stop them:\n{}\n",
+        title, thread_url, unsubscribe_url
+    );
+    Message::builder()
+        .from(from.parse().map_err(|e| format!("SMTP_FROM: {}", e))?)
+        .to(to.parse().map_err(|e| format!("address: {}", e))?)
+        .subject(format!("New reply in \"{}\"", title))
+        .header(ContentType::TEXT_PLAIN)
+        .body(body)
+        .map_err(|e| e.to_string())
+}
+
+// Sends one email for each thread marked due and not mailed today yet.
+// Returns how many were sent. Any lettre transport works, so a stub can
+// stand in for SMTP.
+pub fn send_due<T: Transport>(db: &Db, config: &Config, transport: &T, now: u64) -> usize
+where
+    T::Error: std::fmt::Display,
+{
+    let from = match &config.smtp_from {
+        Some(from) => from,
+        None => return 0,
+    };
+    let emails = db.open_tree("notify_emails").unwrap();
+    let sent = db.open_tree("notify_sent").unwrap();
+    let due = db.open_tree("notify_due").unwrap();
+    let mut count = 0;
+    for thread_id in due.iter().keys() {
+        let thread_id = thread_id.unwrap();
+        due.remove(&thread_id).unwrap();
+        let thread_id = String::from_utf8_lossy(&thread_id).into_owned();
+        if mailed_today(db, &thread_id, now) {
+            continue;
+        }
+        let (thread, email) = match (load_post(db, &thread_id), emails.get(&thread_id).unwrap()) {
+            (Some(thread), Some(value)) => (thread, decrypt(config, &value)),
+            _ => continue,
+        };
+        let email = match email {
+            Some(email) => email,
+            None => {
+                eprintln!("can't read the notification address for thread {}", thread_id);
+                continue;
+            }
+        };
+        // Marked before sending, so a failing address is tried once a day
+        // rather than every batch
+        sent.insert(&thread_id, &day(now).to_be_bytes()).unwrap();
+        let result = message(config, from, &email, &thread_id, &thread.title)
+            .and_then(|message| transport.send(&message).map_err(|e| e.to_string()));
+        match result {
+            Ok(_) => count += 1,
+            Err(e) => eprintln!("reply notification for thread {} failed: {}", thread_id, e),
+        }
+    }
+    count
+}
+
+fn smtp_transport(config: &Config) -> Result<SmtpTransport, String> {
+    let host = config.smtp_host.as_deref().unwrap_or_default();
+    let mut builder = match config.smtp_tls {
+        SmtpTls::StartTls => SmtpTransport::starttls_relay(host).map_err(|e| e.to_string())?,
+        SmtpTls::Tls => SmtpTransport::relay(host).map_err(|e| e.to_string())?,
+        SmtpTls::None => SmtpTransport::builder_dangerous(host),
+    };
+    if let Some(port) = config.smtp_port {
+        builder = builder.port(port);
+    }
+    if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    Ok(builder.timeout(Some(SMTP_TIMEOUT)).build())
+}
+
+// Starts the background sender, if notifications are configured
+pub fn start(config: &Config, db: &Db) {
+    if !config.notify_enabled() {
+        return;
+    }
+    let transport = match smtp_transport(config) {
+        Ok(transport) => transport,
+        Err(e) => {
+            eprintln!("reply notifications are off, SMTP settings are unusable: {}", e);
+            return;
+        }
+    };
+    let config = config.clone();
+    let db = db.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(BATCH_INTERVAL);
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        send_due(&db, &config, &transport, now);
+    });
+}
+
+// The link from the emails. Anyone holding it may stop them, which is all
+// it can do.
+pub async fn unsubscribe(db: web::Data<Db>, config: web::Data<Config>, token: web::Path<String>) -> HttpResponse {
+    let (response, heading, message) = match check_token(&config, &token) {
+        Some(thread_id) => {
+            forget(&db, thread_id);
+            (
+                HttpResponse::Ok(),
+                "Unsubscribed",
+                "You won't get any more emails about replies to this thread.",
+            )
+        }
+        None => (
+            HttpResponse::NotFound(),
+            "Link not recognised",
+            "This unsubscribe link isn't valid. It may have been cut short by your email program.",
+        ),
+    };
+    let template = NoticeTemplate {
+        config: &config,
+        heading,
+        message,
+        back_url: config.index_url(),
+    };
+    render::respond(response, &template, "the unsubscribe notice")
+}
@@ -0,0 +1,66 @@
+// Stable reply numbers. Each reply gets the next number in its thread when
+// it goes into the main tree, and keeps it: deleting reply 2 leaves 3 as
+// "Reply 3", so anchors and saved links still point at the same post. The
+// last number handed out per thread is kept in `reply_numbers`.
+
+use sled::transaction::{TransactionalTree, UnabortableTransactionError};
+use sled::{Db, IVec};
+use std::collections::HashMap;
+
+use crate::{counters, schema, Post};
+
+// Inside the transaction that stores the reply, see changes::insert_reply
+pub fn next_in(reply_numbers: &TransactionalTree, thread_id: &str) -> Result<u64, UnabortableTransactionError> {
+    counters::increment_in(reply_numbers, thread_id, 1)
+}
+
+// The highest number handed out so far, 0 if the thread never had replies
+pub fn last(db: &Db, thread_id: &str) -> u64 {
+    counters::get(&db.open_tree("reply_numbers").unwrap(), thread_id)
+}
+
+pub fn forget_thread(db: &Db, thread_id: &str) {
+    db.open_tree("reply_numbers").unwrap().remove(thread_id).unwrap();
+}
+
+// Replies from before numbers were stored are numbered once, on first
+// start, in the order they were posted.
+pub fn assign_if_missing(db: &Db) {
+    let meta = db.open_tree("meta").unwrap();
+    if meta.contains_key("reply_numbers_assigned").unwrap() {
+        return;
+    }
+    let mut replies: Vec<(Post, IVec)> = db
+        .iter()
+        .values()
+        .filter_map(|raw| {
+            let raw = raw.unwrap();
+            let post = Post::upgrade(&raw).ok()?;
+            post.parent_id.as_ref()?;
+            Some((post, raw))
+        })
+        .collect();
+    replies.sort_by_key(|(post, _)| post.timestamp);
+
+    let mut assigned: HashMap<String, u64> = HashMap::new();
+    for (post, raw) in replies {
+        let thread_id = post.parent_id.clone().unwrap();
+        let number = assigned.entry(thread_id).or_insert(0);
+        *number += 1;
+        if post.reply_number.is_some() {
+            continue;
+        }
+        let number = *number;
+        if let Ok(updated) = schema::merge_fields(&raw, |fields| {
+            fields.insert("reply_number".to_string(), number.into());
+        }) {
+            db.insert(&post.id, updated).unwrap();
+        }
+    }
+    let numbers = db.open_tree("reply_numbers").unwrap();
+    for (thread_id, number) in assigned {
+        counters::increment(&numbers, thread_id, number as i64);
+    }
+    meta.insert("reply_numbers_assigned", &[]).unwrap();
+    db.flush().unwrap();
+}
@@ -0,0 +1,70 @@
+// Approval queue for first-time posters. Held posts live in the `pending`
+// tree, which no public handler reads, until an admin approves them.
+
+use sled::Db;
+
+use crate::changes::ReplyRefused;
+use crate::config::Config;
+use crate::settings::BoardSettings;
+use crate::Post;
+
+pub fn is_approved_poster(db: &Db, ip_hash: &str) -> bool {
+    db.open_tree("approved_posters").unwrap().contains_key(ip_hash).unwrap()
+}
+
+pub fn hold(db: &Db, post: &Post) {
+    let tree = db.open_tree("pending").unwrap();
+    tree.insert(&post.id, serde_json::to_vec(post).unwrap()).unwrap();
+    tree.flush().unwrap();
+}
+
+pub fn list(db: &Db) -> Vec<Post> {
+    let mut posts: Vec<Post> = db
+        .open_tree("pending")
+        .unwrap()
+        .iter()
+        .values()
+        .filter_map(|bytes| Post::upgrade(&bytes.unwrap()).ok())
+        .collect();
+    posts.sort_by_key(|post| post.timestamp);
+    posts
+}
+
+pub fn take(db: &Db, id: &str) -> Option<Post> {
+    take_raw(db, id).map(|(post, _)| post)
+}
+
+fn take_raw(db: &Db, id: &str) -> Option<(Post, Vec<u8>)> {
+    let raw = db.open_tree("pending").unwrap().remove(id).unwrap()?;
+    let post = Post::upgrade(&raw).ok()?;
+    Some((post, raw.to_vec()))
+}
+
+// Moves the held record into the main tree unchanged, see schema.rs.
+// Ok(None) if it's no longer held. A reply its thread refuses stays held
+// for a moderator to reject, unless the thread is gone, when it's dropped
+// with its file.
+pub fn approve(db: &Db, config: &Config, settings: &BoardSettings, id: &str) -> Result<Option<Post>, ReplyRefused> {
+    let (post, raw) = match take_raw(db, id) {
+        Some(held) => held,
+        None => return Ok(None),
+    };
+    match crate::store_record(db, settings, &post, &raw, false) {
+        Ok(stored) => {
+            if let Some(ip_hash) = &post.ip_hash {
+                db.open_tree("approved_posters").unwrap().insert(ip_hash, &[]).unwrap();
+            }
+            Ok(Some(stored))
+        }
+        Err(ReplyRefused::ThreadGone) => {
+            if let Some(file) = &post.file {
+                let _ = std::fs::remove_file(config.upload_path(file));
+            }
+            Err(ReplyRefused::ThreadGone)
+        }
+        Err(refused) => {
+            db.open_tree("pending").unwrap().insert(id, raw).unwrap();
+            Err(refused)
+        }
+    }
+}
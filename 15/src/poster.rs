@@ -0,0 +1,76 @@
+// Poster identity without storing addresses: a salted hash of the client IP.
+
+use actix_web::HttpRequest;
+use sha2::{Digest, Sha256};
+use sled::Db;
+use uuid::Uuid;
+
+use crate::admin::Admin;
+use crate::config::Config;
+
+// The salt is generated once per database so hashes stay stable across
+// restarts but can't be reversed with a table of all IPv4 addresses.
+fn salt(db: &Db) -> String {
+    let meta = db.open_tree("meta").unwrap();
+    let fresh = Uuid::new_v4().to_string();
+    let _ = meta.compare_and_swap("ip_salt", None as Option<&[u8]>, Some(fresh.as_bytes())).unwrap();
+    String::from_utf8(meta.get("ip_salt").unwrap().unwrap().to_vec()).unwrap()
+}
+
+pub fn client_ip(config: &Config, req: &HttpRequest) -> Option<String> {
+    if config.trust_proxy_headers {
+        req.connection_info().realip_remote_addr().map(|addr| strip_port(addr).to_string())
+    } else {
+        req.peer_addr().map(|addr| addr.ip().to_string())
+    }
+}
+
+fn strip_port(addr: &str) -> &str {
+    // "[::1]:1234" and "1.2.3.4:1234"; bare IPv6 addresses have several colons
+    if let Some(rest) = addr.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match addr.rsplit_once(':') {
+        Some((ip, _)) if !ip.contains(':') => ip,
+        _ => addr,
+    }
+}
+
+// Splits a submitted name into the name to store and, for "name#secret"
+// when tripcodes are on, a tripcode derived from the secret. With names
+// disabled everything submitted is dropped.
+pub fn parse_name(db: &Db, config: &Config, submitted: &str) -> (Option<String>, Option<String>) {
+    if !config.names_enabled() {
+        return (None, None);
+    }
+    let (name, tripcode) = match submitted.split_once('#') {
+        Some((name, secret)) if config.tripcodes && !secret.is_empty() => (name, Some(tripcode(db, secret))),
+        _ => (submitted, None),
+    };
+    let name = name.trim();
+    (Some(name.to_string()).filter(|n| !n.is_empty()), tripcode)
+}
+
+// Salted with the board's salt so tripcodes can't be matched against
+// tables from other sites.
+fn tripcode(db: &Db, secret: &str) -> String {
+    let digest = Sha256::digest(format!("{}#{}", salt(db), secret).as_bytes());
+    digest.iter().take(5).map(|b| format!("{:02x}", b)).collect()
+}
+
+// Options with a meaning, matched without regard to case, see
+// validation::parse_options
+pub const OPTION_FLAGS: [&str; 1] = ["#admin"];
+
+// "#admin" in the options field asks for the staff capcode; it's only
+// granted with an admin session, never from the options alone.
+pub fn capcode(config: &Config, admin: Option<&Admin>, options: &str) -> Option<String> {
+    let requested = options.split_whitespace().any(|option| option == "#admin");
+    admin.filter(|_| requested).map(|_| config.capcode_name.clone())
+}
+
+pub fn ip_hash(db: &Db, config: &Config, req: &HttpRequest) -> Option<String> {
+    let ip = client_ip(config, req)?;
+    let digest = Sha256::digest(format!("{}{}", salt(db), ip).as_bytes());
+    Some(digest.iter().take(8).map(|b| format!("{:02x}", b)).collect())
+}
@@ -0,0 +1,87 @@
+// How many different people posted in each thread, counted by ip hash.
+// The set of hashes seen in a thread is kept in `thread_posters` as
+// "{thread id}/{ip hash}", and its size in the `poster_counts` counters.
+// Sets stop growing at CAP, so a busy thread never holds more than CAP
+// hashes and shows "100+" from then on. Only the count ever leaves this
+// module; the hashes are never shown or returned by the API.
+//
+// Posts without an ip hash (no client address was known) aren't counted.
+// Deleting a post doesn't lower the count: its author still took part.
+
+use sled::Db;
+
+use crate::{counters, Post};
+
+pub const CAP: u64 = 100;
+
+fn poster_key(thread_id: &str, ip_hash: &str) -> String {
+    format!("{}/{}", thread_id, ip_hash)
+}
+
+// Adds the author of a thread or reply to its thread's set
+pub fn record(db: &Db, post: &Post) {
+    let ip_hash = match &post.ip_hash {
+        Some(ip_hash) => ip_hash,
+        None => return,
+    };
+    let thread_id = post.parent_id.as_deref().unwrap_or(&post.id);
+    let counts = db.open_tree("poster_counts").unwrap();
+    if counters::get(&counts, thread_id) >= CAP {
+        return;
+    }
+    let posters = db.open_tree("thread_posters").unwrap();
+    let seen = posters.insert(poster_key(thread_id, ip_hash), &[]).unwrap();
+    if seen.is_none() {
+        let _ = counters::increment_capped(&counts, thread_id, 1, CAP);
+    }
+}
+
+pub fn count(db: &Db, thread_id: &str) -> u64 {
+    counters::get(&db.open_tree("poster_counts").unwrap(), thread_id)
+}
+
+// "12", or "100+" once the cap is reached
+pub fn label(count: u64) -> String {
+    if count >= CAP {
+        format!("{}+", CAP)
+    } else {
+        count.to_string()
+    }
+}
+
+// "37 replies, 12 posters"
+pub fn summary(replies: u64, posters: u64) -> String {
+    format!(
+        "{} {}, {} {}",
+        replies,
+        if replies == 1 { "reply" } else { "replies" },
+        label(posters),
+        if posters == 1 { "poster" } else { "posters" },
+    )
+}
+
+// Returns how many entries were removed.
+pub fn forget_thread(db: &Db, thread_id: &str) -> usize {
+    let posters = db.open_tree("thread_posters").unwrap();
+    let keys: Vec<_> = posters.scan_prefix(format!("{}/", thread_id)).keys().map(|key| key.unwrap()).collect();
+    for key in &keys {
+        posters.remove(key).unwrap();
+    }
+    db.open_tree("poster_counts").unwrap().remove(thread_id).unwrap();
+    keys.len()
+}
+
+// Threads from before posters were counted, once, on first start.
+pub fn build_if_missing(db: &Db) {
+    let meta = db.open_tree("meta").unwrap();
+    if meta.contains_key("thread_posters_built").unwrap() {
+        return;
+    }
+    for bytes in db.iter().values() {
+        if let Ok(post) = Post::upgrade(&bytes.unwrap()) {
+            record(db, &post);
+        }
+    }
+    meta.insert("thread_posters_built", &[]).unwrap();
+    db.flush().unwrap();
+}
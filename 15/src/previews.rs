@@ -0,0 +1,197 @@
+// The last few replies shown under each thread on the index. They're kept
+// rendered in `previews`, so the index reads one record per thread and no
+// replies:
+//
+//   previews      "{thread id}" -> {"generation", "omitted", "html"}
+//   preview_gens  "{thread id}" -> counter
+//
+// Every new or deleted reply bumps its thread's generation and renders the
+// preview again, and so does an edit to a reply the preview shows, see the
+// EventHandler impl. An entry only counts while its generation is
+// current, so one built from replies read before a change is rebuilt
+// rather than shown. The HTML comes from the
+// same templates as the thread page. Entries are dropped at startup, so a
+// new build or config never serves markup from the old one.
+// PREVIEW_CACHE=false renders them on every request instead.
+
+use askama::Template;
+use serde::{Deserialize, Serialize};
+use sled::Db;
+
+use crate::config::Config;
+use crate::events::{BoardEvent, EventHandler};
+use crate::{counters, load_post, render, storage, Post};
+
+const PREVIEW_REPLIES: usize = 3;
+
+#[derive(Serialize, Deserialize)]
+pub struct Preview {
+    generation: u64,
+    // Replies not shown
+    pub omitted: u64,
+    pub html: String,
+}
+
+#[derive(Template)]
+#[template(path = "index_preview.html")]
+struct PreviewTemplate<'a> {
+    config: &'a Config,
+    replies: &'a [Post],
+}
+
+fn generation(db: &Db, thread_id: &str) -> u64 {
+    counters::get(&db.open_tree("preview_gens").unwrap(), thread_id)
+}
+
+// The thread's last PREVIEW_REPLIES replies by number, oldest first.
+// Replies posted within the same second share a timestamp, so the
+// `replies` index can't tell which came last.
+fn last_replies(db: &Db, thread_id: &str) -> Vec<Post> {
+    let range = format!("{}/", thread_id)..format!("{}0", thread_id);
+    let mut replies: Vec<Post> = db
+        .open_tree("numbers")
+        .unwrap()
+        .range(range)
+        .values()
+        .rev()
+        .filter_map(|id| load_post(db, std::str::from_utf8(&id.unwrap()).ok()?))
+        .take(PREVIEW_REPLIES)
+        .collect();
+    replies.reverse();
+    replies
+}
+
+fn build(db: &Db, config: &Config, thread_id: &str, generation: u64) -> Option<Preview> {
+    let replies = last_replies(db, thread_id);
+    let html = render::to_string(
+        &PreviewTemplate {
+            config,
+            replies: &replies,
+        },
+        &format!("the preview of thread {}", thread_id),
+    )?;
+    Some(Preview {
+        generation,
+        omitted: crate::reply_count(db, thread_id).saturating_sub(replies.len() as u64),
+        html,
+    })
+}
+
+fn store(db: &Db, thread_id: &str, preview: &Preview) {
+    db.open_tree("previews").unwrap().insert(thread_id, serde_json::to_vec(preview).unwrap()).unwrap();
+}
+
+// For the index. Renders and stores the preview when there's no current
+// one; None if it fails to render.
+pub fn get(db: &Db, config: &Config, thread_id: &str) -> Option<Preview> {
+    let current = generation(db, thread_id);
+    if !config.preview_cache {
+        return build(db, config, thread_id, current);
+    }
+    let cached = db.open_tree("previews").unwrap().get(thread_id).unwrap();
+    if let Some(preview) = cached.and_then(|bytes| serde_json::from_slice::<Preview>(&bytes).ok()) {
+        if preview.generation == current {
+            return Some(preview);
+        }
+    }
+    let preview = build(db, config, thread_id, current)?;
+    store(db, thread_id, &preview);
+    Some(preview)
+}
+
+// Under the thread's lock, so a reply's refresh can't land after the
+// thread's deletion dropped its entries
+fn refresh(db: &Db, config: &Config, thread_id: &str) {
+    let _lock = storage::lock_thread(thread_id);
+    if load_post(db, thread_id).is_none() {
+        return;
+    }
+    let generation = counters::increment(&db.open_tree("preview_gens").unwrap(), thread_id, 1);
+    if let Some(preview) = build(db, config, thread_id, generation) {
+        store(db, thread_id, &preview);
+    }
+}
+
+// For a thread being deleted
+fn forget(db: &Db, thread_id: &str) {
+    for tree in ["previews", "preview_gens"] {
+        db.open_tree(tree).unwrap().remove(thread_id).unwrap();
+    }
+}
+
+// At startup, see the top of this file
+pub fn clear(db: &Db) {
+    db.open_tree("previews").unwrap().clear().unwrap();
+}
+
+pub struct PreviewCache {
+    db: Db,
+    config: Config,
+}
+
+impl PreviewCache {
+    pub fn new(db: &Db, config: &Config) -> PreviewCache {
+        PreviewCache {
+            db: db.clone(),
+            config: config.clone(),
+        }
+    }
+}
+
+impl EventHandler for PreviewCache {
+    fn name(&self) -> &'static str {
+        "index_previews"
+    }
+
+    fn handle(&self, event: &BoardEvent) -> Result<(), String> {
+        match event {
+            BoardEvent::Created { post, .. } => {
+                if let Some(thread_id) = &post.parent_id {
+                    refresh(&self.db, &self.config, thread_id);
+                }
+            }
+            BoardEvent::Edited { post_id } => {
+                let thread_id = match load_post(&self.db, post_id).and_then(|post| post.parent_id) {
+                    Some(thread_id) => thread_id,
+                    None => return Ok(()),
+                };
+                if last_replies(&self.db, &thread_id).iter().any(|reply| reply.id == *post_id) {
+                    refresh(&self.db, &self.config, &thread_id);
+                }
+            }
+            BoardEvent::Deleted { posts } => {
+                let gone: Vec<&str> = posts.iter().filter(|post| post.parent_id.is_none()).map(|post| post.id.as_str()).collect();
+                for thread_id in &gone {
+                    forget(&self.db, thread_id);
+                }
+                let mut touched: Vec<&str> = posts
+                    .iter()
+                    .filter_map(|post| post.parent_id.as_deref())
+                    .filter(|thread_id| !gone.contains(thread_id))
+                    .collect();
+                touched.sort_unstable();
+                touched.dedup();
+                for thread_id in touched {
+                    refresh(&self.db, &self.config, thread_id);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// For checking the index templates
+pub fn sample() -> Preview {
+    Preview {
+        generation: 0,
+        omitted: 2,
+        html: String::new(),
+    }
+}
+
+pub fn check_templates(config: &Config, reply: &Post) -> Result<(), String> {
+    render::check(&PreviewTemplate {
+        config,
+        replies: std::slice::from_ref(reply),
+    })
+}
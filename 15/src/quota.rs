@@ -0,0 +1,41 @@
+// Per-poster posting quota: at most POSTS_PER_HOUR posts from one ip hash
+// in each clock hour, counted in the `post_quota` tree as windowed
+// counters. Off when POSTS_PER_HOUR is 0. It can change without a restart,
+// see runtime.rs.
+
+use sled::Db;
+
+use crate::activity::HOUR;
+use crate::counters;
+use crate::rejection::{ErrorCode, FieldError};
+use crate::runtime::RuntimeSettings;
+
+// Uses up one post of the hour's quota, or says why it can't.
+pub fn take(db: &Db, runtime: &RuntimeSettings, ip_hash: &str, now: u64) -> Result<(), FieldError> {
+    if runtime.posts_per_hour == 0 {
+        return Ok(());
+    }
+    let key = counters::bucket_key(now / HOUR, ip_hash);
+    counters::increment_capped(&db.open_tree("post_quota").unwrap(), &key, 1, runtime.posts_per_hour)
+        .map(|_| ())
+        .map_err(|_| {
+            let message = format!("You can make at most {} posts an hour. Try again later.", runtime.posts_per_hour);
+            FieldError::new("post", ErrorCode::RateLimited, message)
+                .max(runtime.posts_per_hour)
+                .retry_after(HOUR - now % HOUR)
+        })
+}
+
+// How much of the hour's quota `ip_hash` has used, 0 when there's no quota
+pub fn used(db: &Db, runtime: &RuntimeSettings, ip_hash: &str, now: u64) -> u64 {
+    if runtime.posts_per_hour == 0 {
+        return 0;
+    }
+    counters::get(&db.open_tree("post_quota").unwrap(), counters::bucket_key(now / HOUR, ip_hash))
+}
+
+// Drops the buckets of hours that ended before `before`; only the current
+// one is read. Returns how many entries were removed.
+pub fn prune(db: &Db, before: u64) -> usize {
+    counters::expire_buckets(&db.open_tree("post_quota").unwrap(), before / HOUR)
+}
@@ -0,0 +1,85 @@
+// Replies quoting a reply number that isn't in the thread, usually a typo
+// in ">>123". Before such a reply is stored the form comes back with the
+// message as typed and a warning listing the numbers, and goes through
+// once "Post anyway" is ticked. Numbers are per thread, so new threads
+// aren't checked, and neither are API clients, which get no form.
+
+use actix_web::HttpResponse;
+use askama::Template;
+use sled::Db;
+
+use crate::config::Config;
+use crate::reply_form::FormState;
+use crate::{format, indexes, render};
+
+// More quotes than this in one message are left unchecked
+const MAX_CHECKED: usize = 50;
+
+// Quoted numbers with no reply behind them, in the order they appear
+pub fn unresolved(db: &Db, thread_id: &str, message: &str) -> Vec<u64> {
+    let mut quoted = format::find_quotes(message);
+    if quoted.is_empty() {
+        return quoted;
+    }
+    quoted.truncate(MAX_CHECKED);
+    let found = indexes::numbered_replies(db, thread_id, &quoted);
+    quoted.into_iter().zip(found).filter(|(_, id)| id.is_none()).map(|(number, _)| number).collect()
+}
+
+#[derive(Template)]
+#[template(path = "confirm_quotes.html")]
+struct ConfirmTemplate<'a> {
+    config: &'a Config,
+    // The template puts a newline straight after <textarea>, which
+    // browsers drop, so a message starting with one comes back unchanged
+    draft: &'a FormState,
+    // ">>12, >>40"
+    numbers: String,
+    several: bool,
+    submit_token: String,
+    // See admin::submit_url
+    submit_url: String,
+}
+
+pub fn confirm_page(
+    config: &Config,
+    draft: &FormState,
+    unresolved: &[u64],
+    submit_token: String,
+    submit_url: String,
+) -> HttpResponse {
+    let template = ConfirmTemplate {
+        config,
+        draft,
+        numbers: unresolved.iter().map(|number| format!(">>{}", number)).collect::<Vec<_>>().join(", "),
+        several: unresolved.len() > 1,
+        submit_token,
+        submit_url,
+    };
+    render::respond(HttpResponse::Ok(), &template, "the quote warning")
+}
+
+pub fn check_templates(config: &Config) -> Result<(), String> {
+    for had_file in [true, false] {
+        let draft = FormState {
+            parent_id: "thread".to_string(),
+            return_to: Some(config.index_url()),
+            title: "Title".to_string(),
+            name: String::new(),
+            options: String::new(),
+            remember_options: true,
+            message: "\n>>3 see above".to_string(),
+            had_file,
+            rendered_at: Some(1),
+        };
+        render::check(&ConfirmTemplate {
+            config,
+            draft: &draft,
+            numbers: ">>3".to_string(),
+            several: had_file,
+            submit_token: String::new(),
+            submit_url: config.url_for("/submit"),
+        })?;
+    }
+    Ok(())
+}
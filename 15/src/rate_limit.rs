@@ -0,0 +1,230 @@
+// Per-client token buckets, applied as middleware on each route.
+//
+// Routes are grouped into classes with their own bucket parameters, so
+// hammering the preview endpoint doesn't use up a client's posting budget
+// and vice versa.
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpRequest, HttpResponse, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use sled::Db;
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+
+use crate::activity::HOUR;
+use crate::config::Config;
+use crate::exemptions::ExemptionCache;
+use crate::{format, poster, quota};
+use crate::rejection::{self, ErrorCode, FieldError, Rejection};
+use crate::runtime::RuntimeCache;
+
+// Past this many tracked clients, buckets that have refilled completely
+// are dropped since they're indistinguishable from new ones.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RouteClass {
+    // Anything that stores data
+    Write,
+    // Full page renders and endpoints that run the formatter
+    Render,
+    // Small lookups
+    Cheap,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BucketPolicy {
+    pub burst: f64,
+    pub per_second: f64,
+}
+
+impl BucketPolicy {
+    // "burst:per_second", e.g. "5:0.1" allows 5 at once then one every 10 s
+    pub fn parse(value: &str) -> Option<BucketPolicy> {
+        let (burst, per_second) = value.split_once(':')?;
+        let policy = BucketPolicy {
+            burst: burst.trim().parse().ok()?,
+            per_second: per_second.trim().parse().ok()?,
+        };
+        if policy.burst >= 1.0 && policy.per_second > 0.0 {
+            Some(policy)
+        } else {
+            None
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    policy: BucketPolicy,
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<(RouteClass, String), Bucket>>,
+}
+
+impl RateLimiter {
+    // Takes a token, or returns how many seconds until one is available.
+    pub fn check(&self, class: RouteClass, policy: BucketPolicy, client: &str) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| {
+                let refilled = now.duration_since(bucket.updated).as_secs_f64() * bucket.policy.per_second;
+                bucket.tokens + refilled < bucket.policy.burst
+            });
+        }
+
+        let bucket = buckets.entry((class, client.to_string())).or_insert(Bucket {
+            tokens: policy.burst,
+            updated: now,
+            policy,
+        });
+        bucket.policy = policy;
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * policy.per_second).min(policy.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / policy.per_second).ceil() as u64)
+        }
+    }
+
+    // What `check` would say, without taking a token
+    pub fn peek(&self, class: RouteClass, policy: BucketPolicy, client: &str) -> Result<(), u64> {
+        let buckets = self.buckets.lock().unwrap();
+        let bucket = match buckets.get(&(class, client.to_string())) {
+            Some(bucket) => bucket,
+            None => return Ok(()),
+        };
+        let elapsed = Instant::now().duration_since(bucket.updated).as_secs_f64();
+        let tokens = (bucket.tokens + elapsed * policy.per_second).min(policy.burst);
+        if tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(((1.0 - tokens) / policy.per_second).ceil() as u64)
+        }
+    }
+}
+
+// A line for the post forms on where the requesting poster stands: how long
+// until the posting limit lets them through, or how much of the hourly
+// quota they've used. None when there's nothing to tell, which includes
+// exempt posters. Pages showing a line mustn't be cached for anyone else.
+pub fn posting_status(req: &HttpRequest) -> Option<String> {
+    let config = req.app_data::<web::Data<Config>>().unwrap();
+    if is_exempt(config, req) {
+        return None;
+    }
+    let limiter = req.app_data::<web::Data<RateLimiter>>().unwrap();
+    let runtime = req.app_data::<web::Data<RuntimeCache>>().unwrap().get();
+    let client = poster::client_ip(config, req).unwrap_or_default();
+    if let Err(wait) = limiter.peek(RouteClass::Write, runtime.rate_limit(RouteClass::Write), &client) {
+        return Some(format!("You can post again in {} s.", wait));
+    }
+    let db = req.app_data::<web::Data<Db>>().unwrap();
+    let ip_hash = poster::ip_hash(db, config, req)?;
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    match quota::used(db, &runtime, &ip_hash, now) {
+        0 => None,
+        used if used >= runtime.posts_per_hour => Some(format!(
+            "All {} hourly posts used. You can post again in {}.",
+            runtime.posts_per_hour,
+            format::human_duration(HOUR - now % HOUR)
+        )),
+        used => Some(format!("{} of {} hourly posts used.", used, runtime.posts_per_hour)),
+    }
+}
+
+pub struct RateLimit {
+    class: RouteClass,
+}
+
+impl RateLimit {
+    pub fn new(class: RouteClass) -> RateLimit {
+        RateLimit { class }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            class: self.class,
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    class: RouteClass,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let config = req.app_data::<web::Data<Config>>().unwrap().clone();
+        let limiter = req.app_data::<web::Data<RateLimiter>>().unwrap().clone();
+        let policy = req.app_data::<web::Data<RuntimeCache>>().unwrap().get().rate_limit(self.class);
+        let client = poster::client_ip(&config, req.request()).unwrap_or_default();
+
+        // Exempt posters skip the posting limit only
+        let checked = if self.class == RouteClass::Write && is_exempt(&config, req.request()) {
+            Ok(())
+        } else {
+            limiter.check(self.class, policy, &client)
+        };
+        if let Err(retry_after) = checked {
+            let response = if rejection::wants_json(req.request()) {
+                let message = format!("Too many requests, try again in {} seconds.", retry_after);
+                let error = FieldError::new("post", ErrorCode::RateLimited, message).retry_after(retry_after);
+                Rejection::new(&config, req.request(), None, vec![error]).error_response()
+            } else {
+                HttpResponse::TooManyRequests()
+                    .append_header(("Retry-After", retry_after.to_string()))
+                    .content_type("text/plain")
+                    .body(format!("Too many requests, try again in {} seconds.", retry_after))
+            };
+            return Box::pin(async move { Ok(req.into_response(response).map_into_right_body()) });
+        }
+
+        let service = self.service.clone();
+        Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) })
+    }
+}
+
+fn is_exempt(config: &Config, req: &HttpRequest) -> bool {
+    let db = req.app_data::<web::Data<Db>>().unwrap();
+    let exemptions = req.app_data::<web::Data<ExemptionCache>>().unwrap();
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    poster::ip_hash(db, config, req).is_some_and(|ip_hash| exemptions.is_exempt(db, &ip_hash, now))
+}
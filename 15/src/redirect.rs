@@ -0,0 +1,157 @@
+// Redirects after form posts. A form can say where to go next with a
+// `return_to` field or query parameter, so that e.g. deleting a thread on
+// page 3 of the admin listing lands back on page 3. It is only followed
+// when it is a path on this board with one of the shapes in RETURN_PATHS
+// (plus an optional plain query string and #anchor); anything else, like
+// "https://evil.example" or "//evil.example", falls back to the action's
+// default target, so the board can't be used as an open redirect.
+
+use actix_web::{HttpResponse, HttpResponseBuilder};
+use serde::Deserialize;
+
+use crate::config::Config;
+
+// Paths relative to the base path; "*" is one id segment
+const RETURN_PATHS: &[&str] = &[
+    "/",
+    "/post/*",
+    "/stats",
+    "/archive",
+    "/archive/*",
+    "/archive/*/*",
+    "/admin/posts",
+    "/admin/pending",
+    "/admin/flagged-images",
+    "/admin/settings",
+    "/admin/takedown",
+    "/admin/exemptions",
+];
+
+#[derive(Deserialize)]
+pub struct ReturnTo {
+    pub return_to: Option<String>,
+}
+
+// Callers add cookies before finishing where they need to
+pub fn see_other(location: &str) -> HttpResponseBuilder {
+    let mut response = HttpResponse::SeeOther();
+    response.append_header(("Location", location));
+    response
+}
+
+// A plain 302, for links that moved rather than form posts
+pub fn found(location: &str) -> HttpResponse {
+    HttpResponse::Found().append_header(("Location", location)).finish()
+}
+
+// To `return_to` if it's allowed, otherwise to `default`
+pub fn back(config: &Config, return_to: Option<&str>, default: String) -> HttpResponse {
+    see_other(&location(config, return_to, default)).finish()
+}
+
+// Where `back` goes, for callers that add to it
+pub fn location(config: &Config, return_to: Option<&str>, default: String) -> String {
+    return_to.and_then(|raw| return_target(config, raw)).unwrap_or(default)
+}
+
+// `raw` if it's a path on this board we're willing to send people to
+pub fn return_target(config: &Config, raw: &str) -> Option<String> {
+    let (rest, anchor) = match raw.split_once('#') {
+        Some((rest, anchor)) => (rest, Some(anchor)),
+        None => (raw, None),
+    };
+    let (path, query) = match rest.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (rest, None),
+    };
+    let path = path.strip_prefix(config.base_path.as_str())?;
+    if !RETURN_PATHS.iter().any(|shape| path_matches(shape, path)) {
+        return None;
+    }
+    let query_ok = query.unwrap_or_default().bytes().all(|b| b.is_ascii_alphanumeric() || b"=&_-.%".contains(&b));
+    let anchor_ok = anchor.unwrap_or_default().bytes().all(|b| b.is_ascii_alphanumeric() || b"_-".contains(&b));
+    if !query_ok || !anchor_ok {
+        return None;
+    }
+    Some(raw.to_string())
+}
+
+// Whether `path` has the shape `shape`, where "*" is one id segment
+pub fn path_matches(shape: &str, path: &str) -> bool {
+    let mut shape_parts = shape.split('/');
+    let mut path_parts = path.split('/');
+    loop {
+        match (shape_parts.next(), path_parts.next()) {
+            (None, None) => return true,
+            (Some("*"), Some(part)) => {
+                if part.is_empty() || !part.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+                    return false;
+                }
+            }
+            (Some(expected), Some(part)) if expected == part => {}
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(base_path: &str) -> Config {
+        let mut config = Config::from_env();
+        config.base_path = base_path.to_string();
+        config
+    }
+
+    #[test]
+    fn paths_on_the_board_are_followed() {
+        let config = config("");
+        for raw in ["/", "/?page=3", "/post/abc-123?page=2#p105", "/post/abc-123#r7", "/archive/2024/05", "/admin/posts?page=2&x=%20"] {
+            assert_eq!(return_target(&config, raw).as_deref(), Some(raw), "{}", raw);
+        }
+        assert_eq!(location(&config, Some("/post/abc-123?page=2#p105"), "/".to_string()), "/post/abc-123?page=2#p105");
+    }
+
+    #[test]
+    fn anything_else_falls_back_to_the_default() {
+        let config = config("");
+        let refused = [
+            "https://evil.example",
+            "https://evil.example/post/abc",
+            "//evil.example",
+            "//evil.example/post/abc",
+            "/\\evil.example",
+            "\\\\evil.example",
+            "javascript:alert(1)",
+            "evil.example",
+            "",
+            "/post/../../evil",
+            "/post/abc/../../evil",
+            "/post/",
+            "/post/abc/extra",
+            "/post/a.b",
+            "/post/abc?next=https://evil.example",
+            "/post/abc?x=<script>",
+            "/post/abc#\"><script>",
+            "/post/abc#a b",
+            "/admin/posts\r\nLocation: https://evil.example",
+            "/unknown",
+        ];
+        for raw in refused {
+            assert_eq!(return_target(&config, raw), None, "{:?}", raw);
+            assert_eq!(location(&config, Some(raw), "/default".to_string()), "/default", "{:?}", raw);
+        }
+        assert_eq!(location(&config, None, "/default".to_string()), "/default");
+    }
+
+    #[test]
+    fn under_a_base_path_only_its_own_paths_are_followed() {
+        let config = config("/board");
+        assert_eq!(return_target(&config, "/board/post/abc?page=2#p105").as_deref(), Some("/board/post/abc?page=2#p105"));
+        assert_eq!(return_target(&config, "/board/").as_deref(), Some("/board/"));
+        for raw in ["/post/abc", "/boardevil", "/board//evil.example", "/board/../post/abc", "//board/post/abc"] {
+            assert_eq!(return_target(&config, raw), None, "{:?}", raw);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+// `reindex`: works out again, from the posts themselves, what the board
+// otherwise keeps up to date as posts come, change and go, for repairing
+// it when that has gone wrong:
+//
+//   your_project_name reindex
+//
+// Quote backlinks (see backlinks.rs) are rebuilt from every reply's
+// message and `reply_counts` from the replies there are, so the result is
+// the same whatever state they were in. The archive database is done too
+// when ARCHIVE_DB points at one. Like verify-files this runs with the
+// server stopped.
+
+use sled::Db;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::config::Config;
+use crate::{backlinks, counters, startup, storage};
+
+pub fn run(args: &[String]) -> std::io::Result<()> {
+    if let Some(arg) = args.first() {
+        eprintln!("reindex takes no arguments, got {:?}", arg);
+        std::process::exit(2);
+    }
+    let config = Config::from_env();
+    let mut databases = vec!["my_db".to_string()];
+    if let Some(path) = config.archive_db.as_ref().filter(|path| path.exists()) {
+        databases.push(path.to_string_lossy().into_owned());
+    }
+    for path in &databases {
+        let db = startup::open_or_exit(path, None);
+        let (backlinks, threads) = reindex(&db, config.scan_threads);
+        db.flush()?;
+        eprintln!("{}: {} backlinks, {} threads with replies", path, backlinks, threads);
+    }
+    Ok(())
+}
+
+// Returns how many backlinks there are and how many threads have replies
+pub fn reindex(db: &Db, parallelism: usize) -> (usize, usize) {
+    let backlinks = backlinks::rebuild(db, parallelism);
+    (backlinks, rebuild_reply_counts(db, parallelism))
+}
+
+fn rebuild_reply_counts(db: &Db, parallelism: usize) -> usize {
+    let counts: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+    let scan = storage::scan_all_parallel(db, parallelism, |post| {
+        if let Some(thread_id) = post.parent_id {
+            *counts.lock().unwrap().entry(thread_id).or_default() += 1;
+        }
+    });
+    scan.log_unreadable("reply counts");
+    let tree = db.open_tree("reply_counts").unwrap();
+    tree.clear().unwrap();
+    let mut threads = 0;
+    for (thread_id, count) in counts.into_inner().unwrap() {
+        // Replies left behind by a thread that's gone don't count
+        if db.contains_key(&thread_id).unwrap() {
+            counters::increment(&tree, &thread_id, count as i64);
+            threads += 1;
+        }
+    }
+    db.flush().unwrap();
+    threads
+}
@@ -0,0 +1,217 @@
+// Why a post was refused, in a form both the HTML form path and API
+// clients can use. Validation produces FieldErrors; a Rejection carries
+// them to the client as the styled rejection page, or as
+//
+//   { "error": "validation_failed",
+//     "fields": [{ "field": "title", "code": "too_long", "max": 15, "message": "..." }] }
+//
+// for clients that ask for JSON.
+
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use askama::Template;
+use serde::Serialize;
+use std::fmt;
+
+use crate::config::Config;
+use crate::render;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Missing,
+    TooLong,
+    // Not a value the field accepts, e.g. a closing time in the past
+    Invalid,
+    Spam,
+    Locked,
+    // Replying to a thread that isn't there
+    ThreadGone,
+    Banned,
+    RateLimited,
+    FileTooLarge,
+    UnsupportedMedia,
+    // The file was taken down and may not be posted again
+    Blocked,
+    UploadFailed,
+    // The upload volume is nearly full; text-only posts still work
+    UploadsDisabled,
+    TooSlow,
+    TooManyChunks,
+    // A request with the same submit token is still being read, see
+    // replay.rs
+    InProgress,
+    // Every upload slot is taken, see upload_slots.rs; text-only posts
+    // still work
+    UploadsBusy,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    // A form field, or "post" for problems with the post as a whole
+    pub field: &'static str,
+    pub code: ErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, code: ErrorCode, message: impl Into<String>) -> FieldError {
+        FieldError {
+            field,
+            code,
+            max: None,
+            retry_after: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn max(mut self, max: u64) -> FieldError {
+        self.max = Some(max);
+        self
+    }
+
+    pub fn retry_after(mut self, seconds: u64) -> FieldError {
+        self.retry_after = Some(seconds);
+        self
+    }
+}
+
+// JSON for clients that list application/json in Accept without also
+// accepting HTML, the way browsers do.
+pub fn wants_json(req: &HttpRequest) -> bool {
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .unwrap_or_default();
+    accept.contains("application/json") && !accept.contains("text/html")
+}
+
+#[derive(Template)]
+#[template(path = "rejected.html")]
+struct RejectedTemplate<'a> {
+    config: &'a Config,
+    reason: &'a str,
+    back_url: String,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'static str,
+    fields: &'a [FieldError],
+}
+
+pub struct Rejection {
+    config: web::Data<Config>,
+    back_url: String,
+    json: bool,
+    status: StatusCode,
+    errors: Vec<FieldError>,
+    // Shown to browsers in place of the rejection page, see page()
+    page: Option<String>,
+}
+
+impl Rejection {
+    pub fn new(config: &web::Data<Config>, req: &HttpRequest, parent_id: Option<&str>, errors: Vec<FieldError>) -> Rejection {
+        // Too-fast posting, a full disk and busy uploads get their own
+        // statuses so clients know to wait, and a deleted thread says it's
+        // gone
+        let thread_gone = errors.iter().any(|error| error.code == ErrorCode::ThreadGone);
+        let status = if errors.iter().any(|error| error.code == ErrorCode::RateLimited) {
+            StatusCode::TOO_MANY_REQUESTS
+        } else if errors.iter().any(|error| matches!(error.code, ErrorCode::UploadsDisabled | ErrorCode::UploadsBusy)) {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else if errors.iter().any(|error| error.code == ErrorCode::InProgress) {
+            StatusCode::CONFLICT
+        } else if thread_gone {
+            StatusCode::GONE
+        } else {
+            StatusCode::BAD_REQUEST
+        };
+        Rejection {
+            config: config.clone(),
+            back_url: match parent_id {
+                Some(parent_id) if !thread_gone => config.post_url(parent_id),
+                _ => config.index_url(),
+            },
+            json: wants_json(req),
+            status,
+            errors,
+            page: None,
+        }
+    }
+
+    pub fn errors(&self) -> &[FieldError] {
+        &self.errors
+    }
+
+    // A page of the caller's to show browsers instead, such as the thread
+    // with the reply form filled back in. JSON clients still get the JSON,
+    // and the status and Retry-After stay the same either way.
+    pub fn page(mut self, html: String) -> Rejection {
+        self.page = Some(html);
+        self
+    }
+
+    pub fn status(mut self, status: StatusCode) -> Rejection {
+        self.status = status;
+        self
+    }
+
+    fn reason(&self) -> String {
+        self.errors.iter().map(|error| error.message.as_str()).collect::<Vec<_>>().join(" ")
+    }
+}
+
+impl fmt::Debug for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Rejection").field("errors", &self.errors).finish()
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.reason())
+    }
+}
+
+impl ResponseError for Rejection {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status);
+        if let Some(seconds) = self.errors.iter().find_map(|error| error.retry_after) {
+            response.insert_header((header::RETRY_AFTER, seconds.to_string()));
+        }
+        if self.json {
+            return response.json(ErrorBody {
+                error: "validation_failed",
+                fields: &self.errors,
+            });
+        }
+        if let Some(page) = &self.page {
+            return response.content_type("text/html").body(page.clone());
+        }
+        let template = RejectedTemplate {
+            config: &self.config,
+            reason: &self.reason(),
+            back_url: self.back_url.clone(),
+        };
+        render::respond(response, &template, "a rejected post")
+    }
+}
+
+pub fn check_templates(config: &Config) -> Result<(), String> {
+    render::check(&RejectedTemplate {
+        config,
+        reason: "The title is too long.",
+        back_url: config.index_url(),
+    })
+}
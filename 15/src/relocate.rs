@@ -0,0 +1,128 @@
+// `migrate-uploads`: moves files from the old flat upload directory into the
+// dated layout, see upload.rs.
+//
+//   your_project_name migrate-uploads [--dry-run]
+//
+// One pass over the posts, held ones included: each file still stored under
+// just its name goes to "YYYY/MM/" for the month its post was made, and the
+// post's record and the `uploads` index are rewritten to match right after
+// the move, so an interrupted run leaves nothing pointing at the wrong
+// place and can simply be run again. Posts whose file is missing are left
+// alone for verify-files to report. Links to the old flat paths stop
+// working once their file has moved. With --dry-run nothing is moved or
+// rewritten and the moves are only listed. Like verify-files this runs with
+// the server stopped.
+
+use serde::Serialize;
+use sled::{Db, Tree};
+
+use crate::config::Config;
+use crate::{schema, startup, upload, Post};
+
+#[derive(Serialize)]
+struct Move {
+    post_id: String,
+    from: String,
+    to: String,
+}
+
+#[derive(Serialize, Default)]
+struct Totals {
+    posts_scanned: usize,
+    already_dated: usize,
+    moved: usize,
+    missing: usize,
+    failed: usize,
+}
+
+#[derive(Serialize, Default)]
+struct Report {
+    dry_run: bool,
+    moves: Vec<Move>,
+    missing: Vec<Move>,
+    failed: Vec<String>,
+    totals: Totals,
+}
+
+// The file goes first and the record after it; a record pointing at a file
+// that isn't there yet would be served as a 404.
+fn relocate(db: &Db, config: &Config, tree: &Tree, post: &Post, to: &str) -> Result<(), String> {
+    let from = post.file.as_deref().unwrap_or_default();
+    let target = config.upload_path(to);
+    if let Some(dir) = target.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    upload::move_into_place(&config.upload_path(from), &target).map_err(|e| e.to_string())?;
+
+    let raw = tree.get(&post.id).unwrap().ok_or("the post went away")?;
+    let updated = schema::merge_fields(&raw, |fields| {
+        fields.insert("file".to_string(), to.into());
+    })
+    .map_err(|_| "the record can't be read".to_string())?;
+    tree.insert(&post.id, updated).unwrap();
+    let uploads = db.open_tree("uploads").unwrap();
+    if let Some(owner) = uploads.remove(from).unwrap() {
+        uploads.insert(to, owner).unwrap();
+    }
+    Ok(())
+}
+
+pub fn run(args: &[String]) -> std::io::Result<()> {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let config = Config::from_env();
+    let db = startup::open_or_exit("my_db", None);
+    let mut report = Report {
+        dry_run,
+        ..Report::default()
+    };
+
+    let main: &Tree = &db;
+    let pending = db.open_tree("pending").unwrap();
+    for tree in [main, &pending] {
+        let posts: Vec<Post> = tree.iter().values().filter_map(|bytes| Post::upgrade(&bytes.unwrap()).ok()).collect();
+        for post in posts {
+            report.totals.posts_scanned += 1;
+            let from = match &post.file {
+                Some(file) if !file.contains('/') => file.clone(),
+                Some(_) => {
+                    report.totals.already_dated += 1;
+                    continue;
+                }
+                None => continue,
+            };
+            let entry = Move {
+                post_id: post.id.clone(),
+                to: format!("{}/{}", upload::dated_dir(post.timestamp), from),
+                from,
+            };
+            if !config.upload_path(&entry.from).is_file() {
+                report.missing.push(entry);
+                continue;
+            }
+            if !dry_run {
+                if let Err(e) = relocate(&db, &config, tree, &post, &entry.to) {
+                    report.failed.push(format!("{}: {}", entry.from, e));
+                    continue;
+                }
+            }
+            report.moves.push(entry);
+        }
+    }
+
+    db.flush()?;
+    report.totals.moved = report.moves.len();
+    report.totals.missing = report.missing.len();
+    report.totals.failed = report.failed.len();
+
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    eprintln!(
+        "{} posts: {} {}, {} already dated, {} missing, {} failed",
+        report.totals.posts_scanned,
+        report.totals.moved,
+        if dry_run { "to move" } else { "moved" },
+        report.totals.already_dated,
+        report.totals.missing,
+        report.totals.failed,
+    );
+    Ok(())
+}
@@ -0,0 +1,74 @@
+// The reply form's options field, remembered for posters who always use
+// the same ones. A reply sent with "Remember my options" ticked keeps its
+// options in the `post_options` cookie, one sent without it clears the
+// cookie, and thread pages fill the field in from it. New threads leave it
+// alone.
+//
+// The cookie is "{hex of the options}.{signature}", an HMAC under a key
+// kept in the database like the age cookie's, so a hand-edited one is
+// ignored. What's kept has been through validation::parse_options, so
+// it's at most 100 characters with no markup or line breaks.
+
+use actix_web::cookie::time::Duration as CookieDuration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{HttpRequest, HttpResponse};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sled::Db;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::upload;
+
+const COOKIE: &str = "post_options";
+const COOKIE_DAYS: i64 = 365;
+
+fn key(db: &Db) -> Vec<u8> {
+    let meta = db.open_tree("meta").unwrap();
+    let fresh = Uuid::new_v4().to_string();
+    let _ = meta.compare_and_swap("options_cookie_key", None as Option<&[u8]>, Some(fresh.as_bytes())).unwrap();
+    meta.get("options_cookie_key").unwrap().unwrap().to_vec()
+}
+
+fn signature(db: &Db, encoded: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key(db)).unwrap();
+    mac.update(format!("{}:{}", COOKIE, encoded).as_bytes());
+    upload::hex(&mac.finalize().into_bytes())
+}
+
+fn decode_hex(encoded: &str) -> Option<String> {
+    let bytes = (0..encoded.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(encoded.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+// The remembered options, None without a cookie or with one that doesn't
+// check out
+pub fn options(db: &Db, req: &HttpRequest) -> Option<String> {
+    let cookie = req.cookie(COOKIE)?;
+    let (encoded, given) = cookie.value().split_once('.')?;
+    if given != signature(db, encoded) {
+        return None;
+    }
+    decode_hex(encoded).filter(|options| !options.is_empty())
+}
+
+// After a reply is taken: keeps `options` when the box was ticked and
+// there's something to keep, otherwise drops any cookie there is
+pub fn apply(db: &Db, config: &Config, req: &HttpRequest, response: &mut HttpResponse, remember: bool, options: &str) {
+    let encoded = upload::hex(options.as_bytes());
+    let value = format!("{}.{}", encoded, signature(db, &encoded));
+    let cookie = Cookie::build(COOKIE, value)
+        .path(config.index_url())
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::days(COOKIE_DAYS))
+        .finish();
+    if remember && !options.is_empty() {
+        response.add_cookie(&cookie).unwrap();
+    } else if req.cookie(COOKIE).is_some() {
+        response.add_removal_cookie(&cookie).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+// Turning templates into responses. A template that fails to render is
+// logged with its name and what it was showing, and the visitor gets a
+// small fixed page instead of a bare 500; on the index only the failing
+// thread is left out. `self_check` renders every template against made-up
+// data at startup, so a broken template stops the deploy rather than the
+// first visitor.
+
+use actix_web::{HttpResponse, HttpResponseBuilder};
+use askama::Template;
+
+use crate::config::Config;
+use crate::upload::MediaKind;
+use crate::{admin, age_gate, announcement, archive, previews, quotes, rejection, schema, stats, timings, widget, Post};
+
+const FALLBACK_PAGE: &str = "<!DOCTYPE html>\n<html>\n<head><meta charset=\"UTF-8\"><title>Error</title></head>\n<body><main><p>This page could not be shown right now. Please try again later.</p></main></body>\n</html>\n";
+const FALLBACK_FRAGMENT: &str = "<article class=\"post\"><p>This post could not be shown.</p></article>";
+
+// "PostViewTemplate" rather than the full path and lifetimes
+fn name<T>() -> &'static str {
+    let name = std::any::type_name::<T>().split('<').next().unwrap_or_default();
+    name.rsplit("::").next().unwrap_or_default()
+}
+
+// None, logged, if it fails. `context` says what was being shown, e.g.
+// "post {id}".
+pub fn to_string<T: Template>(template: &T, context: &str) -> Option<String> {
+    let rendered = timings::time(timings::Op::Render, name::<T>(), || template.render());
+    match rendered {
+        Ok(html) => Some(html),
+        Err(e) => {
+            eprintln!("rendering {} for {} failed: {}", name::<T>(), context, e);
+            None
+        }
+    }
+}
+
+// The page with `response`'s status, or error_page if it fails
+pub fn respond<T: Template>(mut response: HttpResponseBuilder, template: &T, context: &str) -> HttpResponse {
+    match to_string(template, context) {
+        Some(html) => response.content_type("text/html").body(html),
+        None => error_page(),
+    }
+}
+
+pub fn error_page() -> HttpResponse {
+    HttpResponse::InternalServerError().content_type("text/html").body(FALLBACK_PAGE)
+}
+
+// For pieces of a page, which get a placeholder instead
+pub fn fragment<T: Template>(template: &T, context: &str) -> String {
+    to_string(template, context).unwrap_or_else(|| FALLBACK_FRAGMENT.to_string())
+}
+
+// For self_check
+pub fn check<T: Template>(template: &T) -> Result<(), String> {
+    template.render().map(|_| ()).map_err(|e| format!("{}: {}", name::<T>(), e))
+}
+
+// A thread and a reply with every optional field filled in, so each branch
+// of the templates gets exercised.
+fn fixtures() -> (Post, Post) {
+    let thread = Post {
+        schema: schema::POST_SCHEMA,
+        id: "00000000-0000-0000-0000-000000000001".to_string(),
+        parent_id: None,
+        // Quotes to escape in title attributes, and an emoji joined across
+        // the card cut
+        title: "Self-check \"quoted\" & <b> 👩\u{200D}👩\u{200D}👧 past the cut, and longer than a page title".to_string(),
+        name: Some("Tester".to_string()),
+        tripcode: Some("0123456789".to_string()),
+        capcode: Some("Admin".to_string()),
+        message: ">quoted\n>>1\nhttps://example.com <b>not bold</b> & more".to_string(),
+        file: Some("00000000-0000-0000-0000-000000000001.png".to_string()),
+        timestamp: 1,
+        ip_hash: Some("0123456789abcdef".to_string()),
+        upload_error: None,
+        file_hash: Some("0".repeat(64)),
+        original_name: Some("picture.png".to_string()),
+        file_size: Some(1536),
+        media_kind: Some(MediaKind::Image),
+        reply_number: None,
+        removal_reason: None,
+        locks_at: Some(u64::MAX),
+        width: Some(640),
+        height: Some(480),
+        converted_from: Some("heic".to_string()),
+        slow_mode_secs: Some(60),
+        thumbnail: None,
+        media_pruned: false,
+        announcement: true,
+    };
+    let reply = Post {
+        id: "00000000-0000-0000-0000-000000000002".to_string(),
+        parent_id: Some(thread.id.clone()),
+        name: None,
+        tripcode: None,
+        capcode: None,
+        file: None,
+        upload_error: Some("The upload failed.".to_string()),
+        file_hash: None,
+        original_name: None,
+        file_size: None,
+        media_kind: None,
+        reply_number: Some(1),
+        removal_reason: Some("Removed.".to_string()),
+        locks_at: None,
+        width: None,
+        height: None,
+        converted_from: None,
+        slow_mode_secs: None,
+        thumbnail: Some("00000000-0000-0000-0000-000000000002-thumb.png".to_string()),
+        media_pruned: true,
+        announcement: false,
+        ..thread.clone()
+    };
+    (thread, reply)
+}
+
+pub fn self_check(config: &Config) -> Result<(), String> {
+    let (thread, reply) = fixtures();
+    crate::check_templates(config, &thread, &reply)?;
+    admin::check_templates(config, &thread, &reply)?;
+    stats::check_templates(config)?;
+    archive::check_templates(config, &thread)?;
+    widget::check_templates(config, &thread)?;
+    age_gate::check_templates(config)?;
+    quotes::check_templates(config)?;
+    previews::check_templates(config, &reply)?;
+    timings::check_templates(config)?;
+    announcement::check_templates(config)?;
+    rejection::check_templates(config)
+}
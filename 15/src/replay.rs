@@ -0,0 +1,119 @@
+// Protection against the same post being sent more than once, as happens
+// when someone on a bad connection retries a slow upload. Every post form
+// carries a random `submit_token` as its first field.
+//
+// While a request with a token is being read, the token is in InFlight, and
+// a second request with it gets a 409 straight away instead of uploading
+// the file again alongside the first. The token leaves the set when the
+// first request finishes, fails or is dropped. Because it's the first
+// field, the decision is made before any of the file is read; save_post
+// refuses a token that comes later.
+//
+// Once a post is saved its token is kept in `submit_tokens` for KEEP_SECS
+// with the post's id and a hash of its message. A later request with the
+// same token and message is answered as the first one was, without making
+// another post. A different message under the same token (the form sent
+// again after going back) is a new post.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sled::Db;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+use crate::upload;
+
+const KEEP_SECS: u64 = 24 * 60 * 60;
+const MAX_TOKEN_LEN: usize = 64;
+
+// For the hidden field of a freshly rendered form
+pub fn new_token() -> String {
+    Uuid::new_v4().to_string()
+}
+
+pub fn is_valid_token(token: &str) -> bool {
+    !token.is_empty() && token.len() <= MAX_TOKEN_LEN && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+#[derive(Default)]
+pub struct InFlight {
+    tokens: Arc<Mutex<HashSet<String>>>,
+}
+
+impl InFlight {
+    // None when a request with this token is already being read
+    pub fn begin(&self, token: &str) -> Option<InFlightGuard> {
+        if !self.tokens.lock().unwrap().insert(token.to_string()) {
+            return None;
+        }
+        Some(InFlightGuard {
+            tokens: self.tokens.clone(),
+            token: token.to_string(),
+        })
+    }
+}
+
+// Takes the token out of the set when dropped, however the request ends
+pub struct InFlightGuard {
+    tokens: Arc<Mutex<HashSet<String>>>,
+    token: String,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.tokens.lock().unwrap().remove(&self.token);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Completed {
+    post_id: String,
+    message_hash: String,
+    at: u64,
+}
+
+fn message_hash(message: &str) -> String {
+    upload::hex(&Sha256::digest(message.as_bytes()))
+}
+
+// Whether a post was already made with this token and message
+pub fn is_replay(db: &Db, token: &str, message: &str, now: u64) -> bool {
+    let completed = db
+        .open_tree("submit_tokens")
+        .unwrap()
+        .get(token)
+        .unwrap()
+        .and_then(|bytes| serde_json::from_slice::<Completed>(&bytes).ok());
+    match completed {
+        Some(completed) => now.saturating_sub(completed.at) <= KEEP_SECS && completed.message_hash == message_hash(message),
+        None => false,
+    }
+}
+
+pub fn record(db: &Db, token: &str, post_id: &str, message: &str, now: u64) {
+    let completed = Completed {
+        post_id: post_id.to_string(),
+        message_hash: message_hash(message),
+        at: now,
+    };
+    db.open_tree("submit_tokens").unwrap().insert(token, serde_json::to_vec(&completed).unwrap()).unwrap();
+}
+
+// Returns how many tokens were removed.
+pub fn prune(db: &Db, now: u64) -> usize {
+    let tree = db.open_tree("submit_tokens").unwrap();
+    let expired: Vec<_> = tree
+        .iter()
+        .map(|item| item.unwrap())
+        .filter(|(_, bytes)| {
+            let at = serde_json::from_slice::<Completed>(bytes).map(|completed| completed.at).unwrap_or(0);
+            now.saturating_sub(at) > KEEP_SECS
+        })
+        .map(|(token, _)| token)
+        .collect();
+    for token in &expired {
+        tree.remove(token).unwrap();
+    }
+    expired.len()
+}
@@ -0,0 +1,100 @@
+// The reply form as it was sent, for putting it back when a reply can't go
+// in yet: on the quote warning (quotes.rs), and on the thread page when
+// validation refuses a reply, with a banner saying which fields were wrong.
+//
+// The thread page's form starts from one of three places, in this order:
+//
+//   1. a sent form, field for field, options and the remember box included
+//   2. otherwise ?quote=N, which starts the message with ">>N"
+//   3. otherwise empty, with any remembered options (remember.rs)
+//
+// A sent form wins outright, so a reply that started from ?quote= and came
+// back keeps the one quote line it was sent with instead of gaining another.
+// The file is never kept, only noted.
+//
+// The form also carries when it was shown (`rendered_at`). A stored reply
+// goes back to its thread as ?just_posted=N&since=T#rN, and the thread page
+// then says on reply N how many replies went in while it was being written,
+// since those weren't on the page its writer was looking at. A form that
+// comes back keeps the time it was first shown.
+
+use crate::rejection::FieldError;
+use crate::Post;
+
+pub struct FormState {
+    pub parent_id: String,
+    pub return_to: Option<String>,
+    pub title: String,
+    // As typed, tripcode password and all, the way it was sent
+    pub name: String,
+    pub options: String,
+    pub remember_options: bool,
+    pub message: String,
+    pub had_file: bool,
+    pub rendered_at: Option<u64>,
+}
+
+fn field_label(field: &str) -> Option<&'static str> {
+    match field {
+        "title" => Some("Title"),
+        "name" => Some("Name"),
+        "options" => Some("Options"),
+        "message" => Some("Message"),
+        "file" => Some("File"),
+        "email" => Some("Email"),
+        _ => None,
+    }
+}
+
+// One line per error, led by the field it's about, e.g. "Title: The title
+// is too long." Problems with the post as a whole have no field to name.
+pub fn banner(errors: &[FieldError]) -> Vec<String> {
+    errors
+        .iter()
+        .map(|error| match field_label(error.field) {
+            Some(label) => format!("{}: {}", label, error.message),
+            None => error.message.clone(),
+        })
+        .collect()
+}
+
+// Where reply `number` goes once it's stored: `location` on the reply's
+// `page` and with its anchor swapped for the reply's, and with what the
+// thread page needs to say what was posted meanwhile
+pub fn after_reply(location: &str, number: u64, page: u64, rendered_at: Option<u64>) -> String {
+    let location = location.split('#').next().unwrap_or_default();
+    let (path, query) = location.split_once('?').unwrap_or((location, ""));
+    let mut params: Vec<String> = query
+        .split('&')
+        .filter(|param| !param.is_empty() && *param != "page" && !param.starts_with("page="))
+        .map(str::to_string)
+        .collect();
+    if page > 0 {
+        params.push(format!("page={}", page));
+    }
+    params.push(format!("just_posted={}", number));
+    if let Some(rendered_at) = rendered_at {
+        params.push(format!("since={}", rendered_at));
+    }
+    format!("{}?{}#r{}", path, params.join("&"), number)
+}
+
+// Replies before reply `number` posted after `since`. Post times are whole
+// seconds, so one that went in the same second as the form was shown isn't
+// counted; it may well have been on the page.
+pub fn posted_meanwhile<'a>(replies: impl Iterator<Item = &'a Post>, number: u64, since: u64) -> usize {
+    replies
+        .filter(|reply| reply.reply_number.is_some_and(|n| n < number))
+        .filter(|reply| reply.timestamp > since)
+        .count()
+}
+
+// "2 replies were posted while you were writing, above yours."
+pub fn meanwhile_notice(count: usize, newest_first: bool) -> String {
+    let lead = match count {
+        1 => "1 reply was".to_string(),
+        count => format!("{} replies were", count),
+    };
+    let place = if newest_first { "below" } else { "above" };
+    format!("{} posted while you were writing, {} yours.", lead, place)
+}
@@ -0,0 +1,214 @@
+// How long dated data is kept. Each class below is purged by the hourly
+// maintenance job once it's older than its retention; RETENTION sets any
+// of them, e.g. "archives=180d, watches=30d, audit=forever":
+//
+//   archives  archived threads, by when they were archived   forever
+//   quota     hourly posting quota buckets                   0 (this hour only)
+//   watches   reply notification addresses, by when given    90d
+//   audit     the admin action log                           forever
+//   activity  board-wide hourly post counts behind /stats    30d
+//   changes   the board change log behind the export         forever
+//
+// Durations are a whole number with s, m, h or d; "forever" keeps a class
+// for good. Classes left out keep their default. Every class is a
+// time-keyed tree read from the oldest end, except archives, which is
+// small enough to scan. Archived threads are deleted like any other
+// thread, events and all. Whatever each run removes is written to the
+// audit log, and the totals since startup are at /readyz.
+//
+// The change log is also capped at EXPORT_RETAINED_CHANGES entries, and the
+// popular threads buckets at a day, whatever the retention.
+
+use serde::Serialize;
+use sled::Db;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::activity::{self, HEATMAP_DAYS};
+use crate::config::Config;
+use crate::events::{BoardEvent, EventBus};
+use crate::{archive, audit, changes, notify, quota, storage};
+
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum DataClass {
+    Archives,
+    Quota,
+    Watches,
+    Audit,
+    Activity,
+    Changes,
+}
+
+impl DataClass {
+    pub const ALL: [DataClass; 6] = [
+        DataClass::Archives,
+        DataClass::Quota,
+        DataClass::Watches,
+        DataClass::Audit,
+        DataClass::Activity,
+        DataClass::Changes,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DataClass::Archives => "archives",
+            DataClass::Quota => "quota",
+            DataClass::Watches => "watches",
+            DataClass::Audit => "audit",
+            DataClass::Activity => "activity",
+            DataClass::Changes => "changes",
+        }
+    }
+
+    // Seconds, None for forever. What the board did before retention was
+    // configurable.
+    fn default_retention(self) -> Option<u64> {
+        match self {
+            DataClass::Archives | DataClass::Audit | DataClass::Changes => None,
+            DataClass::Quota => Some(0),
+            DataClass::Watches => Some(90 * DAY),
+            DataClass::Activity => Some(HEATMAP_DAYS * DAY),
+        }
+    }
+}
+
+impl FromStr for DataClass {
+    type Err = ();
+
+    fn from_str(raw: &str) -> Result<DataClass, ()> {
+        DataClass::ALL.iter().copied().find(|class| class.as_str() == raw).ok_or(())
+    }
+}
+
+// "180d" -> 180 days in seconds, "forever" -> None
+pub fn parse_duration(raw: &str) -> Result<Option<u64>, String> {
+    if raw == "forever" {
+        return Ok(None);
+    }
+    let split = raw.find(|c: char| !c.is_ascii_digit()).unwrap_or(raw.len());
+    let (number, unit) = (&raw[..split], &raw[split..]);
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => DAY,
+        "" if number == "0" => 0,
+        _ => return Err(format!("\"{}\" should be a number with s, m, h or d, or \"forever\"", raw)),
+    };
+    let number: u64 = number.parse().map_err(|_| format!("\"{}\" doesn't start with a number", raw))?;
+    number.checked_mul(unit).map(Some).ok_or_else(|| format!("{} is too long", raw))
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    // Only the classes that were set
+    overrides: BTreeMap<DataClass, Option<u64>>,
+}
+
+impl RetentionPolicy {
+    // "class=duration" pairs separated by commas
+    pub fn parse(raw: &str) -> Result<RetentionPolicy, String> {
+        let mut overrides = BTreeMap::new();
+        for entry in raw.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (class, duration) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("\"{}\" should look like class=duration", entry))?;
+            let class: DataClass = class.trim().parse().map_err(|_| {
+                let known: Vec<&str> = DataClass::ALL.iter().map(|class| class.as_str()).collect();
+                format!("\"{}\" isn't a data class, try one of {}", class.trim(), known.join(", "))
+            })?;
+            overrides.insert(class, parse_duration(duration.trim())?);
+        }
+        Ok(RetentionPolicy { overrides })
+    }
+
+    // Seconds, None for forever
+    pub fn retention(&self, class: DataClass) -> Option<u64> {
+        self.overrides.get(&class).copied().unwrap_or_else(|| class.default_retention())
+    }
+}
+
+// Entries removed per class since startup, for /readyz
+#[derive(Default)]
+pub struct RetentionCounts {
+    purged: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+#[derive(Serialize)]
+pub struct ClassCounts {
+    retention_secs: Option<u64>,
+    purged: u64,
+}
+
+impl RetentionCounts {
+    fn add(&self, class: DataClass, removed: usize) {
+        *self.purged.lock().unwrap().entry(class.as_str()).or_default() += removed as u64;
+    }
+
+    pub fn counts(&self, policy: &RetentionPolicy) -> BTreeMap<&'static str, ClassCounts> {
+        let purged = self.purged.lock().unwrap();
+        DataClass::ALL
+            .iter()
+            .map(|&class| {
+                let counts = ClassCounts {
+                    retention_secs: policy.retention(class),
+                    purged: purged.get(class.as_str()).copied().unwrap_or(0),
+                };
+                (class.as_str(), counts)
+            })
+            .collect()
+    }
+}
+
+// Deletes threads archived before `before`. Returns how many went.
+fn purge_archives(db: &Db, config: &Config, events: &EventBus, before: u64) -> usize {
+    let mut deleted = 0;
+    for thread_id in archive::archived_before(db, before) {
+        let report = storage::delete_thread(db, config, &thread_id);
+        if report.posts == 0 {
+            continue;
+        }
+        audit::record(db, "maintenance", "delete_thread", &thread_id);
+        events.publish(BoardEvent::Deleted { posts: report.removed });
+        deleted += 1;
+    }
+    deleted
+}
+
+fn purge(db: &Db, config: &Config, events: &EventBus, class: DataClass, before: u64) -> usize {
+    match class {
+        DataClass::Archives => purge_archives(db, config, events, before),
+        DataClass::Quota => quota::prune(db, before),
+        DataClass::Watches => notify::prune(db, before),
+        DataClass::Audit => audit::prune(db, before),
+        DataClass::Activity => activity::prune_post_hours(db, before),
+        DataClass::Changes => changes::prune_board_changes(db, before.saturating_mul(1000)),
+    }
+}
+
+// Purges every class with a retention, and writes one audit entry such as
+// "quota: 12, watches: 1" if anything went. Returns how many entries went
+// in all.
+pub fn run(db: &Db, config: &Config, events: &EventBus, counts: &RetentionCounts, now: u64) -> usize {
+    let mut total = 0;
+    let mut report = Vec::new();
+    for class in DataClass::ALL {
+        let retention = match config.retention.retention(class) {
+            Some(retention) => retention,
+            None => continue,
+        };
+        let removed = purge(db, config, events, class, now.saturating_sub(retention));
+        if removed > 0 {
+            report.push(format!("{}: {}", class.as_str(), removed));
+            counts.add(class, removed);
+        }
+        total += removed;
+    }
+    if !report.is_empty() {
+        audit::record(db, "maintenance", "retention", &report.join(", "));
+    }
+    total
+}
@@ -0,0 +1,174 @@
+// Limits on a request by what its route is for: how large a body it may
+// send, how long its handler may take and whether the connection is kept
+// open afterwards. Posts with files need megabytes, everything else a few
+// kilobytes at most, and admin exports need far longer than a page should
+// ever take.
+//
+// Each request is put in a class by its path (see class_for), and the class
+// gets its limits from LIMITS_UPLOAD, LIMITS_STANDARD and LIMITS_EXPORT, as
+// "size:seconds" with ":close" to close the connection after the response,
+// e.g. LIMITS_STANDARD=16KB:30 or LIMITS_EXPORT=64KB:3600:close.
+//
+// A body declared larger than the class allows is refused with a 413 before
+// the handler runs. One sent without a length is cut off once it passes the
+// limit, which the handler's extractor turns into a 413 of its own.
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::{ErrorServiceUnavailable, PayloadError};
+use actix_web::http::header::CONTENT_LENGTH;
+use actix_web::http::ConnectionType;
+use actix_web::rt::time::timeout;
+use actix_web::web::{self, Bytes};
+use actix_web::{Error, HttpMessage, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use futures_util::{Stream, StreamExt};
+use std::future::{ready, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::bytesize;
+use crate::config::Config;
+use crate::redirect::path_matches;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LimitClass {
+    // Forms that can carry a file
+    Upload,
+    // Whole-board and per-post archives for admins
+    Export,
+    // Everything else
+    Standard,
+}
+
+// Paths relative to the base path, with "*" for one id segment as in
+// redirect.rs
+const UPLOAD_PATHS: &[&str] = &["/submit", "/admin/takedown"];
+const EXPORT_PATHS: &[&str] = &["/admin/export/stream", "/admin/post/*/dossier.zip"];
+
+pub fn class_for(config: &Config, path: &str) -> LimitClass {
+    let path = match path.strip_prefix(config.base_path.as_str()) {
+        Some(path) => path,
+        None => return LimitClass::Standard,
+    };
+    if UPLOAD_PATHS.iter().any(|shape| path_matches(shape, path)) {
+        LimitClass::Upload
+    } else if EXPORT_PATHS.iter().any(|shape| path_matches(shape, path)) {
+        LimitClass::Export
+    } else {
+        LimitClass::Standard
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Limits {
+    pub max_body: u64,
+    pub timeout: Duration,
+    pub keep_alive: bool,
+}
+
+impl Limits {
+    // "size:seconds" or "size:seconds:close", the size with or without a unit
+    pub fn parse(value: &str) -> Result<Limits, String> {
+        let mut parts = value.split(':').map(str::trim);
+        let max_body = bytesize::parse(parts.next().unwrap_or_default())?;
+        let seconds: u64 = match parts.next().map(str::parse) {
+            Some(Ok(seconds)) if seconds > 0 => seconds,
+            _ => return Err("expected a number of seconds after the size".to_string()),
+        };
+        let keep_alive = match parts.next() {
+            None => true,
+            Some("close") => false,
+            Some(other) => return Err(format!("expected \"close\" or nothing, not {:?}", other)),
+        };
+        if parts.next().is_some() {
+            return Err("expected \"size:seconds\" with an optional \":close\"".to_string());
+        }
+        Ok(Limits {
+            max_body,
+            timeout: Duration::from_secs(seconds),
+            keep_alive,
+        })
+    }
+}
+
+pub struct RouteLimits;
+
+impl<S, B> Transform<S, ServiceRequest> for RouteLimits
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RouteLimitsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RouteLimitsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RouteLimitsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RouteLimitsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let config = req.app_data::<web::Data<Config>>().unwrap().clone();
+        let limits = config.limits(class_for(&config, req.path()));
+
+        let declared = req.headers().get(CONTENT_LENGTH).and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+        if declared.is_some_and(|length| length > limits.max_body) {
+            let response = HttpResponse::PayloadTooLarge()
+                .content_type("text/plain")
+                .body(format!("The request is too large. The limit is {}.", config.human_size(limits.max_body)));
+            return Box::pin(async move { Ok(req.into_response(response).map_into_right_body()) });
+        }
+        let payload = capped(req.take_payload(), limits.max_body);
+        req.set_payload(payload);
+
+        let service = self.service.clone();
+        Box::pin(async move {
+            let mut res = match timeout(limits.timeout, service.call(req)).await {
+                Ok(res) => res?,
+                Err(_) => return Err(ErrorServiceUnavailable("The request took too long.")),
+            };
+            if !limits.keep_alive {
+                res.response_mut().head_mut().set_connection_type(ConnectionType::Close);
+            }
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+// `payload`, ending in an overflow error once more than `max` bytes of it
+// have been read
+fn capped(payload: Payload, max: u64) -> Payload {
+    let mut read = 0u64;
+    let stream = payload.map(move |chunk| {
+        let chunk = chunk?;
+        read += chunk.len() as u64;
+        if read > max {
+            Err(PayloadError::Overflow)
+        } else {
+            Ok(chunk)
+        }
+    });
+    let stream: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> = Box::pin(stream);
+    Payload::from(stream)
+}
@@ -0,0 +1,200 @@
+// Settings that can change while the board runs: the rate limits and the
+// hourly post quota. They start from the environment like the rest of
+// Config, and CONFIG_FILE can name a file of KEY=value lines, with the same
+// names as the environment variables, that overrides them:
+//
+//   # Quieter at night
+//   RATE_LIMIT_WRITE=3:0.05
+//   POSTS_PER_HOUR=20
+//
+// The file is read at startup, on SIGHUP and from the button on
+// /admin/settings. A file with any bad line is refused as a whole and the
+// settings stay as they were. Everything else, like the upload directory
+// or the base path, is read once at startup and needs a restart. Board
+// settings, wordfilters and announcements are changed from the admin pages
+// and never needed one, see settings.rs.
+//
+// Requests take a snapshot with RuntimeCache::get as they start, so a
+// reload applies from the next request on and never halfway through one.
+
+use actix_web::web;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::config::{env_or, policy_or};
+use crate::rate_limit::{BucketPolicy, RouteClass};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RuntimeSettings {
+    // Token buckets per route class, as "burst:per_second"
+    pub rate_limit_write: BucketPolicy,
+    pub rate_limit_render: BucketPolicy,
+    pub rate_limit_cheap: BucketPolicy,
+    // Most posts one poster may make per clock hour, 0 for no limit
+    pub posts_per_hour: u64,
+}
+
+impl RuntimeSettings {
+    pub fn from_env() -> RuntimeSettings {
+        RuntimeSettings {
+            rate_limit_write: policy_or("RATE_LIMIT_WRITE", 5.0, 0.1),
+            rate_limit_render: policy_or("RATE_LIMIT_RENDER", 60.0, 2.0),
+            rate_limit_cheap: policy_or("RATE_LIMIT_CHEAP", 120.0, 10.0),
+            posts_per_hour: env_or("POSTS_PER_HOUR", 0),
+        }
+    }
+
+    pub fn rate_limit(&self, class: RouteClass) -> BucketPolicy {
+        match class {
+            RouteClass::Write => self.rate_limit_write,
+            RouteClass::Render => self.rate_limit_render,
+            RouteClass::Cheap => self.rate_limit_cheap,
+        }
+    }
+
+    // These settings with the file's lines applied over them, or what's
+    // wrong with each bad line
+    pub fn with_file(&self, text: &str) -> Result<RuntimeSettings, Vec<String>> {
+        let mut settings = *self;
+        let mut errors = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => {
+                    errors.push(format!("line {}: expected KEY=value", n + 1));
+                    continue;
+                }
+            };
+            let applied = match key {
+                "RATE_LIMIT_WRITE" => policy(value).map(|policy| settings.rate_limit_write = policy),
+                "RATE_LIMIT_RENDER" => policy(value).map(|policy| settings.rate_limit_render = policy),
+                "RATE_LIMIT_CHEAP" => policy(value).map(|policy| settings.rate_limit_cheap = policy),
+                "POSTS_PER_HOUR" => value
+                    .parse()
+                    .map(|posts| settings.posts_per_hour = posts)
+                    .map_err(|_| "expected a whole number".to_string()),
+                _ => Err("can't be changed without a restart".to_string()),
+            };
+            if let Err(e) = applied {
+                errors.push(format!("line {}: {}: {}", n + 1, key, e));
+            }
+        }
+        if errors.is_empty() {
+            Ok(settings)
+        } else {
+            Err(errors)
+        }
+    }
+
+    // The names of the settings that differ from `other`
+    pub fn changed(&self, other: &RuntimeSettings) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.rate_limit_write != other.rate_limit_write {
+            changed.push("RATE_LIMIT_WRITE");
+        }
+        if self.rate_limit_render != other.rate_limit_render {
+            changed.push("RATE_LIMIT_RENDER");
+        }
+        if self.rate_limit_cheap != other.rate_limit_cheap {
+            changed.push("RATE_LIMIT_CHEAP");
+        }
+        if self.posts_per_hour != other.posts_per_hour {
+            changed.push("POSTS_PER_HOUR");
+        }
+        changed
+    }
+}
+
+fn policy(value: &str) -> Result<BucketPolicy, String> {
+    BucketPolicy::parse(value).ok_or_else(|| "expected \"burst:per_second\", e.g. 5:0.1".to_string())
+}
+
+pub struct RuntimeCache {
+    // From the environment; the file is applied over these on every reload
+    base: RuntimeSettings,
+    file: Option<PathBuf>,
+    current: RwLock<Arc<RuntimeSettings>>,
+}
+
+impl RuntimeCache {
+    // Reads the file, if there is one. A bad file is reported and the
+    // environment's settings used, as at startup it's better to come up.
+    pub fn new(base: RuntimeSettings, file: Option<PathBuf>) -> RuntimeCache {
+        let cache = RuntimeCache {
+            base,
+            file,
+            current: RwLock::new(Arc::new(base)),
+        };
+        if cache.file.is_some() {
+            if let Err(e) = cache.reload() {
+                eprintln!("{}, using the environment's settings", e);
+            }
+        }
+        cache
+    }
+
+    pub fn get(&self) -> Arc<RuntimeSettings> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    // Reads the file again. Returns the names of the settings that changed,
+    // or why nothing did.
+    pub fn reload(&self) -> Result<Vec<&'static str>, String> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Err("CONFIG_FILE isn't set".to_string()),
+        };
+        let text = std::fs::read_to_string(file).map_err(|e| format!("reading {}: {}", file.display(), e))?;
+        let loaded = self
+            .base
+            .with_file(&text)
+            .map_err(|errors| format!("{} not loaded: {}", file.display(), errors.join("; ")))?;
+        let mut current = self.current.write().unwrap();
+        let changed = current.changed(&loaded);
+        *current = Arc::new(loaded);
+        Ok(changed)
+    }
+}
+
+// "RATE_LIMIT_WRITE, POSTS_PER_HOUR changed" for the log and the admin page
+pub fn describe(changed: &[&str]) -> String {
+    if changed.is_empty() {
+        "nothing changed".to_string()
+    } else {
+        format!("{} changed", changed.join(", "))
+    }
+}
+
+// Reloads on every SIGHUP for as long as the server runs
+#[cfg(unix)]
+pub async fn reload_on_hangup(cache: web::Data<RuntimeCache>) {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    if cache.file().is_none() {
+        return;
+    }
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            eprintln!("can't listen for SIGHUP, the config file won't be reloaded: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match cache.reload() {
+            Ok(changed) => eprintln!("config reloaded: {}", describe(&changed)),
+            Err(e) => eprintln!("{}, keeping the current settings", e),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn reload_on_hangup(_cache: web::Data<RuntimeCache>) {}
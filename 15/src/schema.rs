@@ -0,0 +1,162 @@
+// Versioning for stored `Post` records.
+//
+// Every record carries a `schema` number. Old records (no field) are
+// schema 0 and are upgraded in memory when read. The policy for records
+// written by a *newer* binary, which can happen during a rolling upgrade:
+//
+// - they can be read and rendered; unknown fields are ignored by serde,
+// - they must never be rewritten from a deserialized `Post`, because that
+//   would silently drop the fields this binary doesn't know about,
+// - in-place changes go through `merge_fields`, which edits the raw JSON
+//   object and leaves everything else untouched.
+//
+// Adding a field: give it `#[serde(default)]`, bump POST_SCHEMA, and add a
+// step to `upgrade` if old records need more than the default.
+
+use serde_json::{Map, Value};
+use std::fmt;
+
+use crate::Post;
+
+pub const POST_SCHEMA: u16 = 11;
+
+#[derive(Debug)]
+pub enum UpgradeError {
+    Corrupt(serde_json::Error),
+    NotAnObject,
+}
+
+impl fmt::Display for UpgradeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpgradeError::Corrupt(e) => write!(f, "corrupt post record: {}", e),
+            UpgradeError::NotAnObject => write!(f, "post record is not a JSON object"),
+        }
+    }
+}
+
+impl Post {
+    // The one place raw post bytes become a `Post`. Records from newer
+    // binaries keep their schema number.
+    pub fn upgrade(raw: &[u8]) -> Result<Post, UpgradeError> {
+        let value: Value = serde_json::from_slice(raw).map_err(UpgradeError::Corrupt)?;
+        if !value.is_object() {
+            return Err(UpgradeError::NotAnObject);
+        }
+        let mut post: Post = serde_json::from_value(value).map_err(UpgradeError::Corrupt)?;
+
+        // 0 -> 1: the field was introduced, nothing else changed
+        // 1 -> 2: file_hash, name, tripcode, capcode, original_name and
+        //         file_size added, all optional
+        // 2 -> 3: media_kind added; Post::media_kind covers records without
+        //         it and upload::backfill_media_kinds stores it once
+        // 3 -> 4: reply_number added, assigned once to existing replies by
+        //         numbering::assign_if_missing
+        // 4 -> 5: removal_reason added, optional
+        // 5 -> 6: locks_at added, optional
+        // 6 -> 7: width and height added, optional; images from before
+        //         are shown at the old fixed size
+        // 7 -> 8: converted_from added, optional
+        // 8 -> 9: slow_mode_secs added, optional
+        // 9 -> 10: thumbnail and media_pruned added, both empty until
+        //          media_prune.rs sets them
+        // 10 -> 11: announcement added, false for everything before
+        if post.schema < POST_SCHEMA {
+            post.schema = POST_SCHEMA;
+        }
+
+        Ok(post)
+    }
+}
+
+// Applies `update` to the JSON object in `raw`, keeping every field the
+// update doesn't touch, including ones this binary doesn't know about.
+pub fn merge_fields(raw: &[u8], update: impl FnOnce(&mut Map<String, Value>)) -> Result<Vec<u8>, UpgradeError> {
+    let mut value: Value = serde_json::from_slice(raw).map_err(UpgradeError::Corrupt)?;
+    let object = value.as_object_mut().ok_or(UpgradeError::NotAnObject)?;
+    update(object);
+    Ok(serde_json::to_vec(&value).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // A thread as a binary one schema ahead might write it, with fields
+    // this one has never heard of
+    fn future_thread() -> Value {
+        json!({
+            "schema": POST_SCHEMA + 1,
+            "id": "thread",
+            "parent_id": null,
+            "title": "From the future",
+            "message": "Hello",
+            "file": null,
+            "timestamp": 100,
+            "tags": ["news", "meta"],
+            "pinned_until": 5000,
+        })
+    }
+
+    fn assert_future_fields(raw: &[u8]) {
+        let value: Value = serde_json::from_slice(raw).unwrap();
+        assert_eq!(value["schema"], POST_SCHEMA + 1);
+        assert_eq!(value["tags"], json!(["news", "meta"]));
+        assert_eq!(value["pinned_until"], 5000);
+    }
+
+    #[test]
+    fn old_records_are_upgraded() {
+        let raw = json!({"id": "old", "parent_id": null, "title": "Old", "message": "Hi", "file": null});
+        let post = Post::upgrade(raw.to_string().as_bytes()).unwrap();
+        assert_eq!(post.schema, POST_SCHEMA);
+        assert_eq!(post.title, "Old");
+    }
+
+    #[test]
+    fn future_records_read_and_keep_their_schema() {
+        let post = Post::upgrade(future_thread().to_string().as_bytes()).unwrap();
+        assert_eq!(post.schema, POST_SCHEMA + 1);
+        assert_eq!(post.message, "Hello");
+        assert_eq!(post.timestamp, 100);
+    }
+
+    #[test]
+    fn merging_keeps_unknown_fields() {
+        let raw = future_thread().to_string();
+        let merged = merge_fields(raw.as_bytes(), |fields| {
+            fields.insert("message".to_string(), "Edited".into());
+        })
+        .unwrap();
+        assert_future_fields(&merged);
+        assert_eq!(Post::upgrade(&merged).unwrap().message, "Edited");
+
+        // Rewriting from a Post is what the policy rules out: it would
+        // lose them
+        let rewritten = serde_json::to_vec(&Post::upgrade(&merged).unwrap()).unwrap();
+        let value: Value = serde_json::from_slice(&rewritten).unwrap();
+        assert!(value.get("tags").is_none());
+    }
+
+    #[test]
+    fn bumps_and_edits_keep_unknown_fields() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.insert("thread", future_thread().to_string().as_bytes()).unwrap();
+
+        crate::bump_thread(&db, "thread", 200);
+        crate::edits::edit(&db, "thread", "Edited", "admin", None).unwrap();
+
+        let raw = db.get("thread").unwrap().unwrap();
+        assert_future_fields(&raw);
+        let post = Post::upgrade(&raw).unwrap();
+        assert_eq!((post.timestamp, post.message.as_str()), (200, "Edited"));
+    }
+
+    #[test]
+    fn records_that_arent_posts_are_refused() {
+        assert!(matches!(Post::upgrade(b"[1, 2]"), Err(UpgradeError::NotAnObject)));
+        assert!(matches!(Post::upgrade(b"{\"id\":"), Err(UpgradeError::Corrupt(_))));
+        assert!(matches!(merge_fields(b"\"text\"", |_| {}), Err(UpgradeError::NotAnObject)));
+    }
+}
@@ -0,0 +1,221 @@
+// `seed`: fills an empty database with made-up threads and replies, for
+// trying out paging, pruning and performance with realistic amounts of data.
+//
+//   your_project_name seed [--threads 500] [--replies-per-thread 0..200]
+//                          [--with-media 0.3] [--days 30] [--force]
+//
+// Posts go through store_post like real ones, so numbering, bumps, every
+// index and the counters come out as if people had posted them. Threads
+// start at random times over the last `--days` days and their replies
+// follow in order up to now. `--with-media` is the fraction of posts that
+// get a solid-colour PNG in the upload directory. Refuses to add to a
+// database that already has posts unless given --force. Like verify-files
+// this runs with the server stopped.
+
+use image::{ImageBuffer, ImageOutputFormat, Rgb};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+use std::time::{Instant, SystemTime};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::settings::SettingsCache;
+use crate::startup::ServerFlags;
+use crate::upload::{self, MediaKind};
+use crate::changes::ReplyRefused;
+use crate::{schema, store_post, Post};
+
+const WORDS: &[&str] = &[
+    "lorem", "ipsum", "dolor", "sit", "amet", "consectetur", "adipiscing", "elit", "sed", "do", "eiusmod", "tempor",
+    "incididunt", "ut", "labore", "et", "dolore", "magna", "aliqua", "enim", "ad", "minim", "veniam", "quis",
+    "nostrud", "exercitation", "ullamco", "laboris", "nisi", "aliquip", "ex", "ea", "commodo", "consequat", "duis",
+    "aute", "irure", "in", "reprehenderit", "voluptate", "velit", "esse", "cillum", "fugiat", "nulla", "pariatur",
+];
+const MAX_TITLE_CHARS: usize = 15;
+const IMAGE_SIDE: u32 = 64;
+
+struct Options {
+    threads: usize,
+    min_replies: usize,
+    max_replies: usize,
+    media: f64,
+    days: u64,
+    force: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Options, String> {
+        let value = |flag: &str| args.iter().position(|arg| arg == flag).and_then(|i| args.get(i + 1));
+        let number = |flag: &str, default: &str| -> Result<f64, String> {
+            let raw = value(flag).map(String::as_str).unwrap_or(default);
+            raw.parse().map_err(|_| format!("{} needs a number, got {:?}", flag, raw))
+        };
+        let replies = value("--replies-per-thread").map(String::as_str).unwrap_or("0..20");
+        let (min_replies, max_replies) = match replies.split_once("..") {
+            Some((min, max)) => (min.parse(), max.parse()),
+            None => (replies.parse(), replies.parse()),
+        };
+        let (min_replies, max_replies) = match (min_replies, max_replies) {
+            (Ok(min), Ok(max)) if min <= max => (min, max),
+            _ => return Err(format!("--replies-per-thread needs MIN..MAX, got {:?}", replies)),
+        };
+        let media = number("--with-media", "0")?;
+        if !(0.0..=1.0).contains(&media) {
+            return Err("--with-media is a fraction between 0 and 1".to_string());
+        }
+        Ok(Options {
+            threads: number("--threads", "100")? as usize,
+            min_replies,
+            max_replies,
+            media,
+            days: (number("--days", "30")? as u64).max(1),
+            force: args.iter().any(|arg| arg == "--force"),
+        })
+    }
+}
+
+#[derive(Default)]
+struct Totals {
+    threads: usize,
+    replies: usize,
+    files: usize,
+}
+
+pub fn run(args: &[String]) -> std::io::Result<()> {
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let config = Config::from_env();
+    let db = crate::open_db(&config, &ServerFlags::default());
+    if !db.is_empty() && !options.force {
+        eprintln!("the database already has posts; pass --force to add to them");
+        std::process::exit(1);
+    }
+    std::fs::create_dir_all(&config.upload_dir)?;
+    let settings = SettingsCache::default().get(&db);
+
+    let started = Instant::now();
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    let earliest = now - options.days * 24 * 60 * 60;
+    let mut rng = rand::thread_rng();
+    // A pool of made-up posters, so threads have a mix of regulars and
+    // one-off visitors
+    let posters: Vec<String> = (0..(options.threads * 2).max(10)).map(|_| random_hash(&mut rng)).collect();
+    let mut totals = Totals::default();
+
+    for _ in 0..options.threads {
+        let created = rng.gen_range(earliest..=now);
+        let thread = new_post(&config, &options, &mut rng, &posters, None, created, &mut totals)?;
+        let thread = stored(store_post(&db, &settings, &thread, false))?;
+        totals.threads += 1;
+
+        let count = rng.gen_range(options.min_replies..=options.max_replies);
+        let mut times: Vec<u64> = (0..count).map(|_| rng.gen_range(created..=now)).collect();
+        times.sort_unstable();
+        for timestamp in times {
+            let reply = new_post(&config, &options, &mut rng, &posters, Some(&thread.id), timestamp, &mut totals)?;
+            stored(store_post(&db, &settings, &reply, false))?;
+            totals.replies += 1;
+        }
+    }
+    db.flush()?;
+
+    println!(
+        "seeded {} threads, {} replies and {} files in {:.1}s; database is now {} on disk",
+        totals.threads,
+        totals.replies,
+        totals.files,
+        started.elapsed().as_secs_f64(),
+        config.human_size(db.size_on_disk()?),
+    );
+    Ok(())
+}
+
+fn new_post(
+    config: &Config,
+    options: &Options,
+    rng: &mut impl Rng,
+    posters: &[String],
+    parent_id: Option<&str>,
+    timestamp: u64,
+    totals: &mut Totals,
+) -> std::io::Result<Post> {
+    let mut title = sentence(rng, 1..3);
+    title.truncate(MAX_TITLE_CHARS);
+    let paragraphs: Vec<String> = (0..rng.gen_range(1..4)).map(|_| sentence(rng, 5..40)).collect();
+    let mut post = Post {
+        schema: schema::POST_SCHEMA,
+        id: Uuid::new_v4().to_string(),
+        parent_id: parent_id.map(str::to_string),
+        title: title.trim().to_string(),
+        name: None,
+        tripcode: None,
+        capcode: None,
+        message: paragraphs.join("\n\n"),
+        file: None,
+        timestamp,
+        ip_hash: posters.choose(rng).cloned(),
+        upload_error: None,
+        file_hash: None,
+        original_name: None,
+        file_size: None,
+        media_kind: None,
+        // Assigned by store_record
+        reply_number: None,
+        removal_reason: None,
+        locks_at: None,
+        width: None,
+        height: None,
+        converted_from: None,
+        slow_mode_secs: None,
+        thumbnail: None,
+        media_pruned: false,
+        announcement: false,
+    };
+    if rng.gen_bool(options.media) {
+        let png = placeholder_png(rng);
+        let dir = upload::dated_dir(timestamp);
+        std::fs::create_dir_all(config.upload_path(&dir))?;
+        let file_name = format!("{}/{}.png", dir, Uuid::new_v4());
+        std::fs::write(config.upload_path(&file_name), &png)?;
+        post.file = Some(file_name);
+        post.file_hash = Some(upload::hex(&Sha256::digest(&png)));
+        post.original_name = Some(format!("{}.png", sentence(rng, 1..2)));
+        post.file_size = Some(png.len() as u64);
+        post.media_kind = Some(MediaKind::Image);
+        post.width = Some(IMAGE_SIDE);
+        post.height = Some(IMAGE_SIDE);
+        totals.files += 1;
+    }
+    Ok(post)
+}
+
+// Seeded threads are open, so this only fails if something else deletes
+// them while seeding
+fn stored(result: Result<Post, ReplyRefused>) -> std::io::Result<Post> {
+    result.map_err(|refused| std::io::Error::other(format!("a seeded reply was refused: {:?}", refused)))
+}
+
+fn sentence(rng: &mut impl Rng, words: std::ops::Range<usize>) -> String {
+    let count = rng.gen_range(words);
+    (0..count).map(|_| *WORDS.choose(rng).unwrap()).collect::<Vec<_>>().join(" ")
+}
+
+// Shaped like poster::ip_hash: 16 hex digits
+fn random_hash(rng: &mut impl Rng) -> String {
+    upload::hex(&rng.gen::<[u8; 8]>())
+}
+
+fn placeholder_png(rng: &mut impl Rng) -> Vec<u8> {
+    let colour: Rgb<u8> = Rgb(rng.gen());
+    let image = ImageBuffer::from_pixel(IMAGE_SIDE, IMAGE_SIDE, colour);
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageOutputFormat::Png).unwrap();
+    png.into_inner()
+}
@@ -0,0 +1,90 @@
+// When this visitor last viewed each thread, kept in their browser rather
+// than on the server. The `seen` cookie holds the MAX_THREADS threads
+// viewed most recently, newest first, as
+//
+//   "{first 16 hex digits of the id}.{timestamp},..."
+//
+// which stays around 1.4 KB when full, well under the 4 KB browsers allow.
+// The index shows "N new" on threads with replies since, and the thread
+// page marks where the new replies start. Threads never viewed get neither.
+
+use actix_web::cookie::{time::Duration as CookieDuration, Cookie};
+use actix_web::HttpRequest;
+use sled::Db;
+
+use crate::config::Config;
+use crate::indexes;
+
+const COOKIE: &str = "seen";
+const MAX_THREADS: usize = 50;
+const ID_DIGITS: usize = 16;
+
+#[derive(Default)]
+pub struct LastSeen {
+    // Most recently viewed first
+    entries: Vec<(String, u64)>,
+}
+
+fn short_id(thread_id: &str) -> String {
+    thread_id.chars().filter(|c| c.is_ascii_hexdigit()).take(ID_DIGITS).collect::<String>().to_ascii_lowercase()
+}
+
+impl LastSeen {
+    // Entries that don't parse are dropped, so a mangled cookie only
+    // forgets threads
+    pub fn from_request(req: &HttpRequest) -> LastSeen {
+        let entries = req
+            .cookie(COOKIE)
+            .map(|cookie| {
+                cookie
+                    .value()
+                    .split(',')
+                    .filter_map(|entry| {
+                        let (id, timestamp) = entry.split_once('.')?;
+                        let valid = id.len() == ID_DIGITS && id.chars().all(|c| c.is_ascii_hexdigit());
+                        Some((id.to_string(), timestamp.parse().ok()?)).filter(|_| valid)
+                    })
+                    .take(MAX_THREADS)
+                    .collect()
+            })
+            .unwrap_or_default();
+        LastSeen { entries }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, thread_id: &str) -> Option<u64> {
+        let id = short_id(thread_id);
+        self.entries.iter().find(|(seen, _)| *seen == id).map(|(_, timestamp)| *timestamp)
+    }
+
+    // Replies to `thread_id` since it was last viewed, None if it never was
+    pub fn new_replies(&self, db: &Db, thread_id: &str) -> Option<usize> {
+        let seen = self.get(thread_id)?;
+        let from = indexes::reply_key(thread_id, seen + 1, "");
+        // Just past the thread's keys: '0' sorts right after '/'
+        let to = format!("{}0", thread_id);
+        Some(db.open_tree("replies").unwrap().range(from..to).count())
+    }
+
+    // The cookie after viewing `thread_id` with everything up to `timestamp`
+    // on the page
+    pub fn viewed(mut self, config: &Config, thread_id: &str, timestamp: u64) -> Cookie<'static> {
+        let id = short_id(thread_id);
+        self.entries.retain(|(seen, _)| *seen != id);
+        self.entries.insert(0, (id, timestamp));
+        self.entries.truncate(MAX_THREADS);
+        let value = self
+            .entries
+            .iter()
+            .map(|(id, timestamp)| format!("{}.{}", id, timestamp))
+            .collect::<Vec<_>>()
+            .join(",");
+        Cookie::build(COOKIE, value)
+            .path(config.index_url())
+            .max_age(CookieDuration::days(365))
+            .finish()
+    }
+}
@@ -0,0 +1,153 @@
+// Board settings an admin can change at runtime. They live in the
+// `board_settings` tree and are cached in memory; every write goes through
+// SettingsCache::save, which replaces the cached copy.
+
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use std::sync::{Arc, RwLock};
+
+const SETTINGS_KEY: &str = "board";
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct BoardSettings {
+    pub name: String,
+    pub description: String,
+    pub posts_per_page: usize,
+    // Replies past this many no longer bump the thread, 0 for no limit
+    pub bump_limit: usize,
+    pub require_file_for_threads: bool,
+    // Applied to messages as they're posted, in order
+    pub wordfilters: Vec<Wordfilter>,
+    // Posts matching these are flagged for a look, see watchlist.rs
+    pub watch_patterns: Vec<WatchPattern>,
+    // Locked boards stay readable but reject every post
+    pub locked: bool,
+    // Adult boards ask visitors to confirm their age first, see age_gate.rs
+    pub nsfw: bool,
+}
+
+impl Default for BoardSettings {
+    fn default() -> BoardSettings {
+        BoardSettings {
+            name: "Main Board".to_string(),
+            description: String::new(),
+            posts_per_page: 30,
+            bump_limit: 300,
+            require_file_for_threads: false,
+            wordfilters: Vec::new(),
+            watch_patterns: Vec::new(),
+            locked: false,
+            nsfw: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Wordfilter {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WatchPattern {
+    pub name: String,
+    pub pattern: String,
+}
+
+impl BoardSettings {
+    pub fn apply_wordfilters(&self, message: &str) -> String {
+        self.wordfilters
+            .iter()
+            .fold(message.to_string(), |text, filter| text.replace(&filter.from, &filter.to))
+    }
+
+    // One "from=to" per line, the way the admin form shows them
+    pub fn wordfilter_lines(&self) -> String {
+        self.wordfilters
+            .iter()
+            .map(|filter| format!("{}={}", filter.from, filter.to))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // One "name=regex" per line, see watchlist::parse_patterns
+    pub fn watch_pattern_lines(&self) -> String {
+        self.watch_patterns
+            .iter()
+            .map(|pattern| format!("{}={}", pattern.name, pattern.pattern))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn bumps(&self, reply_count: usize) -> bool {
+        self.bump_limit == 0 || reply_count <= self.bump_limit
+    }
+}
+
+pub fn parse_wordfilters(lines: &str) -> Result<Vec<Wordfilter>, String> {
+    lines
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| match line.split_once('=') {
+            Some((from, to)) if !from.is_empty() => Ok(Wordfilter {
+                from: from.to_string(),
+                to: to.to_string(),
+            }),
+            _ => Err(format!("Wordfilter \"{}\" should look like from=to.", line)),
+        })
+        .collect()
+}
+
+pub fn validate(settings: &BoardSettings) -> Result<(), String> {
+    let name_len = settings.name.trim().chars().count();
+    if name_len == 0 || name_len > 50 {
+        return Err("The board name must be 1 to 50 characters.".to_string());
+    }
+    if settings.description.chars().count() > 500 {
+        return Err("The description can be at most 500 characters.".to_string());
+    }
+    if !(1..=200).contains(&settings.posts_per_page) {
+        return Err("Posts per page must be between 1 and 200.".to_string());
+    }
+    if settings.bump_limit > 10_000 {
+        return Err("The bump limit can be at most 10000.".to_string());
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct SettingsCache {
+    current: RwLock<Option<Arc<BoardSettings>>>,
+}
+
+impl SettingsCache {
+    pub fn get(&self, db: &Db) -> Arc<BoardSettings> {
+        if let Some(settings) = self.current.read().unwrap().as_ref() {
+            return settings.clone();
+        }
+        let settings = Arc::new(load(db));
+        *self.current.write().unwrap() = Some(settings.clone());
+        settings
+    }
+
+    // Holds the lock across the write so the cache can't end up with a
+    // different copy than the tree.
+    pub fn save(&self, db: &Db, settings: BoardSettings) {
+        let mut current = self.current.write().unwrap();
+        let tree = db.open_tree("board_settings").unwrap();
+        tree.insert(SETTINGS_KEY, serde_json::to_vec(&settings).unwrap()).unwrap();
+        tree.flush().unwrap();
+        *current = Some(Arc::new(settings));
+    }
+}
+
+fn load(db: &Db) -> BoardSettings {
+    db.open_tree("board_settings")
+        .unwrap()
+        .get(SETTINGS_KEY)
+        .unwrap()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
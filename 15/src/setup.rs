@@ -0,0 +1,71 @@
+// The setup checklist on a new board's admin panel. A database created
+// from scratch gets a `setup_pending` marker in the meta tree; while it's
+// there, /admin/posts lists the few things worth doing before the board
+// goes public, ticked off as they're done. Dismissing it removes the
+// marker for good. Boards from before this never see it.
+
+use actix_web::{web, HttpResponse};
+use sled::Db;
+
+use crate::admin::Admin;
+use crate::config::Config;
+use crate::redirect;
+use crate::settings::BoardSettings;
+
+const MARKER: &str = "setup_pending";
+
+pub struct Step {
+    pub title: &'static str,
+    pub detail: String,
+    pub done: bool,
+    // Where it's done, when that's a page rather than the environment
+    pub link: Option<String>,
+}
+
+// Called once the database is open. sled says whether it found existing
+// data, which is the only time a board can be told apart from a new one.
+pub fn mark_if_new(db: &Db) {
+    if !db.was_recovered() {
+        db.open_tree("meta").unwrap().insert(MARKER, &[]).unwrap();
+    }
+}
+
+pub fn pending(db: &Db) -> bool {
+    db.open_tree("meta").unwrap().contains_key(MARKER).unwrap()
+}
+
+// Empty once dismissed
+pub fn checklist(db: &Db, config: &Config, settings: &BoardSettings) -> Vec<Step> {
+    if !pending(db) {
+        return Vec::new();
+    }
+    vec![
+        Step {
+            title: "Set an admin password",
+            detail: "ADMIN_PASSWORD, which you logged in with.".to_string(),
+            done: config.admin_password.is_some(),
+            link: None,
+        },
+        Step {
+            title: "Set upload limits",
+            detail: format!(
+                "Files up to {} each, of: {}. MAX_UPLOAD_BYTES and ALLOWED_EXTENSIONS change these.",
+                config.human_size(config.max_upload_bytes),
+                config.allowed_extensions.join(", ")
+            ),
+            done: config.upload_limits_set,
+            link: None,
+        },
+        Step {
+            title: "Write the rules",
+            detail: "The board description is shown under its name on the index; put the rules there.".to_string(),
+            done: !settings.description.trim().is_empty(),
+            link: Some(config.url_for("/admin/settings")),
+        },
+    ]
+}
+
+pub async fn dismiss(db: web::Data<Db>, config: web::Data<Config>, _admin: Admin) -> HttpResponse {
+    db.open_tree("meta").unwrap().remove(MARKER).unwrap();
+    redirect::see_other(&config.url_for("/admin/posts")).finish()
+}
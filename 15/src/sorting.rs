@@ -0,0 +1,190 @@
+// Orders for the thread listing on the index and in /api/threads, picked
+// with `?sort=`:
+//
+//   bump      most recently bumped first (the default), from `bumps`
+//   creation  newest threads first, from `creations`
+//   replies   most replies first, from the `reply_counts` counters
+//   activity  most replies in the last day first, from the `activity` buckets
+//
+// Every order is a list of keys walked from the largest down, so an API
+// cursor is just the last key handed out. Bump and creation orders are the
+// index trees themselves. Replies and activity are ranked in a snapshot
+// that is rebuilt every few minutes, with keys
+// "{count:020}/{bump time:020}/{thread id}" so ties go to the thread
+// bumped last. Those two orders can lag a little behind new replies.
+
+use sled::{Db, Tree};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{activity, archive, counters, indexes, load_post, Post};
+
+const SNAPSHOT_TTL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ThreadSort {
+    Bump,
+    Creation,
+    Replies,
+    Activity,
+}
+
+impl ThreadSort {
+    pub const ALL: [ThreadSort; 4] = [ThreadSort::Bump, ThreadSort::Creation, ThreadSort::Replies, ThreadSort::Activity];
+
+    // Missing or unknown values are the bump order
+    pub fn parse(raw: Option<&str>) -> ThreadSort {
+        match raw.unwrap_or_default().trim() {
+            "creation" => ThreadSort::Creation,
+            "replies" => ThreadSort::Replies,
+            "activity" => ThreadSort::Activity,
+            _ => ThreadSort::Bump,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ThreadSort::Bump => "bump",
+            ThreadSort::Creation => "creation",
+            ThreadSort::Replies => "replies",
+            ThreadSort::Activity => "activity",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ThreadSort::Bump => "Last bump",
+            ThreadSort::Creation => "Newest",
+            ThreadSort::Replies => "Most replies",
+            ThreadSort::Activity => "Most active",
+        }
+    }
+
+    fn tree(self, db: &Db) -> Option<Tree> {
+        match self {
+            ThreadSort::Bump => Some(db.open_tree("bumps").unwrap()),
+            ThreadSort::Creation => Some(db.open_tree("creations").unwrap()),
+            ThreadSort::Replies | ThreadSort::Activity => None,
+        }
+    }
+
+    // Whether `key` is shaped like this order's keys, for checking cursors
+    pub fn is_key(self, key: &str) -> bool {
+        match self {
+            ThreadSort::Bump | ThreadSort::Creation => indexes::parse_bump_key(key).is_some(),
+            ThreadSort::Replies | ThreadSort::Activity => match key.split_once('/') {
+                Some((count, rest)) => count.len() == 20 && count.parse::<u64>().is_ok() && indexes::parse_bump_key(rest).is_some(),
+                None => false,
+            },
+        }
+    }
+}
+
+// When it was built, and the keys largest first
+type Snapshot = (Instant, Arc<Vec<String>>);
+
+#[derive(Default)]
+pub struct Rankings {
+    snapshots: Mutex<HashMap<ThreadSort, Snapshot>>,
+}
+
+impl Rankings {
+    // Keys for replies or activity order, largest first
+    fn snapshot(&self, db: &Db, sort: ThreadSort) -> Arc<Vec<String>> {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        if let Some((built, keys)) = snapshots.get(&sort) {
+            if built.elapsed() < SNAPSHOT_TTL {
+                return keys.clone();
+            }
+        }
+        let keys = Arc::new(rank(db, sort));
+        snapshots.insert(sort, (Instant::now(), keys.clone()));
+        keys
+    }
+}
+
+// Reads only index keys and counters, never the posts themselves.
+fn rank(db: &Db, sort: ThreadSort) -> Vec<String> {
+    let counts: HashMap<String, u64> = match sort {
+        ThreadSort::Replies => db
+            .open_tree("reply_counts")
+            .unwrap()
+            .iter()
+            .filter_map(|entry| {
+                let (thread_id, count) = entry.unwrap();
+                Some((String::from_utf8(thread_id.to_vec()).ok()?, counters::decode(Some(&count))))
+            })
+            .collect(),
+        _ => {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            activity::recent_replies(db, now)
+        }
+    };
+    // A racing bump can leave an old key behind; the newest one wins
+    let mut bumps: HashMap<String, u64> = HashMap::new();
+    for key in db.open_tree("bumps").unwrap().iter().keys() {
+        let key = key.unwrap();
+        if let Some((timestamp, thread_id)) = indexes::parse_bump_key(std::str::from_utf8(&key).unwrap_or_default()) {
+            let bump = bumps.entry(thread_id.to_string()).or_insert(timestamp);
+            *bump = (*bump).max(timestamp);
+        }
+    }
+    let mut keys: Vec<String> = bumps
+        .into_iter()
+        .map(|(thread_id, bump)| {
+            let count = counts.get(&thread_id).copied().unwrap_or(0);
+            format!("{:020}/{}", count, indexes::bump_key(bump, &thread_id))
+        })
+        .collect();
+    keys.sort_unstable_by(|a, b| b.cmp(a));
+    keys
+}
+
+// Threads in `sort` order, starting after the key `after`. Keys are
+// checked lazily, so a page only loads the threads it skips and shows.
+// Entries for deleted or archived threads, and bumps left behind by a
+// race, are removed from the index trees as they're found.
+pub fn threads<'a>(
+    db: &'a Db,
+    rankings: &Rankings,
+    sort: ThreadSort,
+    after: Option<&str>,
+) -> impl Iterator<Item = (String, Post)> + 'a {
+    let keys: Box<dyn Iterator<Item = String>> = match sort.tree(db) {
+        Some(tree) => {
+            let entries = match after {
+                Some(after) => tree.range(..after),
+                None => tree.iter(),
+            };
+            Box::new(entries.keys().rev().map(|key| String::from_utf8_lossy(&key.unwrap()).into_owned()))
+        }
+        None => {
+            let snapshot = rankings.snapshot(db, sort);
+            let start = match after {
+                Some(after) => snapshot.partition_point(|key| key.as_str() >= after),
+                None => 0,
+            };
+            Box::new((start..snapshot.len()).map(move |i| snapshot[i].clone()))
+        }
+    };
+    let tree = sort.tree(db);
+    keys.filter_map(move |key| {
+        let thread_id = key.rsplit('/').next()?;
+        let thread = load_post(db, thread_id);
+        let current = match (&thread, sort) {
+            (Some(thread), ThreadSort::Bump) => {
+                indexes::parse_bump_key(&key).map(|(timestamp, _)| timestamp) == Some(thread.timestamp) && !archive::is_archived(db, &thread.id)
+            }
+            (Some(thread), _) => !archive::is_archived(db, &thread.id),
+            (None, _) => false,
+        };
+        if !current {
+            if let Some(tree) = &tree {
+                tree.remove(&key).unwrap();
+            }
+            return None;
+        }
+        Some((key, thread.unwrap()))
+    })
+}
@@ -0,0 +1,295 @@
+// Opening the database, and saying why not when it can't be. sled's own
+// errors don't name the cause plainly, so each kind of failure gets a
+// message with what to do about it and its own exit code for supervisors:
+//
+//   75  another process holds the lock (named from /proc/locks on Linux)
+//   77  the directory or its files can't be read and written
+//   65  the data is damaged or from an incompatible sled version
+//   74  any other I/O error
+//   70  anything else sled reports
+//
+// `--wait-for-lock <secs>` keeps retrying while the lock is held, backing
+// off up to MAX_BACKOFF, for restarts where the old process is still on
+// its way out.
+
+use sled::Db;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const LOCK_HELD: i32 = 75;
+const NO_PERMISSION: i32 = 77;
+const BAD_DATA: i32 = 65;
+const IO_ERROR: i32 = 74;
+const OTHER: i32 = 70;
+
+const FIRST_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+// Flags for running the server, rather than a subcommand
+#[derive(Default)]
+pub struct ServerFlags {
+    // `--db memory`: a throwaway database, for demos
+    pub in_memory: bool,
+    pub wait_for_lock: Option<Duration>,
+}
+
+impl ServerFlags {
+    pub fn parse(args: &[String]) -> Result<ServerFlags, String> {
+        let mut flags = ServerFlags::default();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args.next().map(String::as_str);
+            match (flag.as_str(), value) {
+                ("--db", Some("memory")) => flags.in_memory = true,
+                ("--wait-for-lock", Some(secs)) => {
+                    let secs: u64 = secs.parse().map_err(|_| format!("--wait-for-lock needs a number of seconds, got {:?}", secs))?;
+                    flags.wait_for_lock = Some(Duration::from_secs(secs));
+                }
+                ("--db", _) => return Err("usage: --db memory".to_string()),
+                ("--wait-for-lock", None) => return Err("usage: --wait-for-lock <secs>".to_string()),
+                _ => return Err(format!("unknown command: {}", flag)),
+            }
+        }
+        Ok(flags)
+    }
+}
+
+enum Failure {
+    LockHeld,
+    Permission,
+    BadData,
+    Io,
+    Other,
+}
+
+// sled reports a held lock as an Other I/O error carrying this text
+fn classify(e: &sled::Error) -> Failure {
+    match e {
+        sled::Error::Io(io) if io.to_string().contains("could not acquire lock") => Failure::LockHeld,
+        sled::Error::Io(io) if matches!(io.kind(), ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem) => Failure::Permission,
+        sled::Error::Io(_) => Failure::Io,
+        sled::Error::Corruption { .. } | sled::Error::Unsupported(_) => Failure::BadData,
+        _ => Failure::Other,
+    }
+}
+
+fn absolute(path: &str) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| std::env::current_dir().unwrap_or_default().join(path))
+}
+
+// Who holds the lock on `file`, as "pid 123 (command)". Linux lists file
+// locks in /proc/locks by inode; elsewhere this is None.
+#[cfg(unix)]
+fn lock_holder(file: &Path) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+    let inode = std::fs::metadata(file).ok()?.ino();
+    let locks = std::fs::read_to_string("/proc/locks").ok()?;
+    // "1: FLOCK  ADVISORY  WRITE 1234 fd:01:5678 0 EOF"; waiting locks
+    // have a "->" after the number
+    let pid = locks.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (pid, file_id) = (fields.get(4)?, fields.get(5)?);
+        let holds = fields.get(1) != Some(&"->") && file_id.rsplit(':').next()?.parse::<u64>().ok()? == inode;
+        holds.then(|| pid.to_string())
+    })?;
+    let command = std::fs::read(format!("/proc/{}/cmdline", pid))
+        .ok()
+        .map(|raw| String::from_utf8_lossy(&raw).split('\0').filter(|part| !part.is_empty()).collect::<Vec<_>>().join(" "))
+        .filter(|command| !command.is_empty());
+    Some(match command {
+        Some(command) => format!("pid {} ({})", pid, command),
+        None => format!("pid {}", pid),
+    })
+}
+
+#[cfg(not(unix))]
+fn lock_holder(_file: &Path) -> Option<String> {
+    None
+}
+
+// Why the database couldn't be opened: the exit code for its kind of
+// failure, and what to print
+#[derive(Debug)]
+pub struct Diagnosis {
+    pub code: i32,
+    pub lines: Vec<String>,
+}
+
+fn diagnose(path: &str, e: &sled::Error) -> Diagnosis {
+    let at = absolute(path);
+    let (code, lines) = match classify(e) {
+        Failure::LockHeld => {
+            let holder = lock_holder(&at.join("db")).map_or_else(String::new, |holder| format!(" by {}", holder));
+            let lines = vec![
+                format!("the database at {} is in use{}.", at.display(), holder),
+                "Stop the other instance first, or pass --wait-for-lock <secs> to wait for it to exit.".to_string(),
+            ];
+            (LOCK_HELD, lines)
+        }
+        Failure::Permission => {
+            let lines = vec![
+                format!("can't open the database at {}: {}.", at.display(), e),
+                "The board needs to read and write that directory and every file in it, on a writable filesystem.".to_string(),
+            ];
+            (NO_PERMISSION, lines)
+        }
+        Failure::BadData => {
+            let lines = vec![
+                format!("the database at {} can't be read: {}.", at.display(), e),
+                "It's damaged or was written by an incompatible sled version. Move it aside and restore a backup, such as one taken from /admin/export/stream.".to_string(),
+            ];
+            (BAD_DATA, lines)
+        }
+        Failure::Io => (IO_ERROR, vec![format!("opening the database at {} failed: {}", at.display(), e)]),
+        Failure::Other => (OTHER, vec![format!("opening the database at {} failed: {}", at.display(), e)]),
+    };
+    Diagnosis { code, lines }
+}
+
+// UPLOAD_DIR as the server uses it: created if it's missing and made
+// absolute, so every path under it is built the same way whatever the
+// working directory or the separators it was given with
+pub fn prepare_upload_dir(dir: &Path) -> Result<PathBuf, String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("can't create the upload directory {}: {}", dir.display(), e))?;
+    let dir = std::fs::canonicalize(dir).map_err(|e| format!("can't resolve the upload directory {}: {}", dir.display(), e))?;
+    if !dir.is_dir() {
+        return Err(format!("the upload directory {} isn't a directory", dir.display()));
+    }
+    Ok(dir)
+}
+
+// Opens the sled database at `path`, waiting up to `wait_for_lock` for
+// another process to let go of it
+pub fn open(path: &str, wait_for_lock: Option<Duration>) -> Result<Db, Diagnosis> {
+    let started = Instant::now();
+    let mut backoff = FIRST_BACKOFF;
+    let mut first = true;
+    loop {
+        let e = match sled::open(path) {
+            Ok(db) => return Ok(db),
+            Err(e) => e,
+        };
+        let waited = started.elapsed();
+        match (classify(&e), wait_for_lock) {
+            (Failure::LockHeld, Some(wait)) if waited < wait => {
+                if std::mem::replace(&mut first, false) {
+                    eprintln!("the database at {} is in use, waiting up to {} s for it", absolute(path).display(), wait.as_secs());
+                }
+                std::thread::sleep(backoff.min(wait - waited));
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            _ => return Err(diagnose(path, &e)),
+        }
+    }
+}
+
+// The same, printing what went wrong and what to do and exiting with its
+// code if it can't
+pub fn open_or_exit(path: &str, wait_for_lock: Option<Duration>) -> Db {
+    open(path, wait_for_lock).unwrap_or_else(|diagnosis| {
+        for line in &diagnosis.lines {
+            eprintln!("{}", line);
+        }
+        std::process::exit(diagnosis.code)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use tempfile::TempDir;
+
+    fn db_path(dir: &TempDir) -> String {
+        dir.path().join("db").to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn a_held_lock_is_named_with_its_holder() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = db_path(&dir);
+        let _held = sled::open(&path).unwrap();
+        let diagnosis = open(&path, None).unwrap_err();
+        assert_eq!(diagnosis.code, LOCK_HELD);
+        let at = std::fs::canonicalize(&path).unwrap();
+        assert!(diagnosis.lines[0].starts_with(&format!("the database at {} is in use", at.display())), "{:?}", diagnosis);
+        if cfg!(target_os = "linux") {
+            assert!(diagnosis.lines[0].contains(&format!(" by pid {} (", std::process::id())), "{:?}", diagnosis);
+        }
+        assert!(diagnosis.lines[1].contains("--wait-for-lock"));
+
+        // Waiting only helps if it's let go of in time
+        let started = Instant::now();
+        let diagnosis = open(&path, Some(Duration::from_secs(1))).unwrap_err();
+        assert_eq!(diagnosis.code, LOCK_HELD);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn waiting_for_the_lock_opens_once_it_is_let_go_of() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = db_path(&dir);
+        let held = sled::open(&path).unwrap();
+        held.insert("kept", "yes").unwrap();
+        held.flush().unwrap();
+        let (opening, waiting) = mpsc::channel();
+        let holder = std::thread::spawn(move || {
+            waiting.recv().unwrap();
+            std::thread::sleep(Duration::from_millis(300));
+            drop(held);
+        });
+        opening.send(()).unwrap();
+        let db = open(&path, Some(Duration::from_secs(30))).unwrap();
+        assert_eq!(db.get("kept").unwrap().as_deref(), Some(&b"yes"[..]));
+        holder.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn an_unwritable_directory_says_so() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = db_path(&dir);
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o500)).unwrap();
+        // Root can write there anyway, so the real case is only tried
+        // where the permissions hold
+        if std::fs::write(dir.path().join("probe"), b"").is_err() {
+            let diagnosis = open(&path, Some(Duration::from_secs(5))).unwrap_err();
+            assert_eq!(diagnosis.code, NO_PERMISSION, "{:?}", diagnosis);
+            assert!(diagnosis.lines[0].contains(&dir.path().canonicalize().unwrap().join("db").display().to_string()), "{:?}", diagnosis);
+        }
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o700)).unwrap();
+
+        for kind in [ErrorKind::PermissionDenied, ErrorKind::ReadOnlyFilesystem] {
+            let diagnosis = diagnose(&path, &sled::Error::Io(std::io::Error::from(kind)));
+            assert_eq!(diagnosis.code, NO_PERMISSION, "{:?}", kind);
+            assert!(diagnosis.lines[1].contains("read and write that directory"));
+        }
+    }
+
+    #[test]
+    fn other_failures_have_codes_of_their_own() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"not a directory").unwrap();
+        let under_a_file = file.join("db");
+        assert_eq!(open(under_a_file.to_str().unwrap(), None).unwrap_err().code, IO_ERROR);
+
+        let path = db_path(&dir);
+        assert_eq!(diagnose(&path, &sled::Error::Unsupported("version".to_string())).code, BAD_DATA);
+        assert_eq!(diagnose(&path, &sled::Error::ReportableBug("bug".to_string())).code, OTHER);
+        let codes = [LOCK_HELD, NO_PERMISSION, BAD_DATA, IO_ERROR, OTHER];
+        assert!(codes.iter().all(|code| codes.iter().filter(|other| *other == code).count() == 1));
+    }
+
+    #[test]
+    fn flags_are_read_in_pairs() {
+        let parse = |args: &[&str]| ServerFlags::parse(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>());
+        let flags = parse(&["--wait-for-lock", "30", "--db", "memory"]).unwrap();
+        assert_eq!((flags.in_memory, flags.wait_for_lock), (true, Some(Duration::from_secs(30))));
+        assert_eq!(parse(&["--wait-for-lock"]).err().as_deref(), Some("usage: --wait-for-lock <secs>"));
+        assert!(parse(&["--wait-for-lock", "soon"]).err().unwrap().contains("\"soon\""));
+        assert_eq!(parse(&["serve"]).err().as_deref(), Some("unknown command: serve"));
+    }
+}
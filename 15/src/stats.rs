@@ -0,0 +1,128 @@
+// Board statistics. For now that's a heatmap of posts by weekday and hour
+// (UTC) over the last HEATMAP_DAYS, built from the board-wide hourly
+// buckets in activity.rs. It's a bounded read of HEATMAP_DAYS * 24 keys,
+// cached for CACHE_TTL.
+
+use actix_web::{web, HttpResponse};
+use askama::Template;
+use serde::Serialize;
+use sled::Db;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::activity::{self, HEATMAP_DAYS, HOUR};
+use crate::config::Config;
+use crate::render;
+
+const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+// Posts per weekday (Monday first) and hour of day. A cell is None when
+// none of its hours in the window are after the board's first post, so a
+// young board shows "no data" rather than a misleading zero.
+#[derive(Serialize)]
+pub struct Heatmap {
+    days: u64,
+    timezone: &'static str,
+    heatmap: Vec<Vec<Option<u64>>>,
+    #[serde(skip)]
+    busiest: u64,
+}
+
+impl Heatmap {
+    // Whether the board has had a post yet
+    fn is_empty(&self) -> bool {
+        self.heatmap.iter().flatten().all(Option::is_none)
+    }
+}
+
+impl Heatmap {
+    fn build(db: &Db, now: u64) -> Heatmap {
+        let to = now / HOUR;
+        let from = to + 1 - HEATMAP_DAYS * 24;
+        let counts = activity::post_hours(db, from, to);
+        let since = activity::post_hours_since(db).unwrap_or(u64::MAX);
+
+        let mut heatmap = vec![vec![None; 24]; 7];
+        for hour in from.max(since)..=to {
+            // 1970-01-01 was a Thursday
+            let weekday = ((hour / 24 + 3) % 7) as usize;
+            let cell: &mut Option<u64> = &mut heatmap[weekday][(hour % 24) as usize];
+            *cell = Some(cell.unwrap_or(0) + counts.get(&hour).copied().unwrap_or(0));
+        }
+        let busiest = heatmap.iter().flatten().flatten().copied().max().unwrap_or(0);
+        Heatmap {
+            days: HEATMAP_DAYS,
+            timezone: "UTC",
+            heatmap,
+            busiest,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct StatsCache {
+    heatmap: Mutex<Option<(Instant, Arc<Heatmap>)>>,
+}
+
+impl StatsCache {
+    fn heatmap(&self, db: &Db) -> Arc<Heatmap> {
+        let mut cached = self.heatmap.lock().unwrap();
+        if let Some((built, heatmap)) = cached.as_ref() {
+            if built.elapsed() < CACHE_TTL {
+                return heatmap.clone();
+            }
+        }
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let heatmap = Arc::new(Heatmap::build(db, now));
+        *cached = Some((Instant::now(), heatmap.clone()));
+        heatmap
+    }
+}
+
+#[derive(Template)]
+#[template(path = "stats.html")]
+struct StatsTemplate<'a> {
+    config: &'a Config,
+    heatmap: &'a Heatmap,
+}
+
+impl StatsTemplate<'_> {
+    fn rows(&self) -> Vec<(&'static str, &[Option<u64>])> {
+        WEEKDAYS.iter().copied().zip(self.heatmap.heatmap.iter().map(Vec::as_slice)).collect()
+    }
+
+    // Background for a cell, darker for busier hours
+    fn cell_style(&self, count: &u64) -> String {
+        let intensity = if self.heatmap.busiest == 0 { 0.0 } else { *count as f64 / self.heatmap.busiest as f64 };
+        format!("background-color: rgba(0, 102, 204, {:.2})", 0.05 + intensity * 0.95)
+    }
+}
+
+pub async fn stats(db: web::Data<Db>, config: web::Data<Config>, cache: web::Data<StatsCache>) -> HttpResponse {
+    let heatmap = cache.heatmap(&db);
+    let template = StatsTemplate {
+        config: &config,
+        heatmap: &heatmap,
+    };
+    render::respond(HttpResponse::Ok(), &template, "the stats page")
+}
+
+pub fn check_templates(config: &Config) -> Result<(), String> {
+    let mut heatmap = Heatmap {
+        days: HEATMAP_DAYS,
+        timezone: "UTC",
+        heatmap: vec![vec![None; 24]; 7],
+        busiest: 3,
+    };
+    heatmap.heatmap[0][0] = Some(3);
+    heatmap.heatmap[1][12] = Some(0);
+    render::check(&StatsTemplate {
+        config,
+        heatmap: &heatmap,
+    })
+}
+
+pub async fn stats_json(db: web::Data<Db>, cache: web::Data<StatsCache>) -> HttpResponse {
+    HttpResponse::Ok().json(&*cache.heatmap(&db))
+}
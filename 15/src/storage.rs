@@ -0,0 +1,273 @@
+// Removing posts along with everything that refers to them, and reading
+// every post at once for the jobs that need to, see scan_all_parallel.
+
+use serde::Serialize;
+use sled::Db;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::config::Config;
+use crate::timings::{self, Op};
+use crate::{activity, announcement, archive, backlinks, changes, edits, indexes, load_post, moderation, notify, numbering, posters, schema, upload, watchlist, Post};
+
+const TAKEDOWN_NOTICE: &str = "This file was removed for legal reasons.";
+const LOCK_STRIPES: usize = 64;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNLOCKED: Mutex<()> = Mutex::new(());
+static THREAD_LOCKS: [Mutex<()>; LOCK_STRIPES] = [UNLOCKED; LOCK_STRIPES];
+
+// Held while a reply is stored and while a thread is deleted, so a delete
+// can't run between a reply going in and its counters and indexes being
+// written, and leave those behind. Also taken by event handlers that keep
+// per-thread state, before they check the thread is still there. Threads
+// share LOCK_STRIPES locks by a hash of their id; nothing takes a second
+// lock while holding one.
+pub fn lock_thread(thread_id: &str) -> MutexGuard<'static, ()> {
+    let mut hasher = DefaultHasher::new();
+    thread_id.hash(&mut hasher);
+    THREAD_LOCKS[hasher.finish() as usize % LOCK_STRIPES]
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+// What a deletion actually removed, counted per kind of record.
+#[derive(Serialize, Default)]
+pub struct DeletionReport {
+    pub posts: usize,
+    pub pending: usize,
+    pub files: usize,
+    pub index_entries: usize,
+    pub hash_entries: usize,
+    pub upload_entries: usize,
+    pub flags: usize,
+    // Every post removed, replies before their thread
+    #[serde(skip)]
+    pub removed: Vec<Post>,
+}
+
+fn remove_file(config: &Config, post: &Post, report: &mut DeletionReport) {
+    for file in post.file.iter().chain(&post.thumbnail) {
+        if std::fs::remove_file(config.upload_path(file)).is_ok() {
+            report.files += 1;
+        }
+    }
+}
+
+fn remove_one(db: &Db, config: &Config, post: &Post, keep_files: bool, report: &mut DeletionReport) {
+    let removed = match &post.parent_id {
+        Some(thread_id) => changes::remove_reply(db, thread_id, &post.id),
+        None => db.remove(&post.id).unwrap().is_some(),
+    };
+    if removed {
+        report.posts += 1;
+        report.removed.push(post.clone());
+    }
+    if !keep_files {
+        remove_file(config, post, report);
+    }
+    report.index_entries += indexes::remove(db, post);
+    report.index_entries += indexes::unpost(db, post);
+    report.index_entries += backlinks::forget(db, post);
+    if post.parent_id.is_none() {
+        announcement::forget(db, &post.id);
+    }
+    report.upload_entries += upload::forget(db, post) as usize;
+    report.index_entries += edits::forget(db, &post.id);
+    report.flags += watchlist::forget(db, &post.id);
+    let (hash_entries, flags) = moderation::forget_upload(db, post);
+    report.hash_entries += hash_entries;
+    report.flags += flags;
+}
+
+// Deletes a thread: the first post, every reply, replies still waiting in
+// the approval queue, their files, their entries in the listing, upload and
+// hash indexes and the flagged tree, and the thread's change log. Replies
+// go first so an interrupted delete never leaves replies pointing at a
+// missing thread. Returns an empty report if `thread_id` isn't the first
+// post of a thread.
+pub fn delete_thread(db: &Db, config: &Config, thread_id: &str) -> DeletionReport {
+    remove_thread(db, config, thread_id, false)
+}
+
+// Takes a thread out of this database like delete_thread, but leaves the
+// files of its posts, for a thread that now lives in the archive database,
+// see archive_db.rs. Held replies still go with their files.
+pub fn move_thread_out(db: &Db, config: &Config, thread_id: &str) -> DeletionReport {
+    remove_thread(db, config, thread_id, true)
+}
+
+fn remove_thread(db: &Db, config: &Config, thread_id: &str, keep_files: bool) -> DeletionReport {
+    let _lock = lock_thread(thread_id);
+    let mut report = DeletionReport::default();
+    let op = match load_post(db, thread_id) {
+        Some(post) if post.parent_id.is_none() => post,
+        _ => return report,
+    };
+    report.index_entries += indexes::remove_started(db, &op);
+
+    let replies: Vec<Post> = db
+        .iter()
+        .values()
+        .filter_map(|bytes| Post::upgrade(&bytes.unwrap()).ok())
+        .filter(|reply| reply.parent_id.as_deref() == Some(thread_id))
+        .collect();
+    for reply in &replies {
+        remove_one(db, config, reply, keep_files, &mut report);
+    }
+
+    let pending = db.open_tree("pending").unwrap();
+    let held: Vec<Post> = pending
+        .iter()
+        .values()
+        .filter_map(|bytes| Post::upgrade(&bytes.unwrap()).ok())
+        .filter(|post| post.parent_id.as_deref() == Some(thread_id))
+        .collect();
+    for post in &held {
+        if pending.remove(&post.id).unwrap().is_some() {
+            report.pending += 1;
+        }
+        remove_file(config, post, &mut report);
+    }
+
+    remove_one(db, config, &op, keep_files, &mut report);
+    db.open_tree("reply_counts").unwrap().remove(thread_id).unwrap();
+    db.open_tree("last_reply_times").unwrap().remove(thread_id).unwrap();
+    numbering::forget_thread(db, thread_id);
+    report.index_entries += changes::forget_thread(db, thread_id);
+    report.index_entries += posters::forget_thread(db, thread_id);
+    report.index_entries += backlinks::forget_thread(db, thread_id);
+    report.index_entries += notify::forget(db, thread_id);
+    report.index_entries += archive::forget(db, thread_id);
+    report.index_entries += activity::forget_thread(db, thread_id);
+    db.flush().unwrap();
+    report
+}
+
+fn clear_attachment(raw: &[u8]) -> Option<Vec<u8>> {
+    schema::merge_fields(raw, |fields| {
+        for field in ["file", "file_hash", "original_name", "file_size", "media_kind", "width", "height"] {
+            fields.insert(field.to_string(), serde_json::Value::Null);
+        }
+        fields.insert("removal_reason".to_string(), TAKEDOWN_NOTICE.into());
+    })
+    .ok()
+}
+
+// Removes every copy of a file for a takedown: the files themselves, and
+// the attachment on every post and held post that had one, which keep
+// their text and show a notice instead. The hash is blocked so the file
+// can't be posted again. Returns the ids of the posts changed.
+pub fn take_down(db: &Db, config: &Config, hash: &str) -> (Vec<String>, DeletionReport) {
+    let mut report = DeletionReport::default();
+    let mut changed = Vec::new();
+    moderation::block_hash(db, hash);
+
+    for post in moderation::posts_with_hash(db, hash) {
+        let previous = db
+            .fetch_and_update(&post.id, |old| old.map(|old| clear_attachment(old).unwrap_or_else(|| old.to_vec())))
+            .unwrap();
+        if previous.is_none() {
+            continue;
+        }
+        remove_file(config, &post, &mut report);
+        report.upload_entries += upload::forget(db, &post) as usize;
+        let (hash_entries, flags) = moderation::forget_upload(db, &post);
+        report.hash_entries += hash_entries;
+        report.flags += flags;
+        changed.push(post.id);
+    }
+
+    let pending = db.open_tree("pending").unwrap();
+    for item in pending.iter() {
+        let (id, raw) = item.unwrap();
+        let post = match Post::upgrade(&raw) {
+            Ok(post) if post.file_hash.as_deref() == Some(hash) => post,
+            _ => continue,
+        };
+        if let Some(cleared) = clear_attachment(&raw) {
+            pending.insert(&id, cleared).unwrap();
+            remove_file(config, &post, &mut report);
+            report.pending += 1;
+            changed.push(post.id);
+        }
+    }
+    db.flush().unwrap();
+    (changed, report)
+}
+
+// Deletes a single reply, or the whole thread when `id` is a first post.
+pub fn delete_post(db: &Db, config: &Config, id: &str) -> DeletionReport {
+    let mut report = DeletionReport::default();
+    match load_post(db, id) {
+        Some(post) if post.parent_id.is_none() => return delete_thread(db, config, id),
+        Some(post) => {
+            remove_one(db, config, &post, false, &mut report);
+            crate::uncount_reply(db, post.parent_id.as_deref().unwrap());
+        }
+        None => {}
+    }
+    db.flush().unwrap();
+    report
+}
+
+// Keys that didn't parse as posts, with why, from scan_all_parallel.
+#[derive(Default)]
+pub struct ScanReport {
+    pub posts: usize,
+    pub unreadable: Vec<(String, String)>,
+}
+
+impl ScanReport {
+    // For jobs with nowhere better to put them
+    pub fn log_unreadable(&self, job: &str) {
+        for (key, reason) in &self.unreadable {
+            eprintln!("{}: skipped post {}: {}", job, key, reason);
+        }
+    }
+}
+
+// Parses every post in the main tree on `parallelism` threads and hands
+// each to `consume`, in no particular order and possibly from several
+// threads at once. The keyspace is split by first byte into ranges the
+// threads take in turn, so the work evens out however the ids are spread.
+// Unreadable records are skipped and listed in the report.
+pub fn scan_all_parallel<F>(db: &Db, parallelism: usize, consume: F) -> ScanReport
+where
+    F: Fn(Post) + Sync,
+{
+    let started = timings::start();
+    let next_range = AtomicUsize::new(0);
+    let report = Mutex::new(ScanReport::default());
+    std::thread::scope(|scope| {
+        for _ in 0..parallelism.max(1) {
+            scope.spawn(|| {
+                let mut posts = 0;
+                let mut unreadable = Vec::new();
+                loop {
+                    let first = next_range.fetch_add(1, Ordering::Relaxed);
+                    if first > u8::MAX as usize {
+                        break;
+                    }
+                    for entry in db.scan_prefix([first as u8]) {
+                        let (key, bytes) = entry.unwrap();
+                        match Post::upgrade(&bytes) {
+                            Ok(post) => {
+                                posts += 1;
+                                consume(post);
+                            }
+                            Err(e) => unreadable.push((String::from_utf8_lossy(&key).into_owned(), e.to_string())),
+                        }
+                    }
+                }
+                let mut report = report.lock().unwrap();
+                report.posts += posts;
+                report.unreadable.append(&mut unreadable);
+            });
+        }
+    });
+    timings::record(Op::Scan, "all_posts", started);
+    report.into_inner().unwrap()
+}
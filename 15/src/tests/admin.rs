@@ -0,0 +1,312 @@
+// The admin side, see admin.rs: sessions, with the cookie they ride on,
+// how long they last and the CSRF token their forms have to carry; what
+// a dossier holds; the post list; capcodes; and a post's renderings,
+// checked against a snapshot.
+
+use actix_web::http::header::{CONTENT_TYPE, COOKIE, SET_COOKIE};
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+
+use serde_json::Value;
+
+use super::{admin_login, AdminLogin, Form, TestBoard};
+use crate::settings::BoardSettings;
+use crate::{admin, indexes, moderation, now, storage, Post};
+
+fn board() -> TestBoard {
+    TestBoard::with(|config| {
+        config.admin_password = Some("secret".to_string());
+        config.admin_session_secs = 60 * 60;
+        config.admin_cookie_secure = true;
+    })
+}
+
+async fn login(board: &TestBoard, password: &str) -> super::Response {
+    let req = TestRequest::post()
+        .uri("/admin/login")
+        .insert_header((CONTENT_TYPE, "application/x-www-form-urlencoded"))
+        .set_form([("name", "Mod"), ("password", password)]);
+    board.send(req).await
+}
+
+fn edit(admin: &AdminLogin, post: &Post, query: &str) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/admin/post/{}/edit{}", post.id, query))
+        .insert_header((COOKIE, admin.cookie.as_str()))
+        .set_form([("message", "edited")])
+}
+
+#[actix_web::test]
+async fn the_session_cookie_is_strict_secure_and_expires() {
+    let board = board();
+    let res = login(&board, "secret").await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER);
+    let cookie = res.headers.get(SET_COOKIE).unwrap().to_str().unwrap();
+    let attributes: Vec<_> = cookie.split("; ").skip(1).collect();
+    for expected in ["HttpOnly", "SameSite=Strict", "Secure", "Max-Age=3600"] {
+        assert!(attributes.contains(&expected), "{} in {}", expected, cookie);
+    }
+}
+
+#[actix_web::test]
+async fn a_wrong_password_gets_no_session() {
+    let board = board();
+    for password in ["", "secre", "secret ", "secrets", "SECRET"] {
+        let res = login(&board, password).await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN, "{:?}", password);
+        assert!(res.headers.get(SET_COOKIE).is_none(), "{:?}", password);
+    }
+    assert!(board.db.open_tree("admin_sessions").unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn forms_without_the_token_are_refused() {
+    let board = board();
+    let admin = admin_login(&board).await;
+    let thread = board.thread("Thread", "Start").await;
+
+    for query in ["", "?csrf=", "?csrf=0123456789abcdef0123456789abcdef"] {
+        let res = board.send(edit(&admin, &thread, query)).await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN, "{:?}", query);
+    }
+    assert_eq!(board.find("Thread").message, "Start");
+
+    let res = board.send(edit(&admin, &thread, &format!("?csrf={}", admin.csrf))).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    assert_eq!(board.find("Thread").message, "edited");
+
+    // Another session's token is no good either
+    let other = admin_login(&board).await;
+    assert_ne!(other.csrf, admin.csrf);
+    let res = board.send(edit(&admin, &thread, &format!("?csrf={}", other.csrf))).await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn expired_sessions_are_refused_and_pruned() {
+    let board = board();
+    let admin = admin_login(&board).await;
+    assert_eq!(board.send(admin.get("/admin/settings")).await.status, StatusCode::OK);
+
+    // Backdate the session past ADMIN_SESSION_HOURS
+    let sessions = board.db.open_tree("admin_sessions").unwrap();
+    let (key, bytes) = sessions.iter().next().unwrap().unwrap();
+    let mut session: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    session["created"] = (now() - 60 * 60).into();
+    sessions.insert(&key, serde_json::to_vec(&session).unwrap()).unwrap();
+
+    assert_eq!(board.send(admin.get("/admin/settings")).await.status, StatusCode::FORBIDDEN);
+    assert_eq!(board.send(admin.post("/admin/reload-config")).await.status, StatusCode::FORBIDDEN);
+    assert!(sessions.is_empty());
+
+    // The maintenance job clears out the ones nobody comes back for
+    admin_login(&board).await;
+    assert_eq!(admin::prune_sessions(&board.db, &board.config, now()), 0);
+    assert_eq!(admin::prune_sessions(&board.db, &board.config, now() + 60 * 60), 1);
+    assert!(sessions.is_empty());
+}
+
+// Posts `title` from `ip`, as a reply when there's a thread
+async fn post_from(board: &TestBoard, ip: &str, thread: Option<&Post>, title: &str) -> Post {
+    let form = Form::new().text("title", title).text("message", "Start");
+    let form = match thread {
+        Some(thread) => form.text("parent_id", &thread.id),
+        None => form,
+    };
+    let req = form.request("/submit").peer_addr(format!("{}:4000", ip).parse().unwrap());
+    let res = board.send(req).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    board.find(title)
+}
+
+async fn dossier(board: &TestBoard, admin: &AdminLogin, post: &Post) -> Value {
+    let res = board.send(admin.get(&format!("/admin/post/{}/dossier", post.id))).await;
+    assert_eq!(res.status, StatusCode::OK, "{}", res.body);
+    serde_json::from_str(&res.body).unwrap()
+}
+
+#[actix_web::test]
+async fn dossiers_hold_the_history_poster_bans_and_reports() {
+    let board = board();
+    let admin = admin_login(&board).await;
+    let thread = post_from(&board, "10.0.0.1", None, "Thread").await;
+    let again = post_from(&board, "10.0.0.1", Some(&thread), "Again").await;
+    let other = post_from(&board, "10.0.0.2", Some(&thread), "Other").await;
+
+    let edit = admin.post(&format!("/admin/post/{}/edit", thread.id)).set_form([("message", "Started")]);
+    assert_eq!(board.send(edit).await.status, StatusCode::SEE_OTHER);
+    let ip_hash = thread.ip_hash.clone().unwrap();
+    moderation::ban(&board.db, &ip_hash);
+    let flags = board.db.open_tree("watch_flags").unwrap();
+    flags.insert(format!("phone/{}", thread.id), &thread.timestamp.to_be_bytes()).unwrap();
+
+    let json = dossier(&board, &admin, &thread).await;
+    let messages: Vec<_> = json["edits"].as_array().unwrap().iter().map(|version| &version["message"]).collect();
+    assert_eq!(messages, ["Start", "Started"]);
+    assert_eq!(json["poster"]["ip_hash"], ip_hash.as_str());
+    let others = json["poster"]["other_posts"].as_array().unwrap();
+    assert_eq!(others.len(), 1);
+    assert_eq!(others[0]["id"], again.id.as_str());
+    assert_eq!(others[0]["timestamp"], again.timestamp);
+    assert_eq!(json["bans"][0]["kind"], "poster");
+    assert_eq!(json["bans"][0]["hash"], ip_hash.as_str());
+    assert_eq!(json["reports"][0]["kind"], "watch_pattern");
+    assert_eq!(json["reports"][0]["reason"], "phone");
+
+    // Nothing to tell about the other poster but their ip_hash
+    let json = dossier(&board, &admin, &other).await;
+    assert_ne!(json["poster"]["ip_hash"], ip_hash.as_str());
+    assert_eq!(json["poster"]["other_posts"], serde_json::json!([]));
+    for section in ["edits", "bans", "reports"] {
+        assert!(json.get(section).is_none(), "{}", section);
+    }
+}
+
+// Stored the way save_post stores it, at a time of the test's choosing
+fn store(board: &TestBoard, id: &str, parent_id: Option<&str>, timestamp: u64) {
+    let post = Post {
+        id: id.to_string(),
+        parent_id: parent_id.map(str::to_string),
+        title: id.to_string(),
+        message: "x".to_string(),
+        timestamp,
+        ..Post::default()
+    };
+    crate::store_post(&board.db, &BoardSettings::default(), &post, false).unwrap();
+}
+
+fn ids(posts: &[Post]) -> Vec<&str> {
+    posts.iter().map(|post| post.id.as_str()).collect()
+}
+
+#[actix_web::test]
+async fn the_post_list_pages_newest_first_from_the_index() {
+    let board = board();
+    store(&board, "t1", None, 100);
+    store(&board, "r1", Some("t1"), 200);
+    store(&board, "t2", None, 300);
+    store(&board, "r2", Some("t1"), 400);
+
+    // t1 is listed when it was started, not when it was last bumped
+    let (posts, more) = indexes::latest_posts(&board.db, 0, 2);
+    assert_eq!((ids(&posts), more), (vec!["r2", "t2"], true));
+    let (posts, more) = indexes::latest_posts(&board.db, 2, 2);
+    assert_eq!((ids(&posts), more), (vec!["r1", "t1"], false));
+
+    // Records that don't parse are passed over and kept
+    board.db.insert("broken", "{not json").unwrap();
+    board.db.open_tree("posted").unwrap().insert(indexes::bump_key(350, "broken"), &[]).unwrap();
+    let (posts, _) = indexes::latest_posts(&board.db, 0, 10);
+    assert_eq!(ids(&posts), ["r2", "t2", "r1", "t1"]);
+
+    // Deleted posts go, the bumped thread's entry on the next listing
+    storage::delete_thread(&board.db, &board.config, "t1");
+    let (posts, _) = indexes::latest_posts(&board.db, 0, 10);
+    assert_eq!(ids(&posts), ["t2"]);
+    let posted: Vec<_> = board.db.open_tree("posted").unwrap().iter().keys().map(|key| key.unwrap()).collect();
+    assert_eq!(posted, [indexes::bump_key(300, "t2").as_bytes(), indexes::bump_key(350, "broken").as_bytes()]);
+
+    let res = board.send(admin_login(&board).await.get("/admin/posts")).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res.body.contains("/post/t2"), "{}", res.body);
+}
+
+// A reply with `options`, sent from `admin`'s reply form if there is one
+async fn reply_with_options(board: &TestBoard, thread: &Post, title: &str, options: &str, admin: Option<&AdminLogin>) -> Post {
+    let form = Form::new().text("parent_id", &thread.id).text("title", title).text("message", "Hi").text("options", options);
+    let req = match admin {
+        Some(admin) => form.request(&format!("/submit?csrf={}", admin.csrf)).insert_header((COOKIE, admin.cookie.as_str())),
+        None => form.request("/submit"),
+    };
+    assert_eq!(board.send(req).await.status, StatusCode::SEE_OTHER);
+    board.find(title)
+}
+
+#[actix_web::test]
+async fn capcodes_take_an_admin_session() {
+    let board = TestBoard::with(|config| {
+        config.admin_password = Some("secret".to_string());
+        config.capcode_name = "Janitor".to_string();
+    });
+    let admin = admin_login(&board).await;
+    let thread = board.thread("Thread", "Start").await;
+
+    let plain = reply_with_options(&board, &thread, "Plain", "#admin", None).await;
+    let forger = AdminLogin {
+        cookie: "admin_session=forged".to_string(),
+        csrf: admin.csrf.clone(),
+    };
+    let forged = reply_with_options(&board, &thread, "Forged", "#admin", Some(&forger)).await;
+    let unasked = reply_with_options(&board, &thread, "Unasked", "", Some(&admin)).await;
+    let staff = reply_with_options(&board, &thread, "Staff", "sage #ADMIN", Some(&admin)).await;
+    assert_eq!(plain.capcode, None);
+    assert_eq!(forged.capcode, None);
+    assert_eq!(unasked.capcode, None);
+    assert_eq!(staff.capcode.as_deref(), Some("Janitor"));
+
+    let html = board.get(&format!("/post/{}", thread.id)).await.html();
+    assert_eq!(super::texts(&html, ".capcode"), vec!["## Janitor"]);
+    assert_eq!(super::texts(&html, ".poster-name").iter().filter(|name| name.as_str() == "Anonymous").count(), 4);
+
+    let res = board.get(&format!("/api/post/{}", thread.id)).await;
+    let body: Value = serde_json::from_str(&res.body).unwrap();
+    let capcodes: Vec<&Value> = body["replies"].as_array().unwrap().iter().map(|reply| &reply["capcode"]).collect();
+    assert_eq!(capcodes, vec![&Value::Null, &Value::Null, &Value::Null, &Value::from("Janitor")]);
+}
+
+#[actix_web::test]
+async fn admin_actions_return_only_to_pages_on_the_board() {
+    let board = board();
+    let admin = admin_login(&board).await;
+    let thread = board.thread("Thread", "Start").await;
+    let back = format!("/post/{}?page=2#p105", thread.id);
+    let cases = [
+        (back.clone(), back.as_str()),
+        ("https://evil.example".to_string(), "/admin/posts"),
+        ("//evil.example/post/x".to_string(), "/admin/posts"),
+    ];
+    for (action, (return_to, expected)) in ["sticky", "unsticky", "sticky"].iter().zip(&cases) {
+        let encoded: String = return_to.bytes().map(|b| format!("%{:02X}", b)).collect();
+        let req = admin.post(&format!("/admin/post/{}/{}?return_to={}", thread.id, action, encoded));
+        let res = board.send(req).await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER);
+        assert_eq!(res.location(), *expected, "{}", return_to);
+    }
+}
+
+// Every rendering of a post with markup worth escaping, as snapshots/renderings.txt
+// has it. Run with UPDATE_SNAPSHOTS=1 to write the file afresh after a deliberate change.
+#[actix_web::test]
+async fn renderings_match_the_snapshot() {
+    let board = TestBoard::with(|config| {
+        config.admin_password = Some("secret".to_string());
+        config.public_url = "https://board.example".to_string();
+    });
+    let admin = admin_login(&board).await;
+    let fixture = serde_json::json!({
+        "schema": crate::schema::POST_SCHEMA,
+        "id": "fixture",
+        "parent_id": "thread",
+        "title": "<b>Bold</b> & co",
+        "name": "Anon \"quoted\"",
+        "message": "<script>alert(1)</script> & \"quotes\"\n>greentext\n>>2 quoted\nhttps://example.com/a?b=1&c=<2>",
+        "file": null,
+        "timestamp": 1_700_000_000,
+        "reply_number": 3,
+    });
+    board.db.insert("fixture", serde_json::to_vec(&fixture).unwrap()).unwrap();
+
+    let res = board.send(admin.get("/admin/post/fixture/renderings")).await;
+    let html = res.html();
+    let surfaces = super::texts(&html, ".rendering h4");
+    let sources = super::texts(&html, ".rendering pre.raw-record");
+    assert_eq!(surfaces.len(), sources.len());
+    let rendered: String = surfaces.iter().zip(&sources).map(|(surface, source)| format!("== {} ==\n{}\n", surface, source.replace('\r', ""))).collect();
+
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/tests/snapshots/renderings.txt");
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        std::fs::write(&path, rendered.replace('\n', "\r\n")).unwrap();
+    }
+    let expected = std::fs::read_to_string(&path).unwrap().replace('\r', "");
+    assert!(rendered == expected, "renderings changed, run with UPDATE_SNAPSHOTS=1 to accept:\n{}", rendered);
+}
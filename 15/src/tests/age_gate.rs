@@ -0,0 +1,127 @@
+// Adult boards, see age_gate.rs: nothing is shown before the visitor says
+// they are 18 or over, not even a file linked to directly, and only a
+// cookie the board signed counts as having said so.
+
+use actix_web::body::BodySize;
+use actix_web::http::header::{CACHE_CONTROL, COOKIE, SET_COOKIE};
+use actix_web::http::{Method, StatusCode};
+use actix_web::test::TestRequest;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::{png, texts, Form, Response, TestBoard};
+use crate::{now, upload, Post};
+
+// A thread with a picture on a board then marked adult
+async fn adult_board(board: TestBoard) -> (TestBoard, Post) {
+    let form = Form::new().text("title", "Thread").text("message", "Start").file("file", "pic.png", "image/png", &png(8));
+    board.submit(form).await;
+    let thread = board.find("Thread");
+    let mut settings = (*board.state.settings.get(&board.db)).clone();
+    settings.nsfw = true;
+    board.state.settings.save(&board.db, settings);
+    (board, thread)
+}
+
+fn assert_gated(res: &Response, what: &str) {
+    assert_eq!(res.status, StatusCode::FORBIDDEN, "{}", what);
+    assert_eq!(res.headers.get(CACHE_CONTROL).unwrap(), "private, no-store", "{}", what);
+    let html = scraper::Html::parse_document(&res.body);
+    assert_eq!(texts(&html, "main h3"), ["Main Board is for adults only"], "{}", what);
+}
+
+// An age_ok value issued at `issued`, signed the way the board signs it
+fn signed_cookie(board: &TestBoard, issued: u64) -> String {
+    let key = board.db.open_tree("meta").unwrap().get("age_cookie_key").unwrap().expect("no cookie key yet");
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+    mac.update(format!("age_ok:{}", issued).as_bytes());
+    format!("age_ok={}.{}", issued, upload::hex(&mac.finalize().into_bytes()))
+}
+
+#[actix_web::test]
+async fn media_links_get_the_interstitial_until_confirmed() {
+    let (board, thread) = adult_board(TestBoard::new()).await;
+    let file = board.config.upload_url(thread.file.as_deref().unwrap());
+    let pages = [
+        file.clone(),
+        "/".to_string(),
+        format!("/post/{}", thread.id),
+        format!("/fragment/post/{}", thread.id),
+        "/archive".to_string(),
+    ];
+    for path in &pages {
+        assert_gated(&board.get(path).await, path);
+    }
+    let head = board.send(TestRequest::default().method(Method::HEAD).uri(&file)).await;
+    assert_eq!(head.status, StatusCode::FORBIDDEN);
+
+    // Saying yes goes back to the file, which is then served
+    let html = scraper::Html::parse_document(&board.get(&file).await.body);
+    let return_to = super::attrs(&html, "input[name=return_to]", "value").remove(0);
+    assert_eq!(return_to, file);
+    let req = TestRequest::post().uri("/age-check").set_form([("return_to", return_to.as_str())]);
+    let res = board.send(req).await;
+    assert_eq!((res.status, res.location()), (StatusCode::SEE_OTHER, file.as_str()));
+    let set = res.headers.get(SET_COOKIE).unwrap().to_str().unwrap();
+    assert!(set.contains("HttpOnly") && set.contains("Max-Age=31536000"), "{}", set);
+    let cookie = set.split(';').next().unwrap().to_string();
+    for path in &pages {
+        let res = board.send(TestRequest::get().uri(path).insert_header((COOKIE, cookie.as_str()))).await;
+        assert_eq!(res.status, StatusCode::OK, "{}", path);
+    }
+    let res = board.send(TestRequest::get().uri(&file).insert_header((COOKIE, cookie.as_str()))).await;
+    let stored = std::fs::metadata(board.config.upload_path(thread.file.as_deref().unwrap())).unwrap().len();
+    assert_eq!(res.size, BodySize::Sized(stored));
+
+    // Elsewhere goes to the index instead
+    let req = TestRequest::post().uri("/age-check").set_form([("return_to", "https://elsewhere.example/")]);
+    assert_eq!(board.send(req).await.location(), "/");
+}
+
+#[actix_web::test]
+async fn only_a_fresh_cookie_the_board_signed_counts() {
+    let (board, thread) = adult_board(TestBoard::new()).await;
+    let file = board.config.upload_url(thread.file.as_deref().unwrap());
+    board.send(TestRequest::post().uri("/age-check").set_form([("return_to", "/")])).await;
+
+    let day = 24 * 60 * 60;
+    let valid = signed_cookie(&board, now() - day);
+    let cookies = [
+        ("made up", format!("age_ok={}.{}", now(), "0".repeat(64))),
+        ("unsigned", format!("age_ok={}", now())),
+        ("not a time", "age_ok=yes.please".to_string()),
+        ("a year old", signed_cookie(&board, now() - 366 * day)),
+        ("re-dated", valid.replacen(&(now() - day).to_string(), &now().to_string(), 1)),
+    ];
+    for (what, cookie) in &cookies {
+        let res = board.send(TestRequest::get().uri(&file).insert_header((COOKIE, cookie.as_str()))).await;
+        assert_gated(&res, what);
+    }
+    let res = board.send(TestRequest::get().uri(&file).insert_header((COOKIE, valid.as_str()))).await;
+    assert_eq!(res.status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn scripts_need_the_token() {
+    let (board, _) = adult_board(TestBoard::new()).await;
+    for path in ["/api/threads", "/widget.json", "/api/threads?token=wrong"] {
+        assert_eq!(board.get(path).await.status, StatusCode::NOT_FOUND, "{}", path);
+    }
+
+    let (board, _) = adult_board(TestBoard::with(|config| config.nsfw_api_token = Some("letmein".to_string()))).await;
+    for wrong in ["wrong", "letme", "letmein2", "LETMEIN", ""] {
+        assert_eq!(board.get(&format!("/api/threads?token={}", wrong)).await.status, StatusCode::NOT_FOUND, "{}", wrong);
+    }
+    assert_eq!(board.get("/api/threads?token=letmein").await.status, StatusCode::OK);
+    assert_eq!(board.get("/widget.json?token=letmein").await.status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn other_boards_have_no_gate() {
+    let board = TestBoard::new();
+    let form = Form::new().text("title", "Thread").text("message", "Start").file("file", "pic.png", "image/png", &png(8));
+    board.submit(form).await;
+    let thread = board.find("Thread");
+    assert_eq!(board.get(&board.config.upload_url(thread.file.as_deref().unwrap())).await.status, StatusCode::OK);
+    assert_eq!(board.get("/api/threads").await.status, StatusCode::OK);
+}
@@ -0,0 +1,184 @@
+// Announcements, see announcement.rs: a thread an admin posts as the board
+// from /admin/announce, with the capcode, closed to replies unless asked,
+// stuck to the top of the index and linked from the banner, all at once.
+// Only an admin's new thread is ever one.
+
+use actix_web::http::header::COOKIE;
+use actix_web::http::StatusCode;
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::{admin_login, attrs, select, texts, AdminLogin, Form, Response, TestBoard};
+use crate::audit::AuditEntry;
+use crate::{archive, indexes, now, Post};
+
+fn admin_board() -> TestBoard {
+    TestBoard::with(|config| {
+        config.admin_password = Some("secret".to_string());
+        config.capcode_name = "Board".to_string();
+    })
+}
+
+// Sends the form on /admin/announce the way a browser would
+async fn announce(board: &TestBoard, admin: &AdminLogin, title: &str, locked: bool) -> Response {
+    let html = board.send(admin.get("/admin/announce")).await.html();
+    let action = attrs(&html, "form.post-form", "action").remove(0);
+    let token = attrs(&html, "input[name=submit_token]", "value").remove(0);
+    let mut form = Form::with_token(&token).text("announcement", "1").text("title", title).text("message", "Read this");
+    if locked {
+        form = form.text("announcement_locked", "1");
+    }
+    board.send(form.request(&action).insert_header((COOKIE, admin.cookie.as_str()))).await
+}
+
+fn banner(board: &TestBoard) -> Option<String> {
+    board.db.open_tree("meta").unwrap().get("announcement").unwrap().map(|id| String::from_utf8_lossy(&id).into_owned())
+}
+
+fn audited(board: &TestBoard, action: &str) -> Vec<String> {
+    let audit = board.db.open_tree("audit").unwrap();
+    audit.iter().values().map(|bytes| serde_json::from_slice::<AuditEntry>(&bytes.unwrap()).unwrap()).filter(|entry| entry.action == action).map(|entry| entry.target).collect()
+}
+
+#[actix_web::test]
+async fn an_announcement_is_stuck_closed_capcoded_and_on_the_banner() {
+    let board = admin_board();
+    let admin = admin_login(&board).await;
+    board.thread("Chat", "Not official").await;
+    let res = announce(&board, &admin, "Downtime", true).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    assert_eq!(res.location(), "/");
+    let thread = board.find("Downtime");
+    assert!(thread.announcement);
+    assert_eq!(thread.capcode.as_deref(), Some("Board"));
+    assert!(thread.locks_at.is_some_and(|locks_at| locks_at <= now()));
+    assert_eq!(indexes::stickies(&board.db).iter().map(|sticky| sticky.id.clone()).collect::<Vec<_>>(), [thread.id.as_str()]);
+    assert_eq!(banner(&board), Some(thread.id.clone()));
+    assert_eq!(audited(&board, "announce"), [thread.id.as_str()]);
+
+    let html = board.get("/").await.html();
+    assert_eq!(texts(&html, ".announcement-banner"), ["Announcement Downtime"]);
+    assert_eq!(attrs(&html, ".announcement-banner a", "href"), [format!("/post/{}", thread.id)]);
+    assert_eq!(texts(&html, ".post.sticky.announcement h3 bdi"), ["Downtime"]);
+    assert_eq!(texts(&html, ".post.announcement .capcode"), ["## Board"]);
+    assert_eq!(select(&html, ".post.announcement h3 [title=Closed]").len(), 1);
+    let page = board.get(&format!("/post/{}", thread.id)).await.html();
+    assert_eq!(select(&page, ".original-post.announcement").len(), 1);
+    let api: Value = serde_json::from_str(&board.get(&format!("/api/post/{}", thread.id)).await.body).unwrap();
+    assert_eq!(api["post"]["announcement"], true);
+
+    // Closed to everyone, the admin included
+    let reply = Form::new().text("parent_id", &thread.id).text("title", "Reply").text("message", "When?");
+    assert_eq!(board.submit(reply).await.status, StatusCode::BAD_REQUEST);
+    let reply = Form::new().text("parent_id", &thread.id).text("title", "Reply").text("message", "Soon");
+    let req = reply.request(&format!("/submit?csrf={}", admin.csrf)).insert_header((COOKIE, admin.cookie.as_str()));
+    assert_eq!(board.send(req).await.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn unticking_the_box_leaves_it_open() {
+    let board = admin_board();
+    let admin = admin_login(&board).await;
+    assert_eq!(announce(&board, &admin, "Feedback", false).await.status, StatusCode::SEE_OTHER);
+    let thread = board.find("Feedback");
+    assert!(thread.announcement && thread.locks_at.is_none());
+    assert_eq!(banner(&board), Some(thread.id.clone()));
+    board.reply(&thread, "Reply", "Thanks").await;
+}
+
+#[actix_web::test]
+async fn only_an_admins_new_thread_is_an_announcement() {
+    let board = admin_board();
+    let admin = admin_login(&board).await;
+    let form = Form::new().text("announcement", "1").text("announcement_locked", "1").text("title", "Fake").text("message", "From the board");
+    assert_eq!(board.submit(form).await.status, StatusCode::SEE_OTHER);
+    let fake = board.find("Fake");
+    assert!(!fake.announcement && fake.capcode.is_none() && fake.locks_at.is_none());
+
+    let form = Form::new().text("announcement", "1").text("parent_id", &fake.id).text("title", "Reply").text("message", "Hi");
+    let req = form.request(&format!("/submit?csrf={}", admin.csrf)).insert_header((COOKIE, admin.cookie.as_str()));
+    assert_eq!(board.send(req).await.status, StatusCode::SEE_OTHER);
+    assert!(!board.find("Reply").announcement);
+    assert!(indexes::sticky_ids(&board.db).is_empty());
+    assert_eq!(banner(&board), None);
+    assert!(select(&board.get("/").await.html(), ".announcement-banner, .announcement").is_empty());
+
+    // and only an admin sees the form
+    assert_ne!(board.get("/admin/announce").await.status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn announcements_skip_the_approval_queue() {
+    let board = TestBoard::with(|config| {
+        config.admin_password = Some("secret".to_string());
+        config.approval_queue = true;
+    });
+    let admin = admin_login(&board).await;
+    assert_eq!(announce(&board, &admin, "Rules", true).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(banner(&board), Some(board.find("Rules").id));
+}
+
+#[actix_web::test]
+async fn the_banner_goes_with_its_thread() {
+    let board = admin_board();
+    let admin = admin_login(&board).await;
+    for title in ["First", "Second"].iter() {
+        assert_eq!(announce(&board, &admin, title, true).await.status, StatusCode::SEE_OTHER);
+    }
+    let (first, second) = (board.find("First"), board.find("Second"));
+    // The latest one has the banner; the older stays stuck
+    assert_eq!(banner(&board), Some(second.id.clone()));
+    assert_eq!(texts(&board.get("/").await.html(), ".post.sticky h3 bdi"), ["First", "Second"]);
+    // Deleting the older one leaves it
+    assert_eq!(board.send(admin.post(&format!("/admin/post/{}/delete-thread", first.id))).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(banner(&board), Some(second.id.clone()));
+    assert_eq!(board.send(admin.post(&format!("/admin/post/{}/delete-thread", second.id))).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(banner(&board), None);
+    let html = board.get("/").await.html();
+    assert!(select(&html, ".announcement-banner").is_empty());
+    assert!(indexes::sticky_ids(&board.db).is_empty());
+
+    // and archiving it takes it down too
+    assert_eq!(announce(&board, &admin, "Third", true).await.status, StatusCode::SEE_OTHER);
+    assert!(archive::archive(&board.db, &board.find("Third").id, now()));
+    assert_eq!(banner(&board), None);
+    assert!(select(&board.get("/").await.html(), ".announcement-banner").is_empty());
+}
+
+#[actix_web::test]
+async fn nobody_sees_an_announcement_half_made() {
+    for round in 0..5 {
+        let board = admin_board();
+        let admin = admin_login(&board).await;
+        // Watches the database from another thread while the announcement
+        // goes in, reading the post first so that what's read after it is
+        // at least as new
+        let db = board.db.clone();
+        let done = Arc::new(AtomicBool::new(false));
+        let watcher = {
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let mut seen = 0;
+                // with a last look once it's in, however the threads ran
+                loop {
+                    let finished = done.load(Ordering::SeqCst);
+                    let announced = db.iter().values().filter_map(|bytes| Post::upgrade(&bytes.unwrap()).ok()).find(|post| post.announcement);
+                    if let Some(thread) = announced {
+                        assert!(db.open_tree("stickies").unwrap().contains_key(&thread.id).unwrap(), "not stuck yet");
+                        let banner = db.open_tree("meta").unwrap().get("announcement").unwrap();
+                        assert_eq!(banner.as_deref(), Some(thread.id.as_bytes()), "not on the banner yet");
+                        assert!(thread.capcode.is_some() && thread.locks_at.is_some());
+                        seen += 1;
+                    }
+                    if finished {
+                        return seen;
+                    }
+                }
+            })
+        };
+        assert_eq!(announce(&board, &admin, "Atomic", true).await.status, StatusCode::SEE_OTHER, "round {}", round);
+        done.store(true, Ordering::SeqCst);
+        assert!(watcher.join().unwrap() > 0, "round {}", round);
+    }
+}
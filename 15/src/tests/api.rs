@@ -0,0 +1,237 @@
+// The JSON API, see api.rs: paging with cursors while the board changes
+// underneath, the per-thread change log archivers sync from, and batch
+// fetches for quote previews.
+
+use actix_web::http::StatusCode;
+use serde_json::Value;
+
+use super::{admin_login, TestBoard};
+use crate::{storage, upload};
+
+async fn json(board: &TestBoard, path: &str) -> Value {
+    let res = board.get(path).await;
+    assert_eq!(res.status, StatusCode::OK, "{}: {}", path, res.body);
+    serde_json::from_str(&res.body).unwrap()
+}
+
+fn ids(list: &Value) -> Vec<String> {
+    list.as_array().unwrap().iter().map(|post| post["id"].as_str().unwrap().to_string()).collect()
+}
+
+#[actix_web::test]
+async fn garbage_cursors_are_refused() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    board.reply(&thread, "Reply", "one").await;
+    let other = board.thread("Other", "Start").await;
+    board.reply(&other, "Other reply", "one").await;
+
+    // A key of another thread's replies is as bad as no key at all
+    let elsewhere = upload::hex(format!("{}/{:020}", other.id, 1).as_bytes());
+    let not_a_number = upload::hex(format!("{}/1/x", thread.id).as_bytes());
+    let not_a_key = upload::hex(b"hello");
+    for cursor in ["zz", "abc", "ff", "%00", "", not_a_key.as_str()] {
+        let res = board.get(&format!("/api/threads?cursor={}", cursor)).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "{:?}", cursor);
+        assert_eq!(res.body, r#"{"error":"invalid cursor"}"#);
+    }
+    for cursor in ["zz", "abc", "ff", not_a_key.as_str(), elsewhere.as_str(), not_a_number.as_str()] {
+        let res = board.get(&format!("/api/post/{}?cursor={}", thread.id, cursor)).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "{:?}", cursor);
+        assert_eq!(res.body, r#"{"error":"invalid cursor"}"#);
+    }
+}
+
+#[actix_web::test]
+async fn paging_threads_while_posting_skips_and_repeats_nothing() {
+    let board = TestBoard::new();
+    let mut threads = Vec::new();
+    for n in 0..8 {
+        threads.push(board.thread(&format!("Thread {}", n), "Start").await);
+    }
+    let before = ids(&json(&board, "/api/threads").await["threads"]);
+    assert_eq!(before.len(), 8);
+
+    let mut seen = Vec::new();
+    let mut cursor: Option<String> = None;
+    for page in 0.. {
+        let path = match &cursor {
+            Some(cursor) => format!("/api/threads?limit=3&cursor={}", cursor),
+            None => "/api/threads?limit=3".to_string(),
+        };
+        let body = json(&board, &path).await;
+        seen.extend(ids(&body["threads"]));
+        // Between pages: a new thread, and a bump of one already seen
+        board.thread(&format!("New {}", page), "Start").await;
+        let bumped = threads.iter().find(|thread| thread.id == seen[0]).unwrap();
+        board.reply(bumped, &format!("Bump {}", page), "up").await;
+        cursor = match body["next_cursor"].as_str() {
+            Some(next) => Some(next.to_string()),
+            None => break,
+        };
+    }
+
+    // Every thread there at the start, once each and in its order; threads
+    // started meanwhile may show up further down if they sort there
+    let old: Vec<&String> = seen.iter().filter(|id| before.contains(id)).collect();
+    assert_eq!(old, before.iter().collect::<Vec<_>>());
+    let mut unique = seen.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), seen.len(), "{:?}", seen);
+}
+
+#[actix_web::test]
+async fn paging_replies_while_replying_skips_and_repeats_nothing() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let mut posted = Vec::new();
+    for n in 0..5 {
+        posted.push(board.reply(&thread, &format!("Reply {}", n), "one").await.id);
+    }
+
+    let mut seen = Vec::new();
+    let mut cursor: Option<String> = None;
+    for page in 0.. {
+        let path = match &cursor {
+            Some(cursor) => format!("/api/post/{}?limit=2&cursor={}", thread.id, cursor),
+            None => format!("/api/post/{}?limit=2", thread.id),
+        };
+        let body = json(&board, &path).await;
+        seen.extend(ids(&body["replies"]));
+        if page < 3 {
+            posted.push(board.reply(&thread, &format!("Later {}", page), "more").await.id);
+        }
+        cursor = match body["next_cursor"].as_str() {
+            Some(next) => Some(next.to_string()),
+            None => break,
+        };
+    }
+    assert_eq!(seen, posted);
+}
+
+#[actix_web::test]
+async fn since_leaves_out_threads_not_bumped_after_it() {
+    let board = TestBoard::new();
+    let old = board.thread("Old", "Start").await;
+    let new = board.thread("New", "Start").await;
+    board.reply(&new, "Reply", "bump").await;
+
+    let all = ids(&json(&board, "/api/threads?since=0").await["threads"]);
+    assert_eq!(all.len(), 2);
+    let none = ids(&json(&board, &format!("/api/threads?since={}", old.timestamp + 3600)).await["threads"]);
+    assert!(none.is_empty());
+}
+
+// (version, kind, id, message) for each change after `since`
+async fn changes(board: &TestBoard, thread: &str, since: u64) -> (u64, Vec<(u64, String, String, Option<String>)>) {
+    let body = json(board, &format!("/api/thread/{}/changes?since_version={}", thread, since)).await;
+    assert_eq!(body["full_fetch_required"], false);
+    let changes = body["changes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|change| {
+            (
+                change["version"].as_u64().unwrap(),
+                change["kind"].as_str().unwrap().to_string(),
+                change["id"].as_str().unwrap().to_string(),
+                change["post"]["message"].as_str().map(str::to_string),
+            )
+        })
+        .collect();
+    (body["version"].as_u64().unwrap(), changes)
+}
+
+fn change(version: u64, kind: &str, id: &str, message: Option<&str>) -> (u64, String, String, Option<String>) {
+    (version, kind.to_string(), id.to_string(), message.map(str::to_string))
+}
+
+#[actix_web::test]
+async fn thread_changes_follow_adds_deletes_and_edits() {
+    let board = TestBoard::with(|config| config.admin_password = Some("secret".to_string()));
+    let admin = admin_login(&board).await;
+    let thread = board.thread("Thread", "Start").await;
+    let (version, log) = changes(&board, &thread.id, 0).await;
+    assert_eq!((version, log), (0, Vec::new()));
+
+    let first = board.reply(&thread, "First", "one").await;
+    let second = board.reply(&thread, "Second", "two").await;
+    let (version, log) = changes(&board, &thread.id, 0).await;
+    assert_eq!(version, 2);
+    assert_eq!(log, vec![change(1, "added", &first.id, Some("one")), change(2, "added", &second.id, Some("two"))]);
+
+    // A deleted reply is listed by id only, and so is its earlier add
+    storage::delete_post(&board.db, &board.config, &first.id);
+    let (version, log) = changes(&board, &thread.id, 2).await;
+    assert_eq!((version, log), (3, vec![change(3, "deleted", &first.id, None)]));
+    let (_, log) = changes(&board, &thread.id, 0).await;
+    assert_eq!(log[0], change(1, "added", &first.id, None));
+
+    // Edits carry the new message, the first post's included
+    for (post, message) in [(&second.id, "two, edited"), (&thread.id, "Started")] {
+        let edit = admin.post(&format!("/admin/post/{}/edit", post)).set_form([("message", message)]);
+        assert_eq!(board.send(edit).await.status, StatusCode::SEE_OTHER);
+    }
+    let (version, log) = changes(&board, &thread.id, 3).await;
+    assert_eq!(version, 5);
+    assert_eq!(log, vec![change(4, "edited", &second.id, Some("two, edited")), change(5, "edited", &thread.id, Some("Started"))]);
+
+    // Caught up, and ahead
+    assert_eq!(changes(&board, &thread.id, 5).await, (5, Vec::new()));
+    let res = board.get(&format!("/api/thread/{}/changes?since_version=6", thread.id)).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn batches_leave_out_deleted_and_unknown_posts() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let kept = board.reply(&thread, "Kept", "one").await;
+    let deleted = board.reply(&thread, "Deleted", "two").await;
+    let last = board.reply(&thread, "Last", "three").await;
+    storage::delete_post(&board.db, &board.config, &deleted.id);
+
+    let ids = [thread.id.as_str(), kept.id.as_str(), deleted.id.as_str(), "no-such-post", "", last.id.as_str()].join(",");
+    let res = board.get(&format!("/api/posts?ids={}", ids)).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers.get("cache-control").unwrap(), "public, max-age=10");
+    assert!(!res.body.contains("ip_hash"), "{}", res.body);
+    let body: Value = serde_json::from_str(&res.body).unwrap();
+    let posts = body["posts"].as_object().unwrap();
+    let mut expected = vec![thread.id.clone(), kept.id.clone(), last.id.clone()];
+    expected.sort();
+    assert_eq!(posts.keys().cloned().collect::<Vec<_>>(), expected);
+    assert_eq!(posts[&kept.id]["message"], "one");
+    assert_eq!(posts[&last.id]["reply_number"], 3);
+
+    // By number, keyed by number
+    let body = json(&board, &format!("/api/posts?thread={}&numbers=1,2,3,99", thread.id)).await;
+    let posts = body["posts"].as_object().unwrap();
+    assert_eq!(posts.keys().collect::<Vec<_>>(), ["1", "3"]);
+    assert_eq!(posts["1"]["id"], kept.id.as_str());
+    assert_eq!(posts["3"]["id"], last.id.as_str());
+}
+
+#[actix_web::test]
+async fn batches_past_the_cap_or_malformed_are_refused() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let fifty = vec![thread.id.as_str(); 50].join(",");
+    assert_eq!(json(&board, &format!("/api/posts?ids={}", fifty)).await["posts"].as_object().unwrap().len(), 1);
+
+    let numbers: Vec<String> = (1..=51).map(|n| n.to_string()).collect();
+    let cases = [
+        (format!("ids={},x", fifty), r#"{"error":"at most 50 posts per request"}"#),
+        (format!("thread={}&numbers={}", thread.id, numbers.join(",")), r#"{"error":"at most 50 posts per request"}"#),
+        (format!("thread={}&numbers=1,two", thread.id), r#"{"error":"invalid reply number: two"}"#),
+        ("numbers=1".to_string(), r#"{"error":"numbers needs a thread"}"#),
+        (format!("ids={}&thread={}&numbers=1", thread.id, thread.id), r#"{"error":"give either ids, or thread and numbers"}"#),
+        (String::new(), r#"{"error":"give either ids, or thread and numbers"}"#),
+    ];
+    for (query, error) in cases {
+        let res = board.get(&format!("/api/posts?{}", query)).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", query);
+        assert_eq!(res.body, error, "{}", query);
+    }
+}
@@ -0,0 +1,215 @@
+// Archived threads, see archive.rs: found by the year and month they were
+// started in, and in a database of their own once moved there from the
+// live one, see archive_db.rs. Quotes inside them, and the backlinks back
+// from what they quote, lead where they did whichever database they're in.
+
+use actix_web::http::StatusCode;
+use std::collections::HashMap;
+use tempfile::TempDir;
+
+use super::{attrs, png, texts, Form, TestBoard};
+use crate::indexes::bump_key;
+use crate::{archive, backlinks, edits, load_post, now, storage, upload, Post};
+
+// A board whose archive database is in `dir`, not created yet
+fn board_with_archive(dir: &TempDir) -> TestBoard {
+    let path = dir.path().join("archive");
+    TestBoard::with(|config| config.archive_db = Some(path))
+}
+
+#[actix_web::test]
+async fn archive_pages_explain_a_missing_archive_database() {
+    let dir = tempfile::tempdir().unwrap();
+    let board = board_with_archive(&dir);
+    let thread = board.thread("Old", "Start").await;
+    assert!(archive::archive(&board.db, &thread.id, now()));
+
+    let res = board.get("/archive").await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert!(res.body.contains("No archive yet"), "{}", res.body);
+    assert_eq!(board.get("/archive/2024").await.status, StatusCode::NOT_FOUND);
+
+    // The first move creates it
+    assert_eq!(board.archive().transfer(&board.db, &board.config), 1);
+    assert!(dir.path().join("archive").exists());
+    assert_eq!(board.get("/archive").await.status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn archived_threads_move_to_the_archive_database() {
+    let dir = tempfile::tempdir().unwrap();
+    let board = board_with_archive(&dir);
+    let form = Form::new()
+        .text("title", "Old")
+        .text("message", "Start")
+        .file("file", "photo.png", "image/png", &png(16));
+    assert_eq!(board.submit(form).await.status, StatusCode::SEE_OTHER);
+    let thread = board.find("Old");
+    let first = board.reply(&thread, "First", "one").await;
+    board.reply(&thread, "Second", "two").await;
+    edits::edit(&board.db, &first.id, "one, edited", "admin", None).unwrap();
+    let live = board.thread("Live", "still here").await;
+    assert!(archive::archive(&board.db, &thread.id, now()));
+
+    assert_eq!(board.archive().transfer(&board.db, &board.config), 1);
+    // Nothing left to move
+    assert_eq!(board.archive().transfer(&board.db, &board.config), 0);
+    assert!(load_post(&board.db, &thread.id).is_none());
+    assert!(load_post(&board.db, &first.id).is_none());
+    assert!(load_post(&board.db, &live.id).is_some());
+    let moved = board.archive().get().unwrap();
+    assert!(archive::is_archived(&moved, &thread.id));
+    assert!(!archive::is_archived(&board.db, &thread.id));
+
+    // The edit history went with the reply
+    let history: Vec<String> = edits::stored(&moved, &first.id).into_iter().map(|version| version.message).collect();
+    assert_eq!(history, vec!["one", "one, edited"]);
+    assert!(edits::stored(&board.db, &first.id).is_empty());
+
+    let month = upload::dated_dir(thread.timestamp);
+    let html = board.get(&format!("/archive/{}", month)).await.html();
+    assert_eq!(texts(&html, ".archive-card strong bdi"), vec!["Old"]);
+    assert!(texts(&html, ".archive-card .muted")[0].ends_with("2 replies"));
+
+    let html = board.get(&format!("/post/{}", thread.id)).await.html();
+    assert_eq!(texts(&html, ".original-post h3 bdi"), vec!["Old"]);
+    assert_eq!(texts(&html, ".reply h4 > a:first-child"), vec!["Reply 1", "Reply 2"]);
+    assert!(texts(&html, ".board-locked")[0].contains("archived"));
+
+    // Links to replies and reply numbers still lead into the thread
+    let res = board.get(&format!("/post/{}", first.id)).await;
+    assert_eq!(res.location(), format!("/post/{}#r1", thread.id));
+    let res = board.get(&format!("/post/{}/2", thread.id)).await;
+    assert_eq!(res.location(), format!("/post/{}#r2", thread.id));
+    assert_eq!(board.get(&format!("/post/{}/3", thread.id)).await.status, StatusCode::NOT_FOUND);
+
+    // The file stayed where it was
+    let file = thread.file.unwrap();
+    assert_eq!(board.get(&format!("/static/uploads/{}", file)).await.status, StatusCode::OK);
+}
+
+// Each reply's backlinks on the thread page, by reply
+async fn page_backlinks(board: &TestBoard, thread: &Post) -> Vec<Vec<String>> {
+    let html = board.get(&format!("/post/{}", thread.id)).await.html();
+    (1..=3).map(|number| attrs(&html, &format!("#r{} .backlinks a", number), "href")).collect()
+}
+
+#[actix_web::test]
+async fn quotes_in_an_archived_thread_still_lead_both_ways() {
+    let dir = tempfile::tempdir().unwrap();
+    for move_it in [false, true] {
+        let board = if move_it { board_with_archive(&dir) } else { TestBoard::new() };
+        let thread = board.thread("Old", "Start").await;
+        board.reply(&thread, "First", "one").await;
+        let second = board.reply(&thread, "Second", ">>1 yes").await;
+        board.reply(&thread, "Third", ">>1\n>>2 both").await;
+        let quoted = HashMap::from([(1, vec![2, 3]), (2, vec![3])]);
+        let on_page = vec![vec!["#r2".to_string(), "#r3".to_string()], vec!["#r3".to_string()], vec![]];
+        assert_eq!(backlinks::for_thread(&board.db, &thread.id), quoted);
+        assert!(archive::archive(&board.db, &thread.id, now()));
+        if move_it {
+            assert_eq!(board.archive().transfer(&board.db, &board.config), 1);
+            // The backlinks went with the thread
+            assert!(backlinks::for_thread(&board.db, &thread.id).is_empty());
+            assert_eq!(backlinks::for_thread(&board.archive().get().unwrap(), &thread.id), quoted);
+        }
+
+        // From the quoted reply to the ones quoting it
+        assert_eq!(page_backlinks(&board, &thread).await, on_page, "moved: {}", move_it);
+        // and from a quote to what it quotes, by number or by id
+        let res = board.get(&format!("/post/{}/1", thread.id)).await;
+        assert_eq!(res.location(), format!("/post/{}#r1", thread.id), "moved: {}", move_it);
+        let res = board.get(&format!("/post/{}", second.id)).await;
+        assert_eq!(res.location(), format!("/post/{}#r2", thread.id), "moved: {}", move_it);
+        let html = board.get(&format!("/post/{}", thread.id)).await.html();
+        assert_eq!(texts(&html, "#r3 .post-details p[dir=auto]"), [">>1\n>>2 both"]);
+
+        // A live thread numbering its own replies isn't mixed up with it
+        let live = board.thread("Live", "Start").await;
+        board.reply(&live, "Mine", "one").await;
+        board.reply(&live, "Yours", ">>1 no").await;
+        assert_eq!(backlinks::for_thread(&board.db, &live.id), HashMap::from([(1, vec![2])]));
+        assert_eq!(page_backlinks(&board, &thread).await, on_page, "moved: {}", move_it);
+    }
+}
+
+#[actix_web::test]
+async fn without_an_archive_database_threads_stay_in_the_live_one() {
+    let board = TestBoard::new();
+    let thread = board.thread("Kept", "Start").await;
+    assert!(archive::archive(&board.db, &thread.id, now()));
+
+    assert_eq!(board.archive().transfer(&board.db, &board.config), 0);
+    assert!(load_post(&board.db, &thread.id).is_some());
+    let html = board.get(&format!("/archive/{}", upload::dated_dir(thread.timestamp))).await.html();
+    assert_eq!(texts(&html, ".archive-card strong bdi"), vec!["Kept"]);
+}
+
+// A thread started at `started` and archived
+async fn archived(board: &TestBoard, title: &str, started: u64) -> Post {
+    let mut thread = board.thread(title, "x").await;
+    for tree in ["creations", "bumps", "posted"] {
+        let tree = board.db.open_tree(tree).unwrap();
+        tree.remove(bump_key(thread.timestamp, &thread.id)).unwrap();
+        tree.insert(bump_key(started, &thread.id), &[]).unwrap();
+    }
+    thread.timestamp = started;
+    board.db.insert(&thread.id, serde_json::to_vec(&thread).unwrap()).unwrap();
+    assert!(archive::archive(&board.db, &thread.id, now()));
+    thread
+}
+
+// Each bucket's label and count
+fn buckets(html: &scraper::Html) -> Vec<String> {
+    texts(html, ".archive-buckets li")
+}
+
+#[actix_web::test]
+async fn threads_are_filed_by_the_month_they_started() {
+    let board = TestBoard::new();
+    archived(&board, "New Year's Eve", 1704067199).await;
+    archived(&board, "New Year's Day", 1704067200).await;
+    archived(&board, "Mid June", 1718452800).await;
+    let early = archived(&board, "Early June", 1717329600).await;
+    archived(&board, "End of June", 1719791999).await;
+    archived(&board, "July", 1719792000).await;
+    board.thread("Live", "Not archived").await;
+
+    let html = board.get("/archive").await.html();
+    assert_eq!(buckets(&html), ["2024 (5)", "2023 (1)"]);
+    let html = board.get("/archive/2024").await.html();
+    assert_eq!(buckets(&html), ["July (1)", "June (3)", "January (1)"]);
+    assert_eq!(super::attrs(&html, ".archive-buckets a", "href"), ["/archive/2024/07", "/archive/2024/06", "/archive/2024/01"]);
+    let html = board.get("/archive/2023").await.html();
+    assert_eq!(buckets(&html), ["December (1)"]);
+
+    // A month lists its threads oldest first, with the day each started
+    let html = board.get("/archive/2024/06").await.html();
+    assert_eq!(texts(&html, ".archive-card strong"), ["Early June", "Mid June", "End of June"]);
+    let dates: Vec<String> = texts(&html, ".archive-card .muted").iter().map(|text| text.split(',').next().unwrap().to_string()).collect();
+    assert_eq!(dates, ["2024-06-02", "2024-06-15", "2024-06-30"]);
+    assert_eq!(texts(&board.get("/archive/2024/01").await.html(), ".archive-card strong"), ["New Year's Day"]);
+    assert!(texts(&board.get("/archive/2024/02").await.html(), ".archive-card strong").is_empty());
+
+    // Archiving again changes nothing, deleting takes the thread out of the
+    // counts, and an emptied bucket goes
+    assert!(!archive::archive(&board.db, &early.id, now()));
+    storage::delete_thread(&board.db, &board.config, &early.id);
+    assert_eq!(buckets(&board.get("/archive/2024").await.html()), ["July (1)", "June (2)", "January (1)"]);
+    let july = board.find("July");
+    storage::delete_thread(&board.db, &board.config, &july.id);
+    assert_eq!(buckets(&board.get("/archive/2024").await.html()), ["June (2)", "January (1)"]);
+    assert_eq!(buckets(&board.get("/archive").await.html()), ["2024 (3)", "2023 (1)"]);
+
+    // Archived threads are off the board
+    assert_eq!(texts(&board.get("/").await.html(), ".post h3 bdi"), ["Live"]);
+}
+
+#[actix_web::test]
+async fn archive_pages_have_one_url_each() {
+    let board = TestBoard::new();
+    archived(&board, "June", 1718452800).await;
+    for path in ["/archive/24", "/archive/02024", "/archive/2024/6", "/archive/2024/13", "/archive/2024/00", "/archive/2024/+6", "/archive/year"] {
+        assert_eq!(board.get(path).await.status, StatusCode::NOT_FOUND, "{}", path);
+    }
+}
@@ -0,0 +1,134 @@
+// A board served under BASE_PATH: every link, image, form and redirect it
+// generates stays under the prefix, see Config::url_for.
+
+use actix_web::http::header::LOCATION;
+use actix_web::http::StatusCode;
+use scraper::Html;
+
+use super::{admin_login, attrs, png, Form, TestBoard};
+use crate::{archive, now, upload};
+
+const PREFIX: &str = "/board";
+
+fn board() -> TestBoard {
+    TestBoard::with(|config| {
+        config.base_path = PREFIX.to_string();
+        config.admin_password = Some("secret".to_string());
+    })
+}
+
+// A root-relative URL that leaves the prefix; absolute and relative ones
+// are left to the browser
+fn escapes(url: &str) -> bool {
+    let inside = url == PREFIX || url.starts_with(&format!("{}/", PREFIX)) || url.starts_with(&format!("{}?", PREFIX));
+    url.starts_with('/') && !url.starts_with("//") && !inside
+}
+
+fn assert_inside(page: &str, html: &Html) {
+    for attr in ["href", "src", "action", "formaction", "srcset", "poster"] {
+        for url in attrs(html, &format!("[{}]", attr), attr) {
+            assert!(!escapes(&url), "{} on {} leaves {}: {}", attr, page, PREFIX, url);
+        }
+    }
+}
+
+fn json_inside(page: &str, value: &serde_json::Value) {
+    match value {
+        serde_json::Value::String(text) => assert!(!escapes(text), "{} leaves {}: {}", page, PREFIX, text),
+        serde_json::Value::Array(items) => items.iter().for_each(|item| json_inside(page, item)),
+        serde_json::Value::Object(fields) => fields.values().for_each(|field| json_inside(page, field)),
+        _ => {}
+    }
+}
+
+#[actix_web::test]
+async fn pages_link_only_inside_the_prefix() {
+    let board = board();
+    let form = Form::new()
+        .text("title", "Pictures")
+        .text("message", "Start https://example.com/")
+        .file("file", "a.png", "image/png", &png(16));
+    let res = board.send(form.request(&format!("{}/submit", PREFIX))).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    assert!(!escapes(res.location()), "{}", res.location());
+    let thread = board.find("Pictures");
+
+    for (title, message) in [("Reply", "first"), ("Quoting", ">>1 yes")] {
+        let form = Form::new().text("parent_id", &thread.id).text("title", title).text("message", message);
+        let res = board.send(form.request(&format!("{}/submit", PREFIX))).await;
+        assert!(!escapes(res.location()), "{}", res.location());
+    }
+    let old = {
+        let form = Form::new().text("title", "Old").text("message", "x");
+        board.send(form.request(&format!("{}/submit", PREFIX))).await;
+        board.find("Old")
+    };
+    assert!(archive::archive(&board.db, &old.id, now()));
+    let month = upload::dated_dir(old.timestamp);
+    let year = month.split('/').next().unwrap().to_string();
+
+    let public = [
+        "/".to_string(),
+        "/?page=0&sort=created".to_string(),
+        format!("/post/{}", thread.id),
+        format!("/post/{}?order=newest", thread.id),
+        "/archive".to_string(),
+        format!("/archive/{}", year),
+        format!("/archive/{}", month),
+        "/stats".to_string(),
+        "/widget".to_string(),
+        "/admin/login".to_string(),
+    ];
+    for page in &public {
+        let res = board.get(&format!("{}{}", PREFIX, page)).await;
+        assert_eq!(res.status, StatusCode::OK, "{}", page);
+        assert_inside(page, &res.html());
+    }
+
+    let login = admin_login(&board).await;
+    let admin = [
+        "/admin/posts".to_string(),
+        "/admin/pending".to_string(),
+        "/admin/flagged".to_string(),
+        "/admin/flagged-images".to_string(),
+        "/admin/settings".to_string(),
+        "/admin/exemptions".to_string(),
+        "/admin/performance".to_string(),
+        format!("/admin/post/{}/history", thread.id),
+        format!("/admin/post/{}/renderings", thread.id),
+    ];
+    for page in &admin {
+        let res = board.send(login.get(&format!("{}{}", PREFIX, page))).await;
+        assert_eq!(res.status, StatusCode::OK, "{}", page);
+        assert_inside(page, &res.html());
+    }
+
+    for page in ["/widget.json", "/api/threads", "/stats.json"] {
+        let res = board.get(&format!("{}{}", PREFIX, page)).await;
+        assert_eq!(res.status, StatusCode::OK, "{}", page);
+        json_inside(page, &serde_json::from_str(&res.body).unwrap());
+    }
+}
+
+#[actix_web::test]
+async fn redirects_stay_inside_the_prefix() {
+    let board = board();
+    let thread = {
+        let form = Form::new().text("title", "Thread").text("message", "x");
+        board.send(form.request(&format!("{}/submit", PREFIX))).await;
+        board.find("Thread")
+    };
+    let form = Form::new().text("parent_id", &thread.id).text("title", "Reply").text("message", "y");
+    board.send(form.request(&format!("{}/submit", PREFIX))).await;
+    let reply = board.find("Reply");
+
+    for page in [format!("/post/{}", reply.id), format!("/post/{}/1", thread.id)] {
+        let res = board.get(&format!("{}{}", PREFIX, page)).await;
+        let location = res.headers.get(LOCATION).expect("no redirect").to_str().unwrap();
+        assert!(location.starts_with(&format!("{}/post/{}", PREFIX, thread.id)), "{}: {}", page, location);
+    }
+
+    // Nothing answers outside the prefix
+    assert_eq!(board.get("/").await.status, StatusCode::NOT_FOUND);
+    assert_eq!(board.get(&format!("/post/{}", thread.id)).await.status, StatusCode::NOT_FOUND);
+}
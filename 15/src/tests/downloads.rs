@@ -0,0 +1,163 @@
+// Names suggested for downloads, see filename.rs: cleaned of what file
+// systems refuse, cut short on grapheme boundaries, told apart within a
+// batch, and sent in the headers of the download routes. Attachments that
+// aren't media are shown with their name, size and type.
+
+use actix_web::http::header::CONTENT_DISPOSITION;
+use actix_web::http::StatusCode;
+
+use super::{admin_login, png, select, texts, Form, TestBoard};
+use crate::filename::{suggest_filename, Batch};
+
+const FAMILY: &str = "👨‍👩‍👧";
+
+#[test]
+fn emoji_stay_whole_in_the_name_and_become_underscores_in_ascii() {
+    let suggested = suggest_filename(&format!("🎉🎉 Party {}", FAMILY), "html");
+    assert_eq!(suggested.name, format!("🎉🎉 Party {}.html", FAMILY));
+    assert_eq!(suggested.ascii, "_ Party _.html");
+    let disposition = suggested.disposition();
+    assert!(disposition.starts_with("attachment; filename=\"_ Party _.html\"; filename*=UTF-8''%F0%9F%8E%89"));
+    assert!(disposition.is_ascii());
+
+    // Nothing ASCII to go on
+    assert_eq!(suggest_filename("🎉", "zip").ascii, "download.zip");
+}
+
+#[test]
+fn slashes_and_other_separators_are_replaced() {
+    let suggested = suggest_filename("../../etc/passwd", "txt");
+    assert_eq!(suggested.name, "_.._etc_passwd.txt");
+    assert_eq!(suggest_filename("a\\b:c*d?e<f>g|h", "txt").name, "a_b_c_d_e_f_g_h.txt");
+    // Extensions are letters and digits only
+    assert_eq!(suggest_filename("notes", "../TXT").name, "notes.txt");
+}
+
+#[test]
+fn quotes_cant_break_out_of_the_header() {
+    let suggested = suggest_filename("He said \"hi\"; it's fine", "txt");
+    assert_eq!(suggested.name, "He said _hi_; it's fine.txt");
+    assert_eq!(suggested.ascii, "He said _hi_ it's fine.txt");
+    assert_eq!(
+        suggested.disposition(),
+        "attachment; filename=\"He said _hi_ it's fine.txt\"; \
+         filename*=UTF-8''He%20said%20_hi_%3B%20it%27s%20fine.txt"
+    );
+}
+
+#[test]
+fn long_titles_are_cut_between_graphemes() {
+    let suggested = suggest_filename(&"a".repeat(500), "txt");
+    assert_eq!(suggested.name, format!("{}.txt", "a".repeat(76)));
+
+    let suggested = suggest_filename(&"é".repeat(500), "txt");
+    assert_eq!(suggested.name, format!("{}.txt", "é".repeat(38)));
+    assert_eq!(suggested.ascii, "download.txt");
+
+    // 18 bytes a family, which mustn't lose a member
+    let suggested = suggest_filename(&FAMILY.repeat(500), "zip");
+    assert_eq!(suggested.name, format!("{}.zip", FAMILY.repeat(4)));
+    assert!(suggested.name.len() <= 80);
+}
+
+#[test]
+fn names_windows_keeps_for_devices_are_prefixed() {
+    assert_eq!(suggest_filename("con", "txt").name, "_con.txt");
+    assert_eq!(suggest_filename("LPT1.tar", "gz").name, "_LPT1.tar.gz");
+    assert_eq!(suggest_filename("console", "txt").name, "console.txt");
+}
+
+#[test]
+fn empty_and_dotted_titles_fall_back() {
+    assert_eq!(suggest_filename("", "zip").name, "download.zip");
+    assert_eq!(suggest_filename(" .. ", "zip").name, "download.zip");
+    assert_eq!(suggest_filename(".hidden. ", "").name, "hidden");
+    assert_eq!(suggest_filename("tab\there\nand \u{202E}there", "txt").name, "tab here and there.txt");
+}
+
+#[test]
+fn a_batch_numbers_names_that_would_clash() {
+    let mut batch = Batch::default();
+    assert_eq!(batch.suggest("Notes", "txt").name, "Notes.txt");
+    assert_eq!(batch.suggest("Notes", "txt").name, "Notes-2.txt");
+    assert_eq!(batch.suggest("NOTES", "TXT").name, "NOTES-3.txt");
+    assert_eq!(batch.suggest("Notes", "md").name, "Notes.md");
+
+    // The suffix fits inside the limit too
+    let long = "a".repeat(500);
+    batch.suggest(&long, "txt");
+    let second = batch.suggest(&long, "txt").name;
+    assert_eq!(second, format!("{}-2.txt", "a".repeat(74)));
+}
+
+#[actix_web::test]
+async fn files_download_under_a_clean_original_name() {
+    let board = TestBoard::with(|config| config.allowed_extensions.push("txt".to_string()));
+    let form = Form::new()
+        .text("title", "Paper")
+        .text("message", "attached")
+        .file("file", "résumé 🎉.txt", "text/plain", b"hello");
+    assert_eq!(board.submit(form).await.status, StatusCode::SEE_OTHER);
+    let file = board.find("Paper").file.unwrap();
+
+    let res = board.get(&format!("/static/uploads/{}", file)).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.headers.get(CONTENT_DISPOSITION).unwrap(),
+        "attachment; filename=\"r_sum_ _.txt\"; filename*=UTF-8''r%C3%A9sum%C3%A9%20%F0%9F%8E%89.txt"
+    );
+}
+
+#[actix_web::test]
+async fn a_name_with_quotes_semicolons_and_accents_survives_the_page_and_header() {
+    let board = TestBoard::with(|config| config.allowed_extensions.push("txt".to_string()));
+    let name = "Q3 \"final\"; naïve 文書.txt";
+    let form = Form::new().text("title", "Report").text("message", "attached").file("file", name, "text/plain", b"hello");
+    assert_eq!(board.submit(form).await.status, StatusCode::SEE_OTHER);
+    let post = board.find("Report");
+    assert_eq!(post.original_name.as_deref(), Some(name));
+
+    // Shown as it was uploaded, escaped rather than cut into markup
+    let html = board.get(&format!("/post/{}", post.id)).await.html();
+    let link = format!("a[href=\"/static/uploads/{}\"]", post.file.as_ref().unwrap());
+    assert_eq!(texts(&html, &link), vec![name]);
+    assert_eq!(texts(&html, ".file-download .chip"), vec!["TXT"]);
+    assert_eq!(texts(&html, ".file-download .muted"), vec![board.config.human_size(5)]);
+    assert!(select(&html, "img, video, audio").is_empty());
+
+    let res = board.get(&format!("/static/uploads/{}", post.file.unwrap())).await;
+    assert_eq!(res.body, "hello");
+    assert_eq!(
+        res.headers.get(CONTENT_DISPOSITION).unwrap(),
+        "attachment; filename=\"Q3 _final_ na_ve _.txt\"; \
+         filename*=UTF-8''Q3%20_final_%3B%20na%C3%AFve%20%E6%96%87%E6%9B%B8.txt"
+    );
+}
+
+#[actix_web::test]
+async fn dossiers_and_backups_are_named_for_what_they_hold() {
+    let board = TestBoard::with(|config| config.admin_password = Some("secret".to_string()));
+    let admin = admin_login(&board).await;
+    let form = Form::new()
+        .text("title", "Cats/dogs")
+        .text("message", "Start")
+        .file("file", "dossier.json.png", "image/png", &png(16));
+    assert_eq!(board.submit(form).await.status, StatusCode::SEE_OTHER);
+    let thread = board.find("Cats/dogs");
+
+    let res = board.send(admin.get(&format!("/admin/post/{}/dossier.zip", thread.id))).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.headers.get(CONTENT_DISPOSITION).unwrap(),
+        "attachment; filename=\"Cats_dogs dossier.zip\"; filename*=UTF-8''Cats_dogs%20dossier.zip"
+    );
+    // The upload keeps its name in the zip
+    assert!(res.body.contains("dossier.json"));
+    assert!(res.body.contains("dossier.json.png"));
+
+    let res = board.send(admin.get("/admin/export/stream")).await;
+    assert_eq!(res.status, StatusCode::OK);
+    let disposition = res.headers.get(CONTENT_DISPOSITION).unwrap().to_str().unwrap();
+    assert!(disposition.starts_with("attachment; filename=\"backup to "), "{}", disposition);
+    assert!(disposition.ends_with(".ndjson"), "{}", disposition);
+}
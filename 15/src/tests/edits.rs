@@ -0,0 +1,49 @@
+// Moderator edits and their history, see edits.rs: version numbers under
+// concurrent edits, and what's left of a history when the post goes.
+
+use std::thread;
+
+use super::TestBoard;
+use crate::{edits, storage};
+
+#[actix_web::test]
+async fn concurrent_edits_each_get_their_own_version() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Hello").await;
+    let reply = board.reply(&thread, "Reply", "As posted").await;
+
+    thread::scope(|scope| {
+        for i in 0..8 {
+            let (db, id) = (&board.db, &reply.id);
+            scope.spawn(move || edits::edit(db, id, &format!("Edit {}", i), "admin", None).unwrap());
+        }
+    });
+
+    let history = edits::stored(&board.db, &reply.id);
+    let numbers: Vec<u64> = history.iter().map(|version| version.version).collect();
+    assert_eq!(numbers, (0..=8).collect::<Vec<_>>());
+    assert_eq!(history[0].message, "As posted");
+    let mut messages: Vec<&str> = history[1..].iter().map(|version| version.message.as_str()).collect();
+    messages.sort();
+    assert_eq!(messages, (0..8).map(|i| format!("Edit {}", i)).collect::<Vec<_>>());
+    assert_eq!(board.find("Reply").message, history[8].message);
+}
+
+#[actix_web::test]
+async fn an_edit_racing_a_delete_leaves_no_history() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Hello").await;
+    for i in 0..20 {
+        let reply = board.reply(&thread, &format!("Reply {}", i), "As posted").await;
+        thread::scope(|scope| {
+            let (db, id) = (&board.db, &reply.id);
+            scope.spawn(move || {
+                edits::edit(db, id, "First edit", "admin", None);
+                edits::edit(db, id, "Second edit", "admin", None);
+            });
+            storage::delete_post(&board.db, &board.config, &reply.id);
+        });
+        assert!(edits::stored(&board.db, &reply.id).is_empty(), "reply {}", i);
+        assert_eq!(board.db.open_tree("edit_versions").unwrap().get(&reply.id).unwrap(), None);
+    }
+}
@@ -0,0 +1,196 @@
+// Board events, see events.rs: each change publishes one event once it's
+// stored, and changes that don't happen (refused posts, replayed forms,
+// edits that change nothing, posts that aren't there) publish none. A
+// handler that fails or panics doesn't keep the event from the others.
+
+use actix_web::http::StatusCode;
+use actix_web::rt::time;
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{admin_login, Form, TestBoard};
+use crate::events::{BoardEvent, Delivery, EventHandler};
+use crate::pending;
+
+// Writes down every event it's given, as "created {id}", "edited {id}" or
+// "deleted {ids}"
+#[derive(Clone)]
+struct Recorder {
+    name: &'static str,
+    delivery: Delivery,
+    seen: Arc<Mutex<Vec<String>>>,
+}
+
+impl Recorder {
+    fn new(name: &'static str, delivery: Delivery) -> Recorder {
+        Recorder {
+            name,
+            delivery,
+            seen: Arc::default(),
+        }
+    }
+
+    fn seen(&self) -> Vec<String> {
+        self.seen.lock().unwrap().clone()
+    }
+}
+
+impl EventHandler for Recorder {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn delivery(&self) -> Delivery {
+        self.delivery
+    }
+
+    fn handle(&self, event: &BoardEvent) -> Result<(), String> {
+        let seen = match event {
+            BoardEvent::Created { post, .. } => format!("created {}", post.id),
+            BoardEvent::Edited { post_id } => format!("edited {}", post_id),
+            BoardEvent::Deleted { posts } => {
+                let mut ids: Vec<&str> = posts.iter().map(|post| post.id.as_str()).collect();
+                ids.sort_unstable();
+                format!("deleted {}", ids.join(" "))
+            }
+        };
+        self.seen.lock().unwrap().push(seen);
+        Ok(())
+    }
+}
+
+struct Failing;
+
+impl EventHandler for Failing {
+    fn name(&self) -> &'static str {
+        "failing"
+    }
+
+    fn handle(&self, _: &BoardEvent) -> Result<(), String> {
+        Err("always fails".to_string())
+    }
+}
+
+struct Panicking;
+
+impl EventHandler for Panicking {
+    fn name(&self) -> &'static str {
+        "panicking"
+    }
+
+    fn handle(&self, _: &BoardEvent) -> Result<(), String> {
+        panic!("always panics");
+    }
+}
+
+fn recording(board: &mut TestBoard) -> Recorder {
+    let recorder = Recorder::new("recorder", Delivery::Inline);
+    board.listen(vec![Box::new(recorder.clone())]);
+    recorder
+}
+
+fn admin_board() -> TestBoard {
+    TestBoard::with(|config| config.admin_password = Some("secret".to_string()))
+}
+
+#[actix_web::test]
+async fn each_post_is_created_once() {
+    let mut board = TestBoard::new();
+    let recorder = recording(&mut board);
+    let thread = board.thread("Thread", "Start").await;
+    let reply = board.reply(&thread, "Reply", "More").await;
+
+    // Refused before it's stored
+    let res = board.submit(Form::new().text("title", "Empty").text("message", "")).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", res.body);
+    // The same form twice is one post
+    let form = || Form::with_token("twice").text("parent_id", &thread.id).text("title", "Twice").text("message", "Again");
+    assert_eq!(board.submit(form()).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(board.submit(form()).await.status, StatusCode::SEE_OTHER);
+    let twice = board.find("Twice");
+
+    assert_eq!(recorder.seen(), [format!("created {}", thread.id), format!("created {}", reply.id), format!("created {}", twice.id)]);
+}
+
+#[actix_web::test]
+async fn each_moderator_change_is_published_once() {
+    let mut board = admin_board();
+    let recorder = recording(&mut board);
+    let admin = admin_login(&board).await;
+    let thread = board.thread("Thread", "Start").await;
+    let first = board.reply(&thread, "First", "One").await;
+    let second = board.reply(&thread, "Second", "Two").await;
+    let created = recorder.seen().len();
+
+    let edit = |id: &str, message: &str| admin.post(&format!("/admin/post/{}/edit", id)).set_form([("message", message)]);
+    assert_eq!(board.send(edit(&first.id, "One")).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(board.send(edit(&first.id, "Won")).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(board.send(edit("gone", "Won")).await.status, StatusCode::NOT_FOUND);
+    let restore = admin.post(&format!("/admin/post/{}/restore/0", first.id));
+    assert_eq!(board.send(restore).await.status, StatusCode::SEE_OTHER);
+    let slow = |id: &str| admin.post(&format!("/admin/post/{}/slow-mode", id)).set_form([("secs", "30")]);
+    assert_eq!(board.send(slow(&thread.id)).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(board.send(slow(&first.id)).await.status, StatusCode::NOT_FOUND);
+    let delete = || admin.post(&format!("/admin/post/{}/delete-thread", thread.id));
+    assert_eq!(board.send(delete()).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(board.send(delete()).await.status, StatusCode::NOT_FOUND);
+
+    let mut ids = [thread.id.as_str(), first.id.as_str(), second.id.as_str()];
+    ids.sort_unstable();
+    let expected = [
+        format!("edited {}", first.id),
+        format!("edited {}", first.id),
+        format!("edited {}", thread.id),
+        format!("deleted {}", ids.join(" ")),
+    ];
+    assert_eq!(recorder.seen()[created..], expected);
+}
+
+#[actix_web::test]
+async fn a_held_post_is_created_when_approved_and_only_then() {
+    let mut board = TestBoard::with(|config| {
+        config.admin_password = Some("secret".to_string());
+        config.approval_queue = true;
+    });
+    let recorder = recording(&mut board);
+    let admin = admin_login(&board).await;
+    let res = board.submit(Form::new().text("title", "Held").text("message", "Wait")).await;
+    assert_eq!(res.status, StatusCode::ACCEPTED, "{}", res.body);
+    assert!(recorder.seen().is_empty());
+
+    let held = pending::list(&board.db).remove(0);
+    let approve = || admin.post(&format!("/admin/pending/{}/approve", held.id));
+    assert_eq!(board.send(approve()).await.status, StatusCode::SEE_OTHER);
+    board.send(approve()).await;
+    assert_eq!(recorder.seen(), [format!("created {}", held.id)]);
+}
+
+#[actix_web::test]
+async fn failing_handlers_keep_nothing_from_the_others() {
+    let mut board = TestBoard::new();
+    let inline = Recorder::new("inline", Delivery::Inline);
+    let queued = Recorder::new("queued", Delivery::Queued);
+    board.listen(vec![Box::new(Failing), Box::new(Panicking), Box::new(inline.clone()), Box::new(queued.clone())]);
+    let thread = board.thread("Thread", "Start").await;
+    let expected = [format!("created {}", thread.id)];
+
+    // Inline handlers are done before the response goes out
+    assert_eq!(inline.seen(), expected);
+    for _ in 0..200 {
+        if !queued.seen().is_empty() {
+            break;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(queued.seen(), expected);
+
+    let json: Value = serde_json::from_str(&board.get("/readyz").await.body).unwrap();
+    let events = &json["events"];
+    for (name, handled, failed) in [("failing", 0, 1), ("panicking", 0, 1), ("inline", 1, 0), ("queued", 1, 0)].iter() {
+        assert_eq!(events[name]["handled"], *handled, "{}", name);
+        assert_eq!(events[name]["failed"], *failed, "{}", name);
+        assert_eq!(events[name]["dropped"], 0, "{}", name);
+    }
+}
@@ -0,0 +1,133 @@
+// A brand-new board, see setup.rs: every public page answers 200 with
+// something to say about being empty, and the admin panel lists what to
+// set up until that's dismissed.
+
+use actix_web::http::StatusCode;
+use scraper::Html;
+use serde_json::{json, Value};
+
+use super::{admin_login, select, texts, TestBoard};
+use crate::settings::BoardSettings;
+use crate::setup;
+
+async fn page(board: &TestBoard, path: &str) -> Html {
+    let res = board.get(path).await;
+    assert_eq!(res.status, StatusCode::OK, "{}: {}", path, res.body);
+    Html::parse_document(&res.body)
+}
+
+async fn json(board: &TestBoard, path: &str) -> Value {
+    let res = board.get(path).await;
+    assert_eq!(res.status, StatusCode::OK, "{}: {}", path, res.body);
+    serde_json::from_str(&res.body).unwrap_or_else(|e| panic!("{}: {} in {}", path, e, res.body))
+}
+
+#[actix_web::test]
+async fn every_public_route_answers_an_empty_board() {
+    let board = TestBoard::new();
+    assert!(board.db.open_tree("posts_by_id").unwrap().is_empty());
+
+    let index = page(&board, "/").await;
+    assert_eq!(texts(&index, ".empty-board"), ["No threads yet. Start the first one with the form above."]);
+    assert!(select(&index, ".pagination-links a").is_empty());
+    assert_eq!(select(&index, "form[action=\"/submit\"]").len(), 1);
+    assert_eq!(texts(&page(&board, "/archive").await, ".empty-board"), ["Nothing archived here."]);
+    assert_eq!(texts(&page(&board, "/archive/2024").await, ".empty-board"), ["Nothing archived here."]);
+    assert_eq!(texts(&page(&board, "/archive/2024/06").await, ".empty-board"), ["Nothing archived here."]);
+    assert_eq!(texts(&page(&board, "/stats").await, ".empty-board"), ["Nothing has been posted yet."]);
+    assert!(select(&page(&board, "/widget").await, "a").is_empty());
+
+    assert_eq!(json(&board, "/api/threads").await, json!({"threads": [], "next_cursor": null}));
+    assert_eq!(json(&board, "/widget.json").await, json!({"threads": []}));
+    let stats = json(&board, "/stats.json").await;
+    assert!(stats.is_object(), "{}", stats);
+    let manifest = json(&board, "/.well-known/board.json").await;
+    assert_eq!(manifest["posts"], json!({"threads": 0, "archived_threads": 0, "replies": 0}));
+    json(&board, "/api/posts?ids=nothing").await;
+    for path in ["/readyz", "/theme.css", "/static/style.css"].iter() {
+        assert_eq!(board.get(path).await.status, StatusCode::OK, "{}", path);
+    }
+
+    // and what isn't there is a 404, not an error
+    for path in ["/post/nothing", "/post/nothing/1", "/api/post/nothing", "/fragment/post/nothing"].iter() {
+        assert_eq!(board.get(path).await.status, StatusCode::NOT_FOUND, "{}", path);
+    }
+}
+
+#[actix_web::test]
+async fn a_locked_empty_board_doesnt_invite_posts() {
+    let board = TestBoard::new();
+    let settings = BoardSettings {
+        locked: true,
+        ..BoardSettings::default()
+    };
+    board.state.settings.save(&board.db, settings);
+    assert_eq!(texts(&page(&board, "/").await, ".empty-board"), ["No threads yet."]);
+    // A sticky thread is enough not to be empty
+    let board = TestBoard::new();
+    let thread = board.thread("Rules", "Read me").await;
+    crate::indexes::set_sticky(&board.db, &thread.id, true);
+    assert!(texts(&page(&board, "/").await, ".empty-board").is_empty());
+}
+
+#[actix_web::test]
+async fn a_new_board_lists_what_to_set_up_until_dismissed() {
+    let board = TestBoard::with(|config| config.admin_password = Some("secret".to_string()));
+    assert!(setup::pending(&board.db));
+    let admin = admin_login(&board).await;
+    let checklist = |html: &Html| -> Vec<(String, bool)> {
+        select(html, ".setup-checklist li")
+            .iter()
+            .map(|step| {
+                let done = step.value().attr("class") == Some("done");
+                let detail: String = step
+                    .select(&scraper::Selector::parse(".muted").unwrap())
+                    .flat_map(|span| span.text())
+                    .collect();
+                let text: String = step.text().collect();
+                (text.replace(&detail, "").split_whitespace().collect::<Vec<_>>().join(" "), done)
+            })
+            .collect()
+    };
+    let html = Html::parse_document(&board.send(admin.get("/admin/posts")).await.body);
+    assert_eq!(
+        checklist(&html),
+        [("✓ Set an admin password".to_string(), true), ("☐ Set upload limits".to_string(), false), ("☐ Write the rules".to_string(), false)]
+    );
+
+    // Ticked off as it's done
+    let settings = BoardSettings {
+        description: "Be nice.".to_string(),
+        ..BoardSettings::default()
+    };
+    board.state.settings.save(&board.db, settings);
+    let html = Html::parse_document(&board.send(admin.get("/admin/posts")).await.body);
+    assert!(checklist(&html)[2].1);
+
+    // Dismissed for good
+    assert_eq!(board.send(admin.post("/admin/setup/dismiss")).await.location(), "/admin/posts");
+    assert!(!setup::pending(&board.db));
+    let html = Html::parse_document(&board.send(admin.get("/admin/posts")).await.body);
+    assert!(select(&html, ".setup-checklist").is_empty());
+    // Without the token it isn't
+    let board = TestBoard::with(|config| config.admin_password = Some("secret".to_string()));
+    let admin = admin_login(&board).await;
+    let req = actix_web::test::TestRequest::post().uri("/admin/setup/dismiss").insert_header((actix_web::http::header::COOKIE, admin.cookie.as_str()));
+    assert_ne!(board.send(req).await.status, StatusCode::SEE_OTHER);
+    assert!(setup::pending(&board.db));
+}
+
+#[test]
+fn only_a_new_database_is_marked() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = sled::open(dir.path()).unwrap();
+    setup::mark_if_new(&db);
+    assert!(setup::pending(&db));
+    db.open_tree("meta").unwrap().remove("setup_pending").unwrap();
+    db.flush().unwrap();
+    drop(db);
+    // Opened again, as after a restart, it's an existing board
+    let db = sled::open(dir.path()).unwrap();
+    setup::mark_if_new(&db);
+    assert!(!setup::pending(&db));
+}
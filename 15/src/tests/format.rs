@@ -0,0 +1,204 @@
+// Message formatting, see format.rs: links, quotes and the escaping around
+// them, and the breaks put into long words, checked on the HTML as a
+// browser would parse it. The plain text version has none of that markup
+// or escaping, wherever it's shown.
+
+use proptest::prelude::*;
+use scraper::Html;
+
+use super::{attrs, select, texts, TestBoard};
+use crate::format::{find_quotes, find_urls, format_message, format_message_plain};
+use crate::{archive, now};
+
+// The text a browser shows for a formatted message, which should be the
+// message as it was written
+fn shown(html: &Html) -> String {
+    html.root_element().text().collect()
+}
+
+#[test]
+fn quotes_and_brackets_around_a_url_stay_outside_the_link() {
+    let cases = [
+        ("\"https://a.example/x\"", "https://a.example/x"),
+        ("'https://a.example/x'", "https://a.example/x"),
+        ("<https://a.example/x>", "https://a.example/x"),
+        ("https://a.example/x\"onmouseover=\"alert(1)", "https://a.example/x"),
+        ("https://a.example/x'><script>alert(1)</script>", "https://a.example/x"),
+        ("(see https://a.example/x).", "https://a.example/x"),
+        // Written out entities are text like any other
+        ("&lt;https://a.example/?a=1&b=2&gt;", "https://a.example/?a=1&b=2&gt"),
+    ];
+    for (message, url) in cases {
+        let html = Html::parse_fragment(&format_message(message));
+        assert_eq!(attrs(&html, "a", "href"), vec![url], "{}", message);
+        assert_eq!(select(&html, "script").len(), 0, "{}", message);
+        assert_eq!(attrs(&html, "a", "onmouseover").len(), 0, "{}", message);
+        assert_eq!(shown(&html), message);
+    }
+}
+
+#[test]
+fn only_http_and_https_are_linked() {
+    for message in ["javascript:alert(1)", "data:text/html,<b>x</b>", "xhttps://a.example", "https://", "ftp://a.example"] {
+        let html = Html::parse_fragment(&format_message(message));
+        assert_eq!(select(&html, "a").len(), 0, "{}", message);
+        assert_eq!(select(&html, "b").len(), 0, "{}", message);
+        assert_eq!(shown(&html), message);
+    }
+}
+
+// Text around the URL: anything but letters and digits straight before it,
+// so it starts a link, and a character that ends a URL straight after it
+fn before() -> impl Strategy<Value = String> {
+    "[\"'<>& .,;()\\[\\]=!\n]{0,12}"
+}
+
+fn after() -> impl Strategy<Value = String> {
+    "[\"'<> \n`][\"'<>&x .,;()=!\n`]{0,12}"
+}
+
+fn url() -> impl Strategy<Value = String> {
+    "https?://[a-z0-9]{1,10}\\.example(/[a-z0-9_=&%#?-]{0,20})?[a-z0-9/]".prop_map(String::from)
+}
+
+proptest! {
+    #[test]
+    fn a_url_among_quotes_and_brackets_is_one_link(before in before(), url in url(), after in after()) {
+        let message = format!("{}{}{}", before, url, after);
+        let html = Html::parse_fragment(&format_message(&message));
+        prop_assert_eq!(attrs(&html, "a", "href"), vec![url.clone()]);
+        prop_assert_eq!(attrs(&html, "a", "rel"), vec!["nofollow noopener".to_string()]);
+        // Nothing else became markup, and nothing was lost
+        let elements: Vec<_> = select(&html, "body *, html > *").iter().map(|element| element.value().name().to_string()).collect();
+        prop_assert!(elements.iter().all(|name| matches!(name.as_str(), "a" | "wbr" | "html" | "head" | "body")), "{:?}", elements);
+        prop_assert_eq!(shown(&html), message);
+    }
+}
+
+// The formatted message cut at its break opportunities
+fn pieces(message: &str) -> Vec<String> {
+    format_message(message).split("<wbr>").map(str::to_string).collect()
+}
+
+#[test]
+fn short_words_are_left_alone() {
+    let message = format!("hello & <world> {} {}", "a".repeat(80), "é".repeat(80));
+    assert!(!format_message(&message).contains("<wbr>"));
+    assert_eq!(pieces(&"a".repeat(81)), vec!["a".repeat(80), "a".to_string()]);
+    // Whitespace starts the count again
+    let words = format!("{} {}", "a".repeat(60), "b".repeat(60));
+    assert!(!format_message(&words).contains("<wbr>"));
+}
+
+#[test]
+fn breaks_never_split_a_character_or_an_entity() {
+    assert_eq!(pieces(&"é".repeat(200)), vec!["é".repeat(80), "é".repeat(80), "é".repeat(40)]);
+    assert_eq!(pieces(&"😀".repeat(81)), vec!["😀".repeat(80), "😀".to_string()]);
+    assert_eq!(pieces(&"&".repeat(161)), vec!["&amp;".repeat(80), "&amp;".repeat(80), "&amp;".to_string()]);
+    assert_eq!(pieces(&"<".repeat(90)), vec!["&lt;".repeat(80), "&lt;".repeat(10)]);
+
+    let message = "a&é<😀\"".repeat(50);
+    let html = Html::parse_fragment(&format_message(&message));
+    assert_eq!(shown(&html), message);
+    assert_eq!(select(&html, "wbr").len(), 3);
+}
+
+#[test]
+fn links_keep_their_own_text() {
+    let url = format!("https://a.example/{}", "x".repeat(200));
+    let message = format!("{} {}", "w".repeat(100), url);
+    let html = Html::parse_fragment(&format_message(&message));
+    assert_eq!(attrs(&html, "a", "href"), vec![url]);
+    assert_eq!(select(&html, "a wbr").len(), 0);
+    assert_eq!(select(&html, "wbr").len(), 1);
+}
+
+proptest! {
+    #[test]
+    fn no_run_outlasts_the_limit(message in "[a&<é😀 ]{0,400}") {
+        let html = Html::parse_fragment(&format_message(&message));
+        prop_assert_eq!(shown(&html), message.clone());
+        for piece in pieces(&message) {
+            let piece = Html::parse_fragment(&piece);
+            let longest = shown(&piece).split(' ').map(|run| run.chars().count()).max().unwrap_or(0);
+            prop_assert!(longest <= 80, "{}", longest);
+        }
+    }
+}
+
+#[test]
+fn plain_text_keeps_what_was_typed_without_markup() {
+    let cases = [
+        ("<b>bold</b> & &amp; &lt;", "<b>bold</b> & &amp; &lt;"),
+        ("see >>12, (>>3) and >>>13", "see »12, (»3) and >>>13"),
+        (">>99999999999999999999", ">>99999999999999999999"),
+        (">green\n>>1\n\ttabbed", ">green\n»1\n\ttabbed"),
+        ("https://a.example/?a=1&b=2<script>", "https://a.example/?a=1&b=2<script>"),
+        ("\"https://a.example/x\"onmouseover=\"alert(1)", "\"https://a.example/x\"onmouseover=\"alert(1)"),
+        ("nul\0 esc\x1b cr\r\nnext\u{85}\u{7f}", "nul esc cr\nnext"),
+        ("trailing\n\n  ", "trailing"),
+    ];
+    for (message, plain) in cases.iter() {
+        assert_eq!(format_message_plain(message, 500), *plain, "{:?}", message);
+    }
+}
+
+#[test]
+fn plain_text_is_cut_without_breaks() {
+    let word = "a".repeat(500);
+    assert_eq!(format_message_plain(&word, 200), format!("{}…", "a".repeat(199)));
+    assert_eq!(format_message_plain(&"&".repeat(300), 10), format!("{}…", "&".repeat(9)));
+    let url = format!("https://a.example/{}", "x".repeat(200));
+    assert_eq!(format_message_plain(&url, 500), url);
+    assert_eq!(format_message_plain("", 10), "");
+}
+
+proptest! {
+    #[test]
+    fn plain_text_never_gains_markup_or_entities(
+        message in "([a&<>é;\n\t\x00\x1b ]|>>[0-9]{1,3}|https://a\\.example/[a&<]{0,5}){0,60}",
+        max in 1usize..300,
+    ) {
+        let whole = format_message_plain(&message, usize::MAX);
+        // Only what was typed, less the control characters and the space at
+        // the end
+        let typed: String = message.chars().filter(|&c| !c.is_control() || c == '\n' || c == '\t').collect();
+        let typed = typed.trim_end();
+        for artifact in ["<", "&", "&amp;", "&lt;", "<wbr>", "<a "].iter() {
+            prop_assert_eq!(whole.matches(artifact).count(), typed.matches(artifact).count(), "{}", artifact);
+        }
+        prop_assert!(whole.chars().all(|c| !c.is_control() || c == '\n' || c == '\t'), "{:?}", whole);
+        // The same links and quotes as the HTML
+        let html = Html::parse_fragment(&format_message(&message));
+        let urls: Vec<String> = find_urls(&message).into_iter().map(|range| message[range].to_string()).collect();
+        prop_assert_eq!(attrs(&html, "a", "href"), urls.clone());
+        for url in &urls {
+            prop_assert!(whole.contains(url.as_str()), "{}", url);
+        }
+        for number in find_quotes(&message) {
+            let quote = format!("»{}", number);
+            prop_assert!(whole.contains(&quote), "{}", quote);
+        }
+        let cut = format_message_plain(&message, max);
+        prop_assert!(cut.chars().count() <= max);
+        prop_assert!(cut.matches('<').count() <= message.matches('<').count());
+    }
+}
+
+#[actix_web::test]
+async fn excerpts_show_the_plain_text_escaped_once() {
+    let board = TestBoard::with(|config| config.admin_password = Some("secret".to_string()));
+    let message = "<i>hi</i> &amp; >>1 https://a.example/?a=1&b=2";
+    let plain = "<i>hi</i> &amp; »1 https://a.example/?a=1&b=2";
+    let thread = board.thread("Quoted", message).await;
+    let admin = super::admin_login(&board).await;
+    let html = Html::parse_document(&board.send(admin.get("/admin/posts")).await.body);
+    assert_eq!(texts(&html, ".excerpt"), [plain]);
+    assert!(select(&html, ".excerpt i").is_empty());
+
+    assert!(archive::archive(&board.db, &thread.id, now()));
+    let (year, month, _) = crate::format::civil_date(now());
+    let html = board.get(&format!("/archive/{}/{:02}", year, month)).await.html();
+    assert_eq!(texts(&html, ".excerpt"), [plain]);
+    assert!(select(&html, ".excerpt i, .excerpt a").is_empty());
+}
@@ -0,0 +1,75 @@
+// HEAD requests, see head.rs: each kind of GET route answers HEAD with the
+// headers GET gets and the size its body would have, but no body.
+
+use actix_web::body::BodySize;
+use actix_web::http::header::{HeaderMap, ETAG, IF_NONE_MATCH};
+use actix_web::http::{Method, StatusCode};
+use actix_web::test::TestRequest;
+
+use super::{admin_login, png, Form, Response, TestBoard};
+
+fn listed(headers: &HeaderMap) -> Vec<(String, String)> {
+    let mut listed: Vec<(String, String)> = headers.iter().map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string())).collect();
+    listed.sort();
+    listed
+}
+
+// GET and HEAD of the same request, checked against each other
+async fn same_as_get(board: &TestBoard, get: impl Fn() -> TestRequest) -> (Response, Response) {
+    let full = board.send(get()).await;
+    let head = board.send(get().method(Method::HEAD)).await;
+    let path = get().to_http_request().uri().to_string();
+    assert_eq!(head.status, full.status, "{}", path);
+    assert_eq!(listed(&head.headers), listed(&full.headers), "{}", path);
+    assert_eq!(head.size, full.size, "{}", path);
+    assert_eq!(head.body, "", "{}", path);
+    (full, head)
+}
+
+#[actix_web::test]
+async fn every_kind_of_route_answers_head_like_get() {
+    let board = TestBoard::with(|config| config.admin_password = Some("secret".to_string()));
+    let form = Form::new().text("title", "Thread").text("message", "Start").file("file", "pic.png", "image/png", &png(8));
+    board.submit(form).await;
+    let thread = board.find("Thread");
+    let reply = board.reply(&thread, "Reply", "One").await;
+    let upload = board.config.upload_url(thread.file.as_deref().unwrap());
+
+    let sized = [
+        format!("/post/{}", thread.id),
+        upload,
+        "/static/style.css".to_string(),
+        "/api/threads".to_string(),
+        "/stats.json".to_string(),
+        "/readyz".to_string(),
+    ];
+    for path in &sized {
+        let (full, _) = same_as_get(&board, || TestRequest::get().uri(path)).await;
+        assert_eq!(full.status, StatusCode::OK, "{}", path);
+        assert!(matches!(full.size, BodySize::Sized(size) if size > 0), "{}: {:?}", path, full.size);
+    }
+
+    // The index is streamed, so neither knows its length up front
+    let (full, _) = same_as_get(&board, || TestRequest::get().uri("/")).await;
+    assert_eq!((full.status, full.size), (StatusCode::OK, BodySize::Stream));
+
+    // Redirects, missing pages and admin pages too
+    let (full, _) = same_as_get(&board, || TestRequest::get().uri(&format!("/post/{}", reply.id))).await;
+    assert_eq!(full.status, StatusCode::FOUND);
+    let (full, _) = same_as_get(&board, || TestRequest::get().uri("/post/nothing-here")).await;
+    assert_eq!(full.status, StatusCode::NOT_FOUND);
+    let admin = admin_login(&board).await;
+    let (full, _) = same_as_get(&board, || admin.get("/admin/posts")).await;
+    assert_eq!(full.status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn conditional_head_on_an_upload_uses_its_etag() {
+    let board = TestBoard::new();
+    let form = Form::new().text("title", "Thread").text("message", "Start").file("file", "pic.png", "image/png", &png(8));
+    board.submit(form).await;
+    let upload = board.config.upload_url(board.find("Thread").file.as_deref().unwrap());
+    let etag = board.get(&upload).await.headers.get(ETAG).expect("no ETag").clone();
+    let (full, _) = same_as_get(&board, || TestRequest::get().uri(&upload).insert_header((IF_NONE_MATCH, etag.clone()))).await;
+    assert_eq!(full.status, StatusCode::NOT_MODIFIED);
+}
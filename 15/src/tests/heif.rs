@@ -0,0 +1,150 @@
+// HEIC/HEIF photos, see upload.rs: refused with a way out when no
+// HEIF_CONVERTER is set, and otherwise stored as the converter's JPEG with
+// nothing else left behind. The converter here is a shell command copying
+// a JPEG fixture into place, or one that fails, so the tests don't need a
+// real HEIF decoder; they run wherever there's a `sh`.
+
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::StatusCode;
+use image::{ImageBuffer, ImageOutputFormat, Rgb};
+use serde_json::{json, Value};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+use super::{attrs, Form, TestBoard};
+
+// The start of an iPhone photo: an ISO media ftyp box with the HEIC brands.
+// The fake converters never read past it.
+const HEIC: &[u8] = b"\0\0\0\x18ftypheic\0\0\0\0mif1heic";
+
+// What the converter "makes" of the photo
+struct Fixture {
+    jpeg: Vec<u8>,
+    // Holds the JPEG the converter copies
+    dir: TempDir,
+}
+
+impl Fixture {
+    fn new() -> Fixture {
+        let image = ImageBuffer::from_fn(48, 32, |x, y| Rgb([(x * 5) as u8, (y * 7) as u8, 90u8]));
+        let mut jpeg = Cursor::new(Vec::new());
+        image.write_to(&mut jpeg, ImageOutputFormat::Jpeg(90)).unwrap();
+        let fixture = Fixture {
+            jpeg: jpeg.into_inner(),
+            dir: tempfile::tempdir().unwrap(),
+        };
+        std::fs::write(fixture.path(), &fixture.jpeg).unwrap();
+        fixture
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.path().join("converted.jpg")
+    }
+
+    // Run as "{command} {input} {output}", so the input is $0 and the
+    // output $1
+    fn converter(&self) -> Vec<String> {
+        let script = format!("cp '{}' \"$1\"", self.path().display());
+        vec!["sh".to_string(), "-c".to_string(), script]
+    }
+}
+
+fn photo(title: &str, name: &str) -> Form {
+    Form::new().text("title", title).text("message", "From my phone").file("file", name, "image/heic", HEIC)
+}
+
+// Every file under the upload directory, temporary ones included
+fn stored_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(stored_files(&path));
+        } else {
+            files.push(path);
+        }
+    }
+    files
+}
+
+async fn refused_file(board: &TestBoard, form: Form) -> Value {
+    let res = board.submit(form.json()).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", res.body);
+    let body: Value = serde_json::from_str(&res.body).unwrap();
+    assert_eq!(body["fields"].as_array().unwrap().len(), 1, "{}", body);
+    body["fields"][0].clone()
+}
+
+async fn accepted_types(board: &TestBoard) -> String {
+    attrs(&board.get("/").await.html(), "input[type=file]", "accept").remove(0)
+}
+
+#[actix_web::test]
+async fn without_a_converter_heic_is_refused_with_a_way_out() {
+    let board = TestBoard::new();
+    let field = refused_file(&board, photo("Phone", "IMG_0001.HEIC")).await;
+    assert_eq!((&field["field"], &field["code"]), (&json!("file"), &json!("unsupported_media")));
+    assert!(field["message"].as_str().unwrap().contains("Export the photo as JPEG"), "{}", field);
+    assert!(stored_files(&board.config.upload_dir).is_empty());
+    assert!(!accepted_types(&board).await.contains(".heic"));
+}
+
+#[actix_web::test]
+async fn heic_photos_are_stored_as_the_converted_jpeg() {
+    let fixture = Fixture::new();
+    let board = TestBoard::with(|config| config.heif_converter = Some(fixture.converter()));
+    let accepted = accepted_types(&board).await;
+    assert!(accepted.ends_with(",.heic,.heif"), "{}", accepted);
+
+    for (title, name, format) in [("Upper", "IMG_0001.HEIC", "heic"), ("Lower", "scan.heif", "heif")].iter() {
+        let res = board.submit(photo(title, name)).await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+        let post = board.find(title);
+        let file = post.file.clone().unwrap();
+        assert!(file.ends_with(".jpg"), "{}", file);
+        assert_eq!(post.converted_from.as_deref(), Some(*format));
+        assert_eq!(post.file_size, Some(fixture.jpeg.len() as u64));
+        assert_eq!((post.width, post.height), (Some(48), Some(32)));
+
+        let res = board.get(&board.config.upload_url(&file)).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.headers.get(CONTENT_TYPE).unwrap(), "image/jpeg");
+    }
+    // Only the JPEGs are kept, and no temporary file is left
+    let mut kept: Vec<Vec<u8>> = stored_files(&board.config.upload_dir).iter().map(|path| std::fs::read(path).unwrap()).collect();
+    kept.dedup();
+    assert_eq!(kept, vec![fixture.jpeg.clone()]);
+    assert_eq!(stored_files(&board.config.upload_dir).len(), 2);
+}
+
+#[actix_web::test]
+async fn a_failed_conversion_is_refused_and_leaves_nothing() {
+    // One that fails and one that succeeds without writing anything
+    for converter in [vec!["false".to_string()], vec!["true".to_string()]].iter() {
+        let board = TestBoard::with(|config| config.heif_converter = Some(converter.clone()));
+        let field = refused_file(&board, photo("Phone", "IMG_0001.HEIC")).await;
+        assert_eq!(
+            field,
+            json!({"field": "file", "code": "unsupported_media", "message": "This HEIC photo couldn't be converted. Export it as JPEG and try again."}),
+            "{:?}",
+            converter
+        );
+        assert!(stored_files(&board.config.upload_dir).is_empty(), "{:?}", converter);
+    }
+}
+
+#[actix_web::test]
+async fn the_converted_jpeg_is_held_to_the_size_limit() {
+    let fixture = Fixture::new();
+    let limit = 200;
+    assert!(HEIC.len() < limit && fixture.jpeg.len() > limit, "{} bytes", fixture.jpeg.len());
+    let board = TestBoard::with(|config| {
+        config.heif_converter = Some(fixture.converter());
+        config.max_upload_bytes = limit as u64;
+    });
+    let field = refused_file(&board, photo("Phone", "IMG_0001.HEIC")).await;
+    assert_eq!((&field["code"], &field["max"]), (&json!("file_too_large"), &json!(limit)));
+    assert!(field["message"].as_str().unwrap().ends_with("limit once converted to JPEG."), "{}", field);
+    assert!(stored_files(&board.config.upload_dir).is_empty());
+}
@@ -0,0 +1,401 @@
+// A post's life from the form to the pages: threads and replies, their
+// redirects, escaping, attachments and the index pages, and what's left
+// once a thread is deleted.
+
+use actix_web::http::StatusCode;
+use actix_web::rt::time;
+use regex::Regex;
+use std::collections::HashSet;
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+use super::{admin_login, attrs, png, select, texts, Form, TestBoard};
+use crate::events::BoardEvent;
+use crate::{storage, upload};
+
+#[actix_web::test]
+async fn new_thread_redirects_to_the_index() {
+    let board = TestBoard::new();
+    let res = board.submit(Form::new().text("title", "Hello").text("message", "First post")).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER);
+    assert_eq!(res.location(), "/");
+}
+
+#[actix_web::test]
+async fn reply_redirects_to_its_thread() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let form = Form::new().text("parent_id", &thread.id).text("title", "Reply").text("message", "Answer");
+    let res = board.submit(form).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER);
+    assert_eq!(res.location(), format!("/post/{}?just_posted=1#r1", thread.id));
+}
+
+#[actix_web::test]
+async fn redirect_follows_return_to_on_this_board_only() {
+    let board = TestBoard::new();
+    let form = Form::new().text("title", "Paged").text("message", "x").text("return_to", "/?page=1");
+    assert_eq!(board.submit(form).await.location(), "/?page=1");
+
+    let form = Form::new().text("title", "Away").text("message", "x").text("return_to", "https://evil.example/");
+    assert_eq!(board.submit(form).await.location(), "/");
+}
+
+#[actix_web::test]
+async fn empty_parent_id_starts_a_thread() {
+    let board = TestBoard::new();
+    let res = board.submit(Form::new().text("parent_id", "").text("title", "Blank").text("message", "x")).await;
+    assert_eq!(res.location(), "/");
+    assert!(board.find("Blank").parent_id.is_none());
+}
+
+#[actix_web::test]
+async fn refused_post_explains_itself() {
+    let board = TestBoard::new();
+    let res = board.submit(Form::new().text("message", "No title").json()).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = serde_json::from_str(&res.body).unwrap();
+    let fields = body["fields"].as_array().unwrap();
+    assert!(fields.iter().any(|field| field["field"] == "title" && field["code"] == "missing"), "{}", res.body);
+}
+
+#[actix_web::test]
+async fn index_shows_threads_escaped_and_linked() {
+    let board = TestBoard::new();
+    let thread = board.thread("<b>Hi</b> & co", "<script>alert(1)</script> and \"quotes\"").await;
+
+    let html = board.get("/").await.html();
+    assert_eq!(texts(&html, ".post h3 bdi"), vec!["<b>Hi</b> & co"]);
+    assert!(select(&html, ".post h3 b").is_empty());
+    assert!(select(&html, ".post script").is_empty());
+    assert_eq!(texts(&html, ".post .post-details > p[dir=auto]"), vec!["<script>alert(1)</script> and \"quotes\""]);
+    assert_eq!(attrs(&html, ".post .reply-link a", "href"), vec![format!("/post/{}", thread.id)]);
+}
+
+#[actix_web::test]
+async fn thread_page_shows_replies_escaped_and_numbered() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    board.reply(&thread, "One", "<img src=x onerror=alert(1)>").await;
+    board.reply(&thread, "Two", "plain").await;
+
+    let html = board.get(&format!("/post/{}", thread.id)).await.html();
+    assert_eq!(texts(&html, ".original-post h3 bdi"), vec!["Thread"]);
+    assert_eq!(attrs(&html, ".reply", "id"), vec!["r1", "r2"]);
+    assert_eq!(attrs(&html, ".reply h4 > a:first-child", "href"), vec!["#r1", "#r2"]);
+    assert_eq!(texts(&html, ".reply .post-details > p[dir=auto]"), vec!["<img src=x onerror=alert(1)>", "plain"]);
+    assert!(select(&html, ".reply img").is_empty());
+    assert_eq!(attrs(&html, "#reply-form input[name=parent_id]", "value"), vec![thread.id.clone()]);
+}
+
+#[actix_web::test]
+async fn missing_thread_is_not_found() {
+    let board = TestBoard::new();
+    let res = board.get(&format!("/post/{}", Uuid::new_v4())).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn uploaded_image_is_stored_under_a_uuid_name() {
+    let board = TestBoard::new();
+    let form = Form::new()
+        .text("title", "Picture")
+        .text("message", "Look")
+        .file("file", "holiday photo.png", "image/png", &png(32));
+    let res = board.submit(form).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+
+    let post = board.find("Picture");
+    let file = post.file.clone().expect("the post has no file");
+    let base_name = file.rsplit('/').next().unwrap();
+    let (stem, extension) = base_name.rsplit_once('.').unwrap();
+    assert!(Uuid::parse_str(stem).is_ok(), "{} isn't named by a UUID", file);
+    assert_eq!(extension, "png");
+    assert!(board.config.upload_path(&file).is_file());
+    assert_eq!(post.original_name.as_deref(), Some("holiday photo.png"));
+
+    let html = board.get(&format!("/post/{}", post.id)).await.html();
+    assert_eq!(attrs(&html, ".original-post img.post-file", "src"), vec![format!("/static/uploads/{}", file)]);
+    let served = board.get(&format!("/static/uploads/{}", file)).await;
+    assert_eq!(served.status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn index_pages_through_every_thread_once() {
+    let board = TestBoard::new();
+    for n in 0..40 {
+        board.thread(&format!("Thread {}", n), "x").await;
+    }
+
+    let mut seen = Vec::new();
+    for page in 0..4 {
+        let html = board.get(&format!("/?page={}&per_page=12", page)).await.html();
+        let titles = texts(&html, ".post h3 bdi");
+        assert_eq!(titles.len(), if page < 3 { 12 } else { 4 }, "page {}", page);
+        assert_eq!(texts(&html, ".pagination.current-page"), vec![page.to_string()]);
+        assert_eq!(select(&html, ".pagination-links a.pagination").len(), if page == 0 || page == 3 { 4 } else { 5 });
+        seen.extend(titles);
+    }
+    seen.sort();
+    seen.dedup();
+    assert_eq!(seen.len(), 40);
+
+    // Past the end is the last page
+    let html = board.get("/?page=9&per_page=12").await.html();
+    assert_eq!(texts(&html, ".pagination.current-page"), vec!["3"]);
+    assert_eq!(select(&html, ".post").len(), 4);
+}
+
+// Titles of the stickies and of the other threads on `page`, five to a
+// page, and how many pages there are
+async fn sticky_page(board: &TestBoard, page: usize) -> (Vec<String>, Vec<String>, usize) {
+    let html = board.get(&format!("/?page={}&per_page=5", page)).await.html();
+    let pages = texts(&html, ".pagination-links .pagination").iter().filter(|link| link.parse::<usize>().is_ok()).count();
+    (texts(&html, ".post.sticky h3 bdi"), texts(&html, ".post:not(.sticky) h3 bdi"), pages)
+}
+
+#[actix_web::test]
+async fn stickies_sit_above_page_zero_without_taking_its_slots() {
+    let board = TestBoard::with(|config| config.admin_password = Some("secret".to_string()));
+    let admin = admin_login(&board).await;
+    let mut threads = Vec::new();
+    for n in 0..14 {
+        threads.push(board.thread(&format!("Thread {}", n), "x").await);
+    }
+    // The oldest thread, and the newest, bumped again after it's made sticky
+    for thread in [&threads[0], &threads[13]] {
+        assert_eq!(board.send(admin.post(&format!("/admin/post/{}/sticky", thread.id))).await.status, StatusCode::SEE_OTHER);
+    }
+    board.reply(&threads[13], "Bump", "Up").await;
+
+    let mut seen = Vec::new();
+    for page in 0..3 {
+        let (stickies, others, pages) = sticky_page(&board, page).await;
+        assert_eq!(stickies.len(), if page == 0 { 2 } else { 0 }, "page {}", page);
+        assert_eq!(others.len(), if page < 2 { 5 } else { 2 }, "page {}", page);
+        assert_eq!(pages, 3);
+        seen.extend(others);
+    }
+    let mut expected: Vec<String> = (1..13).map(|n| format!("Thread {}", n)).collect();
+    seen.sort();
+    expected.sort();
+    assert_eq!(seen, expected);
+
+    // More stickies than fit on a page still all show, above the rest
+    for thread in &threads[1..6] {
+        assert_eq!(board.send(admin.post(&format!("/admin/post/{}/sticky", thread.id))).await.status, StatusCode::SEE_OTHER);
+    }
+    let (stickies, others, pages) = sticky_page(&board, 0).await;
+    assert_eq!(stickies.len(), 7);
+    assert_eq!(others.len(), 5);
+    assert_eq!(pages, 2);
+    let (stickies, others, _) = sticky_page(&board, 1).await;
+    assert!(stickies.is_empty());
+    assert_eq!(others.len(), 2);
+}
+
+#[actix_web::test]
+async fn page_numbers_are_checked_on_every_paged_list() {
+    let board = TestBoard::with(|config| config.admin_password = Some("secret".to_string()));
+    let admin = admin_login(&board).await;
+    for n in 0..7 {
+        board.thread(&format!("Thread {}", n), "x").await;
+    }
+
+    // Empty is the first page, and past the end the last; the index has
+    // two pages of five
+    for (page, current) in [("", "0"), ("%20", "0"), ("1", "1"), ("18446744073709551615", "1"), ("99999999999999999999999", "1")] {
+        let res = board.get(&format!("/?per_page=5&page={}", page)).await;
+        assert_eq!(res.status, StatusCode::OK, "page={:?}", page);
+        assert_eq!(texts(&res.html(), ".pagination.current-page"), vec![current], "page={:?}", page);
+        let res = board.send(admin.get(&format!("/admin/posts?page={}", page))).await;
+        assert_eq!(res.status, StatusCode::OK, "page={:?}", page);
+    }
+
+    for page in ["-1", "abc", "1.5", "0x1", "%2B1", "1e3"] {
+        let res = board.get(&format!("/?page={}", page)).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "page={:?}", page);
+        let html = scraper::Html::parse_document(&res.body);
+        assert_eq!(texts(&html, "main h3"), vec!["No such page"]);
+        assert_eq!(attrs(&html, ".back-link", "href"), vec!["/"]);
+        let res = board.send(admin.get(&format!("/admin/posts?page={}", page))).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "page={:?}", page);
+        assert_eq!(attrs(&scraper::Html::parse_document(&res.body), ".back-link", "href"), vec!["/admin/posts"]);
+    }
+}
+
+#[actix_web::test]
+async fn per_page_is_clamped_and_kept_in_the_links() {
+    let board = TestBoard::with(|config| config.max_per_page = 8);
+    for n in 0..12 {
+        board.thread(&format!("Thread {}", n), "x").await;
+    }
+
+    // (asked for, threads on page 0, page links besides the current one)
+    let cases = [("4", 5, 2), ("5", 5, 2), ("6", 6, 1), ("8", 8, 1), ("9", 8, 1), ("18446744073709551616", 8, 1)];
+    for (asked, shown, links) in cases {
+        let html = board.get(&format!("/?per_page={}", asked)).await.html();
+        assert_eq!(select(&html, ".post").len(), shown, "per_page={}", asked);
+        let pages = attrs(&html, ".pagination-links a.pagination", "href");
+        assert_eq!(pages.len(), links + usize::from(links > 0), "per_page={}", asked);
+        assert!(pages.iter().all(|href| href.ends_with(&format!("&per_page={}", shown))), "{:?}", pages);
+        let sorts = attrs(&html, ".sort-links a", "href");
+        assert!(sorts.iter().all(|href| href.contains(&format!("&per_page={}", shown))), "{:?}", sorts);
+    }
+
+    // Junk is the board's default, and isn't passed on
+    for junk in ["", "abc", "-5", "0x10", "7.5"] {
+        let html = board.get(&format!("/?per_page={}", junk)).await.html();
+        assert_eq!(select(&html, ".post").len(), 12, "per_page={:?}", junk);
+        let sorts = attrs(&html, ".sort-links a", "href");
+        assert!(!sorts.is_empty() && sorts.iter().all(|href| !href.contains("per_page")), "{:?}", sorts);
+    }
+
+    // The last page and the links around it, with the sort kept alongside
+    let html = board.get("/?page=2&per_page=5&sort=creation").await.html();
+    assert_eq!(select(&html, ".post").len(), 2);
+    assert_eq!(
+        attrs(&html, ".pagination-links a.pagination", "href"),
+        vec!["/?page=1&per_page=5&sort=creation", "/?page=0&per_page=5&sort=creation", "/?page=1&per_page=5&sort=creation"]
+    );
+
+    // The API clamps the same way
+    for (asked, shown) in [("1", 5), ("7", 7), ("100", 8), ("junk", 12)] {
+        let res = board.get(&format!("/api/threads?per_page={}", asked)).await;
+        let body: serde_json::Value = serde_json::from_str(&res.body).unwrap();
+        assert_eq!(body["threads"].as_array().unwrap().len(), shown, "per_page={}", asked);
+    }
+}
+
+// Trees that record what happened to posts, deletions included, and
+// submit tokens, which are kept a day whatever became of their posts
+const HISTORY: [&[u8]; 3] = [b"audit", b"board_changes", b"submit_tokens"];
+
+// Every key and value outside HISTORY that mentions one of `ids`
+fn mentions(board: &TestBoard, ids: &[String]) -> Vec<String> {
+    let mut found = Vec::new();
+    for name in board.db.tree_names().into_iter().filter(|name| !HISTORY.contains(&name.as_ref())) {
+        let tree = board.db.open_tree(&name).unwrap();
+        for entry in tree.iter() {
+            let (key, value) = entry.unwrap();
+            let key = String::from_utf8_lossy(&key);
+            let value = String::from_utf8_lossy(&value);
+            if ids.iter().any(|id| key.contains(id.as_str()) || value.contains(id.as_str())) {
+                found.push(format!("{}: {}", String::from_utf8_lossy(&name), key));
+            }
+        }
+    }
+    found
+}
+
+#[actix_web::test]
+async fn deleting_a_thread_leaves_nothing_behind() {
+    let board = TestBoard::with(|config| {
+        config.admin_password = Some("secret".to_string());
+        config.duplicate_image_threshold = 1;
+    });
+    let admin = super::admin_login(&board).await;
+    let form = Form::new().text("title", "Doomed").text("message", "Start").file("file", "op.png", "image/png", &png(40));
+    assert_eq!(board.submit(form).await.status, StatusCode::SEE_OTHER);
+    let thread = board.find("Doomed");
+    let mut ids = vec![thread.id.clone()];
+    for n in 1..=10 {
+        // Each quoting the one before, for backlinks
+        let message = if n == 1 { "First".to_string() } else { format!(">>{} and more", n - 1) };
+        let form = Form::new()
+            .text("parent_id", &thread.id)
+            .text("title", &format!("Reply {}", n))
+            .text("message", &message)
+            .file("file", &format!("{}.png", n), "image/png", &png(if n % 2 == 0 { 16 } else { 24 }));
+        assert_eq!(board.submit(form).await.status, StatusCode::SEE_OTHER);
+        ids.push(board.find(&format!("Reply {}", n)).id);
+    }
+    let edit = admin.post(&format!("/admin/post/{}/edit", ids[3])).set_form([("message", ">>1 edited")]);
+    assert_eq!(board.send(edit).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(board.send(admin.post(&format!("/admin/post/{}/sticky", thread.id))).await.status, StatusCode::SEE_OTHER);
+    // A thread of its own, which has to come through untouched
+    let other = board.thread("Survivor", "Still here").await;
+    assert_eq!(upload::stored_files(&board.config.upload_dir).len(), 11);
+
+    let res = board.send(admin.post(&format!("/admin/post/{}/delete-thread", thread.id))).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+
+    assert_eq!(mentions(&board, &ids), Vec::<String>::new());
+    assert!(upload::stored_files(&board.config.upload_dir).is_empty());
+    assert_eq!(board.find("Survivor").id, other.id);
+    assert_eq!(board.get(&format!("/post/{}", thread.id)).await.status, StatusCode::NOT_FOUND);
+}
+
+// Keys in `meta` the board makes for itself, which look like post ids
+const SECRETS: [&str; 3] = ["ip_salt", "options_cookie_key", "age_cookie_key"];
+
+// Every post id outside HISTORY and SECRETS that isn't a post in the main
+// tree
+fn orphans(board: &TestBoard) -> Vec<String> {
+    let uuid = Regex::new("[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}").unwrap();
+    let live: HashSet<String> = board.db.iter().keys().map(|key| String::from_utf8_lossy(&key.unwrap()).into_owned()).collect();
+    let mut found = Vec::new();
+    for name in board.db.tree_names().into_iter().filter(|name| !HISTORY.contains(&name.as_ref())) {
+        for entry in board.db.open_tree(&name).unwrap().iter() {
+            let (key, value) = entry.unwrap();
+            let key = String::from_utf8_lossy(&key);
+            if &*name == b"meta" && SECRETS.contains(&key.as_ref()) {
+                continue;
+            }
+            let text = format!("{} {}", key, String::from_utf8_lossy(&value));
+            for id in uuid.find_iter(&text).filter(|id| !live.contains(id.as_str())) {
+                found.push(format!("{}: {}", String::from_utf8_lossy(&name), id.as_str()));
+            }
+        }
+    }
+    found
+}
+
+#[actix_web::test]
+async fn replies_racing_a_thread_delete_leave_no_orphans() {
+    let board = TestBoard::new();
+    let survivor = board.thread("Survivor", "Stays").await;
+    board.reply(&survivor, "Kept", "Stays too").await;
+    let (mut stored, mut refused) = (0, 0);
+    for round in 0..12 {
+        // The same thread started again each round and deleted on another
+        // thread, as admin::delete_thread does it, while replies to it are
+        // still arriving
+        let thread = board.thread("Again", "Start").await;
+        let (db, config, events, id) = (board.db.clone(), board.config.clone(), board.state.events.clone(), thread.id.clone());
+        let delete = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(25 * round));
+            let report = storage::delete_thread(&db, &config, &id);
+            events.publish(BoardEvent::Deleted { posts: report.removed });
+        });
+        for n in 0..16 {
+            let form = Form::new()
+                .text("parent_id", &thread.id)
+                .text("title", &format!("Reply {} {}", round, n))
+                .text("message", "Racing");
+            let res = board.submit(form).await;
+            match res.status {
+                StatusCode::SEE_OTHER => stored += 1,
+                StatusCode::GONE => refused += 1,
+                status => panic!("{}: {}", status, res.body),
+            }
+        }
+        delete.join().unwrap();
+        assert!(crate::load_post(&board.db, &thread.id).is_none());
+    }
+    assert!(stored > 0 && refused > 0, "{} stored, {} refused", stored, refused);
+
+    // Previews are dropped on the event thread, so the scan is repeated
+    // until it comes up empty or this gives up
+    let mut found = orphans(&board);
+    for _ in 0..200 {
+        if found.is_empty() {
+            break;
+        }
+        time::sleep(Duration::from_millis(25)).await;
+        found = orphans(&board);
+    }
+    assert_eq!(found, Vec::<String>::new());
+    assert_eq!(board.find("Kept").parent_id.as_deref(), Some(survivor.id.as_str()));
+}
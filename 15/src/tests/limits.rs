@@ -0,0 +1,102 @@
+// Body size limits by route class, see route_limits.rs.
+
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use image::{ImageBuffer, ImageOutputFormat, Rgb};
+use std::io::Cursor;
+use std::time::Duration;
+
+use super::{Form, TestBoard};
+use crate::route_limits::{class_for, LimitClass, Limits};
+
+const MIB: u64 = 1024 * 1024;
+
+// A PNG of noise, which doesn't compress, so its size follows its side
+fn noisy_png(side: u32) -> Vec<u8> {
+    let mut state: u32 = 1;
+    let image = ImageBuffer::from_fn(side, side, |_, _| {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        let [r, g, b, _] = state.to_be_bytes();
+        Rgb([r, g, b])
+    });
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageOutputFormat::Png).unwrap();
+    png.into_inner()
+}
+
+fn small_uploads() -> TestBoard {
+    TestBoard::with(|config| {
+        config.max_upload_bytes = MIB;
+        config.limits_upload.max_body = MIB + 64 * 1024;
+    })
+}
+
+#[actix_web::test]
+async fn oversized_body_is_refused_off_the_upload_routes() {
+    let board = TestBoard::new();
+    let body = format!("message={}", "a".repeat(64 * 1024));
+    let req = TestRequest::post()
+        .uri("/preview")
+        .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+        .set_payload(body);
+    let res = board.send(req).await;
+    assert_eq!(res.status, StatusCode::PAYLOAD_TOO_LARGE, "{}", res.body);
+
+    // A small one still goes through
+    let req = TestRequest::post()
+        .uri("/preview")
+        .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+        .set_payload("message=hello");
+    assert_eq!(board.send(req).await.status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn submit_takes_a_file_near_its_limit() {
+    let board = small_uploads();
+    let png = noisy_png(580);
+    assert!(png.len() as u64 > MIB * 9 / 10 && (png.len() as u64) < MIB, "{} bytes", png.len());
+
+    let form = Form::new().text("title", "Big").text("message", "x").file("file", "big.png", "image/png", &png);
+    let res = board.submit(form).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    assert!(board.find("Big").file.is_some());
+}
+
+#[actix_web::test]
+async fn submit_past_its_limit_is_refused() {
+    let board = small_uploads();
+    let padding = vec![0u8; (MIB + 128 * 1024) as usize];
+    let form = Form::new().text("title", "Huge").text("message", "x").file("file", "huge.png", "image/png", &padding);
+    let res = board.submit(form).await;
+    assert_eq!(res.status, StatusCode::PAYLOAD_TOO_LARGE, "{}", res.body);
+}
+
+#[test]
+fn routes_are_classed_by_path() {
+    let mut board = TestBoard::new();
+    board.config.base_path = "/board".to_string();
+    let config = &board.config;
+    assert_eq!(class_for(config, "/board/submit"), LimitClass::Upload);
+    assert_eq!(class_for(config, "/board/admin/takedown"), LimitClass::Upload);
+    assert_eq!(class_for(config, "/board/admin/export/stream"), LimitClass::Export);
+    assert_eq!(class_for(config, "/board/admin/post/abc-123/dossier.zip"), LimitClass::Export);
+    assert_eq!(class_for(config, "/board/admin/post/abc-123/dossier"), LimitClass::Standard);
+    assert_eq!(class_for(config, "/board/preview"), LimitClass::Standard);
+    assert_eq!(class_for(config, "/submit"), LimitClass::Standard);
+}
+
+#[test]
+fn limits_parse() {
+    let limits = Limits::parse("16KB:30").unwrap();
+    assert_eq!(limits.timeout, Duration::from_secs(30));
+    assert!(limits.keep_alive);
+    assert!(limits.max_body >= 16_000);
+
+    let limits = Limits::parse("64KB:3600:close").unwrap();
+    assert_eq!(limits.timeout, Duration::from_secs(3600));
+    assert!(!limits.keep_alive);
+
+    for bad in ["", "16KB", "16KB:0", "16KB:x", "16KB:30:open", "16KB:30:close:1", "lots:30"] {
+        assert!(Limits::parse(bad).is_err(), "{:?}", bad);
+    }
+}
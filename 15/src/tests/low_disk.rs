@@ -0,0 +1,122 @@
+// A nearly full upload volume, see diskspace.rs: with a stand-in for the
+// free space probe, uploads stop and start again while text posts carry
+// on, and /readyz and the admin pages say so.
+
+use actix_web::http::StatusCode;
+use serde_json::{json, Value};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::{admin_login, png, Form, TestBoard};
+use crate::bytesize;
+use crate::diskspace::SpaceProbe;
+
+const MIN_FREE: u64 = 1024 * 1024;
+
+// Reports whatever the test last set, None being a failed probe
+#[derive(Clone)]
+struct FakeSpace(Arc<Mutex<Option<u64>>>);
+
+impl FakeSpace {
+    fn new(available: u64) -> FakeSpace {
+        FakeSpace(Arc::new(Mutex::new(Some(available))))
+    }
+
+    fn set(&self, available: Option<u64>) {
+        *self.0.lock().unwrap() = available;
+    }
+}
+
+impl SpaceProbe for FakeSpace {
+    fn available(&self, _dir: &Path) -> io::Result<u64> {
+        self.0.lock().unwrap().ok_or_else(|| io::Error::other("probe failed"))
+    }
+}
+
+fn board() -> TestBoard {
+    TestBoard::with(|config| {
+        config.min_free_upload_bytes = MIN_FREE;
+        config.admin_password = Some("secret".to_string());
+    })
+}
+
+fn with_file(title: &str) -> Form {
+    Form::new().text("title", title).text("message", "Hi").file("file", "pic.png", "image/png", &png(4))
+}
+
+async fn uploads(board: &TestBoard) -> Value {
+    let body: Value = serde_json::from_str(&board.get("/readyz").await.body).unwrap();
+    body["uploads"].clone()
+}
+
+#[actix_web::test]
+async fn uploads_stop_while_space_is_low_and_come_back() {
+    let mut board = board();
+    let space = FakeSpace::new(10 * MIN_FREE);
+    let disk = board.probe_disk(space.clone());
+    let admin = admin_login(&board).await;
+
+    assert_eq!(board.submit(with_file("Before")).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(uploads(&board).await, "enabled");
+
+    space.set(Some(MIN_FREE - 1));
+    disk.check();
+    assert_eq!(uploads(&board).await, "disabled");
+    let res = board.submit(with_file("During").json()).await;
+    assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+    let body: Value = serde_json::from_str(&res.body).unwrap();
+    assert_eq!(
+        body["fields"],
+        json!([{
+            "field": "file",
+            "code": "uploads_disabled",
+            "message": "Uploads are temporarily disabled. You can still post without a file.",
+        }])
+    );
+    // Text still goes through
+    board.thread("Text only", "Hi").await;
+    let report = disk.low_report().unwrap();
+    let expected = format!("{} free, under the {} minimum", bytesize::format(MIN_FREE - 1, board.config.size_units), bytesize::format(MIN_FREE, board.config.size_units));
+    assert_eq!(report, expected);
+    assert!(board.send(admin.get("/admin/posts")).await.body.contains(&report));
+
+    // Exactly the minimum is enough
+    space.set(Some(MIN_FREE));
+    disk.check();
+    assert_eq!(uploads(&board).await, "enabled");
+    assert_eq!(disk.low_report(), None);
+    assert!(!board.send(admin.get("/admin/posts")).await.body.contains("nearly full"));
+    assert_eq!(board.submit(with_file("After")).await.status, StatusCode::SEE_OTHER);
+    assert!(board.find("After").file.is_some());
+}
+
+#[actix_web::test]
+async fn a_failed_probe_keeps_the_last_state() {
+    let mut board = board();
+    let space = FakeSpace::new(0);
+    let disk = board.probe_disk(space.clone());
+    disk.check();
+    assert!(disk.is_low());
+
+    space.set(None);
+    disk.check();
+    assert!(disk.is_low());
+    assert_eq!(disk.low_report().unwrap(), format!("{} free, under the {} minimum", bytesize::format(0, board.config.size_units), bytesize::format(MIN_FREE, board.config.size_units)));
+
+    space.set(Some(MIN_FREE));
+    disk.check();
+    space.set(None);
+    disk.check();
+    assert!(!disk.is_low());
+}
+
+#[actix_web::test]
+async fn save_post_checks_before_the_first_job_runs() {
+    let mut board = board();
+    // Never checked, so the first upload probes the volume itself
+    board.probe_disk(FakeSpace::new(0));
+    let res = board.submit(with_file("Full").json()).await;
+    assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(uploads(&board).await, "disabled");
+}
@@ -0,0 +1,124 @@
+// The board manifest, see manifest.rs, checked against the schema in
+// static/board.schema.json that listing sites are pointed at: on a new
+// board, on one with everything set, and behind a base path. Listing sites
+// poll it, so it's left out of rate limiting.
+
+use actix_web::http::header::{ACCESS_CONTROL_ALLOW_ORIGIN, CACHE_CONTROL};
+use actix_web::http::StatusCode;
+use serde_json::{json, Value};
+
+use super::{Form, TestBoard};
+use crate::rate_limit::{BucketPolicy, RouteClass};
+use crate::settings::BoardSettings;
+use crate::{archive, now};
+
+const SCHEMA: &str = include_str!("../../static/board.schema.json");
+
+// Every way `manifest` breaks the schema, formats included
+fn schema_errors(manifest: &Value) -> Vec<String> {
+    let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+    let validator = jsonschema::options().should_validate_formats(true).build(&schema).unwrap();
+    validator.iter_errors(manifest).map(|error| format!("{} at {}", error, error.instance_path)).collect()
+}
+
+async fn manifest(board: &TestBoard, path: &str) -> Value {
+    let res = board.get(path).await;
+    assert_eq!(res.status, StatusCode::OK, "{}", path);
+    assert_eq!(res.headers.get(CACHE_CONTROL).unwrap(), "public, max-age=300");
+    assert_eq!(res.headers.get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
+    let manifest: Value = serde_json::from_str(&res.body).unwrap();
+    assert_eq!(schema_errors(&manifest), Vec::<String>::new(), "{}", manifest);
+    manifest
+}
+
+#[actix_web::test]
+async fn the_schema_catches_what_it_should() {
+    let good = json!({
+        "language": "en",
+        "nsfw": false,
+        "posts": {"threads": 0, "archived_threads": 0, "replies": 0},
+        "software": {"name": "board", "version": "1.0"},
+        "features": ["api"],
+    });
+    assert!(schema_errors(&good).is_empty());
+    let mut bad = Vec::new();
+    for (field, value) in [("name", json!(null)), ("description", json!("")), ("url", json!("not a url")), ("nsfw", json!("no")), ("extra", json!(1))].iter() {
+        let mut manifest = good.clone();
+        manifest[*field] = value.clone();
+        bad.push(manifest);
+    }
+    let mut manifest = good.clone();
+    manifest["features"] = json!(["api", "api"]);
+    bad.push(manifest);
+    let mut manifest = good.clone();
+    manifest["posts"]["replies"] = json!(-1);
+    bad.push(manifest);
+    let mut manifest = good;
+    manifest.as_object_mut().unwrap().remove("software");
+    bad.push(manifest);
+    for manifest in bad {
+        assert!(!schema_errors(&manifest).is_empty(), "{}", manifest);
+    }
+}
+
+#[actix_web::test]
+async fn a_new_board_leaves_out_what_it_has_no_value_for() {
+    let board = TestBoard::new();
+    let manifest = manifest(&board, "/.well-known/board.json").await;
+    assert_eq!(manifest["name"], "Main Board");
+    assert_eq!(manifest["posts"], json!({"threads": 0, "archived_threads": 0, "replies": 0}));
+    for field in ["description", "url", "boards"].iter() {
+        assert!(manifest.get(field).is_none(), "{}", field);
+    }
+}
+
+#[actix_web::test]
+async fn a_described_board_behind_a_base_path_matches_the_schema() {
+    let board = TestBoard::with(|config| {
+        config.base_path = "/b".to_string();
+        config.manifest_path = "/about.json".to_string();
+        config.public_url = "https://boards.example".to_string();
+        config.lang = "pt-BR".to_string();
+    });
+    let settings = BoardSettings {
+        name: "Trains".to_string(),
+        description: "Everything on rails".to_string(),
+        nsfw: true,
+        ..BoardSettings::default()
+    };
+    board.state.settings.save(&board.db, settings);
+    for (title, parent) in [("Kept", None), ("Old", None), ("One", Some("Kept")), ("Two", Some("Kept"))].iter() {
+        let mut form = Form::new().text("title", title).text("message", "Hi");
+        if let Some(parent) = parent {
+            form = form.text("parent_id", &board.find(parent).id);
+        }
+        assert_eq!(board.send(form.request("/b/submit")).await.status, StatusCode::SEE_OTHER);
+    }
+    assert!(archive::archive(&board.db, &board.find("Old").id, now()));
+
+    assert_eq!(board.get("/.well-known/board.json").await.status, StatusCode::NOT_FOUND);
+    let manifest = manifest(&board, "/b/about.json").await;
+    assert_eq!(manifest["name"], "Trains");
+    assert_eq!(manifest["description"], "Everything on rails");
+    assert_eq!(manifest["url"], "https://boards.example/b/");
+    assert_eq!(manifest["language"], "pt-BR");
+    assert_eq!(manifest["nsfw"], true);
+    assert_eq!(manifest["posts"], json!({"threads": 1, "archived_threads": 1, "replies": 2}));
+}
+
+#[actix_web::test]
+async fn listing_sites_are_never_rate_limited() {
+    let mut board = TestBoard::new();
+    let one = BucketPolicy {
+        burst: 1.0,
+        per_second: 0.001,
+    };
+    for class in [RouteClass::Write, RouteClass::Render, RouteClass::Cheap].iter() {
+        board.limit(*class, one);
+    }
+    for _ in 0..5 {
+        manifest(&board, "/.well-known/board.json").await;
+    }
+    assert_eq!(board.get("/").await.status, StatusCode::OK);
+    assert_eq!(board.get("/").await.status, StatusCode::TOO_MANY_REQUESTS);
+}
@@ -0,0 +1,138 @@
+// The page structure screen readers find their way by: landmarks, posts
+// as articles named by their headings, labelled form fields and alt text.
+
+use actix_web::http::header::{ACCEPT, CONTENT_LANGUAGE};
+use actix_web::http::StatusCode;
+use scraper::Html;
+
+use super::{attrs, png, select, texts, Form, TestBoard};
+use crate::{archive, now, upload};
+
+// One <main>, and every article named by a heading inside it
+fn assert_landmarks(html: &Html) {
+    assert_eq!(select(html, "main").len(), 1);
+    assert_eq!(attrs(html, "html", "lang"), vec!["en"]);
+    assert_eq!(attrs(html, "html", "dir"), vec!["ltr"]);
+    for article in select(html, "article") {
+        let id = article.value().attr("aria-labelledby").expect("an article without aria-labelledby");
+        let heading = Html::parse_fragment(&article.html());
+        assert_eq!(select(&heading, &format!("h3[id='{}'], h4[id='{}']", id, id)).len(), 1, "no heading {}", id);
+    }
+}
+
+// Every field someone fills in is inside a label with text of its own
+fn assert_fields_labelled(html: &Html) {
+    let fields = select(html, "form input:not([type=hidden]):not([type=checkbox]), form textarea").len();
+    assert!(fields > 0);
+    assert_eq!(select(html, "form label > .visually-hidden + input, form label > .visually-hidden + textarea").len(), fields);
+}
+
+#[actix_web::test]
+async fn index_has_landmarks_and_labels() {
+    let board = TestBoard::new();
+    for n in 0..6 {
+        board.thread(&format!("Thread {}", n), "x").await;
+    }
+    let html = board.get("/?per_page=5").await.html();
+    assert_landmarks(&html);
+    assert_fields_labelled(&html);
+    assert_eq!(select(&html, "header h1").len(), 1);
+    assert_eq!(select(&html, "main article.post").len(), 5);
+    assert_eq!(texts(&html, "nav.pagination-links [aria-current=page]"), vec!["0"]);
+    assert_eq!(select(&html, "nav.pagination-links a[aria-current]").len(), 0);
+    assert_eq!(select(&html, "nav.sort-links [aria-current]").len(), 1);
+    assert_eq!(select(&html, "main footer.theme-footer").len(), 1);
+}
+
+#[actix_web::test]
+async fn thread_page_has_landmarks_and_labels() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    board.reply(&thread, "One", "first").await;
+    board.reply(&thread, "Two", "second").await;
+
+    let html = board.get(&format!("/post/{}", thread.id)).await.html();
+    assert_landmarks(&html);
+    assert_fields_labelled(&html);
+    assert_eq!(attrs(&html, "article.original-post", "aria-labelledby"), vec![format!("title-{}", thread.id)]);
+    assert_eq!(attrs(&html, "article.reply", "id"), vec!["r1", "r2"]);
+    assert_eq!(select(&html, "nav.reply-order [aria-current]").len(), 1);
+}
+
+#[actix_web::test]
+async fn images_are_described_by_their_file_names() {
+    let board = TestBoard::new();
+    let form = Form::new().text("title", "Picture").text("message", "x").file("file", "sunset.png", "image/png", &png(16));
+    board.submit(form).await;
+    let thread = board.find("Picture");
+    let form = Form::new()
+        .text("parent_id", &thread.id)
+        .text("title", "Reply")
+        .text("message", "y")
+        .file("file", "dawn.png", "image/png", &png(16));
+    board.submit(form).await;
+
+    let html = board.get(&format!("/post/{}", thread.id)).await.html();
+    assert_eq!(attrs(&html, "img.post-file", "alt"), vec!["sunset.png", "dawn.png"]);
+
+    // Posts from before the name was kept say whose attachment it is
+    let mut reply = board.find("Reply");
+    reply.original_name = None;
+    board.db.insert(&reply.id, serde_json::to_vec(&reply).unwrap()).unwrap();
+    let html = board.get(&format!("/post/{}", thread.id)).await.html();
+    assert_eq!(attrs(&html, "img.post-file", "alt"), vec!["sunset.png", "Attachment for reply 1"]);
+}
+
+#[actix_web::test]
+async fn archive_has_landmarks() {
+    let board = TestBoard::new();
+    let form = Form::new().text("title", "Old").text("message", "x").file("file", "old.png", "image/png", &png(16));
+    board.submit(form).await;
+    let thread = board.find("Old");
+    assert!(archive::archive(&board.db, &thread.id, now()));
+
+    let html = board.get(&format!("/archive/{}", upload::dated_dir(thread.timestamp))).await.html();
+    assert_landmarks(&html);
+    assert_eq!(select(&html, "main .archive-card").len(), 1);
+    assert_eq!(attrs(&html, ".archive-card img", "alt"), vec!["old.png"]);
+}
+
+#[actix_web::test]
+async fn the_board_language_is_on_every_page_and_response() {
+    let board = TestBoard::with(|config| {
+        config.lang = "pt-BR".to_string();
+        config.admin_password = Some("secret".to_string());
+    });
+    let thread = board.thread("Fio", "Olá").await;
+    let rejected = board.send(Form::new().text("title", "").text("message", "").request("/submit").insert_header((ACCEPT, "text/html"))).await;
+    let pages = [
+        board.get("/").await,
+        board.get(&format!("/post/{}", thread.id)).await,
+        board.get("/archive").await,
+        board.get("/admin/login").await,
+        board.get("/?page=abc").await,
+        rejected,
+    ];
+    assert_eq!(pages.iter().map(|res| res.status.as_u16()).collect::<Vec<_>>(), [200, 200, 200, 200, 400, 400]);
+    for res in &pages {
+        assert_eq!(res.headers.get(CONTENT_LANGUAGE).unwrap(), "pt-BR");
+        let html = Html::parse_document(&res.body);
+        assert_eq!(attrs(&html, "html", "lang"), vec!["pt-BR"], "{}", res.body);
+        assert_eq!(attrs(&html, "html", "dir"), vec!["ltr"]);
+    }
+    // Responses without an html element still say it
+    for path in ["/api/threads", "/theme.css", "/readyz"] {
+        let res = board.get(path).await;
+        assert_eq!(res.status, StatusCode::OK, "{}", path);
+        assert_eq!(res.headers.get(CONTENT_LANGUAGE).unwrap(), "pt-BR", "{}", path);
+    }
+}
+
+#[actix_web::test]
+async fn right_to_left_languages_set_dir() {
+    for (lang, dir) in [("ar", "rtl"), ("he-IL", "rtl"), ("az-Arab", "rtl"), ("pt-BR", "ltr"), ("ku-Latn", "ltr")] {
+        let board = TestBoard::with(|config| config.lang = lang.to_string());
+        let html = board.get("/").await.html();
+        assert_eq!(attrs(&html, "html", "dir"), vec![dir], "{}", lang);
+    }
+}
@@ -0,0 +1,156 @@
+// Shrinking old attachments to thumbnails, see media_prune.rs: oldest
+// first until the files fit the budget or past a given age, never a sticky
+// thread's first post, and never a file another post has taken over.
+
+use actix_web::http::StatusCode;
+use serde_json::Value;
+
+use super::{attrs, png, texts, Form, TestBoard};
+use crate::config::Config;
+use crate::audit::AuditEntry;
+use crate::{indexes, load_post, media_prune, now, upload, Post};
+
+const DAY: u64 = 24 * 60 * 60;
+
+// A reply with a `side` pixel square picture, made `days` ago
+async fn pictured_reply(board: &TestBoard, thread: &Post, title: &str, side: u32, days: u64) -> Post {
+    let form = Form::new().text("parent_id", &thread.id).text("title", title).text("message", "Look").file("file", "pic.png", "image/png", &png(side));
+    assert_eq!(board.submit(form).await.status, StatusCode::SEE_OTHER);
+    let mut reply = board.find(title);
+    reply.timestamp = now() - days * DAY;
+    board.db.insert(&reply.id, serde_json::to_vec(&reply).unwrap()).unwrap();
+    reply
+}
+
+fn on_disk(board: &TestBoard, file: &str) -> bool {
+    board.config.upload_path(file).exists()
+}
+
+fn pruned(board: &TestBoard, post: &Post) -> bool {
+    load_post(&board.db, &post.id).unwrap().media_pruned
+}
+
+// A sticky thread with a picture, and a thread with four pictured replies
+// a day apart, oldest first
+async fn pictured_board(adjust: impl FnOnce(&mut Config)) -> (TestBoard, Post, Vec<Post>) {
+    let board = TestBoard::with(adjust);
+    let form = Form::new().text("title", "Rules").text("message", "Read me").file("file", "rules.png", "image/png", &png(40));
+    assert_eq!(board.submit(form).await.status, StatusCode::SEE_OTHER);
+    let sticky = board.find("Rules");
+    indexes::set_sticky(&board.db, &sticky.id, true);
+    let thread = board.thread("Pictures", "Post them").await;
+    let mut replies = Vec::new();
+    for (n, side) in [30, 20, 50, 10].iter().enumerate() {
+        replies.push(pictured_reply(&board, &thread, &format!("Reply {}", n + 1), *side, 4 - n as u64).await);
+    }
+    (board, sticky, replies)
+}
+
+fn media_prune_reports(board: &TestBoard) -> Vec<String> {
+    let audit = board.db.open_tree("audit").unwrap();
+    let entries = audit.iter().values().map(|bytes| serde_json::from_slice::<AuditEntry>(&bytes.unwrap()).unwrap());
+    entries.filter(|entry| entry.action == "media_prune").map(|entry| entry.target).collect()
+}
+
+fn sizes(posts: &[&Post]) -> u64 {
+    posts.iter().map(|post| post.file_size.unwrap()).sum()
+}
+
+#[actix_web::test]
+async fn the_oldest_go_until_the_rest_fit_the_budget() {
+    let (mut board, sticky, replies) = pictured_board(|_| {}).await;
+    // Room for the sticky picture and the two newest replies' only
+    let budget = sizes(&[&sticky, &replies[2], &replies[3]]);
+    board.config.media_budget = Some(budget);
+    let files: Vec<String> = replies.iter().map(|reply| reply.file.clone().unwrap()).collect();
+
+    assert_eq!(media_prune::run(&board.db, &board.config, &board.state.events, now()), 2);
+    assert!(pruned(&board, &replies[0]) && pruned(&board, &replies[1]));
+    assert!(!pruned(&board, &replies[2]) && !pruned(&board, &replies[3]) && !pruned(&board, &sticky));
+    assert!(!on_disk(&board, &files[0]) && !on_disk(&board, &files[1]));
+    assert!(on_disk(&board, &files[2]) && on_disk(&board, &files[3]));
+    // Now within it, so another run has nothing to do
+    assert_eq!(media_prune::run(&board.db, &board.config, &board.state.events, now()), 0);
+
+    let freed = sizes(&[&replies[0], &replies[1]]);
+    let expected = format!("2 files, {}; {} left of a {} budget", board.config.human_size(freed), board.config.human_size(budget), board.config.human_size(budget));
+    assert_eq!(media_prune_reports(&board), [expected]);
+}
+
+#[actix_web::test]
+async fn a_pruned_post_shows_its_thumbnail() {
+    let (board, _, replies) = pictured_board(|config| config.media_prune_after = Some(3 * DAY)).await;
+    // Replies 1 and 2 are four and three days old
+    assert_eq!(media_prune::run(&board.db, &board.config, &board.state.events, now()), 2);
+    let post = load_post(&board.db, &replies[0].id).unwrap();
+    assert_eq!(post.file, None);
+    let thumbnail = post.thumbnail.clone().unwrap();
+    assert!(thumbnail.ends_with("-thumb.png"), "{}", thumbnail);
+    // Small pictures keep their size
+    assert_eq!(image::open(board.config.upload_path(&thumbnail)).unwrap().width(), 30);
+    assert!(!upload::stored_files(&board.config.upload_dir).contains(&replies[0].file.clone().unwrap()));
+    let thread = load_post(&board.db, post.parent_id.as_deref().unwrap()).unwrap();
+    let large = pictured_reply(&board, &thread, "Large", 400, 5).await;
+    assert_eq!(media_prune::run(&board.db, &board.config, &board.state.events, now()), 1);
+    let large_thumbnail = load_post(&board.db, &large.id).unwrap().thumbnail.unwrap();
+    assert_eq!(image::open(board.config.upload_path(&large_thumbnail)).unwrap().width(), 200);
+
+    let html = board.get(&format!("/post/{}", post.parent_id.as_deref().unwrap())).await.html();
+    assert_eq!(attrs(&html, "#r1 img.post-file", "src"), [board.config.upload_url(&thumbnail)]);
+    assert_eq!(texts(&html, "#r1 p.post-file"), ["Full file no longer available."]);
+    assert!(texts(&html, "#r3 p.post-file").is_empty());
+
+    let res = board.get(&format!("/api/post/{}", post.parent_id.as_deref().unwrap())).await;
+    let thread: Value = serde_json::from_str(&res.body).unwrap();
+    let replies_json = thread["replies"].as_array().unwrap();
+    let first = replies_json.iter().find(|reply| reply["id"] == replies[0].id.as_str()).unwrap();
+    assert_eq!((&first["media_pruned"], &first["file_url"]), (&Value::Bool(true), &Value::Null));
+    assert_eq!(first["thumbnail_url"], board.config.upload_url(&thumbnail).as_str());
+    let third = replies_json.iter().find(|reply| reply["id"] == replies[2].id.as_str()).unwrap();
+    assert_eq!((&third["media_pruned"], &third["thumbnail_url"]), (&Value::Bool(false), &Value::Null));
+}
+
+#[actix_web::test]
+async fn sticky_pictures_count_but_stay() {
+    // A budget of one byte is over for everything
+    let (board, sticky, replies) = pictured_board(|config| config.media_budget = Some(1)).await;
+    assert_eq!(media_prune::run(&board.db, &board.config, &board.state.events, now()), 4);
+    assert!(replies.iter().all(|reply| pruned(&board, reply)));
+    assert!(!pruned(&board, &sticky));
+    assert!(on_disk(&board, sticky.file.as_deref().unwrap()));
+}
+
+#[actix_web::test]
+async fn a_dry_run_changes_nothing() {
+    let (board, _, replies) = pictured_board(|config| {
+        config.media_budget = Some(1);
+        config.media_prune_dry_run = true;
+    })
+    .await;
+    assert_eq!(media_prune::run(&board.db, &board.config, &board.state.events, now()), 0);
+    assert!(replies.iter().all(|reply| !pruned(&board, reply) && on_disk(&board, reply.file.as_deref().unwrap())));
+    assert!(media_prune_reports(&board).is_empty());
+}
+
+#[actix_web::test]
+async fn a_file_another_post_took_over_is_kept() {
+    let (board, _, replies) = pictured_board(|config| config.media_prune_after = Some(3 * DAY)).await;
+    // The newest reply shares the oldest one's file, and the uploads index
+    // names it as the owner now
+    let shared = replies[0].file.clone().unwrap();
+    let mut newer = load_post(&board.db, &replies[3].id).unwrap();
+    std::fs::remove_file(board.config.upload_path(newer.file.as_deref().unwrap())).unwrap();
+    upload::forget(&board.db, &newer);
+    newer.file = Some(shared.clone());
+    board.db.insert(&newer.id, serde_json::to_vec(&newer).unwrap()).unwrap();
+    upload::index(&board.db, &newer);
+
+    // Only reply 2 is due: the older reply no longer owns its file, and
+    // the owner is new
+    assert_eq!(media_prune::run(&board.db, &board.config, &board.state.events, now()), 1);
+    assert!(pruned(&board, &replies[1]));
+    assert!(!pruned(&board, &replies[0]) && !pruned(&board, &newer));
+    assert!(on_disk(&board, &shared));
+    assert_eq!(upload::owner(&board.db, &shared).unwrap().id, newer.id);
+    assert_eq!(attrs(&board.get(&format!("/post/{}", newer.parent_id.as_deref().unwrap())).await.html(), "#r4 img.post-file", "src"), [board.config.upload_url(&shared)]);
+}
@@ -0,0 +1,79 @@
+// `--db memory`, see open_db: the board on a throwaway sled database that
+// starts empty, leaves no my_db behind, and answers the same as one on disk.
+
+use actix_web::http::StatusCode;
+use serde_json::Value;
+use std::path::Path;
+
+use super::{attrs, png, texts, Form, TestBoard};
+use crate::startup::ServerFlags;
+use crate::{edits, storage};
+
+fn flags(args: &[&str]) -> Result<ServerFlags, String> {
+    ServerFlags::parse(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+}
+
+// The same visits on `board`, as what each showed with post ids left out
+async fn tour(board: &TestBoard) -> Vec<String> {
+    let mut seen = Vec::new();
+    let form = Form::new().text("title", "Pictured").text("message", "Start").file("file", "pic.png", "image/png", &png(16));
+    seen.push(board.submit(form).await.status.to_string());
+    let thread = board.find("Pictured");
+    let first = board.reply(&thread, "First", "one").await;
+    board.reply(&thread, "Second", ">>1 yes").await;
+    let other = board.thread("Other", "Later").await;
+    edits::edit(&board.db, &first.id, "one, edited", "admin", None).unwrap();
+    seen.push(board.submit(Form::new().text("title", "No message")).await.status.to_string());
+
+    // Sorted, as posts in the same second are ordered by their random ids
+    let mut listed = texts(&board.get("/").await.html(), ".post h3 bdi");
+    listed.sort();
+    seen.extend(listed);
+    let page = board.get(&format!("/post/{}", thread.id)).await.html();
+    seen.extend(texts(&page, ".reply h4 > a:first-child"));
+    seen.extend(texts(&page, ".reply p[dir=auto]"));
+    seen.extend(attrs(&page, "#r1 .backlinks a", "href"));
+    seen.push(attrs(&page, ".original-post img", "width").join(","));
+    let res = board.get(&format!("/post/{}/2", thread.id)).await;
+    seen.push(res.location().replace(&thread.id, "{thread}"));
+
+    storage::delete_thread(&board.db, &board.config, &other.id);
+    let listed: Value = serde_json::from_str(&board.get("/api/threads").await.body).unwrap();
+    let titles = listed["threads"].as_array().unwrap().iter().map(|thread| thread["title"].to_string());
+    seen.extend(titles);
+    seen.push(board.get(&format!("/post/{}", other.id)).await.status.to_string());
+    seen
+}
+
+#[actix_web::test]
+async fn a_board_in_memory_answers_as_one_on_disk() {
+    let on_disk = tour(&TestBoard::new()).await;
+    let in_memory = tour(&TestBoard::in_memory(|_| {})).await;
+    assert_eq!(in_memory, on_disk);
+    assert_eq!(on_disk.first().map(String::as_str), Some("303 See Other"));
+    assert!(on_disk.contains(&"one, edited".to_string()), "{:?}", on_disk);
+}
+
+#[actix_web::test]
+async fn a_board_in_memory_starts_empty_and_leaves_nothing() {
+    let had_my_db = Path::new("my_db").exists();
+    let board = TestBoard::in_memory(|_| {});
+    assert!(board.db.iter().next().is_none());
+    assert_eq!(board.get("/").await.status, StatusCode::OK);
+    board.thread("Thread", "Start").await;
+    assert_eq!(texts(&board.get("/").await.html(), ".post h3 bdi"), ["Thread"]);
+    // Each is its own
+    let other = TestBoard::in_memory(|_| {});
+    assert!(texts(&other.get("/").await.html(), ".post h3 bdi").is_empty());
+    drop(board);
+    assert_eq!(Path::new("my_db").exists(), had_my_db);
+}
+
+#[test]
+fn only_memory_is_taken_for_db() {
+    assert!(flags(&["--db", "memory"]).unwrap().in_memory);
+    assert!(!flags(&[]).unwrap().in_memory);
+    for bad in [&["--db"][..], &["--db", "disk"], &["--db", "MEMORY"], &["--db", "my_db"]] {
+        assert_eq!(flags(bad).err().as_deref(), Some("usage: --db memory"), "{:?}", bad);
+    }
+}
@@ -0,0 +1,418 @@
+// Tests against the whole board, through the same `app` the server runs.
+// Each TestBoard has its own database and upload directory in a temporary
+// directory that goes when it's dropped, so tests don't see each other.
+//
+// What's here is the harness: a board to send requests to, a builder for
+// the multipart bodies /submit takes, and helpers for reading pages with
+// CSS selectors rather than searching the HTML text. The tests themselves
+// are in the files below, one per area of the board.
+
+mod admin;
+mod age_gate;
+mod announcements;
+mod api;
+mod archive;
+mod base_path;
+mod downloads;
+mod edits;
+mod events;
+mod first_run;
+mod format;
+mod head;
+#[cfg(unix)]
+mod heif;
+mod lifecycle;
+mod limits;
+mod low_disk;
+mod manifest;
+mod markup;
+mod media_prune;
+mod memory;
+mod moderation;
+mod multipart;
+mod notify;
+mod options;
+mod paths;
+mod quotes;
+mod rate_limits;
+mod rejections;
+mod reload;
+mod remember;
+mod replay;
+mod replies;
+mod reply_pages;
+mod retention;
+mod slow_mode;
+mod sorting;
+mod spoofing;
+mod spam;
+mod sticky_order;
+mod themes;
+mod upload_slots;
+mod uploads;
+mod webhooks;
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::Payload;
+use actix_web::error::PayloadError;
+use actix_web::http::header::{HeaderMap, ACCEPT, CONTENT_TYPE, COOKIE, LOCATION, SET_COOKIE};
+use actix_web::http::StatusCode;
+use actix_web::test::{self, TestRequest};
+use actix_web::web::{self, Bytes};
+use futures_util::Stream;
+use image::{ImageBuffer, ImageOutputFormat, Rgb};
+use scraper::{ElementRef, Html, Selector};
+use sled::Db;
+use std::io::Cursor;
+use std::pin::Pin;
+use tempfile::TempDir;
+use uuid::Uuid;
+
+use crate::archive_db::ArchiveDb;
+use crate::config::Config;
+use crate::diskspace::{DiskGuard, SpaceProbe};
+use crate::events::{EventBus, EventHandler};
+use crate::rate_limit::{BucketPolicy, RouteClass};
+use crate::runtime::{RuntimeCache, RuntimeSettings};
+use crate::startup::ServerFlags;
+use crate::{app, event_handlers, open_db, prepare_db, startup, upload, AppState, Post};
+
+// Rate limits high enough that no test runs into them
+const UNLIMITED: BucketPolicy = BucketPolicy {
+    burst: 1_000_000.0,
+    per_second: 1_000_000.0,
+};
+
+type BodyStream = Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>>;
+
+pub struct TestBoard {
+    pub db: Db,
+    pub config: Config,
+    state: AppState,
+    // Holds the database and uploads until the test ends
+    _dir: TempDir,
+}
+
+impl TestBoard {
+    pub fn new() -> TestBoard {
+        TestBoard::with(|_| {})
+    }
+
+    // A board with the config changed by `adjust`, which starts from the
+    // environment's defaults without rate limits. The upload directory is
+    // set up from there the way the server does it.
+    pub fn with(adjust: impl FnOnce(&mut Config)) -> TestBoard {
+        TestBoard::on(adjust, |dir, config| {
+            let db = sled::Config::new().path(dir.path().join("db")).open().unwrap();
+            prepare_db(&db, config);
+            db
+        })
+    }
+
+    // The same on the throwaway database `--db memory` serves from
+    pub fn in_memory(adjust: impl FnOnce(&mut Config)) -> TestBoard {
+        let flags = ServerFlags {
+            in_memory: true,
+            ..ServerFlags::default()
+        };
+        TestBoard::on(adjust, |_, config| open_db(config, &flags))
+    }
+
+    fn on(adjust: impl FnOnce(&mut Config), open: impl FnOnce(&TempDir, &Config) -> Db) -> TestBoard {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::from_env();
+        config.upload_dir = dir.path().join("uploads");
+        adjust(&mut config);
+        config.upload_dir = startup::prepare_upload_dir(&config.upload_dir).unwrap();
+        upload::clean_temp(&config.upload_dir);
+        let db = open(&dir, &config);
+        let mut state = AppState::new(db.clone(), config.clone());
+        let unlimited = RuntimeSettings {
+            rate_limit_write: UNLIMITED,
+            rate_limit_render: UNLIMITED,
+            rate_limit_cheap: UNLIMITED,
+            ..RuntimeSettings::from_env()
+        };
+        state.runtime = web::Data::new(RuntimeCache::new(unlimited, config.config_file.clone()));
+        TestBoard {
+            db,
+            config,
+            state,
+            _dir: dir,
+        }
+    }
+
+    // Puts a limit on one class of routes, which TestBoard::with leaves
+    // unlimited
+    pub fn limit(&mut self, class: RouteClass, policy: BucketPolicy) {
+        self.runtime(|settings| match class {
+            RouteClass::Write => settings.rate_limit_write = policy,
+            RouteClass::Render => settings.rate_limit_render = policy,
+            RouteClass::Cheap => settings.rate_limit_cheap = policy,
+        });
+    }
+
+    // Changes the settings an admin can change while the board runs
+    pub fn runtime(&mut self, adjust: impl FnOnce(&mut RuntimeSettings)) {
+        let mut settings = *self.state.runtime.get();
+        adjust(&mut settings);
+        self.state.runtime = web::Data::new(RuntimeCache::new(settings, self.config.config_file.clone()));
+    }
+
+    // Reads free upload space from `probe` instead of the volume, returning
+    // the guard so a test can run the maintenance job's check
+    pub fn probe_disk(&mut self, probe: impl SpaceProbe + 'static) -> web::Data<DiskGuard> {
+        self.state.disk = web::Data::new(DiskGuard::new(&self.config, Box::new(probe)));
+        self.state.disk.clone()
+    }
+
+    // Starts the board's event bus again with `extra` after its own
+    // handlers, for tests that watch what gets published
+    pub fn listen(&mut self, extra: Vec<Box<dyn EventHandler>>) -> web::Data<EventBus> {
+        let mut handlers = event_handlers(&self.db, &self.config, &self.state.settings);
+        handlers.extend(extra);
+        self.state.events = web::Data::new(EventBus::start(handlers));
+        self.state.events.clone()
+    }
+
+    // Where archived threads go when config.archive_db is set
+    pub fn archive(&self) -> &ArchiveDb {
+        &self.state.archive
+    }
+
+    pub async fn send(&self, req: TestRequest) -> Response {
+        self.call(req, None).await
+    }
+
+    // Sends `req` with its body read from `body`, which can stall or fail
+    // partway as a real connection might
+    pub async fn send_stream<S>(&self, req: TestRequest, body: S) -> Response
+    where
+        S: Stream<Item = Result<Bytes, PayloadError>> + 'static,
+    {
+        self.call(req, Some(Box::pin(body))).await
+    }
+
+    async fn call(&self, req: TestRequest, body: Option<BodyStream>) -> Response {
+        let service = test::init_service(app(self.state.clone())).await;
+        let req = match body {
+            Some(body) => req.to_request().replace_payload(Payload::Stream { payload: body }).0,
+            None => req.to_request(),
+        };
+        let res = test::call_service(&service, req).await;
+        let status = res.status();
+        let headers = res.headers().clone();
+        let size = res.response().body().size();
+        let body = test::read_body(res).await;
+        Response {
+            status,
+            headers,
+            size,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        }
+    }
+
+    pub async fn get(&self, path: &str) -> Response {
+        self.send(TestRequest::get().uri(path)).await
+    }
+
+    pub async fn submit(&self, form: Form) -> Response {
+        self.send(form.request("/submit")).await
+    }
+
+    // Starts a thread and returns it, failing the test if it was refused
+    pub async fn thread(&self, title: &str, message: &str) -> Post {
+        let res = self.submit(Form::new().text("title", title).text("message", message)).await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+        self.find(title)
+    }
+
+    pub async fn reply(&self, thread: &Post, title: &str, message: &str) -> Post {
+        let form = Form::new().text("parent_id", &thread.id).text("title", title).text("message", message);
+        let res = self.submit(form).await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+        self.find(title)
+    }
+
+    // The stored post with this title; tests give their posts distinct ones
+    pub fn find(&self, title: &str) -> Post {
+        let mut found: Vec<Post> = self
+            .db
+            .iter()
+            .values()
+            .filter_map(|bytes| Post::upgrade(&bytes.unwrap()).ok())
+            .filter(|post| post.title == title)
+            .collect();
+        assert_eq!(found.len(), 1, "posts titled {:?}", title);
+        found.pop().unwrap()
+    }
+}
+
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    // What the body said it would be, which the server's Content-Length
+    // comes from
+    pub size: BodySize,
+    // Lossy for files, which the tests only check were served
+    pub body: String,
+}
+
+impl Response {
+    pub fn location(&self) -> &str {
+        self.headers.get(LOCATION).expect("no Location header").to_str().unwrap()
+    }
+
+    pub fn html(&self) -> Html {
+        assert_eq!(self.status, StatusCode::OK, "{}", self.body);
+        Html::parse_document(&self.body)
+    }
+}
+
+// A multipart/form-data body as the post forms send it, starting with a
+// fresh submit token like a page just loaded
+pub struct Form {
+    boundary: String,
+    body: Vec<u8>,
+    json: bool,
+}
+
+impl Form {
+    pub fn new() -> Form {
+        Form::with_token(&Uuid::new_v4().to_string())
+    }
+
+    // The same form sent again, or sent twice at once, shares its token
+    pub fn with_token(token: &str) -> Form {
+        let form = Form {
+            boundary: Uuid::new_v4().simple().to_string(),
+            body: Vec::new(),
+            json: false,
+        };
+        form.text("submit_token", token)
+    }
+
+    pub fn text(mut self, name: &str, value: &str) -> Form {
+        let head = format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n", self.boundary, name);
+        self.body.extend_from_slice(head.as_bytes());
+        self.body.extend_from_slice(value.as_bytes());
+        self.body.extend_from_slice(b"\r\n");
+        self
+    }
+
+    // The file name is quoted with backslash escapes, as curl sends it
+    pub fn file(mut self, name: &str, file_name: &str, content_type: &str, bytes: &[u8]) -> Form {
+        let file_name = file_name.replace('\\', "\\\\").replace('"', "\\\"");
+        let head = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+            self.boundary, name, file_name, content_type
+        );
+        self.body.extend_from_slice(head.as_bytes());
+        self.body.extend_from_slice(bytes);
+        self.body.extend_from_slice(b"\r\n");
+        self
+    }
+
+    // A part with `disposition` as its Content-Disposition, for shapes no
+    // browser sends
+    pub fn raw(mut self, disposition: &str, bytes: &[u8]) -> Form {
+        let head = format!("--{}\r\nContent-Disposition: {}\r\n\r\n", self.boundary, disposition);
+        self.body.extend_from_slice(head.as_bytes());
+        self.body.extend_from_slice(bytes);
+        self.body.extend_from_slice(b"\r\n");
+        self
+    }
+
+    // Asks for errors as JSON, like scripts do
+    pub fn json(mut self) -> Form {
+        self.json = true;
+        self
+    }
+
+    pub fn request(self, path: &str) -> TestRequest {
+        let (req, body) = self.split(path);
+        req.set_payload(body)
+    }
+
+    // The request without its body, and the body, for sending in pieces
+    // with TestBoard::send_stream
+    pub fn split(mut self, path: &str) -> (TestRequest, Vec<u8>) {
+        self.body.extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        let content_type = format!("multipart/form-data; boundary={}", self.boundary);
+        let req = TestRequest::post().uri(path).insert_header((CONTENT_TYPE, content_type));
+        let req = if self.json { req.insert_header((ACCEPT, "application/json")) } else { req };
+        (req, self.body)
+    }
+}
+
+// An admin's session: the cookie, and the CSRF token their forms carry
+pub struct AdminLogin {
+    pub cookie: String,
+    pub csrf: String,
+}
+
+impl AdminLogin {
+    pub fn get(&self, path: &str) -> TestRequest {
+        TestRequest::get().uri(path).insert_header((COOKIE, self.cookie.as_str()))
+    }
+
+    // A POST the way the admin's forms send it, token and all
+    pub fn post(&self, path: &str) -> TestRequest {
+        let separator = if path.contains('?') { '&' } else { '?' };
+        TestRequest::post()
+            .uri(&format!("{}{}csrf={}", path, separator, self.csrf))
+            .insert_header((COOKIE, self.cookie.as_str()))
+    }
+}
+
+// Logs in as "Mod" on a board with admin_password "secret". The token is
+// read off the settings form, as a browser would have it.
+pub async fn admin_login(board: &TestBoard) -> AdminLogin {
+    let base_path = &board.config.base_path;
+    let req = TestRequest::post()
+        .uri(&format!("{}/admin/login", base_path))
+        .insert_header((CONTENT_TYPE, "application/x-www-form-urlencoded"))
+        .set_payload("name=Mod&password=secret");
+    let res = board.send(req).await;
+    let cookie = res.headers.get(SET_COOKIE).unwrap().to_str().unwrap();
+    let cookie = cookie.split(';').next().unwrap().to_string();
+
+    let mut login = AdminLogin {
+        cookie,
+        csrf: String::new(),
+    };
+    let html = board.send(login.get(&format!("{}/admin/settings", base_path))).await.html();
+    let action = attrs(&html, "form.settings-form", "action").remove(0);
+    login.csrf = action.split("csrf=").nth(1).unwrap().to_string();
+    login
+}
+
+// A small PNG of one colour
+pub fn png(side: u32) -> Vec<u8> {
+    let image = ImageBuffer::from_pixel(side, side, Rgb([200u8, 80, 40]));
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageOutputFormat::Png).unwrap();
+    png.into_inner()
+}
+
+pub fn select<'a>(html: &'a Html, selector: &str) -> Vec<ElementRef<'a>> {
+    let selector = Selector::parse(selector).unwrap_or_else(|e| panic!("bad selector {:?}: {:?}", selector, e));
+    html.select(&selector).collect()
+}
+
+// The text of each element `selector` matches, as the browser shows it
+pub fn texts(html: &Html, selector: &str) -> Vec<String> {
+    select(html, selector)
+        .iter()
+        .map(|element| element.text().collect::<String>().trim().to_string())
+        .collect()
+}
+
+// One attribute of each element `selector` matches that has it
+pub fn attrs(html: &Html, selector: &str, attr: &str) -> Vec<String> {
+    select(html, selector)
+        .iter()
+        .filter_map(|element| element.value().attr(attr))
+        .map(str::to_string)
+        .collect()
+}
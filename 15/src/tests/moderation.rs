@@ -0,0 +1,54 @@
+// Signals for moderators, see moderation.rs: the same image turning up
+// from several posters is grouped for review.
+
+use actix_web::http::StatusCode;
+use actix_web::rt::time;
+use scraper::Html;
+use std::collections::HashSet;
+use std::time::Duration;
+
+use super::{admin_login, png, select, texts, AdminLogin, Form, TestBoard};
+
+// The check runs on the event thread, so the page is read until it shows
+// what's expected or this gives up
+async fn flagged_page(board: &TestBoard, admin: &AdminLogin, groups: usize) -> Html {
+    for _ in 0..200 {
+        let res = board.send(admin.get("/admin/flagged-images")).await;
+        assert_eq!(res.status, StatusCode::OK);
+        let html = res.html();
+        if select(&html, ".flagged-group").len() == groups {
+            return html;
+        }
+        time::sleep(Duration::from_millis(25)).await;
+    }
+    panic!("never saw {} flagged groups", groups);
+}
+
+#[actix_web::test]
+async fn one_image_from_three_posters_is_one_group_of_three() {
+    let board = TestBoard::with(|config| {
+        config.admin_password = Some("secret".to_string());
+        config.duplicate_image_threshold = 2;
+    });
+    let admin = admin_login(&board).await;
+    for (n, ip) in ["10.0.0.1", "10.0.0.2", "10.0.0.3"].iter().enumerate() {
+        let form = Form::new()
+            .text("title", &format!("Spam {}", n))
+            .text("message", "Cheap watches")
+            .file("file", "ad.png", "image/png", &png(32));
+        let req = form.request("/submit").peer_addr(format!("{}:4000", ip).parse().unwrap());
+        assert_eq!(board.send(req).await.status, StatusCode::SEE_OTHER);
+    }
+    // A different image from one of them isn't part of it
+    let form = Form::new().text("title", "Other").text("message", "x").file("file", "cat.png", "image/png", &png(48));
+    let req = form.request("/submit").peer_addr("10.0.0.1:4000".parse().unwrap());
+    assert_eq!(board.send(req).await.status, StatusCode::SEE_OTHER);
+
+    let html = flagged_page(&board, &admin, 1).await;
+    let mut titles = texts(&html, ".flagged-group tr strong");
+    titles.sort();
+    assert_eq!(titles, ["Spam 0", "Spam 1", "Spam 2"]);
+    let posters: HashSet<String> = texts(&html, ".flagged-group td .ip-hash").into_iter().collect();
+    assert_eq!(posters.len(), 3);
+    assert_eq!(texts(&html, ".flagged-group > p .muted"), ["on 3 posts"]);
+}
@@ -0,0 +1,102 @@
+// Post forms in shapes no browser sends, see save_post and intake.rs: parts
+// without a name and fields sent twice are refused with a 400, never a 500
+// or a post missing what came after them, and parts with an empty name or
+// an empty file are skipped. Nothing not kept leaves a file behind.
+
+use actix_web::http::StatusCode;
+use serde_json::Value;
+
+use super::{png, Form, Response, TestBoard};
+use crate::upload;
+
+fn thread(title: &str) -> Form {
+    Form::new().text("title", title).text("message", "Start")
+}
+
+fn assert_no_files(board: &TestBoard) {
+    assert!(upload::stored_files(&board.config.upload_dir).is_empty());
+    let temp = board.config.upload_dir.join(upload::TEMP_DIR);
+    assert_eq!(std::fs::read_dir(temp).unwrap().count(), 0);
+}
+
+fn assert_not_stored(board: &TestBoard, title: &str) {
+    assert!(board.db.iter().values().all(|bytes| !String::from_utf8_lossy(&bytes.unwrap()).contains(title)), "{}", title);
+}
+
+async fn refused(board: &TestBoard, form: Form) -> Response {
+    let res = board.submit(form.json()).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", res.body);
+    res
+}
+
+#[actix_web::test]
+async fn a_part_without_a_name_is_refused_and_drops_the_upload() {
+    let board = TestBoard::new();
+    // Before the file and after it
+    let shapes = [
+        ("Bare", thread("Bare").raw("form-data", b"stray").file("file", "pic.png", "image/png", &png(8))),
+        ("Filename only", thread("Filename only").file("file", "pic.png", "image/png", &png(8)).raw("form-data; filename=\"b.png\"", &png(9))),
+        ("Not form data", thread("Not form data").file("file", "pic.png", "image/png", &png(8)).raw("attachment; name=\"message\"", b"x")),
+    ];
+    for (title, form) in shapes {
+        let res = refused(&board, form).await;
+        let body: Value = serde_json::from_str(&res.body).unwrap();
+        assert_eq!(body["fields"][0]["message"], "The form arrived in an unexpected shape. Reload the page and try again.", "{}", title);
+        assert_not_stored(&board, title);
+    }
+    assert_no_files(&board);
+}
+
+#[actix_web::test]
+async fn a_part_with_an_empty_name_is_skipped() {
+    let board = TestBoard::new();
+    for (title, disposition) in [("Empty", "form-data; name=\"\""), ("Empty, filename", "form-data; name=\"\"; filename=\"b.png\"")].iter() {
+        let form = thread(title).raw(disposition, &png(9)).file("file", "pic.png", "image/png", &png(8)).raw(disposition, &[b'x'; 4096]);
+        let res = board.submit(form).await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER, "{}: {}", title, res.body);
+        let post = board.find(title);
+        assert_eq!(post.message, "Start");
+        assert_eq!(post.file_size, Some(png(8).len() as u64), "{}", title);
+    }
+    // Only the named uploads are kept
+    assert_eq!(upload::stored_files(&board.config.upload_dir).len(), 2);
+    let temp = board.config.upload_dir.join(upload::TEMP_DIR);
+    assert_eq!(std::fs::read_dir(temp).unwrap().count(), 0);
+}
+
+#[actix_web::test]
+async fn an_empty_file_part_is_no_file() {
+    let board = TestBoard::new();
+    let res = board.submit(thread("Textual").file("file", "empty.png", "image/png", b"")).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    let post = board.find("Textual");
+    assert_eq!((post.file, post.file_size), (None, None));
+    assert_no_files(&board);
+    let html = board.get(&format!("/post/{}", post.id)).await.html();
+    assert!(super::select(&html, ".original-post img").is_empty());
+}
+
+#[actix_web::test]
+async fn fields_sent_twice_are_refused() {
+    let board = TestBoard::new();
+    let thread_id = board.thread("Thread", "Start").await.id;
+    let shapes = [
+        ("title", thread("Twice").text("title", "Again")),
+        ("message", thread("Twice").text("message", "Again")),
+        ("name", thread("Twice").text("name", "One").text("name", "Two")),
+        ("options", thread("Twice").text("options", "sage").text("options", "sage")),
+        ("email", thread("Twice").text("email", "a@example.com").text("email", "b@example.com")),
+        ("parent_id", thread("Twice").text("parent_id", &thread_id).text("parent_id", &thread_id)),
+        ("file", thread("Twice").file("file", "a.png", "image/png", &png(8)).file("file", "b.png", "image/png", &png(9))),
+    ];
+    for (field, form) in shapes {
+        let res = refused(&board, form).await;
+        let body: Value = serde_json::from_str(&res.body).unwrap();
+        assert_eq!(body["fields"][0]["field"], "post", "{}", field);
+        assert_eq!(body["fields"][0]["message"], "The form arrived in an unexpected shape. Reload the page and try again.", "{}", field);
+    }
+    assert_not_stored(&board, "Twice");
+    assert_not_stored(&board, "Again");
+    assert_no_files(&board);
+    assert!(crate::indexes::thread_replies(&board.db, &thread_id).is_empty());
+}
@@ -0,0 +1,171 @@
+// Reply notification emails, see notify.rs: sent through lettre's stub
+// transport, at most one per thread a day however many replies come in, and
+// none once the link in them has been followed.
+
+use actix_web::http::{Method, StatusCode};
+use actix_web::test::TestRequest;
+use lettre::transport::stub::StubTransport;
+use std::time::{Duration, Instant};
+
+use super::{Form, TestBoard};
+use crate::{notify, now, storage, Post};
+
+const DAY: u64 = 24 * 60 * 60;
+
+fn board() -> TestBoard {
+    TestBoard::with(|config| {
+        config.smtp_host = Some("smtp.example".to_string());
+        config.smtp_from = Some("Board <board@example.com>".to_string());
+        config.notify_key = Some("notify secret".to_string());
+        config.public_url = "https://board.example".to_string();
+    })
+}
+
+async fn thread_with_email(board: &TestBoard, title: &str, email: &str) -> Post {
+    let res = board.submit(Form::new().text("title", title).text("message", "Start").text("email", email)).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    board.find(title)
+}
+
+// The message with its quoted-printable body decoded, which is enough for
+// the ASCII the tests look for
+fn quoted_printable(message: &str) -> String {
+    let (headers, body) = message.split_once("\r\n\r\n").unwrap();
+    let joined = body.replace("=\r\n", "");
+    let mut decoded = Vec::new();
+    let mut bytes = joined.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'=' => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).unwrap(), 16).unwrap());
+            }
+            _ => decoded.push(byte),
+        }
+    }
+    format!("{}\r\n\r\n{}", headers, String::from_utf8(decoded).unwrap())
+}
+
+// Replies mark their thread from the event handler, in the background
+fn wait_until_due(board: &TestBoard, thread: &Post) {
+    let due = board.db.open_tree("notify_due").unwrap();
+    let started = Instant::now();
+    while !due.contains_key(&thread.id).unwrap() {
+        assert!(started.elapsed() < Duration::from_secs(10), "thread never marked due");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[actix_web::test]
+async fn one_email_per_thread_per_day() {
+    let board = board();
+    let thread = thread_with_email(&board, "Mine", "op@example.com").await;
+    let quiet = board.thread("Quiet", "No address").await;
+    // Kept encrypted
+    let stored = board.db.open_tree("notify_emails").unwrap().get(&thread.id).unwrap().unwrap();
+    assert!(!String::from_utf8_lossy(&stored).contains("op@example.com"));
+    assert!(!board.db.open_tree("notify_emails").unwrap().contains_key(&quiet.id).unwrap());
+
+    for n in 1..=3 {
+        board.reply(&thread, &format!("Reply {}", n), "Hi").await;
+    }
+    board.reply(&quiet, "Unheard", "Hi").await;
+    wait_until_due(&board, &thread);
+    let transport = StubTransport::new_ok();
+    let today = now();
+    assert_eq!(notify::send_due(&board.db, &board.config, &transport, today), 1);
+    let messages = transport.messages();
+    assert_eq!(messages.len(), 1);
+    let (envelope, message) = &messages[0];
+    let message = &quoted_printable(message);
+    assert_eq!(envelope.to().iter().map(|to| to.to_string()).collect::<Vec<_>>(), ["op@example.com"]);
+    assert!(message.contains("Subject: New reply in \"Mine\""), "{}", message);
+    assert!(message.contains(&format!("https://board.example/post/{}", thread.id)), "{}", message);
+    let token = notify::unsubscribe_token(&board.config, &thread.id);
+    assert!(message.contains(&format!("https://board.example/unsubscribe/{}", token)), "{}", message);
+
+    // More replies the same day don't mail again
+    notify::reply_added(&board.db, &board.config, &thread.id, today);
+    assert_eq!(notify::send_due(&board.db, &board.config, &transport, today), 0);
+    assert_eq!(transport.messages().len(), 1);
+
+    // The next day's first reply does
+    notify::reply_added(&board.db, &board.config, &thread.id, today + DAY);
+    notify::reply_added(&board.db, &board.config, &thread.id, today + DAY);
+    assert_eq!(notify::send_due(&board.db, &board.config, &transport, today + DAY), 1);
+    assert_eq!(transport.messages().len(), 2);
+}
+
+#[actix_web::test]
+async fn a_failed_send_isnt_retried_the_same_day() {
+    let board = board();
+    let thread = thread_with_email(&board, "Mine", "op@example.com").await;
+    let today = now();
+    notify::reply_added(&board.db, &board.config, &thread.id, today);
+    assert_eq!(notify::send_due(&board.db, &board.config, &StubTransport::new_error(), today), 0);
+    notify::reply_added(&board.db, &board.config, &thread.id, today);
+    let transport = StubTransport::new_ok();
+    assert_eq!(notify::send_due(&board.db, &board.config, &transport, today), 0);
+    notify::reply_added(&board.db, &board.config, &thread.id, today + DAY);
+    assert_eq!(notify::send_due(&board.db, &board.config, &transport, today + DAY), 1);
+}
+
+#[actix_web::test]
+async fn unsubscribing_stops_the_emails() {
+    let board = board();
+    let thread = thread_with_email(&board, "Mine", "op@example.com").await;
+    let token = notify::unsubscribe_token(&board.config, &thread.id);
+
+    let last = if token.ends_with('0') { '1' } else { '0' };
+    let tampered = format!("{}{}", &token[..token.len() - 1], last);
+    for bad in [tampered, thread.id.clone(), format!("{}.", thread.id)] {
+        assert_eq!(board.get(&format!("/unsubscribe/{}", bad)).await.status, StatusCode::NOT_FOUND, "{}", bad);
+    }
+    assert!(board.db.open_tree("notify_emails").unwrap().contains_key(&thread.id).unwrap());
+
+    let res = board.get(&format!("/unsubscribe/{}", token)).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res.body.contains("any more emails about replies to this thread"), "{}", res.body);
+    assert!(!board.db.open_tree("notify_emails").unwrap().contains_key(&thread.id).unwrap());
+    notify::reply_added(&board.db, &board.config, &thread.id, now());
+    let transport = StubTransport::new_ok();
+    assert_eq!(notify::send_due(&board.db, &board.config, &transport, now()), 0);
+    // Following it twice is fine
+    assert_eq!(board.get(&format!("/unsubscribe/{}", token)).await.status, StatusCode::OK);
+}
+
+// Link checkers and mail scanners follow it with HEAD first
+#[actix_web::test]
+async fn a_head_on_the_link_unsubscribes_no_one() {
+    let board = board();
+    let thread = thread_with_email(&board, "Mine", "op@example.com").await;
+    let token = notify::unsubscribe_token(&board.config, &thread.id);
+    let req = TestRequest::default().method(Method::HEAD).uri(&format!("/unsubscribe/{}", token));
+    let res = board.send(req).await;
+    assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(res.body, "");
+    assert!(board.db.open_tree("notify_emails").unwrap().contains_key(&thread.id).unwrap());
+    // and the link itself still works
+    assert_eq!(board.get(&format!("/unsubscribe/{}", token)).await.status, StatusCode::OK);
+    assert!(!board.db.open_tree("notify_emails").unwrap().contains_key(&thread.id).unwrap());
+}
+
+#[actix_web::test]
+async fn addresses_go_with_their_thread() {
+    let board = board();
+    let thread = thread_with_email(&board, "Mine", "op@example.com").await;
+    notify::reply_added(&board.db, &board.config, &thread.id, now());
+    storage::delete_thread(&board.db, &board.config, &thread.id);
+    for tree in ["notify_emails", "notify_due", "notify_sent"] {
+        assert!(board.db.open_tree(tree).unwrap().is_empty(), "{}", tree);
+    }
+}
+
+#[actix_web::test]
+async fn without_smtp_nothing_is_kept() {
+    let board = TestBoard::new();
+    let thread = thread_with_email(&board, "Mine", "op@example.com").await;
+    notify::reply_added(&board.db, &board.config, &thread.id, now());
+    assert!(board.db.open_tree("notify_emails").unwrap().is_empty());
+    assert!(board.db.open_tree("notify_due").unwrap().is_empty());
+}
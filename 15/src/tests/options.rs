@@ -0,0 +1,103 @@
+// The options and email fields, see validation::parse_options and
+// parse_email: line breaks, control characters and overlong values are
+// tidied away before they reach the remember cookie, a form sent back or
+// a mail header, and < or > are refused outright.
+
+use actix_web::http::header::{COOKIE, SET_COOKIE};
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use serde_json::{json, Value};
+
+use super::{attrs, Form, TestBoard};
+use crate::config::Config;
+use crate::validation::{parse_email, parse_options};
+
+const TEN_KB: usize = 10 * 1024;
+
+fn refusal(raw: &str) -> Value {
+    serde_json::to_value(parse_options(raw).unwrap_err()).unwrap()
+}
+
+#[test]
+fn options_lose_line_breaks_and_control_characters() {
+    assert_eq!(parse_options("#ADMIN\r\nBcc: x@y\nSage").unwrap(), "#admin Bcc: x@y Sage");
+    assert_eq!(parse_options("\0one\x1btwo\tthree\u{85}four\u{7f}").unwrap(), "one two three four");
+    assert_eq!(parse_options("\r\n \n\t").unwrap(), "");
+    // Only the flags poster.rs knows about are lowercased
+    assert_eq!(parse_options("#Admin NoKo").unwrap(), "#admin NoKo");
+}
+
+#[test]
+fn ten_kilobytes_of_options_are_cut_to_a_hundred_characters() {
+    let options = parse_options(&"x".repeat(TEN_KB)).unwrap();
+    assert_eq!(options, "x".repeat(100));
+    // Counted in characters, not bytes, and not ending in the space a cut
+    // between words leaves
+    assert_eq!(parse_options(&"é".repeat(TEN_KB)).unwrap().chars().count(), 100);
+    let words = parse_options(&"abc ".repeat(TEN_KB / 4)).unwrap();
+    assert_eq!(words.len(), 99);
+    assert!(!words.ends_with(' '));
+    let lines = parse_options(&"line\n".repeat(TEN_KB / 5)).unwrap();
+    assert!(!lines.contains('\n') && lines.chars().count() <= 100, "{:?}", lines);
+}
+
+#[test]
+fn angle_brackets_are_refused_wherever_they_are() {
+    let expected = json!({"field": "options", "code": "invalid", "message": "Options can't contain < or >."});
+    let long = format!("{}<", "x".repeat(TEN_KB));
+    for raw in ["<script>", "sage >", "a\n<b", long.as_str()].iter() {
+        assert_eq!(refusal(raw), expected, "{:?}", raw);
+    }
+}
+
+#[test]
+fn email_addresses_that_could_break_a_header_are_refused() {
+    let mut config = Config::from_env();
+    config.smtp_host = Some("smtp.example".to_string());
+    config.smtp_from = Some("board@example.com".to_string());
+    config.notify_key = Some("secret".to_string());
+    assert_eq!(parse_email(&config, " op@example.com ", true).unwrap().as_deref(), Some("op@example.com"));
+    // Only threads take one
+    assert_eq!(parse_email(&config, "op@example.com", false).unwrap(), None);
+    let long = format!("{}@example.com", "a".repeat(TEN_KB));
+    for raw in ["op@example.com\r\nBcc: all@example.com", "op@exa\nmple.com", long.as_str()].iter() {
+        let error = serde_json::to_value(parse_email(&config, raw, true).unwrap_err()).unwrap();
+        assert_eq!(error["code"], "invalid", "{:?}", raw);
+    }
+}
+
+#[actix_web::test]
+async fn remembered_and_sent_back_options_are_the_tidied_ones() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let form = Form::new()
+        .text("parent_id", &thread.id)
+        .text("title", "Mine")
+        .text("message", "Hi")
+        .text("options", "#ADMIN\r\nBcc: x@y\n\0Sage")
+        .text("remember_options", "1");
+    let res = board.submit(form).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    let cookie = res.headers.get(SET_COOKIE).unwrap().to_str().unwrap().split(';').next().unwrap().to_string();
+    let req = TestRequest::get().uri(&format!("/post/{}", thread.id)).insert_header((COOKIE, cookie));
+    let html = board.send(req).await.html();
+    assert_eq!(attrs(&html, ".reply-form input[name=options]", "value"), ["#admin Bcc: x@y Sage"]);
+
+    // A reply the quote check sends back shows them cut to length
+    let form = Form::new()
+        .text("parent_id", &thread.id)
+        .text("title", "Typo")
+        .text("message", ">>9")
+        .text("options", &"word\n".repeat(TEN_KB / 5));
+    let html = board.submit(form).await.html();
+    let options = attrs(&html, "input[name=options]", "value").remove(0);
+    assert!(options.chars().count() <= 100 && !options.contains('\n'), "{:?}", options);
+    assert!(options.starts_with("word word"), "{:?}", options);
+
+    // and refused ones as typed, to be fixed
+    let form = Form::new().text("parent_id", &thread.id).text("title", "Bad").text("message", "Hi").text("options", "<b>");
+    let res = board.submit(form.json()).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    let body: Value = serde_json::from_str(&res.body).unwrap();
+    assert_eq!(body["fields"][0]["field"], "options");
+}
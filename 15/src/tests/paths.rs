@@ -0,0 +1,73 @@
+// Upload directories given in awkward shapes: with a trailing separator,
+// with a name that isn't UTF-8, and with Windows separators. Stored names
+// and URLs use forward slashes whatever the platform.
+
+use actix_web::http::StatusCode;
+use std::path::{Path, PathBuf};
+
+use super::{attrs, png, Form, TestBoard};
+use crate::config::Config;
+use crate::upload;
+
+// Posts an image and checks it landed under the upload directory and is
+// linked and served by a forward-slash URL
+async fn upload_round_trip(board: &TestBoard) {
+    let form = Form::new().text("title", "Picture").text("message", "x").file("file", "a.png", "image/png", &png(16));
+    let res = board.submit(form).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+
+    let post = board.find("Picture");
+    let file = post.file.clone().unwrap();
+    assert_eq!(file.split('/').count(), 3, "{} isn't YYYY/MM/name", file);
+    let on_disk = board.config.upload_path(&file);
+    assert!(on_disk.is_file(), "{} is missing", on_disk.display());
+    assert!(on_disk.starts_with(&board.config.upload_dir));
+
+    let url = format!("/static/uploads/{}", file);
+    let html = board.get(&format!("/post/{}", post.id)).await.html();
+    assert_eq!(attrs(&html, ".original-post img.post-file", "src"), vec![url.clone()]);
+    assert_eq!(board.get(&url).await.status, StatusCode::OK);
+}
+
+#[test]
+fn stored_names_become_platform_paths() {
+    let expected = Path::new("uploads").join("2024").join("01").join("a.png");
+    assert_eq!(upload::path_in(Path::new("uploads"), "2024/01/a.png"), expected);
+    assert_eq!(upload::clean_dir(Path::new("uploads/")), PathBuf::from("uploads"));
+    assert_eq!(upload::clean_dir(Path::new("uploads/./")), PathBuf::from("uploads"));
+    assert_eq!(upload::clean_dir(Path::new("/")), PathBuf::from("/"));
+}
+
+#[test]
+fn urls_use_forward_slashes() {
+    let config = Config::from_env();
+    assert_eq!(config.upload_url("2024/01/a.png"), format!("{}/static/uploads/2024/01/a.png", config.base_path));
+}
+
+#[actix_web::test]
+async fn upload_dir_with_a_trailing_separator() {
+    let board = TestBoard::with(|config| config.upload_dir.push(""));
+    assert!(!board.config.upload_dir.as_os_str().to_string_lossy().ends_with(std::path::MAIN_SEPARATOR));
+    upload_round_trip(&board).await;
+}
+
+#[cfg(unix)]
+#[actix_web::test]
+async fn upload_dir_that_isnt_utf8() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let board = TestBoard::with(|config| config.upload_dir.set_file_name(OsStr::from_bytes(b"up\xffloads")));
+    assert!(board.config.upload_dir.to_str().is_none());
+    upload_round_trip(&board).await;
+}
+
+#[cfg(windows)]
+#[actix_web::test]
+async fn upload_dir_with_backslashes() {
+    let board = TestBoard::with(|config| {
+        let raw = format!("{}\\nested\\uploads\\", config.upload_dir.display());
+        config.upload_dir = upload::clean_dir(Path::new(&raw));
+    });
+    upload_round_trip(&board).await;
+}
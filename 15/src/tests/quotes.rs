@@ -0,0 +1,163 @@
+// Quote backlinks: under each reply, the replies quoting it, kept right as
+// messages are edited and rebuilt the same way by `reindex`. Replies
+// quoting numbers not in their thread come back to be checked, see
+// quotes.rs, with the message kept exactly as typed.
+
+use actix_web::http::StatusCode;
+use scraper::Html;
+use std::collections::HashMap;
+
+use super::{admin_login, attrs, png, select, texts, AdminLogin, Form, Response, TestBoard};
+use crate::{backlinks, changes, counters, reindex, Post};
+
+async fn edit(board: &TestBoard, admin: &AdminLogin, post: &Post, message: &str) {
+    let req = admin.post(&format!("/admin/post/{}/edit", post.id)).set_form([("message", message)]);
+    assert_eq!(board.send(req).await.status, StatusCode::SEE_OTHER);
+}
+
+fn quoted_by(board: &TestBoard, thread: &Post) -> HashMap<u64, Vec<u64>> {
+    backlinks::for_thread(&board.db, &thread.id)
+}
+
+// The quote warning's form sent back as a browser would, with "Post
+// anyway" ticked or not
+fn resend(page: &Response, confirm: bool) -> Form {
+    let html = page.html();
+    let token = attrs(&html, "form input[name=submit_token]", "value").remove(0);
+    let mut form = Form::with_token(&token);
+    for input in select(&html, "form input") {
+        let (name, value) = (input.value().attr("name").unwrap(), input.value().attr("value").unwrap_or_default());
+        match input.value().attr("type") {
+            Some("file") => {}
+            _ if name == "submit_token" => {}
+            Some("checkbox") if name == "quotes_confirmed" => {
+                if confirm {
+                    form = form.text(name, value);
+                }
+            }
+            Some("checkbox") if input.value().attr("checked").is_none() => {}
+            _ => form = form.text(name, value),
+        }
+    }
+    let message = select(&html, "form textarea[name=message]").remove(0).text().collect::<String>();
+    form.text("message", &message)
+}
+
+fn reply_count(board: &TestBoard, thread: &Post) -> usize {
+    crate::indexes::thread_replies(&board.db, &thread.id).len()
+}
+
+#[actix_web::test]
+async fn replies_list_the_replies_quoting_them() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    board.reply(&thread, "One", "first").await;
+    board.reply(&thread, "Two", ">>1 agreed").await;
+    board.reply(&thread, "Three", ">>1\n>>2 both").await;
+
+    let html = board.get(&format!("/post/{}", thread.id)).await.html();
+    assert_eq!(attrs(&html, "#r1 .backlinks a", "href"), vec!["#r2", "#r3"]);
+    assert_eq!(attrs(&html, "#r2 .backlinks a", "href"), vec!["#r3"]);
+    assert!(attrs(&html, "#r3 .backlinks a", "href").is_empty());
+}
+
+#[actix_web::test]
+async fn editing_a_quote_away_moves_its_backlink() {
+    let board = TestBoard::with(|config| config.admin_password = Some("secret".to_string()));
+    let admin = admin_login(&board).await;
+    let thread = board.thread("Thread", "Start").await;
+    board.reply(&thread, "One", "first").await;
+    board.reply(&thread, "Two", "second").await;
+    let third = board.reply(&thread, "Three", ">>1 yes").await;
+    assert_eq!(quoted_by(&board, &thread), HashMap::from([(1, vec![3])]));
+    let version = changes::current_version(&board.db, &thread.id);
+
+    edit(&board, &admin, &third, ">>2 rather").await;
+    assert_eq!(quoted_by(&board, &thread), HashMap::from([(2, vec![3])]));
+    assert_eq!(changes::current_version(&board.db, &thread.id), version + 1);
+
+    let html = board.get(&format!("/post/{}", thread.id)).await.html();
+    assert!(attrs(&html, "#r1 .backlinks a", "href").is_empty());
+    assert_eq!(attrs(&html, "#r2 .backlinks a", "href"), vec!["#r3"]);
+
+    // Deleting the quoting reply takes its backlinks along
+    crate::storage::delete_post(&board.db, &board.config, &third.id);
+    assert!(quoted_by(&board, &thread).is_empty());
+}
+
+#[actix_web::test]
+async fn unknown_quotes_come_back_with_the_message_as_typed() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    board.reply(&thread, "One", "first").await;
+    let message = "\n>>1 yes\n>>7 and >>9: <b>bold</b> & \"quoted\"\n\n  indented  ";
+    let form = Form::new()
+        .text("parent_id", &thread.id)
+        .text("return_to", &format!("/post/{}", thread.id))
+        .text("rendered_at", "1700000000")
+        .text("title", "Typo")
+        .text("options", "sage")
+        .text("remember_options", "1")
+        .text("message", message)
+        .file("file", "cat.png", "image/png", &png(16));
+    let page = board.submit(form).await;
+    let html = page.html();
+    assert_eq!(
+        texts(&html, "main .form-error"),
+        ["Your reply quotes >>7, >>9, which aren't replies in this thread. Fix the number, or tick \"Post anyway\"."]
+    );
+    assert_eq!(texts(&html, "main .muted"), ["Choose your file again, it wasn't kept."]);
+    assert_eq!(select(&html, "form textarea").remove(0).text().collect::<String>(), message);
+    assert_eq!(attrs(&html, "form input[name=title]", "value"), ["Typo"]);
+    assert_eq!(attrs(&html, "form input[name=options]", "value"), ["sage"]);
+    assert_eq!(attrs(&html, "form input[name=remember_options]", "checked").len(), 1);
+    assert_eq!(reply_count(&board, &thread), 1);
+    assert!(std::fs::read_dir(board.config.upload_dir.join(crate::upload::TEMP_DIR)).unwrap().next().is_none());
+
+    // Sent back without ticking the box it only asks again
+    let again = board.submit(resend(&page, false)).await;
+    assert_eq!(texts(&Html::parse_document(&again.body), "main .form-error").len(), 1);
+    assert_eq!(reply_count(&board, &thread), 1);
+
+    let res = board.submit(resend(&page, true)).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    assert_eq!(res.location(), format!("/post/{}?just_posted=2&since=1700000000#r2", thread.id));
+    let reply = board.find("Typo");
+    assert_eq!(reply.message, message);
+    assert_eq!(reply.reply_number, Some(2));
+    assert_eq!(reply.file, None);
+}
+
+#[actix_web::test]
+async fn only_replies_from_the_form_with_unknown_quotes_are_checked() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", ">>5 in a first post").await;
+    board.reply(&thread, "One", "first").await;
+    board.reply(&thread, "Two", ">>1 is there").await;
+    let script = Form::new().text("parent_id", &thread.id).text("title", "Script").text("message", ">>40").json();
+    assert_eq!(board.submit(script).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(reply_count(&board, &thread), 3);
+}
+
+#[actix_web::test]
+async fn reindex_rebuilds_what_the_updates_kept() {
+    let board = TestBoard::with(|config| config.admin_password = Some("secret".to_string()));
+    let admin = admin_login(&board).await;
+    let thread = board.thread("Thread", "Start").await;
+    board.reply(&thread, "One", "first").await;
+    let second = board.reply(&thread, "Two", ">>1").await;
+    board.reply(&thread, "Three", ">>1 >>2").await;
+    edit(&board, &admin, &second, "no quote now").await;
+    let kept = quoted_by(&board, &thread);
+
+    // Knocked out of shape, as a crash or a bug might leave them
+    let tree = board.db.open_tree("backlinks").unwrap();
+    tree.clear().unwrap();
+    tree.insert(format!("{}/{:020}/{:020}", thread.id, 7, 9), "gone").unwrap();
+    let counts = board.db.open_tree("reply_counts").unwrap();
+    counters::increment(&counts, &thread.id, 5);
+
+    assert_eq!(reindex::reindex(&board.db, 2), (2, 1));
+    assert_eq!(quoted_by(&board, &thread), kept);
+    assert_eq!(counters::get(&counts, &thread.id), 3);
+}
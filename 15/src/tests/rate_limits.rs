@@ -0,0 +1,138 @@
+// Token buckets per route class, see rate_limit.rs: each class has a
+// budget of its own, and running one out says when to come back. The post
+// forms say the same before anyone submits.
+
+use actix_web::http::header::{CACHE_CONTROL, RETRY_AFTER};
+use actix_web::http::StatusCode;
+
+use actix_web::test::TestRequest;
+
+use super::{texts, Form, Response, TestBoard};
+use crate::rate_limit::{BucketPolicy, RouteClass};
+
+// Three requests, then nothing for the length of the test
+const THREE: BucketPolicy = BucketPolicy {
+    burst: 3.0,
+    per_second: 0.001,
+};
+
+fn board() -> TestBoard {
+    let mut board = TestBoard::new();
+    board.limit(RouteClass::Write, THREE);
+    board.limit(RouteClass::Render, THREE);
+    board
+}
+
+async fn render(board: &TestBoard) -> StatusCode {
+    board.get("/").await.status
+}
+
+async fn write(board: &TestBoard, title: &str) -> StatusCode {
+    board.submit(Form::new().text("title", title).text("message", "x")).await.status
+}
+
+#[actix_web::test]
+async fn running_out_of_renders_leaves_the_writes() {
+    let board = board();
+    for _ in 0..3 {
+        assert_eq!(render(&board).await, StatusCode::OK);
+    }
+    let res = board.get("/").await;
+    assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = res.headers.get(RETRY_AFTER).unwrap().to_str().unwrap().parse().unwrap();
+    assert!(retry_after > 0);
+
+    for title in ["One", "Two", "Three"] {
+        assert_eq!(write(&board, title).await, StatusCode::SEE_OTHER, "{}", title);
+    }
+    assert_eq!(write(&board, "Four").await, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_web::test]
+async fn running_out_of_writes_leaves_the_renders() {
+    let board = board();
+    for title in ["One", "Two", "Three"] {
+        assert_eq!(write(&board, title).await, StatusCode::SEE_OTHER, "{}", title);
+    }
+    assert_eq!(write(&board, "Four").await, StatusCode::TOO_MANY_REQUESTS);
+
+    for _ in 0..3 {
+        assert_eq!(render(&board).await, StatusCode::OK);
+    }
+    assert_eq!(render(&board).await, StatusCode::TOO_MANY_REQUESTS);
+    // Cheap routes were never limited here
+    assert_eq!(board.get("/theme.css").await.status, StatusCode::OK);
+}
+
+const POSTER: &str = "10.0.0.7:4000";
+const SOMEONE_ELSE: &str = "10.0.0.8:4000";
+
+async fn from(board: &TestBoard, address: &str, req: TestRequest) -> Response {
+    board.send(req.peer_addr(address.parse().unwrap())).await
+}
+
+// The status line above the form on `path` as `address` sees it, checking
+// that a page with one isn't cached for anyone else
+async fn status_line(board: &TestBoard, address: &str, path: &str) -> Option<String> {
+    let res = from(board, address, TestRequest::get().uri(path)).await;
+    let lines = texts(&res.html(), ".form-container > p.muted");
+    assert!(lines.len() <= 1, "{:?}", lines);
+    let line = lines.into_iter().next();
+    if line.is_some() {
+        assert_eq!(res.headers.get(CACHE_CONTROL).unwrap(), "private, no-store", "{}", path);
+    }
+    line
+}
+
+// The seconds in "You can post again in 12 s."
+async fn wait_shown(board: &TestBoard) -> u64 {
+    let line = status_line(board, POSTER, "/").await.unwrap();
+    line.strip_prefix("You can post again in ").and_then(|rest| rest.strip_suffix(" s.")).unwrap().parse().unwrap()
+}
+
+async fn post(board: &TestBoard, address: &str, title: &str) -> Response {
+    from(board, address, Form::new().text("title", title).text("message", "x").request("/submit")).await
+}
+
+#[actix_web::test]
+async fn the_forms_count_the_hourly_posts_used() {
+    let mut board = TestBoard::new();
+    board.runtime(|settings| settings.posts_per_hour = 3);
+    assert_eq!(status_line(&board, POSTER, "/").await, None);
+
+    assert_eq!(post(&board, POSTER, "One").await.status, StatusCode::SEE_OTHER);
+    let thread = board.find("One");
+    let thread_page = format!("/post/{}", thread.id);
+    for path in ["/", thread_page.as_str()] {
+        assert_eq!(status_line(&board, POSTER, path).await.as_deref(), Some("1 of 3 hourly posts used."), "{}", path);
+        assert_eq!(status_line(&board, SOMEONE_ELSE, path).await, None, "{}", path);
+    }
+    assert_eq!(post(&board, POSTER, "Two").await.status, StatusCode::SEE_OTHER);
+    assert_eq!(status_line(&board, POSTER, &thread_page).await.as_deref(), Some("2 of 3 hourly posts used."));
+    assert_eq!(post(&board, POSTER, "Three").await.status, StatusCode::SEE_OTHER);
+    let line = status_line(&board, POSTER, "/").await.unwrap();
+    assert!(line.starts_with("All 3 hourly posts used. You can post again in "), "{}", line);
+
+    // Which is what submitting finds
+    assert_eq!(post(&board, POSTER, "Four").await.status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(post(&board, SOMEONE_ELSE, "Elsewhere").await.status, StatusCode::SEE_OTHER);
+    assert_eq!(status_line(&board, SOMEONE_ELSE, "/").await.as_deref(), Some("1 of 3 hourly posts used."));
+}
+
+#[actix_web::test]
+async fn the_forms_give_the_wait_the_limiter_would() {
+    let mut board = TestBoard::new();
+    board.limit(RouteClass::Write, BucketPolicy { burst: 1.0, per_second: 0.05 });
+    assert_eq!(post(&board, POSTER, "One").await.status, StatusCode::SEE_OTHER);
+
+    let shown = wait_shown(&board).await;
+    assert!((1..=20).contains(&shown), "{}", shown);
+    // Looking doesn't use anything up
+    assert!(wait_shown(&board).await.abs_diff(shown) <= 1);
+
+    let res = post(&board, POSTER, "Two").await;
+    assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = res.headers.get(RETRY_AFTER).unwrap().to_str().unwrap().parse().unwrap();
+    assert!(retry_after.abs_diff(shown) <= 1, "{} against {}", retry_after, shown);
+    assert_eq!(status_line(&board, SOMEONE_ELSE, "/").await, None);
+}
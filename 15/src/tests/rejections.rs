@@ -0,0 +1,146 @@
+// Refused posts, see rejection.rs: each kind of failure as the JSON scripts
+// get and as the page the form gets, which say the same thing.
+
+use actix_web::http::header::{CONTENT_TYPE, RETRY_AFTER};
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use scraper::Html;
+use serde_json::{json, Value};
+
+use super::{png, texts, Form, Response, TestBoard};
+use crate::rate_limit::{BucketPolicy, RouteClass};
+
+// The same post sent as a script and as a browser, with the JSON's fields
+async fn refused(board: &TestBoard, status: StatusCode, form: impl Fn() -> Form) -> (Vec<Value>, Response) {
+    let res = board.send(from_poster(form().json().request("/submit"))).await;
+    assert_eq!(res.status, status, "{}", res.body);
+    assert_eq!(res.headers.get(CONTENT_TYPE).unwrap(), "application/json");
+    let body: Value = serde_json::from_str(&res.body).unwrap();
+    assert_eq!(body["error"], "validation_failed");
+    let fields = body["fields"].as_array().unwrap().clone();
+
+    let page = board.send(from_poster(form().request("/submit"))).await;
+    assert_eq!(page.status, status, "{}", page.body);
+    (fields, page)
+}
+
+// Posts need an address for the hourly quota to count them
+fn from_poster(req: TestRequest) -> TestRequest {
+    req.peer_addr("10.0.0.7:4000".parse().unwrap())
+}
+
+// The rejection page, showing every message the JSON had
+fn assert_page_says(page: &Response, fields: &[Value]) {
+    assert!(page.headers.get(CONTENT_TYPE).unwrap().to_str().unwrap().starts_with("text/html"));
+    let html = Html::parse_document(&page.body);
+    assert_eq!(texts(&html, "main h3"), vec!["Your post was rejected"]);
+    let messages: Vec<&str> = fields.iter().map(|field| field["message"].as_str().unwrap()).collect();
+    assert_eq!(texts(&html, "main p"), vec![messages.join(" ")]);
+}
+
+fn thread(title: &str, message: &str) -> Form {
+    Form::new().text("title", title).text("message", message)
+}
+
+#[actix_web::test]
+async fn missing_and_too_long_fields_are_each_listed() {
+    let board = TestBoard::new();
+    let (fields, page) = refused(&board, StatusCode::BAD_REQUEST, || thread("", " ")).await;
+    assert_eq!(
+        fields,
+        [
+            json!({"field": "title", "code": "missing", "message": "A title is required."}),
+            json!({"field": "message", "code": "missing", "message": "A message is required."}),
+        ]
+    );
+    assert_page_says(&page, &fields);
+
+    let (fields, page) = refused(&board, StatusCode::BAD_REQUEST, || thread("Sixteen letters!", "Hi")).await;
+    assert_eq!(
+        fields,
+        [json!({"field": "title", "code": "too_long", "max": 15, "message": "A title can be at most 15 characters."})]
+    );
+    assert_page_says(&page, &fields);
+}
+
+#[actix_web::test]
+async fn spam_is_refused_by_code() {
+    let board = TestBoard::new();
+    let (fields, page) = refused(&board, StatusCode::BAD_REQUEST, || thread("Spam", &"a".repeat(2001))).await;
+    assert_eq!(
+        fields,
+        [json!({"field": "message", "code": "spam", "message": "Your message contains too many repeated characters."})]
+    );
+    assert_page_says(&page, &fields);
+}
+
+#[actix_web::test]
+async fn the_hourly_quota_says_when_to_come_back() {
+    let mut board = TestBoard::new();
+    board.runtime(|settings| settings.posts_per_hour = 1);
+    let res = board.send(from_poster(thread("First", "Hi").request("/submit"))).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER);
+
+    let (fields, page) = refused(&board, StatusCode::TOO_MANY_REQUESTS, || thread("Second", "Hi")).await;
+    assert_eq!(fields.len(), 1);
+    let retry_after = fields[0]["retry_after"].as_u64().unwrap();
+    assert!((1..=3600).contains(&retry_after), "{}", retry_after);
+    assert_eq!(
+        fields[0],
+        json!({
+            "field": "post",
+            "code": "rate_limited",
+            "max": 1,
+            "retry_after": retry_after,
+            "message": "You can make at most 1 posts an hour. Try again later.",
+        })
+    );
+    assert_page_says(&page, &fields);
+    let header: u64 = page.headers.get(RETRY_AFTER).unwrap().to_str().unwrap().parse().unwrap();
+    assert!(header.abs_diff(retry_after) <= 1);
+}
+
+#[actix_web::test]
+async fn the_rate_limiter_answers_scripts_in_json() {
+    let mut board = TestBoard::new();
+    board.limit(RouteClass::Write, BucketPolicy { burst: 1.0, per_second: 0.001 });
+    let res = board.submit(thread("First", "Hi")).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER);
+
+    let res = board.submit(thread("Second", "Hi").json()).await;
+    assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = res.headers.get(RETRY_AFTER).unwrap().to_str().unwrap().parse().unwrap();
+    let body: Value = serde_json::from_str(&res.body).unwrap();
+    let message = format!("Too many requests, try again in {} seconds.", retry_after);
+    let expected = json!({"field": "post", "code": "rate_limited", "retry_after": retry_after, "message": message});
+    assert_eq!(body, json!({"error": "validation_failed", "fields": [expected]}));
+
+    // Browsers get the same sentence, before the form is even read
+    let res = board.submit(thread("Third", "Hi")).await;
+    assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(res.body.starts_with("Too many requests, try again in "), "{}", res.body);
+}
+
+#[actix_web::test]
+async fn files_are_refused_by_size_and_type() {
+    let board = TestBoard::with(|config| config.max_upload_bytes = 1024);
+    let big = png(200);
+    assert!(big.len() > 1024, "{} bytes", big.len());
+    let (fields, page) = refused(&board, StatusCode::BAD_REQUEST, || {
+        thread("Big", "Hi").file("file", "big.png", "image/png", &big)
+    })
+    .await;
+    assert_eq!(fields.len(), 1);
+    assert_eq!((&fields[0]["field"], &fields[0]["code"], &fields[0]["max"]), (&json!("file"), &json!("file_too_large"), &json!(1024)));
+    assert_page_says(&page, &fields);
+
+    let (fields, page) = refused(&board, StatusCode::BAD_REQUEST, || {
+        thread("Program", "Hi").file("file", "setup.exe", "application/octet-stream", b"MZ")
+    })
+    .await;
+    assert_eq!(
+        fields,
+        [json!({"field": "file", "code": "unsupported_media", "message": "Files of type .exe aren't allowed."})]
+    );
+    assert_page_says(&page, &fields);
+}
@@ -0,0 +1,84 @@
+// Reloading the settings in CONFIG_FILE while the board runs, see
+// runtime.rs.
+
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+use super::{admin_login, AdminLogin, Form, TestBoard};
+
+// A board reading its live settings from a file in `dir`, which starts
+// out empty, with an admin logged in. Returns the board, the file and the
+// admin's session.
+async fn board_with_file(dir: &TempDir) -> (TestBoard, PathBuf, AdminLogin) {
+    let file = dir.path().join("board.conf");
+    std::fs::write(&file, "# nothing yet\n").unwrap();
+    let board = TestBoard::with(|config| {
+        config.config_file = Some(file.clone());
+        config.admin_password = Some("secret".to_string());
+    });
+    let admin = admin_login(&board).await;
+    (board, file, admin)
+}
+
+async fn reload(board: &TestBoard, admin: &AdminLogin) -> super::Response {
+    board.send(admin.post("/admin/reload-config")).await
+}
+
+async fn post(board: &TestBoard, title: &str) -> StatusCode {
+    board.submit(Form::new().text("title", title).text("message", "x")).await.status
+}
+
+#[actix_web::test]
+async fn new_limits_apply_to_the_next_request() {
+    let dir = tempfile::tempdir().unwrap();
+    let (board, file, admin) = board_with_file(&dir).await;
+    assert_eq!(post(&board, "One").await, StatusCode::SEE_OTHER);
+    assert_eq!(post(&board, "Two").await, StatusCode::SEE_OTHER);
+
+    std::fs::write(&file, "RATE_LIMIT_WRITE = 1:0.001\n").unwrap();
+    let res = reload(&board, &admin).await;
+    assert_eq!(res.status, StatusCode::OK, "{}", res.body);
+    assert!(res.body.contains("RATE_LIMIT_WRITE changed"), "{}", res.body);
+
+    assert_eq!(post(&board, "Three").await, StatusCode::SEE_OTHER);
+    assert_eq!(post(&board, "Four").await, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_web::test]
+async fn requests_under_way_keep_the_settings_they_started_with() {
+    let dir = tempfile::tempdir().unwrap();
+    let (board, file, _) = board_with_file(&dir).await;
+    let started = board.state.runtime.get();
+    assert_eq!(started.posts_per_hour, 0);
+
+    std::fs::write(&file, "POSTS_PER_HOUR=3\n").unwrap();
+    assert_eq!(board.state.runtime.reload().unwrap(), vec!["POSTS_PER_HOUR"]);
+    assert_eq!(started.posts_per_hour, 0);
+    assert_eq!(board.state.runtime.get().posts_per_hour, 3);
+}
+
+#[actix_web::test]
+async fn a_bad_line_rejects_the_whole_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let (board, file, admin) = board_with_file(&dir).await;
+
+    std::fs::write(&file, "RATE_LIMIT_WRITE=1:0.001\nPOSTS_PER_HOUR=lots\nBASE_PATH=/other\n").unwrap();
+    let res = reload(&board, &admin).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    assert!(res.body.contains("line 2: POSTS_PER_HOUR"), "{}", res.body);
+    assert!(res.body.contains("line 3: BASE_PATH"), "{}", res.body);
+
+    // The good line wasn't applied either
+    assert_eq!(post(&board, "One").await, StatusCode::SEE_OTHER);
+    assert_eq!(post(&board, "Two").await, StatusCode::SEE_OTHER);
+}
+
+#[actix_web::test]
+async fn reloading_needs_an_admin() {
+    let dir = tempfile::tempdir().unwrap();
+    let (board, _, _) = board_with_file(&dir).await;
+    let res = board.send(TestRequest::post().uri("/admin/reload-config")).await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,132 @@
+// "Remember my options", see remember.rs: a reply sent with the box ticked
+// fills the next reply form in, escaped, on a page that isn't cached for
+// anyone else; one sent without it forgets them. New threads and cookies
+// that don't check out are left alone.
+
+use actix_web::http::header::{CACHE_CONTROL, COOKIE, SET_COOKIE};
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use scraper::Html;
+
+use super::{attrs, select, Form, Response, TestBoard};
+use crate::Post;
+
+fn reply(thread: &Post, options: &str, remember: bool) -> Form {
+    let form = Form::new().text("parent_id", &thread.id).text("title", "Reply").text("message", "Hi").text("options", options);
+    if remember {
+        form.text("remember_options", "1")
+    } else {
+        form
+    }
+}
+
+// The post_options cookie the response sets, attributes and all
+fn set_cookie(res: &Response) -> Option<String> {
+    res.headers.get_all(SET_COOKIE).map(|value| value.to_str().unwrap().to_string()).find(|value| value.starts_with("post_options="))
+}
+
+// What a browser sends back of it
+fn sent_back(set: &str) -> String {
+    set.split(';').next().unwrap().to_string()
+}
+
+async fn thread_page(board: &TestBoard, thread: &Post, cookie: Option<&str>) -> (Response, Html) {
+    let mut req = TestRequest::get().uri(&format!("/post/{}", thread.id));
+    if let Some(cookie) = cookie {
+        req = req.insert_header((COOKIE, cookie));
+    }
+    let res = board.send(req).await;
+    let html = res.html();
+    (res, html)
+}
+
+fn form_options(html: &Html) -> (String, bool) {
+    let options = attrs(html, ".reply-form input[name=options]", "value").remove(0);
+    let ticked = select(html, ".reply-form input[name=remember_options]").remove(0).value().attr("checked").is_some();
+    (options, ticked)
+}
+
+fn cache_control(res: &Response) -> String {
+    res.headers.get(CACHE_CONTROL).map_or(String::new(), |value| value.to_str().unwrap().to_string())
+}
+
+#[actix_web::test]
+async fn ticked_options_fill_in_the_next_reply_form() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let (res, html) = thread_page(&board, &thread, None).await;
+    assert_eq!(form_options(&html), (String::new(), false));
+    assert!(!cache_control(&res).contains("private"), "{}", cache_control(&res));
+
+    let res = board.submit(reply(&thread, "sage", true)).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    let set = set_cookie(&res).unwrap();
+    for attribute in ["HttpOnly", "SameSite=Lax", "Path=/", "Max-Age=31536000"].iter() {
+        assert!(set.contains(attribute), "{}", set);
+    }
+    let cookie = sent_back(&set);
+    let (res, html) = thread_page(&board, &thread, Some(&cookie)).await;
+    assert_eq!(form_options(&html), ("sage".to_string(), true));
+    // Only for whoever has the cookie
+    assert_eq!(cache_control(&res), "private, no-store");
+    // and in every thread
+    let other = board.thread("Other", "Start").await;
+    assert_eq!(form_options(&thread_page(&board, &other, Some(&cookie)).await.1), ("sage".to_string(), true));
+}
+
+#[actix_web::test]
+async fn remembered_options_are_escaped_into_the_field() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let options = "\" autofocus onfocus=alert(1) x=\"&amp;";
+    let cookie = sent_back(&set_cookie(&board.submit(reply(&thread, options, true)).await).unwrap());
+    let (res, html) = thread_page(&board, &thread, Some(&cookie)).await;
+    assert_eq!(form_options(&html), (options.to_string(), true));
+    assert!(select(&html, "input[onfocus]").is_empty());
+    assert!(res.body.contains("value=\"&quot; autofocus onfocus=alert(1) x=&quot;&amp;amp;\""), "{}", res.body);
+}
+
+#[actix_web::test]
+async fn unticking_the_box_forgets_them() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let cookie = sent_back(&set_cookie(&board.submit(reply(&thread, "sage", true)).await).unwrap());
+
+    let req = reply(&thread, "sage", false).request("/submit").insert_header((COOKIE, cookie.as_str()));
+    let res = board.send(req).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    let removal = set_cookie(&res).unwrap();
+    assert!(removal.contains("Max-Age=0"), "{}", removal);
+    let (res, html) = thread_page(&board, &thread, Some(&sent_back(&removal))).await;
+    assert_eq!(form_options(&html), (String::new(), false));
+    assert!(!cache_control(&res).contains("private"), "{}", cache_control(&res));
+
+    // Ticked with nothing to keep forgets them too
+    let req = reply(&thread, "", true).request("/submit").insert_header((COOKIE, cookie.as_str()));
+    assert!(set_cookie(&board.send(req).await).unwrap().contains("Max-Age=0"));
+    // and without a cookie there's nothing to forget
+    assert_eq!(set_cookie(&board.submit(reply(&thread, "sage", false)).await), None);
+}
+
+#[actix_web::test]
+async fn new_threads_and_tampered_cookies_are_left_alone() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let cookie = sent_back(&set_cookie(&board.submit(reply(&thread, "sage", true)).await).unwrap());
+
+    let form = Form::new().text("title", "New").text("message", "Start").text("options", "nonoko").text("remember_options", "1");
+    let res = board.send(form.request("/submit").insert_header((COOKIE, cookie.as_str()))).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    assert_eq!(set_cookie(&res), None);
+    assert_eq!(form_options(&thread_page(&board, &thread, Some(&cookie)).await.1).0, "sage");
+
+    // The same signature on other options doesn't check out
+    let (_, signature) = cookie.split_once('.').unwrap();
+    let forged = format!("post_options={}.{}", crate::upload::hex(b"#admin"), signature);
+    let (res, html) = thread_page(&board, &thread, Some(&forged)).await;
+    assert_eq!(form_options(&html), (String::new(), false));
+    assert!(!cache_control(&res).contains("private"), "{}", cache_control(&res));
+    for junk in ["post_options=zz.zz", "post_options=nodot", "post_options="].iter() {
+        assert_eq!(form_options(&thread_page(&board, &thread, Some(junk)).await.1), (String::new(), false), "{}", junk);
+    }
+}
@@ -0,0 +1,102 @@
+// Posts sent more than once, see replay.rs: a copy arriving while the first
+// is still uploading is turned away, one arriving after it is answered
+// without a second post, and a failed first copy doesn't block the retry.
+
+use actix_web::error::PayloadError;
+use actix_web::http::StatusCode;
+use actix_web::rt::time;
+use actix_web::web::Bytes;
+use futures_util::{future, stream, StreamExt};
+use serde_json::Value;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use super::{png, Form, TestBoard};
+use crate::upload;
+
+const TOKEN: &str = "4b2f3c1e-5d6a-4e7b-8c9d-0a1b2c3d4e5f";
+
+fn upload_form(token: &str, message: &str) -> Form {
+    Form::with_token(token).text("title", "Slow").text("message", message).file("file", "pic.png", "image/png", &png(64))
+}
+
+fn posts_titled(board: &TestBoard, title: &str) -> usize {
+    board.db.iter().values().filter_map(|bytes| crate::Post::upgrade(&bytes.unwrap()).ok()).filter(|post| post.title == title).count()
+}
+
+#[actix_web::test]
+async fn a_copy_sent_during_the_upload_is_turned_away() {
+    let board = TestBoard::new();
+    let (req, body) = upload_form(TOKEN, "Hello").split("/submit");
+    let half = body.len() - 100;
+    let (first_half, rest) = (Bytes::copy_from_slice(&body[..half]), Bytes::copy_from_slice(&body[half..]));
+
+    // The first copy stops partway through its file until the second is
+    // answered
+    let waiting = Rc::new(Cell::new(false));
+    let released = Rc::new(Cell::new(false));
+    let first_body = {
+        let (waiting, released) = (waiting.clone(), released.clone());
+        stream::iter(vec![Ok::<_, PayloadError>(first_half)]).chain(stream::once(async move {
+            waiting.set(true);
+            while !released.get() {
+                time::sleep(Duration::from_millis(1)).await;
+            }
+            Ok(rest)
+        }))
+    };
+    let second = async {
+        while !waiting.get() {
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        let res = board.submit(upload_form(TOKEN, "Hello").json()).await;
+        released.set(true);
+        res
+    };
+    let (first, second) = future::join(board.send_stream(req, first_body), second).await;
+
+    assert_eq!(second.status, StatusCode::CONFLICT, "{}", second.body);
+    let body: Value = serde_json::from_str(&second.body).unwrap();
+    assert_eq!(body["fields"][0]["code"], "in_progress");
+    assert_eq!(body["fields"][0]["message"], "This upload is already in progress.");
+    assert_eq!(first.status, StatusCode::SEE_OTHER, "{}", first.body);
+    assert_eq!(posts_titled(&board, "Slow"), 1);
+    assert_eq!(upload::stored_files(&board.config.upload_dir).len(), 1);
+
+    // Once it's in, the same post again is answered without a copy
+    let res = board.submit(upload_form(TOKEN, "Hello")).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    assert_eq!(posts_titled(&board, "Slow"), 1);
+    assert_eq!(upload::stored_files(&board.config.upload_dir).len(), 1);
+
+    // A different message under the token is a new post
+    let res = board.submit(upload_form(TOKEN, "Hello again")).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    assert_eq!(posts_titled(&board, "Slow"), 2);
+}
+
+#[actix_web::test]
+async fn a_failed_first_copy_lets_the_retry_through() {
+    let board = TestBoard::new();
+    let (req, body) = upload_form(TOKEN, "Hello").split("/submit");
+    let broken = stream::iter(vec![Ok(Bytes::copy_from_slice(&body[..body.len() - 100])), Err(PayloadError::Incomplete(None))]);
+    let res = board.send_stream(req, broken).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", res.body);
+    assert_eq!(posts_titled(&board, "Slow"), 0);
+
+    let res = board.submit(upload_form(TOKEN, "Hello")).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    assert_eq!(posts_titled(&board, "Slow"), 1);
+}
+
+#[actix_web::test]
+async fn the_token_only_counts_as_the_first_field() {
+    let board = TestBoard::new();
+    let form = Form::new().text("submit_token", TOKEN).text("title", "Late").text("message", "Hi").json();
+    let res = board.submit(form).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", res.body);
+    let body: Value = serde_json::from_str(&res.body).unwrap();
+    assert_eq!((&body["fields"][0]["field"], &body["fields"][0]["code"]), (&Value::from("submit_token"), &Value::from("invalid")));
+    assert_eq!(posts_titled(&board, "Late"), 0);
+}
@@ -0,0 +1,286 @@
+// Replying on a thread page: where a reply lands, the note on it about
+// replies that went in while it was being written, and reply numbers that
+// stay put when earlier replies are deleted. A refused reply comes back
+// with the form as it was sent, quote line and all. Links to a reply lead
+// to it in its thread, or show it alone once the thread is gone.
+
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::http::StatusCode;
+use scraper::Html;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{attrs, select, texts, Form, TestBoard};
+use crate::{storage, Post};
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+// Moves a post back in time, for replies that were on the page already
+fn backdate(board: &TestBoard, post: &Post, seconds: u64) {
+    let mut post = post.clone();
+    post.timestamp -= seconds;
+    board.db.insert(&post.id, serde_json::to_vec(&post).unwrap()).unwrap();
+}
+
+// Sends a reply the way the thread page's form does
+async fn reply_from_page(board: &TestBoard, thread: &Post, title: &str, rendered_at: u64) -> String {
+    let form = Form::new()
+        .text("parent_id", &thread.id)
+        .text("return_to", &format!("/post/{}", thread.id))
+        .text("rendered_at", &rendered_at.to_string())
+        .text("title", title)
+        .text("message", "Mine");
+    let res = board.submit(form).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    res.location().to_string()
+}
+
+// The page a redirect goes to; the anchor stays with the browser
+fn without_anchor(location: &str) -> &str {
+    location.split('#').next().unwrap()
+}
+
+// The reply form's message, untrimmed
+fn form_message(html: &Html) -> String {
+    select(html, "#reply-form textarea[name=message]").remove(0).text().collect()
+}
+
+// The reply form's fields as a browser would send them: hidden and text
+// inputs, ticked boxes and the message
+fn reply_form_fields(html: &Html) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    for input in select(html, "#reply-form input") {
+        let input = input.value();
+        let (name, value) = (input.attr("name").unwrap(), input.attr("value").unwrap_or_default());
+        match input.attr("type") {
+            Some("file") => {}
+            Some("checkbox") if input.attr("checked").is_none() => {}
+            _ => fields.push((name.to_string(), value.to_string())),
+        }
+    }
+    fields.push(("message".to_string(), form_message(html)));
+    fields
+}
+
+// Sends them as a reply, token and all
+async fn send_fields(board: &TestBoard, fields: &[(String, String)]) -> super::Response {
+    let token = fields.iter().find(|(name, _)| name == "submit_token").unwrap().1.clone();
+    let form = fields.iter().filter(|(name, _)| name != "submit_token").fold(Form::with_token(&token), |form, (name, value)| form.text(name, value));
+    board.submit(form).await
+}
+
+// Sends them with `changes` made
+async fn send_form(board: &TestBoard, html: &Html, changes: &[(&str, &str)]) -> super::Response {
+    let mut fields = reply_form_fields(html);
+    for (field, value) in changes {
+        fields.iter_mut().filter(|(name, _)| name == field).for_each(|(_, old)| *old = value.to_string());
+    }
+    send_fields(board, &fields).await
+}
+
+#[actix_web::test]
+async fn reply_form_carries_when_it_was_shown() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let before = now();
+    let html = board.get(&format!("/post/{}", thread.id)).await.html();
+    let shown: Vec<u64> = attrs(&html, "#reply-form input[name=rendered_at]", "value")
+        .iter()
+        .map(|value| value.parse().unwrap())
+        .collect();
+    assert_eq!(shown.len(), 1);
+    assert!(shown[0] >= before && shown[0] <= now());
+}
+
+#[actix_web::test]
+async fn replies_posted_while_writing_are_counted() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let early = board.reply(&thread, "Early", "Already there").await;
+    backdate(&board, &early, 120);
+
+    // The page is shown a minute ago, and two replies go in while it's
+    // being answered
+    let rendered_at = now() - 60;
+    board.reply(&thread, "Other 1", "Meanwhile").await;
+    board.reply(&thread, "Other 2", "Meanwhile").await;
+    let location = reply_from_page(&board, &thread, "Mine", rendered_at).await;
+    assert_eq!(location, format!("/post/{}?just_posted=4&since={}#r4", thread.id, rendered_at));
+
+    // Replies after it don't count
+    board.reply(&thread, "Later", "After").await;
+    let res = board.get(without_anchor(&location)).await;
+    assert!(res.headers.get(CACHE_CONTROL).unwrap().to_str().unwrap().contains("no-store"));
+    let html = res.html();
+    assert_eq!(texts(&html, ".meanwhile"), texts(&html, "#r4 .meanwhile"));
+    assert_eq!(texts(&html, "#r4 .meanwhile"), vec!["2 replies were posted while you were writing, above yours."]);
+
+    let html = board.get(&format!("{}&order=desc", without_anchor(&location))).await.html();
+    assert_eq!(texts(&html, "#r4 .meanwhile"), vec!["2 replies were posted while you were writing, below yours."]);
+
+    // Only on the way back from posting
+    let html = board.get(&format!("/post/{}", thread.id)).await.html();
+    assert!(texts(&html, ".meanwhile").is_empty());
+}
+
+#[actix_web::test]
+async fn no_note_when_nobody_else_replied() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let early = board.reply(&thread, "Early", "Already there").await;
+    backdate(&board, &early, 120);
+
+    let location = reply_from_page(&board, &thread, "Mine", now() - 60).await;
+    assert!(location.ends_with("#r2"), "{}", location);
+    let html = board.get(without_anchor(&location)).await.html();
+    assert!(texts(&html, ".meanwhile").is_empty());
+    assert_eq!(attrs(&html, ".reply", "id"), vec!["r1", "r2"]);
+}
+
+#[actix_web::test]
+async fn form_sent_back_keeps_when_it_was_shown() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let form = Form::new()
+        .text("parent_id", &thread.id)
+        .text("rendered_at", "1234")
+        .text("title", "Far too long a title")
+        .text("message", "Mine");
+    let res = board.submit(form).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    let html = scraper::Html::parse_document(&res.body);
+    assert_eq!(attrs(&html, "#reply-form input[name=rendered_at]", "value"), vec!["1234"]);
+}
+
+#[actix_web::test]
+async fn a_refused_quote_reply_is_sent_again_with_one_quote_line() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    board.reply(&thread, "One", "first").await;
+    let html = board.get(&format!("/post/{}?quote=1", thread.id)).await.html();
+    assert_eq!(form_message(&html), ">>1\n");
+    assert_eq!(attrs(&html, "#reply-form", "action"), ["/submit#reply-form"]);
+
+    // Written under the quote, with options to keep, and a title too long
+    let mut fields = reply_form_fields(&html);
+    for (name, value) in fields.iter_mut() {
+        match name.as_str() {
+            "title" => *value = "Far too long a title".to_string(),
+            "message" => value.push_str("agreed"),
+            "options" => *value = "sage".to_string(),
+            _ => {}
+        }
+    }
+    fields.push(("remember_options".to_string(), "1".to_string()));
+    let res = send_fields(&board, &fields).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    let bounced = Html::parse_document(&res.body);
+    let banner = texts(&bounced, ".form-error");
+    assert_eq!(banner.len(), 1);
+    assert!(banner[0].starts_with("Title: "), "{:?}", banner);
+    assert_eq!(form_message(&bounced), ">>1\nagreed");
+    assert_eq!(attrs(&bounced, "#reply-form input[name=title]", "value"), ["Far too long a title"]);
+    assert_eq!(attrs(&bounced, "#reply-form input[name=options]", "value"), ["sage"]);
+    assert_eq!(attrs(&bounced, "#reply-form input[name=remember_options]", "checked").len(), 1);
+    // and it still goes back to the form when sent
+    assert_eq!(attrs(&bounced, "#reply-form", "action"), ["/submit#reply-form"]);
+
+    // Refused again, still the one line
+    let res = send_form(&board, &bounced, &[("options", "<b>")]).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    let bounced = Html::parse_document(&res.body);
+    assert_eq!(form_message(&bounced), ">>1\nagreed");
+
+    let res = send_form(&board, &bounced, &[("title", "Fixed"), ("options", "sage")]).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    let reply = board.find("Fixed");
+    assert_eq!(reply.message, ">>1\nagreed");
+    assert_eq!(reply.message.matches(">>1").count(), 1);
+    assert!(res.location().ends_with("#r2"), "{}", res.location());
+}
+
+#[actix_web::test]
+async fn a_sent_form_wins_over_a_quote_in_the_page_address() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    board.reply(&thread, "One", "first").await;
+    let html = board.get(&format!("/post/{}?quote=1", thread.id)).await.html();
+    // The quote line taken out before sending, and the form refused
+    let res = send_form(&board, &html, &[("message", "")]).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    let bounced = Html::parse_document(&res.body);
+    assert_eq!(texts(&bounced, ".form-error"), ["Title: A title is required.", "Message: A message is required."]);
+    assert_eq!(form_message(&bounced), "");
+}
+
+#[actix_web::test]
+async fn deleting_a_reply_leaves_the_other_numbers_alone() {
+    for show_deleted in [true, false] {
+        let board = TestBoard::with(|config| config.show_deleted_replies = show_deleted);
+        let thread = board.thread("Thread", "Start").await;
+        let mut replies = Vec::new();
+        for n in 1..=5 {
+            let message = if n == 1 { "First".to_string() } else { format!("Number {} quoting >>1", n) };
+            replies.push(board.reply(&thread, &format!("Reply {}", n), &message).await);
+        }
+        storage::delete_post(&board.db, &board.config, &replies[1].id);
+
+        let html = board.get(&format!("/post/{}", thread.id)).await.html();
+        assert_eq!(attrs(&html, "article.reply", "id"), ["r1", "r3", "r4", "r5"], "show_deleted={}", show_deleted);
+        assert_eq!(attrs(&html, "article.reply h4 a[href^='#r']", "href"), ["#r1", "#r3", "#r4", "#r5"]);
+        assert_eq!(texts(&html, "article.reply h4 a[href^='#r']"), ["Reply 1", "Reply 3", "Reply 4", "Reply 5"]);
+        assert_eq!(texts(&html, "article#r1 .backlinks a"), [">>3", ">>4", ">>5"]);
+        if show_deleted {
+            assert_eq!(attrs(&html, ".reply-deleted", "id"), ["r2"]);
+            assert_eq!(texts(&html, ".reply-deleted p"), ["Reply 2 was deleted."]);
+        } else {
+            assert!(attrs(&html, ".reply-deleted", "id").is_empty());
+        }
+
+        // The next reply carries on from the highest number, not the count
+        let sixth = board.reply(&thread, "Reply 6", "After").await;
+        assert_eq!(sixth.reply_number, Some(6));
+        let html = board.get(&format!("/post/{}", thread.id)).await.html();
+        assert_eq!(attrs(&html, "article.reply", "id"), ["r1", "r3", "r4", "r5", "r6"]);
+    }
+}
+
+#[actix_web::test]
+async fn links_to_replies_go_to_their_place_in_the_thread() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    board.reply(&thread, "First", "One").await;
+    let second = board.reply(&thread, "Second", "Two").await;
+    let in_place = format!("/post/{}#r2", thread.id);
+
+    // By the reply's id and by its number in the thread
+    let res = board.get(&format!("/post/{}", second.id)).await;
+    assert_eq!((res.status, res.location()), (StatusCode::FOUND, in_place.as_str()));
+    let res = board.get(&format!("/post/{}/2", thread.id)).await;
+    assert_eq!((res.status, res.location()), (StatusCode::FOUND, in_place.as_str()));
+    for path in [format!("/post/{}/3", thread.id), format!("/post/{}/0", thread.id), format!("/post/{}/two", thread.id), format!("/post/{}/1", second.id)] {
+        assert_eq!(board.get(&path).await.status, StatusCode::NOT_FOUND, "{}", path);
+    }
+
+    // The thread itself renders as always
+    let html = board.get(&format!("/post/{}", thread.id)).await.html();
+    assert_eq!(attrs(&html, "article.reply", "id"), ["r1", "r2"]);
+    assert_eq!(select(&html, "#reply-form").len(), 1);
+    assert!(!html.html().contains("no longer exists"));
+}
+
+#[actix_web::test]
+async fn a_reply_whose_thread_is_gone_is_shown_on_its_own() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let reply = board.reply(&thread, "Orphan", "Still here").await;
+    board.db.remove(&thread.id).unwrap();
+
+    let html = board.get(&format!("/post/{}", reply.id)).await.html();
+    assert_eq!(texts(&html, ".board-locked"), ["This is a reply to a thread that no longer exists."]);
+    assert!(html.html().contains("Still here"));
+    assert!(select(&html, "#reply-form").is_empty());
+    assert!(select(&html, ".reply-order").is_empty());
+    assert_eq!(board.get(&format!("/post/{}/1", thread.id)).await.status, StatusCode::NOT_FOUND);
+}
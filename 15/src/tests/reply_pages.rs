@@ -0,0 +1,111 @@
+// Long threads in pages of replies, see Config::replies_per_page. Pages go
+// by reply number, so everything that links to a reply (the admin lists, a
+// reply's own URL, backlinks and the way back from the reply form) lands
+// on the page it's on.
+
+use actix_web::http::StatusCode;
+
+use super::{admin_login, attrs, texts, Form, TestBoard};
+use crate::Post;
+
+// `count` replies titled "Reply N", sent without looking each one up
+async fn long_thread(board: &TestBoard, count: u64) -> Post {
+    let thread = board.thread("Long", "Start").await;
+    for number in 1..=count {
+        let form = Form::new().text("parent_id", &thread.id).text("title", &format!("Reply {}", number)).text("message", "More");
+        assert_eq!(board.submit(form).await.status, StatusCode::SEE_OTHER);
+    }
+    thread
+}
+
+#[actix_web::test]
+async fn a_hit_on_a_reply_links_to_its_page() {
+    let board = TestBoard::with(|config| {
+        config.admin_password = Some("secret".to_string());
+        config.replies_per_page = 75;
+    });
+    let thread = long_thread(&board, 250).await;
+    let hit = board.find("Reply 180");
+    assert_eq!(hit.reply_number, Some(180));
+    let expected = format!("/post/{}?page=2#r180", thread.id);
+    assert_eq!(board.config.url_of(&hit), expected);
+    assert_eq!(board.config.url_of(&thread), format!("/post/{}", thread.id));
+
+    // The way a watch pattern flags it, for the admin's list of matches
+    let flags = board.db.open_tree("watch_flags").unwrap();
+    flags.insert(format!("phone/{}", hit.id), &hit.timestamp.to_be_bytes()).unwrap();
+    let admin = admin_login(&board).await;
+    let html = board.send(admin.get("/admin/flagged")).await.html();
+    assert_eq!(attrs(&html, "a[href*='#r']", "href"), vec![expected.clone()]);
+
+    for path in [format!("/post/{}", hit.id), format!("/post/{}/180", thread.id)].iter() {
+        let res = board.get(path).await;
+        assert_eq!(res.status, StatusCode::FOUND, "{}", path);
+        assert_eq!(res.location(), expected, "{}", path);
+    }
+
+    // Page 2 holds replies 151 to 225, with a link to each of the 4 pages
+    let html = board.get(&format!("/post/{}?page=2", thread.id)).await.html();
+    let ids = attrs(&html, "article.reply", "id");
+    let wanted: Vec<String> = (151..=225).map(|number| format!("r{}", number)).collect();
+    assert_eq!(ids, wanted);
+    assert_eq!(texts(&html, ".pagination-links .current-page"), ["2"]);
+    let pages = attrs(&html, ".pagination-links a", "href");
+    assert_eq!(pages, [format!("/post/{}", thread.id), format!("/post/{}?page=1", thread.id), format!("/post/{}?page=3", thread.id)]);
+}
+
+#[actix_web::test]
+async fn pages_past_the_end_show_the_last_and_junk_is_refused() {
+    let board = TestBoard::with(|config| config.replies_per_page = 2);
+    let thread = long_thread(&board, 5).await;
+
+    let html = board.get(&format!("/post/{}?page=99", thread.id)).await.html();
+    assert_eq!(attrs(&html, "article.reply", "id"), ["r5"]);
+    assert_eq!(texts(&html, ".pagination-links .current-page"), ["2"]);
+    for page in ["-1", "x", "1.5"].iter() {
+        let res = board.get(&format!("/post/{}?page={}", thread.id, page)).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", page);
+    }
+}
+
+#[actix_web::test]
+async fn backlinks_and_the_reply_form_go_to_the_right_page() {
+    let board = TestBoard::with(|config| config.replies_per_page = 2);
+    let thread = long_thread(&board, 2).await;
+    board.reply(&thread, "Quoting", ">>1 about that").await;
+
+    // Reply 3 is on page 1, so reply 1's backlink on page 0 goes there
+    let html = board.get(&format!("/post/{}", thread.id)).await.html();
+    assert_eq!(attrs(&html, "#r1 .backlinks a", "href"), [format!("/post/{}?page=1#r3", thread.id)]);
+
+    // Sent from page 1 in the newest-first order, reply 4 stays on page 1
+    let form = Form::new()
+        .text("parent_id", &thread.id)
+        .text("return_to", &format!("/post/{}?page=1&order=desc", thread.id))
+        .text("title", "Fourth")
+        .text("message", "Here");
+    let res = board.submit(form).await;
+    assert_eq!(res.location(), format!("/post/{}?order=desc&page=1&just_posted=4#r4", thread.id));
+    // and reply 5 starts page 2
+    let form = Form::new()
+        .text("parent_id", &thread.id)
+        .text("return_to", &format!("/post/{}?page=1", thread.id))
+        .text("title", "Fifth")
+        .text("message", "There");
+    let res = board.submit(form).await;
+    assert_eq!(res.location(), format!("/post/{}?page=2&just_posted=5#r5", thread.id));
+    let html = board.get(res.location()).await.html();
+    assert_eq!(attrs(&html, "article.reply", "id"), ["r5"]);
+}
+
+#[actix_web::test]
+async fn unpaged_threads_keep_every_reply_on_one_page() {
+    let board = TestBoard::new();
+    let thread = long_thread(&board, 3).await;
+    let third = board.find("Reply 3");
+    assert_eq!(board.config.url_of(&third), format!("/post/{}#r3", thread.id));
+
+    let html = board.get(&format!("/post/{}?page=1", thread.id)).await.html();
+    assert_eq!(attrs(&html, "article.reply", "id"), ["r1", "r2", "r3"]);
+    assert!(texts(&html, ".pagination-links").is_empty());
+}
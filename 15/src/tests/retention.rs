@@ -0,0 +1,181 @@
+// Retention, see retention.rs: each class seeded with a record just past
+// its retention and one just inside it, and only the first goes. Classes
+// kept forever, or left at their defaults, lose nothing they shouldn't.
+
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+use super::TestBoard;
+use crate::audit::AuditEntry;
+use crate::changes::BoardChange;
+use crate::retention::{self, DataClass, RetentionPolicy};
+use crate::{archive, counters, now, Post};
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+// Either side of the cutoff by more than an hour, as the hourly buckets
+// are kept or dropped whole
+const MARGIN: u64 = 2 * HOUR;
+
+fn board(retention: &str) -> TestBoard {
+    let retention = RetentionPolicy::parse(retention).unwrap();
+    TestBoard::with(|config| {
+        config.retention = retention;
+        config.notify_key = Some("notify secret".to_string());
+    })
+}
+
+// Keys of a tree, for what's left in it
+fn keys(board: &TestBoard, tree: &str) -> Vec<String> {
+    board.db.open_tree(tree).unwrap().iter().keys().map(|key| String::from_utf8_lossy(&key.unwrap()).into_owned()).collect()
+}
+
+// The board log entries, with when each was made
+fn logged(board: &TestBoard) -> BTreeMap<u64, BoardChange> {
+    let log = board.db.open_tree("board_changes").unwrap();
+    log.iter().values().map(|bytes| serde_json::from_slice::<BoardChange>(&bytes.unwrap()).unwrap()).map(|change| (change.sequence, change)).collect()
+}
+
+fn backdate_change(board: &TestBoard, mut change: BoardChange, at: u64) {
+    change.at = at * 1000;
+    let log = board.db.open_tree("board_changes").unwrap();
+    log.insert(format!("{:020}", change.sequence), serde_json::to_vec(&change).unwrap()).unwrap();
+}
+
+fn audit_at(board: &TestBoard, at: u64, target: &str) {
+    let entry = AuditEntry {
+        admin: "Mod".to_string(),
+        action: "test".to_string(),
+        target: target.to_string(),
+        timestamp: at,
+    };
+    let key = format!("{:020}-{}", at, target);
+    board.db.open_tree("audit").unwrap().insert(key, serde_json::to_vec(&entry).unwrap()).unwrap();
+}
+
+// One record of every class at `at`, each named after `label`. Returns
+// the thread that was archived then.
+async fn seed(board: &TestBoard, label: &str, at: &BTreeMap<DataClass, u64>) -> Post {
+    let thread = board.thread(label, "Start").await;
+    assert!(archive::archive(&board.db, &thread.id, at[&DataClass::Archives]));
+    let quota = board.db.open_tree("post_quota").unwrap();
+    counters::increment_bucket(&quota, at[&DataClass::Quota] / HOUR, label, 1);
+    crate::notify::subscribe(&board.db, &board.config, label, "op@example.com", at[&DataClass::Watches]);
+    audit_at(board, at[&DataClass::Audit], label);
+    let hours = board.db.open_tree("post_hours").unwrap();
+    counters::increment_bucket(&hours, at[&DataClass::Activity] / HOUR, "", 1);
+    let (_, change) = logged(board).into_iter().find(|(_, change)| change.post_id == thread.id).unwrap();
+    backdate_change(board, change, at[&DataClass::Changes]);
+    thread
+}
+
+fn purged(board: &TestBoard) -> Value {
+    let counts = board.state.retained.counts(&board.config.retention);
+    let counts = serde_json::to_value(counts).unwrap();
+    let purged: BTreeMap<String, Value> = counts.as_object().unwrap().iter().map(|(class, counts)| (class.clone(), counts["purged"].clone())).collect();
+    serde_json::to_value(purged).unwrap()
+}
+
+#[actix_web::test]
+async fn only_records_past_their_retention_go() {
+    let board = board("archives=180d, quota=7d, watches=90d, audit=30d, activity=30d, changes=30d");
+    let now = now();
+    let days = [
+        (DataClass::Archives, 180),
+        (DataClass::Quota, 7),
+        (DataClass::Watches, 90),
+        (DataClass::Audit, 30),
+        (DataClass::Activity, 30),
+        (DataClass::Changes, 30),
+    ];
+    let expired: BTreeMap<DataClass, u64> = days.iter().map(|&(class, days)| (class, now - days * DAY - MARGIN)).collect();
+    let fresh: BTreeMap<DataClass, u64> = days.iter().map(|&(class, days)| (class, now - days * DAY + MARGIN)).collect();
+    let old = seed(&board, "old", &expired).await;
+    let kept = seed(&board, "kept", &fresh).await;
+    let audited = keys(&board, "audit").len();
+    let logged_before = logged(&board).len();
+
+    assert_eq!(retention::run(&board.db, &board.config, &board.state.events, &board.state.retained, now), 6);
+
+    assert!(crate::load_post(&board.db, &old.id).is_none());
+    assert!(archive::is_archived(&board.db, &kept.id));
+    let quota = keys(&board, "post_quota");
+    assert_eq!(quota, [counters::bucket_key(fresh[&DataClass::Quota] / HOUR, "kept")]);
+    assert_eq!(keys(&board, "notify_emails"), ["kept"]);
+    let targets: Vec<String> = board
+        .db
+        .open_tree("audit")
+        .unwrap()
+        .iter()
+        .values()
+        .map(|bytes| serde_json::from_slice::<AuditEntry>(&bytes.unwrap()).unwrap())
+        .map(|entry| format!("{} {}", entry.action, entry.target))
+        .collect();
+    assert!(!targets.contains(&"test old".to_string()), "{:?}", targets);
+    assert!(targets.contains(&"test kept".to_string()), "{:?}", targets);
+    // The run adds a delete for the archived thread and its report, both
+    // stamped `now`, so in no particular order
+    assert_eq!(targets.len(), audited - 1 + 2);
+    assert!(targets.contains(&format!("delete_thread {}", old.id)), "{:?}", targets);
+    assert!(targets.contains(&"retention archives: 1, quota: 1, watches: 1, audit: 1, activity: 1, changes: 1".to_string()), "{:?}", targets);
+    assert!(!keys(&board, "post_hours").contains(&counters::bucket_key(expired[&DataClass::Activity] / HOUR, "")));
+    assert!(keys(&board, "post_hours").contains(&counters::bucket_key(fresh[&DataClass::Activity] / HOUR, "")));
+    let changes = logged(&board);
+    assert!(changes.values().all(|change| change.post_id != old.id || change.at >= (now - 30 * DAY) * 1000));
+    // The deletion of the old thread is logged as it goes
+    assert_eq!(changes.len(), logged_before - 1 + 1);
+    assert!(changes.values().any(|change| change.post_id == old.id && change.at >= (now - 30 * DAY) * 1000));
+
+    let expected = json!({"archives": 1, "quota": 1, "watches": 1, "audit": 1, "activity": 1, "changes": 1});
+    assert_eq!(purged(&board), expected);
+    // A second run finds nothing more
+    assert_eq!(retention::run(&board.db, &board.config, &board.state.events, &board.state.retained, now), 0);
+    assert_eq!(purged(&board), expected);
+}
+
+#[actix_web::test]
+async fn forever_keeps_everything() {
+    let board = board("archives=forever, quota=forever, watches=forever, audit=forever, activity=forever, changes=forever");
+    let now = now();
+    let long_ago: BTreeMap<DataClass, u64> = DataClass::ALL.iter().map(|&class| (class, now - 3650 * DAY)).collect();
+    let old = seed(&board, "old", &long_ago).await;
+    let trees = ["archived", "post_quota", "notify_emails", "audit", "post_hours", "board_changes"];
+    let before: Vec<usize> = trees.iter().map(|tree| keys(&board, tree).len()).collect();
+
+    assert_eq!(retention::run(&board.db, &board.config, &board.state.events, &board.state.retained, now), 0);
+    let after: Vec<usize> = trees.iter().map(|tree| keys(&board, tree).len()).collect();
+    assert_eq!(after, before);
+    assert!(archive::is_archived(&board.db, &old.id));
+}
+
+#[actix_web::test]
+async fn classes_left_out_keep_their_defaults() {
+    let board = board("");
+    let now = now();
+    let policy = &board.config.retention;
+    assert_eq!(policy.retention(DataClass::Archives), None);
+    assert_eq!(policy.retention(DataClass::Quota), Some(0));
+    // Ten years back: gone where there's a default, kept where it's forever
+    let long_ago: BTreeMap<DataClass, u64> = DataClass::ALL.iter().map(|&class| (class, now - 3650 * DAY)).collect();
+    let old = seed(&board, "old", &long_ago).await;
+    retention::run(&board.db, &board.config, &board.state.events, &board.state.retained, now);
+    assert!(archive::is_archived(&board.db, &old.id));
+    assert!(keys(&board, "post_quota").is_empty());
+    assert!(keys(&board, "notify_emails").is_empty());
+    assert!(keys(&board, "audit").iter().any(|key| key.ends_with("-old")));
+    assert_eq!(purged(&board), json!({"archives": 0, "quota": 1, "watches": 1, "audit": 0, "activity": 1, "changes": 0}));
+}
+
+#[test]
+fn policies_are_parsed_strictly() {
+    let policy = RetentionPolicy::parse(" quota = 7d ,audit=forever,, activity=12h").unwrap();
+    assert_eq!(policy.retention(DataClass::Quota), Some(7 * DAY));
+    assert_eq!(policy.retention(DataClass::Audit), None);
+    assert_eq!(policy.retention(DataClass::Activity), Some(12 * HOUR));
+    assert_eq!(policy.retention(DataClass::Watches), Some(90 * DAY));
+    assert_eq!(retention::parse_duration("0"), Ok(Some(0)));
+    assert_eq!(retention::parse_duration("90s"), Ok(Some(90)));
+    for bad in ["tombstones=30d", "quota", "quota=7", "quota=7w", "quota=-1d", "quota=d", "quota=99999999999999999999d"].iter() {
+        assert!(RetentionPolicy::parse(bad).is_err(), "{}", bad);
+    }
+}
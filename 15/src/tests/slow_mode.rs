@@ -0,0 +1,102 @@
+// Slow mode, see changes::insert_reply: one reply per interval in a thread,
+// however many are sent at once, with the wait on the refusal and the
+// interval on the thread page. Moderators aren't held to it.
+
+use actix_web::http::header::{COOKIE, RETRY_AFTER};
+use actix_web::http::StatusCode;
+use scraper::Html;
+use std::sync::{Arc, Barrier};
+
+use super::{admin_login, texts, Form, TestBoard};
+use crate::changes::ReplyRefused;
+use crate::settings::BoardSettings;
+use crate::{now, store_post, Post};
+
+const INTERVAL: u32 = 60;
+const AT_ONCE: usize = 8;
+
+fn slow_board() -> TestBoard {
+    TestBoard::with(|config| config.admin_password = Some("secret".to_string()))
+}
+
+// Replies to `thread` all stamped `timestamp`, stored from their own threads
+// at the same moment. Returns how many got in, and the refusals.
+fn all_at_once(board: &TestBoard, thread: &Post, round: usize, timestamp: u64) -> (usize, Vec<ReplyRefused>) {
+    let barrier = Arc::new(Barrier::new(AT_ONCE));
+    let sent: Vec<_> = (0..AT_ONCE)
+        .map(|n| {
+            let (db, barrier) = (board.db.clone(), barrier.clone());
+            let reply = Post {
+                id: format!("round{}-{}", round, n),
+                parent_id: Some(thread.id.clone()),
+                title: format!("Round {} {}", round, n),
+                message: "Me first".to_string(),
+                timestamp,
+                ..Post::default()
+            };
+            std::thread::spawn(move || {
+                barrier.wait();
+                store_post(&db, &BoardSettings::default(), &reply, true)
+            })
+        })
+        .collect();
+    let results: Vec<_> = sent.into_iter().map(|sent| sent.join().unwrap()).collect();
+    let refused = results.iter().filter_map(|result| result.as_ref().err().copied()).collect();
+    (results.iter().filter(|result| result.is_ok()).count(), refused)
+}
+
+#[actix_web::test]
+async fn of_replies_sent_at_once_one_gets_in_per_interval() {
+    let board = slow_board();
+    let thread = board.thread("Heated", "Calm down").await;
+    assert!(crate::set_slow_mode(&board.db, &thread.id, Some(INTERVAL)));
+    let start = now();
+    let interval = u64::from(INTERVAL);
+
+    let (stored, refused) = all_at_once(&board, &thread, 0, start);
+    assert_eq!(stored, 1);
+    assert_eq!(refused, vec![ReplyRefused::SlowMode { wait_secs: interval }; AT_ONCE - 1]);
+    // Halfway through the interval none do
+    let (stored, refused) = all_at_once(&board, &thread, 1, start + interval / 2);
+    assert_eq!(stored, 0);
+    assert_eq!(refused, vec![ReplyRefused::SlowMode { wait_secs: interval / 2 }; AT_ONCE]);
+    let (stored, _) = all_at_once(&board, &thread, 2, start + interval);
+    assert_eq!(stored, 1);
+
+    // Numbered one after the other, with nothing skipped
+    let mut numbers: Vec<u64> = crate::indexes::thread_replies(&board.db, &thread.id).iter().filter_map(|reply| reply.reply_number).collect();
+    numbers.sort_unstable();
+    assert_eq!(numbers, [1, 2]);
+}
+
+#[actix_web::test]
+async fn a_reply_too_soon_is_told_how_long_to_wait() {
+    let board = slow_board();
+    let admin = admin_login(&board).await;
+    let thread = board.thread("Heated", "Calm down").await;
+    let slow = |secs: &str| admin.post(&format!("/admin/post/{}/slow-mode", thread.id)).set_form([("secs", secs)]);
+    assert_eq!(board.send(slow("60")).await.status, StatusCode::SEE_OTHER);
+    let html = board.get(&format!("/post/{}", thread.id)).await.html();
+    assert_eq!(texts(&html, ".slow-mode"), ["Slow mode: 1 post / 60 s"]);
+
+    board.reply(&thread, "First", "Me").await;
+    let reply = || Form::new().text("parent_id", &thread.id).text("title", "Second").text("message", "Me too");
+    let res = board.submit(reply()).await;
+    assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS, "{}", res.body);
+    let wait: u64 = res.headers.get(RETRY_AFTER).unwrap().to_str().unwrap().parse().unwrap();
+    assert!((59..=60).contains(&wait), "{}", wait);
+    let page = Html::parse_document(&res.body);
+    assert_eq!(texts(&page, ".form-error"), [format!("This thread is in slow mode. You can reply again in {} s.", wait)]);
+
+    // A moderator's reply goes in regardless
+    let form = Form::new().text("parent_id", &thread.id).text("title", "Moderator").text("message", "Cool it");
+    let req = form.request(&format!("/submit?csrf={}", admin.csrf)).insert_header((COOKIE, admin.cookie.as_str()));
+    assert_eq!(board.send(req).await.status, StatusCode::SEE_OTHER);
+
+    // and once it's off so does everyone's
+    assert_eq!(board.send(slow("0")).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(board.submit(reply()).await.status, StatusCode::SEE_OTHER);
+    let html = board.get(&format!("/post/{}", thread.id)).await.html();
+    assert!(texts(&html, ".slow-mode").is_empty());
+    assert_eq!(crate::indexes::thread_replies(&board.db, &thread.id).len(), 3);
+}
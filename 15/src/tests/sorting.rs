@@ -0,0 +1,101 @@
+// Thread orders, see sorting.rs: the same threads listed by last bump, by
+// when they started, by replies and by replies in the last day, on the
+// index and in /api/threads.
+
+use serde_json::Value;
+
+use super::{texts, TestBoard};
+use crate::indexes::bump_key;
+use crate::{activity, counters, now, Post};
+
+const DAY: u64 = 24 * 60 * 60;
+
+// A thread started at `created` and last bumped at `bumped`, with its index
+// entries moved to match
+async fn placed(board: &TestBoard, title: &str, created: u64, bumped: u64) -> Post {
+    let mut thread = board.thread(title, "x").await;
+    for tree in ["creations", "bumps", "posted"] {
+        board.db.open_tree(tree).unwrap().remove(bump_key(thread.timestamp, &thread.id)).unwrap();
+    }
+    thread.timestamp = bumped;
+    board.db.insert(&thread.id, serde_json::to_vec(&thread).unwrap()).unwrap();
+    for (tree, time) in [("creations", created), ("posted", created), ("bumps", bumped)] {
+        board.db.open_tree(tree).unwrap().insert(bump_key(time, &thread.id), &[]).unwrap();
+    }
+    thread
+}
+
+// `total` replies counted for `thread`, `recent` of them in the last hour
+// and the rest two days ago
+fn replied(board: &TestBoard, thread: &Post, total: u64, recent: u64) {
+    counters::increment(&board.db.open_tree("reply_counts").unwrap(), &thread.id, total as i64);
+    for n in 0..total {
+        let at = if n < recent { now() } else { now() - 2 * DAY };
+        activity::record_reply(&board.db, &thread.id, at);
+    }
+}
+
+async fn index_titles(board: &TestBoard, query: &str) -> Vec<String> {
+    texts(&board.get(&format!("/?{}", query)).await.html(), ".post h3 bdi")
+}
+
+// Titles from /api/threads, a page of `limit` at a time
+async fn api_titles(board: &TestBoard, sort: &str, limit: usize) -> Vec<String> {
+    let mut titles = Vec::new();
+    let mut cursor = String::new();
+    loop {
+        let res = board.get(&format!("/api/threads?sort={}&limit={}{}", sort, limit, cursor)).await;
+        let body: Value = serde_json::from_str(&res.body).unwrap();
+        titles.extend(body["threads"].as_array().unwrap().iter().map(|thread| thread["title"].as_str().unwrap().to_string()));
+        match body["next_cursor"].as_str() {
+            Some(next) => cursor = format!("&cursor={}", next),
+            None => return titles,
+        }
+    }
+}
+
+#[actix_web::test]
+async fn each_order_ranks_the_threads_its_own_way() {
+    let board = TestBoard::new();
+    let start = now() - 10 * DAY;
+    // Started in alphabetical order, bumped in another
+    let alpha = placed(&board, "Alpha", start, start + 9 * DAY).await;
+    let bravo = placed(&board, "Bravo", start + DAY, start + DAY).await;
+    let charlie = placed(&board, "Charlie", start + 2 * DAY, start + 8 * DAY).await;
+    let delta = placed(&board, "Delta", start + 3 * DAY, start + 3 * DAY).await;
+    replied(&board, &alpha, 2, 2);
+    replied(&board, &bravo, 6, 0);
+    replied(&board, &charlie, 4, 4);
+    replied(&board, &delta, 3, 1);
+
+    let orders = [
+        ("bump", ["Alpha", "Charlie", "Delta", "Bravo"]),
+        ("creation", ["Delta", "Charlie", "Bravo", "Alpha"]),
+        ("replies", ["Bravo", "Charlie", "Delta", "Alpha"]),
+        ("activity", ["Charlie", "Alpha", "Delta", "Bravo"]),
+    ];
+    for (sort, expected) in orders {
+        assert_eq!(index_titles(&board, &format!("sort={}", sort)).await, expected, "{}", sort);
+        assert_eq!(api_titles(&board, sort, 50).await, expected, "{}", sort);
+        // Cursors carry on in the same order
+        assert_eq!(api_titles(&board, sort, 1).await, expected, "{}", sort);
+    }
+
+    // Anything else is the bump order
+    for query in ["", "sort=", "sort=oldest", "sort=REPLIES"] {
+        assert_eq!(index_titles(&board, query).await, ["Alpha", "Charlie", "Delta", "Bravo"], "{:?}", query);
+    }
+}
+
+#[actix_web::test]
+async fn ties_go_to_the_thread_bumped_last() {
+    let board = TestBoard::new();
+    let start = now() - 10 * DAY;
+    for (n, title) in ["Old", "Middle", "New"].iter().enumerate() {
+        let thread = placed(&board, title, start, start + n as u64).await;
+        replied(&board, &thread, 2, 2);
+    }
+    for sort in ["replies", "activity"] {
+        assert_eq!(index_titles(&board, &format!("sort={}", sort)).await, ["New", "Middle", "Old"], "{}", sort);
+    }
+}
@@ -0,0 +1,112 @@
+// The spam heuristics of validation.rs: messages that are mostly links or
+// one character repeated, and the posts that look a bit like that but
+// aren't spam.
+
+use actix_web::http::header::COOKIE;
+use actix_web::http::StatusCode;
+
+use super::{admin_login, Form, TestBoard};
+use crate::config::Config;
+use crate::rejection::ErrorCode;
+use crate::settings::BoardSettings;
+use crate::validation::{validate_post, Submission};
+
+fn submission(message: &str) -> Submission<'_> {
+    Submission {
+        title: "Title",
+        name: None,
+        message,
+        is_thread: true,
+        has_file: false,
+        thread_locks_at: None,
+        thread_archived: false,
+        timestamp: 0,
+        skip_spam_checks: false,
+    }
+}
+
+fn is_spam(submission: &Submission) -> bool {
+    let config = Config::from_env();
+    match validate_post(&config, &BoardSettings::default(), submission) {
+        Ok(()) => false,
+        Err(errors) => errors.iter().any(|error| error.code == ErrorCode::Spam),
+    }
+}
+
+fn links(count: usize) -> String {
+    (0..count).map(|n| format!("https://spam.example/buy/{}", n)).collect::<Vec<_>>().join(" ")
+}
+
+#[test]
+fn one_long_url_with_a_comment_is_fine() {
+    let url = format!("https://maps.example/place/{}", "a1b2c3d4".repeat(40));
+    assert!(!is_spam(&submission(&format!("{} this is where we met", url))));
+    assert!(!is_spam(&submission(&format!("look: {}", url))));
+    assert!(!is_spam(&submission(&url)));
+}
+
+#[test]
+fn a_few_links_among_text_are_fine() {
+    let text = "Here are the two sources I mentioned earlier in the thread, both worth reading in full. ";
+    let message = format!("{}{}{}", text.repeat(3), links(2), text);
+    assert!(!is_spam(&submission(&message)));
+    // Short messages are never "mostly links"
+    assert!(!is_spam(&submission(&links(4))));
+}
+
+#[test]
+fn messages_of_links_are_spam() {
+    assert!(is_spam(&submission(&links(40))));
+    assert!(is_spam(&submission(&format!("cheap {} now", links(10)))));
+}
+
+#[test]
+fn whitespace_padding_doesnt_hide_links() {
+    let padded = links(12).replace(' ', &format!("{}\n\n\t", " ".repeat(60)));
+    assert!(is_spam(&submission(&padded)));
+}
+
+#[test]
+fn repeat_runs_are_limited() {
+    let config = Config::from_env();
+    let max = config.spam_max_repeat_run;
+    assert!(!is_spam(&submission(&"a".repeat(max))));
+    assert!(is_spam(&submission(&"a".repeat(max + 1))));
+    // Spaces between don't help
+    assert!(is_spam(&submission(&"a ".repeat(max + 1))));
+    // Alternating isn't a run
+    assert!(!is_spam(&submission(&"ab".repeat(max + 1))));
+}
+
+#[test]
+fn repeat_runs_count_graphemes() {
+    let max = Config::from_env().spam_max_repeat_run;
+    // A thumb with a skin tone is two chars, an e with a combining accent too
+    for grapheme in ["👍🏽", "e\u{301}", "👨‍👩‍👧"] {
+        assert!(!is_spam(&submission(&grapheme.repeat(max))), "{}", grapheme);
+        assert!(is_spam(&submission(&grapheme.repeat(max + 1))), "{}", grapheme);
+    }
+    // Different skin tones are different graphemes
+    assert!(!is_spam(&submission(&"👍🏽👍🏿".repeat(max))));
+}
+
+#[test]
+fn exempt_posters_skip_the_checks() {
+    let message = links(40);
+    let mut exempt = submission(&message);
+    exempt.skip_spam_checks = true;
+    assert!(!is_spam(&exempt));
+}
+
+#[actix_web::test]
+async fn admins_skip_the_checks() {
+    let board = TestBoard::with(|config| config.admin_password = Some("secret".to_string()));
+    let form = || Form::new().text("title", "Links").text("message", &links(40));
+    assert_eq!(board.submit(form()).await.status, StatusCode::BAD_REQUEST);
+
+    let admin = admin_login(&board).await;
+    let req = form().request(&format!("/submit?csrf={}", admin.csrf));
+    let res = board.send(req.insert_header((COOKIE, admin.cookie.as_str()))).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    board.find("Links");
+}
@@ -0,0 +1,58 @@
+// Text that could pass for something else, see validation::strip_format_chars:
+// bidi overrides and invisible characters are taken out of titles, names
+// and options before they're stored, and what posters write is isolated
+// from the page around it.
+
+use actix_web::http::StatusCode;
+use scraper::Html;
+
+use super::{attrs, select, texts, Form, TestBoard};
+
+// "cat", then everything after shown right to left: "catexe.jpg"
+const SPOOFED: &str = "cat\u{202E}gpj.exe";
+
+#[actix_web::test]
+async fn overrides_are_taken_out_of_titles_and_names() {
+    let board = TestBoard::new();
+    let form = Form::new()
+        .text("title", SPOOFED)
+        .text("name", "Mo\u{200B}d\u{2066}\u{2069}")
+        .text("message", "Kept \u{202E}as written");
+    assert_eq!(board.submit(form).await.status, StatusCode::SEE_OTHER);
+    let thread = board.find("catgpj.exe");
+    assert_eq!(thread.name.as_deref(), Some("Mod"));
+    assert_eq!(thread.message, "Kept \u{202E}as written");
+
+    for path in ["/".to_string(), format!("/post/{}", thread.id)] {
+        let html = board.get(&path).await.html();
+        // Titles and names each sit in a <bdi>, messages in dir="auto"
+        assert_eq!(texts(&html, "h3 > bdi"), ["catgpj.exe"], "{}", path);
+        assert_eq!(texts(&html, ".poster-name > bdi"), ["Mod"], "{}", path);
+        assert_eq!(select(&html, "p[dir=auto]")[0].text().collect::<String>(), "Kept \u{202E}as written", "{}", path);
+        assert!(!html.html().contains("cat\u{202E}"), "{}", path);
+    }
+}
+
+#[actix_web::test]
+async fn emoji_keep_their_joiners() {
+    let board = TestBoard::new();
+    // A family, and a joiner on its own that joins nothing
+    let title = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467} hi\u{200D}";
+    assert_eq!(board.submit(Form::new().text("title", title).text("message", "x")).await.status, StatusCode::SEE_OTHER);
+    board.find("\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467} hi");
+}
+
+#[actix_web::test]
+async fn options_sent_back_are_stripped_too() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let form = Form::new()
+        .text("parent_id", &thread.id)
+        .text("title", "Far too long a title")
+        .text("options", "\u{202E}nosage\u{200B}")
+        .text("message", "Mine");
+    let res = board.submit(form).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    let html = Html::parse_document(&res.body);
+    assert_eq!(attrs(&html, "#reply-form input[name=options]", "value"), ["nosage"]);
+}
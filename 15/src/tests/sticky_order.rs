@@ -0,0 +1,165 @@
+// The order of sticky threads, see indexes.rs: four stickies moved up and
+// down with the buttons on /admin/posts, and the order kept without gaps
+// as stickies come and go.
+
+use actix_web::http::StatusCode;
+use scraper::Html;
+use std::convert::TryInto;
+
+use super::{admin_login, select, texts, AdminLogin, TestBoard};
+use crate::audit::AuditEntry;
+use crate::indexes::{self, Direction};
+use crate::{archive, now, Post};
+
+async fn four_stickies() -> (TestBoard, AdminLogin, Vec<Post>) {
+    let board = TestBoard::with(|config| config.admin_password = Some("secret".to_string()));
+    let admin = admin_login(&board).await;
+    let mut threads = Vec::new();
+    for title in ["Rules", "FAQ", "News", "Meta"].iter() {
+        let thread = board.thread(title, "Read me").await;
+        assert_eq!(board.send(admin.post(&format!("/admin/post/{}/sticky", thread.id))).await.status, StatusCode::SEE_OTHER);
+        threads.push(thread);
+    }
+    board.thread("Chat", "Not sticky").await;
+    (board, admin, threads)
+}
+
+// The stickies at the top of the index
+async fn on_the_index(board: &TestBoard) -> Vec<String> {
+    texts(&board.get("/").await.html(), ".post.sticky h3 bdi")
+}
+
+// The sticky table on /admin/posts: each title with its buttons
+async fn in_the_panel(board: &TestBoard, admin: &AdminLogin) -> Vec<(String, Vec<String>)> {
+    let html = Html::parse_document(&board.send(admin.get("/admin/posts")).await.body);
+    select(&html, ".sticky-order tr")
+        .iter()
+        .map(|row| {
+            let row = Html::parse_fragment(&format!("<table>{}</table>", row.html()));
+            (texts(&row, "td a bdi").remove(0), texts(&row, "button"))
+        })
+        .collect()
+}
+
+async fn move_sticky(board: &TestBoard, admin: &AdminLogin, thread: &Post, dir: &str) -> StatusCode {
+    board.send(admin.post(&format!("/admin/post/{}/sticky-order?dir={}", thread.id, dir))).await.status
+}
+
+// The order each entry in the stickies index holds, in order
+fn orders(board: &TestBoard) -> Vec<u32> {
+    let stickies = board.db.open_tree("stickies").unwrap();
+    let mut orders: Vec<u32> = stickies.iter().values().map(|order| u32::from_be_bytes(order.unwrap().as_ref().try_into().unwrap())).collect();
+    orders.sort_unstable();
+    orders
+}
+
+fn audited(board: &TestBoard, action: &str) -> Vec<String> {
+    let audit = board.db.open_tree("audit").unwrap();
+    audit.iter().values().map(|bytes| serde_json::from_slice::<AuditEntry>(&bytes.unwrap()).unwrap()).filter(|entry| entry.action == action).map(|entry| entry.target).collect()
+}
+
+#[actix_web::test]
+async fn four_stickies_are_shown_in_the_order_set() {
+    let (board, admin, threads) = four_stickies().await;
+    // In the order they were stuck, not by bump
+    assert_eq!(on_the_index(&board).await, ["Rules", "FAQ", "News", "Meta"]);
+    board.reply(&threads[3], "Bump", "Up").await;
+    assert_eq!(on_the_index(&board).await, ["Rules", "FAQ", "News", "Meta"]);
+    assert_eq!(orders(&board), [0, 1, 2, 3]);
+
+    assert_eq!(move_sticky(&board, &admin, &threads[3], "up").await, StatusCode::SEE_OTHER);
+    assert_eq!(on_the_index(&board).await, ["Rules", "FAQ", "Meta", "News"]);
+    assert_eq!(move_sticky(&board, &admin, &threads[3], "up").await, StatusCode::SEE_OTHER);
+    assert_eq!(move_sticky(&board, &admin, &threads[0], "down").await, StatusCode::SEE_OTHER);
+    assert_eq!(on_the_index(&board).await, ["Meta", "Rules", "FAQ", "News"]);
+    assert_eq!(move_sticky(&board, &admin, &threads[1], "down").await, StatusCode::SEE_OTHER);
+    assert_eq!(on_the_index(&board).await, ["Meta", "Rules", "News", "FAQ"]);
+    assert_eq!(orders(&board), [0, 1, 2, 3]);
+    // The rest of the board is below them as ever
+    assert_eq!(texts(&board.get("/").await.html(), ".post:not(.sticky) h3 bdi"), ["Chat"]);
+
+    // The panel lists them the same way, with no Up for the first or Down
+    // for the last
+    let up_down = || vec!["Up".to_string(), "Down".to_string()];
+    assert_eq!(
+        in_the_panel(&board, &admin).await,
+        [
+            ("Meta".to_string(), vec!["Down".to_string()]),
+            ("Rules".to_string(), up_down()),
+            ("News".to_string(), up_down()),
+            ("FAQ".to_string(), vec!["Up".to_string()]),
+        ]
+    );
+    assert_eq!(audited(&board, "sticky_up"), [threads[3].id.clone(), threads[3].id.clone()]);
+    assert_eq!(audited(&board, "sticky_down").len(), 2);
+}
+
+#[actix_web::test]
+async fn moves_off_the_ends_or_of_no_sticky_change_nothing() {
+    let (board, admin, threads) = four_stickies().await;
+    assert_eq!(move_sticky(&board, &admin, &threads[0], "up").await, StatusCode::SEE_OTHER);
+    assert_eq!(move_sticky(&board, &admin, &threads[3], "down").await, StatusCode::SEE_OTHER);
+    assert_eq!(on_the_index(&board).await, ["Rules", "FAQ", "News", "Meta"]);
+
+    assert_eq!(move_sticky(&board, &admin, &threads[1], "sideways").await, StatusCode::BAD_REQUEST);
+    let chat = board.find("Chat");
+    assert_eq!(move_sticky(&board, &admin, &chat, "up").await, StatusCode::NOT_FOUND);
+    assert_eq!(board.send(admin.post("/admin/post/nothing/sticky-order?dir=up")).await.status, StatusCode::NOT_FOUND);
+    // Not without the token either
+    let req = admin.get(&format!("/admin/post/{}/sticky-order?dir=up", threads[1].id)).method(actix_web::http::Method::POST);
+    assert_ne!(board.send(req).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(on_the_index(&board).await, ["Rules", "FAQ", "News", "Meta"]);
+    assert!(audited(&board, "sticky_up").iter().all(|target| *target == threads[0].id));
+}
+
+#[actix_web::test]
+async fn stickies_that_go_leave_no_gaps() {
+    let (board, admin, threads) = four_stickies().await;
+    assert_eq!(board.send(admin.post(&format!("/admin/post/{}/unsticky", threads[1].id))).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(orders(&board), [0, 1, 2]);
+    assert_eq!(on_the_index(&board).await, ["Rules", "News", "Meta"]);
+    // Stuck again it goes last
+    assert_eq!(board.send(admin.post(&format!("/admin/post/{}/sticky", threads[1].id))).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(on_the_index(&board).await, ["Rules", "News", "Meta", "FAQ"]);
+    assert_eq!(orders(&board), [0, 1, 2, 3]);
+    // and sticking it twice doesn't move it
+    assert_eq!(board.send(admin.post(&format!("/admin/post/{}/sticky", threads[1].id))).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(on_the_index(&board).await, ["Rules", "News", "Meta", "FAQ"]);
+
+    assert!(archive::archive(&board.db, &threads[0].id, now()));
+    assert_eq!(orders(&board), [0, 1, 2]);
+    assert_eq!(board.send(admin.post(&format!("/admin/post/{}/delete-thread", threads[3].id))).await.status, StatusCode::SEE_OTHER);
+    assert_eq!(orders(&board), [0, 1]);
+    assert_eq!(on_the_index(&board).await, ["News", "FAQ"]);
+    // and a new one still goes last
+    let new = board.thread("New", "Read me").await;
+    indexes::set_sticky(&board.db, &new.id, true);
+    assert_eq!(orders(&board), [0, 1, 2]);
+    assert_eq!(on_the_index(&board).await, ["News", "FAQ", "New"]);
+}
+
+#[actix_web::test]
+async fn stickies_from_before_the_order_go_last_until_numbered() {
+    let board = TestBoard::new();
+    let ordered = board.thread("Ordered", "Read me").await;
+    indexes::set_sticky(&board.db, &ordered.id, true);
+    let stickies = board.db.open_tree("stickies").unwrap();
+    let mut old = Vec::new();
+    for title in ["Old one", "Old two"].iter() {
+        let thread = board.thread(title, "Read me").await;
+        stickies.insert(thread.id.as_bytes(), &[]).unwrap();
+        old.push(thread);
+    }
+    // Most recently bumped first among themselves, a minute on as posts
+    // in the same second can't be told apart
+    crate::bump_thread(&board.db, &old[0].id, now() + 60);
+    assert_eq!(on_the_index(&board).await, ["Ordered", "Old one", "Old two"]);
+    // A new sticky goes after the ordered ones, so before them
+    let new = board.thread("New", "Read me").await;
+    indexes::set_sticky(&board.db, &new.id, true);
+    assert_eq!(on_the_index(&board).await, ["Ordered", "New", "Old one", "Old two"]);
+
+    assert!(indexes::move_sticky(&board.db, &old[1].id, Direction::Up));
+    assert_eq!(on_the_index(&board).await, ["Ordered", "New", "Old two", "Old one"]);
+    assert_eq!(orders(&board), [0, 1, 2, 3]);
+}
@@ -0,0 +1,99 @@
+// Themes, see theme.rs: /theme.css serves the sheet for the `theme` cookie,
+// the default one for no cookie or a name that isn't a theme, and POST
+// /theme sets or clears the cookie and comes back to the page it was on.
+
+use actix_web::http::header::{CACHE_CONTROL, COOKIE, ETAG, IF_NONE_MATCH, SET_COOKIE, VARY};
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+
+use super::{attrs, Response, TestBoard};
+use crate::theme::Theme;
+
+fn sheet(theme: Theme) -> String {
+    std::fs::read_to_string(format!("static/themes/{}.css", theme.as_str())).unwrap()
+}
+
+async fn stylesheet(board: &TestBoard, cookie: Option<&str>) -> Response {
+    let mut req = TestRequest::get().uri("/theme.css");
+    if let Some(cookie) = cookie {
+        req = req.insert_header((COOKIE, cookie));
+    }
+    let res = board.send(req).await;
+    assert_eq!(res.status, StatusCode::OK, "{:?}", cookie);
+    assert_eq!(res.headers.get(VARY).unwrap(), "Cookie");
+    assert_eq!(res.headers.get(CACHE_CONTROL).unwrap(), "private, no-cache");
+    res
+}
+
+async fn pick(board: &TestBoard, theme: &str, return_to: &str) -> Response {
+    let req = TestRequest::post().uri("/theme").set_form([("theme", theme), ("return_to", return_to)]);
+    let res = board.send(req).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", theme);
+    res
+}
+
+fn theme_cookie(res: &Response) -> String {
+    res.headers.get(SET_COOKIE).unwrap().to_str().unwrap().to_string()
+}
+
+#[actix_web::test]
+async fn no_pick_or_a_bad_one_gets_the_default_theme() {
+    for default in Theme::ALL.iter() {
+        let board = TestBoard::with(|config| config.default_theme = *default);
+        for cookie in [None, Some("theme=bogus"), Some("theme="), Some("theme=Dark"), Some("theme=../light")].iter() {
+            assert_eq!(stylesheet(&board, *cookie).await.body, sheet(*default), "{:?} {:?}", default, cookie);
+        }
+    }
+    assert_ne!(sheet(Theme::Dark), sheet(Theme::Yotsuba));
+}
+
+#[actix_web::test]
+async fn a_picked_theme_comes_back_with_its_cookie() {
+    let board = TestBoard::new();
+    let res = pick(&board, "yotsuba", "/stats").await;
+    assert_eq!(res.location(), "/stats");
+    let set = theme_cookie(&res);
+    for attribute in ["theme=yotsuba", "Path=/", "SameSite=Lax", "Max-Age=31536000"].iter() {
+        assert!(set.contains(attribute), "{}", set);
+    }
+    let cookie = set.split(';').next().unwrap().to_string();
+    let picked = stylesheet(&board, Some(&cookie)).await;
+    assert_eq!(picked.body, sheet(Theme::Yotsuba));
+
+    // Revalidated by ETag, which tells the themes apart
+    let etag = picked.headers.get(ETAG).unwrap().clone();
+    let req = TestRequest::get().uri("/theme.css").insert_header((COOKIE, cookie.as_str())).insert_header((IF_NONE_MATCH, etag.clone()));
+    assert_eq!(board.send(req).await.status, StatusCode::NOT_MODIFIED);
+    let req = TestRequest::get().uri("/theme.css").insert_header((IF_NONE_MATCH, etag));
+    assert_eq!(board.send(req).await.body, sheet(Theme::Light));
+}
+
+#[actix_web::test]
+async fn a_bad_pick_clears_the_cookie_and_stays_on_the_board() {
+    let board = TestBoard::new();
+    let res = pick(&board, "bogus", "https://elsewhere.example/").await;
+    assert_eq!(res.location(), "/");
+    let set = theme_cookie(&res);
+    assert!(set.starts_with("theme=;") && set.contains("Max-Age=0"), "{}", set);
+    let cookie = set.split(';').next().unwrap().to_string();
+    assert_eq!(stylesheet(&board, Some(&cookie)).await.body, sheet(Theme::Light));
+}
+
+#[actix_web::test]
+async fn every_public_page_links_the_theme_and_offers_the_picker() {
+    let board = TestBoard::new();
+    let thread = board.thread("Thread", "Start").await;
+    let thread_url = format!("/post/{}", thread.id);
+    for page in ["/", thread_url.as_str(), "/stats", "/archive"].iter() {
+        let res = board.get(page).await;
+        assert_eq!(res.status, StatusCode::OK, "{}", page);
+        let html = scraper::Html::parse_document(&res.body);
+        let sheets = attrs(&html, "link[rel=stylesheet]", "href");
+        assert_eq!(sheets[sheets.len() - 2..], ["/static/style.css", "/theme.css"], "{}", page);
+        assert_eq!(attrs(&html, ".theme-footer form", "action"), ["/theme"], "{}", page);
+        assert_eq!(attrs(&html, ".theme-footer input[name=return_to]", "value"), [*page], "{}", page);
+        assert_eq!(attrs(&html, ".theme-footer button[name=theme]", "value"), ["light", "dark", "yotsuba"], "{}", page);
+        // and the picker comes back to it
+        assert_eq!(pick(&board, "dark", page).await.location(), *page);
+    }
+}
@@ -0,0 +1,185 @@
+// Upload slots, see upload_slots.rs: more uploads at once than there are
+// slots, with two held open partway through their files. The rest are
+// turned away with a 503 that says why, or wait their turn, while pages
+// and text-only posts go on as normal and each slot comes back however
+// its upload ended.
+
+use actix_web::error::PayloadError;
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::http::StatusCode;
+use actix_web::rt::time;
+use actix_web::web::Bytes;
+use futures_util::{future, stream, StreamExt};
+use serde_json::Value;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::{admin_login, png, texts, Form, Response, TestBoard};
+use crate::upload;
+
+fn upload_form(title: &str) -> Form {
+    Form::new().text("title", title).text("message", "With a file").file("file", "pic.png", "image/png", &png(64))
+}
+
+// An upload that stops partway through its file, holding its slot, until
+// `released` is set
+async fn held_upload(board: &TestBoard, title: &str, released: Rc<Cell<bool>>) -> Response {
+    let (req, body) = upload_form(title).split("/submit");
+    let half = body.len() - 100;
+    let (first_half, rest) = (Bytes::copy_from_slice(&body[..half]), Bytes::copy_from_slice(&body[half..]));
+    let body = stream::iter(vec![Ok::<_, PayloadError>(first_half)]).chain(stream::once(async move {
+        while !released.get() {
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        Ok(rest)
+    }));
+    board.send_stream(req, body).await
+}
+
+// Waits until the slots are reported as `report`
+async fn until_reported(board: &TestBoard, report: &str) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while board.state.upload_slots.report() != report {
+        assert!(Instant::now() < deadline, "{}", board.state.upload_slots.report());
+        time::sleep(Duration::from_millis(1)).await;
+    }
+}
+
+fn busy(res: &Response) {
+    assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE, "{}", res.body);
+    assert_eq!(res.headers.get(RETRY_AFTER).unwrap(), "5");
+    let body: Value = serde_json::from_str(&res.body).unwrap();
+    assert_eq!(body["fields"][0]["field"], "file");
+    assert_eq!(body["fields"][0]["code"], "uploads_busy");
+    assert_eq!(body["fields"][0]["message"], "Too many uploads in progress, try again shortly. You can still post without a file.");
+}
+
+#[actix_web::test]
+async fn more_uploads_than_slots_are_turned_away_while_pages_stay_fast() {
+    let board = TestBoard::with(|config| {
+        config.admin_password = Some("secret".to_string());
+        config.max_concurrent_uploads = 2;
+        config.upload_slot_wait_secs = 0;
+    });
+    let admin = admin_login(&board).await;
+    let released = Rc::new(Cell::new(false));
+    let held = future::join(held_upload(&board, "Held 0", released.clone()), held_upload(&board, "Held 1", released.clone()));
+    let meanwhile = async {
+        until_reported(&board, "2 of 2 upload slots in use").await;
+        let started = Instant::now();
+        let turned_away = future::join_all((0..8).map(|n| board.submit(upload_form(&format!("Busy {}", n)).json()))).await;
+        let answered_in = started.elapsed();
+        let mut page_loads = Vec::new();
+        for _ in 0..5 {
+            let started = Instant::now();
+            assert_eq!(board.get("/").await.status, StatusCode::OK);
+            page_loads.push(started.elapsed());
+        }
+        let text_only = board.submit(Form::new().text("title", "Text").text("message", "No file")).await;
+        let html = board.send(admin.get("/admin/posts")).await.html();
+        released.set(true);
+        (turned_away, answered_in, page_loads, text_only, html)
+    };
+    let ((first, second), (turned_away, answered_in, page_loads, text_only, html)) = future::join(held, meanwhile).await;
+
+    // A clear answer at once, not a hang
+    assert_eq!(turned_away.len(), 8);
+    turned_away.iter().for_each(busy);
+    assert!(answered_in < Duration::from_secs(2), "{:?}", answered_in);
+    assert!(page_loads.iter().all(|load| *load < Duration::from_secs(1)), "{:?}", page_loads);
+    assert_eq!(text_only.status, StatusCode::SEE_OTHER, "{}", text_only.body);
+    assert_eq!(texts(&html, ".form-container p.muted")[0], "Uploads: 2 of 2 upload slots in use, 8 turned away since the server started.");
+
+    // The held ones go through and give their slots back
+    assert_eq!(first.status, StatusCode::SEE_OTHER, "{}", first.body);
+    assert_eq!(second.status, StatusCode::SEE_OTHER, "{}", second.body);
+    assert_eq!(board.state.upload_slots.report(), "0 of 2 upload slots in use, 8 turned away since the server started");
+    assert!(board.find("Held 0").file.is_some() && board.find("Held 1").file.is_some());
+    assert!(board.db.iter().values().filter_map(|bytes| crate::Post::upgrade(&bytes.unwrap()).ok()).all(|post| !post.title.starts_with("Busy")));
+    assert_eq!(upload::stored_files(&board.config.upload_dir).len(), 2);
+    assert_eq!(board.submit(upload_form("After")).await.status, StatusCode::SEE_OTHER);
+    let html = board.send(admin.get("/admin/performance")).await.html();
+    assert!(texts(&html, "p").contains(&"Uploads: 0 of 2 upload slots in use, 8 turned away since the server started.".to_string()));
+}
+
+#[actix_web::test]
+async fn a_waiting_upload_takes_the_slot_that_frees() {
+    let board = TestBoard::with(|config| {
+        config.max_concurrent_uploads = 1;
+        config.upload_slot_wait_secs = 10;
+    });
+    let released = Rc::new(Cell::new(false));
+    let held = held_upload(&board, "Held", released.clone());
+    let waiting = async {
+        until_reported(&board, "1 of 1 upload slots in use").await;
+        let release = async {
+            time::sleep(Duration::from_millis(200)).await;
+            released.set(true);
+        };
+        let started = Instant::now();
+        let (res, _) = future::join(board.submit(upload_form("Waited")), release).await;
+        (res, started.elapsed())
+    };
+    let (held, (waited, took)) = future::join(held, waiting).await;
+    assert_eq!(held.status, StatusCode::SEE_OTHER, "{}", held.body);
+    assert_eq!(waited.status, StatusCode::SEE_OTHER, "{}", waited.body);
+    assert!(took >= Duration::from_millis(200) && took < Duration::from_secs(10), "{:?}", took);
+    assert_eq!(board.state.upload_slots.report(), "0 of 1 upload slots in use");
+}
+
+#[actix_web::test]
+async fn the_wait_runs_out_with_the_same_answer() {
+    let board = TestBoard::with(|config| {
+        config.max_concurrent_uploads = 1;
+        config.upload_slot_wait_secs = 1;
+    });
+    let released = Rc::new(Cell::new(false));
+    let held = held_upload(&board, "Held", released.clone());
+    let waiting = async {
+        until_reported(&board, "1 of 1 upload slots in use").await;
+        let started = Instant::now();
+        let res = board.submit(upload_form("Gave up").json()).await;
+        released.set(true);
+        (res, started.elapsed())
+    };
+    let (held, (gave_up, took)) = future::join(held, waiting).await;
+    busy(&gave_up);
+    assert!(took >= Duration::from_secs(1) && took < Duration::from_secs(5), "{:?}", took);
+    assert_eq!(held.status, StatusCode::SEE_OTHER);
+}
+
+#[actix_web::test]
+async fn a_failed_upload_gives_its_slot_back() {
+    let board = TestBoard::with(|config| {
+        config.max_concurrent_uploads = 1;
+        config.upload_slot_wait_secs = 0;
+    });
+    let (req, body) = upload_form("Broken").split("/submit");
+    let broken = stream::iter(vec![Ok(Bytes::copy_from_slice(&body[..body.len() - 100])), Err(PayloadError::Incomplete(None))]);
+    assert_eq!(board.send_stream(req, broken).await.status, StatusCode::BAD_REQUEST);
+    let form = Form::new().text("title", "Bad").text("message", "Not a picture").file("file", "pic.png", "image/png", b"not a png");
+    assert_eq!(board.submit(form).await.status, StatusCode::BAD_REQUEST);
+    assert_eq!(board.state.upload_slots.report(), "0 of 1 upload slots in use");
+    assert_eq!(board.submit(upload_form("Fine")).await.status, StatusCode::SEE_OTHER);
+}
+
+#[actix_web::test]
+async fn no_limit_still_counts() {
+    let board = TestBoard::with(|config| config.max_concurrent_uploads = 0);
+    let released = Rc::new(Cell::new(false));
+    let held = future::join3(
+        held_upload(&board, "Held 0", released.clone()),
+        held_upload(&board, "Held 1", released.clone()),
+        held_upload(&board, "Held 2", released.clone()),
+    );
+    let meanwhile = async {
+        until_reported(&board, "3 uploads in progress, no limit").await;
+        released.set(true);
+    };
+    let ((first, second, third), _) = future::join(held, meanwhile).await;
+    for res in [first, second, third].iter() {
+        assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+    }
+    assert_eq!(board.state.upload_slots.report(), "0 uploads in progress, no limit");
+}
@@ -0,0 +1,180 @@
+// Attachments on their way in, see upload.rs and intake.rs: what's kept
+// when the body breaks off, stalls or trickles in, what's left on disk,
+// and the size pages give an image they show.
+
+use actix_web::error::PayloadError;
+use actix_web::http::StatusCode;
+use actix_web::rt::time;
+use actix_web::test::TestRequest;
+use actix_web::web::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use image::{ImageBuffer, ImageOutputFormat, Rgb};
+use std::io::Cursor;
+use std::time::Duration;
+
+use super::{attrs, Form, TestBoard};
+use crate::upload;
+
+// Enough of a video to arrive in several chunks
+const CLIP_BYTES: usize = 64 * 1024;
+const CHUNK_BYTES: usize = 4 * 1024;
+
+// A body sent a chunk at a time with a pause before each
+fn trickle(
+    chunks: Vec<Result<Bytes, PayloadError>>,
+    pause: Duration,
+) -> impl Stream<Item = Result<Bytes, PayloadError>> {
+    stream::iter(chunks).then(move |chunk| async move {
+        time::sleep(pause).await;
+        chunk
+    })
+}
+
+fn pieces(body: &[u8], size: usize) -> Vec<Result<Bytes, PayloadError>> {
+    body.chunks(size).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect()
+}
+
+// A post with a video
+fn clip_post(title: &str) -> (TestRequest, Vec<u8>) {
+    let form = Form::new()
+        .text("title", title)
+        .text("message", "Watch this")
+        .file("file", "clip.webm", "video/webm", &vec![7; CLIP_BYTES]);
+    form.split("/submit")
+}
+
+// The same, sent in chunks until halfway through the video,
+// where the connection fails. Each chunk takes a moment, as over a network:
+// the multipart parser reports an error as soon as it reads one, ahead of
+// any fields still buffered before it.
+fn broken_upload(title: &str) -> (TestRequest, impl Stream<Item = Result<Bytes, PayloadError>>) {
+    let (req, body) = clip_post(title);
+    let cut = body.len() - CLIP_BYTES / 2;
+    let mut chunks = pieces(&body[..cut], CHUNK_BYTES);
+    chunks.push(Err(PayloadError::Incomplete(None)));
+    (req, trickle(chunks, Duration::from_millis(1)))
+}
+
+// Nothing in the database mentions the post
+fn assert_not_stored(board: &TestBoard, title: &str) {
+    assert!(board.db.iter().values().all(|bytes| !String::from_utf8_lossy(&bytes.unwrap()).contains(title)));
+}
+
+// Nothing stored and nothing half-written left behind
+fn assert_no_files(board: &TestBoard) {
+    assert!(upload::stored_files(&board.config.upload_dir).is_empty());
+    let temp = board.config.upload_dir.join(upload::TEMP_DIR);
+    assert_eq!(std::fs::read_dir(temp).unwrap().count(), 0);
+}
+
+#[actix_web::test]
+async fn a_broken_upload_rejects_the_post() {
+    let board = TestBoard::new();
+    assert!(board.config.reject_post_on_upload_failure);
+    let (req, chunks) = broken_upload("Broken");
+    let res = board.send_stream(req, chunks).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", res.body);
+    assert!(res.body.contains("The attachment failed to upload."), "{}", res.body);
+    assert_not_stored(&board, "Broken");
+    assert_no_files(&board);
+}
+
+#[actix_web::test]
+async fn a_broken_upload_can_leave_the_text() {
+    let board = TestBoard::with(|config| config.reject_post_on_upload_failure = false);
+    let (req, chunks) = broken_upload("Text only");
+    let res = board.send_stream(req, chunks).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER, "{}", res.body);
+
+    let post = board.find("Text only");
+    assert_eq!(post.message, "Watch this");
+    assert_eq!(post.file, None);
+    assert_eq!(post.upload_error.as_deref(), Some("The attachment failed to upload."));
+    assert_no_files(&board);
+
+    let html = board.get(&format!("/post/{}", post.id)).await.html();
+    assert_eq!(super::texts(&html, ".upload-error"), vec!["The attachment failed to upload."]);
+    assert!(super::select(&html, "video").is_empty());
+}
+
+#[actix_web::test]
+async fn a_body_in_too_many_pieces_is_refused() {
+    let board = TestBoard::with(|config| config.intake_max_chunks = 64);
+    let (req, body) = clip_post("Crumbs");
+    let res = board.send_stream(req, trickle(pieces(&body, 16), Duration::from_millis(1))).await;
+    assert_eq!(res.status, StatusCode::PAYLOAD_TOO_LARGE, "{}", res.body);
+    assert!(res.body.contains("The post was sent in too many pieces."), "{}", res.body);
+    assert_not_stored(&board, "Crumbs");
+    assert_no_files(&board);
+}
+
+#[actix_web::test]
+async fn a_body_that_stalls_is_timed_out() {
+    let board = TestBoard::with(|config| config.intake_max_secs = 1);
+    let (req, body) = clip_post("Stalled");
+    // Half the video, then nothing for longer than the whole body may take
+    let half = body.len() - CLIP_BYTES / 2;
+    let rest = Bytes::copy_from_slice(&body[half..]);
+    let chunks = trickle(pieces(&body[..half], CHUNK_BYTES), Duration::from_millis(1)).chain(stream::once(async move {
+        time::sleep(Duration::from_secs(5)).await;
+        Ok(rest)
+    }));
+    let res = board.send_stream(req, chunks).await;
+    assert_eq!(res.status, StatusCode::REQUEST_TIMEOUT, "{}", res.body);
+    assert!(res.body.contains("The post took too long to arrive."), "{}", res.body);
+    assert_not_stored(&board, "Stalled");
+    assert_no_files(&board);
+}
+
+#[actix_web::test]
+async fn a_body_below_the_minimum_rate_is_timed_out() {
+    let board = TestBoard::with(|config| {
+        config.intake_rate_window_secs = 1;
+        config.intake_min_bytes_per_sec = CLIP_BYTES as u64;
+    });
+    // About 20 KB a second, with a window's worth taking over three
+    let (req, body) = clip_post("Slow");
+    let res = board.send_stream(req, trickle(pieces(&body, 1024), Duration::from_millis(50))).await;
+    assert_eq!(res.status, StatusCode::REQUEST_TIMEOUT, "{}", res.body);
+    assert_not_stored(&board, "Slow");
+    assert_no_files(&board);
+}
+
+fn picture(width: u32, height: u32) -> Vec<u8> {
+    let image = ImageBuffer::from_pixel(width, height, Rgb([40u8, 80, 200]));
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageOutputFormat::Png).unwrap();
+    png.into_inner()
+}
+
+// The width and height of the image on a thread's page
+async fn shown_size(board: &TestBoard, id: &str) -> (String, String) {
+    let html = board.get(&format!("/post/{}", id)).await.html();
+    let selector = "img.post-file";
+    let (width, height) = (attrs(&html, selector, "width"), attrs(&html, selector, "height"));
+    assert_eq!((width.len(), height.len()), (1, 1), "{}", html.html());
+    (width[0].clone(), height[0].clone())
+}
+
+#[actix_web::test]
+async fn image_tags_carry_the_stored_size() {
+    let board = TestBoard::new();
+    for (title, width, height) in [("Wide", 400, 100), ("Small", 120, 60), ("Tall", 90, 900)] {
+        let png = picture(width, height);
+        let form = Form::new().text("title", title).text("message", "x").file("file", "pic.png", "image/png", &png);
+        assert_eq!(board.submit(form).await.status, StatusCode::SEE_OTHER);
+        let post = board.find(title);
+        assert_eq!((post.width, post.height), (Some(width), Some(height)));
+    }
+
+    // Scaled down into 200 pixels keeping the shape, never enlarged
+    assert_eq!(shown_size(&board, &board.find("Wide").id).await, ("200".into(), "50".into()));
+    assert_eq!(shown_size(&board, &board.find("Small").id).await, ("120".into(), "60".into()));
+    assert_eq!(shown_size(&board, &board.find("Tall").id).await, ("20".into(), "200".into()));
+
+    // An image from before sizes were stored keeps the square
+    let mut old = board.find("Wide");
+    (old.width, old.height) = (None, None);
+    board.db.insert(&old.id, serde_json::to_vec(&old).unwrap()).unwrap();
+    assert_eq!(shown_size(&board, &old.id).await, ("200".into(), "200".into()));
+}
@@ -0,0 +1,138 @@
+// Webhooks, see webhooks.rs: what a bridge listening on the other end
+// receives when threads and replies are posted, checked against a local
+// endpoint that records each request.
+
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+use super::{png, Form, TestBoard};
+use crate::upload;
+
+// One request as the endpoint saw it, header names lowercased
+struct Received {
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Received {
+    fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+// Listens on a free local port and answers each request with the next of
+// `statuses`, then 200 once they run out. Returns the URL to configure and
+// what came in, in order.
+fn endpoint(statuses: Vec<u16>) -> (String, Receiver<Received>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (sender, received) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut statuses = statuses.into_iter();
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut headers = HashMap::new();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                match line.trim_end().split_once(':') {
+                    Some((name, value)) => headers.insert(name.to_ascii_lowercase(), value.trim().to_string()),
+                    None => break,
+                };
+            }
+            let length = headers.get("content-length").map_or(0, |length| length.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let status = statuses.next().unwrap_or(200);
+            write!(stream, "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+            if sender.send(Received { headers, body }).is_err() {
+                break;
+            }
+        }
+    });
+    (url, received)
+}
+
+fn next(received: &Receiver<Received>) -> Received {
+    received.recv_timeout(Duration::from_secs(10)).expect("no webhook request")
+}
+
+fn signed(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    format!("sha256={}", upload::hex(&mac.finalize().into_bytes()))
+}
+
+#[actix_web::test]
+async fn new_threads_are_announced_signed_and_retried() {
+    let (url, received) = endpoint(vec![500]);
+    let board = TestBoard::with(|config| {
+        config.webhook_urls = vec![url];
+        config.webhook_secret = Some("shared secret".to_string());
+        config.webhook_attempts = 3;
+        config.public_url = "https://board.example".to_string();
+    });
+    let message = format!("{}\n>>1 and more", "a".repeat(300));
+    let form = Form::new().text("title", "Mirrored").text("message", &message).file("file", "pic.png", "image/png", &png(4));
+    board.submit(form).await;
+    let thread = board.find("Mirrored");
+
+    // The first attempt gets a 500, the retry the same body
+    let refused = next(&received);
+    let sent = next(&received);
+    assert_eq!(sent.body, refused.body);
+    assert_eq!(sent.headers["content-type"], "application/json");
+    assert_eq!(sent.headers["x-signature"], signed("shared secret", &sent.body));
+    assert_ne!(sent.headers["x-signature"], signed("another secret", &sent.body));
+
+    let payload = sent.json();
+    let mut fields: Vec<&str> = payload.as_object().unwrap().keys().map(String::as_str).collect();
+    fields.sort();
+    assert_eq!(fields, ["file_url", "id", "media_type", "message", "parent_id", "timestamp", "title"]);
+    assert_eq!(payload["id"], thread.id.as_str());
+    assert_eq!(payload["parent_id"], Value::Null);
+    assert_eq!(payload["title"], "Mirrored");
+    assert_eq!(payload["message"], format!("{}…", "a".repeat(199)));
+    let file_url = format!("https://board.example{}", board.config.upload_url(thread.file.as_deref().unwrap()));
+    assert_eq!(payload["file_url"], file_url.as_str());
+    assert_eq!(payload["media_type"], "image");
+    assert_eq!(payload["timestamp"], thread.timestamp);
+
+    // Replies aren't announced unless asked for
+    board.reply(&thread, "Reply", "Not mirrored").await;
+    board.thread("Second", "Short").await;
+    let second = next(&received).json();
+    assert_eq!((second["title"].as_str(), second["message"].as_str()), (Some("Second"), Some("Short")));
+    assert_eq!((second["file_url"].clone(), second["media_type"].clone()), (Value::Null, Value::Null));
+}
+
+#[actix_web::test]
+async fn replies_are_announced_when_asked_for() {
+    let (url, received) = endpoint(Vec::new());
+    let board = TestBoard::with(|config| {
+        config.webhook_urls = vec![url];
+        config.webhook_replies = true;
+    });
+    let thread = board.thread("Thread", "Start").await;
+    board.reply(&thread, "First", "One").await;
+    let reply = board.reply(&thread, "Reply", "Quoting >>1").await;
+
+    assert_eq!(next(&received).json()["id"], thread.id.as_str());
+    assert_eq!(next(&received).json()["title"], "First");
+    let quoting = next(&received);
+    let payload = quoting.json();
+    assert_eq!(payload["id"], reply.id.as_str());
+    assert_eq!(payload["parent_id"], thread.id.as_str());
+    assert_eq!(payload["message"], "Quoting »1");
+    // Without a secret nothing is signed
+    assert!(!quoting.headers.contains_key("x-signature"));
+}
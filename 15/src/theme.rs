@@ -0,0 +1,97 @@
+// Themes visitors can pick from the footer of the public pages, without
+// any script. style.css is the base every page links; the picked theme's
+// file in static/themes goes on top of it, served at /theme.css. That URL
+// is the same for every theme, so pages don't need to know which one is
+// picked and nothing rendered has to vary by it: the response varies by
+// cookie instead and is revalidated against the file's ETag on each page
+// view, so a switch shows on the next page.
+//
+// The pick is kept in the `theme` cookie. Anything that isn't a known theme
+// name counts as no pick, which is DEFAULT_THEME.
+
+use actix_files::NamedFile;
+use actix_web::cookie::time::Duration as CookieDuration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::header;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use std::str::FromStr;
+
+use crate::config::Config;
+use crate::redirect;
+
+const COOKIE: &str = "theme";
+const COOKIE_DAYS: i64 = 365;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Theme {
+    Light,
+    Dark,
+    Yotsuba,
+}
+
+impl Theme {
+    pub const ALL: [Theme; 3] = [Theme::Light, Theme::Dark, Theme::Yotsuba];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+            Theme::Yotsuba => "yotsuba",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Theme::Light => "Light",
+            Theme::Dark => "Dark",
+            Theme::Yotsuba => "Classic",
+        }
+    }
+}
+
+impl FromStr for Theme {
+    type Err = ();
+
+    fn from_str(raw: &str) -> Result<Theme, ()> {
+        Theme::ALL.iter().copied().find(|theme| theme.as_str() == raw).ok_or(())
+    }
+}
+
+fn picked(config: &Config, req: &HttpRequest) -> Theme {
+    req.cookie(COOKIE)
+        .and_then(|cookie| cookie.value().parse().ok())
+        .unwrap_or(config.default_theme)
+}
+
+pub async fn stylesheet(config: web::Data<Config>, req: HttpRequest) -> Result<HttpResponse, Error> {
+    let theme = picked(&config, &req);
+    let file = NamedFile::open_async(format!("./static/themes/{}.css", theme.as_str())).await?;
+    let mut response = file.use_last_modified(false).into_response(&req);
+    let headers = response.headers_mut();
+    headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("private, no-cache"));
+    headers.insert(header::VARY, header::HeaderValue::from_static("Cookie"));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+pub struct ThemeForm {
+    theme: String,
+    return_to: Option<String>,
+}
+
+// A name that isn't a theme clears the pick, back to the default
+pub async fn pick(config: web::Data<Config>, form: web::Form<ThemeForm>) -> HttpResponse {
+    let cookie = Cookie::build(COOKIE, form.theme.clone())
+        .path(config.index_url())
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::days(COOKIE_DAYS))
+        .finish();
+    let mut response = redirect::back(&config, form.return_to.as_deref(), config.index_url());
+    if form.theme.parse::<Theme>().is_ok() {
+        response.add_cookie(&cookie).unwrap();
+    } else {
+        response.add_removal_cookie(&cookie).unwrap();
+    }
+    response
+}
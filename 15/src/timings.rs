@@ -0,0 +1,177 @@
+// How long storage reads and writes and template renders take, to tell
+// slow sled scans from slow HTML. With TIMINGS=true each timed call adds a
+// sample to a ring buffer; /admin/performance shows the median and 95th
+// percentile per operation over the last WINDOW_SECS. Off, which is the
+// default, a timed call costs one relaxed atomic load.
+//
+// Storage has no layer of its own, so the calls timed are the helpers the
+// pages go through: loading a post, the index scans, the transaction that
+// stores a post, and full scans over every post. Renders are timed in
+// render::to_string, by template.
+
+use actix_web::{web, HttpResponse};
+use askama::Template;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::admin::Admin;
+use crate::config::Config;
+use crate::render;
+use crate::upload_slots::UploadSlots;
+
+const WINDOW_SECS: u64 = 5 * 60;
+// Oldest samples go first past this, however recent
+const MAX_SAMPLES: usize = 50_000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SAMPLES: Mutex<VecDeque<Sample>> = Mutex::new(VecDeque::new());
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Op {
+    Get,
+    Scan,
+    Insert,
+    Transaction,
+    Render,
+}
+
+impl Op {
+    fn as_str(self) -> &'static str {
+        match self {
+            Op::Get => "get",
+            Op::Scan => "scan",
+            Op::Insert => "insert",
+            Op::Transaction => "transaction",
+            Op::Render => "render",
+        }
+    }
+}
+
+struct Sample {
+    at: Instant,
+    op: Op,
+    // What was timed, e.g. "thread_replies" or "PostViewTemplate"
+    name: &'static str,
+    took: Duration,
+}
+
+pub fn enable(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn start() -> Option<Instant> {
+    ENABLED.load(Ordering::Relaxed).then(Instant::now)
+}
+
+// Takes what start() returned, so nothing is kept while timings are off
+pub fn record(op: Op, name: &'static str, started: Option<Instant>) {
+    let started = match started {
+        Some(started) => started,
+        None => return,
+    };
+    let now = Instant::now();
+    let mut samples = SAMPLES.lock().unwrap();
+    while samples.len() >= MAX_SAMPLES || samples.front().is_some_and(|oldest| now.duration_since(oldest.at).as_secs() > WINDOW_SECS) {
+        samples.pop_front();
+    }
+    samples.push_back(Sample {
+        at: now,
+        op,
+        name,
+        took: now - started,
+    });
+}
+
+pub fn time<T>(op: Op, name: &'static str, f: impl FnOnce() -> T) -> T {
+    let started = start();
+    let result = f();
+    record(op, name, started);
+    result
+}
+
+pub struct Row {
+    pub op: &'static str,
+    pub name: &'static str,
+    pub count: usize,
+    // Milliseconds
+    pub p50: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+// The nearest-rank percentile of sorted durations
+fn percentile(sorted: &[Duration], percent: usize) -> f64 {
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    sorted[rank - 1].as_secs_f64() * 1000.0
+}
+
+// Per operation over the last WINDOW_SECS, slowest median first
+fn summary() -> Vec<Row> {
+    let now = Instant::now();
+    let mut grouped: BTreeMap<(Op, &'static str), Vec<Duration>> = BTreeMap::new();
+    for sample in SAMPLES.lock().unwrap().iter() {
+        if now.duration_since(sample.at).as_secs() <= WINDOW_SECS {
+            grouped.entry((sample.op, sample.name)).or_default().push(sample.took);
+        }
+    }
+    let mut rows: Vec<Row> = grouped
+        .into_iter()
+        .map(|((op, name), mut took)| {
+            took.sort();
+            Row {
+                op: op.as_str(),
+                name,
+                count: took.len(),
+                p50: percentile(&took, 50),
+                p95: percentile(&took, 95),
+                max: percentile(&took, 100),
+            }
+        })
+        .collect();
+    rows.sort_by(|a, b| b.p50.total_cmp(&a.p50));
+    rows
+}
+
+#[derive(Template)]
+#[template(path = "admin_performance.html")]
+struct PerformanceTemplate<'a> {
+    config: &'a Config,
+    enabled: bool,
+    window_mins: u64,
+    rows: Vec<Row>,
+    // See UploadSlots::report
+    uploads: String,
+}
+
+pub async fn performance(config: web::Data<Config>, upload_slots: web::Data<UploadSlots>, _admin: Admin) -> HttpResponse {
+    let template = PerformanceTemplate {
+        config: &config,
+        uploads: upload_slots.report(),
+        enabled: ENABLED.load(Ordering::Relaxed),
+        window_mins: WINDOW_SECS / 60,
+        rows: summary(),
+    };
+    render::respond(HttpResponse::Ok(), &template, "the performance page")
+}
+
+pub fn check_templates(config: &Config) -> Result<(), String> {
+    for enabled in [true, false] {
+        render::check(&PerformanceTemplate {
+            config,
+            enabled,
+            window_mins: WINDOW_SECS / 60,
+            uploads: "3 of 8 upload slots in use, 12 turned away since the server started".to_string(),
+            rows: vec![Row {
+                op: Op::Scan.as_str(),
+                name: "thread_replies",
+                count: 3,
+                p50: 0.25,
+                p95: 1.5,
+                max: 2.0,
+            }],
+        })?;
+    }
+    Ok(())
+}
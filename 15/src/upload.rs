@@ -0,0 +1,850 @@
+// Upload handling as a pipeline. The multipart field is streamed to a
+// temporary file (enforcing the type allow-list and size cap, and hashing
+// as it goes), then each configured ProcessingStage runs over the temporary
+// file in order, and only then is the file moved to its final name. A post
+// can never point at a truncated or half-processed file.
+//
+// Temporary files live in TEMP_DIR inside the upload directory, so the
+// final move is a rename on the same filesystem even when the upload
+// directory is a mounted volume. If the rename fails anyway the file is
+// copied, synced and the original removed. Temporary files older than
+// TEMP_MAX_AGE are left over from crashes and get cleaned up.
+//
+// HEIC/HEIF photos, as iPhones take them, are turned into JPEGs by the
+// HEIF_CONVERTER command before the stages run, and only the JPEG is kept.
+// The post records what it was converted from.
+//
+// Files are stored under "YYYY/MM/" for the month they were uploaded (UTC),
+// and post.file holds that relative path. Files from before this layout sit
+// directly in the upload directory under just their name; they are still
+// served, and `migrate-uploads` moves them, see relocate.rs.
+
+use actix_multipart::Field;
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sled::Db;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::format;
+use crate::intake::{Intake, IntakeError};
+use crate::moderation;
+use crate::rejection::{ErrorCode, FieldError};
+use crate::schema;
+use crate::validation;
+use crate::{load_post, Post};
+
+pub const TEMP_DIR: &str = ".tmp";
+pub const TEMP_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+pub const HEIF_EXTENSIONS: [&str; 2] = ["heic", "heif"];
+const CONVERT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Image,
+    Video,
+    Audio,
+    Other,
+}
+
+impl MediaKind {
+    pub fn from_extension(extension: &str) -> MediaKind {
+        match extension {
+            "jpg" | "jpeg" | "png" | "gif" | "webp" => MediaKind::Image,
+            "mp4" | "webm" => MediaKind::Video,
+            "mp3" => MediaKind::Audio,
+            _ => MediaKind::Other,
+        }
+    }
+
+    pub fn from_file_name(file_name: &str) -> MediaKind {
+        MediaKind::from_extension(&extension_of(file_name))
+    }
+}
+
+// What is known about an upload so far. Stages may fill in more.
+pub struct UploadMeta {
+    pub client_name: String,
+    pub extension: String,
+    pub kind: MediaKind,
+    pub size: u64,
+    pub sha256: String,
+    // Images only, set by MeasureImage
+    pub dimensions: Option<Dimensions>,
+    // The extension the poster uploaded, when the file was converted
+    pub converted_from: Option<String>,
+}
+
+// The result of a successful upload, as recorded on the post.
+pub struct StoredFile {
+    pub file_name: String,
+    pub original_name: String,
+    pub size: u64,
+    pub sha256: String,
+    pub kind: MediaKind,
+    pub dimensions: Option<Dimensions>,
+    pub converted_from: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Dimensions {
+    // Scaled down to fit a `max` pixel square, keeping the shape. Never
+    // enlarged, and never below one pixel on a side.
+    pub fn fit_within(self, max: u32) -> Dimensions {
+        let longest = self.width.max(self.height);
+        if longest <= max {
+            return self;
+        }
+        let scale = |side: u32| {
+            let scaled = (u64::from(side) * u64::from(max) + u64::from(longest) / 2) / u64::from(longest);
+            scaled.max(1) as u32
+        };
+        Dimensions {
+            width: scale(self.width),
+            height: scale(self.height),
+        }
+    }
+}
+
+pub enum UploadError {
+    // The file isn't acceptable; the post is always rejected
+    Rejected(FieldError),
+    // Something went wrong storing it; REJECT_POST_ON_UPLOAD_FAILURE decides
+    Failed(String),
+    // The body broke an intake limit; the whole request is refused
+    Aborted(IntakeError),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OnFailure {
+    RejectPost,
+    // Log and carry on with the file as it is
+    Degrade,
+}
+
+pub trait ProcessingStage: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn applies_to(&self, kind: MediaKind) -> bool;
+    fn on_failure(&self) -> OnFailure;
+    // Runs on a blocking thread; `path` is the temporary file.
+    fn process(&self, path: &Path, meta: &mut UploadMeta) -> Result<(), String>;
+}
+
+pub struct UploadPipeline {
+    db: Db,
+    upload_dir: PathBuf,
+    allowed_extensions: Vec<String>,
+    max_bytes: u64,
+    // max_bytes as the error message shows it
+    max_size: String,
+    heif_converter: Option<Vec<String>>,
+    stages: Vec<Box<dyn ProcessingStage>>,
+}
+
+impl UploadPipeline {
+    pub fn from_config(config: &Config, db: &Db) -> UploadPipeline {
+        let mut stages: Vec<Box<dyn ProcessingStage>> = Vec::new();
+        if let Some(on_failure) = config.sniff_uploads {
+            stages.push(Box::new(SniffImage { on_failure }));
+        }
+        stages.push(Box::new(MeasureImage));
+        UploadPipeline {
+            db: db.clone(),
+            upload_dir: config.upload_dir.clone(),
+            allowed_extensions: config.allowed_extensions.clone(),
+            max_bytes: config.max_upload_bytes,
+            max_size: config.human_size(config.max_upload_bytes),
+            heif_converter: config.heif_converter.clone(),
+            stages,
+        }
+    }
+
+    pub async fn run(self, field: &mut Field, client_name: &str, intake: &mut Intake) -> Result<Option<StoredFile>, UploadError> {
+        let extension = extension_of(client_name);
+        if extension.is_empty() {
+            return Err(UploadError::Rejected(FieldError::new(
+                "file",
+                ErrorCode::UnsupportedMedia,
+                "Attachments need a file extension.",
+            )));
+        }
+        let convert = HEIF_EXTENSIONS.contains(&extension.as_str());
+        if convert && self.heif_converter.is_none() {
+            return Err(UploadError::Rejected(FieldError::new(
+                "file",
+                ErrorCode::UnsupportedMedia,
+                "HEIC photos can't be posted here. Export the photo as JPEG (on an iPhone, share it to Files or set Camera > Formats to Most Compatible) and try again.",
+            )));
+        }
+        if !convert && !self.allowed_extensions.contains(&extension) {
+            return Err(UploadError::Rejected(FieldError::new(
+                "file",
+                ErrorCode::UnsupportedMedia,
+                format!("Files of type .{} aren't allowed.", extension),
+            )));
+        }
+
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let stored_extension = if convert { "jpg" } else { extension.as_str() };
+        let base_name = format!("{}.{}", Uuid::new_v4(), stored_extension);
+        let file_name = format!("{}/{}", dated_dir(now), base_name);
+        let part_path = self.upload_dir.join(TEMP_DIR).join(format!("{}.part", base_name));
+        let final_path = path_in(&self.upload_dir, &file_name);
+
+        let result = match self.accumulate(field, &part_path, intake).await {
+            // A part with nothing in it is no file, as when none is chosen
+            Ok((0, _)) => {
+                let _ = std::fs::remove_file(&part_path);
+                return Ok(None);
+            }
+            Ok((size, sha256)) => {
+                let meta = UploadMeta {
+                    client_name: original_name(client_name),
+                    kind: MediaKind::from_extension(stored_extension),
+                    extension,
+                    size,
+                    sha256,
+                    dimensions: None,
+                    converted_from: None,
+                };
+                let from = part_path.clone();
+                web::block(move || self.finish(&from, &final_path, meta))
+                    .await
+                    .unwrap_or_else(|e| Err(UploadError::Failed(e.to_string())))
+            }
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(meta) => Ok(Some(StoredFile {
+                file_name,
+                original_name: meta.client_name,
+                size: meta.size,
+                sha256: meta.sha256,
+                kind: meta.kind,
+                dimensions: meta.dimensions,
+                converted_from: meta.converted_from,
+            })),
+            Err(e) => {
+                let _ = std::fs::remove_file(&part_path);
+                Err(match e {
+                    UploadError::Failed(reason) => {
+                        eprintln!("upload of {} failed: {}", file_name, reason);
+                        UploadError::Failed("The attachment failed to upload.".to_string())
+                    }
+                    rejected => rejected,
+                })
+            }
+        }
+    }
+
+    // Streams the field into `part_path`, returning its size and hash.
+    async fn accumulate(&self, field: &mut Field, part_path: &Path, intake: &mut Intake) -> Result<(u64, String), UploadError> {
+        let failed = |e: String| UploadError::Failed(e);
+        let path = part_path.to_path_buf();
+        let mut f = web::block(move || std::fs::File::create(path))
+            .await
+            .map_err(|e| failed(e.to_string()))?
+            .map_err(|e| failed(e.to_string()))?;
+
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        while let Some(chunk) = intake.next_chunk(field).await.map_err(UploadError::Aborted)? {
+            let data = chunk.map_err(failed)?;
+            size += data.len() as u64;
+            if size > self.max_bytes {
+                return Err(UploadError::Rejected(
+                    FieldError::new(
+                        "file",
+                        ErrorCode::FileTooLarge,
+                        format!("The attachment exceeds the {} limit.", self.max_size),
+                    )
+                    .max(self.max_bytes),
+                ));
+            }
+            hasher.update(&data);
+            f = web::block(move || f.write_all(&data).map(|_| f))
+                .await
+                .map_err(|e| failed(e.to_string()))?
+                .map_err(|e| failed(e.to_string()))?;
+        }
+
+        web::block(move || f.sync_all())
+            .await
+            .map_err(|e| failed(e.to_string()))?
+            .map_err(|e| failed(e.to_string()))?;
+
+        Ok((size, hex(&hasher.finalize())))
+    }
+
+    // Converts HEIC photos, checks the hash against taken-down files, runs
+    // the stages and moves the file into place.
+    fn finish(&self, part_path: &Path, final_path: &Path, mut meta: UploadMeta) -> Result<UploadMeta, UploadError> {
+        if let Some(command) = self.heif_converter.as_deref().filter(|_| HEIF_EXTENSIONS.contains(&meta.extension.as_str())) {
+            self.convert_to_jpeg(command, part_path, &mut meta)?;
+        }
+        if moderation::is_blocked_hash(&self.db, &meta.sha256) {
+            return Err(UploadError::Rejected(FieldError::new("file", ErrorCode::Blocked, "This file can't be posted.")));
+        }
+        let kind = meta.kind;
+        for stage in self.stages.iter().filter(|stage| stage.applies_to(kind)) {
+            if let Err(reason) = stage.process(part_path, &mut meta) {
+                match stage.on_failure() {
+                    OnFailure::RejectPost => {
+                        return Err(UploadError::Rejected(FieldError::new("file", ErrorCode::UnsupportedMedia, reason)))
+                    }
+                    OnFailure::Degrade => eprintln!("upload stage {} skipped: {}", stage.name(), reason),
+                }
+            }
+        }
+        if let Some(dir) = final_path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| UploadError::Failed(e.to_string()))?;
+        }
+        move_into_place(part_path, final_path).map_err(|e| UploadError::Failed(e.to_string()))?;
+        Ok(meta)
+    }
+
+    // Replaces the temporary file with the converter's JPEG, which is held
+    // to the same size limit as uploads
+    fn convert_to_jpeg(&self, command: &[String], part_path: &Path, meta: &mut UploadMeta) -> Result<(), UploadError> {
+        let output = part_path.with_extension("converted.jpg");
+        let converted = run_converter(command, part_path, &output).and_then(|()| {
+            let size = std::fs::metadata(&output).map_err(|e| e.to_string())?.len();
+            let sha256 = hash_file(&output).map_err(|e| e.to_string())?;
+            Ok((size, sha256))
+        });
+        let (size, sha256) = match converted {
+            Ok(converted) => converted,
+            Err(reason) => {
+                let _ = std::fs::remove_file(&output);
+                eprintln!("converting {} failed: {}", meta.client_name, reason);
+                return Err(UploadError::Rejected(FieldError::new(
+                    "file",
+                    ErrorCode::UnsupportedMedia,
+                    "This HEIC photo couldn't be converted. Export it as JPEG and try again.",
+                )));
+            }
+        };
+        if size > self.max_bytes {
+            let _ = std::fs::remove_file(&output);
+            return Err(UploadError::Rejected(
+                FieldError::new(
+                    "file",
+                    ErrorCode::FileTooLarge,
+                    format!("The photo exceeds the {} limit once converted to JPEG.", self.max_size),
+                )
+                .max(self.max_bytes),
+            ));
+        }
+        std::fs::rename(&output, part_path).map_err(|e| UploadError::Failed(e.to_string()))?;
+        meta.converted_from = Some(std::mem::replace(&mut meta.extension, "jpg".to_string()));
+        meta.size = size;
+        meta.sha256 = sha256;
+        Ok(())
+    }
+}
+
+// Runs on a blocking thread, and is killed if it takes longer than
+// CONVERT_TIMEOUT
+fn run_converter(command: &[String], input: &Path, output: &Path) -> Result<(), String> {
+    let mut child = std::process::Command::new(&command[0])
+        .args(&command[1..])
+        .arg(input)
+        .arg(output)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .map_err(|e| format!("{}: {}", command[0], e))?;
+    let started = std::time::Instant::now();
+    loop {
+        match child.try_wait().map_err(|e| e.to_string())? {
+            Some(status) if status.success() => return Ok(()),
+            Some(status) => return Err(format!("{} exited with {}", command[0], status)),
+            None if started.elapsed() >= CONVERT_TIMEOUT => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("{} took longer than {:?}", command[0], CONVERT_TIMEOUT));
+            }
+            None => std::thread::sleep(Duration::from_millis(20)),
+        }
+    }
+}
+
+fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+// A rename, or for paths on different filesystems (EXDEV) a copy that is
+// synced before the original is removed.
+pub fn move_into_place(from: &Path, to: &Path) -> std::io::Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    copy_into_place(from, to)
+}
+
+// The copy for move_into_place. A copy that fails partway is removed and
+// the original kept.
+fn copy_into_place(from: &Path, to: &Path) -> std::io::Result<()> {
+    let copied = std::fs::copy(from, to).and_then(|_| std::fs::File::open(to)?.sync_all());
+    if let Err(e) = copied {
+        let _ = std::fs::remove_file(to);
+        return Err(e);
+    }
+    std::fs::remove_file(from)
+}
+
+// Where the file stored as `file` is under `upload_dir`. Stored names are
+// keys rather than paths: "YYYY/MM/name" or a bare name, always with
+// forward slashes as they appear in URLs. Their parts are joined here
+// with the platform's separator.
+pub fn path_in(upload_dir: &Path, file: &str) -> PathBuf {
+    file.split('/').fold(upload_dir.to_path_buf(), |path, part| path.join(part))
+}
+
+// A configured directory without a trailing separator or "." parts, so
+// "uploads/" and "uploads/./" are both "uploads"
+pub fn clean_dir(dir: &Path) -> PathBuf {
+    let cleaned: PathBuf = dir.components().collect();
+    if cleaned.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        cleaned
+    }
+}
+
+// "YYYY/MM" for a time in seconds since the epoch, in UTC
+pub fn dated_dir(timestamp: u64) -> String {
+    let (year, month, _) = format::civil_date(timestamp);
+    format!("{:04}/{:02}", year, month)
+}
+
+// Whether a path under the upload directory is one a stored file could
+// have: a bare name from the flat layout or "YYYY/MM/name", with no empty,
+// hidden or parent parts that could reach outside it or into TEMP_DIR.
+pub fn is_stored_path(file: &str) -> bool {
+    let parts: Vec<&str> = file.split('/').collect();
+    let shaped = match parts.as_slice() {
+        [_] => true,
+        [year, month, _] => {
+            year.len() == 4 && month.len() == 2 && year.chars().chain(month.chars()).all(|c| c.is_ascii_digit())
+        }
+        _ => false,
+    };
+    shaped && parts.iter().all(|part| !part.is_empty() && !part.starts_with('.') && !part.contains('\\'))
+}
+
+// Every file in the upload directory, as paths relative to it: the flat
+// files at the top and those in the dated directories. TEMP_DIR and other
+// hidden entries are skipped.
+pub fn stored_files(upload_dir: &Path) -> Vec<String> {
+    fn walk(dir: &Path, prefix: &str, depth: usize, found: &mut Vec<String>) {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+            let relative = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+            let path = entry.path();
+            if path.is_file() {
+                found.push(relative);
+            } else if path.is_dir() && depth < 2 {
+                walk(&path, &relative, depth + 1, found);
+            }
+        }
+    }
+    let mut found = Vec::new();
+    walk(upload_dir, "", 0, &mut found);
+    found
+}
+
+// Files in the temporary directory that are too old to belong to an upload
+// still in progress.
+pub fn stale_temp_files(upload_dir: &Path) -> Vec<PathBuf> {
+    let entries = match std::fs::read_dir(upload_dir.join(TEMP_DIR)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let now = SystemTime::now();
+    entries
+        .flatten()
+        .filter(|entry| {
+            let modified = entry.metadata().and_then(|meta| meta.modified());
+            let age = modified.ok().and_then(|modified| now.duration_since(modified).ok());
+            entry.path().is_file() && age.map(|age| age > TEMP_MAX_AGE).unwrap_or(false)
+        })
+        .map(|entry| entry.path())
+        .collect()
+}
+
+// Creates the temporary directory if needed and removes stale files from
+// it. Returns how many were removed.
+pub fn clean_temp(upload_dir: &Path) -> usize {
+    if let Err(e) = std::fs::create_dir_all(upload_dir.join(TEMP_DIR)) {
+        eprintln!("can't create upload temp directory: {}", e);
+        return 0;
+    }
+    stale_temp_files(upload_dir)
+        .into_iter()
+        .filter(|path| std::fs::remove_file(path).is_ok())
+        .count()
+}
+
+// Lowercased, and only kept if it's plain alphanumerics.
+fn extension_of(client_name: &str) -> String {
+    client_name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+        .filter(|ext| !ext.is_empty() && ext.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or_default()
+}
+
+// Reads an image's pixel size from its header, so pages can reserve the
+// space before it loads. Images it can't read are kept without one.
+struct MeasureImage;
+
+impl ProcessingStage for MeasureImage {
+    fn name(&self) -> &'static str {
+        "measure"
+    }
+
+    fn applies_to(&self, kind: MediaKind) -> bool {
+        kind == MediaKind::Image
+    }
+
+    fn on_failure(&self) -> OnFailure {
+        OnFailure::Degrade
+    }
+
+    fn process(&self, path: &Path, meta: &mut UploadMeta) -> Result<(), String> {
+        // The temporary file's name ends in .part, so the format comes from
+        // the contents
+        let (width, height) = image::io::Reader::open(path)
+            .and_then(|reader| reader.with_guessed_format())
+            .map_err(|e| e.to_string())?
+            .into_dimensions()
+            .map_err(|e| e.to_string())?;
+        meta.dimensions = Some(Dimensions { width, height });
+        Ok(())
+    }
+}
+
+// Checks an image's leading bytes match its extension, so an image tag
+// never gets pointed at something else.
+struct SniffImage {
+    on_failure: OnFailure,
+}
+
+impl ProcessingStage for SniffImage {
+    fn name(&self) -> &'static str {
+        "sniff"
+    }
+
+    fn applies_to(&self, kind: MediaKind) -> bool {
+        kind == MediaKind::Image
+    }
+
+    fn on_failure(&self) -> OnFailure {
+        self.on_failure
+    }
+
+    fn process(&self, path: &Path, meta: &mut UploadMeta) -> Result<(), String> {
+        let mut head = [0u8; 12];
+        let read = std::fs::File::open(path)
+            .and_then(|mut f| f.read(&mut head))
+            .map_err(|e| e.to_string())?;
+        let head = &head[..read];
+        let matches = match meta.extension.as_str() {
+            "jpg" | "jpeg" => head.starts_with(&[0xff, 0xd8, 0xff]),
+            "png" => head.starts_with(b"\x89PNG\r\n\x1a\n"),
+            "gif" => head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a"),
+            "webp" => head.len() == 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WEBP",
+            _ => true,
+        };
+        if matches {
+            Ok(())
+        } else {
+            Err(format!("The attachment isn't a valid .{} image.", meta.extension))
+        }
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// The name the poster's browser sent, without any directory part some
+// clients include, or characters that could disguise its extension.
+pub fn original_name(client_name: &str) -> String {
+    validation::strip_format_chars(client_name.rsplit(['/', '\\']).next().unwrap_or(client_name))
+}
+
+// Stored file name to owning post, so the uploads route can find the
+// original name to serve a file under.
+pub fn index(db: &Db, post: &Post) {
+    if let Some(file) = &post.file {
+        db.open_tree("uploads").unwrap().insert(file, post.id.as_bytes()).unwrap();
+    }
+}
+
+pub fn forget(db: &Db, post: &Post) -> bool {
+    match &post.file {
+        Some(file) => db.open_tree("uploads").unwrap().remove(file).unwrap().is_some(),
+        None => false,
+    }
+}
+
+pub fn owner(db: &Db, file: &str) -> Option<Post> {
+    let id = db.open_tree("uploads").unwrap().get(file).unwrap()?;
+    load_post(db, &String::from_utf8_lossy(&id))
+}
+
+// Posts from before media_kind was stored get it written once, on first
+// start. Until then Post::media_kind works it out from the file name.
+pub fn backfill_media_kinds(db: &Db) {
+    let meta = db.open_tree("meta").unwrap();
+    if meta.contains_key("media_kinds_backfilled").unwrap() {
+        return;
+    }
+    let mut filled = 0;
+    for entry in db.iter() {
+        let (key, raw) = entry.unwrap();
+        let post = match Post::upgrade(&raw) {
+            Ok(post) => post,
+            Err(_) => continue,
+        };
+        if post.media_kind.is_some() {
+            continue;
+        }
+        let kind = match post.media_kind() {
+            Some(kind) => kind,
+            None => continue,
+        };
+        if let Ok(updated) = schema::merge_fields(&raw, |fields| {
+            fields.insert("media_kind".to_string(), serde_json::to_value(kind).unwrap());
+        }) {
+            db.insert(key, updated).unwrap();
+            filled += 1;
+        }
+    }
+    if filled > 0 {
+        println!("stored the media kind of {} existing posts", filled);
+    }
+    meta.insert("media_kinds_backfilled", &[]).unwrap();
+    db.flush().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, ImageOutputFormat, Rgb};
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    // An image of `width` x `height` in `format`
+    fn fixture(width: u32, height: u32, format: ImageOutputFormat) -> Vec<u8> {
+        let image = ImageBuffer::from_pixel(width, height, Rgb([10u8, 120, 60]));
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, format).unwrap();
+        bytes.into_inner()
+    }
+
+    fn meta(extension: &str, bytes: &[u8]) -> UploadMeta {
+        UploadMeta {
+            client_name: format!("fixture.{}", extension),
+            extension: extension.to_string(),
+            kind: MediaKind::from_extension(extension),
+            size: bytes.len() as u64,
+            sha256: hex(&Sha256::digest(bytes)),
+            dimensions: None,
+            converted_from: None,
+        }
+    }
+
+    // Writes `bytes` as a temporary upload and runs `stage` on it
+    fn run(stage: &dyn ProcessingStage, extension: &str, bytes: &[u8]) -> (Result<(), String>, UploadMeta) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("fixture.part");
+        std::fs::write(&path, bytes).unwrap();
+        let mut meta = meta(extension, bytes);
+        (stage.process(&path, &mut meta), meta)
+    }
+
+    #[test]
+    fn sniffing_passes_images_whose_bytes_match_their_extension() {
+        let stage = SniffImage {
+            on_failure: OnFailure::RejectPost,
+        };
+        let fixtures = [
+            ("png", fixture(4, 4, ImageOutputFormat::Png)),
+            ("jpg", fixture(4, 4, ImageOutputFormat::Jpeg(80))),
+            ("jpeg", fixture(4, 4, ImageOutputFormat::Jpeg(80))),
+            ("gif", fixture(4, 4, ImageOutputFormat::Gif)),
+            ("webp", fixture(4, 4, ImageOutputFormat::WebP)),
+        ];
+        for (extension, bytes) in &fixtures {
+            assert_eq!(run(&stage, extension, bytes).0, Ok(()), "{}", extension);
+        }
+    }
+
+    #[test]
+    fn sniffing_refuses_images_under_another_extension() {
+        let stage = SniffImage {
+            on_failure: OnFailure::RejectPost,
+        };
+        let png = fixture(4, 4, ImageOutputFormat::Png);
+        assert_eq!(run(&stage, "jpg", &png).0, Err("The attachment isn't a valid .jpg image.".to_string()));
+        assert!(run(&stage, "gif", b"<html>").0.is_err());
+        // Too short to hold the WebP header
+        assert!(run(&stage, "webp", b"RIFF").0.is_err());
+        assert!(run(&stage, "png", b"").0.is_err());
+    }
+
+    #[test]
+    fn sniffing_is_for_images_only_and_fails_as_configured() {
+        let stage = SniffImage {
+            on_failure: OnFailure::Degrade,
+        };
+        assert!(stage.applies_to(MediaKind::Image));
+        assert!(!stage.applies_to(MediaKind::Video));
+        assert!(!stage.applies_to(MediaKind::Other));
+        assert!(stage.on_failure() == OnFailure::Degrade);
+    }
+
+    #[test]
+    fn measuring_reads_the_size_from_the_contents() {
+        let (result, meta) = run(&MeasureImage, "png", &fixture(30, 20, ImageOutputFormat::Png));
+        assert_eq!(result, Ok(()));
+        assert_eq!(meta.dimensions, Some(Dimensions { width: 30, height: 20 }));
+
+        // Even when the extension is wrong, as the temporary name has none
+        let (_, meta) = run(&MeasureImage, "gif", &fixture(7, 9, ImageOutputFormat::Jpeg(80)));
+        assert_eq!(meta.dimensions, Some(Dimensions { width: 7, height: 9 }));
+    }
+
+    #[test]
+    fn measuring_leaves_unreadable_images_without_a_size() {
+        let (result, meta) = run(&MeasureImage, "png", b"not an image");
+        assert!(result.is_err());
+        assert_eq!(meta.dimensions, None);
+        assert!(MeasureImage.on_failure() == OnFailure::Degrade);
+        assert!(!MeasureImage.applies_to(MediaKind::Audio));
+    }
+
+    // A pipeline over a temporary database and upload directory
+    fn pipeline(dir: &TempDir, sniff: Option<OnFailure>) -> (UploadPipeline, Db) {
+        let mut config = Config::from_env();
+        config.upload_dir = dir.path().to_path_buf();
+        config.sniff_uploads = sniff;
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        (UploadPipeline::from_config(&config, &db), db)
+    }
+
+    // Runs the stages on `bytes` and moves the file into place as "stored.{extension}"
+    fn finish(pipeline: &UploadPipeline, dir: &TempDir, extension: &str, bytes: &[u8]) -> Result<UploadMeta, UploadError> {
+        let part = dir.path().join("upload.part");
+        std::fs::write(&part, bytes).unwrap();
+        pipeline.finish(&part, &dir.path().join(format!("stored.{}", extension)), meta(extension, bytes))
+    }
+
+    #[test]
+    fn stages_follow_the_config() {
+        let dir = TempDir::new().unwrap();
+        let names = |sniff| pipeline(&dir, sniff).0.stages.iter().map(|stage| stage.name()).collect::<Vec<_>>();
+        assert_eq!(names(None), vec!["measure"]);
+        assert_eq!(names(Some(OnFailure::RejectPost)), vec!["sniff", "measure"]);
+    }
+
+    #[test]
+    fn a_rejecting_stage_refuses_the_file_and_a_degrading_one_keeps_it() {
+        let png = fixture(12, 6, ImageOutputFormat::Png);
+
+        let dir = TempDir::new().unwrap();
+        let (strict, _db) = pipeline(&dir, Some(OnFailure::RejectPost));
+        assert!(matches!(finish(&strict, &dir, "jpg", &png), Err(UploadError::Rejected(_))));
+        assert!(!dir.path().join("stored.jpg").exists());
+
+        let dir = TempDir::new().unwrap();
+        let (lenient, _db) = pipeline(&dir, Some(OnFailure::Degrade));
+        let meta = finish(&lenient, &dir, "jpg", &png).ok().unwrap();
+        // Later stages still ran
+        assert_eq!(meta.dimensions, Some(Dimensions { width: 12, height: 6 }));
+        assert_eq!(std::fs::read(dir.path().join("stored.jpg")).unwrap(), png);
+        assert!(!dir.path().join("upload.part").exists());
+    }
+
+    #[test]
+    fn stages_skip_other_kinds_of_file() {
+        let dir = TempDir::new().unwrap();
+        let (pipeline, _db) = pipeline(&dir, Some(OnFailure::RejectPost));
+        let meta = finish(&pipeline, &dir, "webm", b"not checked").ok().unwrap();
+        assert_eq!(meta.dimensions, None);
+        assert!(dir.path().join("stored.webm").exists());
+    }
+
+    #[test]
+    fn taken_down_files_are_refused_before_any_stage() {
+        let dir = TempDir::new().unwrap();
+        let (pipeline, db) = pipeline(&dir, None);
+        let png = fixture(4, 4, ImageOutputFormat::Png);
+        moderation::block_hash(&db, &hex(&Sha256::digest(&png)));
+        assert!(matches!(finish(&pipeline, &dir, "png", &png), Err(UploadError::Rejected(_))));
+        assert!(!dir.path().join("stored.png").exists());
+    }
+
+    #[test]
+    fn the_copy_fallback_moves_a_file_between_directories() {
+        let (from_dir, to_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (from, to) = (from_dir.path().join("upload.part"), to_dir.path().join("stored.png"));
+        std::fs::write(&from, b"picture").unwrap();
+        copy_into_place(&from, &to).unwrap();
+        assert_eq!(std::fs::read(&to).unwrap(), b"picture");
+        assert!(!from.exists());
+
+        // With nowhere to copy to, the original stays and nothing is left behind
+        std::fs::write(&from, b"picture").unwrap();
+        let missing = to_dir.path().join("missing").join("stored.png");
+        assert!(copy_into_place(&from, &missing).is_err());
+        assert!(from.exists());
+        assert!(!missing.exists());
+
+        // Whichever way it goes, a move between the two ends up in the same place
+        let moved = to_dir.path().join("moved.png");
+        move_into_place(&from, &moved).unwrap();
+        assert_eq!(std::fs::read(&moved).unwrap(), b"picture");
+        assert!(!from.exists());
+    }
+
+    #[test]
+    fn only_stale_temp_files_are_cleaned() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(clean_temp(dir.path()), 0);
+        let temp = dir.path().join(TEMP_DIR);
+        let (stale, fresh) = (temp.join("stale.part"), temp.join("fresh.part"));
+        std::fs::write(&stale, b"left over").unwrap();
+        std::fs::write(&fresh, b"in flight").unwrap();
+        let two_hours_ago = SystemTime::now() - 2 * TEMP_MAX_AGE;
+        std::fs::File::options().write(true).open(&stale).unwrap().set_modified(two_hours_ago).unwrap();
+
+        assert_eq!(clean_temp(dir.path()), 1);
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+    }
+}
@@ -0,0 +1,96 @@
+// A cap on how many uploads are read at once, so a burst of large files
+// can't use up file descriptors and blocking threads and stall page loads.
+// save_post takes a slot when a post's file part starts, before any of it
+// is read, and gives it back once the file is stored; text-only posts never
+// take one.
+//
+// With all MAX_CONCURRENT_UPLOADS slots taken, an upload waits up to
+// UPLOAD_SLOT_WAIT_SECS for one to free and is then turned away with a 503,
+// or at once with UPLOAD_SLOT_WAIT_SECS=0. MAX_CONCURRENT_UPLOADS=0 leaves
+// uploads uncapped, though they're still counted.
+
+use actix_web::rt::time::sleep;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+// How often a waiting upload looks for a free slot
+const POLL: Duration = Duration::from_millis(50);
+
+pub struct UploadSlots {
+    max: usize,
+    wait: Duration,
+    in_use: Arc<AtomicUsize>,
+    // Since the server started
+    turned_away: AtomicU64,
+}
+
+impl UploadSlots {
+    pub fn new(config: &Config) -> UploadSlots {
+        UploadSlots {
+            max: config.max_concurrent_uploads,
+            wait: Duration::from_secs(config.upload_slot_wait_secs),
+            in_use: Arc::new(AtomicUsize::new(0)),
+            turned_away: AtomicU64::new(0),
+        }
+    }
+
+    fn try_take(&self) -> Option<UploadSlot> {
+        self.in_use
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_use| {
+                (self.max == 0 || in_use < self.max).then_some(in_use + 1)
+            })
+            .ok()?;
+        Some(UploadSlot {
+            in_use: self.in_use.clone(),
+        })
+    }
+
+    // None once the wait is over with every slot still taken
+    pub async fn take(&self) -> Option<UploadSlot> {
+        let deadline = Instant::now() + self.wait;
+        loop {
+            if let Some(slot) = self.try_take() {
+                return Some(slot);
+            }
+            if Instant::now() >= deadline {
+                self.turned_away.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            sleep(POLL).await;
+        }
+    }
+
+    // Suggested to clients that were turned away
+    pub fn retry_after(&self) -> u64 {
+        self.wait.as_secs().max(5)
+    }
+
+    // "3 of 8 upload slots in use, 12 turned away since the server
+    // started", for the admin pages
+    pub fn report(&self) -> String {
+        let in_use = self.in_use.load(Ordering::Relaxed);
+        let mut report = match self.max {
+            0 => format!("{} uploads in progress, no limit", in_use),
+            max => format!("{} of {} upload slots in use", in_use, max),
+        };
+        let turned_away = self.turned_away.load(Ordering::Relaxed);
+        if turned_away > 0 {
+            report.push_str(&format!(", {} turned away since the server started", turned_away));
+        }
+        report
+    }
+}
+
+// Gives the slot back when dropped, however the upload ends
+pub struct UploadSlot {
+    in_use: Arc<AtomicUsize>,
+}
+
+impl Drop for UploadSlot {
+    fn drop(&mut self) {
+        self.in_use.fetch_sub(1, Ordering::AcqRel);
+    }
+}
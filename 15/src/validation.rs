@@ -0,0 +1,265 @@
+// Checks run on a submitted post before anything is stored. Every problem
+// found is reported, see rejection.rs for how they reach the client.
+
+use std::ops::Range;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::changes::ReplyRefused;
+use crate::config::Config;
+use crate::format;
+use crate::poster;
+use crate::rejection::{ErrorCode, FieldError};
+use crate::settings::BoardSettings;
+
+// The form's maxlength attributes match these
+const MAX_TITLE_CHARS: usize = 15;
+const MAX_NAME_CHARS: usize = 50;
+const MAX_OPTIONS_CHARS: usize = 100;
+// The longest address SMTP can deliver to
+const MAX_EMAIL_CHARS: usize = 254;
+pub const MAX_MESSAGE_CHARS: usize = 100_000;
+
+pub struct Submission<'a> {
+    pub title: &'a str,
+    // As it will be stored, after any tripcode is split off
+    pub name: Option<&'a str>,
+    pub message: &'a str,
+    pub is_thread: bool,
+    pub has_file: bool,
+    // For replies, when the thread closes
+    pub thread_locks_at: Option<u64>,
+    pub thread_archived: bool,
+    pub timestamp: u64,
+    // For posters with an exemption, see exemptions.rs, and for admins
+    pub skip_spam_checks: bool,
+}
+
+pub fn validate_post(config: &Config, settings: &BoardSettings, submission: &Submission) -> Result<(), Vec<FieldError>> {
+    if settings.locked {
+        return Err(vec![FieldError::new("post", ErrorCode::Locked, "The board is locked.")]);
+    }
+    if submission.thread_archived {
+        return Err(vec![refusal(ReplyRefused::Archived)]);
+    }
+    if submission.thread_locks_at.map(|locks_at| submission.timestamp >= locks_at).unwrap_or(false) {
+        return Err(vec![refusal(ReplyRefused::Closed)]);
+    }
+    let mut errors = Vec::new();
+    if settings.require_file_for_threads && submission.is_thread && !submission.has_file {
+        errors.push(FieldError::new("file", ErrorCode::Missing, "New threads need an attachment."));
+    }
+    errors.extend(check_length("title", "A title", submission.title, MAX_TITLE_CHARS));
+    errors.extend(check_name(config, submission.name));
+    errors.extend(check_length("message", "A message", submission.message, MAX_MESSAGE_CHARS));
+    if !submission.skip_spam_checks {
+        errors.extend(check_spam(config, &normalize_message(submission.message)));
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+pub fn refusal(refused: ReplyRefused) -> FieldError {
+    match refused {
+        ReplyRefused::ThreadGone => FieldError::new("post", ErrorCode::ThreadGone, "This thread was deleted."),
+        ReplyRefused::Archived => FieldError::new("post", ErrorCode::Locked, "This thread is archived."),
+        ReplyRefused::Closed => FieldError::new("post", ErrorCode::Locked, "This thread is closed."),
+        ReplyRefused::SlowMode { wait_secs } => {
+            let message = format!("This thread is in slow mode. You can reply again in {} s.", wait_secs);
+            FieldError::new("post", ErrorCode::RateLimited, message).retry_after(wait_secs)
+        }
+    }
+}
+
+// "Close after N hours" from the new-thread form, as the time the thread
+// closes. Empty means it never does.
+pub fn parse_lock_after(config: &Config, raw: &str, now: u64) -> Result<Option<u64>, FieldError> {
+    const FIELD: &str = "lock_after_hours";
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Ok(None);
+    }
+    let hours: i64 = raw
+        .parse()
+        .map_err(|_| FieldError::new(FIELD, ErrorCode::Invalid, "The closing time has to be a whole number of hours."))?;
+    if hours <= 0 {
+        return Err(FieldError::new(FIELD, ErrorCode::Invalid, "The closing time has to be in the future."));
+    }
+    if hours as u64 > config.max_thread_lock_hours {
+        let message = format!("Threads can close at most {} hours after they start.", config.max_thread_lock_hours);
+        return Err(FieldError::new(FIELD, ErrorCode::Invalid, message).max(config.max_thread_lock_hours));
+    }
+    Ok(Some(now + hours as u64 * 60 * 60))
+}
+
+// The optional notification address on the new-thread form. Ignored on
+// replies and when notifications aren't set up.
+pub fn parse_email(config: &Config, raw: &str, is_thread: bool) -> Result<Option<String>, FieldError> {
+    let raw = raw.trim();
+    if raw.is_empty() || !is_thread || !config.notify_enabled() {
+        return Ok(None);
+    }
+    // lettre refuses these too, but the address goes into mail headers
+    let plausible = raw.chars().count() <= MAX_EMAIL_CHARS && !raw.contains(char::is_control);
+    match raw.parse::<lettre::Address>() {
+        Ok(address) if plausible => Ok(Some(address.to_string())),
+        _ => Err(FieldError::new("email", ErrorCode::Invalid, "That doesn't look like an email address.")),
+    }
+}
+
+// The options field, tidied: control characters, line breaks included,
+// become spaces, runs of whitespace collapse, the flags poster.rs knows
+// are lowercased and the rest is kept as typed, up to MAX_OPTIONS_CHARS.
+// It's escaped wherever it's shown, but it also ends up in logs and in
+// front of moderators, and < or > have no use in it, so those are refused.
+pub fn parse_options(raw: &str) -> Result<String, FieldError> {
+    if raw.contains(['<', '>']) {
+        return Err(FieldError::new("options", ErrorCode::Invalid, "Options can't contain < or >."));
+    }
+    let options = raw
+        .split(|c: char| c.is_whitespace() || c.is_control())
+        .filter(|option| !option.is_empty())
+        .map(|option| {
+            let lower = option.to_lowercase();
+            if poster::OPTION_FLAGS.contains(&lower.as_str()) {
+                lower
+            } else {
+                option.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    Ok(options.chars().take(MAX_OPTIONS_CHARS).collect::<String>().trim_end().to_string())
+}
+
+// Invisible formatting characters are taken out of titles, names, the
+// options field and file names: bidi overrides and isolates that can
+// reorder text ("cat\u{202E}gpj.exe" shows as "catexe.jpg"), zero width
+// spaces and the like, and joiners except a single one between two emoji.
+// Messages keep theirs; the templates isolate them with dir="auto".
+pub fn strip_format_chars(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut kept = String::with_capacity(text.len());
+    for (i, &c) in chars.iter().enumerate() {
+        if c == '\u{200D}' {
+            let joins_emoji = i > 0 && is_pictograph(chars[i - 1]) && chars.get(i + 1).copied().is_some_and(is_pictograph);
+            if !joins_emoji {
+                continue;
+            }
+        } else if is_format_char(c) {
+            continue;
+        }
+        kept.push(c);
+    }
+    kept
+}
+
+// Unicode's "Cf" (format) category
+fn is_format_char(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00AD}'
+            | '\u{0600}'..='\u{0605}'
+            | '\u{061C}'
+            | '\u{06DD}'
+            | '\u{070F}'
+            | '\u{0890}'..='\u{0891}'
+            | '\u{08E2}'
+            | '\u{180E}'
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{206F}'
+            | '\u{FEFF}'
+            | '\u{FFF9}'..='\u{FFFB}'
+            | '\u{110BD}'
+            | '\u{110CD}'
+            | '\u{13430}'..='\u{1343F}'
+            | '\u{1BCA0}'..='\u{1BCA3}'
+            | '\u{1D173}'..='\u{1D17A}'
+            | '\u{E0001}'
+            | '\u{E0020}'..='\u{E007F}'
+    )
+}
+
+// Emoji as far as joining goes, with any skin tone or variation selector
+// counting as part of the one before
+fn is_pictograph(c: char) -> bool {
+    matches!(c, '\u{2600}'..='\u{27BF}' | '\u{FE0F}' | '\u{1F000}'..='\u{1FAFF}')
+}
+
+fn check_length(field: &'static str, what: &str, value: &str, max: usize) -> Option<FieldError> {
+    if value.trim().is_empty() {
+        return Some(FieldError::new(field, ErrorCode::Missing, format!("{} is required.", what)));
+    }
+    if value.chars().count() > max {
+        let message = format!("{} can be at most {} characters.", what, max);
+        return Some(FieldError::new(field, ErrorCode::TooLong, message).max(max as u64));
+    }
+    None
+}
+
+fn check_name(config: &Config, name: Option<&str>) -> Option<FieldError> {
+    match name {
+        None if config.names_required() => Some(FieldError::new("name", ErrorCode::Missing, "A name is required.")),
+        Some(name) if name.chars().count() > MAX_NAME_CHARS => {
+            let message = format!("Names can be at most {} characters.", MAX_NAME_CHARS);
+            Some(FieldError::new("name", ErrorCode::TooLong, message).max(MAX_NAME_CHARS as u64))
+        }
+        _ => None,
+    }
+}
+
+// Whitespace is collapsed so padding can't dilute the URL fraction or break
+// up a repeated run.
+fn normalize_message(message: &str) -> String {
+    message.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// A message with one link is someone sharing it, however long the link,
+// so "mostly links" takes at least two
+fn check_spam(config: &Config, message: &str) -> Option<FieldError> {
+    let length = message.chars().count();
+    let urls = format::find_urls(message);
+    if length > config.spam_url_min_length && urls.len() > 1 && url_fraction(message, &urls) > config.spam_url_fraction {
+        return Some(FieldError::new("message", ErrorCode::Spam, "Your message is mostly links."));
+    }
+    if longest_repeat_run(message) > config.spam_max_repeat_run {
+        return Some(FieldError::new(
+            "message",
+            ErrorCode::Spam,
+            "Your message contains too many repeated characters.",
+        ));
+    }
+    None
+}
+
+fn url_fraction(message: &str, urls: &[Range<usize>]) -> f64 {
+    let total = message.chars().filter(|c| !c.is_whitespace()).count();
+    if total == 0 {
+        return 0.0;
+    }
+    let in_urls: usize = urls.iter().map(|range| message[range.clone()].chars().count()).sum();
+    in_urls as f64 / total as f64
+}
+
+// Counted in graphemes, so "👍🏽" or "é" written as e and an accent repeat as
+// one character would. Spaces between the repeats don't reset the run,
+// "a a a a" counts as four.
+fn longest_repeat_run(message: &str) -> usize {
+    let mut longest = 0;
+    let mut current = 0;
+    let mut previous = None;
+    for grapheme in message.graphemes(true).filter(|grapheme| !grapheme.trim().is_empty()) {
+        if Some(grapheme) == previous {
+            current += 1;
+        } else {
+            current = 1;
+            previous = Some(grapheme);
+        }
+        longest = longest.max(current);
+    }
+    longest
+}
@@ -0,0 +1,220 @@
+// `verify-files`: checks every upload a post refers to against the disk.
+//
+//   your_project_name verify-files [--fix] [--output report.json]
+//
+// Reports referenced files that are missing, files that are empty or don't
+// match their stored hash, files in the upload directory no post refers
+// to, including stale temporary files, and post records that can't be
+// read. Posts are checked on SCAN_THREADS threads. Orphans are listed by their
+// path relative to the upload directory. With --fix, posts whose file
+// is missing get the file cleared and a notice in its place. sled allows one process per database, so this
+// runs with the server stopped.
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sled::Db;
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::config::Config;
+use crate::{schema, startup, storage, upload, Post};
+
+const MISSING_NOTICE: &str = "The attachment is no longer available.";
+
+#[derive(Serialize)]
+struct MissingFile {
+    post_id: String,
+    file: String,
+}
+
+#[derive(Serialize)]
+struct CorruptFile {
+    post_id: String,
+    file: String,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct UnreadablePost {
+    key: String,
+    reason: String,
+}
+
+#[derive(Serialize, Default)]
+struct Totals {
+    posts_scanned: usize,
+    unreadable: usize,
+    files_referenced: usize,
+    ok: usize,
+    missing: usize,
+    corrupt: usize,
+    orphaned: usize,
+    fixed: usize,
+}
+
+#[derive(Serialize, Default)]
+struct Report {
+    missing: Vec<MissingFile>,
+    corrupt: Vec<CorruptFile>,
+    orphaned: Vec<String>,
+    unreadable: Vec<UnreadablePost>,
+    totals: Totals,
+}
+
+enum FileState {
+    Ok,
+    Missing,
+    Corrupt(String),
+}
+
+// Hashes in chunks so large files aren't read into memory.
+fn check_file(path: &Path, expected_hash: Option<&str>) -> FileState {
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return FileState::Missing,
+        Err(e) => return FileState::Corrupt(e.to_string()),
+    };
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                hasher.update(&buf[..n]);
+                size += n;
+            }
+            Err(e) => return FileState::Corrupt(e.to_string()),
+        }
+    }
+    if size == 0 {
+        return FileState::Corrupt("empty file".to_string());
+    }
+    match expected_hash {
+        Some(expected) if upload::hex(&hasher.finalize()) != expected => {
+            FileState::Corrupt("content doesn't match the stored hash".to_string())
+        }
+        _ => FileState::Ok,
+    }
+}
+
+// Clears the file fields in place, see schema.rs.
+fn clear_file(db: &Db, post: &Post) {
+    db.update_and_fetch(&post.id, |old| {
+        let old = old?;
+        let cleared = schema::merge_fields(old, |fields| {
+            for field in ["file", "file_hash", "original_name", "file_size", "media_kind", "width", "height"] {
+                fields.insert(field.to_string(), serde_json::Value::Null);
+            }
+            fields.insert("upload_error".to_string(), MISSING_NOTICE.into());
+        });
+        Some(cleared.unwrap_or_else(|_| old.to_vec()))
+    })
+    .unwrap();
+    upload::forget(db, post);
+}
+
+pub fn run(args: &[String]) -> std::io::Result<()> {
+    let fix = args.iter().any(|arg| arg == "--fix");
+    let output = args
+        .iter()
+        .position(|arg| arg == "--output")
+        .and_then(|i| args.get(i + 1));
+
+    let config = Config::from_env();
+    let db = startup::open_or_exit("my_db", None);
+    let report = Mutex::new(Report::default());
+    let referenced = Mutex::new(HashSet::new());
+
+    // Files are hashed outside the locks
+    let scan = storage::scan_all_parallel(&db, config.scan_threads, |post| {
+        // Thumbnails of pruned files aren't checked, only kept, see
+        // media_prune.rs
+        if let Some(thumbnail) = &post.thumbnail {
+            referenced.lock().unwrap().insert(thumbnail.clone());
+        }
+        let file = match &post.file {
+            Some(file) => file.clone(),
+            None => return,
+        };
+        let state = check_file(&config.upload_path(&file), post.file_hash.as_deref());
+        if fix && matches!(state, FileState::Missing) {
+            clear_file(&db, &post);
+        }
+        let mut report = report.lock().unwrap();
+        report.totals.files_referenced += 1;
+        match state {
+            FileState::Ok => report.totals.ok += 1,
+            FileState::Missing => {
+                if fix {
+                    report.totals.fixed += 1;
+                }
+                report.missing.push(MissingFile { post_id: post.id.clone(), file: file.clone() });
+            }
+            FileState::Corrupt(reason) => report.corrupt.push(CorruptFile {
+                post_id: post.id.clone(),
+                file: file.clone(),
+                reason,
+            }),
+        }
+        referenced.lock().unwrap().insert(file);
+    });
+    let mut report = report.into_inner().unwrap();
+    let mut referenced = referenced.into_inner().unwrap();
+    report.totals.posts_scanned = scan.posts;
+    report.unreadable = scan
+        .unreadable
+        .into_iter()
+        .map(|(key, reason)| UnreadablePost { key, reason })
+        .collect();
+    // Threads finish in any order; the report is sorted so runs compare
+    report.missing.sort_by(|a, b| a.post_id.cmp(&b.post_id));
+    report.corrupt.sort_by(|a, b| a.post_id.cmp(&b.post_id));
+
+    // Held posts aren't checked, but their files aren't orphans either
+    for bytes in db.open_tree("pending").unwrap().iter().values() {
+        if let Some(file) = Post::upgrade(&bytes.unwrap()).ok().and_then(|post| post.file) {
+            referenced.insert(file);
+        }
+    }
+
+    // Both the flat layout and the dated directories, see upload.rs
+    for file in upload::stored_files(&config.upload_dir) {
+        if !referenced.contains(&file) {
+            report.orphaned.push(file);
+        }
+    }
+    // Temporary files young enough to be an upload in progress are skipped
+    for path in upload::stale_temp_files(&config.upload_dir) {
+        if let Some(name) = path.file_name() {
+            report.orphaned.push(format!("{}/{}", upload::TEMP_DIR, name.to_string_lossy()));
+        }
+    }
+    report.orphaned.sort();
+
+    db.flush()?;
+    report.totals.missing = report.missing.len();
+    report.totals.corrupt = report.corrupt.len();
+    report.totals.orphaned = report.orphaned.len();
+    report.totals.unreadable = report.unreadable.len();
+
+    let json = serde_json::to_string_pretty(&report).unwrap();
+    match output {
+        Some(path) => std::fs::write(path, json)?,
+        None => println!("{}", json),
+    }
+    eprintln!(
+        "{} posts ({} unreadable), {} files: {} ok, {} missing, {} corrupt, {} orphaned{}",
+        report.totals.posts_scanned,
+        report.totals.unreadable,
+        report.totals.files_referenced,
+        report.totals.ok,
+        report.totals.missing,
+        report.totals.corrupt,
+        report.totals.orphaned,
+        if fix { format!(", {} fixed", report.totals.fixed) } else { String::new() },
+    );
+    Ok(())
+}
@@ -0,0 +1,256 @@
+// Patterns moderators want to hear about without blocking, such as phone
+// numbers or invite links. They're kept in the board settings as named
+// regexes, one "name=regex" per line on the settings page, and checked
+// against each new post's title, name and message once it's stored.
+// Matches go in `watch_flags`, keyed "{pattern name}/{post_id}" with the
+// post time as the value, and are listed by pattern at /admin/flagged.
+//
+// The `regex` crate runs in time linear in the text, so a pattern can't be
+// made to backtrack forever. On top of that patterns are capped in number,
+// length and compiled size, and only the first MAX_SCAN_CHARS of a post are
+// looked at. All patterns are compiled into one set, rebuilt only when the
+// settings change.
+
+use actix_web::web;
+use regex::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
+use sled::Db;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::events::{BoardEvent, EventHandler};
+use crate::settings::{BoardSettings, SettingsCache, WatchPattern};
+use crate::{format, load_post, Post};
+
+pub const MAX_PATTERNS: usize = 50;
+pub const MAX_PATTERN_CHARS: usize = 500;
+const MAX_NAME_CHARS: usize = 30;
+const MAX_COMPILED_BYTES: usize = 1 << 20;
+const MAX_SCAN_CHARS: usize = 20_000;
+// How much of the message a snippet shows before the first match
+const SNIPPET_LEAD_CHARS: usize = 40;
+
+fn flag_key(name: &str, post_id: &str) -> String {
+    format!("{}/{}", name, post_id)
+}
+
+fn compile(patterns: &[WatchPattern]) -> Result<RegexSet, regex::Error> {
+    RegexSetBuilder::new(patterns.iter().map(|pattern| &pattern.pattern))
+        .size_limit(MAX_COMPILED_BYTES)
+        .build()
+}
+
+// One "name=regex" per line. Names are letters, digits, '-' and '_' so
+// they can sit in keys and URLs.
+pub fn parse_patterns(lines: &str) -> Result<Vec<WatchPattern>, String> {
+    let patterns = lines
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (name, pattern) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Watch pattern \"{}\" should look like name=regex.", line))?;
+            let name = name.trim();
+            let valid_name = !name.is_empty()
+                && name.chars().count() <= MAX_NAME_CHARS
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid_name {
+                return Err(format!(
+                    "Watch pattern names are 1 to {} letters, digits, - or _, not \"{}\".",
+                    MAX_NAME_CHARS, name
+                ));
+            }
+            if pattern.is_empty() || pattern.chars().count() > MAX_PATTERN_CHARS {
+                return Err(format!("The \"{}\" pattern must be 1 to {} characters.", name, MAX_PATTERN_CHARS));
+            }
+            Ok(WatchPattern {
+                name: name.to_string(),
+                pattern: pattern.to_string(),
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    if patterns.len() > MAX_PATTERNS {
+        return Err(format!("There can be at most {} watch patterns.", MAX_PATTERNS));
+    }
+    compile(&patterns).map_err(|e| format!("A watch pattern doesn't compile: {}", e))?;
+    Ok(patterns)
+}
+
+// Flags new posts from posters the spam checks apply to. Keeps the
+// compiled set for the settings it was built from.
+pub struct Watchlist {
+    db: Db,
+    settings: web::Data<SettingsCache>,
+    compiled: Mutex<Option<(Arc<BoardSettings>, Arc<RegexSet>)>>,
+}
+
+impl Watchlist {
+    pub fn new(db: &Db, settings: web::Data<SettingsCache>) -> Watchlist {
+        Watchlist {
+            db: db.clone(),
+            settings,
+            compiled: Mutex::new(None),
+        }
+    }
+
+    // Saving settings replaces the cached Arc, which is how a change is
+    // noticed
+    fn set(&self, settings: &Arc<BoardSettings>) -> Arc<RegexSet> {
+        let mut compiled = self.compiled.lock().unwrap();
+        if let Some((built_from, set)) = compiled.as_ref() {
+            if Arc::ptr_eq(built_from, settings) {
+                return set.clone();
+            }
+        }
+        // Checked when saved, so this only fails if the limits shrink
+        let set = Arc::new(compile(&settings.watch_patterns).unwrap_or_else(|e| {
+            eprintln!("watch patterns don't compile, ignoring them: {}", e);
+            RegexSet::empty()
+        }));
+        *compiled = Some((settings.clone(), set.clone()));
+        set
+    }
+
+    // Names of the patterns the post matches
+    fn matches(&self, settings: &Arc<BoardSettings>, post: &Post) -> Vec<String> {
+        if settings.watch_patterns.is_empty() {
+            return Vec::new();
+        }
+        let text = format!("{}\n{}\n{}", post.title, post.name.as_deref().unwrap_or_default(), post.message);
+        let text = match text.char_indices().nth(MAX_SCAN_CHARS) {
+            Some((end, _)) => &text[..end],
+            None => &text,
+        };
+        self.set(settings)
+            .matches(text)
+            .iter()
+            .filter_map(|i| settings.watch_patterns.get(i))
+            .map(|pattern| pattern.name.clone())
+            .collect()
+    }
+}
+
+// One pattern on its own, for marking up what it matched
+pub fn pattern(settings: &BoardSettings, name: &str) -> Option<Regex> {
+    let pattern = settings.watch_patterns.iter().find(|pattern| pattern.name == name)?;
+    RegexBuilder::new(&pattern.pattern).size_limit(MAX_COMPILED_BYTES).build().ok()
+}
+
+// Up to `max_chars` of `message` from a little before the first match, as
+// HTML with each match in <mark>. Without a match, or a pattern, it's the
+// start of the message.
+pub fn snippet(message: &str, pattern: Option<&Regex>, max_chars: usize) -> String {
+    let first = pattern.and_then(|pattern| pattern.find(message)).map_or(0, |found| found.start());
+    let start = message[..first].char_indices().rev().nth(SNIPPET_LEAD_CHARS - 1).map_or(0, |(i, _)| i);
+    let end = message[start..].char_indices().nth(max_chars).map_or(message.len(), |(i, _)| start + i);
+    let window = &message[start..end];
+    let mut out = String::new();
+    if start > 0 {
+        out.push('…');
+    }
+    let mut last = 0;
+    for found in pattern.into_iter().flat_map(|pattern| pattern.find_iter(window)) {
+        if found.is_empty() {
+            continue;
+        }
+        out.push_str(&format::escape_html(&window[last..found.start()]));
+        out.push_str("<mark>");
+        out.push_str(&format::escape_html(found.as_str()));
+        out.push_str("</mark>");
+        last = found.end();
+    }
+    out.push_str(&format::escape_html(&window[last..]));
+    if end < message.len() {
+        out.push('…');
+    }
+    out
+}
+
+impl EventHandler for Watchlist {
+    fn name(&self) -> &'static str {
+        "watch_patterns"
+    }
+
+    fn handle(&self, event: &BoardEvent) -> Result<(), String> {
+        if let BoardEvent::Created { post, spam_checks: true } = event {
+            let settings = self.settings.get(&self.db);
+            flag(&self.db, post, &self.matches(&settings, post));
+        }
+        Ok(())
+    }
+}
+
+fn flag(db: &Db, post: &Post, names: &[String]) {
+    let flags = db.open_tree("watch_flags").unwrap();
+    for name in names {
+        flags.insert(flag_key(name, &post.id), &post.timestamp.to_be_bytes()).unwrap();
+    }
+}
+
+// Drops a deleted post's flags. The queue is small, so it's scanned.
+// Returns how many entries were removed.
+pub fn forget(db: &Db, post_id: &str) -> usize {
+    let flags = db.open_tree("watch_flags").unwrap();
+    let suffix = format!("/{}", post_id);
+    let mut removed = 0;
+    for key in flags.iter().keys() {
+        let key = key.unwrap();
+        if key.ends_with(suffix.as_bytes()) && flags.remove(&key).unwrap().is_some() {
+            removed += 1;
+        }
+    }
+    removed
+}
+
+// Flagged posts grouped by pattern name, oldest first. Posts deleted since
+// are left out.
+pub fn groups(db: &Db) -> BTreeMap<String, Vec<Post>> {
+    let mut groups: BTreeMap<String, Vec<Post>> = BTreeMap::new();
+    for key in db.open_tree("watch_flags").unwrap().iter().keys() {
+        let key = String::from_utf8_lossy(&key.unwrap()).into_owned();
+        if let Some((name, post_id)) = key.split_once('/') {
+            if let Some(post) = load_post(db, post_id) {
+                groups.entry(name.to_string()).or_default().push(post);
+            }
+        }
+    }
+    for posts in groups.values_mut() {
+        posts.sort_by_key(|post| post.timestamp);
+    }
+    groups
+}
+
+pub fn group(db: &Db, name: &str) -> Vec<Post> {
+    db.open_tree("watch_flags")
+        .unwrap()
+        .scan_prefix(format!("{}/", name))
+        .keys()
+        .filter_map(|key| {
+            let key = key.unwrap();
+            let key = String::from_utf8_lossy(&key);
+            load_post(db, key.split_once('/')?.1)
+        })
+        .collect()
+}
+
+// Names of the patterns the post is flagged under, for its dossier
+pub fn patterns_for(db: &Db, post_id: &str) -> Vec<String> {
+    let suffix = format!("/{}", post_id);
+    db.open_tree("watch_flags")
+        .unwrap()
+        .iter()
+        .keys()
+        .filter_map(|key| {
+            let key = String::from_utf8_lossy(&key.unwrap()).into_owned();
+            key.strip_suffix(&suffix).map(str::to_string)
+        })
+        .collect()
+}
+
+// Clears a pattern's flags once a moderator has looked at them
+pub fn dismiss(db: &Db, name: &str) {
+    let flags = db.open_tree("watch_flags").unwrap();
+    for key in flags.scan_prefix(format!("{}/", name)).keys() {
+        flags.remove(key.unwrap()).unwrap();
+    }
+}
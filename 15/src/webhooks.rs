@@ -0,0 +1,156 @@
+// Announces new threads (and optionally replies) to external services,
+// e.g. a Discord or Matrix bridge. Posting only puts a payload on a bounded
+// queue; a background thread does the sending, so a slow or dead endpoint
+// never holds up or fails a post. When the queue is full the announcement
+// is dropped and logged.
+//
+// With WEBHOOK_SECRET set each request carries
+// `X-Signature: sha256=<hex HMAC-SHA256 of the body>`.
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::events::{BoardEvent, EventHandler};
+use crate::{format, upload, Post};
+
+const QUEUE_SIZE: usize = 256;
+const MESSAGE_PREVIEW: usize = 200;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize)]
+struct Payload {
+    id: String,
+    parent_id: Option<String>,
+    title: String,
+    message: String,
+    file_url: Option<String>,
+    media_type: Option<upload::MediaKind>,
+    timestamp: u64,
+}
+
+impl Payload {
+    fn new(config: &Config, post: &Post) -> Payload {
+        Payload {
+            id: post.id.clone(),
+            parent_id: post.parent_id.clone(),
+            title: post.title.clone(),
+            message: format::format_message_plain(&post.message, MESSAGE_PREVIEW),
+            file_url: post
+                .file
+                .as_deref()
+                .map(|file| format!("{}{}", config.public_url, config.upload_url(file))),
+            media_type: post.media_kind(),
+            timestamp: post.timestamp,
+        }
+    }
+}
+
+struct Job {
+    body: Vec<u8>,
+}
+
+pub struct Webhooks {
+    config: Config,
+    // None when no webhook URLs are configured
+    queue: Option<SyncSender<Job>>,
+}
+
+impl Webhooks {
+    pub fn start(config: &Config) -> Webhooks {
+        if config.webhook_urls.is_empty() {
+            return Webhooks {
+                config: config.clone(),
+                queue: None,
+            };
+        }
+        let (queue, jobs) = mpsc::sync_channel(QUEUE_SIZE);
+        let urls = config.webhook_urls.clone();
+        let secret = config.webhook_secret.clone();
+        let attempts = config.webhook_attempts;
+        std::thread::spawn(move || send_all(jobs, &urls, secret.as_deref(), attempts));
+        Webhooks {
+            config: config.clone(),
+            queue: Some(queue),
+        }
+    }
+
+    // Called once a post is visible on the board
+    fn announce(&self, post: &Post) {
+        let config = &self.config;
+        let queue = match &self.queue {
+            Some(queue) => queue,
+            None => return,
+        };
+        if post.parent_id.is_some() && !config.webhook_replies {
+            return;
+        }
+        match queue.try_send(Job { body: payload(config, post).into_bytes() }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => eprintln!("webhook queue full, not announcing post {}", post.id),
+            Err(TrySendError::Disconnected(_)) => eprintln!("webhook sender stopped, not announcing post {}", post.id),
+        }
+    }
+}
+
+impl EventHandler for Webhooks {
+    fn name(&self) -> &'static str {
+        "webhooks"
+    }
+
+    // Only queues the payload, the sending has its own thread
+    fn handle(&self, event: &BoardEvent) -> Result<(), String> {
+        if let BoardEvent::Created { post, .. } = event {
+            self.announce(post);
+        }
+        Ok(())
+    }
+}
+
+// The JSON body sent for a post
+pub fn payload(config: &Config, post: &Post) -> String {
+    serde_json::to_string(&Payload::new(config, post)).unwrap()
+}
+
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    format!("sha256={}", upload::hex(&mac.finalize().into_bytes()))
+}
+
+fn send_all(jobs: Receiver<Job>, urls: &[String], secret: Option<&str>, attempts: u32) {
+    let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+    for job in jobs {
+        let signature = secret.map(|secret| signature(secret, &job.body));
+        for url in urls {
+            if let Err(e) = send(&agent, url, &job.body, signature.as_deref(), attempts) {
+                eprintln!("webhook {} failed after {} attempts: {}", url, attempts, e);
+            }
+        }
+    }
+}
+
+// Retries with exponential backoff: 1s, 2s, 4s, ...
+fn send(agent: &ureq::Agent, url: &str, body: &[u8], signature: Option<&str>, attempts: u32) -> Result<(), String> {
+    let mut delay = FIRST_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let mut request = agent.post(url).set("Content-Type", "application/json");
+        if let Some(signature) = signature {
+            request = request.set("X-Signature", signature);
+        }
+        match request.send_bytes(body) {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt >= attempts => return Err(e.to_string()),
+            Err(_) => {
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+// A small list of the latest threads for other sites to embed: /widget is
+// a bare HTML page meant for an iframe, /widget.json the same list for
+// scripts. Which sites may frame or fetch them is WIDGET_ORIGINS.
+
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use askama::Template;
+use serde::{Deserialize, Serialize};
+use sled::Db;
+
+use crate::age_gate::ApiAccess;
+use crate::api::ApiPost;
+use crate::config::Config;
+use crate::{indexes, render, Post};
+
+const DEFAULT_LIMIT: usize = 5;
+const MAX_LIMIT: usize = 20;
+// Long enough that embedding sites don't hit the board on every page view
+const CACHE_CONTROL: &str = "public, max-age=60";
+
+#[derive(Deserialize)]
+pub struct WidgetQuery {
+    limit: Option<usize>,
+    // "compact" (the default) shows titles only, "full" adds a snippet
+    style: Option<String>,
+}
+
+impl WidgetQuery {
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "widget.html")]
+struct WidgetTemplate<'a> {
+    config: &'a Config,
+    threads: &'a [Post],
+    compact: bool,
+}
+
+impl WidgetTemplate<'_> {
+    fn thread_url(&self, thread: &Post) -> String {
+        format!("{}{}", self.config.public_url, self.config.post_url(&thread.id))
+    }
+}
+
+#[derive(Serialize)]
+struct WidgetList {
+    threads: Vec<ApiPost>,
+}
+
+fn frame_ancestors(config: &Config) -> String {
+    if config.widget_origins.is_empty() {
+        "'none'".to_string()
+    } else {
+        config.widget_origins.join(" ")
+    }
+}
+
+pub fn render(config: &Config, threads: &[Post], compact: bool) -> String {
+    render::fragment(&WidgetTemplate { config, threads, compact }, "the widget")
+}
+
+pub fn check_templates(config: &Config, thread: &Post) -> Result<(), String> {
+    for compact in [true, false] {
+        render::check(&WidgetTemplate {
+            config,
+            threads: std::slice::from_ref(thread),
+            compact,
+        })?;
+    }
+    Ok(())
+}
+
+pub async fn widget(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    _access: ApiAccess,
+    query: web::Query<WidgetQuery>,
+) -> HttpResponse {
+    let threads = indexes::latest_threads(&db, query.limit());
+    let compact = query.style.as_deref() != Some("full");
+    let policy = format!(
+        "default-src 'none'; style-src 'unsafe-inline'; img-src 'none'; frame-ancestors {}",
+        frame_ancestors(&config)
+    );
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header((header::CONTENT_SECURITY_POLICY, policy))
+        .insert_header((header::CACHE_CONTROL, CACHE_CONTROL));
+    let template = WidgetTemplate {
+        config: &config,
+        threads: &threads,
+        compact,
+    };
+    render::respond(response, &template, "the widget")
+}
+
+pub async fn widget_json(
+    db: web::Data<Db>,
+    config: web::Data<Config>,
+    _access: ApiAccess,
+    req: HttpRequest,
+    query: web::Query<WidgetQuery>,
+) -> HttpResponse {
+    let threads = indexes::latest_threads(&db, query.limit())
+        .into_iter()
+        .map(|thread| ApiPost::new(&config, thread))
+        .collect();
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header((header::CACHE_CONTROL, CACHE_CONTROL))
+        .insert_header((header::VARY, "Origin"));
+    let origin = req.headers().get(header::ORIGIN).and_then(|origin| origin.to_str().ok());
+    if let Some(origin) = origin {
+        if config.widget_origins.iter().any(|allowed| allowed == "*" || allowed == origin) {
+            response.insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin));
+        }
+    }
+    response.json(WidgetList { threads })
+}
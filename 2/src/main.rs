@@ -15,6 +15,28 @@ struct Post {
     file: Option<String>,
 }
 
+// Titles, messages and file names come from whoever posted them, so they
+// go into the page escaped
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// A message escaped, keeping its line breaks as <br>
+fn message_html(message: &str) -> String {
+    escape_html(message).replace("\r\n", "\n").replace('\n', "<br>")
+}
+
 async fn save_post(
     db: web::Data<Db>,
     upload_dir: web::Data<String>,
@@ -97,6 +119,7 @@ async fn index(db: web::Data<Db>) -> impl Responder {
         .iter()
         .map(|post| {
             let file_html = if let Some(file) = &post.file {
+                let file = escape_html(file);
                 let extension = file.split('.').last().unwrap_or("");
                 match extension {
                     "jpg" | "jpeg" | "png" | "gif" | "webp" => format!(r#"<img src="/static/uploads/{}" width="200" height="200" alt="Image">"#, file),
@@ -114,8 +137,8 @@ async fn index(db: web::Data<Db>) -> impl Responder {
                     <p>{}</p>
                     {}
                 </div>"#,
-                post.title,
-                post.message,
+                escape_html(&post.title),
+                message_html(&post.message),
                 file_html
             )
         })
@@ -164,3 +187,58 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    const NASTY: &str = "<script>alert(1)</script> & \"quoted\" 'single' a > b";
+    const ESCAPED: &str = "&lt;script&gt;alert(1)&lt;/script&gt; &amp; &quot;quoted&quot; &#39;single&#39; a &gt; b";
+
+    fn multipart(fields: &[(&str, &str)]) -> (String, Vec<u8>) {
+        let boundary = "XBOUNDARYX";
+        let mut body = Vec::new();
+        for (name, value) in fields {
+            let head = format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n", boundary, name);
+            body.extend_from_slice(head.as_bytes());
+            body.extend_from_slice(value.as_bytes());
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+        (format!("multipart/form-data; boundary={}", boundary), body)
+    }
+
+    #[test]
+    fn escapes_markup_characters() {
+        assert_eq!(escape_html(NASTY), ESCAPED);
+    }
+
+    #[test]
+    fn message_keeps_its_line_breaks() {
+        assert_eq!(message_html("one\r\n<two>\nthree"), "one<br>&lt;two&gt;<br>three");
+    }
+
+    #[actix_web::test]
+    async fn posts_are_escaped_on_the_index() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(db.clone()))
+                .app_data(web::Data::new(String::new()))
+                .route("/", web::get().to(index))
+                .route("/submit", web::post().to(save_post)),
+        )
+        .await;
+
+        let (content_type, body) = multipart(&[("title", NASTY), ("message", &format!("{}\nsecond line", NASTY))]);
+        let req = TestRequest::post().uri("/submit").insert_header(("Content-Type", content_type)).set_payload(body);
+        call_service(&app, req.to_request()).await;
+
+        let index = call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await;
+        let index = String::from_utf8(index.to_vec()).unwrap();
+        assert_eq!(index.matches(ESCAPED).count(), 2, "{}", index);
+        assert!(index.contains(&format!("{}<br>second line", ESCAPED)));
+        assert!(!index.contains("<script>"));
+    }
+}